@@ -5,20 +5,28 @@ use clap::{Parser, Subcommand, CommandFactory, ValueHint};
 
 use crate::{
     utils::fs::{
-        get_git_dir,
-        search_git_dir,
+        discover_repository,
+        Repository,
         to_pathbuf,
     },
+    utils::{config::Config, zlib, hash},
     command::{
         Init, Add, Rm, Commit, Branch, Checkout,
         CatFile, SubCommand, HashObject,
         UpdateIndex, CommitTree, ReadTree, WriteTree,
-        Merge, Fetch, Pull, Push, Remote,
+        Merge, Fetch, Pull, Push, Remote, Reset, RevParse, LsFiles, Show,
+        MergeBase, UpdateRef, SymbolicRef, Reflog, LsRemote, Fsck, Clean, Stash, LsTree, CherryPick, Revert, Archive, Describe, Mv, Grep, ShowRef, Status, Diff, Tag, Rebase, Clone, Blame, Shortlog, Notes, Worktree, Bisect, Bundle, Submodule, RevList, Mktree, UnpackObjects, PackObjects,
+        Gc, CountObjects,
     },
     GitError,
     Result,
 };
 
+/// commands which read or write files in the work tree, git refuses to run them in a bare repository
+const NEED_WORK_TREE: &[&str] = &[
+    "add", "checkout", "cherry-pick", "clean", "commit", "merge", "mv",
+    "pull", "rebase", "revert", "rm", "stash", "status", "submodule",
+];
 
 #[derive(Parser, Debug)]
 #[command(name = "git", about = "git commandline")]
@@ -27,26 +35,33 @@ pub struct Git {
     #[arg(short = 'C', value_hint = ValueHint::DirPath, help = "Run as if git was started in <path> instead of the current working directory.")]
     change_dir: Option<PathBuf>,
 
-    #[arg(required = true, allow_hyphen_values = true)]
+    #[arg(short = 'c', value_name = "name=value", help = "Pass a configuration parameter to the command, overriding the config files.")]
+    config: Vec<String>,
+
+    #[arg(required = true, allow_hyphen_values = true, trailing_var_arg = true)]
     subcommands: Vec<String>,
 }
 
 
 impl Git {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self> {
-        Ok(Self::try_parse_from(args)?)
+        Ok(Self::try_parse_from(args).map_err(GitError::from)?)
     }
 
     pub fn execute(&mut self) -> Result<i32> {
-        get_args(self.subcommands.clone().into_iter())
-            .and_then(|cmd| {
-                if self.change_dir.is_some() {
-                    cmd.run(search_git_dir(self.change_dir.take().unwrap()))
-                }
-                else {
-                    cmd.run(get_git_dir())
-                }
-            })
+        Config::set_overrides(&self.config)?;
+        let cmd = get_args(self.subcommands.clone().into_iter())?;
+        let repo = discover_repository(self.change_dir.take());
+        if let Ok(repo) = &repo && NEED_WORK_TREE.contains(&self.subcommands[0].as_str()) {
+            repo.work_tree()?;
+        }
+        let config = match &repo {
+            Ok(repo) => Config::read_all(&repo.gitdir)?,
+            Err(_) => Config::read_global()?,
+        };
+        zlib::init_level(&config)?;
+        hash::init_algorithm(&config)?;
+        cmd.run(repo)
     }
 }
 
@@ -60,16 +75,54 @@ pub fn get_args(raw_args: impl Iterator<Item=String>) -> Result<Box<dyn SubComma
         "cat-file" => CatFile::from_args(raw_args),
         "commit" => Commit::from_args(raw_args),
         "merge" => Merge::from_args(raw_args),
+        "merge-base" => MergeBase::from_args(raw_args),
         "fetch" => Fetch::from_args(raw_args),
         "pull" => Pull::from_args(raw_args),
         "push" => Push::from_args(raw_args),
         "remote" => Remote::from_args(raw_args),
+        "ls-remote" => LsRemote::from_args(raw_args),
+        "fsck" => Fsck::from_args(raw_args),
+        "clean" => Clean::from_args(raw_args),
+        "stash" => Stash::from_args(raw_args),
+        "cherry-pick" => CherryPick::from_args(raw_args),
+        "revert" => Revert::from_args(raw_args),
+        "archive" => Archive::from_args(raw_args),
+        "describe" => Describe::from_args(raw_args),
+        "mv" => Mv::from_args(raw_args),
+        "grep" => Grep::from_args(raw_args),
+        "show-ref" => ShowRef::from_args(raw_args),
+        "status" => Status::from_args(raw_args),
+        "diff" => Diff::from_args(raw_args),
+        "tag" => Tag::from_args(raw_args),
+        "rebase" => Rebase::from_args(raw_args),
+        "clone" => Clone::from_args(raw_args),
+        "blame" => Blame::from_args(raw_args),
+        "shortlog" => Shortlog::from_args(raw_args),
+        "notes" => Notes::from_args(raw_args),
+        "worktree" => Worktree::from_args(raw_args),
+        "bisect" => Bisect::from_args(raw_args),
+        "bundle" => Bundle::from_args(raw_args),
+        "submodule" => Submodule::from_args(raw_args),
+        "rev-list" => RevList::from_args(raw_args),
+        "mktree" => Mktree::from_args(raw_args),
+        "unpack-objects" => UnpackObjects::from_args(raw_args),
+        "pack-objects" => PackObjects::from_args(raw_args),
+        "ls-tree" => LsTree::from_args(raw_args),
+        "gc" | "repack" => Gc::from_args(raw_args),
+        "count-objects" => CountObjects::from_args(raw_args),
         "init"   => Init::from_args(raw_args),
         "add"    => Add::from_args(raw_args),
         "rm"     => Rm::from_args(raw_args),
         "branch" => Branch::from_args(raw_args),
         "checkout" => Checkout::from_args(raw_args),
+        "reset" => Reset::from_args(raw_args),
+        "rev-parse" => RevParse::from_args(raw_args),
+        "ls-files" => LsFiles::from_args(raw_args),
+        "show" => Show::from_args(raw_args),
+        "reflog" => Reflog::from_args(raw_args),
         "update-index" => UpdateIndex::from_args(raw_args),
+        "update-ref" => UpdateRef::from_args(raw_args),
+        "symbolic-ref" => SymbolicRef::from_args(raw_args),
         "write-tree" => WriteTree::from_args(raw_args),
         "commit-tree" => CommitTree::from_args(raw_args),
         "read-tree" => ReadTree::from_args(raw_args),
@@ -92,7 +145,7 @@ mod test {
         let args = to_strings(&["init"]);
         let command = get_args(args);
         assert!(command.is_ok());
-        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Init{dir: None}));
+        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Init{bare: false, initial_branch: None, object_format: None, dir: None}));
     }
 
     #[test]
@@ -109,17 +162,17 @@ mod test {
         let args = to_strings(&["commit", "-m", "messages"]);
         let command = get_args(args);
         assert!(command.is_ok());
-        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Commit { message: Some("messages".to_string()), all: false }));
+        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Commit { message: Some("messages".to_string()), all: false, amend: false }));
 
         let args = to_strings(&["commit", "-m", "messages", "-a"]);
         let command = get_args(args);
         assert!(command.is_ok());
-        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Commit { message: Some("messages".to_string()), all: true }));
+        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Commit { message: Some("messages".to_string()), all: true, amend: false }));
 
         let args = to_strings(&["commit", "--message", "messages", "--all"]);
         let command = get_args(args);
         assert!(command.is_ok());
-        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Commit { message: Some("messages".to_string()), all: true }));
+        assert_eq!(format!("{:?}", command.unwrap()), format!("{:?}", Commit { message: Some("messages".to_string()), all: true, amend: false }));
     }
 
     use std::fs::{
@@ -139,7 +192,7 @@ mod test {
 
         let args = to_strings(&["add", "-n", ".no_exist_s"]);
         let command = get_args(args).unwrap();
-        let a = command.run(Ok(PathBuf::from("/tmp")));
+        let a = command.run(Ok(Repository::at("/tmp")));
         assert!(a.is_err());
 
         File::create("add_tmp1").unwrap();
@@ -166,7 +219,7 @@ mod test {
 
         let args = to_strings(&["rm", "--cached", "-n", ".no_exist_s"]);
         let command = get_args(args).unwrap();
-        let a = command.run(Ok(PathBuf::from("/tmp")));
+        let a = command.run(Ok(Repository::at("/tmp")));
         assert!(a.is_err());
 
         File::create("rm_tmp1").unwrap();
@@ -179,4 +232,85 @@ mod test {
         remove_file("rm_tmp2").unwrap();
         remove_file("rm_tmp3").unwrap();
     }
+
+    #[test]
+    fn test_work_tree_resolution() {
+        use crate::utils::test::{shell_spawn, setup_test_git_dir, tempdir};
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let sub = temp_path.join("sub");
+        let sub_str = sub.to_str().unwrap();
+        std::fs::create_dir_all(&sub).unwrap();
+        for name in ["sub/a.txt", "sub/b.txt", "top.txt"] {
+            std::fs::write(temp_path.join(name), name).unwrap();
+        }
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        // started from the crate directory, paths are relative to the -C directory like in git
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", sub_str][..], args].concat());
+
+        cargo(&["add", "a.txt"]).unwrap();
+        cargo(&["update-index", "--add", "b.txt"]).unwrap();
+        cargo(&["add", "../top.txt"]).unwrap();
+        assert_eq!(git(&["ls-files"]), "sub/a.txt\nsub/b.txt\ntop.txt\n");
+        git(&["commit", "-m", "init"]);
+
+        std::fs::write(sub.join("a.txt"), "changed").unwrap();
+        cargo(&["checkout", "--", "a.txt"]).unwrap();
+        assert_eq!(std::fs::read_to_string(sub.join("a.txt")).unwrap(), "sub/a.txt");
+        assert!(!temp_path.join("a.txt").exists() && !PathBuf::from("a.txt").exists());
+        cargo(&["rm", "b.txt"]).unwrap();
+        assert!(!sub.join("b.txt").exists());
+        assert_eq!(git(&["status", "--porcelain"]), "D  sub/b.txt\n");
+
+        // a git dir away from its work tree, found through the environment
+        let work = tempdir().unwrap();
+        let work_path = work.path();
+        std::fs::write(work_path.join("f.txt"), "f").unwrap();
+        let binary = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git");
+        let run = |args: &[&str]| std::process::Command::new(&binary)
+            .current_dir(work_path)
+            .env("GIT_DIR", temp_path.join(".git"))
+            .env("GIT_WORK_TREE", work_path)
+            .args(args)
+            .status()
+            .unwrap()
+            .success();
+        assert!(run(&["add", "f.txt"]));
+        assert!(git(&["ls-files", "--stage", "f.txt"]).ends_with("\tf.txt\n"));
+        std::fs::write(work_path.join("f.txt"), "changed").unwrap();
+        assert!(run(&["checkout", "--", "f.txt"]));
+        assert_eq!(std::fs::read_to_string(work_path.join("f.txt")).unwrap(), "f");
+        assert!(!temp_path.join("f.txt").exists());
+        assert!(!run(&["add", "missing.txt"]));
+    }
+
+    #[test]
+    fn test_bare_has_no_work_tree() {
+        use crate::utils::test::{shell_spawn, tempdir};
+        let temp = tempdir().unwrap();
+        let temp_path = temp.path();
+        let bare = temp_path.join("repo.git");
+        shell_spawn(&["git", "init", "--bare", bare.to_str().unwrap()]).unwrap();
+        // the bare repository's parent is not its work tree, nothing there may be touched
+        std::fs::write(temp_path.join("precious.txt"), "keep me").unwrap();
+        let run = |args: &[&str]| std::process::Command::new("cargo")
+            .args([&["run", "--quiet", "--", "-C", bare.to_str().unwrap()][..], args].concat())
+            .output()
+            .unwrap();
+        for args in [&["clean", "-f"][..], &["clean", "-n"], &["status"], &["add", "."]] {
+            let ours = run(args);
+            let theirs = std::process::Command::new("git").arg("-C").arg(&bare).args(args).output().unwrap();
+            assert_eq!(ours.status.code(), Some(128), "{:?}", args);
+            assert_eq!(ours.status.code(), theirs.status.code(), "{:?}", args);
+            assert_eq!(String::from_utf8_lossy(&ours.stderr), "fatal: this operation must be run in a work tree\n");
+            assert_eq!(ours.stderr, theirs.stderr, "{:?}", args);
+            assert!(ours.stdout.is_empty(), "{:?}", args);
+        }
+        assert_eq!(std::fs::read_to_string(temp_path.join("precious.txt")).unwrap(), "keep me");
+        // commands which only need the git dir still run
+        let ours = run(&["symbolic-ref", "HEAD"]);
+        assert!(ours.status.success());
+        assert_eq!(String::from_utf8_lossy(&ours.stdout), shell_spawn(&["git", "-C", bare.to_str().unwrap(), "symbolic-ref", "HEAD"]).unwrap());
+    }
 }
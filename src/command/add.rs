@@ -2,9 +2,13 @@ use std::{
     path::{
         PathBuf,
         Path,
+        Component,
     },
+    collections::HashSet,
     result,
     iter::once,
+    fs,
+    io::{self, BufRead, Write},
 };
 use clap::{Parser, Subcommand};
 use itertools::Either;
@@ -17,20 +21,26 @@ use crate::{
         index::{
             Index,
             IndexEntry,
+            LockedIndexWriter,
         },
         fs::{
             walk,
             write_object,
+            read_object,
             read_file_as_bytes,
-            add_object,
+            add_objects,
             calc_relative_path,
         },
+        pool::default_threads,
         tree::FileMode,
         blob::Blob,
+        ignore::Ignore,
+        diff::{hunks, apply_hunks, is_binary, Hunk},
     },
 };
 
 use super::SubCommand;
+use crate::utils::fs::{Repository, work_tree};
 
 fn output(input: &str) -> result::Result<PathBuf, String> {
     //println!("input = {}", input);
@@ -43,7 +53,26 @@ pub struct Add {
     #[arg(short = 'n', long = "dry-run", help = "dry run", action = clap::ArgAction::SetTrue, required = false)]
     dry_run: bool,
 
-    #[arg(required = true, num_args = 1.., value_parser=output)]
+    #[arg(short = 'f', long = "force", help = "allow adding otherwise ignored files")]
+    force: bool,
+
+    #[arg(short = 'p', long = "patch", help = "choose the hunks of tracked files to stage, answering y/n for each")]
+    patch: bool,
+
+    #[arg(long = "hunk", num_args = 2, value_names = ["FILE", "N..M"], conflicts_with = "patch",
+        help = "stage only hunks N to M (counted from 1) of FILE, the rest stays in the worktree")]
+    hunk: Option<Vec<String>>,
+
+    #[arg(short = 'u', long = "update", conflicts_with_all = ["all", "patch", "hunk"],
+        help = "stage modifications and deletions of tracked files, untracked files are left alone")]
+    update: bool,
+
+    #[arg(short = 'A', long = "all", conflicts_with_all = ["patch", "hunk"],
+        help = "like -u, and also add untracked files which are not ignored")]
+    all: bool,
+
+    /// -u/-A work on the whole tree without paths
+    #[arg(required_unless_present_any = ["hunk", "update", "all"], num_args = 1.., value_parser=output)]
     paths: Vec<PathBuf>,
 }
 
@@ -52,46 +81,238 @@ impl Add {
         Ok(Box::new(Add::try_parse_from(args)?))
     }
 
-    fn walk_path(&self, project_root: PathBuf) -> Result<Vec<PathBuf>> {
-        self.paths.clone().into_iter()
-            .map(|p|project_root.join(p))
+    fn walk_path(&self, repo: &Repository) -> Result<Vec<PathBuf>> {
+        let project_root = repo.work_tree()?;
+        let roots = match self.paths.is_empty() {
+            true => vec![project_root.to_path_buf()],
+            false => self.paths.iter().map(|p| repo.resolve(p)).collect(),
+        };
+        roots.into_iter()
+            // a deleted path is staged by -A, there is nothing to walk
+            .filter(|root| !self.all || fs::symlink_metadata(root).is_ok())
             .map(walk)
             .collect::<Result<Vec<_>>>()?.into_iter()
             .flatten()
             .filter(|x| !x.starts_with(project_root.join(".git")))
-            .map(|p| calc_relative_path(&project_root, &p))
+            .map(|p| calc_relative_path(project_root, &p))
             .collect::<Result<Vec<_>>>()
     }
-}
 
-impl SubCommand for Add {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        let index_file = gitdir.join("index");
-        let project_root = gitdir.parent().expect("find git dir implementation fail");
+    /// drop ignored files unless they are tracked already
+    /// returns the explicitly named paths which are ignored
+    fn filter_ignored(&self, repo: &Repository, index: &Index, paths: &mut Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        if self.force {
+            return Ok(Vec::new());
+        }
+        let project_root = repo.work_tree()?;
+        let ignore = Ignore::load(project_root)?;
+        let explicit = self.paths.iter()
+            .filter_map(|p| calc_relative_path(project_root, repo.resolve(p)).ok())
+            .collect::<Vec<_>>();
+
+        let mut rejected = Vec::new();
+        paths.retain(|path| {
+            let tracked = index.entries.iter().any(|en| Path::new(&en.name) == path);
+            if tracked || !ignore.is_ignored(path, false) {
+                return true;
+            }
+            if explicit.contains(path) {
+                rejected.push(path.clone());
+            }
+            false
+        });
+        Ok(rejected)
+    }
 
-        let mut index = Index::new();
-        if index_file.exists() {
-            // ugly
-            index = index.read_from_file(&index_file)?;
+    /// the paths given as prefixes relative to the project root, an empty path for all of it
+    /// only looked at lexically, deleted files have to match as well
+    fn scope(&self, repo: &Repository) -> Vec<PathBuf> {
+        if self.paths.is_empty() {
+            return vec![PathBuf::new()];
         }
+        self.paths.iter().map(|p| {
+            let path = repo.resolve(p);
+            let mut normalized = PathBuf::new();
+            for component in repo.work_tree().ok().and_then(|top| path.strip_prefix(top).ok()).unwrap_or(&path).components() {
+                match component {
+                    Component::CurDir => {},
+                    Component::ParentDir => { normalized.pop(); },
+                    component => normalized.push(component),
+                }
+            }
+            normalized
+        }).collect()
+    }
 
-        //println!("index_file exists index = {:?}", index);
+    /// -u/-A: the tracked files under `scope`, the ones gone from the worktree are dropped from the index
+    /// right away, like `rm --cached`, the others are returned unless their stat data says they are unchanged
+    fn update_tracked(repo: &Repository, index: &mut Index, scope: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let project_root = repo.work_tree()?;
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        for entry in index.entries.iter().filter(|en| scope.iter().any(|dir| en.name.starts_with(dir))) {
+            let path = project_root.join(&entry.name);
+            if !fs::symlink_metadata(&path).is_ok_and(|meta| !meta.is_dir()) {
+                deleted.push(entry.name.clone());
+            }
+            // 冲突的路径总是重新加入，清掉 stage 1/2/3
+            else if entry.stage != 0 || !entry.is_stat_clean(&path) {
+                changed.push(entry.name.clone());
+            }
+        }
+        deleted.iter().for_each(|name| { index.remove_entry(name); });
+        changed.dedup();
+        Ok(changed)
+    }
 
-        let _ = self.walk_path(project_root.to_path_buf())?
+    /// `N` or `N..M`, both ends included, as 0-based indices into `count` hunks
+    fn parse_hunk_range(range: &str, count: usize) -> Result<std::ops::RangeInclusive<usize>> {
+        let invalid = || GitError::invalid_command(format!("invalid hunk range '{}', there are {} hunk(s)", range, count));
+        let (start, end) = range.split_once("..").unwrap_or((range, range));
+        let (start, end) = (start.parse::<usize>().map_err(|_| invalid())?, end.parse::<usize>().map_err(|_| invalid())?);
+        if start == 0 || start > end || end > count {
+            return Err(invalid());
+        }
+        Ok(start - 1..=end - 1)
+    }
+
+    /// stage the hunks between the index version of `path` and the worktree for which `select` says yes
+    /// only the index changes, the worktree file is left as it is
+    fn stage_hunks(gitdir: &Path, index: &mut Index, path: &Path, mut select: impl FnMut(usize, &[Hunk]) -> Result<bool>) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let name = path.display().to_string();
+        let Some(entry) = index.entries.iter_mut().find(|en| en.name == name && en.stage == 0) else {
+            return Err(GitError::invalid_command(format!("{} is not tracked, add the whole file first", name)));
+        };
+        let old: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), &entry.hash)?.into();
+        let new = fs::read(project_root.join(path))
+            .map_err(|_| GitError::failed_to_read_file(&name))?;
+        if is_binary(&old) || is_binary(&new) {
+            return Err(GitError::invalid_command(format!("{} is a binary file, it can only be added as a whole", name)));
+        }
+        let old = String::from_utf8(old).map_err(|_| GitError::invalid_command(format!("{} is not utf-8 text", name)))?;
+        let new = String::from_utf8(new).map_err(|_| GitError::invalid_command(format!("{} is not utf-8 text", name)))?;
+
+        let hunks = hunks(&old, &new);
+        let mut selected = Vec::new();
+        for i in 0..hunks.len() {
+            if select(i, &hunks)? {
+                selected.push(&hunks[i]);
+            }
+        }
+        if selected.is_empty() {
+            return Ok(());
+        }
+        let hash = write_object::<Blob>(gitdir.to_path_buf(), apply_hunks(&old, &selected).into_bytes())?;
+        // no stat data: the worktree file no longer matches the entry
+        *entry = IndexEntry::new(entry.mode, hash, name);
+        Ok(())
+    }
+
+    /// add -p: show every hunk and read the answer from stdin
+    fn stage_interactively(&self, repo: &Repository, index: &mut Index) -> Result<()> {
+        let gitdir = &repo.gitdir;
+        let paths = self.walk_path(repo)?
             .into_iter()
-            .map(|path| -> Result<()> {
-                let path_string = path.display().to_string();
-                if let Some(i) = index.entries.iter().position(|en|en.name == path_string) {
-                    index.entries[i] = add_object::<Blob>(gitdir.clone(), path.clone())?
+            .filter(|path| index.entries.iter().any(|en| Path::new(&en.name) == path))
+            .collect::<Vec<_>>();
+        let mut stdin = io::stdin().lock();
+        let mut quit = false;
+        for path in paths {
+            // 同一个文件里 a/d 决定剩下的所有 hunk
+            let mut rest = None;
+            let mut header_shown = false;
+            Self::stage_hunks(gitdir, index, &path, |i, hunks| {
+                if quit {
+                    return Ok(false);
                 }
-                else {
-                    index.add_entry(add_object::<Blob>(gitdir.clone(), path.clone())?);
+                if let Some(answer) = rest {
+                    return Ok(answer);
                 }
-                Ok(())
-            })
-            .collect::<Result<Vec<_>>>()?;
-        index.write_to_file(&index_file)?;
+                if !header_shown {
+                    println!("diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}", path.display());
+                    header_shown = true;
+                }
+                print!("{}({}/{}) Stage this hunk [y,n,q,a,d]? ", hunks[i].text, i + 1, hunks.len());
+                io::stdout().flush()?;
+                let mut answer = String::new();
+                if stdin.read_line(&mut answer)? == 0 {
+                    quit = true;
+                    return Ok(false);
+                }
+                match answer.trim() {
+                    "y" => Ok(true),
+                    "a" => { rest = Some(true); Ok(true) },
+                    "d" => { rest = Some(false); Ok(false) },
+                    "q" => { quit = true; Ok(false) },
+                    _ => Ok(false),
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl SubCommand for Add {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = repo.gitdir.clone();
+        let index_file = gitdir.join("index");
+        let project_root = repo.work_tree()?;
+        // 读之前就拿锁，并发的 add 不会互相覆盖
+        let lock = LockedIndexWriter::lock(&index_file)?;
+
+        let mut index = Index::read_or_default(&gitdir)?;
+
+        //println!("index_file exists index = {:?}", index);
+        if let Some([file, range]) = self.hunk.as_deref() {
+            let path = calc_relative_path(project_root, repo.resolve(file))?;
+            let mut count = 0;
+            // the number of hunks is only known once the diff is there
+            Self::stage_hunks(&gitdir, &mut index, &path, |i, hunks| {
+                count = hunks.len();
+                Ok(Self::parse_hunk_range(range, count)?.contains(&i))
+            })?;
+            if count == 0 {
+                return Err(GitError::invalid_command(format!("no changes to stage in {}", file)));
+            }
+            lock.commit(&index)?;
+            return Ok(0);
+        }
+        if self.patch {
+            self.stage_interactively(&repo, &mut index)?;
+            lock.commit(&index)?;
+            return Ok(0);
+        }
+
+        let (paths, rejected) = if self.update || self.all {
+            let mut paths = Self::update_tracked(&repo, &mut index, &self.scope(&repo))?;
+            let mut rejected = Vec::new();
+            if self.all {
+                let tracked = index.entries.iter().map(|en| en.name.clone()).collect::<HashSet<_>>();
+                let mut untracked = self.walk_path(&repo)?;
+                untracked.retain(|path| !tracked.contains(path));
+                rejected = self.filter_ignored(&repo, &index, &mut untracked)?;
+                paths.extend(untracked);
+            }
+            (paths, rejected)
+        }
+        else {
+            let mut paths = self.walk_path(&repo)?;
+            let rejected = self.filter_ignored(&repo, &index, &mut paths)?;
+            (paths, rejected)
+        };
+
+        // objects are written on all cpus, the index only once everything succeeded
+        index.add_entries(add_objects::<Blob>(&gitdir, &paths, default_threads())?);
+        lock.commit(&index)?;
+
+        if !rejected.is_empty() {
+            eprintln!("The following paths are ignored by one of your .gitignore files:");
+            rejected.iter().for_each(|p| eprintln!("{}", p.display()));
+            eprintln!("hint: Use -f if you really want to add them.");
+            return Ok(1);
+        }
         Ok(0)
     }
 }
@@ -260,4 +481,297 @@ mod test {
 
         assert_eq!(origin, real);
     }
+
+    #[test]
+    fn test_gitignore() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        let files = [
+            (".gitignore", "# build output\ntarget/\n*.log\n!keep.log\n"),
+            ("tracked.log", "old"),
+            ("a.txt", "a"),
+            ("keep.log", "keep"),
+            ("debug.log", "debug"),
+            ("target/debug/git", "bin"),
+            ("sub/.gitignore", "*.tmp\n!keep.log\n"),
+            ("sub/x.tmp", "x"),
+            ("sub/y.txt", "y"),
+        ];
+        for (name, content) in files {
+            let path = temp_path1.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        // tracked before it gets ignored
+        let _ = shell_spawn(&["git", "-C", temp_path_str1, "add", "-f", "tracked.log"]).unwrap();
+        std::fs::write(temp_path1.join("tracked.log"), "new").unwrap();
+
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["add", "-f", "debug.log"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        let _ = run_both(cmds, git, cargo).unwrap();
+
+        let origin = shell_spawn(&["git", "-C", temp_path_str1, "ls-files", "--stage"]).unwrap();
+        let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
+        assert_eq!(origin, real);
+
+        // explicitly named ignored files are refused without -f
+        let status = std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", temp_path_str2, "add", "sub/x.tmp"])
+            .output()
+            .unwrap()
+            .status;
+        assert_eq!(status.code(), Some(1));
+        let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
+        assert_eq!(origin, real);
+    }
+
+    #[test]
+    fn test_update_and_all() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        let files = [
+            (".gitignore", "*.log\n"),
+            ("a.txt", "a"),
+            ("b.txt", "b"),
+            ("sub/c.txt", "c"),
+            ("sub/d.txt", "d"),
+        ];
+        for (name, content) in files {
+            let path = temp_path1.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat()).unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "init"]);
+        // modify, delete and add files, in the top directory and in sub
+        std::fs::write(temp_path1.join("a.txt"), "a2").unwrap();
+        std::fs::remove_file(temp_path1.join("b.txt")).unwrap();
+        std::fs::write(temp_path1.join("new.txt"), "new").unwrap();
+        std::fs::write(temp_path1.join("sub/c.txt"), "c2").unwrap();
+        std::fs::remove_file(temp_path1.join("sub/d.txt")).unwrap();
+        std::fs::write(temp_path1.join("sub/e.txt"), "e").unwrap();
+        std::fs::write(temp_path1.join("sub/debug.log"), "ignored").unwrap();
+
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2][..], args].concat()).unwrap();
+        let staged = || (
+            shell_spawn(&["git", "-C", temp_path_str1, "ls-files", "--stage"]).unwrap(),
+            shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap(),
+        );
+        for args in [&["add", "-u", "sub"][..], &["add", "--update", "b.txt"], &["add", "-u"], &["add", "-A", "sub"], &["add", "--all"]] {
+            git(args);
+            cargo(args);
+            let (origin, real) = staged();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        let (origin, _) = staged();
+        assert!(!origin.contains("b.txt") && !origin.contains("sub/d.txt") && !origin.contains("debug.log"));
+        assert!(origin.contains("new.txt") && origin.contains("sub/e.txt"));
+
+        git(&["commit", "-m", "changes"]);
+        cargo(&["commit", "-m", "changes"]);
+        assert_eq!(git(&["rev-parse", "HEAD^{tree}"]), shell_spawn(&["git", "-C", temp_path_str2, "rev-parse", "HEAD^{tree}"]).unwrap());
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str2, "status", "--porcelain"]).unwrap(), "");
+    }
+
+    #[test]
+    fn test_index_stat() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        let _ = touch_file_in(temp_path.join("inner")).unwrap().keep().unwrap();
+        std::fs::write(temp_path.join("a.txt"), "hello").unwrap();
+        let _ = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "add", "."]).unwrap();
+
+        // git trusts the stat data, a zeroed stat would make every file look modified
+        let dirty = shell_spawn(&["git", "-C", temp_path_str, "diff-files", "--name-only"]).unwrap();
+        assert_eq!(dirty, "");
+
+        // refreshing with real git must not change anything
+        let ours = shell_spawn(&["git", "-C", temp_path_str, "ls-files", "--debug"]).unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "add", "."]).unwrap();
+        let theirs = shell_spawn(&["git", "-C", temp_path_str, "ls-files", "--debug"]).unwrap();
+        assert_eq!(ours, theirs);
+        assert!(ours.contains("size: 5"));
+    }
+
+    #[test]
+    fn test_add_hunks() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let lines = (1..=30).map(|i| format!("line {}\n", i)).collect::<Vec<_>>();
+        std::fs::write(temp_path1.join("f.txt"), lines.concat()).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "add", "f.txt"]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "commit", "-m", "init"]).unwrap();
+        // two changes far enough apart for two hunks, the second one at the end without a newline
+        let mut changed = lines.clone();
+        changed[1] = "changed 2\n".to_string();
+        changed[29] = "changed 30".to_string();
+        std::fs::write(temp_path1.join("f.txt"), changed.concat()).unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+        let temp3 = tempdir().unwrap();
+        let temp_path3 = temp3.path();
+        let temp_path_str3 = temp_path3.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path3).unwrap();
+
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "add", "--hunk", "f.txt", "1"]).unwrap();
+        let cached = shell_spawn(&["git", "-C", temp_path_str2, "diff", "--cached"]).unwrap();
+        let unstaged = shell_spawn(&["git", "-C", temp_path_str2, "diff"]).unwrap();
+        assert!(cached.contains("+changed 2") && !cached.contains("changed 30"), "{}", cached);
+        assert!(unstaged.contains("+changed 30") && !unstaged.contains("changed 2"), "{}", unstaged);
+        assert_eq!(std::fs::read_to_string(temp_path2.join("f.txt")).unwrap(), changed.concat());
+
+        // the same answers given to git add -p and ours stage the same blob
+        let add_patch = |cmd: &[&str], answers: &str| {
+            let mut child = std::process::Command::new(cmd[0])
+                .args(&cmd[1..])
+                .args(["add", "-p", "f.txt"])
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(answers.as_bytes()).unwrap();
+            assert!(child.wait().unwrap().success());
+        };
+        add_patch(&["git", "-C", temp_path_str1], "n\ny\n");
+        add_patch(&["cargo", "run", "--quiet", "--", "-C", temp_path_str3], "n\ny\n");
+        let stage = |path: &str| shell_spawn(&["git", "-C", path, "ls-files", "--stage"]).unwrap();
+        assert_eq!(stage(temp_path_str1), stage(temp_path_str3));
+        assert!(shell_spawn(&["git", "-C", temp_path_str3, "diff"]).unwrap().contains("+changed 2"));
+
+        // the remaining hunk is now the only one, out of range numbers are refused
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "add", "--hunk", "f.txt"];
+        assert!(shell_spawn(&[&cargo[..], &["2"]].concat()).is_err());
+        assert!(shell_spawn(&[&cargo[..], &["0..1"]].concat()).is_err());
+        shell_spawn(&[&cargo[..], &["1..1"]].concat()).unwrap();
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str2, "diff"]).unwrap(), "");
+        assert!(shell_spawn(&[&cargo[..], &["1"]].concat()).is_err());
+    }
+
+    /// peak resident memory of this process since the last reset, in bytes
+    fn reset_and_read_peak_rss() -> impl Fn() -> u64 {
+        std::fs::write("/proc/self/clear_refs", "5").unwrap();
+        || {
+            let status = std::fs::read_to_string("/proc/self/status").unwrap();
+            let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+            line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() * 1024
+        }
+    }
+
+    #[test]
+    fn test_parallel_add() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let gitdir = temp_path.join(".git");
+        let paths = (0..5000)
+            .map(|i| {
+                let dir = temp_path.join(format!("d{}", i % 50));
+                std::fs::create_dir_all(&dir).unwrap();
+                let path = dir.join(format!("{}.txt", i));
+                std::fs::write(&path, format!("file {}\n", i).repeat(i % 7 + 1)).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+
+        // 顺序和并行各写一遍，得到的 index 必须逐字节相同
+        let mut indexes = vec![];
+        for threads in [1, 4] {
+            let start = std::time::Instant::now();
+            let entries = add_objects::<Blob>(&gitdir, &paths, threads).unwrap();
+            println!("{} thread(s): {:?}", threads, start.elapsed());
+            let mut index = Index::new();
+            index.add_entries(entries);
+            let path = temp_path.join(format!("index.{}", threads));
+            index.write_to_file(&path).unwrap();
+            indexes.push(std::fs::read(&path).unwrap());
+            std::fs::remove_file(&path).unwrap();
+        }
+        assert_eq!(indexes[0], indexes[1]);
+
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "add", "."]).unwrap();
+        let ours = shell_spawn(&["git", "-C", temp_path_str, "ls-files", "-s"]).unwrap();
+        assert_eq!(ours.lines().count(), 5000);
+        shell_spawn(&["git", "-C", temp_path_str, "add", "."]).unwrap();
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str, "ls-files", "-s"]).unwrap(), ours);
+        assert!(shell_spawn(&["git", "-C", temp_path_str, "fsck", "--full"]).is_ok());
+
+        // 有一个文件读不了，整个 add 失败，index 不动
+        let index_before = std::fs::read(gitdir.join("index")).unwrap();
+        std::fs::write(&paths[0], "changed\n").unwrap();
+        std::fs::set_permissions(&paths[2500], std::os::unix::fs::PermissionsExt::from_mode(0o000)).unwrap();
+        if std::fs::read(&paths[2500]).is_err() {
+            assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "add", "."]).is_err());
+            assert_eq!(std::fs::read(gitdir.join("index")).unwrap(), index_before);
+        }
+    }
+
+    #[test]
+    #[ignore = "writes a 300 MB file, run with --ignored"]
+    fn test_large_file_streaming() {
+        use crate::utils::fs::{add_object, write_blob_to_file};
+        use std::io::Write;
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let gitdir = temp_path.join(".git");
+        let big = temp_path.join("big.bin");
+
+        // random bytes do not compress, the object is as large as the file
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&big).unwrap());
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut chunk = vec![0u8; 1 << 20];
+        for _ in 0..300 {
+            for byte in chunk.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *byte = state as u8;
+            }
+            file.write_all(&chunk).unwrap();
+        }
+        drop(file);
+        drop(chunk);
+        let limit = 64 << 20;
+
+        let peak = reset_and_read_peak_rss();
+        let entry = add_object::<Blob>(gitdir.clone(), "big.bin").unwrap();
+        assert!(peak() < limit, "peak rss {} while adding", peak());
+        let expected = shell_spawn(&["git", "-C", temp_path_str, "hash-object", "big.bin"]).unwrap();
+        assert_eq!(entry.hash, expected.trim());
+        assert!(shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-e", &entry.hash.to_hex()]).is_ok());
+
+        std::fs::remove_file(&big).unwrap();
+        let peak = reset_and_read_peak_rss();
+        write_blob_to_file(&gitdir, &entry.hash, &big).unwrap();
+        assert!(peak() < limit, "peak rss {} while checking out", peak());
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str, "hash-object", "big.bin"]).unwrap(), expected);
+    }
 }
@@ -0,0 +1,257 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        blob::Blob,
+        commit::Commit,
+        fs::read_object,
+        hash::ObjectId,
+        ident::split_ident,
+        revparse::{resolve_commit, resolve_tree},
+        tar::{EntryKind, TarWriter},
+        tree::{FileMode, Tree},
+        zip::ZipWriter,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "archive", about = "Create an archive of files from a named tree")]
+pub struct Archive {
+    #[arg(long, value_parser = ["tar", "zip"], help = "format of the archive, from the -o file name or tar by default")]
+    format: Option<String>,
+
+    #[arg(long, value_name = "prefix/", default_value = "", help = "prepend <prefix>/ to every path in the archive")]
+    prefix: String,
+
+    #[arg(short = 'o', long = "output", value_name = "file", help = "write the archive to <file> instead of stdout")]
+    output: Option<PathBuf>,
+
+    #[arg(help = "the commit or tree to archive")]
+    tree_ish: String,
+}
+
+/// where the tree walk puts its entries, the same for every format
+trait ArchiveWriter {
+    fn append(&mut self, path: &[u8], kind: EntryKind, mode: u32, mtime: i64, data: &[u8]) -> Result<()>;
+}
+
+impl<W: Write> ArchiveWriter for TarWriter<W> {
+    fn append(&mut self, path: &[u8], kind: EntryKind, mode: u32, mtime: i64, data: &[u8]) -> Result<()> {
+        TarWriter::append(self, path, kind, mode, mtime, data)
+    }
+}
+
+impl<W: Write> ArchiveWriter for ZipWriter<W> {
+    fn append(&mut self, path: &[u8], kind: EntryKind, mode: u32, mtime: i64, data: &[u8]) -> Result<()> {
+        ZipWriter::append(self, path, kind, mode, mtime, data)
+    }
+}
+
+impl Archive {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Archive::try_parse_from(args)?))
+    }
+
+    /// every entry below `tree_hash` in tree order, a directory before its contents
+    /// paths and contents come from the objects only, never from the worktree
+    fn write_tree(gitdir: &Path, tar: &mut impl ArchiveWriter, tree_hash: &ObjectId, dir: &[u8], mtime: i64) -> Result<()> {
+        let tree = read_object::<Tree>(gitdir.to_path_buf(), tree_hash)?;
+        for entry in tree.0 {
+            let mut path = dir.to_vec();
+            path.extend_from_slice(entry.path.as_os_str().as_bytes());
+            match entry.mode {
+                FileMode::Tree => {
+                    path.push(b'/');
+                    tar.append(&path, EntryKind::Directory, 0o755, mtime, b"")?;
+                    Self::write_tree(gitdir, tar, &entry.hash, &path, mtime)?;
+                },
+                // a submodule's commit is not in this repository, git leaves an empty directory
+                FileMode::Commit => {
+                    path.push(b'/');
+                    tar.append(&path, EntryKind::Directory, 0o755, mtime, b"")?;
+                },
+                FileMode::Symbolic => {
+                    let target: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), &entry.hash)?.into();
+                    tar.append(&path, EntryKind::Symlink, 0o777, mtime, &target)?;
+                },
+                FileMode::Blob | FileMode::Exec => {
+                    let content: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), &entry.hash)?.into();
+                    let mode = if entry.mode == FileMode::Exec { 0o755 } else { 0o644 };
+                    tar.append(&path, EntryKind::File, mode, mtime, &content)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// --format wins, otherwise git guesses it from the output file's extension
+    fn format(&self) -> &str {
+        match (&self.format, &self.output) {
+            (Some(format), _) => format,
+            (None, Some(path)) if path.extension().is_some_and(|ext| ext == "zip") => "zip",
+            _ => "tar",
+        }
+    }
+
+    /// the prefix directory first when there is one, then the tree below it
+    fn write_entries(&self, gitdir: &Path, archive: &mut impl ArchiveWriter, tree_hash: &ObjectId, mtime: i64) -> Result<()> {
+        if self.prefix.ends_with('/') {
+            archive.append(self.prefix.as_bytes(), EntryKind::Directory, 0o755, mtime, b"")?;
+        }
+        Self::write_tree(gitdir, archive, tree_hash, self.prefix.as_bytes(), mtime)
+    }
+}
+
+impl SubCommand for Archive {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let tree_hash = resolve_tree(gitdir, &self.tree_ish)
+            .map_err(|_| GitError::invalid_command(format!("not a tree object: {}", self.tree_ish)))?;
+        // like git: a commit names itself in the archive and dates the files, a bare tree is dated now
+        let commit = resolve_commit(gitdir, &self.tree_ish).ok();
+        let mtime = match &commit {
+            Some(hash) => {
+                let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)?;
+                split_ident(&commit.committer).map(|(_, time, _)| time).unwrap_or_default()
+            },
+            None => chrono::Utc::now().timestamp(),
+        };
+
+        let out: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(File::create(repo.resolve(path))
+                .map_err(|_| GitError::failed_to_write_file(&path.to_string_lossy()))?),
+            None => Box::new(io::stdout().lock()),
+        };
+        let out = BufWriter::new(out);
+        if self.format() == "zip" {
+            let mut zip = ZipWriter::new(out);
+            self.write_entries(gitdir, &mut zip, &tree_hash, mtime)?;
+            zip.finish(&commit.map(|hash| hash.to_hex()).unwrap_or_default())?;
+            return Ok(0);
+        }
+        let mut tar = TarWriter::new(out);
+        if let Some(hash) = &commit {
+            tar.global_comment(&hash.to_hex())?;
+        }
+        self.write_entries(gitdir, &mut tar, &tree_hash, mtime)?;
+        tar.finish()?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+    };
+
+    #[test]
+    fn test_archive() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        std::fs::create_dir_all(temp_path.join("src/nested")).unwrap();
+        std::fs::write(temp_path.join("README"), "readme\n").unwrap();
+        std::fs::write(temp_path.join("repeated.txt"), "compress me\n".repeat(100)).unwrap();
+        std::fs::write(temp_path.join("src/nested/lib.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(temp_path.join("run.sh"), "#!/bin/sh\necho hi\n").unwrap();
+        std::fs::set_permissions(temp_path.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("src/nested/lib.rs", temp_path.join("link")).unwrap();
+        // longer than the 100 bytes of a ustar name
+        let deep = (0..6).map(|i| format!("directory-with-a-long-name-{}", i)).collect::<Vec<_>>().join("/");
+        std::fs::create_dir_all(temp_path.join(&deep)).unwrap();
+        std::fs::write(temp_path.join(&deep).join("f".repeat(120)), "deep\n").unwrap();
+        git(&["add", "."]).unwrap();
+        git(&["commit", "-m", "archive me"]).unwrap();
+        // only objects go into the archive, the worktree is ignored
+        std::fs::write(temp_path.join("README"), "changed\n").unwrap();
+        std::fs::write(temp_path.join("untracked"), "untracked\n").unwrap();
+
+        let out = tempdir().unwrap();
+        let ours = out.path().join("ours.tar");
+        let theirs = out.path().join("theirs.tar");
+        cargo(&["archive", "--prefix=project/", "HEAD", "-o", ours.to_str().unwrap()]).unwrap();
+        git(&["archive", "--prefix=project/", "HEAD", "-o", theirs.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::metadata(&ours).unwrap().len() % 10240, 0);
+
+        let (ours_dir, theirs_dir) = (out.path().join("ours"), out.path().join("theirs"));
+        for (tar, dir) in [(&ours, &ours_dir), (&theirs, &theirs_dir)] {
+            std::fs::create_dir(dir).unwrap();
+            shell_spawn(&["tar", "xf", tar.to_str().unwrap(), "-C", dir.to_str().unwrap()]).unwrap();
+        }
+        shell_spawn(&["diff", "-r", "--no-dereference", ours_dir.to_str().unwrap(), theirs_dir.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read_to_string(ours_dir.join("project/README")).unwrap(), "readme\n");
+        assert!(!ours_dir.join("project/untracked").exists());
+        assert!(std::fs::symlink_metadata(ours_dir.join("project/link")).unwrap().is_symlink());
+        assert_eq!(std::fs::metadata(ours_dir.join("project/run.sh")).unwrap().permissions().mode() & 0o111, 0o111);
+        let list = |tar: &std::path::Path| shell_spawn(&["tar", "tf", tar.to_str().unwrap()]).unwrap();
+        assert_eq!(list(&ours), list(&theirs));
+
+        // the commit is recorded like git does, a tree has none; stdout without -o
+        let commit_id = shell_spawn(&["sh", "-c", &format!("git get-tar-commit-id < {}", ours.display())]).unwrap();
+        assert_eq!(commit_id, git(&["rev-parse", "HEAD"]).unwrap());
+        let tree = git(&["rev-parse", "HEAD^{tree}"]).unwrap();
+        let tree_tar = shell_spawn(&["sh", "-c", &format!("cargo run --quiet -- -C {} archive {} | tar t", temp_path_str, tree.trim())]).unwrap();
+        assert_eq!(tree_tar, shell_spawn(&["sh", "-c", &format!("git -C {} archive {} | tar t", temp_path_str, tree.trim())]).unwrap());
+        assert!(cargo(&["archive", "--format=7z", "HEAD"]).is_err());
+
+        // zip is written byte for byte like git's, the commit in the archive comment
+        let ours_zip = out.path().join("ours.zip");
+        let theirs_zip = out.path().join("theirs.zip");
+        cargo(&["archive", "--format=zip", "--prefix=project/", "HEAD", "-o", ours_zip.to_str().unwrap()]).unwrap();
+        git(&["archive", "--format=zip", "--prefix=project/", "HEAD", "-o", theirs_zip.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read(&ours_zip).unwrap(), std::fs::read(&theirs_zip).unwrap());
+        let unzipped = out.path().join("unzipped");
+        shell_spawn(&["unzip", "-q", ours_zip.to_str().unwrap(), "-d", unzipped.to_str().unwrap()]).unwrap();
+        shell_spawn(&["diff", "-r", "--no-dereference", unzipped.to_str().unwrap(), theirs_dir.to_str().unwrap()]).unwrap();
+        assert_eq!(shell_spawn(&["unzip", "-z", ours_zip.to_str().unwrap()]).unwrap().lines().last().unwrap(), commit_id.trim());
+        // the format follows the file name, a tree has no comment
+        let (named, tree_zip) = (out.path().join("named.zip"), out.path().join("tree.zip"));
+        cargo(&["archive", "HEAD", "-o", named.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read(&named).unwrap()[..4], *b"PK\x03\x04");
+        cargo(&["archive", "--format=zip", tree.trim(), "-o", tree_zip.to_str().unwrap()]).unwrap();
+        assert!(std::fs::read(&tree_zip).unwrap().ends_with(&[0, 0]));
+    }
+
+    #[test]
+    fn test_archive_zip64() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        // one entry more than the 16 bit count of the plain end record holds
+        let blob = git(&["hash-object", "-w", "/dev/null"]).unwrap();
+        let listing = temp.path().join("listing");
+        let lines = (0..=u16::MAX as u32).map(|i| format!("100644 blob {}\tf{}\n", blob.trim(), i)).collect::<String>();
+        std::fs::write(&listing, lines).unwrap();
+        let tree = std::process::Command::new("git").args(["-C", temp_path_str, "mktree"])
+            .stdin(std::fs::File::open(&listing).unwrap()).output().unwrap();
+        let tree = String::from_utf8(tree.stdout).unwrap();
+        let commit = git(&["commit-tree", tree.trim(), "-m", "many"]).unwrap();
+
+        let out = tempdir().unwrap();
+        let (ours, theirs) = (out.path().join("ours.zip"), out.path().join("theirs.zip"));
+        cargo(&["archive", "--format=zip", commit.trim(), "-o", ours.to_str().unwrap()]).unwrap();
+        git(&["archive", "--format=zip", commit.trim(), "-o", theirs.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read(&ours).unwrap(), std::fs::read(&theirs).unwrap());
+        assert!(shell_spawn(&["unzip", "-tq", ours.to_str().unwrap()]).unwrap().contains("No errors detected"));
+    }
+}
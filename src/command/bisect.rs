@@ -0,0 +1,377 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::Path,
+};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    GitError,
+    Result,
+    command::{Checkout, Show},
+    utils::{
+        commit::Commit,
+        fs::read_object,
+        hash::ObjectId,
+        refs::{
+            current_branch,
+            for_each_ref,
+            head_to_hash,
+            ref_exists,
+            read_ref_commit,
+            write_head_commit_with_log,
+            write_head_ref_with_log,
+            write_ref_commit,
+        },
+        revparse::resolve_commit,
+        revwalk,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+const BAD_REF: &str = "refs/bisect/bad";
+const GOOD_PREFIX: &str = "refs/bisect/good-";
+/// the files of a bisection in progress, all next to HEAD like git keeps them
+const STATE_FILES: [&str; 5] = ["BISECT_START", "BISECT_TERMS", "BISECT_NAMES", "BISECT_LOG", "BISECT_EXPECTED_REV"];
+
+#[derive(Parser, Debug)]
+#[command(name = "bisect", about = "Use binary search to find the commit that introduced a bug")]
+pub struct Bisect {
+    #[command(subcommand)]
+    command: BisectCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum BisectCommand {
+    /// start a bisection, optionally with the bad commit and the good ones
+    Start {
+        bad: Option<String>,
+        good: Vec<String>,
+    },
+    /// mark a commit as having the bug, HEAD by default
+    Bad {
+        rev: Option<String>,
+    },
+    /// mark commits as not having the bug, HEAD by default
+    Good {
+        revs: Vec<String>,
+    },
+    /// end the bisection and go back to where it started, or to `commit`
+    Reset {
+        commit: Option<String>,
+    },
+}
+
+impl Bisect {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Bisect::try_parse_from(args)?))
+    }
+
+    fn is_bisecting(gitdir: &Path) -> bool {
+        gitdir.join("BISECT_START").is_file()
+    }
+
+    fn log(gitdir: &Path, line: &str) -> Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(gitdir.join("BISECT_LOG"))?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    fn subject(gitdir: &Path, hash: &ObjectId) -> Result<String> {
+        let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)?;
+        Ok(commit.message.lines().next().unwrap_or_default().to_string())
+    }
+
+    /// record `rev` as good or bad, in the refs and in BISECT_LOG
+    fn mark(gitdir: &Path, term: &str, rev: &str, logged: bool) -> Result<()> {
+        let hash = resolve_commit(gitdir, rev)?;
+        match term {
+            "bad" => write_ref_commit(gitdir, BAD_REF, &hash)?,
+            _ => write_ref_commit(gitdir, &format!("{}{}", GOOD_PREFIX, hash), &hash)?,
+        }
+        Self::log(gitdir, &format!("# {}: [{}] {}", term, hash, Self::subject(gitdir, &hash)?))?;
+        // start records the commits it was given as one command of its own
+        if logged {
+            Self::log(gitdir, &format!("git bisect {} {}", term, hash))?;
+        }
+        Ok(())
+    }
+
+    fn goods(gitdir: &Path) -> Result<Vec<ObjectId>> {
+        Ok(for_each_ref(gitdir, GOOD_PREFIX)?.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// git's estimate of how many more answers it needs for `all` candidates
+    fn estimate_steps(all: usize) -> usize {
+        if all < 3 {
+            return 0;
+        }
+        let n = all.ilog2() as usize;
+        let e = 1 << n;
+        if e < 3 * (all - e) { n } else { n - 1 }
+    }
+
+    /// the candidate splitting the suspects most evenly, with how many of them it reaches itself
+    /// candidates come oldest first; like git, a halfway merge wins, then a halfway commit, then the best split
+    fn midpoint(candidates: &[ObjectId], cache: &HashMap<ObjectId, Commit>) -> (ObjectId, usize) {
+        let set = candidates.iter().collect::<HashSet<_>>();
+        let interesting = |hash: &ObjectId| cache[hash].parent_hash.iter().filter(|parent| set.contains(parent)).count();
+        let weight = |hash: &ObjectId| {
+            let mut seen = HashSet::from([hash]);
+            let mut stack = vec![hash];
+            while let Some(hash) = stack.pop() {
+                for parent in cache[hash].parent_hash.iter().filter(|parent| set.contains(parent)) {
+                    if seen.insert(parent) {
+                        stack.push(parent);
+                    }
+                }
+            }
+            seen.len()
+        };
+        let all = candidates.len();
+        let weights = candidates.iter().map(|hash| (hash, interesting(hash), weight(hash))).collect::<Vec<_>>();
+        let halfway = |weight: usize| (2 * weight).abs_diff(all) <= 1;
+        let found = weights.iter().find(|(_, parents, weight)| *parents > 1 && halfway(*weight))
+            .or_else(|| weights.iter().find(|(_, parents, weight)| *parents == 1 && halfway(*weight)));
+        if let Some((hash, _, weight)) = found {
+            return (**hash, *weight);
+        }
+        let mut best = (candidates[0], 0, 0);
+        for (hash, _, weight) in &weights {
+            let distance = (*weight).min(all - weight);
+            if distance > best.2 {
+                best = (**hash, *weight, distance);
+            }
+        }
+        (best.0, best.1)
+    }
+
+    /// what to do once something was marked: wait for more, check out the next commit or name the culprit
+    fn next(gitdir: &Path) -> Result<i32> {
+        let goods = Self::goods(gitdir)?;
+        let bad = ref_exists(gitdir, BAD_REF).then(|| read_ref_commit(gitdir, BAD_REF)).transpose()?;
+        let status = match (&bad, goods.len()) {
+            (None, 0) => Some("waiting for both good and bad commits".to_string()),
+            (Some(_), 0) => Some("waiting for good commit(s), bad commit known".to_string()),
+            (None, 1) => Some("waiting for bad commit, 1 good commit known".to_string()),
+            (None, n) => Some(format!("waiting for bad commit, {} good commits known", n)),
+            _ => None,
+        };
+        let Some(bad) = bad.filter(|_| status.is_none()) else {
+            let status = status.unwrap_or_default();
+            println!("status: {}", status);
+            Self::log(gitdir, &format!("# status: {}", status))?;
+            return Ok(0);
+        };
+
+        // the suspects: what the bad commit reaches and no good one does
+        let mut cache = HashMap::new();
+        let cleared = revwalk::reachable(gitdir, goods, &mut cache)?.into_iter().collect::<HashSet<_>>();
+        let candidates = revwalk::date_order(gitdir, vec![bad], &mut cache)?
+            .into_iter()
+            .rev()
+            .filter(|hash| !cleared.contains(hash))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            eprintln!("{} was both good and bad", bad);
+            return Ok(1);
+        }
+        let (next, reaches) = Self::midpoint(&candidates, &cache);
+        if next == bad {
+            let commit = read_object::<Commit>(gitdir.to_path_buf(), &bad)?;
+            println!("{} is the first bad commit", bad);
+            print!("{}", Show::commit_header(&bad, &commit));
+            Self::log(gitdir, &format!("# first bad commit: [{}] {}", bad, Self::subject(gitdir, &bad)?))?;
+            return Ok(0);
+        }
+
+        let left = candidates.len() - reaches - 1;
+        let steps = Self::estimate_steps(candidates.len());
+        println!("Bisecting: {} revision{} left to test after this (roughly {} step{})",
+            left, if left == 1 { "" } else { "s" }, steps, if steps == 1 { "" } else { "s" });
+        fs::write(gitdir.join("BISECT_EXPECTED_REV"), format!("{}\n", next))?;
+        let current = head_to_hash(gitdir).ok();
+        let from = current_branch(gitdir).ok().or(current.map(|hash| hash.to_hex())).unwrap_or_default();
+        Checkout::switch_worktree(gitdir, current.as_ref(), &next)?;
+        write_head_commit_with_log(gitdir, &next, &format!("checkout: moving from {} to {}", from, next))?;
+        println!("[{}] {}", next, Self::subject(gitdir, &next)?);
+        Ok(0)
+    }
+
+    fn start(gitdir: &Path, bad: Option<&str>, good: &[String]) -> Result<i32> {
+        // starting over keeps the place the first start was made from
+        let start = match fs::read_to_string(gitdir.join("BISECT_START")) {
+            Ok(start) => start.trim().to_string(),
+            Err(_) => current_branch(gitdir).or_else(|_| head_to_hash(gitdir).map(|hash| hash.to_hex()))?,
+        };
+        Self::clean(gitdir)?;
+        fs::write(gitdir.join("BISECT_START"), format!("{}\n", start))?;
+        fs::write(gitdir.join("BISECT_TERMS"), "bad\ngood\n")?;
+        fs::write(gitdir.join("BISECT_NAMES"), "\n")?;
+        fs::write(gitdir.join("BISECT_LOG"), "")?;
+        if let Some(bad) = bad {
+            Self::mark(gitdir, "bad", bad, false)?;
+        }
+        for good in good {
+            Self::mark(gitdir, "good", good, false)?;
+        }
+        let args = bad.into_iter().chain(good.iter().map(String::as_str))
+            .map(|arg| format!(" '{}'", arg))
+            .collect::<String>();
+        Self::log(gitdir, &format!("git bisect start{}", args))?;
+        Self::next(gitdir)
+    }
+
+    /// forget the bisection: its refs and its files
+    fn clean(gitdir: &Path) -> Result<()> {
+        let refs = gitdir.join("refs").join("bisect");
+        if refs.exists() {
+            fs::remove_dir_all(&refs).map_err(|_| GitError::failed_to_remove_file(refs.display().to_string()))?;
+        }
+        for name in STATE_FILES {
+            let _ = fs::remove_file(gitdir.join(name));
+        }
+        Ok(())
+    }
+
+    fn reset(gitdir: &Path, commit: Option<&str>) -> Result<i32> {
+        if !Self::is_bisecting(gitdir) {
+            println!("We are not bisecting.");
+            return Ok(0);
+        }
+        let start = fs::read_to_string(gitdir.join("BISECT_START"))?.trim().to_string();
+        let target = commit.unwrap_or(&start);
+        let current = head_to_hash(gitdir).ok();
+        let from = current_branch(gitdir).ok().or(current.map(|hash| hash.to_hex())).unwrap_or_default();
+        let message = format!("checkout: moving from {} to {}", from, target);
+        let branch = format!("refs/heads/{}", target);
+        if ref_exists(gitdir, &branch) {
+            Checkout::switch_worktree(gitdir, current.as_ref(), &read_ref_commit(gitdir, &branch)?)?;
+            write_head_ref_with_log(gitdir, &branch, &message)?;
+            eprintln!("Switched to branch '{}'", target);
+        } else {
+            let hash = resolve_commit(gitdir, target)
+                .map_err(|_| GitError::invalid_command(format!("'{}' is not a valid commit", target)))?;
+            Checkout::switch_worktree(gitdir, current.as_ref(), &hash)?;
+            write_head_commit_with_log(gitdir, &hash, &message)?;
+            eprintln!("HEAD is now at {} {}", &hash.to_hex()[..7], Self::subject(gitdir, &hash)?);
+        }
+        Self::clean(gitdir)?;
+        Ok(0)
+    }
+}
+
+impl SubCommand for Bisect {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let started = Self::is_bisecting(&gitdir);
+        match &self.command {
+            BisectCommand::Start { bad, good } => Self::start(&gitdir, bad.as_deref(), good),
+            BisectCommand::Reset { commit } => Self::reset(&gitdir, commit.as_deref()),
+            _ if !started => {
+                eprintln!("You need to start by \"git bisect start\"");
+                Ok(1)
+            },
+            BisectCommand::Bad { rev } => {
+                Self::mark(&gitdir, "bad", rev.as_deref().unwrap_or("HEAD"), true)?;
+                Self::next(&gitdir)
+            },
+            BisectCommand::Good { revs } => {
+                let head = ["HEAD".to_string()];
+                for rev in if revs.is_empty() { &head[..] } else { revs } {
+                    Self::mark(&gitdir, "good", rev, true)?;
+                }
+                Self::next(&gitdir)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{shell_spawn, setup_test_git_dir};
+
+    #[test]
+    fn test_bisect() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+        // one commit a minute, so both walks see the same order
+        let mut time = 1600000000;
+        let mut commit = |args: &[&str]| {
+            time += 60;
+            let date = format!("{} +0000", time);
+            let status = Command::new("git").arg("-C").arg(temp_path_str).args(args)
+                .env("GIT_AUTHOR_DATE", &date)
+                .env("GIT_COMMITTER_DATE", &date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", args);
+        };
+        for i in 1..=6 {
+            std::fs::write(temp_path.join("f"), format!("{}\n", i)).unwrap();
+            git(&["add", "f"]).unwrap();
+            commit(&["commit", "-q", "-m", &format!("c{}", i)]);
+        }
+        git(&["checkout", "-q", "-b", "side", "HEAD~3"]).unwrap();
+        for i in 1..=3 {
+            std::fs::write(temp_path.join("g"), format!("{}\n", i)).unwrap();
+            git(&["add", "g"]).unwrap();
+            commit(&["commit", "-q", "-m", &format!("side {}", i)]);
+        }
+        git(&["checkout", "-q", "master"]).unwrap();
+        commit(&["merge", "-q", "--no-edit", "side"]);
+        for i in 7..=9 {
+            std::fs::write(temp_path.join("f"), format!("{}\n", i)).unwrap();
+            git(&["add", "f"]).unwrap();
+            commit(&["commit", "-q", "-m", &format!("c{}", i)]);
+        }
+
+        assert!(cargo(&["bisect", "good"]).is_err());
+        assert_eq!(cargo(&["bisect", "reset"]).unwrap(), "We are not bisecting.\n");
+
+        // the same answers lead both through the same commits to the same culprit
+        let culprit = git(&["rev-parse", "side~1"]).unwrap();
+        let culprit = culprit.trim();
+        let session = |run: &dyn Fn(&[&str]) -> Result<String, String>| {
+            let mut outputs = vec![run(&["bisect", "start"]).unwrap(), run(&["bisect", "bad"]).unwrap()];
+            outputs.push(run(&["bisect", "good", "HEAD~8"]).unwrap());
+            for _ in 0..10 {
+                let head = git(&["rev-parse", "HEAD"]).unwrap();
+                let answer = match git(&["merge-base", "--is-ancestor", culprit, head.trim()]) {
+                    Ok(_) => "bad",
+                    Err(_) => "good",
+                };
+                let out = run(&["bisect", answer]).unwrap();
+                let done = out.contains("is the first bad commit");
+                // no diffstat here, the commit itself is the same
+                outputs.push(out.lines().take(6).collect::<Vec<_>>().join("\n"));
+                if done {
+                    break;
+                }
+            }
+            outputs.push(std::fs::read_to_string(temp_path.join(".git/BISECT_LOG")).unwrap());
+            run(&["bisect", "reset"]).unwrap();
+            outputs
+        };
+        let ours = session(&cargo);
+        assert_eq!(git(&["rev-parse", "--abbrev-ref", "HEAD"]).unwrap(), "master\n");
+        assert!(!temp_path.join(".git/BISECT_START").exists());
+        assert!(git(&["for-each-ref", "refs/bisect"]).unwrap().is_empty());
+        assert_eq!(ours, session(&git));
+        assert!(ours.iter().any(|out| out.starts_with(&format!("{} is the first bad commit", culprit))));
+
+        // start with the commits, and go back somewhere else when done
+        let out = cargo(&["bisect", "start", "HEAD", "HEAD~8"]).unwrap();
+        git(&["bisect", "log"]).unwrap();
+        assert!(out.starts_with("Bisecting: "), "{}", out);
+        cargo(&["bisect", "reset", "side"]).unwrap();
+        assert_eq!(git(&["rev-parse", "--abbrev-ref", "HEAD"]).unwrap(), "side\n");
+        assert_eq!(git(&["status", "--porcelain"]).unwrap(), "");
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::{BinaryHeap, HashMap},
+    io::{self, Write},
+    path::{Component, Path},
+};
+use clap::Parser;
+use similar::{DiffOp, TextDiff};
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        blob::Blob,
+        commit::Commit,
+        fs::{read_object, read_worktree_file},
+        hash::{algorithm, ObjectId},
+        ident::{format_iso_date, git_timestamp, split_ident},
+        revparse::{resolve_commit, tree_entry},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "blame", about = "Show what revision and author last modified each line of a file")]
+pub struct Blame {
+    #[arg(short = 's', help = "suppress the author name and timestamp")]
+    suppress: bool,
+
+    #[arg(short = 'l', help = "show the full commit hash")]
+    long: bool,
+
+    #[arg(short = 'e', long = "show-email", help = "show the author email instead of the name")]
+    email: bool,
+
+    #[arg(value_name = "rev", help = "[<rev>] <file>, the working tree version when no revision is given")]
+    args: Vec<String>,
+
+    #[arg(last = true, value_name = "file")]
+    file: Option<String>,
+}
+
+/// a commit's version of the file, and its lines still looking for an owner: (line here, line in the result)
+struct Suspect {
+    content: String,
+    lines: Vec<(usize, usize)>,
+}
+
+impl Blame {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Blame::try_parse_from(args)?))
+    }
+
+    /// `[<rev>] <file>` or `[<rev>] -- <file>`
+    fn target(&self) -> Result<(Option<&str>, &str)> {
+        match (self.args.as_slice(), &self.file) {
+            ([], Some(file)) => Ok((None, file)),
+            ([rev], Some(file)) | ([rev, file], None) => Ok((Some(rev), file)),
+            ([file], None) => Ok((None, file)),
+            _ => Err(GitError::invalid_command("usage: git blame [<options>] [<rev>] [--] <file>".to_string())),
+        }
+    }
+
+    /// `file` given where the command started, relative to the project root
+    fn normalize(repo: &Repository, file: &str) -> String {
+        let path = repo.prefix.join(file);
+        let mut normalized = Vec::new();
+        for component in repo.work_tree().ok().and_then(|top| path.strip_prefix(top).ok()).unwrap_or(&path).components() {
+            match component {
+                Component::CurDir => {},
+                Component::ParentDir => { normalized.pop(); },
+                component => normalized.push(component.as_os_str().to_string_lossy()),
+            }
+        }
+        normalized.join("/")
+    }
+
+    /// the file as of `commit`, None when it is not there
+    fn file_at(gitdir: &Path, commit: &ObjectId, path: &str) -> Result<Option<String>> {
+        let commit = read_object::<Commit>(gitdir.to_path_buf(), commit)?;
+        match tree_entry(gitdir, &commit.tree_hash, path)? {
+            Some(hash) => {
+                let blob: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), &hash)?.into();
+                Ok(Some(String::from_utf8_lossy(&blob).to_string()))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn commit_time(gitdir: &Path, hash: &ObjectId) -> Result<i64> {
+        let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)?;
+        Ok(split_ident(&commit.committer).map(|(_, ts, _)| ts).unwrap_or_default())
+    }
+
+    /// moves the lines `parent` has unchanged from `suspect` over to it, matched by a line diff
+    fn pass_unchanged(parent: &str, suspect: &mut Suspect) -> Vec<(usize, usize)> {
+        let mut old_line = HashMap::new();
+        if parent == suspect.content {
+            old_line.extend(suspect.lines.iter().map(|&(line, _)| (line, line)));
+        } else {
+            for op in TextDiff::from_lines(parent, &suspect.content).ops() {
+                if let DiffOp::Equal { old_index, new_index, len } = *op {
+                    old_line.extend((0..len).map(|i| (new_index + i, old_index + i)));
+                }
+            }
+        }
+        let mut passed = Vec::new();
+        suspect.lines.retain(|&(line, result)| match old_line.get(&line) {
+            Some(&old) => { passed.push((old, result)); false },
+            None => true,
+        });
+        passed
+    }
+
+    /// the commit owning every line of `content`, the file at `path` as of `start` or the working tree's version of it;
+    /// commits are visited newest first, each keeps the lines none of its parents has
+    fn blame(gitdir: &Path, start: &ObjectId, path: &str, content: &str, worktree: bool) -> Result<Vec<ObjectId>> {
+        let start_content = Self::file_at(gitdir, start, path)?
+            .ok_or_else(|| GitError::invalid_command(format!("no such path '{}' in {}", path, start)))?;
+        let count = content.split_inclusive('\n').count();
+        let mut owners = vec![ObjectId::null(); count];
+        let mut first = Suspect { content: content.to_string(), lines: (0..count).map(|i| (i, i)).collect() };
+        if worktree {
+            // what the working tree changed stays "not committed yet"
+            first.lines = Self::pass_unchanged(&start_content, &mut first);
+            first.content = start_content;
+        }
+
+        let mut suspects = HashMap::from([(*start, first)]);
+        let mut queue = BinaryHeap::from([(Self::commit_time(gitdir, start)?, *start)]);
+        while let Some((_, hash)) = queue.pop() {
+            // a commit reached twice is queued twice, the first visit takes all its lines
+            let Some(mut suspect) = suspects.remove(&hash) else { continue };
+            let commit = read_object::<Commit>(gitdir.to_path_buf(), &hash)?;
+            for parent in &commit.parent_hash {
+                if suspect.lines.is_empty() {
+                    break;
+                }
+                let Some(parent_content) = Self::file_at(gitdir, parent, path)? else { continue };
+                let passed = Self::pass_unchanged(&parent_content, &mut suspect);
+                if passed.is_empty() {
+                    continue;
+                }
+                if !suspects.contains_key(parent) {
+                    queue.push((Self::commit_time(gitdir, parent)?, *parent));
+                }
+                suspects.entry(*parent)
+                    .or_insert_with(|| Suspect { content: parent_content, lines: Vec::new() })
+                    .lines.extend(passed);
+            }
+            for (_, result) in suspect.lines {
+                owners[result] = hash;
+            }
+        }
+        Ok(owners)
+    }
+}
+
+impl SubCommand for Blame {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let (rev, file) = self.target()?;
+        let path = Self::normalize(&repo, file);
+        let start = resolve_commit(gitdir, rev.unwrap_or("HEAD"))?;
+        let content = match rev {
+            Some(_) => Self::file_at(gitdir, &start, &path)?
+                .ok_or_else(|| GitError::invalid_command(format!("no such path {} in {}", path, rev.unwrap_or_default())))?,
+            None => {
+                let (_, bytes) = read_worktree_file(repo.work_tree()?.join(&path))?;
+                String::from_utf8_lossy(&bytes).to_string()
+            },
+        };
+        let owners = Self::blame(gitdir, &start, &path, &content, rev.is_none())?;
+
+        // hash column, who and when, per commit
+        let width = if self.long { algorithm().hex_len() } else { 8 };
+        let mut info: HashMap<&ObjectId, (String, String, String)> = HashMap::new();
+        for owner in &owners {
+            if info.contains_key(owner) {
+                continue;
+            }
+            let hex = owner.to_hex();
+            let (column, who, date) = if *owner == ObjectId::null() {
+                let now = git_timestamp();
+                let (ts, tz) = now.split_once(' ').unwrap_or_default();
+                let who = "Not Committed Yet <not.committed.yet>".to_string();
+                (hex[..width].to_string(), who, format_iso_date(ts.parse().unwrap_or_default(), tz))
+            } else {
+                let commit = read_object::<Commit>(gitdir.to_path_buf(), owner)?;
+                // a root commit is where the history ends, marked like git's boundary commits
+                let column = match commit.parent_hash.is_empty() {
+                    true => format!("^{}", &hex[..width - 1]),
+                    false => hex[..width].to_string(),
+                };
+                let (who, ts, tz) = split_ident(&commit.author).unwrap_or((&commit.author, 0, "+0000"));
+                (column, who.to_string(), format_iso_date(ts, tz))
+            };
+            let (name, email) = who.rsplit_once(" <").unwrap_or((&who, ""));
+            let who = match self.email { true => format!("<{}", email), false => name.to_string() };
+            info.insert(owner, (column, who, date));
+        }
+
+        let author_width = info.values().map(|(_, who, _)| who.chars().count()).max().unwrap_or_default();
+        let number_width = owners.len().to_string().len();
+        let mut out = io::stdout().lock();
+        for (i, (line, owner)) in content.split_inclusive('\n').zip(&owners).enumerate() {
+            let (column, who, date) = &info[owner];
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            match self.suppress {
+                true => writeln!(out, "{} {:>width$}) {}", column, i + 1, line, width = number_width)?,
+                false => writeln!(out, "{} ({:<author_width$} {} {:>width$}) {}", column, who, date, i + 1, line,
+                    author_width = author_width, width = number_width)?,
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{shell_spawn, setup_test_git_dir};
+
+    #[test]
+    fn test_blame() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "blame"][..], args].concat());
+        let commit = |message: &str, date: &str, files: &[(&str, &str)]| {
+            for (name, content) in files {
+                std::fs::write(temp_path.join(name), content).unwrap();
+            }
+            git(&["add", "."]);
+            shell_spawn(&["env", &format!("GIT_AUTHOR_DATE={}", date), &format!("GIT_COMMITTER_DATE={}", date),
+                "git", "-C", temp_path_str, "commit", "-m", message]).unwrap();
+        };
+
+        std::fs::create_dir_all(temp_path.join("dir")).unwrap();
+        commit("first", "1600000000 +0800", &[("a.txt", "one\ntwo\nthree\nfour\nfive\n"), ("dir/b.txt", "b\n")]);
+        commit("second", "1600001000 -0130", &[("a.txt", "one\nTWO\nthree\nfour\nfive\nsix\n")]);
+        git(&["config", "user.name", "Someone Longer"]);
+        git(&["config", "user.email", "longer@example.com"]);
+        commit("third", "1600002000 +0000", &[("a.txt", "zero\none\nTWO\nthree\nfive\nsix\n"), ("dir/b.txt", "b\nc\n")]);
+        // a merge keeps the lines each side brought in
+        git(&["checkout", "-b", "side", "HEAD~1"]);
+        commit("side", "1600003000 +0000", &[("a.txt", "one\nTWO\nthree\nfour\nfive\nsix\nseven\n")]);
+        git(&["checkout", "master"]);
+        shell_spawn(&["env", "GIT_AUTHOR_DATE=1600004000 +0000", "GIT_COMMITTER_DATE=1600004000 +0000",
+            "git", "-C", temp_path_str, "merge", "--no-edit", "side"]).unwrap();
+
+        for args in [
+            &["a.txt"][..],
+            &["-s", "a.txt"],
+            &["-l", "a.txt"],
+            &["-e", "a.txt"],
+            &["HEAD~1", "a.txt"],
+            &["side", "--", "a.txt"],
+            &["-s", "dir/b.txt"],
+        ] {
+            assert_eq!(cargo(args).unwrap(), git(&[&["blame"][..], args].concat()), "{:?}", args);
+        }
+
+        // working tree changes belong to nobody yet
+        std::fs::write(temp_path.join("a.txt"), "zero\none\nchanged\nthree\nfive\nsix\nseven\n").unwrap();
+        let ours = cargo(&["-s", "a.txt"]).unwrap();
+        assert_eq!(ours, git(&["blame", "-s", "a.txt"]));
+        assert!(ours.lines().nth(2).unwrap().starts_with("00000000 "));
+
+        assert!(cargo(&["missing.txt"]).is_err());
+        assert!(cargo(&["HEAD~1", "--", "dir"]).is_err());
+    }
+}
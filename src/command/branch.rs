@@ -1,75 +1,309 @@
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use clap::{Parser, Subcommand};
 use std::fs;
 
 use crate::{
     GitError,
     Result,
-    utils::refs::{read_head_ref, read_ref_commit, write_ref_commit},
+    utils::refs::{read_head_ref, read_ref_commit, write_ref_commit_with_log, head_to_hash, list_branches, is_detached, delete_ref, current_branch, check_branch_name, ref_exists, ref_file},
+    utils::revparse::resolve_commit,
+    utils::revwalk,
+    utils::config::{Config, Upstream},
+    utils::hash::ObjectId,
 };
 
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 #[derive(Parser, Debug)]
-#[command(name = "branch", about = "列出或创建分支")]
+#[command(name = "branch", about = "列出、创建或删除分支")]
 pub struct Branch {
 
-    #[arg(short = 'd', long = "delete", help = "删除分支")]
+    #[arg(short = 'd', long = "delete", help = "删除已合并到 HEAD 的分支")]
     delete: bool,
+
+    #[arg(short = 'D', help = "强制删除分支，即使尚未合并")]
+    force_delete: bool,
+
+    #[arg(short = 'u', long = "set-upstream-to", value_name = "upstream", help = "设置分支（默认当前分支）的上游")]
+    set_upstream_to: Option<String>,
+
+    #[arg(long = "unset-upstream", help = "删除分支（默认当前分支）的上游信息")]
+    unset_upstream: bool,
+
     /// 新分支名（如果不指定则列出所有分支）
     branch_name: Option<String>,
+
+    /// 新分支的起点：分支、远程跟踪分支、标签或者提交，默认是 HEAD
+    start_point: Option<String>,
 }
 
 impl Branch {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
         Ok(Box::new(Branch::try_parse_from(args)?))
     }
-}
 
-impl SubCommand for Branch {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        let heads_dir = gitdir.join("refs/heads");
-        if self.delete {
-            if let Some(ref branch_name) = self.branch_name {
-                let branch_path = heads_dir.join(branch_name);
-                if branch_path.exists() {
-                    let current_ref = read_head_ref(&gitdir)?;
-                    if format!("refs/heads/{}", branch_name) == current_ref {
-                        return Err(GitError::invalid_command(format!("can not remove current ref '{}'", branch_name)));
-                    }
-                    fs::remove_file(&branch_path)
-                        .map_err(|_| GitError::failed_to_write_file(&branch_path.to_string_lossy()))?;
-                    //println!("Branch '{}' deleted", branch_name);
-                } else {
-                    //println!("Branch '{}' does not exist", branch_name);
-                }
+    fn list(gitdir: &Path) -> Result<()> {
+        let current_ref = read_head_ref(gitdir).ok();
+        if is_detached(gitdir) {
+            println!("* (HEAD detached at {})", &head_to_hash(gitdir)?.to_hex()[..7]);
+        }
+        for name in list_branches(gitdir)? {
+            if current_ref.as_deref() == Some(&format!("refs/heads/{}", name)) {
+                println!("* {}", name);
             } else {
-                return Err(GitError::invalid_command("no file to remove".to_string()));
+                println!("  {}", name);
             }
-        } else if let Some(ref branch_name) = self.branch_name {
-            let head_ref = read_head_ref(&gitdir)?;
-            let commit_hash = read_ref_commit(&gitdir, &head_ref)?;
-            let new_branch = heads_dir.join(branch_name);
-            if new_branch.exists() {
-                return Err(GitError::invalid_command(format!("branch '{}' already exist", branch_name)));
-            }
-            fs::write(&new_branch, format!("{}\n", commit_hash))
-                .map_err(|_| GitError::failed_to_write_file(&new_branch.to_string_lossy()))?;
-            //println!("Branch '{}' created at {}", branch_name, commit_hash);
+        }
+        Ok(())
+    }
+
+    /// check that `branch_name` can be created and find the commit it would start at, nothing is written
+    /// `None` when there is no start point and HEAD is unborn
+    pub fn new_branch_commit(gitdir: &Path, branch_name: &str, start_point: Option<&str>) -> Result<Option<ObjectId>> {
+        check_branch_name(branch_name)?;
+        if ref_exists(gitdir, &format!("refs/heads/{}", branch_name)) {
+            return Err(GitError::invalid_command(format!("a branch named '{}' already exists", branch_name)));
+        }
+        match start_point {
+            Some(start_point) => resolve_commit(gitdir, start_point).map(Some).map_err(|_| GitError::invalid_command(format!(
+                "'{}' is not a commit and a branch '{}' cannot be created from it", start_point, branch_name))),
+            None => Ok(head_to_hash(gitdir).ok()),
+        }
+    }
+
+    /// write refs/heads/<branch_name> at `commit`, branch names with slashes get their directories
+    /// a branch made from a remote-tracking ref follows it, like git's default branch.autoSetupMerge
+    pub fn create_at(gitdir: &Path, branch_name: &str, start_point: Option<&str>, commit: &ObjectId) -> Result<()> {
+        let ref_path = format!("refs/heads/{}", branch_name);
+        let new_branch = ref_file(gitdir, &ref_path);
+        fs::create_dir_all(new_branch.parent().unwrap())
+            .map_err(|_| GitError::failed_to_write_file(&new_branch.to_string_lossy()))?;
+        let message = format!("branch: Created from {}", start_point.unwrap_or("HEAD"));
+        write_ref_commit_with_log(gitdir, &ref_path, commit, &message)?;
+        if let Some(tracking) = start_point.and_then(|start_point| Self::remote_tracking_ref(gitdir, start_point))
+            && Config::read_all(gitdir)?.upstream_of_tracking_ref(&tracking).is_some() {
+            Self::set_upstream(gitdir, branch_name, &tracking)?;
+        }
+        Ok(())
+    }
+
+    /// `origin/main` or `refs/remotes/origin/main` as a remote-tracking ref, a local branch of the same name wins
+    fn remote_tracking_ref(gitdir: &Path, start_point: &str) -> Option<String> {
+        if start_point.starts_with("refs/remotes/") {
+            return Some(start_point.to_string());
+        }
+        let tracking = format!("refs/remotes/{}", start_point);
+        (!ref_exists(gitdir, &format!("refs/heads/{}", start_point)) && ref_exists(gitdir, &tracking)).then_some(tracking)
+    }
+
+    fn create(gitdir: &Path, branch_name: &str, start_point: Option<&str>) -> Result<()> {
+        let commit = Self::new_branch_commit(gitdir, branch_name, start_point)?
+            .ok_or_else(|| GitError::invalid_command(format!("not a valid object name: '{}'", current_branch(gitdir).unwrap_or("HEAD".to_string()))))?;
+        Self::create_at(gitdir, branch_name, start_point, &commit)
+    }
+
+    /// `origin/main` 这样的远程跟踪分支按 fetch 的 refspec 找回远程和远程分支名，
+    /// 本地分支记成 remote = .，和 git 写的配置一样
+    pub fn set_upstream(gitdir: &Path, branch_name: &str, upstream_name: &str) -> Result<()> {
+        let config = Config::read_all(gitdir)?;
+        let candidates = [
+            upstream_name.to_string(),
+            format!("refs/heads/{}", upstream_name),
+            format!("refs/remotes/{}", upstream_name),
+        ];
+        let refname = candidates.iter()
+            .skip(if upstream_name.starts_with("refs/") { 0 } else { 1 })
+            .find(|refname| ref_exists(gitdir, refname))
+            .ok_or_else(|| GitError::invalid_command(format!("the requested upstream branch '{}' does not exist", upstream_name)))?;
+        let upstream = if refname.starts_with("refs/heads/") {
+            Upstream { remote: ".".to_string(), merge: refname.clone() }
         } else {
-            let current_ref = read_head_ref(&gitdir)?;
-            for entry in fs::read_dir(&heads_dir)? {
-                let entry = entry?;
-                let name = entry.file_name().to_string_lossy().to_string();
-                if format!("refs/heads/{}", name) == current_ref {
-                    // println!("* {}", name);
-                } else {
-                    // println!("  {}", name);
-                }
+            config.upstream_of_tracking_ref(refname)
+                .ok_or_else(|| GitError::invalid_command(format!("the requested upstream branch '{}' is not tracked by any remote", upstream_name)))?
+        };
+
+        Config::set(gitdir, &format!("branch.{}.remote", branch_name), &upstream.remote)?;
+        Config::set(gitdir, &format!("branch.{}.merge", branch_name), &upstream.merge)?;
+        let shown = refname.strip_prefix("refs/heads/").or_else(|| refname.strip_prefix("refs/remotes/")).unwrap_or(refname);
+        println!("branch '{}' set up to track '{}'.", branch_name, shown);
+        Ok(())
+    }
+
+    fn unset_upstream(gitdir: &Path, branch_name: &str) -> Result<()> {
+        if Config::read(gitdir)?.upstream(branch_name).is_none() {
+            return Err(GitError::invalid_command(format!("branch '{}' has no upstream information", branch_name)));
+        }
+        Config::unset(gitdir, &format!("branch.{}.remote", branch_name))?;
+        Config::unset(gitdir, &format!("branch.{}.merge", branch_name))
+    }
+
+    fn remove(gitdir: &Path, branch_name: &str, force: bool) -> Result<()> {
+        if !ref_exists(gitdir, &format!("refs/heads/{}", branch_name)) {
+            return Err(GitError::invalid_command(format!("branch '{}' not found", branch_name)));
+        }
+
+        let current_ref = read_head_ref(gitdir).ok();
+        if current_ref.as_deref() == Some(&format!("refs/heads/{}", branch_name)) {
+            return Err(GitError::invalid_command(format!("can not remove current ref '{}'", branch_name)));
+        }
+
+        let branch_hash = read_ref_commit(gitdir, &format!("refs/heads/{}", branch_name))?;
+        if !force {
+            let head_hash = head_to_hash(gitdir)?;
+            let merged = revwalk::ancestors(gitdir, &head_hash)?
+                .contains(&branch_hash);
+            if !merged {
+                return Err(GitError::invalid_command(format!(
+                    "the branch '{}' is not fully merged.\nIf you are sure you want to delete it, run 'git branch -D {}'",
+                    branch_name, branch_name
+                )));
             }
         }
 
+        // feature/foo leaves refs/heads/feature behind, delete_ref cleans up empty directories
+        delete_ref(gitdir, &format!("refs/heads/{}", branch_name))?;
+
+        println!("Deleted branch {} (was {}).", branch_name, &branch_hash.to_hex()[..7]);
+        Ok(())
+    }
+}
+
+impl SubCommand for Branch {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        if self.set_upstream_to.is_some() || self.unset_upstream {
+            let branch_name = match &self.branch_name {
+                Some(branch_name) => branch_name.clone(),
+                None => current_branch(&gitdir)?,
+            };
+            if !ref_exists(&gitdir, &format!("refs/heads/{}", branch_name)) {
+                return Err(GitError::invalid_command(format!("branch '{}' does not exist", branch_name)));
+            }
+            match &self.set_upstream_to {
+                Some(upstream) => Self::set_upstream(&gitdir, &branch_name, upstream)?,
+                None => Self::unset_upstream(&gitdir, &branch_name)?,
+            }
+            return Ok(0);
+        }
+        match (&self.branch_name, self.delete || self.force_delete) {
+            (Some(branch_name), true) => Self::remove(&gitdir, branch_name, self.force_delete)?,
+            (None, true) => return Err(GitError::invalid_command("branch name required".to_string())),
+            (Some(branch_name), false) => Self::create(&gitdir, branch_name, self.start_point.as_deref())?,
+            (None, false) => Self::list(&gitdir)?,
+        }
         Ok(0)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        mktemp_in,
+        cmd_seq,
+        tempdir,
+        cp_dir,
+        run_both,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_list_and_delete() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        let file1 = mktemp_in(temp_path1).unwrap();
+        let file1_str = file1.file_name().unwrap().to_str().unwrap();
+        let file2 = mktemp_in(temp_path1).unwrap();
+        let file2_str = file2.file_name().unwrap().to_str().unwrap();
+
+        let setup_cmds: ArgsList = &[
+            (&["add", file1_str], false),
+            (&["commit", "-m", "first"], false),
+            (&["checkout", "-b", "unmerged"], false),
+            (&["add", file2_str], false),
+            (&["commit", "-m", "second"], false),
+            (&["checkout", "master"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let cmds: ArgsList = &[
+            (&["branch", "feature/foo"], false),
+            (&["branch", "zeta"], false),
+            (&["branch"], false),
+        ];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        let (origin, real) = run_both(cmds, git, cargo).unwrap();
+        assert_eq!(origin[2], real[2]);
+
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "branch", "-d", "unmerged"]).is_err());
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "branch", "-d", "master"]).is_err());
+
+        let cmds: ArgsList = &[
+            (&["branch", "-d", "feature/foo"], false),
+            (&["branch", "-D", "unmerged"], false),
+            (&["branch"], false),
+        ];
+        let (origin, real) = run_both(cmds, git, cargo).unwrap();
+        assert_eq!(origin[2], real[2]);
+        assert!(!temp_path2.join(".git/refs/heads/feature").exists());
+    }
+
+    #[test]
+    fn test_upstream_tracking() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(&[(&["commit", "--allow-empty", "-m", "first"], false), (&["commit", "--allow-empty", "-m", "second"], false)])(git_up).unwrap();
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        let remote_url = upstream.path().join(".git");
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", local_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", local_str][..], args].concat());
+        git(&["commit", "--allow-empty", "-m", "local"]).unwrap();
+        git(&["remote", "add", "origin", remote_url.to_str().unwrap()]).unwrap();
+        cargo(&["fetch", "origin"]).unwrap();
+
+        // checkout -b from a remote-tracking branch records it as upstream
+        let out = cargo(&["checkout", "-b", "topic", "origin/master"]).unwrap();
+        assert_eq!(out, "branch 'topic' set up to track 'origin/master'.\n");
+        assert_eq!(git(&["config", "branch.topic.remote"]).unwrap(), "origin\n");
+        assert_eq!(git(&["config", "branch.topic.merge"]).unwrap(), "refs/heads/master\n");
+        assert_eq!(git(&["rev-parse", "--abbrev-ref", "@{u}"]).unwrap(), "origin/master\n");
+        let tip = git(&["rev-parse", "origin/master"]).unwrap();
+        assert_eq!(git(&["rev-parse", "HEAD"]).unwrap(), tip);
+        for rev in ["@{u}", "@{upstream}", "topic@{U}", "HEAD@{u}"] {
+            assert_eq!(cargo(&["rev-parse", rev]).unwrap(), tip, "{}", rev);
+        }
+        assert_eq!(cargo(&["rev-parse", "@{u}~1"]).unwrap(), git(&["rev-parse", "origin/master~1"]).unwrap());
+
+        // master has no upstream yet: rev-parse and push refuse like git
+        assert!(cargo(&["rev-parse", "master@{u}"]).is_err());
+        cargo(&["checkout", "master"]).unwrap();
+        assert!(cargo(&["push"]).is_err());
+
+        // a local branch is tracked through remote "."
+        cargo(&["branch", "--set-upstream-to", "topic"]).unwrap();
+        assert_eq!(git(&["config", "branch.master.remote"]).unwrap(), ".\n");
+        assert_eq!(git(&["rev-parse", "--abbrev-ref", "master@{u}"]).unwrap(), "topic\n");
+        cargo(&["branch", "-u", "origin/master", "master"]).unwrap();
+        assert_eq!(git(&["rev-parse", "--abbrev-ref", "master@{u}"]).unwrap(), "origin/master\n");
+        assert!(cargo(&["branch", "-u", "origin/nope"]).is_err());
+
+        cargo(&["branch", "--unset-upstream"]).unwrap();
+        assert!(git(&["config", "branch.master.remote"]).is_err());
+        assert!(cargo(&["branch", "--unset-upstream"]).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        commit::Commit,
+        fs::{object_exists, read_obj, read_object},
+        hash::{algorithm, HashAlgorithm, ObjectId},
+        objtype::Obj,
+        packfile::{write_pack, PackfileProcessor},
+        refs::{for_each_ref, head_to_hash, read_ref_commit},
+        revparse::{dwim_ref, resolve_commit},
+        revwalk,
+        tree::{FileMode, Tree},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "bundle", about = "Move objects and refs by archive")]
+pub struct Bundle {
+    #[command(subcommand)]
+    command: BundleCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum BundleCommand {
+    /// write the refs and the history they need into `file`
+    Create {
+        file: String,
+        /// refs to record, `a..b` or `^a` leave out what the receiver already has, `--all` for every ref
+        #[arg(required = true, allow_hyphen_values = true)]
+        revisions: Vec<String>,
+    },
+    /// check that `file` is a bundle this repository has the prerequisites of
+    Verify {
+        file: String,
+    },
+    /// list the refs recorded in `file`
+    ListHeads {
+        file: String,
+    },
+    /// store the objects of `file` and print its refs, the refs themselves are not touched
+    Unbundle {
+        file: String,
+    },
+}
+
+/// what a bundle file holds: the commits the receiver must have, the refs and the pack
+struct BundleFile {
+    prerequisites: Vec<(ObjectId, String)>,
+    refs: Vec<(ObjectId, String)>,
+    pack: Vec<u8>,
+}
+
+/// the revisions given to create: refs to record, commits to send and commits to leave out with their history
+struct Revisions {
+    refs: Vec<(ObjectId, String)>,
+    include: Vec<ObjectId>,
+    exclude: Vec<ObjectId>,
+}
+
+impl Bundle {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Bundle::try_parse_from(args)?))
+    }
+
+    /// `# v2 git bundle`, or v3 with the object format when it is not sha1, then the lines up to a blank one
+    fn read(path: &Path) -> Result<BundleFile> {
+        let not_bundle = || GitError::invalid_command(format!("'{}' does not look like a v2 or v3 bundle file", path.display()));
+        let data = fs::read(path).map_err(|_| GitError::failed_to_read_file(&path.to_string_lossy()))?;
+        let mut bundle = BundleFile { prerequisites: Vec::new(), refs: Vec::new(), pack: Vec::new() };
+        let mut rest = data.as_slice();
+        let mut first = true;
+        loop {
+            let end = rest.iter().position(|&b| b == b'\n').ok_or_else(not_bundle)?;
+            let line = String::from_utf8_lossy(&rest[..end]).to_string();
+            rest = &rest[end + 1..];
+            if first {
+                if line != "# v2 git bundle" && line != "# v3 git bundle" {
+                    return Err(not_bundle());
+                }
+                first = false;
+                continue;
+            }
+            if line.is_empty() {
+                break;
+            }
+            if let Some(format) = line.strip_prefix("@object-format=") {
+                if format != algorithm().name() {
+                    return Err(GitError::invalid_command(format!("the bundle uses {}, the repository {}", format, algorithm().name())));
+                }
+                continue;
+            }
+            match line.strip_prefix('-') {
+                Some(prerequisite) => {
+                    let (hash, comment) = prerequisite.split_once(' ').unwrap_or((prerequisite, ""));
+                    bundle.prerequisites.push((ObjectId::from_hex(hash).map_err(|_| not_bundle())?, comment.to_string()));
+                },
+                None => {
+                    let (hash, refname) = line.split_once(' ').ok_or_else(not_bundle)?;
+                    bundle.refs.push((ObjectId::from_hex(hash).map_err(|_| not_bundle())?, refname.to_string()));
+                },
+            }
+        }
+        bundle.pack = rest.to_vec();
+        Ok(bundle)
+    }
+
+    /// the refs a bundle records, the commits to walk from and the ones the receiver is expected to have
+    fn revisions(gitdir: &Path, revisions: &[String]) -> Result<Revisions> {
+        let mut revs = Revisions { refs: Vec::new(), include: Vec::new(), exclude: Vec::new() };
+        let mut positive = Vec::new();
+        for rev in revisions {
+            if rev == "--all" {
+                for (refname, hash) in for_each_ref(gitdir, "refs/")? {
+                    revs.include.push(resolve_commit(gitdir, &hash.to_hex())?);
+                    revs.refs.push((hash, refname));
+                }
+                positive.extend(head_to_hash(gitdir).ok().map(|_| "HEAD".to_string()));
+            } else if let Some((from, to)) = rev.split_once("..") {
+                let or_head = |rev: &str| if rev.is_empty() { "HEAD".to_string() } else { rev.to_string() };
+                revs.exclude.push(resolve_commit(gitdir, &or_head(from))?);
+                positive.push(or_head(to));
+            } else if let Some(rev) = rev.strip_prefix('^') {
+                revs.exclude.push(resolve_commit(gitdir, rev)?);
+            } else {
+                positive.push(rev.clone());
+            }
+        }
+        for rev in positive {
+            revs.include.push(resolve_commit(gitdir, &rev)?);
+            // only refs are recorded, `master~1` just adds history
+            match rev.as_str() {
+                "HEAD" => revs.refs.push((head_to_hash(gitdir)?, rev)),
+                rev => if let Some(refname) = dwim_ref(gitdir, rev) {
+                    revs.refs.push((read_ref_commit(gitdir, &refname)?, refname));
+                },
+            }
+        }
+        Ok(revs)
+    }
+
+    /// `tree` and everything below it, submodule commits are not ours to send
+    fn tree_objects(gitdir: &Path, tree: &ObjectId, out: &mut HashSet<ObjectId>) -> Result<()> {
+        if !out.insert(*tree) {
+            return Ok(());
+        }
+        for entry in read_object::<Tree>(gitdir.to_path_buf(), tree)?.iter() {
+            match entry.mode {
+                FileMode::Tree => Self::tree_objects(gitdir, &entry.hash, out)?,
+                FileMode::Commit => {},
+                _ => { out.insert(entry.hash); },
+            }
+        }
+        Ok(())
+    }
+
+    fn create(gitdir: &Path, file: &Path, revisions: &[String]) -> Result<i32> {
+        let Revisions { refs, include, exclude } = Self::revisions(gitdir, revisions)?;
+        let mut cache = HashMap::new();
+        let excluded = revwalk::reachable(gitdir, exclude, &mut cache)?.into_iter().collect::<HashSet<_>>();
+        let commits = revwalk::date_order(gitdir, include, &mut cache)?
+            .into_iter()
+            .filter(|hash| !excluded.contains(hash))
+            .collect::<Vec<_>>();
+        if commits.is_empty() || refs.is_empty() {
+            return Err(GitError::refused("Refusing to create empty bundle.".to_string()));
+        }
+
+        // the excluded parents of what is sent, their trees are what the receiver already has
+        let mut prerequisites = Vec::new();
+        for hash in &commits {
+            for parent in &cache[hash].parent_hash {
+                if excluded.contains(parent) && !prerequisites.contains(parent) {
+                    prerequisites.push(*parent);
+                }
+            }
+        }
+        let mut known = HashSet::new();
+        for hash in &prerequisites {
+            Self::tree_objects(gitdir, &cache[hash].tree_hash, &mut known)?;
+        }
+        let mut objects = HashSet::new();
+        for hash in &commits {
+            Self::tree_objects(gitdir, &cache[hash].tree_hash, &mut objects)?;
+        }
+        let mut objects = commits.iter().copied()
+            .chain(objects.into_iter().filter(|hash| !known.contains(hash)))
+            .collect::<Vec<_>>();
+        // annotated tags travel along with the refs pointing at them
+        for (hash, _) in &refs {
+            if let Obj::G(_) = read_obj(gitdir.to_path_buf(), hash)? && !objects.contains(hash) {
+                objects.push(*hash);
+            }
+        }
+
+        let mut out = match algorithm() {
+            HashAlgorithm::Sha1 => b"# v2 git bundle\n".to_vec(),
+            other => format!("# v3 git bundle\n@object-format={}\n", other.name()).into_bytes(),
+        };
+        for hash in &prerequisites {
+            let subject = cache[hash].message.lines().next().unwrap_or_default().to_string();
+            out.extend(format!("-{} {}\n", hash, subject).into_bytes());
+        }
+        for (hash, refname) in &refs {
+            out.extend(format!("{} {}\n", hash, refname).into_bytes());
+        }
+        out.push(b'\n');
+        out.extend(write_pack(gitdir, &objects.iter().copied().map(ObjectId::to_hex).collect::<Vec<_>>())?);
+        fs::write(file, out).map_err(|_| GitError::failed_to_write_file(&file.to_string_lossy()))?;
+        Ok(0)
+    }
+
+    /// "this ref" or "these N refs", as git words it
+    fn count(n: usize) -> String {
+        match n {
+            1 => "this ref".to_string(),
+            n => format!("these {} refs", n),
+        }
+    }
+
+    fn verify(gitdir: &Path, name: &str, bundle: &BundleFile) -> Result<i32> {
+        let missing = bundle.prerequisites.iter()
+            .filter(|(hash, _)| !object_exists(gitdir, hash) || read_object::<Commit>(gitdir.to_path_buf(), hash).is_err())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            eprintln!("error: Repository lacks these prerequisite commits:");
+            for (hash, _) in missing {
+                eprintln!("error: {} ", hash);
+            }
+            return Ok(1);
+        }
+        println!("The bundle contains {}:", Self::count(bundle.refs.len()));
+        for (hash, refname) in &bundle.refs {
+            println!("{} {}", hash, refname);
+        }
+        match bundle.prerequisites.len() {
+            0 => println!("The bundle records a complete history."),
+            n => {
+                println!("The bundle requires {}:", Self::count(n));
+                // git keeps the comment for itself
+                for (hash, _) in &bundle.prerequisites {
+                    println!("{} ", hash);
+                }
+            },
+        }
+        println!("The bundle uses this hash algorithm: {}", algorithm().name());
+        eprintln!("{} is okay", name);
+        Ok(0)
+    }
+}
+
+impl SubCommand for Bundle {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        match &self.command {
+            BundleCommand::Create { file, revisions } => Self::create(gitdir, &repo.resolve(file), revisions),
+            BundleCommand::Verify { file } => Self::verify(gitdir, file, &Self::read(&repo.resolve(file))?),
+            BundleCommand::ListHeads { file } => {
+                for (hash, refname) in Self::read(&repo.resolve(file))?.refs {
+                    println!("{} {}", hash, refname);
+                }
+                Ok(0)
+            },
+            BundleCommand::Unbundle { file } => {
+                let bundle = Self::read(&repo.resolve(file))?;
+                if let Some((hash, _)) = bundle.prerequisites.iter().find(|(hash, _)| !object_exists(gitdir, hash)) {
+                    eprintln!("error: Repository lacks these prerequisite commits:\nerror: {} ", hash);
+                    return Ok(1);
+                }
+                PackfileProcessor::new(gitdir.clone()).store_packfile(&bundle.pack)?;
+                for (hash, refname) in &bundle.refs {
+                    println!("{} {}", hash, refname);
+                }
+                Ok(0)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{shell_spawn, setup_test_git_dir, tempdir};
+
+    #[test]
+    fn test_bundle() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let out = tempdir().unwrap();
+        let out_path = out.path();
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-C", dir][..], args].concat());
+        let cargo = |dir: &str, args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", dir][..], args].concat());
+        let file = |name: &str| out_path.join(name).to_str().unwrap().to_string();
+        // the text before the pack, where git and we agree byte for byte
+        let header = |name: &str| {
+            let data = std::fs::read(file(name)).unwrap();
+            let end = data.windows(5).position(|w| w == b"\nPACK").unwrap();
+            String::from_utf8_lossy(&data[..end]).to_string()
+        };
+
+        std::fs::create_dir(temp_path.join("dir")).unwrap();
+        for i in 1..=5 {
+            std::fs::write(temp_path.join("a.txt"), format!("{}\n", i)).unwrap();
+            std::fs::write(temp_path.join("dir/b.txt"), format!("{}\n", i % 2)).unwrap();
+            git(temp_path_str, &["add", "."]).unwrap();
+            git(temp_path_str, &["commit", "-q", "-m", &format!("c{}", i)]).unwrap();
+        }
+        git(temp_path_str, &["tag", "-a", "v1", "-m", "tagged", "HEAD~2"]).unwrap();
+        git(temp_path_str, &["branch", "old", "HEAD~3"]).unwrap();
+
+        for (name, args) in [
+            ("all", &["--all"][..]),
+            ("range", &["HEAD~2..master"]),
+            ("tags", &["v1", "HEAD~1", "^old"]),
+        ] {
+            cargo(temp_path_str, &[&["bundle", "create", &file(&format!("{}.ours", name))][..], args].concat()).unwrap();
+            git(temp_path_str, &[&["bundle", "create", "-q", &file(&format!("{}.git", name))][..], args].concat()).unwrap();
+            assert_eq!(header(&format!("{}.ours", name)), header(&format!("{}.git", name)), "{:?}", args);
+            // git takes our bundles, and we read theirs the same way
+            for file in [file(&format!("{}.ours", name)), file(&format!("{}.git", name))] {
+                let theirs = git(temp_path_str, &["bundle", "verify", &file]).unwrap();
+                assert_eq!(cargo(temp_path_str, &["bundle", "verify", &file]).unwrap(), theirs, "{:?}", args);
+                assert_eq!(cargo(temp_path_str, &["bundle", "list-heads", &file]).unwrap(), git(temp_path_str, &["bundle", "list-heads", &file]).unwrap());
+            }
+        }
+        assert!(cargo(temp_path_str, &["bundle", "create", &file("empty"), "master", "^master"]).is_err());
+
+        // a full bundle clones, an incremental one needs its prerequisites
+        let clone = out_path.join("clone");
+        let clone_str = clone.to_str().unwrap();
+        git(out_path.to_str().unwrap(), &["clone", "-q", &file("all.ours"), "clone"]).unwrap();
+        git(clone_str, &["fsck", "--strict"]).unwrap();
+        assert_eq!(git(clone_str, &["rev-parse", "HEAD"]).unwrap(), git(temp_path_str, &["rev-parse", "HEAD"]).unwrap());
+
+        let empty = out_path.join("empty");
+        git(out_path.to_str().unwrap(), &["init", "-q", "empty"]).unwrap();
+        assert!(cargo(empty.to_str().unwrap(), &["bundle", "verify", &file("range.git")]).is_err());
+        assert!(cargo(empty.to_str().unwrap(), &["bundle", "unbundle", &file("range.git")]).is_err());
+        // a clone which stops right at the prerequisite
+        git(temp_path_str, &["branch", "base", "HEAD~2"]).unwrap();
+        let partial = out_path.join("partial");
+        let partial_str = partial.to_str().unwrap();
+        git(out_path.to_str().unwrap(), &["clone", "-q", "--no-local", "--single-branch", "--no-tags", "-b", "base", temp_path_str, "partial"]).unwrap();
+        assert_eq!(cargo(partial_str, &["bundle", "verify", &file("range.ours")]).unwrap(), git(partial_str, &["bundle", "verify", &file("range.ours")]).unwrap());
+        // the pack's progress goes to stderr, the refs to stdout
+        let unbundled = cargo(partial_str, &["bundle", "unbundle", &file("range.ours")]).unwrap();
+        assert!(unbundled.ends_with(&git(temp_path_str, &["bundle", "list-heads", &file("range.ours")]).unwrap()), "{}", unbundled);
+        let tip = git(temp_path_str, &["rev-parse", "master"]).unwrap();
+        git(partial_str, &["update-ref", "refs/heads/master", tip.trim()]).unwrap();
+        git(partial_str, &["fsck", "--strict"]).unwrap();
+    }
+}
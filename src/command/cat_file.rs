@@ -1,13 +1,9 @@
-use std::path::PathBuf;
 use std::convert::Into;
 use clap::{Parser, Subcommand, CommandFactory};
 
 use crate::utils::{
-    zlib::{
-        decompress_file,
-        decompress_file_as_bytes,
-    },
-    fs::{obj_to_pathbuf_legacy, obj_to_pathbuf},
+    zlib::decompress_file,
+    fs::{obj_to_pathbuf_legacy, obj_to_pathbuf, object_exists, read_raw_object},
     objtype::{
         ObjType,
         parse_meta,
@@ -23,6 +19,7 @@ use crate::{
     Result,
 };
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 use nom::{
     bytes::complete::{tag, take, take_until},
@@ -44,8 +41,8 @@ pub struct CatFile {
     #[arg(short = 't', group = "option", help = "show object type (one of 'blob', 'tree', 'commit', 'tag', ...)")]
     show_type: bool,
 
-    #[arg(required = true, value_parser = obj_to_pathbuf_legacy)]
-    objpath: PathBuf,
+    #[arg(required = true, value_parser = |s: &str| obj_to_pathbuf_legacy(s).map(|_| s.to_string()))]
+    object: String,
 }
 
 impl CatFile {
@@ -53,14 +50,13 @@ impl CatFile {
         Ok(Box::new(CatFile::try_parse_from(args)?))
     }
 
-    pub fn cat(&self, path: PathBuf) -> Result<()> {
-        let obj: Obj = decompress_file_as_bytes(&path)?.try_into()?;
+    pub fn cat(&self, bytes: Vec<u8>) -> Result<()> {
+        let obj: Obj = bytes.try_into()?;
         print!("{}", obj);
         Ok(())
     }
 
-    pub fn cat_type(&self, path: PathBuf) -> Result<()> {
-        let bytes = decompress_file_as_bytes(&path)?;
+    pub fn cat_type(&self, bytes: Vec<u8>) -> Result<()> {
         let (_, (t, _)) = parse_meta(&bytes).map_err(|x|x.to_string()).map_err(GitError::invalid_obj)?;
         println!("{}", String::from_utf8(t.to_vec()).map_err(|x|x.to_string()).map_err(GitError::invalid_obj)?);
         Ok(())
@@ -69,24 +65,21 @@ impl CatFile {
 
 
 impl SubCommand for CatFile {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let mut gitdir = gitdir?;
-        gitdir.push(&self.objpath);
-        if !gitdir.exists()
-        {
-            if self.check_exist {
-                Ok((!gitdir.exists()) as i32)
-            }
-            else {
-                Err(GitError::file_notfound(format!("{} 不存在", gitdir.to_str().unwrap())))
-            }
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        // 松散对象和 pack 里的对象都算
+        if self.check_exist {
+            Ok((!object_exists(&gitdir, &self.object)) as i32)
+        }
+        else if !object_exists(&gitdir, &self.object) {
+            Err(GitError::file_notfound(format!("{} 不存在", self.object)))
         }
         else if self.print {
-            self.cat(gitdir)?;
+            self.cat(read_raw_object(&gitdir, &self.object)?)?;
             Ok(0)
         }
         else if self.show_type {
-            self.cat_type(gitdir)?;
+            self.cat_type(read_raw_object(&gitdir, &self.object)?)?;
             Ok(0)
         }
         else {
@@ -119,12 +112,12 @@ mod test {
 
         let _ = shell_spawn(&["ls", "-lahR", temp_path_str]).unwrap();
 
-        let _ = shell_spawn(&["git", "-C", temp_path_str, "add", &file1_str]).unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "add", file1_str]).unwrap();
         let hash = shell_spawn(&["git", "-C", temp_path_str, "hash-object", file1_str]).unwrap();
         let hash = hash.strip_suffix("\n").unwrap();
 
-        let origin = shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-p", &hash]).unwrap();
-        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "cat-file", "-p", &hash]).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-p", hash]).unwrap();
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "cat-file", "-p", hash]).unwrap();
         assert_eq!(origin, real);
     }
 
@@ -140,13 +133,13 @@ mod test {
         let file2 = mktemp_in(&temp).unwrap();
         let file2_str = file2.to_str().unwrap();
 
-        let _ = shell_spawn(&["git", "-C", temp_path_str, "update-index", "--add", &file1_str, &file2_str]).unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "update-index", "--add", file1_str, file2_str]).unwrap();
         let hash = shell_spawn(&["git", "-C", temp_path_str, "write-tree"]).unwrap();
         let hash = hash.strip_suffix("\n").unwrap();
 
-        let origin = shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-p", &hash]).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-p", hash]).unwrap();
         println!("origin = {}", origin);
-        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "cat-file", "-p", &hash]).unwrap();
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "cat-file", "-p", hash]).unwrap();
         assert_eq!(origin, real);
     }
 
@@ -160,14 +153,14 @@ mod test {
         let file1_str = file1.to_str().unwrap();
         let file2 = mktemp_in(&temp).unwrap();
         let file2_str = file2.to_str().unwrap();
-        let _ = shell_spawn(&["git", "-C", temp_path_str, "add", &file1_str, &file2_str]).unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "add", file1_str, file2_str]).unwrap();
         let _ = shell_spawn(&["git", "-C", temp_path_str, "commit", "-m", "commit-message"]).unwrap();
         let hash = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD"]).unwrap();
         let hash = hash.strip_suffix("\n").unwrap();
 
-        let origin = shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-p", &hash]).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "cat-file", "-p", hash]).unwrap();
         println!("{}", origin);
-        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "cat-file", "-p", &hash]).unwrap();
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "cat-file", "-p", hash]).unwrap();
         println!("{}", real);
         assert_eq!(origin, real);
     }
@@ -1,11 +1,12 @@
 use std::path::{
     PathBuf,
-    Path
+    Path,
+    Component,
 };
 
 use clap::{Parser, Subcommand};
 use crate::{
-    command::ReadTree,
+    command::{ReadTree, Branch, read_tree::{Rejection, TreeMerge}},
     GitError,
     Result,
     utils::refs::{
@@ -15,32 +16,41 @@ use crate::{
         write_ref_commit,
         write_head_commit,
         read_head_commit,
+        head_to_hash,
+        current_branch,
+        write_ref_commit_with_log,
+        write_head_ref_with_log,
+        write_head_commit_with_log,
+        ref_exists,
     },
 };
 use super::SubCommand;
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
 use std::{
     fs,
-    fs::File,
-    io::Write,
-    os::unix::fs::PermissionsExt,
+    os::unix::{fs::{symlink, PermissionsExt}, ffi::OsStrExt},
+    collections::HashSet,
 };
 
 use crate::utils::{
     tree::{
         Tree,
         FileMode,
-        TreeEntry,
     },
     zlib::decompress_file_bytes,
     blob::Blob,
     index::Index,
-    hash::hash_object,
-    index::IndexEntry,
+    hash::{hash_object, ObjectId},
+    index::{IndexEntry, LockedIndexWriter},
+    revparse::{rev_parse, resolve_tree},
+    ignore::glob_match,
+    diff::flatten_tree,
+    config::Config,
     commit::Commit,
     fs::{
         write_object,
         read_object,
-        calc_relative_path,
+        write_blob_to_file,
     }
 };
 
@@ -55,6 +65,9 @@ pub struct Checkout {
 
     #[arg(help = "文件/目录名", required = false)]
     paths: Vec<String>,
+
+    #[arg(last = true, help = "`--` 之后的参数都是路径，不会被当成分支或提交")]
+    pathspec: Vec<String>,
 }
 
 impl Checkout {
@@ -63,98 +76,156 @@ impl Checkout {
             create_new_branch: false,
             branch_name_or_commit_hash: branch_name,
             paths,
+            pathspec: vec![],
         }
     }
 
-    pub fn read_tree(gitdir: &Path, hash: String) -> Result<Tree> {
-        read_object::<Tree>(gitdir.to_path_buf(), &hash)
+    pub fn read_tree(gitdir: &Path, hash: &ObjectId) -> Result<Tree> {
+        read_object::<Tree>(gitdir.to_path_buf(), hash)
             .map_err(|_| GitError::invalid_command(format!("failed to parse tree data for {}", hash)))
     }
 
-    pub fn read_blob(gitdir: &Path, hash: &str) -> Result<Blob> {
+    pub fn read_blob(gitdir: &Path, hash: &ObjectId) -> Result<Blob> {
         read_object::<Blob>(gitdir.to_path_buf(), hash)
             .map_err(|_| GitError::invalid_command(format!("failed to parse blob data for {}", hash)))
     }
 
-    pub fn read_commit(gitdir: &Path, hash: &str) -> Result<(Commit, Tree)> {
+    pub fn read_commit(gitdir: &Path, hash: &ObjectId) -> Result<(Commit, Tree)> {
         let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)
             .map_err(|_| GitError::invalid_command(format!("failed to parse commit data for {}", hash)))?;
 
-        let tree_hash = commit.tree_hash.clone();
-        Ok((commit, Self::read_tree(gitdir, tree_hash)?))
+        let tree = Self::read_tree(gitdir, &commit.tree_hash)?;
+        Ok((commit, tree))
     }
 
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
         Ok(Box::new(Checkout::try_parse_from(args)?))
     }
 
-    pub fn restore_workspace(gitdir: &PathBuf, commit_hash: &str) -> Result<()> {
-        let (_, tree) = Self::read_commit(gitdir, commit_hash)?;
+    /// move worktree and index from `current_commit` to `target_commit`, a two-way `read-tree -m -u`
+    /// local changes are carried over, unless the switch would overwrite them
+    pub fn switch_worktree(gitdir: &Path, current_commit: Option<&ObjectId>, target_commit: &ObjectId) -> Result<()> {
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let old = Index::read_or_default(gitdir)?;
+        let current_tree = match current_commit {
+            Some(current) => Some(Self::read_commit(gitdir, current)?.0.tree_hash),
+            None => None,
+        };
+        let (target, _) = Self::read_commit(gitdir, target_commit)?;
+
+        let trees = [current_tree.as_ref(), Some(&target.tree_hash)];
+        let TreeMerge { mut index, rejected } = ReadTree::unpack_trees(gitdir, &old, &trees, true)?;
+        if !rejected.is_empty() {
+            return Err(Self::rejection_error(&rejected));
+        }
+        ReadTree::checkout_index(gitdir, &old, &mut index)?;
+        lock.commit(&index)?;
+        Ok(())
+    }
+
+    /// git's wording when a branch switch would lose local changes
+    fn rejection_error(rejected: &[(Rejection, PathBuf)]) -> Box<dyn std::error::Error> {
+        let paths = |untracked: bool| rejected.iter()
+            .filter(|(rejection, _)| (*rejection == Rejection::Untracked) == untracked)
+            .map(|(_, path)| format!("\t{}\n", path.display()))
+            .collect::<String>();
+        let mut message = String::new();
+        let (changed, untracked) = (paths(false), paths(true));
+        if !changed.is_empty() {
+            message += &format!("error: Your local changes to the following files would be overwritten by checkout:\n{}Please commit your changes or stash them before you switch branches.\n", changed);
+        }
+        if !untracked.is_empty() {
+            message += &format!("error: The following untracked working tree files would be overwritten by checkout:\n{}Please move or remove them before you switch branches.\n", untracked);
+        }
+        GitError::merge_conflict(format!("{}Aborting", message))
+    }
+
+    /// what HEAD is on for the reflog: the branch name, or the commit when detached
+    pub(crate) fn head_description(gitdir: &Path) -> String {
+        current_branch(gitdir)
+            .or_else(|_| head_to_hash(gitdir).map(|hash| hash.to_hex()))
+            .unwrap_or_default()
+    }
 
-                // 获取当前 index
+    /// the worktree matches the index right now, remember the stat data for later checks
+    pub fn refresh_index_stat(gitdir: &Path) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
         let index_path = gitdir.join("index");
-        let index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
+        let lock = LockedIndexWriter::lock(&index_path)?;
+        let mut index = Index::new().read_from_file(&index_path)?;
+        index.refresh_stat(project_root);
+        lock.commit(&index)?;
+        Ok(())
+    }
 
-        // 删除 index 中记录的所有文件
-        for entry in &index.entries {
-            let file_path = gitdir.parent().unwrap().join(&entry.name);
-            if file_path.is_file() {
+    /// switch the worktree from `from_commit` (None for an unborn branch) to `to_commit`
+    /// files tracked in `from_commit` but absent in `to_commit` are removed, untracked files are left alone
+    pub fn restore_workspace(gitdir: &Path, from_commit: Option<&ObjectId>, to_commit: &ObjectId) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let (_, tree) = Self::read_commit(gitdir, to_commit)?;
+
+        let target_paths = tree
+            .flatten(gitdir)?
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect::<HashSet<_>>();
+        let current_paths = match from_commit {
+            Some(hash) => Self::read_commit(gitdir, hash)?.1
+                .flatten(gitdir)?
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let mut index = Index::read_or_default(gitdir)?;
+
+        for path in current_paths.iter().filter(|p| !target_paths.contains(*p)) {
+            let file_path = project_root.join(path);
+            if file_path.is_file() || file_path.is_symlink() {
                 fs::remove_file(&file_path).map_err(|_| {
                     GitError::failed_to_write_file(&file_path.to_string_lossy())
                 })?;
-            } else if file_path.is_dir() {
-                fs::remove_dir_all(&file_path).map_err(|_| {
-                    GitError::failed_to_write_file(&file_path.to_string_lossy())
-                })?;
-            }
-        }
-        Checkout::restore_tree(gitdir, &PathBuf::from("."), &tree)?;
-        Ok(())
-    }
-
-    fn extract_tree_hash(data: &[u8]) -> Option<String> {
-        let mut content = String::from_utf8_lossy(data).to_string();
-        content = content.replace("tree ", "\ntree ");
-        // //println!("content: {}", content);
-        for line in content.lines() {
-            if let Some(hash) = line.strip_prefix("tree ") {
-                return Some(hash.to_string()); // 提取 tree_hash
             }
+            remove_empty_parents(project_root, &file_path);
+            index.remove_entry(path);
         }
+        lock.commit(&index)?;
 
-        None 
+        Checkout::restore_tree(gitdir, project_root, &tree)?;
+        Ok(())
     }
 
-    fn restore_tree(gitdir: &PathBuf, base_path:&Path, tree: &Tree) -> Result<()> {
+    fn restore_tree(gitdir: &Path, base_path:&Path, tree: &Tree) -> Result<()> {
         for entry in &tree.0 {
             //println!("entry: {:?}", entry);
             let file_path = base_path.join(&entry.path);
+            // 写文件会跟随已有的符号链接，先把原来的文件或链接删掉
+            if file_path.is_symlink() || file_path.is_file() {
+                fs::remove_file(&file_path)
+                    .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+            }
 
             match entry.mode {
                 FileMode::Blob =>{
-                    let blob = Self::read_blob(gitdir, &entry.hash)?;
-                    let content: Vec<u8> = blob.into();
-                    //println!("content: {:?}", content);
-                    fs::write(&file_path, content)
-                        .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()))?;
+                    write_blob_to_file(gitdir, &entry.hash, &file_path)?;
+                    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o644))?;
                 },
                 FileMode::Exec =>{
+                    write_blob_to_file(gitdir, &entry.hash, &file_path)?;
+                    // 设置权限为 rwxr-xr-x (八进制表示)
+                    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755))?;
+                },
+                FileMode::Symbolic => {
                     let blob = Self::read_blob(gitdir, &entry.hash)?;
-                    let content: Vec<u8> = blob.into();
-                    let mut file = File::create(&file_path)?;
-                    file.write_all(&content)?;
-
-                    let mut permissions = file.metadata()?.permissions();
-                    permissions.set_mode(FileMode::Exec as u32); // 设置权限为 rwxr-xr-x (八进制表示)
-                    file.set_permissions(permissions)?;
-
+                    symlink(String::from_utf8_lossy(blob.as_bytes()).as_ref(), &file_path)
+                        .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()))?;
                 },
                 FileMode::Tree => {
                     fs::create_dir_all(&file_path)
                         .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()))?;
-                    let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
+                    let sub_tree = Self::read_tree(gitdir, &entry.hash)?;
                     Checkout::restore_tree(gitdir, &file_path, &sub_tree)?;
                 },
                 _ => {
@@ -173,7 +244,7 @@ impl Checkout {
         })?;
 
         
-        if let Some(entry) = index.entries.iter().find(|e| e.name == path.to_string_lossy()) {
+        if let Some(entry) = index.entries.iter().find(|e| e.name == path) {
             let blob = Self::read_blob(gitdir, &entry.hash)?;
             let content = Vec::<u8>::from(blob);
             return Ok(Some(content));
@@ -181,582 +252,176 @@ impl Checkout {
         Ok(None)
     }
 
-    fn is_workspace_modified(gitdir: &PathBuf) -> Result<bool> {
-        let index_path = gitdir.join("index");
-        let index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
-
-        // 遍历 index 中的所有条目
-        for entry in &index.entries {
-            let file_path = PathBuf::from(&entry.name);
-
-            // 检查工作区中是否存在对应的文件
-            if !file_path.exists() {
-                //println!("File deleted: {:?}", file_path);
-                return Ok(true); // 文件被删除
-            }
-
-            // 如果是文件（blob），计算文件哈希并比较
-            if entry.mode == 0o100644 {
-                let file_content = fs::read(&file_path).map_err(|_| {
-                    GitError::failed_to_read_file(&file_path.to_string_lossy())
-                })?;
-                let file_hash = hash_object::<Blob>(file_content)?;
-                if file_hash != entry.hash {
-                    //println!("File modified: {:?}", file_path);
-                    return Ok(true); // 文件内容不同
-                }
-            }
-
-            // 如果是目录（tree），递归检查子条目
-            if entry.mode == 0o40000 {
-                let tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                if Self::is_workspace_modified_for_tree(gitdir, &file_path, &tree)? {
-                    return Ok(true);
-                }
-            }
-        }
-
-        Ok(false) // 工作区和 index 一致
-    }
-
-    fn is_workspace_modified_for_tree(gitdir: &PathBuf, base_path: &Path, tree: &Tree) -> Result<bool> {
-        for entry in &tree.0 {
-            let file_path = base_path.join(&entry.path);
-
-            // 检查工作区中是否存在对应的文件
-            if !file_path.exists() {
-                //println!("File deleted: {:?}", file_path);
-                return Ok(true); // 文件被删除
-            }
-
-            // 如果是文件（blob）或可执行文件，计算文件哈希并比较
-            if entry.mode == FileMode::Blob || entry.mode == FileMode::Exec {
-                let file_content = fs::read(&file_path).map_err(|_| {
-                    GitError::failed_to_read_file(&file_path.to_string_lossy())
-                })?;
-                let file_hash = hash_object::<Blob>(file_content)?;
-                if file_hash != entry.hash {
-                    //println!("File modified: {:?}", file_path);
-                    return Ok(true); // 文件内容不同
-                }
-            }
-
-            // 如果是目录（tree），递归检查子条目
-            if entry.mode == FileMode::Tree {
-                let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                if Self::is_workspace_modified_for_tree(gitdir, &file_path, &sub_tree)? {
-                    return Ok(true);
-                }
-            }
-        }
-
-        Ok(false) // 工作区和 tree 一致
-    }
-    fn is_index_modified(gitdir: &Path, tree: &Tree) -> Result<bool> {
-        // 读取 index 文件
-        let index_path = gitdir.join("index");
-        let index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
-
-        // 递归检查 tree 和 index 是否一致
-        Self::is_index_modified_for_tree(gitdir, tree, &PathBuf::new(), &index)
-    }
-
-    fn is_index_modified_for_tree(gitdir: &Path, tree: &Tree, base_path: &Path, index: &Index) -> Result<bool> {
-        for entry in &tree.0 {
-            let entry_path = base_path.join(&entry.path);
-            
-            match entry.mode {
-                FileMode::Blob | FileMode::Exec => {
-                    // 对于文件，在 index 中查找对应条目
-                    if let Some(index_entry) = index.entries.iter().find(|e| e.name == entry_path.to_string_lossy()) {
-                        // 比较 tree 文件的哈希值与 index 中的哈希值
-                        if entry.hash != index_entry.hash {
-                            //println!("Index modified for file: {:?}", entry_path);
-                            return Ok(true); // 文件内容不同
-                        }
-                    } else {
-                        // 如果 tree 中的文件在 index 中不存在
-                        //println!("Index missing for file: {:?}", entry_path);
-                        return Ok(true); // 文件缺失
-                    }
-                }
-                FileMode::Tree => {
-                    // 对于目录，递归检查其内容
-                    let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                    if Self::is_index_modified_for_tree(gitdir, &sub_tree, &entry_path, index)? {
-                        //println!("Index modified for tree: {:?}", entry_path);
-                        return Ok(true);
-                    }
-                }
-                _ => {
-                    return Err(GitError::invalid_command(format!(
-                        "Unsupported file mode: {:?}",
-                        entry.mode
-                    )));
-                }
-            }
-        }
-
-        Ok(false) // 当前层级的 tree 和 index 一致
-    }
-
-    fn merge_tree_into_index_wrapper(gitdir: &Path, tree: &Tree, prefix: &Path) -> Result<()> {
-        let index_path = gitdir.join("index");
-        let mut index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
-
-        Checkout::merge_tree_into_index(gitdir, tree, prefix, &mut index)?;
-
-        index.write_to_file(&index_path).map_err(|_| {
-            GitError::failed_to_write_file(&index_path.to_string_lossy())
-        })?;
-
-        Ok(())
-    }
-
-    fn merge_tree_into_index(gitdir: &Path, tree: &Tree, prefix: &Path, index: &mut Index) -> Result<()> {
-
-        for entry in &tree.0 {
-            let entry_path = prefix.join(&entry.path); // 添加前缀到当前条目路径
-
-            if entry.mode == FileMode::Tree {
-                // 如果是子目录（tree），递归处理
-                let sub_tree = Checkout::read_tree(gitdir, entry.hash.clone())?;
-                Self::merge_tree_into_index(gitdir, &sub_tree, &entry_path, index)?; // 递归调用时传递当前路径作为前缀
-            } else if entry.mode == FileMode::Blob || entry.mode == FileMode::Exec {
-                // 如果是文件（blob或可执行文件），检查是否已存在于 index 中
-                if index.entries.iter().any(|e| e.name == entry_path.to_string_lossy()) {
-                    // 如果 index 中已存在该条目，则跳过
-                    continue;
-                }
-
-                // 如果 index 中不存在该条目，添加新的条目
-                index.entries.push(IndexEntry {
-                    name: entry_path.to_string_lossy().to_string(),
-                    mode: entry.mode as u32,
-                    hash: entry.hash.clone(),
-                });
-            } else {
-                // 如果是其他类型，返回错误
-                return Err(GitError::invalid_command(format!(
-                    "Unsupported file mode: {:?}",
-                    entry.mode
-                )));
-            }
-        }
-
-        Ok(())
-    }
-
-    fn merge_index_into_workspace(gitdir: &PathBuf) -> Result<()> {
-        let index_path = gitdir.join("index");
-        let index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
-
-        for entry in &index.entries {
-            let file_path = PathBuf::from(&entry.name);
-
-            match entry.mode {
-                0o100644 | 0o100755 => {
-                    // 如果是文件（blob）或可执行文件，处理文件内容
-                    if let Some(parent) = file_path.parent() {
-                    // 确保父目录存在
-                        fs::create_dir_all(parent).map_err(|_| {
-                            GitError::failed_to_write_file(&parent.to_string_lossy())
-                        })?;
-                    }
-                    if file_path.exists() {
-                        let file_content = fs::read(&file_path).map_err(|_| {
-                            GitError::failed_to_read_file(&file_path.to_string_lossy())
-                        })?;
-                        let file_hash = hash_object::<Blob>(file_content)?;
-                        if file_hash != entry.hash {
-                            //println!("Conflict in workspace for file: {:?}", file_path);
-                            continue;
-                        }
-                    }
-
-                    let blob = Self::read_blob(gitdir, &entry.hash)?;
-                    let content: Vec<u8> = Vec::from(blob);
-                    fs::write(&file_path, content).map_err(|_| {
-                        println!("Failed to write file");
-                        GitError::failed_to_write_file(&file_path.to_string_lossy())
-                    })?;
-                    
-                    // 如果是可执行文件，设置执行权限
-                    if entry.mode == 0o100755 {
-                        let mut permissions = fs::metadata(&file_path)?.permissions();
-                        permissions.set_mode(0o755);
-                        fs::set_permissions(&file_path, permissions)?;
-                    }
-                }
-                0o40000 => {
-                    // 如果是目录（tree），递归处理子条目
-                    if !file_path.exists() {
-                        fs::create_dir_all(&file_path).map_err(|_| {
-                            GitError::failed_to_write_file(&file_path.to_string_lossy())
-                        })?;
-                    }
-                    let tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                    Self::merge_index_into_workspace_for_tree(gitdir, &file_path, &tree)?;
-                }
-                _ => {
-                    return Err(GitError::invalid_command(format!(
-                        "Unsupported file mode: {:?}",
-                        entry.mode
-                    )));
-                }
-            }
-        }
-        Ok(())
-    }
-
-    fn merge_index_into_workspace_for_tree(gitdir: &PathBuf, base_path: &Path, tree: &Tree) -> Result<()> {
-        for entry in &tree.0 {
-            let file_path = base_path.join(&entry.path);
-
-            match entry.mode {
-                FileMode::Blob | FileMode::Exec => {
-                    // 如果是文件（blob）或可执行文件，处理文件内容
-                    if file_path.exists() {
-                        let file_content = fs::read(&file_path).map_err(|_| {
-                            GitError::failed_to_read_file(&file_path.to_string_lossy())
-                        })?;
-                        let file_hash = hash_object::<Blob>(file_content)?;
-                        if file_hash != entry.hash {
-                            //println!("Conflict in workspace for file: {:?}", file_path);
-                            continue;
-                        }
-                    }
-                    let blob = Self::read_blob(gitdir, &entry.hash)?;
-                    let content = Vec::<u8>::from(blob);
-                    fs::write(&file_path, content).map_err(|_| {
-                        GitError::failed_to_write_file(&file_path.to_string_lossy())
-                    })?;
-                    
-                    // 如果是可执行文件，设置执行权限
-                    if matches!(entry.mode, FileMode::Exec) {
-                        let mut permissions = fs::metadata(&file_path)?.permissions();
-                        permissions.set_mode(0o755);
-                        fs::set_permissions(&file_path, permissions)?;
-                    }
-                }
-                FileMode::Tree => {
-                    // 如果是目录（tree），递归处理子条目
-                    if !file_path.exists() {
-                        fs::create_dir_all(&file_path).map_err(|_| {
-                            GitError::failed_to_write_file(&file_path.to_string_lossy())
-                        })?;
-                    }
-
-                    let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                    Self::merge_index_into_workspace_for_tree(gitdir, &file_path, &sub_tree)?;
-                }
-                _ => {
-                    return Err(GitError::invalid_command(format!(
-                        "Unsupported file mode: {:?}",
-                        entry.mode
-                    )));
-                }
+    /// `pathspec` given where the command started, relative to the project root, `.` for all of it
+    pub(crate) fn normalize_pathspec(repo: &Repository, pathspec: &str) -> String {
+        let path = repo.prefix.join(pathspec);
+        let mut normalized = Vec::new();
+        for component in repo.work_tree().ok().and_then(|top| path.strip_prefix(top).ok()).unwrap_or(&path).components() {
+            match component {
+                Component::CurDir => {},
+                Component::ParentDir => { normalized.pop(); },
+                component => normalized.push(component.as_os_str().to_string_lossy()),
             }
         }
-        Ok(())
+        if normalized.is_empty() { ".".to_string() } else { normalized.join("/") }
     }
 
-
-    fn restore_from_index(gitdir: &PathBuf, paths: &[PathBuf]) -> Result<()> {
-        let index_path = gitdir.join("index");
-        let index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
-
-        for path in paths {
-            for entry in &index.entries {
-                let entry_path = PathBuf::from(&entry.name);
-                if entry_path.starts_with(path) {
-                    if entry.mode == 0o40000 {
-                        // 如果是目录，创建目录并递归恢复其内容
-                        fs::create_dir_all(&entry_path).map_err(|_| {
-                            GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                        })?;
-                        let tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                        Self::restore_from_index_for_tree(gitdir, &entry_path, &tree)?;
-                    } else if entry.mode == 0o100644 {
-                        // 如果是文件，恢复文件内容
-                        let blob = Self::read_blob(gitdir, &entry.hash)?;
-                        let content = Vec::<u8>::from(blob);
-                        fs::write(&entry_path, content).map_err(|_| {
-                            GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                        })?;
-                    }
-                    //println!("Restored: {:?}", entry_path);
-                }
-            }
-        }
-
-        Ok(())
+    /// `pathspec` selects `path` itself or a directory above it, literally or as a glob
+    pub(crate) fn pathspec_matches(pathspec: &str, path: &Path) -> bool {
+        pathspec == "." || path.ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| {
+                let dir = dir.as_os_str().as_bytes();
+                dir == pathspec.as_bytes() || glob_match(pathspec.as_bytes(), dir)
+            })
     }
 
-    fn restore_from_index_for_tree(gitdir: &PathBuf, base_path: &Path, tree: &Tree) -> Result<()> {
-        for entry in &tree.0 {
-            let entry_path = base_path.join(&entry.path);
-            if entry.mode == FileMode::Tree {
-                // 如果是目录，递归处理
-                fs::create_dir_all(&entry_path).map_err(|_| {
-                    GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                })?;
-                let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                Self::restore_from_index_for_tree(gitdir, &entry_path, &sub_tree)?;
-            } else if entry.mode == FileMode::Blob || entry.mode == FileMode::Exec {
-                // 如果是文件或可执行文件，恢复文件内容
-                let blob = Self::read_blob(gitdir, &entry.hash)?;
-                let content = Vec::<u8>::from(blob);
-                fs::write(&entry_path, content).map_err(|_| {
-                    GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                })?;
-                
-                // 如果是可执行文件，设置执行权限
-                if entry.mode == FileMode::Exec {
-                    let mut permissions = fs::metadata(&entry_path)?.permissions();
-                    permissions.set_mode(0o755);
-                    fs::set_permissions(&entry_path, permissions)?;
-                }
-            }
-            //println!("Restored: {:?}", entry_path);
+    /// overwrite the files selected by `pathspecs` with their version in the index,
+    /// or in `tree_hash` which updates the index as well
+    /// nothing is touched unless every pathspec matches something
+    fn restore_paths(gitdir: &Path, tree_hash: Option<&ObjectId>, pathspecs: &[String]) -> Result<()> {
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let mut index = Index::read_or_default(gitdir)?;
+        let candidates = match tree_hash {
+            Some(tree_hash) => flatten_tree(gitdir, tree_hash)?
+                .into_iter()
+                .map(|(path, (mode, hash))| IndexEntry::new(mode, hash, path))
+                .collect::<Vec<_>>(),
+            None => index.entries.clone(),
+        };
+
+        let unmatched = pathspecs.iter()
+            .filter(|pathspec| !candidates.iter().any(|entry| Self::pathspec_matches(pathspec, &entry.name)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !unmatched.is_empty() {
+            return Err(GitError::pathspec_not_matched(unmatched));
         }
-        Ok(())
-    }
-
-    fn restore_from_commit(gitdir: &PathBuf, commit_hash: &str, paths: &[PathBuf]) -> Result<()> {
-        let (_, tree) = Self::read_commit(gitdir, commit_hash)?;
-
-        for path in paths {
-            Self::restore_path_from_tree(gitdir, path, &tree, PathBuf::new())?;
-        }
-
-        Ok(())
-    }
-
-    fn restore_path_from_tree(
-        gitdir: &PathBuf,
-        path: &Path,
-        tree: &Tree,
-        base_path: PathBuf,
-    ) -> Result<()> {
-        if let Some(first_component) = path.components().next() {
-            let first_component = first_component.as_os_str();
-            let remaining_path = path.strip_prefix(first_component).unwrap_or(path);
-
-            for entry in &tree.0 {
-                let entry_path = base_path.join(&entry.path);
-
-                if entry.path == first_component {
-                    if remaining_path.as_os_str().is_empty() {
-                        // 完全匹配路径
-                        if entry.mode == FileMode::Tree {
-                            // 恢复整个目录
-                            fs::create_dir_all(&entry_path).map_err(|_| {
-                                GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                            })?;
-                            let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                            Self::restore_from_commit_for_tree(gitdir, &entry_path, &sub_tree)?;
-                        } else if entry.mode == FileMode::Blob || entry.mode == FileMode::Exec {
-                            // 恢复文件或可执行文件
-                            let blob = Self::read_blob(gitdir, &entry.hash)?;
-                            let content = Vec::<u8>::from(blob);
-                            fs::write(&entry_path, content).map_err(|_| {
-                                GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                            })?;
-                            
-                            // 如果是可执行文件，设置执行权限
-                            if entry.mode == FileMode::Exec {
-                                let mut permissions = fs::metadata(&entry_path)?.permissions();
-                                permissions.set_mode(0o755);
-                                fs::set_permissions(&entry_path, permissions)?;
-                            }
-                        }
-
-                        // 更新 index
-                        Self::update_index(gitdir, &entry_path, entry)?;
-                        //println!("Restored: {:?}", entry_path);
-                    } else if entry.mode == FileMode::Tree {
-                        // 递归处理子目录
-                        let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                        Self::restore_path_from_tree(gitdir, &PathBuf::from(remaining_path), &sub_tree, entry_path)?;
-                    }
-                }
-            }
+        let selected = candidates.into_iter()
+            .filter(|entry| pathspecs.iter().any(|pathspec| Self::pathspec_matches(pathspec, &entry.name)))
+            .collect::<Vec<_>>();
+        if let Some(entry) = selected.iter().find(|entry| entry.stage != 0) {
+            return Err(GitError::merge_conflict(format!("error: path '{}' is unmerged", entry.name.display())));
         }
 
-        Ok(())
-    }
-
-    fn restore_from_commit_for_tree(gitdir: &PathBuf, base_path: &Path, tree: &Tree) -> Result<()> {
-        for entry in &tree.0 {
-            let entry_path = base_path.join(&entry.path);
-            if entry.mode == FileMode::Tree {
-                // 如果是目录，递归处理
-                fs::create_dir_all(&entry_path).map_err(|_| {
-                    GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                })?;
-                let sub_tree = Self::read_tree(gitdir, entry.hash.clone())?;
-                Self::restore_from_commit_for_tree(gitdir, &entry_path, &sub_tree)?;
-            } else if entry.mode == FileMode::Blob || entry.mode == FileMode::Exec {
-                // 如果是文件或可执行文件，恢复文件内容
-                let blob = Self::read_blob(gitdir, &entry.hash)?;
-                let content = Vec::<u8>::from(blob);
-                fs::write(&entry_path, content).map_err(|_| {
-                    GitError::failed_to_write_file(&entry_path.to_string_lossy())
-                })?;
-                
-                // 如果是可执行文件，设置执行权限
-                if entry.mode == FileMode::Exec {
-                    let mut permissions = fs::metadata(&entry_path)?.permissions();
-                    permissions.set_mode(0o755);
-                    fs::set_permissions(&entry_path, permissions)?;
-                }
+        for mut entry in selected {
+            if entry.mode != FileMode::Commit as u32 {
+                ReadTree::checkout_entry(gitdir, &mut entry)?;
             }
-            //println!("Restored: {:?}", entry_path);
+            index.add_entry(entry);
         }
-        Ok(())
-    }
-
-    fn update_index(gitdir: &Path, entry_path: &Path, entry: &TreeEntry) -> Result<()> {
-        let index_path = gitdir.join("index");
-        let mut index = Index::new().read_from_file(&index_path).map_err(|_| {
-            GitError::failed_to_read_file(&index_path.to_string_lossy())
-        })?;
-
-        let existing_entry = index.entries.iter_mut().find(|e| e.name == entry_path.to_string_lossy());
-        if let Some(existing_entry) = existing_entry {
-            // 如果存在同名条目，更新条目
-            existing_entry.mode = entry.mode as u32;
-            existing_entry.hash = entry.hash.clone();
-        } else {
-            // 如果不存在同名条目，新增条目
-            index.entries.push(IndexEntry {
-                name: entry_path.to_string_lossy().to_string(),
-                mode: entry.mode as u32,
-                hash: entry.hash.clone(),
-            });
-        }
-
-        index.write_to_file(&index_path).map_err(|_| {
-            GitError::failed_to_write_file(&index_path.to_string_lossy())
-        })?;
-
+        lock.commit(&index)?;
         Ok(())
     }
 
 }
 
 impl SubCommand for Checkout {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = repo.gitdir.clone();
         //let mut paths: Vec<PathBuf> = self.paths.iter().map(PathBuf::from).collect();
-        let project_root = gitdir.parent().expect("failed to find git dir implementation"). to_path_buf();
-        let mut paths: Vec<PathBuf> = self.paths.iter()
-            .map(|p| calc_relative_path(&project_root, p))
-            .collect::<Result<Vec<_>>>()?; 
+        if self.create_new_branch && !self.pathspec.is_empty() {
+            return Err(GitError::invalid_command(format!("Cannot update paths and switch to branch '{}' at the same time.",
+                self.branch_name_or_commit_hash.as_deref().unwrap_or_default())));
+        }
+        // after `--` there are only paths, and what comes before it has to be a tree-ish
+        if !self.pathspec.is_empty() {
+            let pathspecs = self.paths.iter()
+                .chain(&self.pathspec)
+                .map(|pathspec| Self::normalize_pathspec(&repo, pathspec))
+                .collect::<Vec<_>>();
+            let tree_hash = match &self.branch_name_or_commit_hash {
+                Some(rev) => Some(resolve_tree(&gitdir, rev)
+                    .map_err(|_| GitError::invalid_command(format!("invalid reference: {}", rev)))?),
+                None => None,
+            };
+            Self::restore_paths(&gitdir, tree_hash.as_ref(), &pathspecs)?;
+            return Ok(0);
+        }
+        // with -b the only extra argument is the start point, not a path
+        let mut paths = self.paths.iter()
+            .filter(|_| !self.create_new_branch)
+            .map(|pathspec| Self::normalize_pathspec(&repo, pathspec))
+            .collect::<Vec<_>>();
         //println!("create_new_branch: {:?}", self.create_new_branch);
         //println!("branch_name_or_commit_hash: {:?}", self.branch_name_or_commit_hash);
         //println!("paths: {:?}", self.paths);
         if let Some(ref commit_or_branch) = self.branch_name_or_commit_hash {
-            if commit_or_branch == "HEAD" || commit_or_branch.len() == 40 {
+            let is_branch = commit_or_branch.starts_with("refs/")
+                || ref_exists(&gitdir, &format!("refs/heads/{}", commit_or_branch));
+            let commit_hash = if self.create_new_branch || is_branch {
+                None
+            } else {
+                rev_parse(&gitdir, commit_or_branch).ok()
+            };
+            if let Some(commit_hash) = commit_hash {
                 // println!("checkout from commit {}", commit_or_branch);
-                let commit_hash = if commit_or_branch == "HEAD" {
-                    read_ref_commit(&gitdir, &read_head_ref(&gitdir)?)?
-                } else {
-                    commit_or_branch.clone()
-                };
-                Checkout::restore_from_commit(&gitdir, &commit_hash, &paths)?;
-                write_head_commit(&gitdir, &commit_hash)?;
+                if !paths.is_empty() {
+                    // checkout <commit> -- <paths> only restores files, HEAD stays where it is
+                    let (commit, _) = Self::read_commit(&gitdir, &commit_hash)?;
+                    Checkout::restore_paths(&gitdir, Some(&commit.tree_hash), &paths)?;
+                }
+                else if commit_or_branch != "HEAD" {
+                    // detach HEAD at the given commit
+                    let current_commit = head_to_hash(&gitdir).ok();
+                    let reflog_message = format!("checkout: moving from {} to {}", Self::head_description(&gitdir), commit_or_branch);
+                    Checkout::switch_worktree(&gitdir, current_commit.as_ref(), &commit_hash)?;
+                    write_head_commit_with_log(&gitdir, &commit_hash, &reflog_message)?;
+                    println!("HEAD is now at {}", &commit_hash.to_hex()[..7]);
+                }
+                return Ok(0);
             }
             else {
                 // 切换分支逻辑
-                let (branch_path, ref_path) = if commit_or_branch.starts_with("refs/") {
+                let ref_path = if commit_or_branch.starts_with("refs/") {
                     // 如果是完整引用路径，直接使用
-                    let branch_path = gitdir.join(commit_or_branch);
-                    (branch_path, commit_or_branch.to_string())
+                    commit_or_branch.to_string()
                 } else {
                     // 如果是简单分支名，添加 refs/heads/ 前缀
-                    let heads_dir = gitdir.join("refs/heads");
-                    let branch_path = heads_dir.join(commit_or_branch);
-                    (branch_path, format!("refs/heads/{}", commit_or_branch))
+                    format!("refs/heads/{}", commit_or_branch)
                 };
 
                 if self.create_new_branch {
-                    if branch_path.exists() {
-                        return Err(GitError::invalid_command(format!("branch '{}' already exists", commit_or_branch)));
+                    // checkout -b <branch> [<start-point>]，再多的参数 git 会当成路径
+                    if self.paths.len() > 1 {
+                        return Err(GitError::invalid_command(format!("Cannot update paths and switch to branch '{}' at the same time.", commit_or_branch)));
                     }
-                    let head_ref = read_head_ref(&gitdir)?;
-                    let head_ref_path = gitdir.join(&head_ref);
-                    if head_ref_path.exists() {
-                        let commit_hash = read_ref_commit(&gitdir, &head_ref)?;
-                        // 确保父目录存在
-                        if let Some(parent) = branch_path.parent() {
-                            fs::create_dir_all(parent)?;
+                    let start_point = self.paths.first().map(String::as_str);
+                    let start_hash = Branch::new_branch_commit(&gitdir, commit_or_branch, start_point)?;
+                    let reflog_message = format!("checkout: moving from {} to {}", Self::head_description(&gitdir), commit_or_branch);
+                    // 在未提交过的分支上只需要切换 HEAD
+                    if let Some(start_hash) = start_hash {
+                        // 先切换工作区，本地修改会被覆盖时分支不会被创建
+                        if start_point.is_some() {
+                            let current_commit = head_to_hash(&gitdir).ok();
+                            Checkout::switch_worktree(&gitdir, current_commit.as_ref(), &start_hash)?;
                         }
-                        fs::write(&branch_path, format!("{}\n", commit_hash))
-                            .map_err(|_| GitError::failed_to_write_file(&branch_path.to_string_lossy()))?;
+                        Branch::create_at(&gitdir, commit_or_branch, start_point, &start_hash)?;
                     }
-                    write_head_ref(&gitdir, &ref_path)?;
+                    write_head_ref_with_log(&gitdir, &ref_path, &reflog_message)?;
                     return Ok(0);
 
-                } else if !branch_path.exists() {
-                    paths.push(PathBuf::from(commit_or_branch));
+                } else if !ref_exists(&gitdir, &ref_path) {
+                    paths.push(Self::normalize_pathspec(&repo, commit_or_branch));
                 } else {
-                    let current_ref = read_head_ref(&gitdir)?;
-                    if ref_path == current_ref {
+                    if read_head_ref(&gitdir).ok().as_deref() == Some(ref_path.as_str()) {
                         return Err(GitError::invalid_command(format!("already on branch '{}'", commit_or_branch)));
                     }
 
-                    let current_commit_hash = read_ref_commit(&gitdir, &current_ref)?;
-                    //println!("Current commit hash: {}", current_commit_hash);
-                    let (_, tree) = Self::read_commit(&gitdir, &current_commit_hash)?;
-
-                    let workspace_modified = Self::is_workspace_modified(&gitdir)?;
-                    let index_modified = Self::is_index_modified(&gitdir, &tree)?;
-                    //println!("Workspace modified: {}, Index modified: {}", workspace_modified, index_modified);
-
-                    if !workspace_modified && !index_modified {
-                        let commit_hash = read_ref_commit(&gitdir, &ref_path)?;
-                        
-                        write_head_ref(&gitdir, &ref_path)?;
-                        let tree_hash = {
-                            let commit_path = gitdir.join("objects").join(&commit_hash[0..2]).join(&commit_hash[2..]);
-                            let decompressed = decompress_file_bytes(&commit_path)?;
-                            Checkout::extract_tree_hash(&decompressed)
-                                .ok_or_else(|| GitError::invalid_command(format!("commit {} does not contain a tree", commit_hash)))?
-                        };
-                        Checkout::restore_workspace(&gitdir, &commit_hash)?;
-                        
-                        let read_tree = ReadTree {
-                            prefix: None,
-                            tree_hash: tree_hash.clone(),
-                        };
-                        read_tree.run(Ok(gitdir.clone()))?;
-                        return Ok(0);
-                    }
-
+                    // HEAD may be detached, so go through head_to_hash rather than the branch ref
+                    let current_commit = head_to_hash(&gitdir).ok();
                     let next_commit_hash = read_ref_commit(&gitdir, &ref_path)?;
-                    let (_, nexttree) = Self::read_commit(&gitdir, &next_commit_hash)?;
-                    Checkout::merge_tree_into_index_wrapper(&gitdir, &nexttree, Path::new(""))?;
-                    Checkout::merge_index_into_workspace(&gitdir)?;
-                    write_head_ref(&gitdir, &ref_path)?;
+                    let reflog_message = format!("checkout: moving from {} to {}", Self::head_description(&gitdir), commit_or_branch);
+                    Checkout::switch_worktree(&gitdir, current_commit.as_ref(), &next_commit_hash)?;
+                    write_head_ref_with_log(&gitdir, &ref_path, &reflog_message)?;
                     return Ok(0);
                 }
             }
         }
         if !paths.is_empty(){
             //只指定文件路径/目录
-            Checkout::restore_from_index(&gitdir, &paths)?;
+            Checkout::restore_paths(&gitdir, None, &paths)?;
         } else {
             return Err(GitError::invalid_command("no commit hash or branch name provided".to_string()));
         }
@@ -772,7 +437,7 @@ impl SubCommand for Checkout {
 mod test {
     use crate::utils::test::{
         shell_spawn, setup_test_git_dir,
-        tempdir,
+        tempdir, cmd_seq, cp_dir, run_both, ArgsList,
     };
     use super::*;
 
@@ -884,6 +549,413 @@ mod test {
         assert_eq!(content_a, "A1");
     }
 
+    #[test]
+    fn test_switch_removes_files_missing_in_target() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        std::fs::write(temp_path1.join("a.txt"), "a").unwrap();
+        std::fs::create_dir_all(temp_path1.join("dir")).unwrap();
+        std::fs::write(temp_path1.join("extra.txt"), "extra").unwrap();
+        std::fs::write(temp_path1.join("dir/extra2.txt"), "extra2").unwrap();
+
+        let setup_cmds: ArgsList = &[
+            (&["add", "a.txt"], false),
+            (&["commit", "-m", "master"], false),
+            (&["checkout", "-b", "extra"], false),
+            (&["add", "extra.txt", "dir/extra2.txt"], false),
+            (&["commit", "-m", "extra"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        cmd_seq(setup_cmds)(git).unwrap();
+        // an untracked file must survive every switch
+        std::fs::write(temp_path1.join("untracked.txt"), "keep me").unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        for branch in ["master", "extra", "master"] {
+            let cmds: ArgsList = &[(&["checkout", branch], false)];
+            let _ = run_both(cmds, git, cargo).unwrap();
+
+            let origin = shell_spawn(&["ls", "-R", temp_path_str1]).unwrap();
+            let real = shell_spawn(&["ls", "-R", temp_path_str2]).unwrap();
+            assert_eq!(origin.replace(temp_path_str1, ""), real.replace(temp_path_str2, ""));
+
+            let origin = shell_spawn(&["git", "-C", temp_path_str1, "ls-files", "--stage"]).unwrap();
+            let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
+            assert_eq!(origin, real);
+        }
+    }
+
+    #[test]
+    fn test_switch_with_local_changes() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        std::fs::write(temp_path1.join("same.txt"), "same").unwrap();
+        std::fs::write(temp_path1.join("differs.txt"), "master").unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", "same.txt", "differs.txt"], false),
+            (&["commit", "-m", "master"], false),
+            (&["checkout", "-b", "other"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        cmd_seq(setup_cmds)(git).unwrap();
+        std::fs::write(temp_path1.join("differs.txt"), "other").unwrap();
+        std::fs::write(temp_path1.join("new.txt"), "new").unwrap();
+        cmd_seq(&[(&["add", "differs.txt", "new.txt"], false), (&["commit", "-m", "other"], false), (&["checkout", "master"], false)])(git).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        // a change to a path both branches agree on is carried over, staged or not
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        for path in [temp_path1, temp_path2] {
+            std::fs::write(path.join("same.txt"), "local").unwrap();
+        }
+        let _ = run_both(&[(&["checkout", "other"], false)], git, cargo).unwrap();
+        for args in [&["status", "--porcelain"][..], &["ls-files", "--stage"]] {
+            let origin = shell_spawn(&[&git[..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        assert_eq!(std::fs::read_to_string(temp_path2.join("same.txt")).unwrap(), "local");
+
+        // a change to a path the switch replaces stops it, nothing is touched
+        std::fs::write(temp_path2.join("differs.txt"), "local").unwrap();
+        assert!(shell_spawn(&[&cargo[..], &["checkout", "master"]].concat()).is_err());
+        assert_eq!(std::fs::read_to_string(temp_path2.join("differs.txt")).unwrap(), "local");
+        assert!(temp_path2.join("new.txt").exists());
+        let head = shell_spawn(&["git", "-C", temp_path_str2, "symbolic-ref", "HEAD"]).unwrap();
+        assert_eq!(head, "refs/heads/other\n");
+
+        // so does an untracked file in the way of a new one
+        shell_spawn(&["git", "-C", temp_path_str2, "checkout", "differs.txt"]).unwrap();
+        shell_spawn(&[&cargo[..], &["checkout", "master"]].concat()).unwrap();
+        std::fs::write(temp_path2.join("new.txt"), "untracked").unwrap();
+        assert!(shell_spawn(&[&cargo[..], &["checkout", "other"]].concat()).is_err());
+        assert_eq!(std::fs::read_to_string(temp_path2.join("new.txt")).unwrap(), "untracked");
+    }
+
+    #[test]
+    fn test_detached_head() {
+        let repo = setup_test_git_dir();
+        let repo_str = repo.path().to_str().unwrap();
+        let file_path = repo.path().join("foo.txt");
+
+        std::fs::write(&file_path, "v1").unwrap();
+        shell_spawn(&["git", "-C", repo_str, "add", "foo.txt"]).unwrap();
+        shell_spawn(&["git", "-C", repo_str, "commit", "-m", "c1"]).unwrap();
+        std::fs::write(&file_path, "v2").unwrap();
+        std::fs::write(repo.path().join("bar.txt"), "bar").unwrap();
+        shell_spawn(&["git", "-C", repo_str, "add", "foo.txt", "bar.txt"]).unwrap();
+        shell_spawn(&["git", "-C", repo_str, "commit", "-m", "c2"]).unwrap();
+
+        let commit1 = shell_spawn(&["git", "-C", repo_str, "rev-parse", "HEAD~1"]).unwrap().trim().to_string();
+        let cargo = |args: &[&str]| {
+            let mut cmd = vec!["cargo", "run", "--quiet", "--", "-C", repo_str];
+            cmd.extend_from_slice(args);
+            shell_spawn(&cmd)
+        };
+
+        cargo(&["checkout", &commit1]).unwrap();
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "v1");
+        assert!(!repo.path().join("bar.txt").exists());
+        let head = std::fs::read_to_string(repo.path().join(".git/HEAD")).unwrap();
+        assert_eq!(head.trim(), commit1);
+        assert_eq!(shell_spawn(&["git", "-C", repo_str, "status", "--porcelain"]).unwrap(), "");
+        assert!(cargo(&["commit", "-m", "detached"]).is_err());
+
+        cargo(&["checkout", "master"]).unwrap();
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "v2");
+        assert_eq!(std::fs::read_to_string(repo.path().join("bar.txt")).unwrap(), "bar");
+        let head = shell_spawn(&["git", "-C", repo_str, "symbolic-ref", "HEAD"]).unwrap();
+        assert_eq!(head.trim(), "refs/heads/master");
+    }
+
+    #[test]
+    fn test_checkout_pathspec() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let files = ["a.txt", "ab.md", "other", "src/main.rs", "src/lib.rs", "src/nested/deep.txt", "docs/x.md"];
+        let write_all = |version: &str| for name in files {
+            let path = temp_path1.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, format!("{} {}", name, version)).unwrap();
+        };
+        // a file and a branch both called `other`
+        write_all("v1");
+        shell_spawn(&["git", "-C", temp_path_str1, "add", "."]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "commit", "-m", "v1"]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "branch", "other"]).unwrap();
+        write_all("v2");
+        shell_spawn(&["git", "-C", temp_path_str1, "commit", "-am", "v2"]).unwrap();
+        write_all("v3");
+        std::fs::remove_file(temp_path1.join("docs/x.md")).unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let git = &["git", "-C", temp_path_str1];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        let cmds: ArgsList = &[
+            (&["checkout", "--", "other"], false),
+            (&["checkout", "HEAD~1", "--", "src/*.rs"], false),
+            (&["checkout", "--", "a?txt", "src/nested"], false),
+            (&["checkout", "HEAD", "--", "docs"], false),
+        ];
+        let _ = run_both(cmds, git, cargo).unwrap();
+        let state = |path: &str| ["status --porcelain", "symbolic-ref HEAD"]
+            .map(|cmd| shell_spawn(&[&["git", "-C", path][..], &cmd.split(' ').collect::<Vec<_>>()].concat()).unwrap());
+        assert_eq!(state(temp_path_str1), state(temp_path_str2));
+        for name in files {
+            let origin = std::fs::read_to_string(temp_path1.join(name)).unwrap();
+            let real = std::fs::read_to_string(temp_path2.join(name)).unwrap();
+            assert_eq!(origin, real, "{}", name);
+        }
+        assert_eq!(std::fs::read_to_string(temp_path2.join("src/lib.rs")).unwrap(), "src/lib.rs v1");
+        assert_eq!(std::fs::read_to_string(temp_path2.join("ab.md")).unwrap(), "ab.md v3");
+
+        // one pathspec without a match and nothing is restored
+        let output = std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", temp_path_str2, "checkout", "--", "ab.md", "nope*"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stderr).trim(), "error: pathspec 'nope*' did not match any file(s) known to git");
+        assert_eq!(std::fs::read_to_string(temp_path2.join("ab.md")).unwrap(), "ab.md v3");
+        assert!(shell_spawn(&[&cargo[..], &["checkout", "nope"]].concat()).is_err());
+        assert!(shell_spawn(&[&cargo[..], &["checkout", "nope", "--", "ab.md"]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str1][..], args].concat());
+        std::fs::create_dir(temp_path1.join("dir")).unwrap();
+        std::fs::write(temp_path1.join("dir/target.txt"), "target").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "plain"]);
+        git(&["branch", "plain"]);
+        // relative links to a file and to a directory, and one pointing nowhere
+        symlink("dir/target.txt", temp_path1.join("link")).unwrap();
+        symlink("../dir", temp_path1.join("dir/up")).unwrap();
+        symlink("missing", temp_path1.join("dangling")).unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "links"]);
+        let links = ["link", "dir/up", "dangling"];
+        let readlink = |name: &str| std::fs::read_link(temp_path1.join(name)).unwrap();
+        let targets = links.map(readlink);
+
+        cargo(&["checkout", "plain"]).unwrap();
+        assert!(links.iter().all(|name| std::fs::symlink_metadata(temp_path1.join(name)).is_err()));
+        cargo(&["checkout", "master"]).unwrap();
+        assert_eq!(links.map(readlink), targets);
+        assert_eq!(git(&["status", "--porcelain"]), "");
+
+        // a regular file in place of a link is replaced by the link again, the target is untouched
+        std::fs::remove_file(temp_path1.join("link")).unwrap();
+        std::fs::write(temp_path1.join("link"), "not a link").unwrap();
+        cargo(&["checkout", "--", "link"]).unwrap();
+        assert_eq!(readlink("link"), targets[0]);
+        assert_eq!(std::fs::read_to_string(temp_path1.join("dir/target.txt")).unwrap(), "target");
+
+        // retargeted links are staged as links, never by following them
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+        for temp_path in [temp_path1, temp_path2] {
+            std::fs::remove_file(temp_path.join("link")).unwrap();
+            symlink("dir", temp_path.join("link")).unwrap();
+            symlink("link/target.txt", temp_path.join("new")).unwrap();
+        }
+        cargo(&["add", "link", "new", "dir"]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str2, "add", "link", "new", "dir"]).unwrap();
+        assert_eq!(git(&["ls-files", "-s"]), shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "-s"]).unwrap());
+        assert_eq!(git(&["status", "--porcelain"]), "M  link\nA  new\n");
+        // an unchanged link is removed without -f, what it points at stays
+        cargo(&["rm", "dir/up"]).unwrap();
+        assert!(!temp_path1.join("dir/up").is_symlink() && temp_path1.join("dir/target.txt").exists());
+    }
+
+    #[test]
+    fn test_exec_bit() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| std::process::Command::new("cargo")
+            .args([&["run", "--quiet", "--", "-C", temp_path_str][..], args].concat())
+            .output()
+            .unwrap();
+        let script = temp_path.join("run.sh");
+        let chmod = |mode: u32| std::fs::set_permissions(&script, std::fs::Permissions::from_mode(mode)).unwrap();
+        let mode = || std::fs::metadata(&script).unwrap().permissions().mode() & 0o777;
+        std::fs::write(&script, "#!/bin/sh\necho hi\n").unwrap();
+        chmod(0o644);
+        git(&["add", "run.sh"]).unwrap();
+        git(&["commit", "-m", "plain"]).unwrap();
+        chmod(0o755);
+        git(&["commit", "-am", "exec"]).unwrap();
+
+        // only the bit changed: the diff has the mode lines and no hunk
+        let origin = git(&["show", "HEAD"]).unwrap();
+        assert!(origin.contains("old mode 100644\nnew mode 100755\n"));
+        assert_eq!(String::from_utf8_lossy(&cargo(&["show", "HEAD"]).stdout), origin);
+
+        // losing the bit is a local modification, same content or not
+        chmod(0o644);
+        let output = cargo(&["rm", "run.sh"]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("has local modifications"));
+        assert!(!cargo(&["checkout", "HEAD~1"]).status.success());
+        assert!(cargo(&["checkout", "--", "run.sh"]).status.success());
+        assert_eq!(mode(), 0o755);
+        assert_eq!(git(&["status", "--porcelain"]).unwrap(), "");
+
+        // switching commits and resetting set the bit from the tree entry
+        assert!(cargo(&["checkout", "HEAD~1"]).status.success());
+        assert_eq!(mode(), 0o644);
+        assert!(cargo(&["checkout", "master"]).status.success());
+        assert_eq!(mode(), 0o755);
+        assert!(cargo(&["reset", "--hard", "HEAD~1"]).status.success());
+        assert_eq!(mode(), 0o644);
+        assert_eq!(git(&["status", "--porcelain"]).unwrap(), "");
+    }
+
+    #[test]
+    fn test_non_utf8_and_spaced_names() {
+        use std::ffi::OsStr;
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+        // café.txt 用 Latin-1 编码，不是合法的 utf-8
+        let latin1 = temp_path.join(OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&latin1, "latin-1").unwrap();
+        std::fs::create_dir_all(temp_path.join("sub dir")).unwrap();
+        std::fs::write(temp_path.join("sub dir/with space.txt"), "spaces").unwrap();
+        std::fs::write(temp_path.join("sub dir/tab\there"), "tab").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "odd names"]);
+        git(&["rm", "-rq", "."]);
+        git(&["commit", "-m", "gone"]);
+
+        cargo(&["checkout", "HEAD~1"]).unwrap();
+        assert_eq!(std::fs::read(&latin1).unwrap(), b"latin-1");
+        assert_eq!(std::fs::read(temp_path.join("sub dir/with space.txt")).unwrap(), b"spaces");
+        assert_eq!(git(&["status", "--porcelain"]), "");
+        // the index we wrote reads back the same in git, names byte for byte
+        assert_eq!(git(&["ls-files", "-s"]), cargo(&["ls-files", "-s"]).unwrap());
+        assert_eq!(git(&["write-tree"]), cargo(&["write-tree"]).unwrap());
+        for args in [&["ls-tree", "HEAD"][..], &["ls-tree", "-r", "HEAD"], &["ls-tree", "-r", "--name-only", "HEAD"]] {
+            assert_eq!(git(args), cargo(args).unwrap());
+        }
+        assert!(git(&["ls-files"]).contains("\"caf\\351.txt\""));
+
+        cargo(&["checkout", "master"]).unwrap();
+        assert!(!latin1.exists());
+        assert!(!temp_path.join("sub dir").exists());
+        assert_eq!(git(&["status", "--porcelain"]), "");
+    }
+
+    #[test]
+    fn test_new_branch_from_start_point() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+        std::fs::write(temp_path.join("a.txt"), "v1").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["commit", "-m", "v1"]);
+        git(&["tag", "-a", "v1", "-m", "release v1"]);
+        let v1 = git(&["rev-parse", "HEAD"]);
+        std::fs::write(temp_path.join("a.txt"), "v2").unwrap();
+        git(&["commit", "-am", "v2"]);
+        let v2 = git(&["rev-parse", "HEAD"]);
+
+        // an annotated tag is peeled to its commit, the worktree follows
+        cargo(&["checkout", "-b", "fix", "v1"]).unwrap();
+        assert_eq!(git(&["symbolic-ref", "HEAD"]), "refs/heads/fix\n");
+        assert_eq!(git(&["rev-parse", "fix"]), v1);
+        assert_eq!(std::fs::read_to_string(temp_path.join("a.txt")).unwrap(), "v1");
+        assert_eq!(git(&["status", "--porcelain"]), "");
+        assert_eq!(git(&["reflog", "-1", "--format=%gs", "fix"]), "branch: Created from v1\n");
+
+        // branch names with slashes, from a commit hash and from HEAD~1
+        cargo(&["checkout", "-b", "feature/x", v2.trim()]).unwrap();
+        assert_eq!(git(&["rev-parse", "HEAD"]), v2);
+        assert_eq!(std::fs::read_to_string(temp_path.join("a.txt")).unwrap(), "v2");
+        cargo(&["branch", "old/base", "HEAD~1"]).unwrap();
+        assert_eq!(git(&["rev-parse", "old/base"]), v1);
+        assert_eq!(git(&["symbolic-ref", "HEAD"]), "refs/heads/feature/x\n");
+        cargo(&["branch", "from-tag", "v1"]).unwrap();
+        assert_eq!(git(&["rev-parse", "from-tag"]), v1);
+
+        // refused like git, and nothing is written
+        assert!(cargo(&["checkout", "-b", "nope", "no-such-rev"]).is_err());
+        assert!(!temp_path.join(".git/refs/heads/nope").exists());
+        assert!(cargo(&["checkout", "-b", "fix", "master"]).is_err());
+        assert!(cargo(&["branch", "bad..name"]).is_err());
+        assert!(cargo(&["checkout", "-b", "two", "master", "a.txt"]).is_err());
+        assert_eq!(git(&["symbolic-ref", "HEAD"]), "refs/heads/feature/x\n");
+        assert_eq!(git(&["rev-parse", "fix"]), v1);
+    }
+
+    #[test]
+    fn test_nested_new_files() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+        std::fs::write(temp_path.join("top.txt"), "top").unwrap();
+        git(&["add", "top.txt"]);
+        git(&["commit", "-m", "top"]);
+        git(&["checkout", "-q", "-b", "side"]);
+        std::fs::create_dir_all(temp_path.join("a/b/c")).unwrap();
+        std::fs::write(temp_path.join("a/b/c/d.txt"), "deep").unwrap();
+        git(&["add", "a"]);
+        git(&["commit", "-m", "deep"]);
+        git(&["checkout", "-q", "master"]);
+        assert!(!temp_path.join("a").exists());
+
+        // none of a/b/c exists on master
+        cargo(&["checkout", "side"]).unwrap();
+        assert_eq!(std::fs::read_to_string(temp_path.join("a/b/c/d.txt")).unwrap(), "deep");
+        // directories left empty by the switch are removed again
+        cargo(&["checkout", "master"]).unwrap();
+        assert!(!temp_path.join("a").exists());
+        assert_eq!(git(&["status", "--porcelain"]), "");
+
+        cargo(&["checkout", "side", "--", "a/b/c/d.txt"]).unwrap();
+        assert_eq!(std::fs::read_to_string(temp_path.join("a/b/c/d.txt")).unwrap(), "deep");
+        cargo(&["reset", "--hard", "HEAD"]).unwrap();
+        assert!(!temp_path.join("a").exists());
+        cargo(&["reset", "--hard", "side"]).unwrap();
+        assert_eq!(std::fs::read_to_string(temp_path.join("a/b/c/d.txt")).unwrap(), "deep");
+        assert_eq!(git(&["status", "--porcelain"]), "");
+    }
+
     #[test]
     fn test_ppt_checkout() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -0,0 +1,309 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::{Merge, Reset, WriteTree},
+    utils::{
+        blob::Blob,
+        commit::Commit,
+        fs::{read_object, read_worktree_file, write_object},
+        hash::{hash_object, ObjectId},
+        ident,
+        index::{Index, LockedIndexWriter},
+        refs::{
+            head_to_hash,
+            read_head_ref,
+            read_ref_commit,
+            write_head_commit_with_log,
+            write_ref_commit,
+            write_ref_commit_with_log,
+            current_branch,
+        },
+        revparse::resolve_commit,
+        tree::{Tree, TreeEntry},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::{Repository, work_tree};
+
+#[derive(Parser, Debug)]
+#[command(name = "cherry-pick", about = "Apply the changes introduced by an existing commit")]
+pub struct CherryPick {
+    #[arg(short = 'n', long = "no-commit", help = "apply the changes to the index and worktree without committing")]
+    no_commit: bool,
+
+    #[arg(long, conflicts_with_all = ["no_commit", "commit"], help = "give up a conflicted cherry-pick and go back to ORIG_HEAD")]
+    abort: bool,
+
+    #[arg(required_unless_present = "abort", help = "the commit whose changes are applied onto HEAD")]
+    commit: Option<String>,
+}
+
+impl CherryPick {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(CherryPick::try_parse_from(args)?))
+    }
+
+    /// `1a2b3c4 (subject)`, how git labels the picked side of a conflict
+    pub(crate) fn label(hash: &ObjectId, commit: &Commit) -> String {
+        format!("{} ({})", &hash.to_hex()[..7], commit.message.lines().next().unwrap_or_default())
+    }
+
+    /// git refuses to pick onto staged changes, or onto local changes of the paths the commit touches
+    fn check_local_changes(gitdir: &Path, action: &str, index: &Index, ours: &BTreeMap<PathBuf, TreeEntry>, touched: &BTreeSet<&PathBuf>) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let staged = index.entries.iter()
+            .map(|entry| (&entry.name, (entry.mode, &entry.hash, entry.stage)))
+            .collect::<BTreeMap<_, _>>();
+        let committed = ours.iter()
+            .map(|(path, entry)| (path, (entry.mode as u32, &entry.hash, 0)))
+            .collect::<BTreeMap<_, _>>();
+        let worktree_dirty = |path: &PathBuf| -> Result<bool> {
+            let file_path = project_root.join(path);
+            Ok(match index.entries.iter().find(|entry| &entry.name == path) {
+                Some(entry) if entry.is_stat_clean(&file_path) => false,
+                Some(entry) => match read_worktree_file(&file_path) {
+                    Ok((mode, content)) => mode != entry.mode || hash_object::<Blob>(content)? != entry.hash,
+                    Err(_) => true,
+                },
+                // an untracked file would be overwritten
+                None => fs::symlink_metadata(&file_path).is_ok(),
+            })
+        };
+        let mut dirty = staged != committed;
+        for path in touched {
+            dirty = dirty || worktree_dirty(path)?;
+        }
+        if dirty {
+            return Err(GitError::invalid_command(format!(
+                "your local changes would be overwritten by {0}.\nhint: commit your changes or stash them to proceed.\nfatal: {0} failed", action)));
+        }
+        Ok(())
+    }
+
+    /// back to ORIG_HEAD, the state before the cherry-pick or revert started
+    /// `state_ref` is CHERRY_PICK_HEAD or REVERT_HEAD
+    pub(crate) fn abort(gitdir: &Path, state_ref: &str) -> Result<i32> {
+        if !gitdir.join(state_ref).exists() {
+            return Err(GitError::invalid_command("no cherry-pick or revert in progress".to_string()));
+        }
+        let orig_head = read_ref_commit(gitdir, "ORIG_HEAD")?;
+        Reset::from_internal(&orig_head).run(Ok(Repository::at(gitdir)))?;
+        Merge::clear_merge_state(gitdir)?;
+        Ok(0)
+    }
+
+    fn pick(&self, gitdir: &Path, rev: &str) -> Result<i32> {
+        let hash = resolve_commit(gitdir, rev)?;
+        let picked = read_object::<Commit>(gitdir.to_path_buf(), &hash)?;
+        let base_tree = match picked.parent_hash.as_slice() {
+            [] => write_object::<Tree>(gitdir.to_path_buf(), Vec::new())?,
+            [parent] => read_object::<Commit>(gitdir.to_path_buf(), parent)?.tree_hash,
+            _ => return Err(GitError::invalid_command(format!("commit {} is a merge but no -m option was given.", hash))),
+        };
+        Replay {
+            action: "cherry-pick",
+            state_ref: "CHERRY_PICK_HEAD",
+            label: Self::label(&hash, &picked),
+            hash,
+            base_tree,
+            tree: picked.tree_hash,
+            message: picked.message,
+            author: picked.author,
+        }.apply(gitdir, self.no_commit)
+    }
+}
+
+/// the change from `base_tree` to `tree` applied onto HEAD with a three way merge,
+/// a cherry-pick of commit `hash` or its revert
+pub(crate) struct Replay {
+    /// "cherry-pick" or "revert", for messages and the reflog
+    pub action: &'static str,
+    /// CHERRY_PICK_HEAD, REVERT_HEAD or REBASE_HEAD, left behind when the merge stops on a conflict
+    pub state_ref: &'static str,
+    pub hash: ObjectId,
+    pub base_tree: ObjectId,
+    pub tree: ObjectId,
+    /// names the side of `tree` in conflict markers
+    pub label: String,
+    pub message: String,
+    pub author: String,
+}
+
+impl Replay {
+    pub(crate) fn apply(self, gitdir: &Path, no_commit: bool) -> Result<i32> {
+        let head = head_to_hash(gitdir)
+            .map_err(|_| GitError::invalid_command(format!("can not {} onto an unborn branch", self.action)))?;
+        let head_tree = read_object::<Commit>(gitdir.to_path_buf(), &head)?.tree_hash;
+
+        let ours = Merge::flatten_tree(gitdir, &head_tree)?;
+        let base = Merge::flatten_tree(gitdir, &self.base_tree)?;
+        let theirs = Merge::flatten_tree(gitdir, &self.tree)?;
+        let content = |files: &BTreeMap<PathBuf, TreeEntry>, path: &PathBuf| files.get(path).map(|entry| (entry.mode, entry.hash));
+        let touched = base.keys().chain(theirs.keys())
+            .filter(|path| content(&base, path) != content(&theirs, path))
+            .collect::<BTreeSet<_>>();
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let index = Index::read_or_default(gitdir)?;
+        CherryPick::check_local_changes(gitdir, self.action, &index, &ours, &touched)?;
+
+        write_ref_commit(gitdir, "ORIG_HEAD", &head)?;
+        // commit picks these up when the user finishes after a conflict
+        if !no_commit {
+            write_ref_commit(gitdir, self.state_ref, &self.hash)?;
+            fs::write(gitdir.join("MERGE_MSG"), format!("{}\n", self.message.trim_end()))
+                .map_err(|_| GitError::failed_to_write_file(&gitdir.join("MERGE_MSG").to_string_lossy()))?;
+        }
+
+        let subject = self.message.lines().next().unwrap_or_default().to_string();
+        // merge_tree leaves the result in the index, and on a conflict in the worktree as well
+        let index = match Merge::merge_tree(gitdir.to_path_buf(), lock, &self.base_tree, &head_tree, &self.tree, &self.label) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!("error: could not {} {}... {}", if self.action == "revert" { "revert" } else { "apply" }, &self.hash.to_hex()[..7], subject);
+                match self.state_ref {
+                    "REBASE_HEAD" => eprintln!("hint: after resolving the conflicts, mark the corrected paths with 'git add <paths>' and run 'git rebase --continue'"),
+                    _ => eprintln!("hint: after resolving the conflicts, mark the corrected paths with 'git add <paths>' and run 'git commit'"),
+                }
+                return Err(e);
+            },
+        };
+        Merge::checkout_merged(gitdir, &index, &ours)?;
+        if no_commit {
+            return Ok(0);
+        }
+
+        Self::commit(gitdir, self.action, head, self.author, &self.message)
+    }
+
+    /// commit the index on top of `head` and end the cherry-pick or revert
+    pub(crate) fn commit(gitdir: &Path, action: &str, head: ObjectId, author: String, message: &str) -> Result<i32> {
+        let subject = message.lines().next().unwrap_or_default().to_string();
+        let tree_hash = WriteTree::lazy_fucker(gitdir.to_path_buf())?;
+        let commit = Commit::new(
+            tree_hash,
+            vec![head],
+            author,
+            ident::committer(gitdir)?,
+            message.trim_end(),
+        );
+        let commit_hash = write_object::<Commit>(gitdir.to_path_buf(), commit.into())?;
+        let reflog_message = format!("{}: {}", action, subject);
+        match read_head_ref(gitdir) {
+            Ok(head_ref) => write_ref_commit_with_log(gitdir, &head_ref, &commit_hash, &reflog_message)?,
+            Err(_) => write_head_commit_with_log(gitdir, &commit_hash, &reflog_message)?,
+        }
+        Merge::clear_merge_state(gitdir)?;
+        let branch = current_branch(gitdir).unwrap_or("detached HEAD".to_string());
+        println!("[{} {}] {}", branch, &commit_hash.to_hex()[..7], subject);
+        Ok(0)
+    }
+}
+
+impl SubCommand for CherryPick {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        match &self.commit {
+            _ if self.abort => Self::abort(&gitdir, "CHERRY_PICK_HEAD"),
+            Some(rev) => self.pick(&gitdir, rev),
+            None => unreachable!("clap requires a commit without --abort"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_cherry_pick() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let git1 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat());
+        let git2 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2][..], args].concat());
+
+        std::fs::write(temp_path1.join("f.txt"), "1\n2\n3\n").unwrap();
+        std::fs::write(temp_path1.join("other.txt"), "other\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "base"]).unwrap();
+        git1(&["checkout", "-b", "side"]).unwrap();
+        std::fs::write(temp_path1.join("new.txt"), "new\n").unwrap();
+        std::fs::remove_file(temp_path1.join("other.txt")).unwrap();
+        git1(&["add", "-A"]).unwrap();
+        git1(&["-c", "user.name=side author", "commit", "-m", "add new, drop other"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "1\nside\n3\n").unwrap();
+        git1(&["-c", "user.name=side author", "commit", "-am", "side edit"]).unwrap();
+        git1(&["checkout", "master"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "1\nmaster\n3\n").unwrap();
+        git1(&["commit", "-am", "master edit"]).unwrap();
+        std::fs::write(temp_path1.join("untracked.txt"), "stays\n").unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let same_state = || {
+            for args in [&["ls-files", "-s"][..], &["status", "--porcelain"], &["rev-parse", "HEAD^{tree}"]] {
+                assert_eq!(git1(args).unwrap(), git2(args).unwrap(), "{:?}", args);
+            }
+            for name in ["f.txt", "new.txt", "other.txt"] {
+                assert_eq!(std::fs::read(temp_path1.join(name)).ok(), std::fs::read(temp_path2.join(name)).ok(), "{}", name);
+            }
+        };
+
+        // a clean pick: the original author and message, a new committer
+        git1(&["cherry-pick", "side~1"]).unwrap();
+        cargo(&["cherry-pick", "side~1"]).unwrap();
+        same_state();
+        let log = ["log", "-1", "--format=%an|%s|%P"];
+        assert_eq!(git1(&log).unwrap(), git2(&log).unwrap());
+        assert!(git2(&log).unwrap().starts_with("side author|add new, drop other|"));
+
+        // the same line changed on both sides: stages 1/2/3, markers and CHERRY_PICK_HEAD, exit 1
+        assert!(git1(&["cherry-pick", "side"]).is_err());
+        let status = std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", temp_path_str2, "cherry-pick", "side"])
+            .output()
+            .unwrap()
+            .status;
+        assert_eq!(status.code(), Some(1));
+        same_state();
+        assert!(temp_path2.join(".git/CHERRY_PICK_HEAD").exists());
+
+        // --abort goes back to where the pick started
+        git1(&["cherry-pick", "--abort"]).unwrap();
+        cargo(&["cherry-pick", "--abort"]).unwrap();
+        same_state();
+        assert!(!temp_path2.join(".git/CHERRY_PICK_HEAD").exists());
+        assert!(cargo(&["cherry-pick", "--abort"]).is_err());
+
+        // resolved and committed: the picked commit's author is kept
+        assert!(cargo(&["cherry-pick", "side"]).is_err());
+        std::fs::write(temp_path2.join("f.txt"), "1\nresolved\n3\n").unwrap();
+        git2(&["add", "f.txt"]).unwrap();
+        cargo(&["commit", "-m", "side edit"]).unwrap();
+        assert_eq!(git2(&["log", "-1", "--format=%an|%cn|%s"]).unwrap(), "side author|rust-git|side edit\n");
+        assert!(!temp_path2.join(".git/CHERRY_PICK_HEAD").exists());
+
+        // -n stops after staging, HEAD stays where it is
+        git2(&["reset", "--hard", "HEAD~2"]).unwrap();
+        let head = git2(&["rev-parse", "HEAD"]).unwrap();
+        cargo(&["cherry-pick", "-n", "side~1"]).unwrap();
+        assert_eq!(git2(&["rev-parse", "HEAD"]).unwrap(), head);
+        assert_eq!(git2(&["status", "--porcelain", "--untracked-files=no"]).unwrap(), "A  new.txt\nD  other.txt\n");
+        assert!(!temp_path2.join(".git/CHERRY_PICK_HEAD").exists());
+    }
+}
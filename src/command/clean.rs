@@ -0,0 +1,229 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+use crate::{
+    utils::{
+        config::Config,
+        fs::{walk, calc_relative_path},
+        ignore::Ignore,
+        index::Index,
+    },
+    GitError,
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::{Repository, work_tree};
+
+#[derive(Parser, Debug)]
+#[command(name = "clean", about = "Remove untracked files from the working tree")]
+pub struct Clean {
+    #[arg(short = 'f', long, help = "actually remove the files, without it only a dry run happens unless clean.requireForce is false")]
+    force: bool,
+
+    #[arg(short = 'n', long, help = "only list what would be removed, even with -f")]
+    dry_run: bool,
+
+    #[arg(short = 'd', help = "remove untracked directories too")]
+    directories: bool,
+
+    #[arg(short = 'x', help = "don't use the ignore rules, ignored files are removed as well")]
+    ignored: bool,
+}
+
+impl Clean {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Clean::try_parse_from(args)?))
+    }
+
+    /// directories below `dir` with no file anywhere inside, relative to `project_root`
+    fn empty_dirs(project_root: &Path, dir: &Path, out: &mut Vec<PathBuf>) -> Result<bool> {
+        let mut empty = true;
+        for entry in fs::read_dir(project_root.join(dir))? {
+            let entry = entry?;
+            let rel = dir.join(entry.file_name());
+            // a nested .git counts as content, it is never looked into
+            if !entry.file_type()?.is_dir() || entry.file_name() == ".git" {
+                empty = false;
+            }
+            else if Self::empty_dirs(project_root, &rel, out)? {
+                out.push(rel);
+            }
+            else {
+                empty = false;
+            }
+        }
+        Ok(empty)
+    }
+
+    /// paths to remove, directories end with `/`
+    fn collect(&self, gitdir: &Path) -> Result<BTreeSet<String>> {
+        let project_root = &work_tree(gitdir)?;
+        // without a readable index every file would look untracked, better not to touch anything
+        let index = Index::read_or_default(gitdir)
+            .map_err(|e| GitError::invalid_command(format!("index file corrupt, refusing to clean: {}", e)))?;
+        let tracked = index.entries.iter().map(|entry| PathBuf::from(&entry.name)).collect::<HashSet<_>>();
+        let tracked_dirs = tracked.iter()
+            .flat_map(|path| path.ancestors().skip(1).map(Path::to_path_buf))
+            .collect::<HashSet<_>>();
+        let ignore = if self.ignored { Ignore::default() } else { Ignore::load(project_root)? };
+
+        let files = walk(project_root)?
+            .into_iter()
+            .map(|path| calc_relative_path(project_root, path))
+            .collect::<Result<Vec<_>>>()?;
+        // another repository inside the work tree is never cleaned, like git without -ff
+        let nested_repos = files.iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .filter(|dir| !dir.as_os_str().is_empty() && project_root.join(dir).join(".git").exists())
+            .map(Path::to_path_buf)
+            .collect::<HashSet<_>>();
+        let in_nested_repo = |path: &Path| path.ancestors().any(|dir| nested_repos.contains(dir));
+
+        // what has to stay: ignored files (unless -x) and nested repositories
+        let mut kept = Vec::new();
+        let mut untracked = Vec::new();
+        for path in files.into_iter().filter(|path| !tracked.contains(path)) {
+            if in_nested_repo(&path) || ignore.is_ignored(&path, false) {
+                kept.push(path);
+            }
+            else {
+                untracked.push((path, false));
+            }
+        }
+        let mut empty = Vec::new();
+        Self::empty_dirs(project_root, Path::new(""), &mut empty)?;
+        for dir in empty {
+            if ignore.is_ignored(&dir, true) || in_nested_repo(&dir) {
+                kept.push(dir);
+            }
+            else {
+                untracked.push((dir, true));
+            }
+        }
+        let has_kept = |dir: &Path| kept.iter().any(|path| path.starts_with(dir));
+
+        let mut removals = BTreeSet::new();
+        for (path, is_dir) in untracked {
+            // the outermost untracked directory that can go as a whole, from the top down
+            let mut dirs = path.ancestors()
+                .skip(if is_dir { 0 } else { 1 })
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect::<Vec<_>>();
+            dirs.reverse();
+            let whole_dir = dirs.into_iter()
+                .find(|dir| !tracked_dirs.contains(*dir) && !has_kept(dir));
+            let inside_untracked = path.parent().is_some_and(|parent| !parent.as_os_str().is_empty() && !tracked_dirs.contains(parent));
+
+            match whole_dir {
+                Some(dir) if self.directories => {
+                    removals.insert(format!("{}/", dir.display()));
+                },
+                // without -d, files in untracked directories are left alone
+                _ if is_dir || (inside_untracked && !self.directories) => {},
+                _ => {
+                    removals.insert(path.display().to_string());
+                },
+            }
+        }
+        Ok(removals)
+    }
+}
+
+impl SubCommand for Clean {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let project_root = &work_tree(&gitdir)?;
+        // nothing is removed by accident: without -f it is a dry run unless clean.requireForce is off
+        let require_force = Config::read_all(&gitdir)?.get_bool("clean.requireForce").unwrap_or(true);
+        let dry_run = self.dry_run || (require_force && !self.force);
+
+        for path in self.collect(&gitdir)? {
+            if dry_run {
+                println!("Would remove {}", path);
+                continue;
+            }
+            println!("Removing {}", path);
+            let full_path = project_root.join(&path);
+            let removed = if path.ends_with('/') { fs::remove_dir_all(&full_path) } else { fs::remove_file(&full_path) };
+            removed.map_err(|_| GitError::failed_to_remove_file(path))?;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_clean() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let files = [
+            "tracked.txt", "src/main.rs", "src/new.rs", "untracked.txt", "debug.log", "src/deep/gen.log",
+            "newdir/a.txt", "newdir/sub/b.txt", "mixed/keep.log", "mixed/c.txt", "build/out.bin", ".gitignore",
+        ];
+        for name in files {
+            let path = temp_path1.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, name).unwrap();
+        }
+        fs::create_dir_all(temp_path1.join("empty/inner")).unwrap();
+        fs::write(temp_path1.join(".gitignore"), "*.log\nbuild/\n").unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "add", "tracked.txt", "src/main.rs", ".gitignore"]).unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+        fs::create_dir_all(temp_path2.join("empty/inner")).unwrap();
+
+        let sorted = |out: String| {
+            let mut lines = out.lines().map(str::to_string).collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+        for flags in [&["-n"][..], &["-dn"], &["-xn"], &["-dxn"], &["-fdxn"]] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str1, "clean"][..], flags].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "clean"][..], flags].concat()).unwrap();
+            assert_eq!(sorted(origin), sorted(real), "{:?}", flags);
+        }
+
+        // without -f it only lists, the same as -n, and removes nothing
+        for (flags, git_flags) in [(&[][..], &["-n"][..]), (&["-dx"], &["-dxn"])] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str1, "clean"][..], git_flags].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "clean"][..], flags].concat()).unwrap();
+            assert_eq!(sorted(origin), sorted(real), "{:?}", flags);
+        }
+        assert!(temp_path2.join("untracked.txt").exists() && temp_path2.join("newdir/a.txt").exists());
+
+        // -f removes untracked files but keeps ignored ones and untracked directories
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "clean", "-f"]).unwrap();
+        assert!(!temp_path2.join("untracked.txt").exists() && !temp_path2.join("src/new.rs").exists());
+        assert!(temp_path2.join("debug.log").exists() && temp_path2.join("newdir/a.txt").exists());
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "clean", "-fdx"]).unwrap();
+        let status = shell_spawn(&["git", "-C", temp_path_str2, "status", "--porcelain", "--ignored", "--untracked-files=all"]).unwrap();
+        assert_eq!(status, "A  .gitignore\nA  src/main.rs\nA  tracked.txt\n");
+        assert!(!temp_path2.join("empty").exists() && temp_path2.join(".git/HEAD").exists());
+
+        // with clean.requireForce off no flag is needed
+        fs::write(temp_path2.join("untracked.txt"), "again").unwrap();
+        shell_spawn(&["git", "-C", temp_path_str2, "config", "clean.requireForce", "false"]).unwrap();
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "clean"]).unwrap();
+        assert!(!temp_path2.join("untracked.txt").exists());
+
+        // an unreadable index stops it from treating everything as untracked
+        fs::write(temp_path1.join(".git/index"), "garbage").unwrap();
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str1, "clean", "-f"]).is_err());
+        assert!(temp_path1.join("tracked.txt").exists());
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    env::current_dir,
+    fs,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::{Checkout, Fetch, Init, LsRemote},
+    utils::{
+        config::Config,
+        protocol::RemoteRef,
+        refs::{read_ref_commit, ref_exists, write_head_commit, write_head_ref, write_ref_commit_with_log, write_ref_file},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// the remote a clone sets up, like git's default for --origin
+const ORIGIN: &str = "origin";
+
+#[derive(Parser, Debug)]
+#[command(name = "clone", about = "Clone a repository into a new directory")]
+pub struct Clone {
+    #[arg(short = 'b', long, value_name = "name", help = "check out this branch instead of the one the remote HEAD points at")]
+    branch: Option<String>,
+
+    #[arg(long, value_name = "depth", help = "only fetch the last <depth> commits of each branch")]
+    depth: Option<u32>,
+
+    #[arg(help = "the repository to clone from, an http(s) url or a local path")]
+    repository: String,
+
+    #[arg(help = "the directory to clone into, named after the repository by default")]
+    directory: Option<String>,
+}
+
+impl Clone {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Clone::try_parse_from(args)?))
+    }
+
+    /// `https://host/path/repo.git` and `/path/repo/.git` both become `repo`, like git's guess_dir_name
+    pub(crate) fn guess_dir(repository: &str) -> Result<String> {
+        let trimmed = repository.trim_end_matches('/');
+        let trimmed = trimmed.strip_suffix("/.git").unwrap_or(trimmed);
+        let name = trimmed.rsplit(['/', ':']).next().unwrap_or_default();
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        match name.is_empty() {
+            true => Err(GitError::invalid_command("No directory name could be guessed.\nPlease specify a directory on the command line".to_string())),
+            false => Ok(name.to_string()),
+        }
+    }
+
+    /// urls are kept as they are, local paths are recorded absolute so the clone works from anywhere
+    fn url(&self) -> Result<String> {
+        let repository = &self.repository;
+        if repository.contains("://") || repository.starts_with("git@") {
+            return Ok(repository.clone());
+        }
+        let path = current_dir()?.join(repository);
+        match LsRemote::local_gitdir(&path).join("HEAD").is_file() {
+            true => Ok(path.canonicalize()?.to_string_lossy().to_string()),
+            false => Err(GitError::invalid_command(format!("repository '{}' does not exist", repository))),
+        }
+    }
+
+    /// the branch the remote HEAD points at; servers that don't tell get the branch at the same commit, master first
+    fn remote_head_branch(refs: &[RemoteRef]) -> Option<String> {
+        let head = refs.iter().find(|remote_ref| remote_ref.name == "HEAD")?;
+        let target = head.symref.clone().or_else(|| {
+            let same_commit = refs.iter().filter(|remote_ref| remote_ref.name.starts_with("refs/heads/") && remote_ref.hash == head.hash);
+            same_commit.clone()
+                .find(|remote_ref| remote_ref.name == "refs/heads/master")
+                .or(same_commit.clone().next())
+                .map(|remote_ref| remote_ref.name.clone())
+        })?;
+        target.strip_prefix("refs/heads/").map(str::to_string)
+    }
+
+    /// init, add the remote, fetch it, then the branch to work on and its worktree
+    fn clone_into(&self, target: &Path, url: &str) -> Result<()> {
+        let gitdir = target.join(".git");
+        Init { bare: false, initial_branch: None, object_format: None, dir: Some(target.display().to_string()) }
+            .run(Ok(Repository::at(&gitdir)))?;
+        Config::set(&gitdir, &format!("remote.{}.url", ORIGIN), url)?;
+        Config::set(&gitdir, &format!("remote.{}.fetch", ORIGIN), &format!("+refs/heads/*:refs/remotes/{}/*", ORIGIN))?;
+
+        let mut fetch_args = vec!["fetch".to_string(), ORIGIN.to_string()];
+        if let Some(depth) = self.depth {
+            fetch_args.extend(["--depth".to_string(), depth.to_string()]);
+        }
+        Fetch::try_parse_from(fetch_args)?.run(Ok(Repository::at(&gitdir)))?;
+
+        let refs = LsRemote::list(&Config::read_all(&gitdir)?, url)?;
+        let remote_head = Self::remote_head_branch(&refs);
+        if let Some(branch) = &remote_head {
+            write_ref_file(&gitdir.join(format!("refs/remotes/{}/HEAD", ORIGIN)), &format!("ref: refs/remotes/{}/{}\n", ORIGIN, branch))?;
+        }
+        let message = format!("clone: from {}", url);
+        let commit = match self.branch.as_ref().or(remote_head.as_ref()) {
+            Some(branch) => {
+                let tracking = format!("refs/remotes/{}/{}", ORIGIN, branch);
+                if !ref_exists(&gitdir, &tracking) {
+                    return Err(GitError::invalid_command(format!("Remote branch {} not found in upstream {}", branch, ORIGIN)));
+                }
+                let commit = read_ref_commit(&gitdir, &tracking)?;
+                let refname = format!("refs/heads/{}", branch);
+                // HEAD first, so the branch's reflog entry goes to HEAD's log too
+                write_head_ref(&gitdir, &refname)?;
+                write_ref_commit_with_log(&gitdir, &refname, &commit, &message)?;
+                Config::set(&gitdir, &format!("branch.{}.remote", branch), ORIGIN)?;
+                Config::set(&gitdir, &format!("branch.{}.merge", branch), &refname)?;
+                commit
+            },
+            // the remote HEAD is detached, so is ours
+            None => match refs.iter().find(|remote_ref| remote_ref.name == "HEAD") {
+                Some(head) => {
+                    write_head_commit(&gitdir, &head.hash)?;
+                    head.hash
+                },
+                None => {
+                    eprintln!("warning: You appear to have cloned an empty repository.");
+                    return Ok(());
+                },
+            },
+        };
+        Checkout::switch_worktree(&gitdir, None, &commit)
+    }
+}
+
+impl SubCommand for Clone {
+    fn run(&self, _: Result<Repository>) -> Result<i32> {
+        let dir = match &self.directory {
+            Some(dir) => dir.clone(),
+            None => Self::guess_dir(&self.repository)?,
+        };
+        let target: PathBuf = current_dir()?.join(&dir);
+        if target.exists() && (!target.is_dir() || target.read_dir()?.next().is_some()) {
+            return Err(GitError::invalid_command(format!("destination path '{}' already exists and is not an empty directory.", dir)));
+        }
+        let url = self.url()?;
+        eprintln!("Cloning into '{}'...", dir);
+
+        let created = !target.exists();
+        let result = self.clone_into(&target, &url);
+        // like git, a failed clone leaves nothing behind
+        if result.is_err() {
+            let _ = fs::remove_dir_all(if created { target } else { target.join(".git") });
+        }
+        result.map(|_| 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Clone;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        serve_upload_pack,
+        serve_upload_pack_v0,
+    };
+
+    #[test]
+    fn test_guess_dir() {
+        for (url, dir) in [("https://host/path/repo.git", "repo"), ("/path/repo/.git", "repo"), ("/path/repo/", "repo"), ("git@host:repo.git", "repo")] {
+            assert_eq!(Clone::guess_dir(url).unwrap(), dir, "{}", url);
+        }
+        assert!(Clone::guess_dir("/").is_err());
+    }
+
+    #[test]
+    fn test_clone() {
+        let upstream = setup_test_git_dir();
+        let upstream_path = upstream.path();
+        let upstream_str = upstream_path.to_str().unwrap();
+        let git_up = |args: &[&str]| shell_spawn(&[&["git", "-C", upstream_str][..], args].concat()).unwrap();
+        std::fs::create_dir_all(upstream_path.join("src")).unwrap();
+        std::fs::write(upstream_path.join("src/lib.rs"), "fn lib() {}\n").unwrap();
+        std::fs::write(upstream_path.join("a.txt"), "a\n").unwrap();
+        git_up(&["add", "."]);
+        git_up(&["commit", "-m", "first"]);
+        git_up(&["checkout", "-b", "feature/x"]);
+        std::fs::write(upstream_path.join("b.txt"), "b\n").unwrap();
+        git_up(&["add", "."]);
+        git_up(&["commit", "-m", "feature"]);
+        // the remote HEAD is not on master, the clone follows it
+        git_up(&["checkout", "-b", "trunk", "master"]);
+        git_up(&["commit", "--allow-empty", "-m", "trunk"]);
+
+        let temp = tempdir().unwrap();
+        let temp_path = temp.path();
+        // destinations are absolute, like the ones given to init
+        let dest = |dir: &str| temp_path.join(dir).to_str().unwrap().to_string();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "clone"][..], args].concat());
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-C", &dest(dir)][..], args].concat()).unwrap();
+
+        let (v2, _) = serve_upload_pack(upstream_path.to_path_buf());
+        let (v0, _) = serve_upload_pack_v0(upstream_path.to_path_buf());
+        let sources = [
+            (upstream_str.to_string(), "local"),
+            (format!("http://127.0.0.1:{}", v2), "v2"),
+            (format!("http://127.0.0.1:{}", v0), "v0"),
+        ];
+        // the test server only talks to our client, git's own clone of the local path is the reference
+        shell_spawn(&["git", "clone", upstream_str, &dest("reference")]).unwrap();
+        for (url, dir) in &sources {
+            cargo(&[url, &dest(dir)]).unwrap();
+            for query in [
+                &["for-each-ref", "--format=%(refname) %(objectname) %(symref)"][..],
+                &["symbolic-ref", "HEAD"],
+                &["ls-files", "-s"],
+                &["status", "--porcelain", "--untracked-files=all"],
+                &["config", "--get-regexp", "^(remote\\.origin\\.fetch|branch\\.)"],
+            ] {
+                assert_eq!(git(dir, query), git("reference", query), "{} {:?}", dir, query);
+            }
+            assert_eq!(git(dir, &["config", "remote.origin.url"]), format!("{}\n", url));
+            git(dir, &["fsck", "--full"]);
+        }
+
+        // -b picks another branch, a .git directory works as the source too
+        cargo(&["-b", "feature/x", &format!("{}/.git", upstream_str), &dest("feature")]).unwrap();
+        assert_eq!(git("feature", &["symbolic-ref", "HEAD"]), "refs/heads/feature/x\n");
+        assert_eq!(std::fs::read_to_string(temp_path.join("feature").join("b.txt")).unwrap(), "b\n");
+        assert_eq!(git("feature", &["config", "branch.feature/x.merge"]), "refs/heads/feature/x\n");
+
+        // a missing branch leaves nothing behind, a non-empty destination is refused
+        assert!(cargo(&[upstream_str, "-b", "nope", &dest("missing")]).is_err());
+        assert!(!temp_path.join("missing").exists());
+        assert!(cargo(&[upstream_str, &dest("local")]).is_err());
+        assert!(cargo(&[&dest("nowhere"), &dest("nowhere")]).is_err());
+
+        // an empty repository clones to an unborn branch
+        let empty = setup_test_git_dir();
+        cargo(&[empty.path().to_str().unwrap(), &dest("empty")]).unwrap();
+        assert!(git("empty", &["status", "--porcelain"]).is_empty());
+    }
+
+    #[test]
+    fn test_clone_packed() {
+        let upstream = setup_test_git_dir();
+        let upstream_path = upstream.path();
+        let upstream_str = upstream_path.to_str().unwrap();
+        let git_up = |args: &[&str]| shell_spawn(&[&["git", "-C", upstream_str][..], args].concat()).unwrap();
+        std::fs::create_dir_all(upstream_path.join("src")).unwrap();
+        std::fs::write(upstream_path.join("src/lib.rs"), "fn lib() {}\n").unwrap();
+        git_up(&["add", "."]);
+        git_up(&["commit", "-m", "first"]);
+        std::fs::write(upstream_path.join("a.txt"), "a\n").unwrap();
+        git_up(&["add", "."]);
+        git_up(&["commit", "-m", "second"]);
+
+        // after our gc every object is in a pack, none is left loose
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", upstream_str, "gc"]).unwrap();
+        assert_eq!(git_up(&["count-objects"]), "0 objects, 0 kilobytes\n");
+
+        let temp = tempdir().unwrap();
+        let temp_path = temp.path();
+        let dest = |dir: &str| temp_path.join(dir).to_str().unwrap().to_string();
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-C", &dest(dir)][..], args].concat()).unwrap();
+        shell_spawn(&["git", "clone", upstream_str, &dest("reference")]).unwrap();
+        // a repository made by git clone keeps its objects packed as well
+        for (source, dir) in [(upstream_str.to_string(), "after-gc"), (dest("reference"), "of-git-clone")] {
+            shell_spawn(&["cargo", "run", "--quiet", "--", "clone", &source, &dest(dir)]).unwrap();
+            for query in [&["log", "--format=%H %s"][..], &["ls-files", "-s"], &["status", "--porcelain"]] {
+                assert_eq!(git(dir, query), git("reference", query), "{} {:?}", dir, query);
+            }
+            git(dir, &["fsck", "--full"]);
+        }
+    }
+}
@@ -7,20 +7,22 @@ use crate::{
     GitError,
     Result,
     command::{
-        WriteTree, CommitTree, UpdateRef
+        WriteTree, CommitTree, UpdateRef, Merge,
     },
     utils:: {
         commit,
         tree::Tree,
         index::Index,
-        fs::write_object,
+        fs::{write_object, read_object},
         refs::{
-            read_head_ref, read_ref_commit
+            read_head_ref, read_ref_commit, current_branch
         },
         hash::hash_object,
+        ident,
     },
 };
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 #[derive(Parser, Debug)]
 #[command(name = "commit", about = "记录对存储库的更改")]
@@ -29,47 +31,83 @@ pub struct Commit {
     pub message: Option<String>,
 
     #[arg(short, long, help = "commit all changed files")]
-    pub all: bool
+    pub all: bool,
+
+    #[arg(long, help = "replace the tip of the current branch, the message defaults to the old one")]
+    pub amend: bool,
 }
 
 impl Commit {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
         let cli = Commit::try_parse_from(args)?;
-        cli.message
-            .ok_or_else(||GitError::invalid_command("todo, 在这里调用$EDITOR".to_string()))
-            .map(|message| Box::new(Commit {
-                message: Some(message),
-                all: cli.all,
-            }) as Box<dyn SubCommand>)
+        // --amend can reuse the old message
+        if cli.message.is_none() && !cli.amend {
+            return Err(GitError::invalid_command("todo, 在这里调用$EDITOR".to_string()));
+        }
+        Ok(Box::new(cli))
     }
 
 }
 
 impl SubCommand for Commit {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        // a merge can only be folded into the amended commit once all conflicts are resolved
+        if self.amend && gitdir.join("MERGE_HEAD").exists() && !Index::read_or_default(&gitdir)?.unmerged_paths().is_empty() {
+            return Err(GitError::merge_conflict("fatal: You are in the middle of a merge -- cannot amend.".to_string()));
+        }
+
         // 使用正确的tree构建逻辑而不是简单的转换
         let tree_hash = WriteTree::lazy_fucker(gitdir.clone())?;
 
-        let head_ref = read_head_ref(&gitdir)?;
+        let head_ref = format!("refs/heads/{}", current_branch(&gitdir)?);
         let parent_commit = read_ref_commit(&gitdir, &head_ref).ok();
+        // finishing a merge: the merged branch becomes the second parent
+        let merge_head = read_ref_commit(&gitdir, "MERGE_HEAD").ok();
+        // finishing a cherry-pick: the picked commit keeps its author
+        let picked = match read_ref_commit(&gitdir, "CHERRY_PICK_HEAD") {
+            Ok(hash) => Some(read_object::<commit::Commit>(gitdir.clone(), &hash)?),
+            Err(_) => None,
+        };
 
-        let commit = commit::Commit {
-            tree_hash,
-            parent_hash: if parent_commit.is_none() {vec![]} else { vec![parent_commit.unwrap()] },
-            author: "Default Author <139881912@163.com> 1748165415 +0800".into(),
-            committer: "commiter Author <139881912@163.com> 1748165415 +0800".into(),
-            message: self.message.clone().unwrap(),
+        // --amend 时新提交替换 HEAD：沿用旧提交的父提交、作者和提交信息
+        let amended = match (&parent_commit, self.amend) {
+            (_, false) => None,
+            (None, true) => return Err(GitError::invalid_command("You have nothing to amend.".to_string())),
+            (Some(head), true) => Some(read_object::<commit::Commit>(gitdir.clone(), head)?),
+        };
+        let (parents, author) = match &amended {
+            Some(old) => (old.parent_hash.clone(), old.author.clone()),
+            None => (parent_commit.into_iter().collect::<Vec<_>>(), match picked {
+                Some(picked) => picked.author,
+                None => ident::author(&gitdir)?,
+            }),
+        };
+        let message = match (&self.message, &amended) {
+            (Some(message), _) => message.clone(),
+            (None, Some(old)) => old.message.clone(),
+            (None, None) => unreachable!("from_args requires a message without --amend"),
+        };
+        let reflog_action = match (&parent_commit, &merge_head, self.amend) {
+            (_, _, true) => "commit (amend)",
+            (None, _, _) => "commit (initial)",
+            (_, Some(_), _) => "commit (merge)",
+            _ => "commit",
         };
+        let reflog_message = format!("{}: {}", reflog_action, message.lines().next().unwrap_or_default());
+
+        let commit = commit::Commit::new(
+            tree_hash,
+            parents.into_iter().chain(merge_head).collect(),
+            author,
+            ident::committer(&gitdir)?,
+            &message,
+        );
 
         let commit_hash = write_object::<commit::Commit>(gitdir.clone(), commit.into())?;
 
-        let update_ref = UpdateRef {
-            ref_path: head_ref,
-            commit_hash: commit_hash.clone()
-        };
-        update_ref.run(Ok(gitdir))?;
+        UpdateRef::new(head_ref, &commit_hash, &reflog_message).update(&gitdir)?;
+        Merge::clear_merge_state(&gitdir)?;
 
         println!("{}", commit_hash);
         Ok(0)
@@ -128,6 +166,88 @@ mod test {
     }
 
 
+    #[test]
+    fn test_identity_from_config() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        let file = mktemp_in(temp_path).unwrap();
+        let file_str = file.file_name().unwrap().to_str().unwrap();
+
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str];
+        let cmds: ArgsList = &[
+            (&["add", file_str], false),
+            (&["commit", "-m", "identity"], false),
+        ];
+        cmd_seq(cmds)(cargo).unwrap();
+
+        let author = shell_spawn(&["git", "-C", temp_path_str, "log", "-1", "--format=%an <%ae>|%cn <%ce>"]).unwrap();
+        assert_eq!(author.trim(), "rust-git <163@163.com>|rust-git <163@163.com>");
+
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "config", "--unset", "user.name"]).unwrap();
+        // the binary was built by the cargo run above, call it directly so HOME can be faked
+        let binary = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git");
+        let no_identity = ["sh", "-c", &format!("HOME={} {} -C {} commit -m again", temp_path_str, binary.display(), temp_path_str)];
+        assert!(shell_spawn(&no_identity).is_err());
+    }
+
+    #[test]
+    fn test_amend() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        std::fs::write(temp_path.join("a.txt"), "a").unwrap();
+        git(&["add", "a.txt"]).unwrap();
+        cargo(&["commit", "-m", "first"]).unwrap();
+        std::fs::write(temp_path.join("b.txt"), "b").unwrap();
+        git(&["add", "b.txt"]).unwrap();
+        cargo(&["commit", "-m", "secnod"]).unwrap();
+        let first = git(&["rev-parse", "HEAD~1"]).unwrap().trim().to_string();
+        let old = git(&["rev-parse", "HEAD"]).unwrap().trim().to_string();
+
+        // the forgotten file and the fixed message go into a replacement of HEAD
+        std::fs::write(temp_path.join("c.txt"), "c").unwrap();
+        git(&["add", "c.txt"]).unwrap();
+        cargo(&["commit", "--amend", "-m", "second"]).unwrap();
+        let tree = git(&["write-tree"]).unwrap().trim().to_string();
+        let log = git(&["log", "-1", "--format=%T/%P/%s"]).unwrap();
+        assert_eq!(log.trim(), format!("{}/{}/second", tree, first));
+        assert!(git(&["merge-base", "--is-ancestor", &old, "HEAD"]).is_err());
+        assert!(!git(&["rev-list", "--all"]).unwrap().contains(&old));
+        let reflog = git(&["reflog", "-1", "--format=%gs"]).unwrap();
+        assert_eq!(reflog.trim(), "commit (amend): second");
+
+        // without -m the old message stays
+        cargo(&["commit", "--amend"]).unwrap();
+        assert_eq!(git(&["log", "-1", "--format=%s/%P"]).unwrap().trim(), format!("second/{}", first));
+        assert_eq!(git(&["rev-list", "--count", "HEAD"]).unwrap().trim(), "2");
+
+        // a conflicted merge can't be folded into the amended commit
+        git(&["checkout", "-q", "-b", "side", "HEAD~1"]).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "side").unwrap();
+        git(&["commit", "-qam", "side"]).unwrap();
+        git(&["checkout", "-q", "master"]).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "master").unwrap();
+        git(&["commit", "-qam", "master"]).unwrap();
+        assert!(git(&["merge", "side"]).is_err());
+        let head = git(&["rev-parse", "HEAD"]).unwrap();
+        let output = std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", temp_path_str, "commit", "--amend", "-m", "merged"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stderr).trim(), "fatal: You are in the middle of a merge -- cannot amend.");
+        assert_eq!(git(&["rev-parse", "HEAD"]).unwrap(), head);
+
+        // nothing to amend on an unborn branch
+        let empty = setup_test_git_dir();
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", empty.path().to_str().unwrap(), "commit", "--amend"]).is_err());
+    }
+
     #[test]
     fn test_ppt_add_commit() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -1,30 +1,33 @@
 use std::env;
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use clap::{Parser, Subcommand};
 use crate::utils::{
     zlib::compress_object,
-    hash::hash_object,
+    hash::{hash_object, ObjectId},
     commit::Commit,
-    fs::write_object,
+    fs::{write_object, read_obj},
+    refs::is_hash,
 };
 use crate::{
     GitError,
     Result,
 };
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "commit-tree", about = "Create a commit object from a tree object")]
 pub struct CommitTree {
     #[arg(required = true, help = "The tree object hash")]
     pub tree_hash: String,
 
-    #[arg(short = 'm', required = true, help = "The commit message")]
-    pub message: String,
+    #[arg(short = 'm', help = "The commit message, read from stdin when omitted")]
+    pub message: Option<String>,
 
-    #[arg(short = 'p', help = "The parent commit hash")]
-    pub pcommit: Option<String>,
+    #[arg(short = 'p', help = "The parent commit hash, can be given multiple times")]
+    pub pcommit: Vec<String>,
 }
 
 impl CommitTree {
@@ -43,7 +46,7 @@ impl CommitTree {
 
         let mut content = format!("tree {}\n", self.tree_hash);
 
-        if let Some(parent) = &self.pcommit {
+        for parent in &self.pcommit {
             content.push_str(&format!("parent {}\n", parent));
         }
 
@@ -62,12 +65,22 @@ impl CommitTree {
             author_name, author_email, timestamp, timezone
         ));
 
-        content.push_str(&self.message);
+        content.push_str(self.message.as_deref().unwrap_or_default());
 
         content
     }
 
-    pub fn asshole(self, gitdir: PathBuf) -> Result<String> {
+    /// every parent must be a full hash of an existing object
+    fn check_parents(&self, gitdir: &Path) -> Result<()> {
+        for parent in &self.pcommit {
+            if !is_hash(parent) || read_obj(gitdir.to_path_buf(), parent).is_err() {
+                return Err(GitError::invalid_hash(parent));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn asshole(self, gitdir: PathBuf) -> Result<ObjectId> {
         let commit_content = self.build_commit_content();
 
         write_object::<Commit>(gitdir, commit_content.into_bytes())
@@ -75,10 +88,22 @@ impl CommitTree {
 }
 
 impl SubCommand for CommitTree {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let commit_content = self.build_commit_content();
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        self.check_parents(&gitdir)?;
+
+        // like git, the message comes from stdin as is when there is no -m
+        let commit_tree = match &self.message {
+            Some(_) => self.clone(),
+            None => {
+                let mut message = String::new();
+                io::stdin().read_to_string(&mut message)?;
+                CommitTree { message: Some(message), ..self.clone() }
+            },
+        };
+        let commit_content = commit_tree.build_commit_content();
 
-        let commit_hash = write_object::<Commit>(gitdir?, commit_content.into_bytes())?;
+        let commit_hash = write_object::<Commit>(gitdir, commit_content.into_bytes())?;
 
         println!("{}", commit_hash);
 
@@ -105,8 +130,8 @@ mod tests {
     fn test_build_commit_content() {
         let commit_tree = CommitTree {
             tree_hash: "d8329fc1cc938780ffdd9f94e0d364e0ea74f579".to_string(),
-            message: "Initial commit".to_string(),
-            pcommit: Some("8ea8033adc42a4148773457c1ad871d9e2f21d2e".to_string()),
+            message: Some("Initial commit".to_string()),
+            pcommit: vec!["8ea8033adc42a4148773457c1ad871d9e2f21d2e".to_string()],
         };
 
         let content = commit_tree.build_commit_content();
@@ -133,12 +158,12 @@ mod tests {
 
         let commit_tree = CommitTree {
             tree_hash: "d8329fc1cc938780ffdd9f94e0d364e0ea74f579".to_string(),
-            message: "Initial commit".to_string(),
-            pcommit: None,
+            message: Some("Initial commit".to_string()),
+            pcommit: vec![],
         };
 
         let content = commit_tree.build_commit_content();
-        let commit_hash = write_object::<Commit>(git_dir.clone(), content.into_bytes()).unwrap();
+        let commit_hash = write_object::<Commit>(git_dir.clone(), content.into_bytes()).unwrap().to_hex();
 
         let object_path = git_dir
             .join("objects")
@@ -166,13 +191,55 @@ mod tests {
         let _ = shell_spawn(&["git", "-C", temp_dir, "add", ":/"]).unwrap();
         let tree_hash = shell_spawn(&["git", "-C", temp_dir, "write-tree"]).unwrap();
 
-        let commit_tree = CommitTree::try_parse_from(&["commit-tree", &tree_hash, "-m", "test_with_git"]).unwrap();
+        let commit_tree = CommitTree::try_parse_from(["commit-tree", &tree_hash, "-m", "test_with_git"]).unwrap();
         let content = commit_tree.build_commit_content();
         let commit_hash = write_object::<Commit>(gitdir.clone(), content.clone().into_bytes()).unwrap();
 
 
-        let out = shell_spawn(&["git", "-C", temp_dir, "cat-file", "-p", &commit_hash]).unwrap();
+        let out = shell_spawn(&["git", "-C", temp_dir, "cat-file", "-p", &commit_hash.to_hex()]).unwrap();
         assert_eq!(content, out);
         //println!("{}", out);
     }
+
+    #[test]
+    fn test_multiple_parents_and_stdin() {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path().to_str().unwrap();
+
+        let _ = mktemp_in(temp_path);
+        let _ = shell_spawn(&["git", "-C", temp_path, "add", ":/"]).unwrap();
+        let tree_hash = shell_spawn(&["git", "-C", temp_path, "write-tree"]).unwrap();
+        let tree_hash = tree_hash.trim();
+        let parents = ["one", "two", "three"].map(|msg| {
+            shell_spawn(&["git", "-C", temp_path, "commit-tree", tree_hash, "-m", msg]).unwrap().trim().to_string()
+        });
+
+        let run = |args: &[&str], stdin: &str| {
+            let mut child = Command::new("cargo")
+                .args([&["run", "--quiet", "--", "-C", temp_path, "commit-tree", tree_hash][..], args].concat())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+            child.wait_with_output().unwrap()
+        };
+
+        let output = run(&["-p", &parents[0], "-p", &parents[1], "-p", &parents[2]], "octopus\n\nfrom stdin\n");
+        assert!(output.status.success());
+        let commit_hash = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(commit_hash.len(), 41);
+
+        let out = shell_spawn(&["git", "-C", temp_path, "cat-file", "-p", commit_hash.trim()]).unwrap();
+        let parent_lines = out.lines().filter(|l| l.starts_with("parent ")).collect::<Vec<_>>();
+        assert_eq!(parent_lines, parents.iter().map(|p| format!("parent {}", p)).collect::<Vec<_>>());
+        assert!(out.ends_with("\n\noctopus\n\nfrom stdin\n"));
+
+        // parents have to be full hashes of existing objects
+        assert!(!run(&["-p", &parents[0][..7]], "").status.success());
+        assert!(!run(&["-p", "0123456789012345678901234567890123456789"], "").status.success());
+    }
 }
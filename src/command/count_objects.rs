@@ -0,0 +1,72 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use clap::Parser;
+
+use crate::{
+    utils::{
+        fs::{common_dir, loose_objects},
+        packfile::{has_packed_object, packed_hashes},
+    },
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "count-objects", about = "Count unpacked number of objects and their disk consumption")]
+pub struct CountObjects {
+    #[arg(short = 'v', long, help = "also report the objects in packs and the loose objects a pack already has")]
+    verbose: bool,
+}
+
+impl CountObjects {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(CountObjects::try_parse_from(args)?))
+    }
+
+    /// sizes of the .pack and .idx files, like git only complete packs count
+    fn pack_files(gitdir: &Path) -> Result<(usize, u64)> {
+        let Ok(dir) = std::fs::read_dir(common_dir(gitdir).join("objects").join("pack")) else {
+            return Ok((0, 0));
+        };
+        let mut packs = 0;
+        let mut size = 0;
+        for entry in dir.flatten() {
+            let path = entry.path();
+            let is_idx = path.extension().is_some_and(|ext| ext == "idx")
+                && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("pack-"));
+            let pack = path.with_extension("pack");
+            if is_idx && pack.exists() {
+                packs += 1;
+                size += entry.metadata()?.len() + pack.metadata()?.len();
+            }
+        }
+        Ok((packs, size))
+    }
+}
+
+impl SubCommand for CountObjects {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let loose = loose_objects(&gitdir)?;
+        // git counts the blocks a file takes on disk, not its length
+        let mut size = 0;
+        for (_, path) in &loose {
+            size += std::fs::metadata(path)?.blocks() * 512;
+        }
+
+        if !self.verbose {
+            println!("{} objects, {} kilobytes", loose.len(), size / 1024);
+            return Ok(0);
+        }
+        let (packs, size_pack) = Self::pack_files(&gitdir)?;
+        let prune_packable = loose.iter().filter(|(hash, _)| has_packed_object(&gitdir, hash)).count();
+        println!("count: {}", loose.len());
+        println!("size: {}", size / 1024);
+        println!("in-pack: {}", packed_hashes(&gitdir)?.len());
+        println!("packs: {}", packs);
+        println!("size-pack: {}", size_pack / 1024);
+        println!("prune-packable: {}", prune_packable);
+        Ok(0)
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::status::{Changes, Status},
+    utils::{
+        commit::Commit,
+        fs::{read_obj, work_tree},
+        hash::ObjectId,
+        index::Index,
+        ident::split_ident,
+        objtype::Obj,
+        refs::for_each_ref,
+        revparse::resolve_commit,
+        revwalk,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// git stops looking after this many tags, `--candidates` in git
+const MAX_CANDIDATES: usize = 10;
+
+#[derive(Parser, Debug)]
+#[command(name = "describe", about = "Give an object a human readable name based on an available ref")]
+pub struct Describe {
+    #[arg(long, help = "use any tag, not only annotated ones")]
+    tags: bool,
+
+    #[arg(long, help = "show the abbreviated commit when no tag can describe it")]
+    always: bool,
+
+    #[arg(long, help = "always output the long format, even when the commit is tagged")]
+    long: bool,
+
+    #[arg(long, value_name = "n", default_value_t = 7, help = "use <n> hex digits of the commit, 0 shows only the tag")]
+    abbrev: usize,
+
+    #[arg(long, value_name = "mark", num_args = 0..=1, require_equals = true, default_missing_value = "-dirty",
+        help = "describe HEAD and append <mark> when the working tree has changes")]
+    dirty: Option<String>,
+
+    #[arg(help = "the commit-ish to describe, HEAD by default")]
+    commit: Option<String>,
+}
+
+/// a tag which names a commit, annotated tags win over lightweight ones on the same commit
+struct TagName {
+    name: String,
+    annotated: bool,
+    /// tagger time, a newer annotated tag wins
+    time: i64,
+}
+
+impl Describe {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Describe::try_parse_from(args)?))
+    }
+
+    /// the commit each tag points at, peeled through annotated tags
+    fn tag_names(gitdir: &Path) -> Result<HashMap<ObjectId, TagName>> {
+        let mut names: HashMap<ObjectId, TagName> = HashMap::new();
+        for (refname, mut hash) in for_each_ref(gitdir, "refs/tags/")? {
+            let name = refname["refs/tags/".len()..].to_string();
+            let mut tagger = None;
+            let commit = loop {
+                match read_obj(gitdir.to_path_buf(), &hash)? {
+                    Obj::G(tag) => {
+                        tagger.get_or_insert(tag.tagger);
+                        hash = tag.object;
+                    },
+                    Obj::C(_) => break Some(hash),
+                    // tags of trees and blobs describe nothing
+                    _ => break None,
+                }
+            };
+            let Some(commit) = commit else {
+                continue;
+            };
+            let tag = TagName {
+                name,
+                annotated: tagger.is_some(),
+                time: tagger.as_deref().and_then(split_ident).map(|(_, time, _)| time).unwrap_or_default(),
+            };
+            // refs come sorted, a later one only replaces a worse one
+            let better = |old: &TagName| (tag.annotated, tag.time) > (old.annotated, old.time);
+            if names.get(&commit).is_none_or(better) {
+                names.insert(commit, tag);
+            }
+        }
+        Ok(names)
+    }
+
+    /// staged or unstaged changes to tracked files, untracked ones don't count
+    fn is_dirty(gitdir: &Path) -> Result<bool> {
+        let index = Index::read_or_default(gitdir)?;
+        let mut changes = Changes::new();
+        Status::staged(gitdir, &index, &mut changes)?;
+        Status::unstaged(&work_tree(gitdir)?, &index, &mut changes)?;
+        Ok(!changes.is_empty())
+    }
+}
+
+impl SubCommand for Describe {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        if self.dirty.is_some() && self.commit.is_some() {
+            return Err(GitError::invalid_argument("option '--dirty' and commit-ishes cannot be used together".to_string()));
+        }
+        let hash = resolve_commit(&gitdir, self.commit.as_deref().unwrap_or("HEAD"))?;
+        let hex = hash.to_hex();
+        let abbrev = &hex[..self.abbrev.clamp(4, hex.len())];
+        let suffix = match &self.dirty {
+            Some(mark) if Self::is_dirty(&gitdir)? => mark.as_str(),
+            _ => "",
+        };
+        let all_names = Self::tag_names(&gitdir)?;
+        let has_lightweight = all_names.values().any(|tag| !tag.annotated);
+        let names = all_names.into_iter()
+            .filter(|(_, tag)| self.tags || tag.annotated)
+            .collect::<HashMap<_, _>>();
+
+        if let Some(tag) = names.get(&hash) {
+            match self.long && self.abbrev > 0 {
+                true => println!("{}-0-g{}{}", tag.name, abbrev, suffix),
+                false => println!("{}{}", tag.name, suffix),
+            }
+            return Ok(0);
+        }
+
+        // the nearest tags in date order, then the one with the fewest commits on top of it:
+        // commits reachable from HEAD but not from the tag, so every path through merges counts
+        let mut cache = HashMap::new();
+        let candidates = revwalk::date_order(&gitdir, vec![hash], &mut cache)?
+            .into_iter()
+            .filter(|commit| names.contains_key(commit))
+            .take(MAX_CANDIDATES)
+            .collect::<Vec<_>>();
+        let total = revwalk::reachable(&gitdir, vec![hash], &mut cache)?.len();
+        let mut best: Option<(usize, &ObjectId)> = None;
+        for commit in &candidates {
+            let depth = total - revwalk::reachable(&gitdir, vec![*commit], &mut cache)?.len();
+            if best.is_none_or(|(best_depth, _)| depth < best_depth) {
+                best = Some((depth, commit));
+            }
+        }
+
+        match best {
+            Some((_, commit)) if self.abbrev == 0 => println!("{}{}", names[commit].name, suffix),
+            Some((depth, commit)) => println!("{}-{}-g{}{}", names[commit].name, depth, abbrev, suffix),
+            None if self.always => println!("{}{}", abbrev, suffix),
+            None if names.is_empty() && !has_lightweight => {
+                return Err(GitError::refused("No names found, cannot describe anything.".to_string()));
+            },
+            None if names.is_empty() => {
+                return Err(GitError::refused(format!("No annotated tags can describe '{}'.\nHowever, there were unannotated tags: try --tags.", hash)));
+            },
+            None => {
+                return Err(GitError::refused(format!("No tags can describe '{}'.\nTry --always, or create some tags.", hash)));
+            },
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_describe() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+        // one commit a minute, so the date order git walks in is fixed
+        let mut time = 1600000000;
+        let mut at = |args: &[&str]| {
+            time += 60;
+            let date = format!("{} +0000", time);
+            let status = Command::new("git").arg("-C").arg(temp_path_str).args(args)
+                .env("GIT_AUTHOR_DATE", &date)
+                .env("GIT_COMMITTER_DATE", &date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", args);
+        };
+
+        at(&["commit", "--allow-empty", "-m", "one"]);
+        // dies with git's own message, nothing in front of it
+        let stderr = |program: &[&str]| {
+            let out = Command::new(program[0]).args(&program[1..]).args(["-C", temp_path_str, "describe"]).output().unwrap();
+            (out.status.code(), String::from_utf8(out.stderr).unwrap())
+        };
+        assert_eq!(stderr(&["cargo", "run", "--quiet", "--"]), stderr(&["git"]));
+        assert_eq!(cargo(&["describe", "--always"]).unwrap(), git(&["rev-parse", "--short", "HEAD"]).unwrap());
+        at(&["commit", "--allow-empty", "-m", "two"]);
+        git(&["tag", "light"]).unwrap();
+        // only a lightweight tag: describe needs --tags
+        assert!(cargo(&["describe"]).is_err());
+        at(&["commit", "--allow-empty", "-m", "three"]);
+        at(&["tag", "-a", "v1", "-m", "v1"]);
+        at(&["commit", "--allow-empty", "-m", "four"]);
+        at(&["commit", "--allow-empty", "-m", "five"]);
+        at(&["checkout", "-b", "side", "HEAD~1"]);
+        at(&["commit", "--allow-empty", "-m", "s1"]);
+        at(&["tag", "-a", "v2", "-m", "v2"]);
+        at(&["commit", "--allow-empty", "-m", "s2"]);
+        at(&["checkout", "master"]);
+        at(&["commit", "--allow-empty", "-m", "six"]);
+        at(&["merge", "--no-edit", "side"]);
+        at(&["commit", "--allow-empty", "-m", "seven"]);
+
+        // exact tags, tags at different depths and a merge with a tag on either side
+        let revs = [
+            &[][..], &["--tags"], &["--always"], &["HEAD~1"], &["HEAD~1^2"], &["side"], &["v1"],
+            &["HEAD~3"], &["--tags", "HEAD~4"], &["HEAD~5"], &["--tags", "HEAD~6"], &["--tags", "light"],
+        ];
+        for rev in revs {
+            let args = [&["describe"][..], rev].concat();
+            assert_eq!(cargo(&args).unwrap(), git(&args).unwrap(), "{:?}", args);
+        }
+        assert!(cargo(&["describe", "HEAD~6"]).is_err());
+
+        // the long format, other abbreviations and a dirty mark
+        for args in [
+            &["--long"][..], &["--long", "side"], &["--abbrev=10"], &["--abbrev=0", "HEAD~1"], &["--always", "--abbrev=12", "--tags", "HEAD~6"],
+            &["--dirty"], &["--dirty=.mod", "--long"],
+        ] {
+            let args = [&["describe"][..], args].concat();
+            assert_eq!(cargo(&args).unwrap(), git(&args).unwrap(), "{:?}", args);
+        }
+        // untracked files leave it clean, a staged one does not
+        std::fs::write(temp.path().join("tracked.txt"), "x").unwrap();
+        assert_eq!(cargo(&["describe", "--dirty"]).unwrap(), git(&["describe", "--dirty"]).unwrap());
+        git(&["add", "tracked.txt"]).unwrap();
+        assert!(cargo(&["describe", "--dirty"]).unwrap().ends_with("-dirty\n"));
+        for args in [&["describe", "--dirty"][..], &["describe", "--dirty=-wip", "--abbrev=0"]] {
+            assert_eq!(cargo(args).unwrap(), git(args).unwrap(), "{:?}", args);
+        }
+        assert!(cargo(&["describe", "--dirty", "HEAD"]).is_err());
+    }
+}
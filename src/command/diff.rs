@@ -0,0 +1,199 @@
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::{Grep, ReadTree},
+    utils::{
+        blob::Blob,
+        diff::{diff_files, file_diff, flatten_tree, FileVersion, Files},
+        fs::read_worktree_file,
+        hash::ObjectId,
+        hash::hash_object,
+        index::Index,
+        revparse::resolve_tree,
+        tree::FileMode,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "diff", about = "Show changes between the working tree, the index and commits")]
+pub struct Diff {
+    #[arg(long, visible_alias = "staged", help = "compare the index with HEAD or the given commit")]
+    cached: bool,
+
+    #[arg(value_name = "commit|path", help = "up to two commits or trees, `A..B` names both, then paths to limit the diff to")]
+    args: Vec<String>,
+
+    #[arg(last = true, value_name = "path", help = "paths to limit the diff to")]
+    paths: Vec<PathBuf>,
+}
+
+impl Diff {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Diff::try_parse_from(args)?))
+    }
+
+    /// the leading arguments naming trees, like git an argument is a revision when it resolves to one
+    fn revisions(&self, gitdir: &Path) -> (Vec<ObjectId>, usize) {
+        if let Some((a, b)) = self.args.first().and_then(|arg| arg.split_once("..")) {
+            let side = |rev: &str| resolve_tree(gitdir, if rev.is_empty() { "HEAD" } else { rev }).ok();
+            if let (Some(a), Some(b)) = (side(a), side(b)) {
+                return (vec![a, b], 1);
+            }
+        }
+        let trees = self.args.iter()
+            .take(2)
+            .map_while(|arg| resolve_tree(gitdir, arg).ok())
+            .collect::<Vec<_>>();
+        let used = trees.len();
+        (trees, used)
+    }
+
+    /// (mode, hash) of every stage 0 entry, conflicted paths have no single version
+    fn index_files(index: &Index) -> Files {
+        index.entries.iter()
+            .filter(|entry| entry.stage == 0)
+            .map(|entry| (entry.name.clone(), (entry.mode, entry.hash)))
+            .collect()
+    }
+
+    /// the index against the worktree, the worktree side is hashed but never written
+    fn diff_worktree(repo: &Repository, index: &Index, scope: &[PathBuf]) -> Result<()> {
+        let gitdir = &repo.gitdir;
+        let mut entries = index.entries.iter()
+            // conflicted paths have no single version to compare with, submodules are not looked into
+            .filter(|entry| entry.stage == 0 && entry.mode != FileMode::Commit as u32)
+            .filter(|entry| scope.iter().any(|dir| entry.name.starts_with(dir)))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()));
+
+        let mut stdout = io::stdout().lock();
+        for entry in entries {
+            let path = repo.work_tree()?.join(&entry.name);
+            let new = match fs::symlink_metadata(&path) {
+                Ok(meta) if !meta.is_dir() => {
+                    if ReadTree::is_uptodate(repo.work_tree()?, entry)? {
+                        continue;
+                    }
+                    let (mode, content) = read_worktree_file(&path)?;
+                    Some(FileVersion { mode, hash: hash_object::<Blob>(content.clone())?, content })
+                },
+                // deleted, or replaced by a directory
+                _ => None,
+            };
+            // only the stat data changed
+            if new.as_ref().is_some_and(|new| new.mode == entry.mode && new.hash == entry.hash) {
+                continue;
+            }
+            let old = FileVersion::from_blob(gitdir, entry.mode, &entry.hash)?;
+            let name = String::from_utf8_lossy(entry.name.as_os_str().as_bytes());
+            stdout.write_all(file_diff(&name, Some(&old), new.as_ref()).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl SubCommand for Diff {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let index = Index::read_or_default(gitdir)?;
+        let (trees, used) = self.revisions(gitdir);
+        let mut paths = self.args.iter().skip(used).map(PathBuf::from).collect::<Vec<_>>();
+        paths.extend(self.paths.iter().cloned());
+        // unlike grep the whole work tree is the default, like git
+        let scope = match paths.is_empty() {
+            true => vec![PathBuf::new()],
+            false => Grep::scope(&repo, &paths),
+        };
+        let in_scope = |files: Files| files.into_iter()
+            .filter(|(path, _)| scope.iter().any(|dir| path.starts_with(dir)))
+            .collect::<Files>();
+
+        let (old, new) = match (trees.as_slice(), self.cached) {
+            ([], false) => return Self::diff_worktree(&repo, &index, &scope).map(|_| 0),
+            ([old, new], _) => (flatten_tree(gitdir, old)?, flatten_tree(gitdir, new)?),
+            // before the first commit everything in the index is new
+            ([], true) => match resolve_tree(gitdir, "HEAD") {
+                Ok(head) => (flatten_tree(gitdir, &head)?, Self::index_files(&index)),
+                Err(_) => (Files::new(), Self::index_files(&index)),
+            },
+            ([old], true) => (flatten_tree(gitdir, old)?, Self::index_files(&index)),
+            _ => return Err(GitError::invalid_command("comparing a commit with the worktree is not supported, use --cached or two commits".to_string())),
+        };
+        print!("{}", diff_files(gitdir, &in_scope(old), &in_scope(new))?);
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_diff() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        std::fs::create_dir_all(temp_path.join("src")).unwrap();
+        let lines = (1..=30).map(|i| format!("line {}\n", i)).collect::<String>();
+        std::fs::write(temp_path.join("long.txt"), &lines).unwrap();
+        std::fs::write(temp_path.join("src/lib.rs"), "fn lib() {}\n").unwrap();
+        std::fs::write(temp_path.join("gone.txt"), "gone\n").unwrap();
+        std::fs::write(temp_path.join("run.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::write(temp_path.join("staged.txt"), "one\n").unwrap();
+        std::fs::write(temp_path.join("data.bin"), b"\0binary").unwrap();
+        git(&["add", "."]).unwrap();
+        git(&["commit", "-m", "init"]).unwrap();
+        assert_eq!(cargo(&["diff"]).unwrap(), "");
+
+        // two hunks, a deletion, a mode change, a binary file; staged changes are not shown
+        std::fs::write(temp_path.join("long.txt"), lines.replace("line 2\n", "two\n").replace("line 25\n", "")).unwrap();
+        std::fs::write(temp_path.join("src/lib.rs"), "fn lib() {}\nfn more() {}").unwrap();
+        std::fs::remove_file(temp_path.join("gone.txt")).unwrap();
+        std::fs::set_permissions(temp_path.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(temp_path.join("staged.txt"), "two\n").unwrap();
+        git(&["add", "staged.txt"]).unwrap();
+        std::fs::write(temp_path.join("data.bin"), b"\0changed").unwrap();
+        std::fs::write(temp_path.join("untracked"), "untracked\n").unwrap();
+        // touched but the same content
+        std::fs::write(temp_path.join("staged.txt"), "two\n").unwrap();
+
+        for args in [&["diff"][..], &["diff", "src"], &["diff", "--", "long.txt", "gone.txt"]] {
+            assert_eq!(cargo(args).unwrap(), git(args).unwrap(), "{:?}", args);
+        }
+
+        // the index against HEAD or another commit, and commit against commit
+        git(&["add", "src", "gone.txt"]).unwrap();
+        std::fs::write(temp_path.join("src/new.rs"), "new\n").unwrap();
+        git(&["add", "src/new.rs"]).unwrap();
+        git(&["commit", "-m", "second"]).unwrap();
+        git(&["add", "."]).unwrap();
+        git(&["tag", "v1", "HEAD~1"]).unwrap();
+        let tree = git(&["rev-parse", "HEAD^{tree}"]).unwrap();
+        let diffs: &[&[&str]] = &[
+            &["diff", "--cached"], &["diff", "--staged", "src"], &["diff", "--cached", "v1"], &["diff", "--cached", "HEAD", "--", "run.sh"],
+            &["diff", "HEAD~1", "HEAD"], &["diff", "v1..HEAD"], &["diff", "HEAD", "v1", "src"], &["diff", "HEAD~1", tree.trim(), "--", "gone.txt"],
+        ];
+        for args in diffs {
+            assert_eq!(cargo(args).unwrap(), git(args).unwrap(), "{:?}", args);
+        }
+        assert!(cargo(&["diff", "HEAD"]).is_err());
+    }
+}
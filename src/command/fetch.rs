@@ -1,10 +1,17 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use clap::Parser;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use crate::{GitError, Result, utils::refs::*};
-use crate::utils::protocol::GitProtocol;
-use crate::utils::packfile::PackfileProcessor;
-use super::SubCommand;
+use crate::utils::{fs::read_object, commit::Commit, hash::ObjectId};
+use crate::utils::protocol::{GitProtocol, Deepen, INFINITE_DEPTH};
+use crate::utils::shallow::{read_shallow, write_shallow};
+use crate::utils::config::Config;
+use crate::utils::packfile::{PackfileProcessor, read_packed_object};
+use super::{SubCommand, LsRemote};
+use crate::utils::fs::{Repository, obj_to_pathbuf, object_exists, write_loose_object};
+
+/// 协商时最多告诉服务器的本地提交数量
+const MAX_HAVES: usize = 256;
 
 #[derive(Parser, Debug)]
 #[command(name = "fetch", about = "从远程仓库下载对象和引用")]
@@ -19,12 +26,24 @@ pub struct Fetch {
     /// 显示详细信息
     #[arg(short, long)]
     verbose: bool,
+
+    /// 把收到的对象逐个写成松散对象，而不是保存 pack 和 idx
+    #[arg(long)]
+    unpack: bool,
+
+    /// 只要每个分支最近的 N 个提交；已经是 shallow 的仓库不带这个参数时会补全历史
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    depth: Option<u32>,
+
+    /// 删掉远程已经没有的分支对应的远程跟踪分支
+    #[arg(short, long)]
+    prune: bool,
 }
 
 #[derive(Debug)]
 pub struct FetchResult {
-    pub updated_refs: HashMap<String, String>,
-    pub new_refs: HashMap<String, String>,
+    pub updated_refs: HashMap<String, ObjectId>,
+    pub new_refs: HashMap<String, ObjectId>,
     pub deleted_refs: Vec<String>,
 }
 
@@ -32,9 +51,48 @@ impl Fetch {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
         Ok(Box::new(Fetch::try_parse_from(args)?))
     }
+
+    /// `remote prune <name>`：只比较引用，不下载对象，返回删掉的远程跟踪分支
+    pub fn prune_remote(gitdir: &Path, remote: &str) -> Result<Vec<String>> {
+        let fetch = Fetch::try_parse_from(["fetch", "--prune", remote])?;
+        let config = fetch.read_remote_config(gitdir)?;
+        let branches = if config.url.starts_with("http") {
+            GitProtocol::new(&Config::read_all(gitdir)?, &config.url)?
+                .discover_refs_http(&config.url)?
+                .into_iter()
+                .filter_map(|remote_ref| remote_ref.name.strip_prefix("refs/heads/").map(str::to_string))
+                .collect()
+        } else if config.url.starts_with("git@") || config.url.contains("ssh://") {
+            return Err(GitError::invalid_command(format!("cannot list the refs of {} over ssh", config.url)));
+        } else {
+            list_refs(Path::new(&config.url), "refs/heads")?.into_iter().collect()
+        };
+        fetch.prune_tracking_refs(gitdir, &branches)
+    }
+
+    /// 远程跟踪分支里，远程已经没有的那些删掉（包括 feature/x 这样的子目录），`HEAD` 不动
+    /// 给了 refspec 时只看它们对应的远程跟踪分支，别的分支服务器根本没有列出来
+    fn prune_tracking_refs(&self, gitdir: &Path, branches: &HashSet<String>) -> Result<Vec<String>> {
+        let mut deleted = Vec::new();
+        if !self.prune {
+            return Ok(deleted);
+        }
+        let tracking_dir = format!("refs/remotes/{}", self.remote);
+        let in_scope = |name: &str| self.refspecs.is_empty() || self.refspecs.contains(&format!("refs/heads/{}", name));
+        for name in list_refs(gitdir, &tracking_dir)? {
+            if name == "HEAD" || branches.contains(&name) || !in_scope(&name) {
+                continue;
+            }
+            let ref_name = format!("{}/{}", tracking_dir, name);
+            delete_ref(gitdir, &ref_name)?;
+            println!(" - [deleted]         (none)     -> {}/{}", self.remote, name);
+            deleted.push(ref_name);
+        }
+        Ok(deleted)
+    }
     
     /// 模拟fetch操作 - 用于测试和开发
-    fn simulate_fetch(&self, gitdir: &PathBuf) -> Result<FetchResult> {
+    fn simulate_fetch(&self, gitdir: &Path) -> Result<FetchResult> {
         // 检查远程配置而不是目录
         let _config = self.read_remote_config(gitdir)?;
         
@@ -44,16 +102,16 @@ impl Fetch {
         let mut new_refs = HashMap::new();
         
         // 模拟创建一个远程跟踪分支
-        let remote_ref_path = gitdir.join("refs").join("remotes").join(&self.remote);
+        let remote_ref_path = ref_file(gitdir, &format!("refs/remotes/{}", self.remote));
         if let Some(parent) = remote_ref_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
         
         // 模拟分支引用
         let ref_name = format!("refs/remotes/{}/main", self.remote);
-        let fake_commit = "0000000000000000000000000000000000000000";
+        let fake_commit = ObjectId::null();
         
-        new_refs.insert(ref_name, fake_commit.to_string());
+        new_refs.insert(ref_name, fake_commit);
         println!(" * [simulated]       main -> {}/main", self.remote);
         
         Ok(FetchResult {
@@ -63,43 +121,14 @@ impl Fetch {
         })
     }
     
-    fn read_remote_config(&self, gitdir: &PathBuf) -> Result<RemoteConfig> {
-        let config_path = gitdir.join("config");
-        let config_content = std::fs::read_to_string(config_path)?;
-        
-        // 简单的config解析
-        let mut url = None;
-        let mut fetch_specs = Vec::new();
-        let mut in_remote_section = false;
-        
-        for line in config_content.lines() {
-            let line = line.trim();
-            
-            if line == &format!("[remote \"{}\"]", self.remote) {
-                in_remote_section = true;
-                continue;
-            }
-            
-            if line.starts_with('[') && line.ends_with(']') {
-                in_remote_section = false;
-                continue;
-            }
-            
-            if in_remote_section {
-                if let Some(url_value) = line.strip_prefix("url = ") {
-                    url = Some(url_value.to_string());
-                } else if let Some(fetch_value) = line.strip_prefix("fetch = ") {
-                    fetch_specs.push(fetch_value.to_string());
-                }
-            }
-        }
-        
+    fn read_remote_config(&self, gitdir: &Path) -> Result<RemoteConfig> {
+        let config = Config::read_all(gitdir)?;
+        let url = config.get(&format!("remote.{}.url", self.remote))
+            .ok_or_else(|| GitError::invalid_command(format!("No URL found for remote '{}'", self.remote)))?;
         Ok(RemoteConfig {
             name: self.remote.clone(),
-            url: url.ok_or_else(|| GitError::invalid_command(
-                format!("No URL found for remote '{}'", self.remote)
-            ))?,
-            fetch_specs,
+            url: url.to_string(),
+            fetch_specs: config.get_all(&format!("remote.{}.fetch", self.remote)).into_iter().map(str::to_string).collect(),
         })
     }
     
@@ -121,10 +150,10 @@ impl Fetch {
         }
     }
     
-    fn fetch_via_http(&self, gitdir: &PathBuf, config: &RemoteConfig) -> Result<FetchResult> {
+    fn fetch_via_http(&self, gitdir: &Path, config: &RemoteConfig) -> Result<FetchResult> {
         println!("Fetching via HTTP from {}...", config.url);
         
-        let protocol = GitProtocol::new()?;
+        let protocol = GitProtocol::new(&Config::read_all(gitdir)?, &config.url)?;
         
         // 确定要获取的引用
         let wanted_refs = if self.refspecs.is_empty() {
@@ -134,24 +163,50 @@ impl Fetch {
             self.refspecs.clone()
         };
         
-        // 从远程获取数据
-        let packfile_data = protocol.fetch_via_http(&config.url, &wanted_refs)?;
+        // 从远程获取数据，告诉服务器本地已有的提交
+        let haves = self.local_haves(gitdir)?;
+        if self.verbose {
+            println!("Negotiating with {} local commit(s)", haves.len());
+        }
+        let shallow = read_shallow(gitdir);
+        let deepen = Deepen {
+            depth: self.depth.or((!shallow.is_empty()).then_some(INFINITE_DEPTH)),
+            shallow: shallow.iter().cloned().collect(),
+        };
+        let packfile_data = protocol.fetch_via_http(&config.url, &wanted_refs, &haves, &deepen)?;
+        
+        let branches = packfile_data.refs.iter()
+            .filter_map(|remote_ref| remote_ref.name.strip_prefix("refs/heads/").map(str::to_string))
+            .collect::<HashSet<_>>();
         
         if packfile_data.data.is_empty() {
-            println!("Already up to date");
             return Ok(FetchResult {
                 updated_refs: HashMap::new(),
                 new_refs: HashMap::new(),
-                deleted_refs: vec![],
+                deleted_refs: self.prune_tracking_refs(gitdir, &branches)?,
             });
         }
         
-        // 处理packfile
-        let mut processor = PackfileProcessor::new(gitdir.clone());
-        let created_objects = processor.process_packfile(&packfile_data.data)?;
-        
-        if self.verbose {
-            println!("Received {} objects", created_objects.len());
+        // 处理packfile，校验失败时任何远程跟踪分支都不动，--prune 也不删
+        let mut processor = PackfileProcessor::new(gitdir.to_path_buf());
+        let processed = if self.unpack {
+            processor.process_packfile(&packfile_data.data)
+        } else {
+            processor.store_packfile(&packfile_data.data)
+        };
+        let created_objects = processed
+            .map_err(|e| GitError::invalid_command(format!("pack received from {} was rejected, {}", config.url, e)))?;
+        println!("{} objects received, pack verified", created_objects.len());
+        let deleted_refs = self.prune_tracking_refs(gitdir, &branches)?;
+        
+        // 对象都到了才记录新的边界
+        if deepen.depth.is_some() {
+            let mut shallow = shallow;
+            shallow.extend(packfile_data.shallow.iter().cloned());
+            for hash in &packfile_data.unshallow {
+                shallow.remove(hash);
+            }
+            write_shallow(gitdir, &shallow)?;
         }
         
         // 更新远程跟踪分支
@@ -161,11 +216,7 @@ impl Fetch {
         for remote_ref in &packfile_data.refs {
             if remote_ref.name.starts_with("refs/heads/") {
                 let branch_name = remote_ref.name.strip_prefix("refs/heads/").unwrap();
-                let local_remote_ref_path = gitdir
-                    .join("refs")
-                    .join("remotes")
-                    .join(&self.remote)
-                    .join(branch_name);
+                let local_remote_ref_path = ref_file(gitdir, &format!("refs/remotes/{}/{}", self.remote, branch_name));
                 
                 // 创建目录
                 if let Some(parent) = local_remote_ref_path.parent() {
@@ -176,12 +227,12 @@ impl Fetch {
                 
                 if local_remote_ref_path.exists() {
                     let old_commit = std::fs::read_to_string(&local_remote_ref_path)?.trim().to_string();
-                    if old_commit != remote_ref.hash {
-                        updated_refs.insert(ref_name, remote_ref.hash.clone());
-                        println!("   {}..{}  {}", &old_commit[..8], &remote_ref.hash[..8], branch_name);
+                    if remote_ref.hash != old_commit {
+                        updated_refs.insert(ref_name, remote_ref.hash);
+                        println!("   {}..{}  {}", &old_commit[..8], &remote_ref.hash.to_hex()[..8], branch_name);
                     }
                 } else {
-                    new_refs.insert(ref_name, remote_ref.hash.clone());
+                    new_refs.insert(ref_name, remote_ref.hash);
                     println!(" * [new branch]      {} -> {}/{}", branch_name, self.remote, branch_name);
                 }
                 
@@ -191,20 +242,54 @@ impl Fetch {
         }
         
         // 写入FETCH_HEAD
-        let all_refs: HashMap<String, String> = updated_refs.iter()
+        let all_refs: HashMap<String, ObjectId> = updated_refs.iter()
             .chain(new_refs.iter())
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .map(|(k, v)| (k.clone(), *v))
             .collect();
         self.write_fetch_head(gitdir, &all_refs)?;
         
         Ok(FetchResult {
             updated_refs,
             new_refs,
-            deleted_refs: vec![],
+            deleted_refs,
         })
     }
     
-    fn fetch_via_ssh(&self, gitdir: &PathBuf, config: &RemoteConfig) -> Result<FetchResult> {
+    /// 本地已有的提交：本地分支和远程跟踪分支的 tip，然后按 BFS 顺序加入祖先，最多 MAX_HAVES 个
+    fn local_haves(&self, gitdir: &Path) -> Result<Vec<ObjectId>> {
+        let mut queue = VecDeque::new();
+        for dir in ["refs/heads", "refs/remotes"] {
+            for name in list_refs(gitdir, dir)? {
+                if let Ok(hash) = read_ref_commit(gitdir, &format!("{}/{}", dir, name)) {
+                    queue.push_back(hash);
+                }
+            }
+        }
+
+        // shallow 的边界提交的父提交不在本地
+        let shallow = read_shallow(gitdir);
+        let mut haves = Vec::new();
+        let mut seen = HashSet::new();
+        while let Some(hash) = queue.pop_front() {
+            if haves.len() >= MAX_HAVES {
+                break;
+            }
+            if !seen.insert(hash) {
+                continue;
+            }
+            // 对象不在本地（比如远程跟踪分支指向的提交没下载完）就不能当作 have
+            let Ok(commit) = read_object::<Commit>(gitdir.to_path_buf(), &hash) else {
+                continue;
+            };
+            if !shallow.contains(&hash) {
+                queue.extend(commit.parent_hash);
+            }
+            haves.push(hash);
+        }
+        Ok(haves)
+    }
+
+    fn fetch_via_ssh(&self, gitdir: &Path, config: &RemoteConfig) -> Result<FetchResult> {
         println!("SSH fetch not fully implemented yet");
         println!("Falling back to simulation for SSH URL: {}", config.url);
         self.simulate_fetch(gitdir)
@@ -212,7 +297,10 @@ impl Fetch {
     
     fn fetch_via_local(&self, gitdir: &PathBuf, config: &RemoteConfig) -> Result<FetchResult> {
         // 本地路径fetch（对于开发测试很有用）
-        let remote_gitdir = PathBuf::from(&config.url);
+        if self.depth.is_some() {
+            eprintln!("warning: --depth is ignored for local paths, use an http remote");
+        }
+        let remote_gitdir = LsRemote::local_gitdir(Path::new(&config.url));
         if !remote_gitdir.exists() {
             return Err(GitError::invalid_command(
                 format!("Remote path does not exist: {}", config.url)
@@ -227,49 +315,48 @@ impl Fetch {
         let mut updated_refs = HashMap::new();
         let mut new_refs = HashMap::new();
         
-        // 复制远程分支引用
-        let remote_heads = remote_gitdir.join("refs").join("heads");
-        if remote_heads.exists() {
-            for entry in std::fs::read_dir(&remote_heads)? {
-                let entry = entry?;
-                let branch_name = entry.file_name().to_string_lossy().to_string();
-                let remote_commit = std::fs::read_to_string(entry.path())?.trim().to_string();
-                
-                // 创建/更新本地的远程跟踪分支
-                let local_remote_ref = gitdir.join("refs").join("remotes").join(&self.remote);
-                std::fs::create_dir_all(&local_remote_ref)?;
-                
-                let local_remote_branch = local_remote_ref.join(&branch_name);
-                let ref_name = format!("refs/remotes/{}/{}", self.remote, branch_name);
-                
-                if local_remote_branch.exists() {
-                    let old_commit = std::fs::read_to_string(&local_remote_branch)?.trim().to_string();
-                    if old_commit != remote_commit {
-                        updated_refs.insert(ref_name.clone(), remote_commit.clone());
-                        println!("   {}..{}  {}", &old_commit[..8], &remote_commit[..8], branch_name);
-                    }
-                } else {
-                    new_refs.insert(ref_name.clone(), remote_commit.clone());
-                    println!(" * [new branch]      {} -> {}/{}", branch_name, self.remote, branch_name);
+        // 复制远程分支引用，feature/x 这样的分支在子目录里
+        let branches = list_refs(remote_gitdir, "refs/heads")?;
+        for branch_name in &branches {
+            let remote_commit = read_ref_commit(remote_gitdir, &format!("refs/heads/{}", branch_name))?;
+            
+            // 创建/更新本地的远程跟踪分支
+            let local_remote_branch = ref_file(gitdir, &format!("refs/remotes/{}/{}", self.remote, branch_name));
+            if let Some(parent) = local_remote_branch.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            
+            let ref_name = format!("refs/remotes/{}/{}", self.remote, branch_name);
+            
+            if local_remote_branch.exists() {
+                let old_commit = std::fs::read_to_string(&local_remote_branch)?.trim().to_string();
+                if remote_commit != old_commit {
+                    updated_refs.insert(ref_name.clone(), remote_commit);
+                    println!("   {}..{}  {}", &old_commit[..8], &remote_commit.to_hex()[..8], branch_name);
                 }
-                
-                std::fs::write(&local_remote_branch, format!("{}\n", remote_commit))?;
-                
-                // 递归复制所有依赖对象
-                self.copy_object_recursive(gitdir, remote_gitdir, &remote_commit)?;
+            } else {
+                new_refs.insert(ref_name.clone(), remote_commit);
+                println!(" * [new branch]      {} -> {}/{}", branch_name, self.remote, branch_name);
             }
+            
+            std::fs::write(&local_remote_branch, format!("{}\n", remote_commit))?;
+            
+            // 递归复制所有依赖对象
+            self.copy_object_recursive(gitdir, remote_gitdir, &remote_commit.to_hex())?;
         }
         
+        let deleted_refs = self.prune_tracking_refs(gitdir, &branches.into_iter().collect())?;
+        
         // 写入FETCH_HEAD
         let all_refs = updated_refs.iter().chain(new_refs.iter())
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .map(|(k, v)| (k.clone(), *v))
             .collect();
         self.write_fetch_head(gitdir, &all_refs)?;
         
         Ok(FetchResult {
             updated_refs,
             new_refs,
-            deleted_refs: vec![],
+            deleted_refs,
         })
     }
     
@@ -279,31 +366,33 @@ impl Fetch {
     }
 
     fn copy_object_recursive(&self, gitdir: &PathBuf, remote_gitdir: &PathBuf, object_hash: &str) -> Result<()> {
-        let obj_path = crate::utils::fs::obj_to_pathbuf(gitdir, object_hash);
-        if obj_path.exists() {
+        if object_exists(gitdir, object_hash) {
             return Ok(()); // 对象已存在
         }
-        
-        let remote_obj_path = crate::utils::fs::obj_to_pathbuf(remote_gitdir, object_hash);
-        if !remote_obj_path.exists() {
-            return Err(GitError::invalid_command(
+
+        let obj_path = obj_to_pathbuf(gitdir, object_hash);
+        let remote_obj_path = obj_to_pathbuf(remote_gitdir, object_hash);
+        let obj_data = if remote_obj_path.exists() {
+            // 复制对象文件
+            if let Some(parent) = obj_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(&remote_obj_path, &obj_path)?;
+            crate::utils::zlib::decompress_object(&std::fs::read(&obj_path)?)?
+        } else {
+            // 远程 gc 过或者是 git clone 出来的，对象在 pack 里，取出来写成松散对象
+            let raw = read_packed_object(remote_gitdir, object_hash)?.ok_or_else(|| GitError::invalid_command(
                 format!("Object {} not found in remote repository", object_hash)
-            ));
-        }
-        
-        // 复制对象文件
-        if let Some(parent) = obj_path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-        std::fs::copy(&remote_obj_path, &obj_path)?;
-        
+            ))?;
+            write_loose_object(&obj_path, &crate::utils::zlib::compress(raw.iter().copied())?)?;
+            raw
+        };
+
         if self.verbose {
             println!("Copied object {}", object_hash);
         }
-        
-        // 解析对象内容，递归复制依赖的对象
-        let obj_content = std::fs::read(&obj_path)?;
-                let obj_data = crate::utils::zlib::decompress_object(&obj_content)?;        // 根据对象类型解析依赖
+
+        // 解析对象内容，根据对象类型递归复制依赖的对象
         if let Some(null_pos) = obj_data.iter().position(|&b| b == 0) {
             let header = String::from_utf8_lossy(&obj_data[..null_pos]);
             let content = &obj_data[null_pos + 1..];
@@ -321,12 +410,11 @@ impl Fetch {
 
     fn copy_commit_dependencies(&self, gitdir: &PathBuf, remote_gitdir: &PathBuf, content: &[u8]) -> Result<()> {
         let content_str = String::from_utf8_lossy(content);
+        // 头部行剩下的就是整个 hash，长度跟着仓库的算法走
         for line in content_str.lines() {
-            if line.starts_with("tree ") {
-                let tree_hash = &line[5..45];
+            if let Some(tree_hash) = line.strip_prefix("tree ") {
                 self.copy_object_recursive(gitdir, remote_gitdir, tree_hash)?;
-            } else if line.starts_with("parent ") {
-                let parent_hash = &line[7..47];
+            } else if let Some(parent_hash) = line.strip_prefix("parent ") {
                 self.copy_object_recursive(gitdir, remote_gitdir, parent_hash)?;
             }
         }
@@ -343,16 +431,17 @@ impl Fetch {
                     let _mode = &entry_header[..space_pos];
                     let _name = &entry_header[space_pos + 1..];
                     
-                    // 提取20字节的hash
+                    // 提取 hash，长度看仓库用的算法
+                    let hash_len = crate::utils::hash::algorithm().raw_len();
                     let hash_start = pos + null_pos + 1;
-                    if hash_start + 20 <= content.len() {
-                        let hash_bytes = &content[hash_start..hash_start + 20];
+                    if hash_start + hash_len <= content.len() {
+                        let hash_bytes = &content[hash_start..hash_start + hash_len];
                         let hash = hex::encode(hash_bytes);
                         
                         // 递归复制依赖对象
                         self.copy_object_recursive(gitdir, remote_gitdir, &hash)?;
                         
-                        pos = hash_start + 20;
+                        pos = hash_start + hash_len;
                     } else {
                         break;
                     }
@@ -366,7 +455,7 @@ impl Fetch {
         Ok(())
     }
 
-    fn write_fetch_head(&self, gitdir: &PathBuf, refs: &HashMap<String, String>) -> Result<()> {
+    fn write_fetch_head(&self, gitdir: &Path, refs: &HashMap<String, ObjectId>) -> Result<()> {
         let fetch_head_path = gitdir.join("FETCH_HEAD");
         let mut content = String::new();
         
@@ -393,8 +482,8 @@ struct RemoteConfig {
 }
 
 impl SubCommand for Fetch {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
         
         println!("Fetching from {}...", self.remote);
         
@@ -408,12 +497,336 @@ impl SubCommand for Fetch {
         
         // 显示结果统计
         let total_updates = result.updated_refs.len() + result.new_refs.len();
+        if !result.deleted_refs.is_empty() {
+            println!("Pruned {} reference(s)", result.deleted_refs.len());
+        }
         if total_updates > 0 {
             println!("Fetched {} reference(s)", total_updates);
-        } else {
+        } else if result.deleted_refs.is_empty() {
             println!("Already up to date");
         }
         
         Ok(0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        serve_upload_pack,
+        serve_upload_pack_with,
+        serve_upload_pack_v0,
+        tempdir,
+    };
+
+    #[test]
+    fn test_fetch_sends_haves() {
+        let upstream = setup_test_git_dir();
+        let upstream_path = upstream.path();
+        let upstream_str = upstream_path.to_str().unwrap();
+        for i in 0..10 {
+            std::fs::write(upstream_path.join(format!("file{}.txt", i)), format!("content {}\n", i)).unwrap();
+        }
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(&[(&["add", "."], false), (&["commit", "-m", "first"], false)])(git_up).unwrap();
+        let (port, _) = serve_upload_pack(upstream_path.to_path_buf());
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        let url = format!("http://127.0.0.1:{}", port);
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "origin", &url]).unwrap();
+        let fetch = || shell_spawn(&["cargo", "run", "--quiet", "--", "-C", local_str, "fetch", "origin"]).unwrap();
+
+        // nothing in common yet: commit, tree and the 10 blobs
+        assert!(fetch().contains("Processing 12 objects"));
+
+        // one new commit upstream only brings its own commit, tree and blob
+        std::fs::write(upstream_path.join("file0.txt"), "changed\n").unwrap();
+        cmd_seq(&[(&["commit", "-am", "second"], false)])(git_up).unwrap();
+        assert!(fetch().contains("Processing 3 objects"));
+
+        let tip = shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD"]).unwrap();
+        assert_eq!(shell_spawn(&["git", "-C", local_str, "rev-parse", "refs/remotes/origin/master"]).unwrap(), tip);
+        shell_spawn(&["git", "-C", local_str, "rev-list", "--objects", "refs/remotes/origin/master"]).unwrap();
+
+        // up to date: no pack is requested at all
+        assert!(!fetch().contains("Processing"));
+
+        // the packs are kept as they are, with an index git accepts
+        let pack_dir = local.path().join(".git/objects/pack");
+        let idx_files = std::fs::read_dir(&pack_dir).unwrap()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+            .collect::<Vec<_>>();
+        assert_eq!(idx_files.len(), 2);
+        for idx in idx_files {
+            shell_spawn(&["git", "verify-pack", "-v", idx.to_str().unwrap()]).unwrap();
+        }
+        shell_spawn(&["git", "-C", local_str, "fsck", "--full"]).unwrap();
+
+        // and objects are read back from them
+        let short = &tip[..8];
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", local_str, "rev-parse", &format!("{}~1", short)]).unwrap();
+        assert_eq!(real, shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD~1"]).unwrap());
+    }
+
+    #[test]
+    fn test_fetch_unpack() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        std::fs::write(upstream.path().join("a.txt"), "a\n").unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "first"], false)])(git_up).unwrap();
+        let (port, _) = serve_upload_pack(upstream.path().to_path_buf());
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "origin", &format!("http://127.0.0.1:{}", port)]).unwrap();
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", local_str, "fetch", "--unpack", "origin"]).unwrap();
+
+        assert!(!local.path().join(".git/objects/pack").read_dir().unwrap().any(|_| true));
+        let commit = shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD"]).unwrap();
+        assert!(local.path().join(".git/objects").join(&commit[..2]).join(commit[2..].trim()).exists());
+    }
+
+    #[test]
+    fn test_fetch_through_proxy() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        std::fs::write(upstream.path().join("a.txt"), "a\n").unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "first"], false)])(git_up).unwrap();
+        let (port, requests) = serve_upload_pack(upstream.path().to_path_buf());
+
+        // the remote host doesn't resolve, only the proxy can reach it
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "origin", "http://git.invalid/repo.git"]).unwrap();
+        let proxy = format!("http.proxy=127.0.0.1:{}", port);
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-c", &proxy, "-C", local_str, "fetch", "origin"]).unwrap();
+
+        let requests = requests.try_iter().collect::<Vec<_>>();
+        assert_eq!(requests[0], "GET http://git.invalid/repo.git/info/refs?service=git-upload-pack HTTP/1.1");
+        assert!(requests[1..].iter().all(|line| line == "POST http://git.invalid/repo.git/git-upload-pack HTTP/1.1"));
+        let tip = shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD"]).unwrap();
+        assert_eq!(shell_spawn(&["git", "-C", local_str, "rev-parse", "refs/remotes/origin/master"]).unwrap(), tip);
+
+        // the same from the repository config
+        shell_spawn(&["git", "-C", local_str, "config", "http.proxy", "127.0.0.1:1"]).unwrap();
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", local_str, "fetch", "origin"]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_depth() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        for i in 0..10 {
+            std::fs::write(upstream.path().join("file.txt"), format!("version {}\n", i)).unwrap();
+            cmd_seq(&[(&["add", "file.txt"], false), (&["commit", "-m", &format!("commit {}", i)], false)])(git_up).unwrap();
+        }
+        let (port, _) = serve_upload_pack(upstream.path().to_path_buf());
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "origin", &format!("http://127.0.0.1:{}", port)]).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", local_str][..], args].concat());
+        let commits = || shell_spawn(&["git", "-C", local_str, "cat-file", "--batch-all-objects", "--batch-check=%(objecttype)"])
+            .unwrap()
+            .lines()
+            .filter(|line| *line == "commit")
+            .count();
+        let tip = shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD"]).unwrap();
+
+        cargo(&["fetch", "--depth", "1", "origin"]).unwrap();
+        assert_eq!(commits(), 1);
+        assert_eq!(std::fs::read_to_string(local.path().join(".git/shallow")).unwrap(), tip);
+        // the history stops at the boundary instead of failing on the missing parent
+        let log = shell_spawn(&["git", "-C", local_str, "log", "--oneline", "origin/master"]).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert_eq!(cargo(&["merge-base", "origin/master", "origin/master"]).unwrap(), tip);
+        shell_spawn(&["git", "-C", local_str, "fsck"]).unwrap();
+
+        // a bigger depth moves the boundary down
+        cargo(&["fetch", "--depth", "3", "origin"]).unwrap();
+        assert_eq!(commits(), 3);
+        let boundary = shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD~2"]).unwrap();
+        assert_eq!(std::fs::read_to_string(local.path().join(".git/shallow")).unwrap(), boundary);
+
+        // without --depth the rest of the history comes in and the repository isn't shallow any more
+        cargo(&["fetch", "origin"]).unwrap();
+        assert_eq!(commits(), 10);
+        assert!(!local.path().join(".git/shallow").exists());
+        let log = shell_spawn(&["git", "-C", local_str, "log", "--oneline", "origin/master"]).unwrap();
+        assert_eq!(log.lines().count(), 10);
+        shell_spawn(&["git", "-C", local_str, "fsck"]).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_prune() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(&[
+            (&["commit", "--allow-empty", "-m", "init"], false),
+            (&["branch", "topic"], false),
+            (&["branch", "feature/a"], false),
+            (&["branch", "feature/b"], false),
+            (&["branch", "old/deep/c"], false),
+        ])(git_up).unwrap();
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        let remote_dir = upstream.path().join(".git");
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "origin", remote_dir.to_str().unwrap()]).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", local_str][..], args].concat()).unwrap();
+        let tracking = || shell_spawn(&["git", "-C", local_str, "for-each-ref", "--format=%(refname)", "refs/remotes"]).unwrap();
+
+        cargo(&["fetch", "origin"]);
+        assert_eq!(tracking().lines().count(), 5);
+
+        // without --prune nothing goes away
+        cmd_seq(&[(&["branch", "-D", "topic", "feature/a", "old/deep/c"], false)])(git_up).unwrap();
+        cargo(&["fetch", "origin"]);
+        assert_eq!(tracking().lines().count(), 5);
+
+        let output = cargo(&["fetch", "--prune", "origin"]);
+        assert!(output.contains(" - [deleted]         (none)     -> origin/feature/a"));
+        assert!(output.contains(" - [deleted]         (none)     -> origin/old/deep/c"));
+        assert!(output.contains(" - [deleted]         (none)     -> origin/topic"));
+        assert_eq!(tracking(), "refs/remotes/origin/feature/b\nrefs/remotes/origin/master\n");
+        // the directories of slash-named branches are cleaned up once empty
+        assert!(!local.path().join(".git/refs/remotes/origin/old").exists());
+        assert!(local.path().join(".git/refs/remotes/origin/feature").exists());
+
+        // remote prune only compares refs
+        cmd_seq(&[(&["branch", "-D", "feature/b"], false)])(git_up).unwrap();
+        let output = cargo(&["remote", "prune", "origin"]);
+        assert!(output.contains(" - [deleted]         (none)     -> origin/feature/b"));
+        assert_eq!(tracking(), "refs/remotes/origin/master\n");
+        assert!(!local.path().join(".git/refs/remotes/origin/feature").exists());
+    }
+
+    /// flip the last byte of pack data, in the last sideband packet of band 1, which is the trailer
+    fn flip_pack_trailer(payload: &mut [u8]) {
+        let mut pos = 0;
+        let mut last_data = None;
+        while pos + 4 <= payload.len() {
+            let len = usize::from_str_radix(std::str::from_utf8(&payload[pos..pos + 4]).unwrap(), 16).unwrap();
+            if len > 4 && payload[pos + 4] == 1 {
+                last_data = Some(pos + len - 1);
+            }
+            pos += len.max(4);
+        }
+        payload[last_data.unwrap()] ^= 0x01;
+    }
+
+    #[test]
+    fn test_fetch_corrupt_pack() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        std::fs::write(upstream.path().join("a.txt"), "a\n").unwrap();
+        cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "first"], false), (&["branch", "topic"], false)])(git_up).unwrap();
+        let (good, _) = serve_upload_pack(upstream.path().to_path_buf());
+        let (bad, _) = serve_upload_pack_with(upstream.path().to_path_buf(), |payload| flip_pack_trailer(payload));
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", local_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", local_str][..], args].concat());
+        git(&["remote", "add", "origin", &format!("http://127.0.0.1:{}", good)]).unwrap();
+        assert!(cargo(&["fetch", "origin"]).unwrap().contains("3 objects received, pack verified"));
+
+        // new commits and a deleted branch upstream, but the pack is broken on the way
+        std::fs::write(upstream.path().join("a.txt"), "changed\n").unwrap();
+        cmd_seq(&[(&["commit", "-am", "second"], false), (&["branch", "-D", "topic"], false)])(git_up).unwrap();
+        git(&["remote", "set-url", "origin", &format!("http://127.0.0.1:{}", bad)]).unwrap();
+        let refs = || git(&["for-each-ref"]).unwrap();
+        let packs = || local.path().join(".git/objects/pack").read_dir().unwrap().count();
+        let (refs_before, packs_before) = (refs(), packs());
+        for args in [&["fetch", "origin"][..], &["fetch", "--prune", "origin"], &["fetch", "--unpack", "origin"]] {
+            assert!(cargo(args).is_err(), "{:?}", args);
+            assert_eq!(refs(), refs_before, "{:?}", args);
+            assert_eq!(packs(), packs_before, "{:?}", args);
+        }
+
+        // the same fetch from an intact server goes through
+        git(&["remote", "set-url", "origin", &format!("http://127.0.0.1:{}", good)]).unwrap();
+        cargo(&["fetch", "--prune", "origin"]).unwrap();
+        assert_eq!(git(&["rev-parse", "origin/master"]).unwrap(), shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD"]).unwrap());
+        assert!(git(&["rev-parse", "--verify", "origin/topic"]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_protocol_v2() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        let git_up = |args: &[&str]| shell_spawn(&[&["git", "-C", upstream_str][..], args].concat()).unwrap();
+        std::fs::write(upstream.path().join("a.txt"), "a\n").unwrap();
+        git_up(&["add", "a.txt"]);
+        git_up(&["commit", "-m", "first"]);
+        git_up(&["branch", "feature/x"]);
+        git_up(&["tag", "-a", "v1", "-m", "v1"]);
+
+        // v2 asks ls-refs and then fetch, both POSTs; an old server gets the single v0 request
+        for (server, posts) in [(serve_upload_pack as fn(_) -> _, 2), (serve_upload_pack_v0, 1)] {
+            let (port, requests) = server(upstream.path().to_path_buf());
+            let local = setup_test_git_dir();
+            let local_str = local.path().to_str().unwrap();
+            let git = |args: &[&str]| shell_spawn(&[&["git", "-C", local_str][..], args].concat()).unwrap();
+            let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", local_str][..], args].concat());
+            let url = format!("http://127.0.0.1:{}", port);
+            git(&["remote", "add", "origin", &url]);
+
+            cargo(&["fetch", "origin"]).unwrap();
+            let requests = requests.try_iter().collect::<Vec<_>>();
+            assert_eq!(requests.iter().filter(|line| line.starts_with("POST")).count(), posts, "{:?}", requests);
+            assert_eq!(git(&["for-each-ref", "--format=%(objectname) %(refname:lstrip=3)", "refs/remotes/origin"]),
+                git_up(&["for-each-ref", "--format=%(objectname) %(refname:lstrip=2)", "refs/heads"]));
+            git(&["fsck", "--full"]);
+
+            // ls-remote gives the same listing either way, peeled tags included
+            assert_eq!(cargo(&["ls-remote", &url]).unwrap(), git(&["ls-remote", upstream_str]));
+
+            // haves are negotiated: only the new commit, tree and blob come in
+            std::fs::write(upstream.path().join("a.txt"), format!("changed for {}\n", url)).unwrap();
+            git_up(&["commit", "-am", "second"]);
+            assert!(cargo(&["fetch", "origin"]).unwrap().contains("Processing 3 objects"));
+            assert_eq!(git(&["rev-parse", "origin/master"]), git_up(&["rev-parse", "HEAD"]));
+
+            // a refspec narrows what is listed and fetched, pruning stays inside it
+            git_up(&["commit", "--allow-empty", "-m", "on feature"]);
+            git_up(&["branch", "-f", "feature/x", "HEAD"]);
+            git_up(&["reset", "--hard", "HEAD~1"]);
+            cargo(&["fetch", "--prune", "origin", "refs/heads/feature/x"]).unwrap();
+            assert_eq!(git(&["rev-parse", "origin/feature/x"]), git_up(&["rev-parse", "feature/x"]));
+            assert_eq!(git(&["rev-parse", "origin/master"]), git_up(&["rev-parse", "master"]));
+            git_up(&["branch", "-D", "feature/x"]);
+        }
+    }
+
+    #[test]
+    fn test_fetch_local_sha256() {
+        let temp = tempdir().unwrap();
+        let (up, down) = (temp.path().join("up"), temp.path().join("down"));
+        let (up_str, down_str) = (up.to_str().unwrap(), down.to_str().unwrap());
+        for dir in [up_str, down_str] {
+            shell_spawn(&["git", "init", "-q", "--object-format=sha256", dir]).unwrap();
+        }
+        std::fs::write(up.join("a.txt"), "a\n").unwrap();
+        let git_up = &["git", "-C", up_str, "-c", "user.name=rust-git", "-c", "user.email=163@163.com"];
+        cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "one"], false), (&["commit", "--allow-empty", "-m", "two"], false)])(git_up).unwrap();
+        shell_spawn(&["git", "-C", down_str, "remote", "add", "origin", up_str]).unwrap();
+
+        // 64 个字符的 hash 在提交头里要整个读出来，父提交和 tree 都要拷过来
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", down_str, "fetch", "origin"]).unwrap();
+        let log = |dir: &str, rev: &str| shell_spawn(&["git", "-C", dir, "log", "--format=%H %T", rev]).unwrap();
+        assert_eq!(log(down_str, "origin/master"), log(up_str, "master"));
+        shell_spawn(&["git", "-C", down_str, "fsck", "--full"]).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+use crate::{
+    utils::{
+        commit::Commit,
+        fs::{common_dir, read_raw_object},
+        hash::{hash_raw_object, ObjectId},
+        index::Index,
+        packfile::{packed_hashes, read_packed_object},
+        refs::{for_each_ref, head_to_hash, read_reflog},
+        shallow::read_shallow,
+        tag::Tag,
+        zlib::decompress,
+    },
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// modes git writes into trees, `40000` without the leading zero
+const TREE_MODES: [&str; 5] = ["100644", "100755", "40000", "160000", "120000"];
+
+#[derive(Parser, Debug)]
+#[command(name = "fsck", about = "Verify the connectivity and validity of the objects in the database")]
+pub struct Fsck {
+    #[arg(long, help = "do not report unreachable objects that nothing points at")]
+    no_dangling: bool,
+}
+
+/// one line of the report, everything except `Dangling` means the repository is broken
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Problem {
+    /// not zlib data, or no `type size\0` header
+    Corrupt(String, String),
+    SizeMismatch(String, usize, usize),
+    HashMismatch(String, String),
+    /// the object exists but does not parse as what its header says
+    Broken(&'static str, String, String),
+    BadMode(String, String, String),
+    /// expected kind, hash, referenced by
+    Missing(&'static str, String, String),
+    WrongType(&'static str, String, &'static str),
+    Dangling(&'static str, String),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Corrupt(hash, reason) => write!(f, "corrupt {}: {}", hash, reason),
+            Problem::SizeMismatch(hash, header, actual) => write!(f, "size mismatch {}: header says {} bytes, content has {}", hash, header, actual),
+            Problem::HashMismatch(hash, actual) => write!(f, "hash mismatch {}: content hashes to {}", hash, actual),
+            Problem::Broken(kind, hash, reason) => write!(f, "broken {} {}: {}", kind, hash, reason),
+            Problem::BadMode(hash, name, mode) => write!(f, "bad mode {}: entry '{}' has mode {}", hash, name, mode),
+            Problem::Missing(kind, hash, parent) => write!(f, "missing {} {} (referenced by {})", kind, hash, parent),
+            Problem::WrongType(expected, hash, actual) => write!(f, "wrong type {}: expected {}, found {}", hash, expected, actual),
+            Problem::Dangling(kind, hash) => write!(f, "dangling {} {}", kind, hash),
+        }
+    }
+}
+
+/// what an object points at, with the kind each target must have
+type Links = Vec<(&'static str, String)>;
+
+impl Fsck {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Fsck::try_parse_from(args)?))
+    }
+
+    /// check every loose object on its own, returns the kind of each sound one
+    fn check_loose(gitdir: &Path, problems: &mut Vec<Problem>) -> Result<BTreeMap<String, &'static str>> {
+        let mut objects = BTreeMap::new();
+        let objects_dir = common_dir(gitdir).join("objects");
+        let mut paths = Vec::new();
+        for dir in fs::read_dir(&objects_dir)?.flatten() {
+            let prefix = dir.file_name().to_string_lossy().to_string();
+            if prefix.len() != 2 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())?.flatten() {
+                paths.push((format!("{}{}", prefix, file.file_name().to_string_lossy()), file.path()));
+            }
+        }
+        paths.sort();
+
+        for (hash, path) in paths {
+            match Self::check_raw(&hash, fs::read(&path).ok().and_then(|bytes| decompress(bytes).ok())) {
+                Ok(kind) => {
+                    objects.insert(hash, kind);
+                },
+                Err(problem) => problems.push(problem),
+            }
+        }
+        Ok(objects)
+    }
+
+    /// header, length and hash of a decompressed object
+    fn check_raw(hash: &str, raw: Option<Vec<u8>>) -> std::result::Result<&'static str, Problem> {
+        let raw = raw.ok_or_else(|| Problem::Corrupt(hash.to_string(), "unable to decompress".to_string()))?;
+        let header_end = raw.iter().position(|&b| b == 0)
+            .ok_or_else(|| Problem::Corrupt(hash.to_string(), "no object header".to_string()))?;
+        let header = String::from_utf8_lossy(&raw[..header_end]).to_string();
+        let (kind, size) = header.split_once(' ')
+            .and_then(|(kind, size)| Some((Self::kind(kind)?, size.parse::<usize>().ok()?)))
+            .ok_or_else(|| Problem::Corrupt(hash.to_string(), format!("bad object header '{}'", header)))?;
+        let content = raw[header_end + 1..].to_vec();
+        if content.len() != size {
+            return Err(Problem::SizeMismatch(hash.to_string(), size, content.len()));
+        }
+        let actual = hash_raw_object(kind, content).map_err(|e| Problem::Corrupt(hash.to_string(), e.to_string()))?;
+        if actual != hash {
+            return Err(Problem::HashMismatch(hash.to_string(), actual.to_hex()));
+        }
+        Ok(kind)
+    }
+
+    fn kind(name: &str) -> Option<&'static str> {
+        ["blob", "tree", "commit", "tag"].into_iter().find(|kind| *kind == name)
+    }
+
+    /// where walking starts: HEAD, every ref, what the reflogs remember and the index
+    fn roots(gitdir: &Path) -> Result<Vec<(&'static str, String, String)>> {
+        let mut roots = Vec::new();
+        if let Ok(hash) = head_to_hash(gitdir) {
+            roots.push(("commit", hash.to_hex(), "HEAD".to_string()));
+        }
+        let refs = for_each_ref(gitdir, "refs/")?;
+        for (refname, hash) in &refs {
+            // tags may point at any kind of object
+            let kind = if refname.starts_with("refs/tags/") { "object" } else { "commit" };
+            roots.push((kind, hash.to_hex(), refname.clone()));
+        }
+        for refname in std::iter::once("HEAD").chain(refs.iter().map(|(name, _)| name.as_str())) {
+            for entry in read_reflog(gitdir, refname).unwrap_or_default() {
+                for hash in [entry.old, entry.new] {
+                    if hash != ObjectId::null() {
+                        roots.push(("commit", hash.to_hex(), format!("reflog of {}", refname)));
+                    }
+                }
+            }
+        }
+        for entry in Index::read_or_default(gitdir)?.entries.into_iter().filter(|entry| entry.mode != 0o160000) {
+            roots.push(("blob", entry.hash.to_hex(), "index".to_string()));
+        }
+        Ok(roots)
+    }
+
+    /// the objects `hash` points at; trees are parsed by hand so one bad mode doesn't hide the rest
+    fn links(kind: &'static str, hash: &str, raw: Vec<u8>, shallow: &BTreeSet<String>, problems: &mut Vec<Problem>) -> Links {
+        let broken = |reason: String| Problem::Broken(kind, hash.to_string(), reason);
+        match kind {
+            "commit" => match Commit::try_from(raw) {
+                Ok(commit) => {
+                    let mut links = vec![("tree", commit.tree_hash.to_hex())];
+                    // the parents of a shallow boundary were never fetched
+                    if !shallow.contains(hash) {
+                        links.extend(commit.parent_hash.into_iter().map(|parent| ("commit", parent.to_hex())));
+                    }
+                    links
+                },
+                Err(e) => {
+                    problems.push(broken(e.to_string()));
+                    vec![]
+                },
+            },
+            "tag" => match Tag::try_from(raw) {
+                Ok(tag) => vec![(Self::kind(&tag.kind).unwrap_or("object"), tag.object.to_hex())],
+                Err(e) => {
+                    problems.push(broken(e.to_string()));
+                    vec![]
+                },
+            },
+            "tree" => {
+                let start = raw.iter().position(|&b| b == 0).map_or(raw.len(), |i| i + 1);
+                let mut rest = &raw[start..];
+                let mut links = Vec::new();
+                while !rest.is_empty() {
+                    let Some(space) = rest.iter().position(|&b| b == b' ') else { break };
+                    let Some(nul) = rest.iter().position(|&b| b == 0).filter(|&nul| nul > space && nul + 21 <= rest.len()) else { break };
+                    let mode = String::from_utf8_lossy(&rest[..space]).to_string();
+                    let name = String::from_utf8_lossy(&rest[space + 1..nul]).to_string();
+                    let target = hex::encode(&rest[nul + 1..nul + 21]);
+                    rest = &rest[nul + 21..];
+                    match mode.as_str() {
+                        "40000" => links.push(("tree", target)),
+                        // submodule commits live in another repository
+                        "160000" => {},
+                        _ => links.push(("blob", target)),
+                    }
+                    if !TREE_MODES.contains(&mode.as_str()) {
+                        problems.push(Problem::BadMode(hash.to_string(), name, mode));
+                    }
+                }
+                if !rest.is_empty() {
+                    problems.push(broken("truncated tree entry".to_string()));
+                }
+                links
+            },
+            _ => vec![],
+        }
+    }
+}
+
+impl SubCommand for Fsck {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let mut problems = Vec::new();
+        let mut objects = Self::check_loose(&gitdir, &mut problems)?;
+        // a bad loose copy is still reported even if a pack has the object
+        let corrupt = problems.iter().filter_map(|problem| match problem {
+            Problem::Corrupt(hash, _) | Problem::SizeMismatch(hash, _, _) | Problem::HashMismatch(hash, _) => Some(hash.clone()),
+            _ => None,
+        }).collect::<HashSet<_>>();
+        for hash in packed_hashes(&gitdir)? {
+            if !objects.contains_key(&hash) && !corrupt.contains(&hash) {
+                let raw = read_packed_object(&gitdir, &hash).ok().flatten();
+                match Self::check_raw(&hash, raw) {
+                    Ok(kind) => {
+                        objects.insert(hash, kind);
+                    },
+                    Err(problem) => problems.push(problem),
+                }
+            }
+        }
+        let read = |hash: &str| read_raw_object(&gitdir, hash).unwrap_or_default();
+        let shallow = read_shallow(&gitdir).iter().copied().map(ObjectId::to_hex).collect::<BTreeSet<_>>();
+
+        // connectivity from the roots
+        let mut reachable = HashSet::new();
+        let mut stack = Self::roots(&gitdir)?;
+        while let Some((expected, hash, parent)) = stack.pop() {
+            if !reachable.insert(hash.clone()) {
+                continue;
+            }
+            let Some(&kind) = objects.get(&hash) else {
+                if !corrupt.contains(&hash) {
+                    problems.push(Problem::Missing(expected, hash, parent));
+                }
+                continue;
+            };
+            if expected != "object" && expected != kind {
+                problems.push(Problem::WrongType(expected, hash, kind));
+                continue;
+            }
+            for (child_kind, child) in Self::links(kind, &hash, read(&hash), &shallow, &mut problems) {
+                stack.push((child_kind, child, hash.clone()));
+            }
+        }
+
+        // unreachable objects nothing else points at are dangling, like in git
+        let unreachable = objects.keys().filter(|hash| !reachable.contains(*hash)).cloned().collect::<Vec<_>>();
+        let mut referenced = HashSet::new();
+        for hash in &unreachable {
+            for (_, child) in Self::links(objects[hash], hash, read(hash), &shallow, &mut problems) {
+                referenced.insert(child);
+            }
+        }
+        if !self.no_dangling {
+            problems.extend(unreachable.into_iter()
+                .filter(|hash| !referenced.contains(hash))
+                .map(|hash| Problem::Dangling(objects[&hash], hash)));
+        }
+
+        problems.sort();
+        problems.dedup();
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        let broken = problems.iter().any(|problem| !matches!(problem, Problem::Dangling(..)));
+        Ok(if broken { 1 } else { 0 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        ArgsList,
+    };
+    use crate::utils::zlib::compress;
+
+    #[test]
+    fn test_fsck() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_str = temp_path.to_str().unwrap();
+        fs::write(temp_path.join("a.txt"), "a\n").unwrap();
+        fs::create_dir(temp_path.join("dir")).unwrap();
+        fs::write(temp_path.join("dir/b.txt"), "b\n").unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["commit", "-m", "first"], false),
+            (&["tag", "-a", "v1", "-m", "release"], false),
+        ];
+        let git = &["git", "-C", temp_str];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_str][..], args].concat()).unwrap().trim().to_string();
+        let fsck = || {
+            let output = Command::new("cargo").args(["run", "--quiet", "--", "-C", temp_str, "fsck"]).output().unwrap();
+            (output.status.code(), String::from_utf8_lossy(&output.stdout).to_string())
+        };
+        let object_path = |hash: &str| temp_path.join(".git/objects").join(&hash[..2]).join(&hash[2..]);
+
+        // a healthy repository, the stray blob is only dangling
+        assert_eq!(fsck(), (Some(0), String::new()));
+        let stray = shell_spawn(&["sh", "-c", &format!("echo stray | git -C {} hash-object -w --stdin", temp_str)]).unwrap().trim().to_string();
+        assert_eq!(fsck(), (Some(0), format!("dangling blob {}\n", stray)));
+
+        // a tree entry with a mode git never writes
+        let blob = git(&["rev-parse", "HEAD:a.txt"]);
+        let mut tree = b"100600 odd.txt\0".to_vec();
+        tree.extend(hex::decode(&blob).unwrap());
+        let mut child = Command::new("git").args(["-C", temp_str, "hash-object", "-t", "tree", "-w", "--literally", "--stdin"])
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(&tree).unwrap();
+        let bad_tree = String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap().trim().to_string();
+        let commit = git(&["commit-tree", &bad_tree, "-m", "odd"]);
+        git(&["update-ref", "refs/heads/odd", &commit]);
+        let (code, out) = fsck();
+        assert_eq!(code, Some(1));
+        assert!(out.contains(&format!("bad mode {}: entry 'odd.txt' has mode 100600", bad_tree)), "{}", out);
+        git(&["update-ref", "-d", "refs/heads/odd"]);
+
+        // rewritten content no longer matches its name, and what it pointed at goes missing
+        let subtree = git(&["rev-parse", "HEAD:dir"]);
+        fs::remove_file(object_path(&subtree)).unwrap();
+        let path = object_path(&blob);
+        fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+        fs::write(&path, compress(b"blob 2\0x\n".to_vec()).unwrap()).unwrap();
+        let (code, out) = fsck();
+        assert_eq!(code, Some(1));
+        let root = git(&["rev-parse", "HEAD^{tree}"]);
+        assert!(out.contains(&format!("hash mismatch {}", blob)), "{}", out);
+        assert!(out.contains(&format!("missing tree {} (referenced by {})", subtree, root)), "{}", out);
+        assert!(!out.contains(&format!("missing blob {}", blob)), "{}", out);
+    }
+}
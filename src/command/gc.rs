@@ -0,0 +1,119 @@
+use std::fs;
+use clap::Parser;
+
+use crate::{
+    utils::{
+        fs::loose_objects,
+        packfile::{has_packed_object, read_packed_object, write_pack, PackfileProcessor},
+        zlib::decompress_file_as_bytes,
+    },
+    GitError,
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// 把松散对象打进一个新的 pack，只做压缩，不清理不可达的对象
+#[derive(Parser, Debug)]
+#[command(name = "gc", about = "Pack the loose objects into a single packfile and remove them")]
+pub struct Gc {
+}
+
+impl Gc {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Gc::try_parse_from(args)?))
+    }
+}
+
+impl SubCommand for Gc {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let loose = loose_objects(&gitdir)?;
+        if loose.is_empty() {
+            println!("Nothing new to pack.");
+            return Ok(0);
+        }
+
+        // 已经在某个 pack 里的不用再打一次
+        let to_pack = loose.iter()
+            .filter(|(hash, _)| !has_packed_object(&gitdir, hash))
+            .map(|(hash, _)| hash.clone())
+            .collect::<Vec<_>>();
+        if !to_pack.is_empty() {
+            let pack = write_pack(&gitdir, &to_pack)?;
+            PackfileProcessor::new(gitdir.clone()).store_packfile(&pack)?;
+        }
+
+        // 每个对象都能从 pack 里原样读出来，才开始删松散对象
+        for (hash, path) in &loose {
+            if read_packed_object(&gitdir, hash)? != Some(decompress_file_as_bytes(path)?) {
+                return Err(GitError::corrupt_pack(format!(
+                    "object {} does not read back from the pack, no loose object was removed", hash
+                )));
+            }
+        }
+        for (_, path) in &loose {
+            fs::remove_file(path)?;
+            // 目录里还有别的文件时删不掉，留着就好
+            if let Some(dir) = path.parent() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        println!("Removed {} loose objects", loose.len());
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_gc() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let blobs = temp_path.join("blobs");
+        std::fs::create_dir(&blobs).unwrap();
+        let paths = (0..500)
+            .map(|i| {
+                let path = blobs.join(format!("{}.txt", i));
+                std::fs::write(&path, format!("blob {}\n", i)).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        let mut args = vec!["git", "-C", temp_path_str, "hash-object", "-w"];
+        args.extend(paths.iter().map(String::as_str));
+        let hashes = shell_spawn(&args).unwrap();
+        let hashes = hashes.lines().collect::<Vec<_>>();
+
+        let git = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git");
+        let count_objects = || shell_spawn(&["git", "-C", temp_path_str, "count-objects", "-v"]).unwrap();
+        // make sure the binary is built before running it directly
+        let ours = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "count-objects", "-v"]).unwrap();
+        assert!(count_objects().starts_with(&ours));
+        assert!(ours.starts_with("count: 500\n"));
+        let ours = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "count-objects"]).unwrap();
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str, "count-objects"]).unwrap(), ours);
+
+        shell_spawn(&[git.to_str().unwrap(), "-C", temp_path_str, "gc"]).unwrap();
+        let report = count_objects();
+        assert!(report.starts_with("count: 0\nsize: 0\nin-pack: 500\npacks: 1\n"));
+        assert_eq!(shell_spawn(&[git.to_str().unwrap(), "-C", temp_path_str, "count-objects", "-v"]).unwrap(), report.lines().take(6).map(|line| format!("{}\n", line)).collect::<String>());
+        assert!(shell_spawn(&["git", "-C", temp_path_str, "fsck", "--full"]).is_ok());
+
+        for (i, hash) in hashes.iter().enumerate() {
+            let out = Command::new(&git).args(["-C", temp_path_str, "cat-file", "-p", hash]).output().unwrap();
+            assert_eq!(String::from_utf8_lossy(&out.stdout), format!("blob {}\n", i));
+        }
+
+        // nothing loose is left, a second run has nothing to do
+        let out = shell_spawn(&[git.to_str().unwrap(), "-C", temp_path_str, "repack"]).unwrap();
+        assert_eq!(out, "Nothing new to pack.\n");
+    }
+}
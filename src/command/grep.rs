@@ -0,0 +1,250 @@
+use std::{
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Component, PathBuf},
+};
+use clap::Parser;
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        blob::Blob,
+        diff::{flatten_tree, is_binary},
+        fs::{read_object, relative_to},
+        hash::ObjectId,
+        index::Index,
+        pool::{default_threads, parallel_map},
+        revparse::resolve_tree,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "grep", about = "Print lines matching a pattern in tracked files")]
+pub struct Grep {
+    #[arg(short = 'i', long = "ignore-case", help = "ignore case differences between the pattern and the files")]
+    ignore_case: bool,
+
+    #[arg(short = 'n', long = "line-number", help = "prefix matches with their line number")]
+    line_number: bool,
+
+    #[arg(short = 'l', long = "files-with-matches", help = "show only the names of files that match")]
+    names_only: bool,
+
+    #[arg(short = 'c', long, help = "show the number of matching lines per file instead of the lines")]
+    count: bool,
+
+    #[arg(long, help = "search the blobs in the index instead of the worktree")]
+    cached: bool,
+
+    #[arg(help = "the regular expression to look for")]
+    pattern: String,
+
+    #[arg(value_name = "tree-ish|path", help = "a tree-ish to search instead of the worktree, then paths to limit the search to")]
+    args: Vec<String>,
+
+    #[arg(last = true, value_name = "path", help = "paths to limit the search to")]
+    paths: Vec<PathBuf>,
+}
+
+/// where the content of a searched file comes from
+enum Source {
+    Worktree(PathBuf),
+    Blob(ObjectId),
+}
+
+impl Grep {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Grep::try_parse_from(args)?))
+    }
+
+    /// the pathspecs relative to the project root, by default the current directory
+    /// only looked at lexically, a tree may have paths the worktree does not
+    pub fn scope(repo: &Repository, paths: &[PathBuf]) -> Vec<PathBuf> {
+        if paths.is_empty() {
+            return vec![repo.prefix.clone()];
+        }
+        paths.iter().map(|p| {
+            let path = repo.resolve(p);
+            let mut normalized = PathBuf::new();
+            for component in repo.work_tree().ok().and_then(|top| path.strip_prefix(top).ok()).unwrap_or(&path).components() {
+                match component {
+                    Component::CurDir => {},
+                    Component::ParentDir => { normalized.pop(); },
+                    component => normalized.push(component),
+                }
+            }
+            normalized
+        }).collect()
+    }
+
+    /// the matching lines of one file as `name:line`, `name:lineno:line` with -n,
+    /// just `name` with -l or `name:count` with -c
+    fn search(&self, regex: &Regex, name: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        // like -I, binary files are never reported
+        if is_binary(content) {
+            return out;
+        }
+        let content = content.strip_suffix(b"\n").unwrap_or(content);
+        // -l wins over -c, like in git
+        let counting = self.count && !self.names_only;
+        let mut count = 0;
+        for (lineno, line) in content.split(|b| *b == b'\n').enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+            count += 1;
+            if counting {
+                continue;
+            }
+            out.extend_from_slice(name);
+            if self.names_only {
+                out.push(b'\n');
+                break;
+            }
+            if self.line_number {
+                out.extend_from_slice(format!(":{}", lineno + 1).as_bytes());
+            }
+            out.push(b':');
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        }
+        if counting && count > 0 {
+            out.extend_from_slice(name);
+            out.extend_from_slice(format!(":{}\n", count).as_bytes());
+        }
+        out
+    }
+}
+
+impl SubCommand for Grep {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let regex = RegexBuilder::new(&self.pattern)
+            .case_insensitive(self.ignore_case)
+            .build()
+            .map_err(|e| GitError::invalid_command(format!("invalid pattern: {}", e)))?;
+
+        // like git, the first argument is a tree-ish when it names one, the rest are paths
+        let tree = match self.args.first() {
+            Some(rev) if !self.cached => resolve_tree(gitdir, rev).ok().map(|hash| (rev.clone(), hash)),
+            _ => None,
+        };
+        let mut paths = self.args.iter()
+            .skip(tree.is_some() as usize)
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        paths.extend(self.paths.iter().cloned());
+        let scope = Self::scope(&repo, &paths);
+
+        // (path, mode, blob) of every tracked file in scope, in path order
+        let mut files = match &tree {
+            Some((_, hash)) => flatten_tree(gitdir, hash)?
+                .into_iter()
+                .map(|(path, (mode, hash))| (path, mode, hash))
+                .collect::<Vec<_>>(),
+            None => {
+                Index::read_or_default(gitdir)?.entries.into_iter()
+                    .filter(|entry| !self.cached || entry.stage == 0)
+                    .map(|entry| (entry.name, entry.mode, entry.hash))
+                    .collect()
+            },
+        };
+        files.sort_by(|a, b| a.0.as_os_str().as_bytes().cmp(b.0.as_os_str().as_bytes()));
+        files.dedup_by(|a, b| a.0 == b.0);
+        // only searching the worktree needs one, --cached and trees work in a bare repository
+        let work_tree = match (&tree, self.cached) {
+            (None, false) => Some(repo.work_tree()?),
+            _ => None,
+        };
+        let files = files.into_iter()
+            // symlinks and submodules have no lines to search
+            .filter(|(_, mode, _)| *mode == 0o100644 || *mode == 0o100755)
+            .filter(|(path, _, _)| scope.iter().any(|dir| path.starts_with(dir)))
+            .map(|(path, _, hash)| match work_tree {
+                Some(work_tree) => (path.clone(), Source::Worktree(work_tree.join(path))),
+                None => (path, Source::Blob(hash)),
+            })
+            .collect::<Vec<_>>();
+
+        let results = parallel_map(&files, default_threads(), |(path, source)| {
+            let content = match source {
+                Source::Blob(hash) => read_object::<Blob>(gitdir.to_path_buf(), hash)?.into(),
+                // a tracked file deleted from the worktree has nothing to match
+                Source::Worktree(file) => std::fs::read(file).unwrap_or_default(),
+            };
+            let mut name = match &tree {
+                Some((rev, _)) => format!("{}:", rev).into_bytes(),
+                None => Vec::new(),
+            };
+            name.extend_from_slice(relative_to(&repo.prefix, path).as_os_str().as_bytes());
+            Ok(self.search(&regex, &name, &content))
+        })?;
+
+        let mut stdout = io::stdout().lock();
+        let mut found = false;
+        for out in results.iter().filter(|out| !out.is_empty()) {
+            found = true;
+            stdout.write_all(out)?;
+        }
+        Ok(if found { 0 } else { 1 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_grep() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        std::fs::create_dir_all(temp_path.join("src/inner")).unwrap();
+        std::fs::write(temp_path.join("README"), "Hello world\nnothing here\nhello again\n").unwrap();
+        std::fs::write(temp_path.join("src/main.rs"), "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+        std::fs::write(temp_path.join("src/inner/lib.rs"), "// HELLO from lib\nno newline at the end").unwrap();
+        std::fs::write(temp_path.join("data.bin"), b"hello\0binary\n").unwrap();
+        git(&["add", "."]).unwrap();
+        git(&["commit", "-m", "init"]).unwrap();
+        // the worktree, the index and HEAD all differ now
+        std::fs::write(temp_path.join("README"), "hello worktree\n").unwrap();
+        std::fs::write(temp_path.join("src/main.rs"), "hello index\n").unwrap();
+        git(&["add", "src/main.rs"]).unwrap();
+        std::fs::write(temp_path.join("untracked"), "hello untracked\n").unwrap();
+
+        let searches: &[&[&str]] = &[
+            &["hello"],
+            &["-i", "hello"],
+            &["-l", "-i", "hello"],
+            &["--cached", "hello"],
+            &["-i", "hello", "HEAD"],
+            &["-l", "hello", "HEAD"],
+            &["-i", "hello", "HEAD", "--", "src"],
+            &["-i", "hello", "src/inner"],
+            &["end$"],
+            &["--cached", "-i", "h[e]llo", "--", "src", "README"],
+        ];
+        // binary files are always skipped, -I for git
+        for args in searches {
+            for options in [&[][..], &["-n"], &["-c"]] {
+                let ours = [&["grep"][..], options, args].concat();
+                let theirs = [&["grep", "-I"][..], options, args].concat();
+                assert_eq!(cargo(&ours).unwrap(), git(&theirs).unwrap(), "{:?}", ours);
+            }
+        }
+        // nothing found is exit status 1, like git
+        assert!(cargo(&["grep", "no such text"]).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::fs::write;
+use std::io::{self, Read};
 use std::path::{
     Path,
     PathBuf
@@ -14,8 +15,9 @@ use crate::utils::{
         obj_to_pathbuf,
         read_file_as_bytes,
         write_object,
+        write_raw_object,
     },
-    hash::hash_object,
+    hash::{hash_object, hash_raw_object, ObjectId},
     objtype::{
         ObjType,
         Obj,
@@ -30,6 +32,7 @@ use crate::{
     Result,
 };
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 
 #[derive(Parser, Debug)]
@@ -39,8 +42,14 @@ pub struct HashObject {
     #[arg(required = false, short = 'w', group = "option", help = "Actually write the object into the object database.")]
     write: bool,
 
-    #[arg(required = true)]
-    filepath: PathBuf,
+    #[arg(short = 't', default_value = "blob", value_parser = ["blob", "tree", "commit", "tag"], help = "Specify the type of object to be created (default: \"blob\").")]
+    obj_type: String,
+
+    #[arg(long = "stdin", help = "Read the object from standard input instead of from a file.")]
+    stdin: bool,
+
+    #[arg(required_unless_present = "stdin")]
+    filepaths: Vec<PathBuf>,
 }
 
 impl HashObject {
@@ -48,31 +57,73 @@ impl HashObject {
         Ok(Box::new(HashObject::try_parse_from(args)?))
     }
 
-    pub fn hash(&self, bytes: Vec<u8>) -> Result<String> {
-        hash_object::<Blob>(bytes)
+    pub fn hash(&self, bytes: Vec<u8>) -> Result<ObjectId> {
+        hash_raw_object(&self.obj_type, bytes)
     }
-}
 
+    fn read_path(path: &Path) -> Result<Vec<u8>> {
+        if path.is_dir() {
+            Err(GitError::failed_to_read_file(&format!("{} is a directory", path.display())))
+        }
+        else if !path.exists() {
+            Err(GitError::file_notfound(path.display().to_string()))
+        }
+        else {
+            read_file_as_bytes(&path)
+        }
+    }
 
-impl SubCommand for HashObject {
-    /*  fn run(&self, gitdir: path) -> Result<i32>  */
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let bytes = read_file_as_bytes(&self.filepath)?;
-        let path = self.hash(bytes.clone())?;
-        let gitdir = gitdir?;
+    /// tree and commit contents must parse, otherwise we would write a broken object
+    fn check_format(&self, bytes: &[u8]) -> Result<()> {
+        if self.obj_type == Tree::VALUE || self.obj_type == Commit::VALUE {
+            let mut full = format!("{} {}\0", self.obj_type, bytes.len()).into_bytes();
+            full.extend_from_slice(bytes);
+            let _: Obj = full.try_into()?;
+        }
+        Ok(())
+    }
 
+    fn hash_one(&self, gitdir: &Result<PathBuf>, bytes: Vec<u8>) -> Result<ObjectId> {
+        self.check_format(&bytes)?;
         if self.write {
-            write_object::<Blob>(gitdir, bytes)?;
-            Ok(0)
+            let gitdir = gitdir.as_ref().map_err(|e| GitError::invalid_command(e.to_string()))?;
+            write_raw_object(gitdir.clone(), &self.obj_type, bytes)
         }
         else {
-            println!("{}", path);
-            Ok(0)
+            self.hash(bytes)
         }
     }
 }
 
 
+impl SubCommand for HashObject {
+    /*  fn run(&self, gitdir: path) -> Result<i32>  */
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo.map(|repo| repo.gitdir);
+        let mut inputs = Vec::new();
+        if self.stdin {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            inputs.push(Ok(bytes));
+        }
+        inputs.extend(self.filepaths.iter().map(|path| Self::read_path(path)));
+
+        // like git, keep going after a bad path and only fail at the end
+        let mut failed = false;
+        for input in inputs {
+            match input.and_then(|bytes| self.hash_one(&gitdir, bytes)) {
+                Ok(hash) => println!("{}", hash),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    failed = true;
+                }
+            }
+        }
+        Ok(failed as i32)
+    }
+}
+
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -98,4 +149,76 @@ mod test {
 
         assert_eq!(origin, real);
     }
+
+    #[test]
+    fn test_multiple_and_stdin() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        let file1 = mktemp_in(&temp).unwrap();
+        let file2 = mktemp_in(&temp).unwrap();
+        std::fs::write(&file2, "hello\n").unwrap();
+        let (file1_str, file2_str) = (file1.to_str().unwrap(), file2.to_str().unwrap());
+
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "hash-object", file1_str, file2_str]).unwrap();
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "hash-object", file1_str, file2_str]).unwrap();
+        assert_eq!(origin, real);
+
+        let pipe = format!("cat {} | git -C {} hash-object --stdin", file2_str, temp_path_str);
+        let origin = shell_spawn(&["sh", "-c", &pipe]).unwrap();
+        let pipe = format!("cat {} | cargo run --quiet -- -C {} hash-object --stdin", file2_str, temp_path_str);
+        let real = shell_spawn(&["sh", "-c", &pipe]).unwrap();
+        assert_eq!(origin, real);
+
+        let missing = temp_path.join("no_such_file");
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "hash-object", file1_str, missing.to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn test_compression_level() {
+        let ours = setup_test_git_dir();
+        let theirs = setup_test_git_dir();
+        let (ours_str, theirs_str) = (ours.path().to_str().unwrap(), theirs.path().to_str().unwrap());
+        let content = (0..2000).map(|i| format!("line {} of a blob that compresses well\n", i % 97)).collect::<String>();
+        std::fs::write(ours.path().join("file"), &content).unwrap();
+        std::fs::write(theirs.path().join("file"), &content).unwrap();
+        let (ours_file, theirs_file) = (ours.path().join("file"), theirs.path().join("file"));
+        let (ours_file, theirs_file) = (ours_file.to_str().unwrap(), theirs_file.to_str().unwrap());
+        let loose = |dir: &std::path::Path, hash: &str| {
+            std::fs::read(dir.join(".git/objects").join(&hash[..2]).join(&hash[2..])).unwrap()
+        };
+
+        // git 的松散对象默认是 level 1，显式设成 6 来和我们的默认值比
+        let hash = shell_spawn(&["git", "-C", theirs_str, "-c", "core.compression=6", "hash-object", "-w", theirs_file]).unwrap();
+        let hash = hash.trim();
+        assert_eq!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", ours_str, "hash-object", "-w", ours_file]).unwrap().trim(), hash);
+        assert_eq!(loose(ours.path(), hash), loose(theirs.path(), hash));
+
+        // 配置里的和环境变量给的 level 都要生效
+        for (dir, level) in [(&ours, "9"), (&theirs, "9")] {
+            std::fs::remove_file(dir.path().join(".git/objects").join(&hash[..2]).join(&hash[2..])).unwrap();
+            shell_spawn(&["git", "-C", dir.path().to_str().unwrap(), "config", "core.compression", level]).unwrap();
+        }
+        shell_spawn(&["git", "-C", theirs_str, "hash-object", "-w", theirs_file]).unwrap();
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", ours_str, "hash-object", "-w", ours_file]).unwrap();
+        assert_eq!(loose(ours.path(), hash), loose(theirs.path(), hash));
+
+        shell_spawn(&["git", "-C", ours_str, "config", "--unset", "core.compression"]).unwrap();
+        std::fs::remove_file(ours.path().join(".git/objects").join(&hash[..2]).join(&hash[2..])).unwrap();
+        let git = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git");
+        let status = std::process::Command::new(&git)
+            .args(["-C", ours_str, "hash-object", "-w", ours_file])
+            .env("GIT_COMPRESSION", "9")
+            .status().unwrap();
+        assert!(status.success());
+        assert_eq!(loose(ours.path(), hash), loose(theirs.path(), hash));
+
+        let status = std::process::Command::new(&git)
+            .args(["-C", ours_str, "hash-object", ours_file])
+            .env("GIT_COMPRESSION", "12")
+            .output().unwrap();
+        assert!(!status.status.success());
+        assert!(String::from_utf8_lossy(&status.stderr).contains("bad zlib compression level 12"));
+    }
 }
@@ -1,5 +1,6 @@
 use std::fs;
-use std::path::PathBuf;
+use std::env;
+use std::path::{Path, PathBuf};
 use std::convert::Into;
 use std::env::current_dir;
 use clap::{Parser, Subcommand, CommandFactory};
@@ -9,7 +10,9 @@ use crate::utils::{
         decompress_file,
         decompress_file_as_bytes,
     },
-    fs::obj_to_pathbuf,
+    fs::{obj_to_pathbuf, is_bare_git_dir},
+    config::Config,
+    hash::HashAlgorithm,
     objtype::{
         ObjType,
         parse_meta,
@@ -25,6 +28,7 @@ use crate::{
     Result,
 };
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 use nom::{
     bytes::complete::{tag, take, take_until},
@@ -36,6 +40,15 @@ use nom::{
 #[derive(Parser, Debug)]
 #[command(name = "init", about = "Create an empty Git repository or reinitialize an existing one")]
 pub struct Init {
+    #[arg(long, help = "create a bare repository, the directory itself becomes the git dir")]
+    pub bare: bool,
+
+    #[arg(short = 'b', long, value_name = "branch-name", help = "the branch HEAD points at, instead of init.defaultBranch or master")]
+    pub initial_branch: Option<String>,
+
+    #[arg(long, value_name = "format", help = "the hash objects are named with, sha1 (default) or sha256")]
+    pub object_format: Option<String>,
+
     #[arg(help = "directory to be initialized")]
     pub dir: Option<String>
 }
@@ -44,20 +57,51 @@ impl Init {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
         Ok(Box::new(Init::try_parse_from(args)?))
     }
+
+    /// --initial-branch, then $GIT_DEFAULT_BRANCH, then init.defaultBranch, like git
+    fn initial_branch(&self, gitdir: &Path) -> Result<String> {
+        if let Some(branch) = &self.initial_branch {
+            return Ok(branch.clone());
+        }
+        if let Ok(branch) = env::var("GIT_DEFAULT_BRANCH") && !branch.is_empty() {
+            return Ok(branch);
+        }
+        Ok(Config::read_all(gitdir)?.get("init.defaultbranch").unwrap_or("master").to_string())
+    }
+
+    /// --object-format, then $GIT_DEFAULT_HASH, sha1 otherwise
+    fn object_format(&self) -> Result<HashAlgorithm> {
+        match &self.object_format {
+            Some(format) => format.parse(),
+            None => match env::var("GIT_DEFAULT_HASH") {
+                Ok(format) if !format.is_empty() => format.parse(),
+                _ => Ok(HashAlgorithm::Sha1),
+            },
+        }
+    }
 }
 
 impl SubCommand for Init {
-    fn run(&self, _: Result<PathBuf>) -> Result<i32> {
+    fn run(&self, _: Result<Repository>) -> Result<i32> {
         let curr_path = current_dir()?;
-        let gitdir = if self.dir.is_some() {
+        let target = if self.dir.is_some() {
             curr_path.join(self.dir.clone().unwrap())
         }
         else {
             curr_path
-        }.join(".git");
-        if gitdir.exists() {
+        };
+        let gitdir = if self.bare { target } else { target.join(".git") };
+        // an existing repository is never touched
+        if (self.bare && is_bare_git_dir(&gitdir)) || (!self.bare && gitdir.exists()) {
             return Err(GitError::invalid_command(format!("{} directory already exists", gitdir.display())));
         }
+        let branch = self.initial_branch(&gitdir)?;
+        let object_format = self.object_format()?;
+        let invalid = branch.is_empty() || branch.starts_with(['-', '/']) || branch.ends_with(['/', '.']) || branch.contains("..")
+            || branch.contains([' ', '~', '^', ':', '?', '*', '[', '\\']);
+        if invalid {
+            return Err(GitError::invalid_command(format!("invalid initial branch name: '{}'", branch)));
+        }
 
         let refs = gitdir.join("refs");
         fs::create_dir_all(&refs)
@@ -72,8 +116,16 @@ impl SubCommand for Init {
         fs::create_dir_all(objects.join("info"))?;
         fs::create_dir_all(objects.join("pack"))?;
 
-        std::fs::write( gitdir.join("HEAD"), b"ref: refs/heads/master")
+        std::fs::write( gitdir.join("HEAD"), format!("ref: refs/heads/{}\n", branch))
             .map_err(|_| GitError::invalid_command(format!("Failed to create {} file", gitdir.join("HEAD").display())))?;
+        // extensions are only honoured by version 1, sha1 repositories stay at 0 like git's
+        let version = if object_format == HashAlgorithm::Sha1 { "0" } else { "1" };
+        Config::set(&gitdir, "core.repositoryformatversion", version)?;
+        Config::set(&gitdir, "core.filemode", "true")?;
+        Config::set(&gitdir, "core.bare", if self.bare { "true" } else { "false" })?;
+        if object_format != HashAlgorithm::Sha1 {
+            Config::set(&gitdir, "extensions.objectformat", object_format.name())?;
+        }
         Ok(0)
     }
 }
@@ -106,4 +158,97 @@ mod test {
         let res = shell_spawn(&["cargo", "run", "--quiet", "--", "init", &temp.path().display().to_string()]);
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_bare_and_initial_branch() {
+        let temp = tempdir().unwrap();
+        let temp_path = temp.path();
+        let cargo = |args: &[&str], env: &[(&str, &str)]| std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .envs(env.iter().copied())
+            .output()
+            .unwrap()
+            .status
+            .success();
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-C", temp_path.join(dir).to_str().unwrap()][..], args].concat());
+
+        assert!(cargo(&["init", "--bare", temp_path.join("bare.git").to_str().unwrap()], &[]));
+        assert!(!temp_path.join("bare.git/.git").exists());
+        assert_eq!(git("bare.git", &["rev-parse", "--is-bare-repository"]).unwrap().trim(), "true");
+        assert_eq!(git("bare.git", &["config", "core.bare"]).unwrap().trim(), "true");
+
+        assert!(cargo(&["init", "-b", "main", temp_path.join("work").to_str().unwrap()], &[]));
+        assert_eq!(git("work", &["symbolic-ref", "HEAD"]).unwrap().trim(), "refs/heads/main");
+        assert_eq!(git("work", &["rev-parse", "--is-bare-repository"]).unwrap().trim(), "false");
+        assert!(cargo(&["init", "--initial-branch=trunk", temp_path.join("trunk").to_str().unwrap()], &[]));
+        assert_eq!(git("trunk", &["symbolic-ref", "HEAD"]).unwrap().trim(), "refs/heads/trunk");
+        assert!(cargo(&["init", temp_path.join("env").to_str().unwrap()], &[("GIT_DEFAULT_BRANCH", "develop")]));
+        assert_eq!(git("env", &["symbolic-ref", "HEAD"]).unwrap().trim(), "refs/heads/develop");
+        assert!(cargo(&["-c", "init.defaultBranch=stable", "init", temp_path.join("config").to_str().unwrap()], &[]));
+        assert_eq!(git("config", &["symbolic-ref", "HEAD"]).unwrap().trim(), "refs/heads/stable");
+        assert!(!cargo(&["init", "-b", "bad..name", temp_path.join("bad").to_str().unwrap()], &[]));
+
+        // plumbing runs inside the bare repository it was pushed to
+        git("work", &["config", "user.name", "rust-git"]).unwrap();
+        git("work", &["config", "user.email", "163@163.com"]).unwrap();
+        std::fs::write(temp_path.join("work/a.txt"), "a").unwrap();
+        git("work", &["add", "a.txt"]).unwrap();
+        git("work", &["commit", "-m", "first"]).unwrap();
+        git("work", &["push", temp_path.join("bare.git").to_str().unwrap(), "main"]).unwrap();
+        let bare = temp_path.join("bare.git");
+        let origin = git("bare.git", &["ls-tree", "main"]).unwrap();
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", bare.to_str().unwrap(), "ls-tree", "main"]).unwrap();
+        assert_eq!(origin, real);
+        let inside = std::process::Command::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git"))
+            .current_dir(bare.join("refs"))
+            .args(["rev-parse", "main"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&inside.stdout), git("bare.git", &["rev-parse", "main"]).unwrap());
+
+        // running it again leaves the repository alone
+        assert!(!cargo(&["init", "--bare", bare.to_str().unwrap()], &[]));
+        assert_eq!(git("bare.git", &["rev-parse", "main"]).unwrap(), git("work", &["rev-parse", "main"]).unwrap());
+    }
+
+    #[test]
+    fn test_sha256_repository() {
+        let temp = tempdir().unwrap();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+        let init = |format: &str| shell_spawn(&["cargo", "run", "--quiet", "--", "init", format, temp_path_str]);
+        assert!(init("--object-format=sha512").is_err());
+        init("--object-format=sha256").unwrap();
+        assert_eq!(git(&["rev-parse", "--show-object-format"]).trim(), "sha256");
+        git(&["config", "user.name", "rust-git"]);
+        git(&["config", "user.email", "163@163.com"]);
+
+        std::fs::create_dir_all(temp_path.join("dir")).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "a\n").unwrap();
+        std::fs::write(temp_path.join("dir/b.txt"), "b\n").unwrap();
+        cargo(&["add", "."]).unwrap();
+        assert_eq!(git(&["ls-files", "-s"]), cargo(&["ls-files", "-s"]).unwrap());
+        assert_eq!(git(&["write-tree"]), cargo(&["write-tree"]).unwrap());
+        cargo(&["commit", "-m", "first"]).unwrap();
+        let first = git(&["rev-parse", "HEAD"]);
+        assert_eq!(first.trim().len(), 64);
+        assert_eq!(cargo(&["rev-parse", "HEAD"]).unwrap(), first);
+        let a_txt = temp_path.join("a.txt");
+        assert_eq!(git(&["hash-object", "a.txt"]), cargo(&["hash-object", a_txt.to_str().unwrap()]).unwrap());
+        assert_eq!(git(&["status", "--porcelain"]), "");
+
+        // real git writes on top of it, we read back what it wrote
+        std::fs::write(temp_path.join("a.txt"), "changed\n").unwrap();
+        git(&["commit", "-qam", "second"]);
+        assert_eq!(git(&["ls-tree", "-r", "HEAD"]), cargo(&["ls-tree", "-r", "HEAD"]).unwrap());
+        cargo(&["checkout", first.trim()]).unwrap();
+        assert_eq!(std::fs::read_to_string(temp_path.join("a.txt")).unwrap(), "a\n");
+        assert_eq!(git(&["status", "--porcelain"]), "");
+        cargo(&["checkout", "master"]).unwrap();
+        assert_eq!(std::fs::read_to_string(temp_path.join("a.txt")).unwrap(), "changed\n");
+        assert!(shell_spawn(&["git", "-C", temp_path_str, "fsck", "--full", "--strict"]).is_ok());
+    }
 }
@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use clap::Parser;
+
+use crate::{
+    Result,
+    command::Checkout,
+    utils::{
+        fs::{walk, calc_relative_path, quote_path, relative_to},
+        ignore::Ignore,
+        index::Index,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::{Repository, work_tree};
+
+#[derive(Parser, Debug)]
+#[command(name = "ls-files", about = "Show information about files in the index and the working tree")]
+pub struct LsFiles {
+    #[arg(short = 'c', long, help = "show cached files in the output (default)")]
+    cached: bool,
+
+    #[arg(short = 's', long, help = "show staged contents' mode bits, object name and stage number")]
+    stage: bool,
+
+    #[arg(short = 'o', long, help = "show other (i.e. untracked) files in the output")]
+    others: bool,
+
+    #[arg(long, requires = "others", help = "skip files ignored by .gitignore")]
+    exclude_standard: bool,
+
+    #[arg(short = 'u', long, help = "show only unmerged files, with their stages")]
+    unmerged: bool,
+
+    #[arg(short = 'z', help = "terminate entries with NUL and leave the names unquoted")]
+    nul: bool,
+
+    #[arg(help = "only the files under these paths, the current directory by default")]
+    paths: Vec<String>,
+}
+
+impl LsFiles {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(LsFiles::try_parse_from(args)?))
+    }
+}
+
+impl SubCommand for LsFiles {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let project_root = &work_tree(gitdir)?;
+        let mut index = Index::read_or_default(gitdir)?;
+        // git compares names as raw bytes
+        index.entries.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()).then(a.stage.cmp(&b.stage)));
+
+        // like git, names are shown from the current directory and nothing outside of it is listed unless asked for
+        let pathspecs = match self.paths.is_empty() {
+            true => vec![Checkout::normalize_pathspec(&repo, ".")],
+            false => self.paths.iter().map(|path| Checkout::normalize_pathspec(&repo, path)).collect(),
+        };
+        let selected = |path: &Path| pathspecs.iter().any(|pathspec| Checkout::pathspec_matches(pathspec, path));
+        let show = |path: &Path| {
+            let shown = relative_to(&repo.prefix, path);
+            match self.nul {
+                true => format!("{}\0", shown.display()),
+                false => format!("{}\n", quote_path(&shown)),
+            }
+        };
+
+        if self.cached || self.stage || self.unmerged || !self.others {
+            let unmerged = index.unmerged_paths().into_iter().map(Path::to_path_buf).collect::<Vec<_>>();
+            for entry in index.entries.iter().filter(|entry| selected(&entry.name)) {
+                if self.unmerged && !unmerged.contains(&entry.name) {
+                    continue;
+                }
+                // -u always shows the stages
+                if self.stage || self.unmerged {
+                    print!("{:06o} {} {}\t{}", entry.mode, entry.hash, entry.stage, show(&entry.name));
+                }
+                else {
+                    print!("{}", show(&entry.name));
+                }
+            }
+        }
+
+        if self.others {
+            let ignore = if self.exclude_standard {
+                Ignore::load(project_root)?
+            }
+            else {
+                Ignore::default()
+            };
+            let mut others = walk(project_root)?
+                .into_iter()
+                .map(|p| calc_relative_path(project_root, p))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .filter(|p| !index.entries.iter().any(|e| e.name == *p))
+                .filter(|p| !ignore.is_ignored(p, false))
+                .filter(|p| selected(p))
+                .collect::<Vec<_>>();
+            others.sort_by(|a, b| a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes()));
+            others.iter().for_each(|p| print!("{}", show(p)));
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_ls_files() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        for name in ["b.txt", "a/z.txt", "a-b", "a.txt", "exec.sh", "untracked/x", "ignored.log", ".gitignore"] {
+            let path = temp_path.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        std::fs::write(temp_path.join(".gitignore"), "*.log\n").unwrap();
+        let _ = shell_spawn(&["chmod", "+x", temp_path.join("exec.sh").to_str().unwrap()]).unwrap();
+
+        let setup_cmds: ArgsList = &[
+            (&["add", "b.txt", "a/z.txt", "a-b", "a.txt", "exec.sh"], false),
+        ];
+        let git = &["git", "-C", temp_path_str];
+        cmd_seq(setup_cmds)(git).unwrap();
+
+        let args: &[&[&str]] = &[
+            &["ls-files"],
+            &["ls-files", "--cached"],
+            &["ls-files", "--stage"],
+            &["ls-files", "--others"],
+            &["ls-files", "--others", "--exclude-standard"],
+        ];
+        for args in args {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+
+        // a conflict leaves stages 1-3 behind
+        let git_run = |args: &[&str]| shell_spawn(&[&git[..], args].concat());
+        git_run(&["commit", "-m", "base"]).unwrap();
+        git_run(&["checkout", "-b", "side"]).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "side").unwrap();
+        git_run(&["commit", "-am", "side"]).unwrap();
+        git_run(&["checkout", "master"]).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "master").unwrap();
+        git_run(&["commit", "-am", "master"]).unwrap();
+        assert!(git_run(&["merge", "side"]).is_err());
+
+        let sub = temp_path.join("a");
+        let sub_str = sub.to_str().unwrap();
+        let args: &[(&str, &[&str])] = &[
+            (temp_path_str, &["ls-files", "-u"]),
+            (temp_path_str, &["ls-files", "-s", "a.txt", "a"]),
+            (temp_path_str, &["ls-files", "-z"]),
+            (temp_path_str, &["ls-files", "--others", "untracked"]),
+            // from a subdirectory names are relative to it
+            (sub_str, &["ls-files"]),
+            (sub_str, &["ls-files", "-s", "../b.txt", "."]),
+            (sub_str, &["ls-files", ".."]),
+        ];
+        for (dir, args) in args {
+            let origin = shell_spawn(&[&["git", "-C", dir][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", dir][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{} {:?}", dir, args);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+use crate::{
+    utils::{
+        config::Config,
+        fs::read_obj,
+        hash::ObjectId,
+        objtype::Obj,
+        protocol::{GitProtocol, RemoteRef},
+        refs::{current_branch, for_each_ref, head_to_hash, read_head_ref},
+    },
+    GitError,
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "ls-remote", about = "List references in a remote repository")]
+pub struct LsRemote {
+    #[arg(long, help = "limit to refs/heads")]
+    heads: bool,
+
+    #[arg(short = 't', long, help = "limit to refs/tags")]
+    tags: bool,
+
+    #[arg(help = "a remote name or url, defaults to the upstream remote of the current branch or origin")]
+    repository: Option<String>,
+
+    #[arg(help = "only show refs whose name matches the pattern at a path component boundary")]
+    patterns: Vec<String>,
+}
+
+impl LsRemote {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(LsRemote::try_parse_from(args)?))
+    }
+
+    /// a configured remote name becomes its url, anything else is used as the url itself
+    fn remote_url(&self, gitdir: Option<&Path>, config: &Config) -> Result<String> {
+        let name = match (&self.repository, gitdir) {
+            (Some(name), _) => name.clone(),
+            (None, Some(gitdir)) => current_branch(gitdir).ok()
+                .and_then(|branch| config.upstream(&branch))
+                .map(|upstream| upstream.remote)
+                .filter(|remote| remote != ".")
+                .unwrap_or("origin".to_string()),
+            (None, None) => return Err(GitError::invalid_command("No remote configured to list refs from.".to_string())),
+        };
+        match config.get(&format!("remote.{}.url", name)) {
+            Some(url) => Ok(url.to_string()),
+            None if self.repository.is_none() => Err(GitError::invalid_command(format!("No remote configured to list refs from: {}", name))),
+            None => Ok(name),
+        }
+    }
+
+    /// the refs `url` advertises, HEAD says which branch it points at
+    pub(crate) fn list(config: &Config, url: &str) -> Result<Vec<RemoteRef>> {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            GitProtocol::new(config, url)?.discover_refs_http(url)
+        } else if url.starts_with("git@") || url.contains("ssh://") {
+            Err(GitError::invalid_command(format!("cannot list the refs of {} over ssh", url)))
+        } else {
+            let remote_gitdir = Self::local_gitdir(Path::new(url));
+            if remote_gitdir.join("HEAD").is_file() {
+                Self::local_refs(&remote_gitdir)
+            } else {
+                Err(GitError::invalid_command(format!("'{}' does not appear to be a git repository", url)))
+            }
+        }
+    }
+
+    /// the local paths a remote can be: a .git directory, or a worktree containing one
+    pub(crate) fn local_gitdir(path: &Path) -> PathBuf {
+        if path.join(".git").is_dir() { path.join(".git") } else { path.to_path_buf() }
+    }
+
+    /// the same list a server advertises: HEAD, then every ref sorted, annotated tags peeled
+    fn local_refs(remote_gitdir: &Path) -> Result<Vec<RemoteRef>> {
+        let mut refs = Vec::new();
+        if let Ok(hash) = head_to_hash(remote_gitdir) {
+            refs.push(RemoteRef { name: "HEAD".to_string(), hash, peeled: None, symref: read_head_ref(remote_gitdir).ok() });
+        }
+        for (name, hash) in for_each_ref(remote_gitdir, "refs/")? {
+            let peeled = Self::peel(remote_gitdir, &hash)?;
+            refs.push(RemoteRef { name, hash, peeled, symref: None });
+        }
+        Ok(refs)
+    }
+
+    /// the object an annotated tag finally points at, None for anything that is not a tag
+    fn peel(gitdir: &Path, hash: &ObjectId) -> Result<Option<ObjectId>> {
+        let mut peeled = None;
+        let mut current = *hash;
+        while let Ok(Obj::G(tag)) = read_obj(gitdir.to_path_buf(), &current) {
+            current = tag.object;
+            peeled = Some(current);
+        }
+        Ok(peeled)
+    }
+
+    fn wanted(&self, name: &str) -> bool {
+        let kind = match (self.heads, self.tags) {
+            (false, false) => true,
+            (heads, tags) => (heads && name.starts_with("refs/heads/")) || (tags && name.starts_with("refs/tags/")),
+        };
+        // like git, a pattern matches the whole name or its tail after a `/`
+        kind && (self.patterns.is_empty() || self.patterns.iter().any(|pattern| {
+            name == pattern || name.ends_with(&format!("/{}", pattern.trim_start_matches('/')))
+        }))
+    }
+}
+
+impl SubCommand for LsRemote {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        // a url works outside of a repository too
+        let gitdir = repo.ok().map(|repo| repo.gitdir);
+        let config = match &gitdir {
+            Some(gitdir) => Config::read_all(gitdir)?,
+            None => Config::read_global()?,
+        };
+        let url = self.remote_url(gitdir.as_deref(), &config)?;
+
+        let refs = Self::list(&config, &url)?;
+
+        for remote_ref in &refs {
+            if self.wanted(&remote_ref.name) {
+                println!("{}\t{}", remote_ref.hash, remote_ref.name);
+            }
+            // patterns see the peeled line as `refs/tags/v1^{}`, so `v1` alone skips it like in git
+            let peeled_name = format!("{}^{{}}", remote_ref.name);
+            if let Some(peeled) = &remote_ref.peeled && self.wanted(&peeled_name) {
+                println!("{}\t{}", peeled, peeled_name);
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        serve_upload_pack,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_ls_remote() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "first"], false),
+            (&["tag", "light"], false),
+            (&["tag", "-a", "v1", "-m", "release"], false),
+            (&["checkout", "-b", "feature/x"], false),
+            (&["commit", "--allow-empty", "-m", "second"], false),
+            (&["checkout", "master"], false),
+        ];
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(setup_cmds)(git_up).unwrap();
+        let (port, _) = serve_upload_pack(upstream.path().to_path_buf());
+        let http_url = format!("http://127.0.0.1:{}/repo.git", port);
+
+        let local = setup_test_git_dir();
+        let local_str = local.path().to_str().unwrap();
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "origin", upstream_str]).unwrap();
+        shell_spawn(&["git", "-C", local_str, "remote", "add", "web", &http_url]).unwrap();
+
+        // local paths and http give what real git prints for the same repository
+        let cases: &[(&[&str], &[&str])] = &[(&[], &[]), (&["--heads"], &[]), (&["--tags"], &[]), (&["--heads", "--tags"], &[]), (&[], &["x"]), (&["-t"], &["v1"])];
+        for (flags, patterns) in cases {
+            let origin = shell_spawn(&[&["git", "ls-remote"][..], flags, &[upstream_str], patterns].concat()).unwrap();
+            for remote in ["origin", "web"] {
+                let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", local_str, "ls-remote"][..], flags, &[remote], patterns].concat()).unwrap();
+                assert_eq!(origin, real, "{} {:?} {:?}", remote, flags, patterns);
+            }
+        }
+        assert!(shell_spawn(&["git", "ls-remote", upstream_str]).unwrap().contains("refs/tags/v1^{}"));
+
+        // an unreachable remote fails with the reason, not a generic message
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", local_str, "ls-remote", "http://127.0.0.1:1/repo.git"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(crate::utils::error::EXIT_NETWORK));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.to_lowercase().contains("connection refused"), "{}", stderr);
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+use crate::{
+    utils::{
+        fs::{read_object, quote_path},
+        revparse::resolve_tree,
+        tree::{FileMode, Tree, TreeEntry},
+    },
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "ls-tree", about = "List the contents of a tree object")]
+pub struct LsTree {
+    #[arg(short = 'r', help = "recurse into sub-trees, only blobs are shown")]
+    recursive: bool,
+
+    #[arg(short = 'd', help = "show only the named tree entries themselves")]
+    trees_only: bool,
+
+    #[arg(long, alias = "name-status", help = "list only filenames")]
+    name_only: bool,
+
+    #[arg(help = "a tree, a commit or a tag pointing at one, by hash or revision name")]
+    tree_ish: String,
+}
+
+impl LsTree {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(LsTree::try_parse_from(args)?))
+    }
+
+    /// every sub-tree below `tree`, parents before their children, paths joined with `prefix`
+    fn subtrees(gitdir: &Path, tree: Tree, prefix: &Path, out: &mut Vec<TreeEntry>) -> Result<()> {
+        for entry in tree.0.into_iter().filter(|entry| entry.mode == FileMode::Tree) {
+            let path = prefix.join(&entry.path);
+            let sub_tree = read_object::<Tree>(gitdir.to_path_buf(), &entry.hash)?;
+            out.push(TreeEntry { path: path.clone(), ..entry });
+            Self::subtrees(gitdir, sub_tree, &path, out)?;
+        }
+        Ok(())
+    }
+}
+
+impl SubCommand for LsTree {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let tree = read_object::<Tree>(gitdir.clone(), &resolve_tree(&gitdir, &self.tree_ish)?)?;
+
+        // entries come out in the order they are stored, which is the order git prints
+        let entries = match (self.recursive, self.trees_only) {
+            (true, true) => {
+                let mut out = Vec::new();
+                Self::subtrees(&gitdir, tree, Path::new(""), &mut out)?;
+                out
+            },
+            (true, false) => tree.flatten(&gitdir)?,
+            (false, true) => tree.0.into_iter().filter(|entry| entry.mode == FileMode::Tree).collect(),
+            (false, false) => tree.0,
+        };
+        for entry in entries {
+            if self.name_only {
+                println!("{}", quote_path(&entry.path));
+            }
+            else {
+                println!("{}", entry);
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_ls_tree() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        // `foo.txt` sorts before the directory `foo`, an executable and a symlink for the modes
+        for name in ["a.txt", "foo.txt", "foo/bar.txt", "foo/deep/x.rs", "z/y.txt", "run.sh"] {
+            let path = temp_path.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, name).unwrap();
+        }
+        shell_spawn(&["chmod", "+x", temp_path.join("run.sh").to_str().unwrap()]).unwrap();
+        symlink("a.txt", temp_path.join("link")).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "add", "."]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "commit", "-m", "tree"]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "tag", "-a", "-m", "annotated", "v1"]).unwrap();
+        let tree = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD^{tree}"]).unwrap();
+        let tree = tree.trim();
+
+        for flags in [&[][..], &["-r"], &["-d"], &["-r", "-d"], &["--name-only"], &["-r", "--name-only"]] {
+            for tree_ish in ["HEAD", "master", "v1", tree] {
+                let args = [&["ls-tree"][..], flags, &[tree_ish]].concat();
+                let origin = shell_spawn(&[&["git", "-C", temp_path_str][..], &args].concat()).unwrap();
+                let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], &args].concat()).unwrap();
+                assert_eq!(origin, real, "{:?}", args);
+            }
+        }
+
+        let blob = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD:a.txt"]).unwrap();
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "ls-tree", blob.trim()]).is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use std::fs::write;
-use std::iter::Peekable;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::io;
 use std::cmp::min;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{
     Path,
     PathBuf
@@ -24,8 +25,9 @@ use crate::utils::{
         write_object,
         read_obj,
         read_object,
+        read_worktree_file,
     },
-    hash::hash_object,
+    hash::{hash_object, ObjectId},
     objtype::{
         ObjType,
         Obj,
@@ -34,14 +36,17 @@ use crate::utils::{
         head_to_hash,
         read_ref_commit,
         write_ref_commit,
+        write_ref_commit_with_log,
         write_branch_commit,
         write_head_ref,
         read_head_ref,
         read_branch_commit,
+        current_branch,
     },
     index::{
         Index,
         IndexEntry,
+        LockedIndexWriter,
     },
     blob::Blob,
     tree::{
@@ -50,6 +55,8 @@ use crate::utils::{
         TreeEntry,
     },
     commit::Commit,
+    ident,
+    revwalk,
     test::shell_spawn,
 };
 
@@ -57,20 +64,24 @@ use crate::command::{
     commit,
     update_ref,
     checkout::Checkout,
+    WriteTree,
 };
 use crate::{
     GitError,
     Result,
 };
 use super::SubCommand;
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
 
-type Diffence = (Option<Vec<TreeEntry>>, Option<Vec<(TreeEntry, TreeEntry)>>);
 
 
 #[derive(Parser, Debug)]
 #[command(name = "merge", about = "Join two or more development histories together")]
 pub struct Merge {
 
+    #[arg(short, long, help = "commit message of the merge commit")]
+    message: Option<String>,
+
     #[arg(required = true, help = "branch name you want to merge into HEAD")]
     branch: String
 }
@@ -80,119 +91,52 @@ impl Merge {
         Ok(Box::new(Merge::try_parse_from(args)?))
     }
 
-    fn get_all_ancestor<P>(gitdir: P, hash: Option<String>, mut sofar: Vec<String>) -> Result<Vec<String>>
-    where
-        P: AsRef<Path>
-    {
-        if hash.is_none() {
-            // println!("return {:?}", sofar);
-            Ok(sofar)
+    /// `Merge branch 'topic'`, with ` into <current>` unless merging into master/main like git
+    fn default_message(gitdir: &Path, branch: &str) -> String {
+        let branch = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+        match current_branch(gitdir) {
+            Ok(current) if current != "master" && current != "main" => {
+                format!("Merge branch '{}' into {}", branch, current)
+            },
+            _ => format!("Merge branch '{}'", branch),
         }
-        else {
-            let hash = hash.unwrap();
-            if let Obj::C(Commit {parent_hash,..}) = read_obj(gitdir.as_ref().to_path_buf(), &hash)? {
-                sofar.insert(0, hash);
-                Self::get_all_ancestor(gitdir, if !parent_hash.is_empty() {Some(parent_hash[0].clone())} else {None}, sofar)
-            }
-            else {
-                Err(GitError::broken_commit_history(hash))
+    }
+
+    /// MERGE_HEAD, CHERRY_PICK_HEAD, REVERT_HEAD, REBASE_HEAD and MERGE_MSG only exist while a merge, cherry-pick, revert or rebase step is in progress
+    pub fn clear_merge_state(gitdir: &Path) -> Result<()> {
+        for name in ["MERGE_HEAD", "CHERRY_PICK_HEAD", "REVERT_HEAD", "REBASE_HEAD", "MERGE_MSG"] {
+            let path = gitdir.join(name);
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .map_err(|_| GitError::failed_to_remove_file(path.display().to_string()))?;
             }
         }
+        Ok(())
     }
 
-    fn first_same_commit(gitdir: impl AsRef<Path>, hash1: String, hash2: String) -> Result<String> {
-        let ancestor1 = Self::get_all_ancestor(&gitdir, Some(hash1.clone()), Vec::new())?;
-        let ancestor2 = Self::get_all_ancestor(&gitdir, Some(hash2.clone()), Vec::new())?;
-        let index = ancestor1.iter()
-            .zip(ancestor2.iter()) // 将两个数组的元素一一配对
-            .take_while(|(a, b)| a == b) // 取出相等的元素，直到遇到不相等的为止
-            .count();
-
-        if index >= 1 {
-            Ok(ancestor1[index - 1].clone())
-        }
-        else {
-            Err(GitError::no_same_ancestor(format!("can not find same ancestor for {} and {}", hash1, hash2)))
-        }
+    fn first_same_commit(gitdir: impl AsRef<Path>, hash1: &ObjectId, hash2: &ObjectId) -> Result<ObjectId> {
+        revwalk::merge_bases(gitdir, hash1, hash2)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| GitError::no_same_ancestor(format!("can not find same ancestor for {} and {}", hash1, hash2)))
     }
 
 fn fast_forward(gitdir: impl AsRef<Path>, branch_name: &str, original_branch: &str) -> Result<()> {
     let hash = read_branch_commit(gitdir.as_ref(), branch_name)?;
     println!("Fast-forward: target hash = {}", hash);
 
+    // update the worktree in place, checking out the other branch would show up in the reflog
     println!("Fast-forward: updating working directory to {}", branch_name);
-    let checkout = Checkout::from_internal(Some(branch_name.to_string()), vec![]);
-    let checkout_result = checkout.run(Ok(gitdir.as_ref().to_path_buf()));
-    
-    if let Err(e) = &checkout_result {
-        println!("Checkout failed: {}", e);
-        return checkout_result.map(|_| ());
-    } else {
-        println!("Checkout succeeded");
-    }
+    let current = head_to_hash(gitdir.as_ref())?;
+    Checkout::switch_worktree(gitdir.as_ref(), Some(&current), &hash)?;
 
     println!("Fast-forward: updating branch reference");
-    write_ref_commit(gitdir.as_ref(), original_branch, &hash)?;
-    write_head_ref(gitdir.as_ref(), original_branch)?;
+    write_ref_commit_with_log(gitdir.as_ref(), original_branch, &hash, &format!("merge {}: Fast-forward", branch_name))?;
     println!("Successfully fast-forwarded to {}", hash);
 
     Ok(())
 }
 
-    fn diff_array<T>(mut a: Peekable<T>, mut b: Peekable<T>) -> Diffence
-    where
-        T: Iterator<Item = TreeEntry>,
-    {
-        let head_a = a.peek();
-        let head_b = b.peek();
-        match (head_a, head_b) {
-            (None, None) => (None, None),
-            (Some(_), None) => (Some(a.collect::<Vec<_>>()), None),
-            (None, Some(_)) => (Some(b.collect::<Vec<_>>()), None),
-
-            (Some(head_a), Some(head_b)) if head_a == head_b => {
-                let (head_a, head_b) = (a.next().unwrap(), b.next().unwrap());
-                match Self::diff_array(a, b) {
-                    (diffence, Some(mut same)) => {
-                        same.push((head_a, head_b));
-                        (diffence, Some(same))
-                    },
-                    (diffence, None) => (diffence, Some(vec![(head_a, head_b)])),
-                }
-            },
-            (Some(head_a), Some(head_b)) if head_a < head_b => {
-                let head_a = a.next().unwrap();
-                match Self::diff_array(a, b) {
-                    (Some(mut diffence), same) => {
-                        diffence.push(head_a);
-                        (Some(diffence), same)
-                    },
-                    (None, same) => (Some(vec![head_a]), same),
-                }
-            },
-            (Some(_), Some(_)) => {
-                let head_b = b.next().unwrap();
-                match Self::diff_array(a, b) {
-                    (Some(mut diffence), same) => {
-                        diffence.push(head_b);
-                        (Some(diffence), same)
-                    },
-                    (None, same) => (Some(vec![head_b]), same)
-                }
-            },
-        }
-    }
-
-    fn handle_dirrence_file(index: &mut Index, diffence: Option<Vec<TreeEntry>>) {
-        if let Some(diffence) = diffence {
-            diffence.into_iter()
-                .for_each(|TreeEntry{mode, hash, path}| {
-                    // println!("save {} to stage", path.display());
-                    index.add_entry(IndexEntry::new(mode as u32, hash, path.display().to_string()));
-                })
-        };
-    }
-
     fn diff_text(original: &str, modified: &str) -> Vec<Vec<usize>> {
         let origin = original.split("\n")
             .collect::<Vec<_>>();
@@ -243,105 +187,246 @@ fn fast_forward(gitdir: impl AsRef<Path>, branch_name: &str, original_branch: &s
         ranges
     }
 
-    fn save_conflict_object(index: &mut Index, gitdir: PathBuf, a: &TreeEntry, b: &TreeEntry, a_blob: &str, b_blob: &str) -> Result<()> {
-        index.add_entry(IndexEntry::new(a.mode as u32, a.hash.clone(), a.path.display().to_string()));
-        index.add_entry(IndexEntry::new(b.mode as u32, b.hash.clone(), b.path.display().to_string()));
-        // println!("add {}", a.path.display());
-        // println!("add {}", b.path.display());
+    /// write `content` to `path` in the worktree, creating the parent directories
+    fn write_worktree_file(project_root: &Path, path: &Path, mode: u32, content: &[u8]) -> Result<()> {
+        let file_path = project_root.join(path);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|_| GitError::failed_to_write_file(&parent.to_string_lossy()))?;
+        }
+        // 写文件会跟随已有的符号链接，先把它删掉
+        if file_path.is_symlink() || (mode == FileMode::Symbolic as u32 && file_path.is_file()) {
+            std::fs::remove_file(&file_path)
+                .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+        }
+        if mode == FileMode::Symbolic as u32 {
+            return std::os::unix::fs::symlink(String::from_utf8_lossy(content).as_ref(), &file_path)
+                .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()));
+        }
+        write(&file_path, content)
+            .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()))?;
+        let permissions = if mode == FileMode::Exec as u32 { 0o755 } else { 0o644 };
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(permissions))?;
+        Ok(())
+    }
+
+    /// diffy labels the sides `ours` / `theirs`, git uses `HEAD` and the merged branch
+    fn relabel_markers(conflict: &str, theirs: &str) -> String {
+        conflict.split_inclusive('\n')
+            .map(|line| match line.trim_end_matches('\n') {
+                "<<<<<<< ours" => "<<<<<<< HEAD\n".to_string(),
+                ">>>>>>> theirs" => format!(">>>>>>> {}\n", theirs),
+                _ => line.to_string(),
+            })
+            .collect()
+    }
+
+    /// 3-way merge of a path changed on both sides, `base` is None when both sides added it
+    /// a clean result goes to stage 0, otherwise base / ours / theirs are kept as stage 1 / 2 / 3
+    /// and the worktree file gets the conflict markers, returns whether the path is conflicted
+    fn save_conflict_object(index: &mut Index, gitdir: PathBuf, base: Option<&TreeEntry>, a: &TreeEntry, b: &TreeEntry, theirs: &str) -> Result<bool> {
+        let project_root = &work_tree(&gitdir)?;
+        let name = a.path.display().to_string();
+        let read_blob = |hash: &ObjectId| -> Result<String> {
+            Ok(String::from_utf8(read_object::<Blob>(gitdir.clone(), hash)?.into())?)
+        };
+        let base_blob = base.map(|o| read_blob(&o.hash)).transpose()?.unwrap_or_default();
+        let (a_blob, b_blob) = (read_blob(&a.hash)?, read_blob(&b.hash)?);
+
         let mut mo = MergeOptions::new();
         mo.set_conflict_style(ConflictStyle::Merge);
-        if let Err(diff) = mo.merge("", a_blob, b_blob) {
-            let hash = write_object::<Blob>(gitdir.clone(), diff.into_bytes())?;
-            // println!("add {}", hash);
-            index.add_entry({
-                IndexEntry {
-                    mode: a.mode as u32,
-                    hash,
-                    name: a.path.display().to_string()
+        match mo.merge(&base_blob, &a_blob, &b_blob) {
+            Ok(merged) => {
+                // a mode change on one side only still wins
+                let mode = if base.is_some_and(|o| o.mode == a.mode) { b.mode } else { a.mode };
+                let hash = write_object::<Blob>(gitdir.clone(), merged.clone().into_bytes())?;
+                index.add_entry(IndexEntry::new(mode as u32, hash, name));
+                Self::write_worktree_file(project_root, &a.path, mode as u32, merged.as_bytes())?;
+                Ok(false)
+            },
+            Err(conflict) => {
+                if let Some(o) = base {
+                    index.add_entry(IndexEntry::new(o.mode as u32, o.hash, name.clone()).with_stage(1));
                 }
-            })
+                index.add_entry(IndexEntry::new(a.mode as u32, a.hash, name.clone()).with_stage(2));
+                index.add_entry(IndexEntry::new(b.mode as u32, b.hash, name).with_stage(3));
+                let conflict = Self::relabel_markers(&conflict, theirs);
+                Self::write_worktree_file(project_root, &a.path, a.mode as u32, conflict.as_bytes())?;
+                Ok(true)
+            },
+        }
+    }
+
+    /// bring the merge result into the worktree, used when the merge stops on a conflict
+    /// cleanly merged (stage 0) paths are written, paths of `ours` which were deleted are removed
+    /// paths the merge left as they were in `ours` are not touched, local changes to them stay
+    pub(crate) fn checkout_merged(gitdir: &Path, index: &Index, ours: &BTreeMap<PathBuf, TreeEntry>) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let unchanged = |entry: &IndexEntry| ours.get(&entry.name).is_some_and(|o| o.mode as u32 == entry.mode && o.hash == entry.hash);
+        for entry in index.entries.iter().filter(|entry| entry.stage == 0 && !unchanged(entry)) {
+            let content: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), &entry.hash)?.into();
+            if read_worktree_file(project_root.join(&entry.name)).ok() != Some((entry.mode, content.clone())) {
+                Self::write_worktree_file(project_root, Path::new(&entry.name), entry.mode, &content)?;
+            }
+        }
+        for path in ours.keys() {
+            let name = path.display().to_string();
+            let file_path = project_root.join(path);
+            if !index.entries.iter().any(|entry| entry.name == name) && (file_path.is_file() || file_path.is_symlink()) {
+                std::fs::remove_file(&file_path)
+                    .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+                remove_empty_parents(project_root, &file_path);
+            }
         }
         Ok(())
     }
 
-    #[allow(clippy::manual_try_fold)]
-    fn handle_same_file(index: &mut Index, gitdir: PathBuf, same: Vec<(TreeEntry, TreeEntry)>) -> Result<()> {
-        let (equal, not): (Vec<_>, Vec<_>) = same.into_iter().partition(|(a, b)|a.hash == b.hash);
-        equal.iter()
-            .for_each(|(a, _)| {
-                // println!("add {}", a.path.display());
-                index.add_entry(IndexEntry::new(a.mode as u32, a.hash.clone(), a.path.display().to_string()));
-            });
+    /// conflict message of a path changed on both sides, None if it merged cleanly
+    fn handle_same_file(index: &mut Index, gitdir: PathBuf, base: Option<&TreeEntry>, a: Option<&TreeEntry>, b: Option<&TreeEntry>, theirs: &str) -> Result<Option<String>> {
+        let (a, b) = match (a, b) {
+            (Some(a), Some(b)) => (a, b),
+            // modified on one side, deleted on the other: keep the modified file in the worktree
+            (a, b) => {
+                let (o, kept) = (base.expect("a path missing on both sides is never a conflict"), a.or(b).unwrap());
+                let name = o.path.display().to_string();
+                index.add_entry(IndexEntry::new(o.mode as u32, o.hash, name.clone()).with_stage(1));
+                index.add_entry(IndexEntry::new(kept.mode as u32, kept.hash, name).with_stage(if a.is_some() { 2 } else { 3 }));
+                let content: Vec<u8> = read_object::<Blob>(gitdir.clone(), &kept.hash)?.into();
+                let project_root = &work_tree(&gitdir)?;
+                Self::write_worktree_file(project_root, &kept.path, kept.mode as u32, &content)?;
+                return Ok(Some(format!("Merge conflict in {}: deleted in one branch and modified in the other", o.path.display())));
+            },
+        };
+        if !Self::save_conflict_object(index, gitdir.clone(), base, a, b, theirs)? {
+            return Ok(None);
+        }
 
-        let (_, err): (Vec<_>, Vec<_>) = not.into_iter()
-            .map(|(a, b)| {
-                let a_blob = String::from_utf8(read_object::<Blob>(gitdir.clone(), &a.hash)?.into())?;
-                let b_blob = String::from_utf8(read_object::<Blob>(gitdir.clone(), &b.hash)?.into())?;
-                Self::save_conflict_object(index, gitdir.clone(), &a, &b, &a_blob, &b_blob)?;
-
-                let output = Self::diff_text(&a_blob, &b_blob)
-                    .into_iter()
-                    .map(|v| {
-                        // println!("v.len() = {}", v.len());
-                        if v.len() == 1 {
-                            format!("Merge conflict in {}: {}", a.path.display(), v[0])
-                        }
-                        else {
-                            format!("Merge conflict in {}: [{}, {}]", a.path.display(), v[0], v[v.len() - 1])
-                        }
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\n");
-                Err(GitError::merge_conflict(output))
+        let a_blob = String::from_utf8(read_object::<Blob>(gitdir.clone(), &a.hash)?.into())?;
+        let b_blob = String::from_utf8(read_object::<Blob>(gitdir.clone(), &b.hash)?.into())?;
+        let output = Self::diff_text(&a_blob, &b_blob)
+            .into_iter()
+            .map(|v| {
+                // println!("v.len() = {}", v.len());
+                if v.len() == 1 {
+                    format!("Merge conflict in {}: {}", a.path.display(), v[0])
+                }
+                else {
+                    format!("Merge conflict in {}: [{}, {}]", a.path.display(), v[0], v[v.len() - 1])
+                }
             })
-            .partition(|x: &Result<_>|x.is_ok());
-
-        if !err.is_empty() {
-            err.into_iter()
-                .fold(Err(GitError::merge_conflict("".to_string())), |acc, ele: Result<()>| {
-                    match (acc, ele) {
-                        (Err(a), Err(b)) => {
-                            Err(GitError::merge_conflict( {
-                                let out = a.to_string() + "\n" + &b.to_string();
-                                if out.starts_with("\n") {
-                                    out.strip_prefix("\n").unwrap().to_string()
-                                }
-                                else {
-                                    out
-                                }
-                            }))
-                        },
-                        _ => Ok(())
-                    }
-                })
+            .collect::<Vec<String>>()
+            .join("\n");
+        Ok(Some(output))
+    }
+
+    pub(crate) fn flatten_tree(gitdir: &Path, hash: &ObjectId) -> Result<BTreeMap<PathBuf, TreeEntry>> {
+        let tree = read_object::<Tree>(gitdir.to_path_buf(), hash)?;
+        Ok(tree.flatten(gitdir)?
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect())
+    }
+
+    /// git refuses to merge onto staged changes, or onto local changes of the paths the merge brings in,
+    /// before anything is written; `theirs` differing from both `base` and `ours` is what the merge changes
+    fn check_local_changes(gitdir: &Path, hash_base: &ObjectId, hash_a: &ObjectId, hash_b: &ObjectId) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let index = Index::read_or_default(gitdir)?;
+        let (base, ours, other) = (Self::flatten_tree(gitdir, hash_base)?, Self::flatten_tree(gitdir, hash_a)?, Self::flatten_tree(gitdir, hash_b)?);
+        let header = "error: Your local changes to the following files would be overwritten by merge:\n";
+
+        let staged = index.entries.iter()
+            .map(|entry| (entry.name.clone(), (entry.mode, entry.hash, entry.stage)))
+            .collect::<BTreeMap<_, _>>();
+        let committed = ours.iter()
+            .map(|(path, entry)| (path.clone(), (entry.mode as u32, entry.hash, 0)))
+            .collect::<BTreeMap<_, _>>();
+        let changed = staged.keys().chain(committed.keys())
+            .filter(|path| staged.get(*path) != committed.get(*path))
+            .collect::<BTreeSet<_>>();
+        if !changed.is_empty() {
+            let files = changed.iter().map(|path| format!("  {}\n", path.display())).collect::<String>();
+            return Err(GitError::merge_conflict(format!("{}{}Merge with strategy ort failed.", header, files)));
         }
-        else {
-            Ok(())
+
+        let same = |x: Option<&TreeEntry>, y: Option<&TreeEntry>| x.map(|e| (e.mode, &e.hash)) == y.map(|e| (e.mode, &e.hash));
+        let (mut dirty, mut untracked) = (Vec::new(), Vec::new());
+        for path in base.keys().chain(ours.keys()).chain(other.keys()).collect::<BTreeSet<_>>() {
+            let (o, a, b) = (base.get(path), ours.get(path), other.get(path));
+            if same(o, b) || same(a, b) {
+                continue;
+            }
+            let file_path = project_root.join(path);
+            match index.entries.iter().find(|entry| &entry.name == path) {
+                Some(entry) if entry.is_stat_clean(&file_path) => {},
+                // a tracked file already gone is not in the way
+                Some(entry) => if let Ok((mode, content)) = read_worktree_file(&file_path)
+                    && (mode != entry.mode || hash_object::<Blob>(content)? != entry.hash) {
+                    dirty.push(path);
+                },
+                None if std::fs::symlink_metadata(&file_path).is_ok() => untracked.push(path),
+                None => {},
+            }
+        }
+        let mut message = String::new();
+        if !dirty.is_empty() {
+            let files = dirty.iter().map(|path| format!("\t{}\n", path.display())).collect::<String>();
+            message += &format!("{}{}Please commit your changes or stash them before you merge.\n", header, files);
         }
+        if !untracked.is_empty() {
+            let files = untracked.iter().map(|path| format!("\t{}\n", path.display())).collect::<String>();
+            message += &format!("error: The following untracked working tree files would be overwritten by merge:\n{}Please move or remove them before you merge.\n", files);
+        }
+        if !message.is_empty() {
+            return Err(GitError::merge_conflict(format!("{}Aborting\nMerge with strategy ort failed.", message)));
+        }
+        Ok(())
     }
 
-    fn merge_tree(gitdir: PathBuf, hash_a: String, hash_b: String) -> Result<Index> {
-        let tree_a = read_object::<Tree>(gitdir.clone(), &hash_a)?;
-        let tree_b = read_object::<Tree>(gitdir.clone(), &hash_b)?;
+    /// three way merge of the trees into the index, read and written under `lock`
+    /// | base | ours | theirs | result      |
+    /// | ---  | ---  | ---    | ---         |
+    /// | x    | x    | y      | y (theirs)  |
+    /// | x    | y    | x      | y (ours)    |
+    /// | x    | y    | y      | y           |
+    /// | x    | y    | z      | merge file  |
+    pub(crate) fn merge_tree(gitdir: PathBuf, lock: LockedIndexWriter, hash_base: &ObjectId, hash_a: &ObjectId, hash_b: &ObjectId, theirs: &str) -> Result<Index> {
+        let base = Self::flatten_tree(&gitdir, hash_base)?;
+        let ours = Self::flatten_tree(&gitdir, hash_a)?;
+        let other = Self::flatten_tree(&gitdir, hash_b)?;
+        let paths = base.keys().chain(ours.keys()).chain(other.keys()).collect::<BTreeSet<_>>();
         // println!("tree_a = {}", tree_a);
 
-        let paths_a = tree_a.into_iter_flatten(gitdir.clone())?.into_iter().sorted();
-        let paths_b = tree_b.into_iter_flatten(gitdir.clone())?.into_iter().sorted();
-        let (diffence, same) = Self::diff_array(paths_a.peekable(), paths_b.peekable());
-
-        // overwirte the index file
-        let mut index = Index::new();
-        Self::handle_dirrence_file(&mut index, diffence);
-        if let Some(same) = same {
-            let result = Self::handle_same_file(&mut index, gitdir.clone(), same);
-            if result.is_err() {
-                // println!("before writing to index file, index.len = {}", index.entries.len());
-                index.write_to_file(&gitdir.join("index"))?;
+        let same = |x: Option<&TreeEntry>, y: Option<&TreeEntry>| {
+            x.map(|e| (&e.mode, &e.hash)) == y.map(|e| (&e.mode, &e.hash))
+        };
+
+        // start from the index as it is, entries of the paths the merge leaves alone keep their stat data
+        let mut index = Index::read_or_default(&gitdir)?;
+        let mut conflicts = Vec::new();
+        for path in paths {
+            let (o, a, b) = (base.get(path), ours.get(path), other.get(path));
+            if same(a, b) || same(o, b) {
+                continue;
+            }
+            index.remove_entry(path);
+            if !same(o, a) {
+                if let Some(msg) = Self::handle_same_file(&mut index, gitdir.clone(), o, a, b, theirs)? {
+                    conflicts.push(msg);
+                }
+                continue;
+            }
+            if let Some(TreeEntry { mode, hash, path }) = b {
+                index.add_entry(IndexEntry::new(*mode as u32, *hash, path.display().to_string()));
             }
-            result?;
         }
+
         // println!("before writing to index file, index.len = {}", index.entries.len());
-        index.write_to_file(&gitdir.join("index"))?;
+        lock.commit(&index)?;
+        if !conflicts.is_empty() {
+            Self::checkout_merged(&gitdir, &index, &ours)?;
+            return Err(GitError::merge_conflict(conflicts.join("\n")));
+        }
         Ok(index)
     }
 
@@ -349,8 +434,8 @@ fn fast_forward(gitdir: impl AsRef<Path>, branch_name: &str, original_branch: &s
 
 
 impl SubCommand for Merge {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
         let hash1 = head_to_hash(&gitdir)?;
         let hash2 = if self.branch.starts_with("refs/") {
             // 如果已经是完整的引用路径，直接使用
@@ -359,7 +444,10 @@ impl SubCommand for Merge {
             // 否则假设是分支名，添加 refs/heads/ 前缀
             read_ref_commit(&gitdir, &format!("refs/heads/{}", self.branch))?
         };
-        let base_hash = Self::first_same_commit(&gitdir, hash1.clone(), hash2.clone())?;
+        let base_hash = Self::first_same_commit(&gitdir, &hash1, &hash2)?;
+        if base_hash != hash2 {
+            write_ref_commit(&gitdir, "ORIG_HEAD", &hash1)?;
+        }
 
         if base_hash == hash2 {
             println!("it's already latest");
@@ -382,43 +470,41 @@ impl SubCommand for Merge {
             // | 6   | False | True  | False |
             // | 7   | False | False | True  |
 
+            let commit_base = read_object::<Commit>(gitdir.clone(), &base_hash)?;
             let commit_a = read_object::<Commit>(gitdir.clone(), &hash1)?;
             let commit_b = read_object::<Commit>(gitdir.clone(), &hash2)?;
-            let index = Self::merge_tree(gitdir.clone(), commit_a.tree_hash, commit_b.tree_hash)?;
-
-            // make a new commit
-            let tree = Tree({
-                index.entries
-                .into_iter()
-                .map(|IndexEntry {mode, hash, name}| TreeEntry {
-                    mode: mode.try_into().unwrap(),
-                    hash,
-                    path: PathBuf::from(name),
-                })
-                .collect::<Vec<TreeEntry>>()
-            });
-            let tree_hash = write_object::<Tree>(gitdir.clone(), tree.into())?;
-
-            let commit = Commit {
+            // held from the check to the merged index, no other writer gets in between
+            let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+            Self::check_local_changes(&gitdir, &commit_base.tree_hash, &commit_a.tree_hash, &commit_b.tree_hash)?;
+
+            // keep the merge state around so that `commit` can finish a conflicted merge
+            let message = self.message.clone()
+                .unwrap_or_else(|| Self::default_message(&gitdir, &self.branch));
+            write_ref_commit(&gitdir, "MERGE_HEAD", &hash2)?;
+            write(gitdir.join("MERGE_MSG"), format!("{}\n", message.trim_end()))
+                .map_err(|_| GitError::failed_to_write_file(&gitdir.join("MERGE_MSG").to_string_lossy()))?;
+
+            // merge_tree leaves the merged result in the index
+            let ours = Self::flatten_tree(&gitdir, &commit_a.tree_hash)?;
+            let index = Self::merge_tree(gitdir.clone(), lock, &commit_base.tree_hash, &commit_a.tree_hash, &commit_b.tree_hash, &self.branch)?;
+            let tree_hash = WriteTree::lazy_fucker(gitdir.clone())?;
+
+            let commit = Commit::new(
                 tree_hash,
-                parent_hash: vec![hash1, hash2],
-                author: "Default Author <139881912@163.com> 1748165415 +0800".into(),
-                committer: "commiter Author <139881912@163.com> 1748165415 +0800".into(),
-                message: format!("merge {} into this\n", self.branch)
-            };
+                vec![hash1, hash2],
+                ident::author(&gitdir)?,
+                ident::committer(&gitdir)?,
+                message.trim_end(),
+            );
             let merge_hash = write_object::<Commit>(gitdir.clone(), commit.into())?;
 
-            let update_ref = update_ref::UpdateRef {
-                ref_path: read_head_ref(&gitdir)?,
-                commit_hash: merge_hash.clone(),
-            };
-            update_ref.run(Ok(gitdir.clone()))?;
+            let reflog_message = format!("merge {}: Merge made by the 'ort' strategy.", self.branch);
+            update_ref::UpdateRef::new(read_head_ref(&gitdir)?, &merge_hash, &reflog_message).update(&gitdir)?;
+            Self::clear_merge_state(&gitdir)?;
             println!("{}", merge_hash);
 
-            // Checkout::restore_workspace(&gitdir, &merge_hash)?;
-            let head = read_head_ref(&gitdir)?;
-            let checkout = Checkout::from_internal(Some(head), vec![]);
-            checkout.run(Ok(gitdir))?;
+            // only the paths the merge changed are written, local edits of the others stay
+            Self::checkout_merged(&gitdir, &index, &ours)?;
         }
         Ok(0)
     }
@@ -469,7 +555,7 @@ mod test {
             (&["checkout", "master"], false),
         ];
 
-        let git = &["git", "-C", &temp_path_str1];
+        let git = &["git", "-C", temp_path_str1];
         let mut oper = cmd_seq(setup_cmds);
         let _ = oper(git).unwrap();
         let _ = cp_dir(temp_path1, temp_path2).unwrap();
@@ -524,7 +610,7 @@ mod test {
             (&["commit", "-m", "B"], false),
         ];
 
-        let git = &["git", "-C", &temp_path_str1];
+        let git = &["git", "-C", temp_path_str1];
         let mut oper = cmd_seq(setup_cmds);
         let _ = oper(git).unwrap();
         let _ = cp_dir(temp_path1, temp_path2).unwrap();
@@ -541,11 +627,9 @@ mod test {
         let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
         assert_eq!(
             real.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>(),
             origin.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>()
         );
@@ -576,27 +660,36 @@ mod test {
         let a = file1_str;
         let b = PathBuf::from("world").join(file2_str);
         let c = PathBuf::from("world").join(file3_str);
+        // conflict.txt is added on both branches with different content
+        let git = &["git", "-C", temp_path_str1];
         let setup_cmds: ArgsList = &[
             (&["add", a], false),
             (&["commit", "-m", "branch master"], false),
             (&["checkout", "-b", "A"], false),
-            (&["add", b.to_str().unwrap()], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+        std::fs::write(temp_path1.join("conflict.txt"), "from A\n").unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", b.to_str().unwrap(), "conflict.txt"], false),
             (&["commit", "-m", "A"], false),
             (&["checkout", "master"], false),
-            (&["add", c.to_str().unwrap()], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+        std::fs::write(temp_path1.join("conflict.txt"), "from master\n").unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", c.to_str().unwrap(), "conflict.txt"], false),
             (&["commit", "-m", "B"], false),
         ];
-
-        let git = &["git", "-C", &temp_path_str1];
-        let mut oper = cmd_seq(setup_cmds);
-        let _ = oper(git).unwrap();
+        cmd_seq(setup_cmds)(git).unwrap();
         let _ = cp_dir(temp_path1, temp_path2).unwrap();
 
+        // both exit with 1 on a conflict
         let test_cmds: ArgsList = &[
             (&["merge", "A"], true),
         ];
         let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
-        let _ = run_both(test_cmds, git, cargo).unwrap();
+        assert!(cmd_seq(test_cmds)(git).is_err());
+        assert!(cmd_seq(test_cmds)(cargo).is_err());
 
         // println!("{}", shell_spawn(&["ls", "-lah", temp_path_str1, temp_path1.join(b.to_str().unwrap()).to_str().unwrap()]).unwrap());
 
@@ -607,11 +700,9 @@ mod test {
         println!("real = {real}");
         assert_eq!(
             real.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>(),
             origin.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>()
         );
@@ -622,17 +713,223 @@ mod test {
         println!("{real}");
         assert_eq!(
             real.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>(),
             origin.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>()
         );
+
+        for args in [&["status", "--porcelain"][..], &["rev-parse", "MERGE_HEAD"]] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+
+        // our ls-files reads the conflict stages back the same way git does
+        for args in [&["ls-files", "--stage"][..], &["ls-files"]] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&cargo[..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        let origin = std::fs::read_to_string(temp_path1.join("conflict.txt")).unwrap();
+        let real = std::fs::read_to_string(temp_path2.join("conflict.txt")).unwrap();
+        assert_eq!(origin, real);
+        assert!(real.contains("<<<<<<< HEAD"));
+
+        // adding the resolved file drops stages 1 / 2 / 3
+        std::fs::write(temp_path1.join("conflict.txt"), "resolved\n").unwrap();
+        std::fs::write(temp_path2.join("conflict.txt"), "resolved\n").unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "add", "conflict.txt"]).unwrap();
+        shell_spawn(&[&cargo[..], &["add", "conflict.txt"]].concat()).unwrap();
+        assert_eq!(
+            shell_spawn(&["git", "-C", temp_path_str1, "ls-files", "--stage"]).unwrap(),
+            shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap()
+        );
         // assert!(false);
     }
 
+    #[test]
+    fn test_merge_state() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        std::fs::write(temp_path1.join("a.txt"), "a").unwrap();
+        std::fs::create_dir_all(temp_path1.join("dir")).unwrap();
+        std::fs::write(temp_path1.join("dir/b.txt"), "b").unwrap();
+        std::fs::write(temp_path1.join("c.txt"), "c").unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", "a.txt"], false),
+            (&["commit", "-m", "base"], false),
+            (&["checkout", "-b", "topic"], false),
+            (&["add", "dir/b.txt"], false),
+            (&["commit", "-m", "topic"], false),
+            (&["checkout", "master"], false),
+            (&["add", "c.txt"], false),
+            (&["commit", "-m", "master"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let test_cmds: ArgsList = &[
+            (&["merge", "topic"], false),
+        ];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        let _ = run_both(test_cmds, git, cargo).unwrap();
+
+        for args in [&["log", "-1", "--format=%T %P%n%B"][..], &["rev-parse", "ORIG_HEAD"], &["ls-files", "--stage"], &["status", "--porcelain"]] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        assert!(!temp_path2.join(".git/MERGE_HEAD").exists());
+        assert!(!temp_path2.join(".git/MERGE_MSG").exists());
+
+        // -m overrides the default message
+        let setup_cmds: ArgsList = &[
+            (&["checkout", "-b", "other", "HEAD~1"], false),
+            (&["commit", "--allow-empty", "-m", "other"], false),
+            (&["checkout", "master"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+        let test_cmds: ArgsList = &[
+            (&["merge", "-m", "custom message", "other"], false),
+        ];
+        let _ = run_both(test_cmds, git, cargo).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str1, "log", "-1", "--format=%T %P%n%B"]).unwrap();
+        let real = shell_spawn(&["git", "-C", temp_path_str2, "log", "-1", "--format=%T %P%n%B"]).unwrap();
+        assert_eq!(origin, real);
+    }
+
+    #[test]
+    fn test_three_way_merge() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        let lines = |edit: &[(usize, &str)]| (1..=8)
+            .map(|i| edit.iter().find(|(n, _)| *n == i).map(|(_, l)| l.to_string()).unwrap_or(format!("line {}", i)))
+            .map(|l| l + "\n")
+            .collect::<String>();
+        let write = |name: &str, content: &str| std::fs::write(temp_path1.join(name), content).unwrap();
+
+        let git = &["git", "-C", temp_path_str1];
+        write("both.txt", &lines(&[]));
+        write("ours.txt", &lines(&[]));
+        write("theirs.txt", &lines(&[]));
+        write("deleted.txt", "deleted\n");
+        let setup_cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["commit", "-m", "base"], false),
+            (&["checkout", "-b", "topic"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+
+        // each branch edits a different line of both.txt
+        write("both.txt", &lines(&[(2, "topic")]));
+        write("theirs.txt", &lines(&[(5, "topic")]));
+        write("added.txt", "added\n");
+        let setup_cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["rm", "deleted.txt"], false),
+            (&["commit", "-m", "topic"], false),
+            (&["checkout", "master"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+        write("both.txt", &lines(&[(7, "master")]));
+        write("ours.txt", &lines(&[(1, "master")]));
+        let setup_cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["commit", "-m", "master"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let test_cmds: ArgsList = &[
+            (&["merge", "topic"], false),
+        ];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        let _ = run_both(test_cmds, git, cargo).unwrap();
+
+        for args in [&["log", "-1", "--format=%T %P"][..], &["ls-files", "--stage"], &["status", "--porcelain"]] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        let both = std::fs::read_to_string(temp_path2.join("both.txt")).unwrap();
+        assert_eq!(both, lines(&[(2, "topic"), (7, "master")]));
+        assert!(!temp_path2.join("deleted.txt").exists());
+    }
+
+    #[test]
+    fn test_merge_local_changes() {
+        use std::process::Command;
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "base\n").unwrap();
+        std::fs::write(temp_path.join("b.txt"), "base\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "base"]);
+        git(&["checkout", "-b", "topic"]);
+        std::fs::write(temp_path.join("a.txt"), "topic\n").unwrap();
+        std::fs::write(temp_path.join("c.txt"), "topic\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "topic"]);
+        git(&["checkout", "master"]);
+        std::fs::write(temp_path.join("b.txt"), "master\n").unwrap();
+        git(&["commit", "-am", "master"]);
+
+        // (file, content, staged): an edit of a path the merge changes, a staged new file,
+        // an untracked file in the way, and an edit the merge doesn't touch
+        for (name, content, staged, refused) in [
+            ("a.txt", "dirty\n", false, true),
+            ("new.txt", "new\n", true, true),
+            ("c.txt", "untracked\n", false, true),
+            ("b.txt", "dirty\n", false, false),
+        ] {
+            let (ours, theirs) = (tempdir().unwrap(), tempdir().unwrap());
+            cp_dir(temp_path, ours.path()).unwrap();
+            cp_dir(temp_path, theirs.path()).unwrap();
+            for dir in [ours.path(), theirs.path()] {
+                std::fs::write(dir.join(name), content).unwrap();
+                if staged {
+                    shell_spawn(&["git", "-C", dir.to_str().unwrap(), "add", name]).unwrap();
+                }
+            }
+            let theirs_out = Command::new("git").arg("-C").arg(theirs.path()).args(["merge", "-m", "merge", "topic"]).output().unwrap();
+            let ours_out = Command::new("cargo")
+                .args(["run", "--quiet", "--", "-C", ours.path().to_str().unwrap(), "merge", "-m", "merge", "topic"])
+                .output()
+                .unwrap();
+            assert_eq!(theirs_out.status.success(), !refused, "{}", name);
+            assert_eq!(ours_out.status.success(), !refused, "{} {:?}", name, ours_out);
+            if refused {
+                // nothing is written: same message, HEAD, index and files as before
+                assert_eq!(String::from_utf8_lossy(&ours_out.stderr), String::from_utf8_lossy(&theirs_out.stderr), "{}", name);
+                assert!(!ours.path().join(".git/MERGE_HEAD").exists());
+            }
+            for args in [&["rev-parse", "HEAD^{tree}"][..], &["ls-files", "--stage"], &["status", "--porcelain"]] {
+                let origin = shell_spawn(&[&["git", "-C", theirs.path().to_str().unwrap()][..], args].concat()).unwrap();
+                let real = shell_spawn(&[&["git", "-C", ours.path().to_str().unwrap()][..], args].concat()).unwrap();
+                assert_eq!(origin, real, "{} {:?}", name, args);
+            }
+            assert_eq!(std::fs::read_to_string(ours.path().join(name)).unwrap(), content);
+        }
+    }
+
     #[test]
     fn test_ppt_merge() -> Result<()> {
         let temp_dir = tempdir()?;
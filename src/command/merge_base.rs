@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use clap::Parser;
+
+use crate::{
+    utils::{revparse::rev_parse, revwalk},
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "merge-base", about = "Find as good common ancestors as possible for a merge")]
+pub struct MergeBase {
+    #[arg(short, long, help = "output all merge bases instead of just one")]
+    all: bool,
+
+    #[arg(required = true, num_args = 2, help = "the two commits")]
+    commits: Vec<String>,
+}
+
+impl MergeBase {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(MergeBase::try_parse_from(args)?))
+    }
+}
+
+impl SubCommand for MergeBase {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let mut hashes = Vec::new();
+        for rev in &self.commits {
+            hashes.push(rev_parse(&gitdir, rev)?);
+        }
+
+        let bases = revwalk::merge_bases(&gitdir, &hashes[0], &hashes[1])?;
+        // unrelated histories print nothing and exit with 1
+        if bases.is_empty() {
+            return Ok(1);
+        }
+        let count = if self.all { bases.len() } else { 1 };
+        bases.iter().take(count).for_each(|hash| println!("{}", hash));
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_merge_base() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        // a side branch merged back into master, so the first-parent chains don't line up:
+        //
+        //   o---o---A---M---o   master
+        //        \     /
+        //         x---y---B     topic
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "root"], false),
+            (&["commit", "--allow-empty", "-m", "m1"], false),
+            (&["checkout", "-b", "topic"], false),
+            (&["commit", "--allow-empty", "-m", "x"], false),
+            (&["commit", "--allow-empty", "-m", "y"], false),
+            (&["branch", "y"], false),
+            (&["commit", "--allow-empty", "-m", "B"], false),
+            (&["checkout", "master"], false),
+            (&["commit", "--allow-empty", "-m", "A"], false),
+            (&["merge", "--no-ff", "-m", "M", "y"], false),
+            (&["commit", "--allow-empty", "-m", "m2"], false),
+        ];
+        let git = &["git", "-C", temp_path_str];
+        cmd_seq(setup_cmds)(git).unwrap();
+
+        let merge_base = |args: &[&str]| {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str, "merge-base"][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "merge-base"][..], args].concat()).unwrap();
+            (origin, real)
+        };
+        for args in [&["master", "topic"][..], &["topic", "master"], &["master", "master~2"], &["--all", "master", "topic"]] {
+            let (origin, real) = merge_base(args);
+            assert_eq!(origin, real, "{:?}", args);
+        }
+
+        // criss-cross: both branches merge each other, two bases are equally good
+        let setup_cmds: ArgsList = &[
+            (&["checkout", "-b", "left", "master~1"], false),
+            (&["checkout", "-b", "right", "topic"], false),
+            (&["merge", "--no-ff", "-m", "right merges left", "left"], false),
+            (&["checkout", "left"], false),
+            (&["merge", "--no-ff", "-m", "left merges right", "topic"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+
+        let (origin, real) = merge_base(&["--all", "left", "right"]);
+        let sorted = |s: &str| {
+            let mut lines = s.lines().map(String::from).collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+        assert_eq!(sorted(&origin), sorted(&real));
+        assert_eq!(sorted(&real).len(), 2);
+
+        // which of the equally good bases is picked depends on commit dates, it only has to be one of them
+        let (_, real) = merge_base(&["left", "right"]);
+        assert!(origin.lines().any(|line| line == real.trim()));
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ffi::OsStr,
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        fs::{read_raw_object, unquote_path, write_object},
+        hash::ObjectId,
+        objtype::parse_meta,
+        tree::{tree_entry_cmp, FileMode, Tree, TreeEntry},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "mktree", about = "Build a tree-object from ls-tree formatted text")]
+pub struct Mktree {
+    #[arg(short = 'z', help = "read NUL-terminated entries with unquoted paths, like ls-tree -z prints them")]
+    nul: bool,
+
+    #[arg(long, help = "allow objects that are not in the repository")]
+    missing: bool,
+
+    #[arg(long, help = "build one tree per group of entries, groups are separated by a blank line")]
+    batch: bool,
+}
+
+impl Mktree {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Mktree::try_parse_from(args)?))
+    }
+
+    /// `<mode> SP <type> SP <object> TAB <path>`, checked against the object it names like git does
+    fn parse_entry(&self, gitdir: &Path, line: &str) -> Result<TreeEntry> {
+        let format_error = || GitError::invalid_command(format!("input format error: {}", line));
+        let (meta, path) = line.split_once('\t').ok_or_else(format_error)?;
+        let [mode, kind, hash] = meta.split(' ').collect::<Vec<_>>()[..] else {
+            return Err(format_error());
+        };
+        let mode: FileMode = u32::from_str_radix(mode, 8).ok()
+            .and_then(|mode| mode.try_into().ok())
+            .ok_or_else(format_error)?;
+        let hash = ObjectId::from_hex(hash).map_err(|_| format_error())?;
+        let path = match self.nul {
+            true => path.as_bytes().to_vec(),
+            false => unquote_path(path).ok_or_else(format_error)?,
+        };
+        let path = PathBuf::from(OsStr::from_bytes(&path));
+        if path.as_os_str().as_bytes().contains(&b'/') {
+            return Err(GitError::invalid_command(format!("path {} contains slash", path.display())));
+        }
+
+        let mode_kind = mode.to_string();
+        if kind != mode_kind {
+            return Err(GitError::invalid_command(format!("entry '{}' object type ({}) doesn't match mode type ({})", path.display(), kind, mode_kind)));
+        }
+        // a submodule's commit lives in another repository
+        if mode != FileMode::Commit {
+            match read_raw_object(gitdir, &hash) {
+                Ok(bytes) => {
+                    let (_, (actual, _)) = parse_meta(&bytes).map_err(|x| GitError::invalid_obj(x.to_string()))?;
+                    if actual != kind.as_bytes() {
+                        return Err(GitError::invalid_command(format!("entry '{}' object {} is a {} but specified type was ({})",
+                            path.display(), hash, String::from_utf8_lossy(actual), kind)));
+                    }
+                },
+                Err(_) if self.missing => {},
+                Err(_) => return Err(GitError::invalid_command(format!("entry '{}' object {} is unavailable", path.display(), hash))),
+            }
+        }
+        Ok(TreeEntry { mode, hash, path })
+    }
+
+    fn write_tree(gitdir: &Path, mut entries: Vec<TreeEntry>) -> Result<()> {
+        entries.sort_by(|a, b| tree_entry_cmp(
+            a.path.as_os_str().as_bytes(), a.mode == FileMode::Tree,
+            b.path.as_os_str().as_bytes(), b.mode == FileMode::Tree,
+        ));
+        println!("{}", write_object::<Tree>(gitdir.to_path_buf(), Tree(entries).into())?);
+        Ok(())
+    }
+}
+
+impl SubCommand for Mktree {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input)?;
+        let input = String::from_utf8_lossy(&input);
+        let separator = if self.nul { '\0' } else { '\n' };
+        let mut lines = input.split(separator).collect::<Vec<_>>();
+        // the last entry may or may not be terminated
+        if lines.last() == Some(&"") {
+            lines.pop();
+        }
+
+        let mut entries = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                if !self.batch {
+                    return Err(GitError::invalid_command("input format error: (blank line only valid in batch mode)".to_string()));
+                }
+                Self::write_tree(&gitdir, std::mem::take(&mut entries))?;
+                continue;
+            }
+            entries.push(self.parse_entry(&gitdir, line)?);
+        }
+        // without --batch an empty input is the empty tree, with it a trailing blank line ends the last tree
+        if !self.batch || !entries.is_empty() {
+            Self::write_tree(&gitdir, entries)?;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_mktree() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        for name in ["a.txt", "dir/b.txt", "weird\tname"] {
+            std::fs::create_dir_all(temp_path.join(name).parent().unwrap()).unwrap();
+            std::fs::write(temp_path.join(name), name).unwrap();
+        }
+        shell_spawn(&["git", "-C", temp_path_str, "add", "."]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "commit", "-m", "init"]).unwrap();
+
+        // (exit code, stdout)
+        let run = |program: &[&str], args: &[&str], stdin: &str| {
+            let mut child = Command::new(program[0])
+                .args([&program[1..], &["-C", temp_path_str, "mktree"], args].concat())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+            let output = child.wait_with_output().unwrap();
+            (output.status.code(), String::from_utf8(output.stdout).unwrap())
+        };
+        let git = |args: &[&str], stdin: &str| run(&["git"], args, stdin);
+        let cargo = |args: &[&str], stdin: &str| run(&["cargo", "run", "--quiet", "--"], args, stdin);
+
+        // ls-tree's output builds the same tree again, in any order
+        let listing = shell_spawn(&["git", "-C", temp_path_str, "ls-tree", "HEAD"]).unwrap();
+        let head_tree = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD^{tree}"]).unwrap();
+        assert_eq!(cargo(&[], &listing), (Some(0), head_tree.clone()));
+        let reversed = listing.lines().rev().map(|line| format!("{}\n", line)).collect::<String>();
+        assert_eq!(cargo(&[], &reversed), (Some(0), head_tree.clone()));
+        let listing_z = shell_spawn(&["git", "-C", temp_path_str, "ls-tree", "-z", "HEAD"]).unwrap();
+        assert_eq!(cargo(&["-z"], &listing_z), (Some(0), head_tree));
+
+        let blob = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD:a.txt"]).unwrap();
+        let tree = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD:dir"]).unwrap();
+        let (blob, tree) = (blob.trim(), tree.trim());
+        let missing = "1111111111111111111111111111111111111111";
+        let inputs: &[(&[&str], String)] = &[
+            (&[], String::new()),
+            (&[], format!("100644 blob {blob}\tdir.txt\n040000 tree {tree}\tdir\n100755 blob {blob}\tdir-x\n120000 blob {blob}\tlink")),
+            (&[], format!("160000 commit {missing}\tsub\n100644 blob {blob}\tdup\n100644 blob {blob}\tdup\n")),
+            (&["--missing"], format!("100644 blob {missing}\tgone\n")),
+            (&["--batch"], format!("100644 blob {blob}\tone\n\n040000 tree {tree}\ttwo\n\n")),
+            (&["--batch"], format!("\n100644 blob {blob}\tone")),
+            // each of these is refused
+            (&[], format!("100644 blob {missing}\tgone\n")),
+            (&[], format!("100644 tree {tree}\tmismatch\n")),
+            (&[], format!("100644 blob {tree}\tnot-a-blob\n")),
+            (&[], format!("100644 blob {blob}\tsub/dir\n")),
+            (&[], format!("100644 blob {blob}\tone\n\n")),
+            (&[], "garbage\n".to_string()),
+        ];
+        for (args, input) in inputs {
+            assert_eq!(cargo(args, input), git(args, input), "{:?} {:?}", args, input);
+        }
+    }
+}
@@ -3,6 +3,11 @@
 pub mod add;
 pub mod branch;
 pub mod checkout;
+pub mod reset;
+pub mod rev_parse;
+pub mod ls_files;
+pub mod show;
+pub mod reflog;
 pub mod commit;
 pub mod fetch;
 pub mod init;
@@ -11,6 +16,33 @@ pub mod pull;
 pub mod push;
 pub mod remote;
 pub mod rm;
+pub mod clean;
+pub mod stash;
+pub mod cherry_pick;
+pub mod revert;
+pub mod archive;
+pub mod describe;
+pub mod show_ref;
+pub mod grep;
+pub mod mv;
+pub mod status;
+pub mod diff;
+pub mod tag;
+pub mod rebase;
+pub mod clone;
+pub mod blame;
+pub mod shortlog;
+pub mod notes;
+pub mod worktree;
+pub mod bisect;
+pub mod bundle;
+pub mod submodule;
+pub mod rev_list;
+pub mod mktree;
+pub mod unpack_objects;
+pub mod pack_objects;
+pub mod ls_tree;
+pub mod gc;
 
 /// plumbing command
 /// used internaly by git
@@ -23,6 +55,11 @@ pub mod read_tree;
 pub mod write_tree;
 pub mod commit_tree;
 pub mod update_ref;
+pub mod merge_base;
+pub mod symbolic_ref;
+pub mod ls_remote;
+pub mod fsck;
+pub mod count_objects;
 
 
 pub use init::Init;
@@ -41,18 +78,53 @@ pub use read_tree::ReadTree;
 pub use write_tree::WriteTree;
 pub use commit_tree::CommitTree;
 pub use update_ref::UpdateRef;
+pub use merge_base::MergeBase;
+pub use symbolic_ref::SymbolicRef;
 pub use branch::Branch;
 pub use checkout::Checkout;
+pub use reset::Reset;
+pub use rev_parse::RevParse;
+pub use ls_files::LsFiles;
+pub use show::Show;
+pub use reflog::Reflog;
+pub use ls_remote::LsRemote;
+pub use fsck::Fsck;
+pub use clean::Clean;
+pub use stash::Stash;
+pub use cherry_pick::CherryPick;
+pub use revert::Revert;
+pub use archive::Archive;
+pub use describe::Describe;
+pub use show_ref::ShowRef;
+pub use grep::Grep;
+pub use mv::Mv;
+pub use status::Status;
+pub use diff::Diff;
+pub use tag::Tag;
+pub use rebase::Rebase;
+pub use clone::Clone;
+pub use blame::Blame;
+pub use shortlog::Shortlog;
+pub use notes::Notes;
+pub use worktree::Worktree;
+pub use bisect::Bisect;
+pub use bundle::Bundle;
+pub use submodule::Submodule;
+pub use rev_list::RevList;
+pub use mktree::Mktree;
+pub use unpack_objects::UnpackObjects;
+pub use pack_objects::PackObjects;
+pub use ls_tree::LsTree;
+pub use gc::Gc;
+pub use count_objects::CountObjects;
 
 
 #[allow(unused)]
 use crate::{Result, GitError};
-use std::{
-    fmt::Debug,
-    path::PathBuf,
-};
+use std::fmt::Debug;
+use crate::utils::fs::Repository;
 
 pub trait SubCommand: Debug {
-    fn run(&self, git_dir: Result<PathBuf>) -> Result<i32>;
+    fn run(&self, repo: Result<Repository>) -> Result<i32>;
 }
 
@@ -0,0 +1,270 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        fs::calc_relative_path,
+        index::{Index, LockedIndexWriter},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "mv", about = "移动或重命名文件、目录，并更新索引")]
+pub struct Mv {
+    #[arg(short = 'f', long, help = "move even if the destination exists")]
+    force: bool,
+
+    #[arg(short = 'n', long = "dry-run", help = "only show what would be renamed")]
+    dry_run: bool,
+
+    #[arg(short = 'k', help = "skip the sources which can't be moved instead of failing")]
+    skip_errors: bool,
+
+    #[arg(required = true, num_args = 1.., value_name = "source", help = "tracked files or directories to move")]
+    sources: Vec<PathBuf>,
+
+    #[arg(required = true, value_name = "destination", help = "the new name, or an existing directory to move into")]
+    destination: PathBuf,
+}
+
+impl Mv {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Mv::try_parse_from(args)?))
+    }
+
+    /// `path` relative to the work tree, it need not exist yet:
+    /// the deepest existing ancestor is checked by `calc_relative_path`, the rest must be plain names
+    fn dest_relative_path(repo: &Repository, path: &Path) -> Result<PathBuf> {
+        let full = repo.resolve(path);
+        let existing = full.ancestors()
+            .find(|dir| fs::symlink_metadata(dir).is_ok())
+            .ok_or_else(|| GitError::not_a_repofile(path))?;
+        let rest = full.strip_prefix(existing).unwrap_or(Path::new(""));
+        if rest.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(GitError::not_a_repofile(path));
+        }
+        let base = calc_relative_path(repo.work_tree()?, existing)?;
+        if rest.as_os_str().is_empty() {
+            return Ok(strip_dot(base));
+        }
+        Ok(strip_dot(base.join(rest)))
+    }
+
+    /// every (source, destination) pair relative to the work tree, checked before anything moves
+    fn plan(&self, repo: &Repository, index: &Index) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let root = repo.work_tree()?;
+        let dest = Self::dest_relative_path(repo, &self.destination)?;
+        let into_dir = root.join(&dest).is_dir();
+        if self.sources.len() > 1 && !into_dir {
+            return Err(GitError::invalid_command(format!("destination '{}' is not a directory", self.destination.display())));
+        }
+
+        let mut plan: Vec<(PathBuf, PathBuf)> = Vec::new();
+        for source in &self.sources {
+            match self.plan_source(repo, index, source, &dest, into_dir, &plan) {
+                Ok(pair) => plan.push(pair),
+                // like git -k, the source is left where it is
+                Err(_) if self.skip_errors => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(plan)
+    }
+
+    /// where `source` goes, given the moves already planned before it
+    fn plan_source(&self, repo: &Repository, index: &Index, source: &Path, dest: &Path, into_dir: bool, plan: &[(PathBuf, PathBuf)]) -> Result<(PathBuf, PathBuf)> {
+        let root = repo.work_tree()?;
+        let bad = |why: &str| GitError::invalid_command(format!(
+            "{}, source={}, destination={}", why, source.display(), self.destination.display()
+        ));
+        let src = calc_relative_path(root, repo.resolve(source)).map_err(|_| bad("bad source"))?;
+        if src == Path::new(".") {
+            return Err(bad("bad source"));
+        }
+        let dst = match src.file_name() {
+            Some(name) if into_dir => strip_dot(dest.join(name)),
+            _ => dest.to_path_buf(),
+        };
+        let is_dir = fs::symlink_metadata(root.join(&src)).is_ok_and(|meta| meta.is_dir());
+        let tracked = index.entries.iter()
+            .filter(|entry| entry.name == src || (is_dir && entry.name.starts_with(&src)))
+            .collect::<Vec<_>>();
+
+        if tracked.is_empty() {
+            return Err(bad("not under version control"));
+        }
+        if tracked.iter().any(|entry| entry.stage != 0) {
+            return Err(bad("conflicted"));
+        }
+        if is_dir && dst.starts_with(&src) {
+            return Err(bad("can not move directory into itself"));
+        }
+        if plan.iter().any(|(_, other)| *other == dst) {
+            return Err(bad("multiple sources for the same target"));
+        }
+        if let Ok(meta) = fs::symlink_metadata(root.join(&dst)) {
+            // only a file may be replaced, and only with -f
+            if is_dir || meta.is_dir() || !self.force {
+                return Err(bad("destination exists"));
+            }
+        }
+        else if !self.force && index.entries.iter().any(|entry| entry.name == dst) {
+            return Err(bad("destination exists in the index"));
+        }
+        Ok((src, dst))
+    }
+
+    /// rename everything in `plan`, or nothing: when one rename fails the ones before it are moved back
+    fn rename_all(root: &Path, plan: &[(PathBuf, PathBuf)]) -> Result<()> {
+        for (done, (src, dst)) in plan.iter().enumerate() {
+            let to = root.join(dst);
+            let renamed = match to.parent() {
+                Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::rename(root.join(src), &to)),
+                None => fs::rename(root.join(src), &to),
+            };
+            if let Err(e) = renamed {
+                for (src, dst) in plan[..done].iter().rev() {
+                    let _ = fs::rename(root.join(dst), root.join(src));
+                }
+                return Err(GitError::invalid_command(format!("renaming '{}' failed: {}", src.display(), e)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `./name` is just `name` in the index
+fn strip_dot(path: PathBuf) -> PathBuf {
+    match path.strip_prefix(".") {
+        Ok(rest) => rest.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+impl SubCommand for Mv {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let index_file = repo.gitdir.join("index");
+        let lock = LockedIndexWriter::lock(&index_file)?;
+        let mut index = Index::read_or_default(&repo.gitdir)?;
+
+        let plan = self.plan(&repo, &index)?;
+        for (src, dst) in &plan {
+            println!("Renaming {} to {}", src.display(), dst.display());
+        }
+        if self.dry_run {
+            return Ok(0);
+        }
+
+        Self::rename_all(repo.work_tree()?, &plan)?;
+
+        // content did not change: the entries keep mode and hash, only their names move
+        let replaced = plan.iter().map(|(_, dst)| dst).collect::<Vec<_>>();
+        index.entries.retain(|entry| !replaced.contains(&&entry.name));
+        for entry in index.entries.iter_mut() {
+            if let Some((src, dst)) = plan.iter().find(|(src, _)| entry.name.starts_with(src)) {
+                entry.name = match entry.name.strip_prefix(src) {
+                    Ok(rest) if !rest.as_os_str().is_empty() => dst.join(rest),
+                    _ => dst.clone(),
+                };
+            }
+        }
+        index.entries.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()).then(a.stage.cmp(&b.stage)));
+        lock.commit(&index)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_mv() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let git1 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat());
+        let git2 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2][..], args].concat());
+
+        std::fs::create_dir_all(temp_path1.join("src/inner")).unwrap();
+        std::fs::create_dir_all(temp_path1.join("docs")).unwrap();
+        std::fs::write(temp_path1.join("run.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(temp_path1.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(temp_path1.join("a.txt"), "a\n").unwrap();
+        std::fs::write(temp_path1.join("b.txt"), "b\n").unwrap();
+        std::fs::write(temp_path1.join("src/lib.rs"), "lib\n").unwrap();
+        std::fs::write(temp_path1.join("src/inner/mod.rs"), "mod\n").unwrap();
+        std::fs::write(temp_path1.join("docs/README"), "readme\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "init"]).unwrap();
+        std::fs::write(temp_path1.join("untracked"), "untracked\n").unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        // nothing is touched when one of the sources can't move
+        let stage = git2(&["ls-files", "--stage"]).unwrap();
+        assert!(cargo(&["mv", "untracked", "moved"]).is_err());
+        assert!(cargo(&["mv", "a.txt", "b.txt"]).is_err());
+        assert!(cargo(&["mv", "a.txt", "untracked", "docs"]).is_err());
+        assert!(cargo(&["mv", "a.txt", "b.txt", "c.txt"]).is_err());
+        assert!(cargo(&["mv", "src", "src/inner/deeper"]).is_err());
+        assert!(cargo(&["mv", "a.txt", "../outside"]).is_err());
+        cargo(&["mv", "-n", "src", "lib"]).unwrap();
+        assert_eq!(git2(&["ls-files", "--stage"]).unwrap(), stage);
+        assert!(temp_path2.join("a.txt").exists() && temp_path2.join("src").exists());
+
+        // a rename that fails on disk leaves the index as it was
+        std::fs::create_dir_all(temp_path2.join("blocked")).unwrap();
+        std::fs::write(temp_path2.join("blocked/inner"), "in the way\n").unwrap();
+        assert!(cargo(&["mv", "a.txt", "blocked/inner/a.txt"]).is_err());
+        assert_eq!(git2(&["ls-files", "--stage"]).unwrap(), stage);
+        assert!(temp_path2.join("a.txt").exists());
+        std::fs::remove_dir_all(temp_path2.join("blocked")).unwrap();
+
+        // -k skips what can't move and moves the rest
+        for args in [&["mv", "-k", "untracked", "a.txt", "missing", "docs"][..], &["mv", "-k", "docs/a.txt", "b.txt"], &["mv", "-k", "docs/a.txt", "a.txt"]] {
+            git1(args).unwrap();
+            cargo(args).unwrap();
+            assert_eq!(git1(&["ls-files", "--stage"]).unwrap(), git2(&["ls-files", "--stage"]).unwrap(), "{:?}", args);
+        }
+        assert!(temp_path2.join("untracked").exists() && temp_path2.join("a.txt").exists());
+
+        // a file keeps its mode, a directory moves with every entry below it, -f replaces a file
+        let moves: &[&[&str]] = &[
+            &["mv", "run.sh", "start.sh"],
+            &["mv", "src", "lib"],
+            &["mv", "a.txt", "lib/inner/mod.rs", "docs"],
+            &["mv", "-f", "b.txt", "docs/README"],
+        ];
+        for args in moves {
+            git1(args).unwrap();
+            cargo(args).unwrap();
+            assert_eq!(git1(&["ls-files", "--stage"]).unwrap(), git2(&["ls-files", "--stage"]).unwrap(), "{:?}", args);
+            assert_eq!(git1(&["status", "--porcelain"]).unwrap(), git2(&["status", "--porcelain"]).unwrap(), "{:?}", args);
+        }
+        assert_eq!(std::fs::read_to_string(temp_path2.join("docs/README")).unwrap(), "b\n");
+        assert!(temp_path2.join("untracked").exists());
+
+        // unlike git the parents of a new location are created
+        cargo(&["mv", "lib", "deep/er/lib"]).unwrap();
+        assert_eq!(git2(&["ls-files"]).unwrap(), "deep/er/lib/lib.rs\ndocs/README\ndocs/a.txt\ndocs/mod.rs\nstart.sh\n");
+        assert_eq!(std::fs::read_to_string(temp_path2.join("deep/er/lib/lib.rs")).unwrap(), "lib\n");
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    Result,
+    utils::{
+        blob::Blob,
+        commit::Commit,
+        diff::flatten_tree,
+        fs::{read_object, write_object},
+        hash::ObjectId,
+        ident,
+        refs::{read_ref_commit, ref_exists, write_ref_commit_with_log},
+        revparse::rev_parse,
+        tree::{FileMode, Tree, TreeEntry},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// where notes live unless told otherwise, like git's core.notesRef default
+const NOTES_REF: &str = "refs/notes/commits";
+
+#[derive(Parser, Debug)]
+#[command(name = "notes", about = "Add or inspect object notes")]
+pub struct Notes {
+    #[command(subcommand)]
+    command: Option<NotesCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum NotesCommand {
+    /// attach a note to an object, HEAD by default
+    Add {
+        /// replace a note the object already has
+        #[arg(short, long)]
+        force: bool,
+        /// the note, several are joined as paragraphs
+        #[arg(short, long = "message", required = true)]
+        message: Vec<String>,
+        object: Option<String>,
+    },
+    /// print the note of an object, HEAD by default
+    Show {
+        object: Option<String>,
+    },
+    /// list `<note> <object>` for every note, or the note blob of one object (default)
+    List {
+        object: Option<String>,
+    },
+}
+
+impl Notes {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Notes::try_parse_from(args)?))
+    }
+
+    /// object -> note blob; notes trees may fan out into `ab/cdef...` directories once they grow
+    pub(crate) fn read_all(gitdir: &Path) -> Result<BTreeMap<ObjectId, ObjectId>> {
+        if !ref_exists(gitdir, NOTES_REF) {
+            return Ok(BTreeMap::new());
+        }
+        let commit = read_object::<Commit>(gitdir.to_path_buf(), &read_ref_commit(gitdir, NOTES_REF)?)?;
+        Ok(flatten_tree(gitdir, &commit.tree_hash)?
+            .into_iter()
+            .filter_map(|(path, (_, hash))| Some((ObjectId::from_hex(&path.to_string_lossy().replace('/', "")).ok()?, hash)))
+            .collect())
+    }
+
+    /// the note attached to `object`, None when it has none
+    pub(crate) fn read(gitdir: &Path, object: &ObjectId) -> Result<Option<String>> {
+        match Self::read_all(gitdir)?.get(object) {
+            Some(blob) => {
+                let note: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), blob)?.into();
+                Ok(Some(String::from_utf8_lossy(&note).to_string()))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// a new notes commit on top of the old one, its tree flat with one blob per object
+    fn write_all(gitdir: &Path, notes: &BTreeMap<ObjectId, ObjectId>, message: &str) -> Result<()> {
+        let tree = Tree(notes.iter()
+            .map(|(object, blob)| TreeEntry { mode: FileMode::Blob, hash: *blob, path: PathBuf::from(object.to_hex()) })
+            .collect());
+        let tree_hash = write_object::<Tree>(gitdir.to_path_buf(), tree.into())?;
+        let parents = match ref_exists(gitdir, NOTES_REF) {
+            true => vec![read_ref_commit(gitdir, NOTES_REF)?],
+            false => Vec::new(),
+        };
+        let commit = Commit::new(tree_hash, parents, ident::author(gitdir)?, ident::committer(gitdir)?, message);
+        let commit_hash = write_object::<Commit>(gitdir.to_path_buf(), commit.into())?;
+        write_ref_commit_with_log(gitdir, NOTES_REF, &commit_hash, &format!("notes: {}", message))
+    }
+
+    /// each -m is a paragraph, trailing blanks and empty lines around it dropped
+    fn clean_message(messages: &[String]) -> String {
+        let paragraphs = messages.iter()
+            .map(|message| message.lines().map(str::trim_end).collect::<Vec<_>>().join("\n").trim_matches('\n').to_string())
+            .filter(|paragraph| !paragraph.is_empty())
+            .collect::<Vec<_>>();
+        match paragraphs.is_empty() {
+            true => String::new(),
+            false => paragraphs.join("\n\n") + "\n",
+        }
+    }
+
+    fn add(gitdir: &Path, force: bool, messages: &[String], object: &ObjectId) -> Result<i32> {
+        let mut notes = Self::read_all(gitdir)?;
+        let exists = notes.contains_key(object);
+        if exists && !force {
+            eprintln!("error: Cannot add notes. Found existing notes for object {}. Use '-f' to overwrite existing notes", object);
+            return Ok(1);
+        }
+        let note = Self::clean_message(messages);
+        // an empty note removes the old one, like git
+        if note.is_empty() {
+            if exists {
+                eprintln!("Removing note for object {}", object);
+                notes.remove(object);
+                Self::write_all(gitdir, &notes, "Notes removed by 'git notes add'")?;
+            }
+            return Ok(0);
+        }
+        if exists {
+            eprintln!("Overwriting existing notes for object {}", object);
+        }
+        let blob = write_object::<Blob>(gitdir.to_path_buf(), note.into_bytes())?;
+        notes.insert(*object, blob);
+        Self::write_all(gitdir, &notes, "Notes added by 'git notes add'")?;
+        Ok(0)
+    }
+}
+
+impl SubCommand for Notes {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let object = |object: &Option<String>| rev_parse(&gitdir, object.as_deref().unwrap_or("HEAD"));
+        let no_note = |object: &ObjectId| {
+            eprintln!("error: no note found for object {}.", object);
+            Ok(1)
+        };
+        match &self.command {
+            Some(NotesCommand::Add { force, message, object: name }) => Self::add(&gitdir, *force, message, &object(name)?),
+            Some(NotesCommand::Show { object: name }) => {
+                let object = object(name)?;
+                match Self::read(&gitdir, &object)? {
+                    Some(note) => print!("{}", note),
+                    None => return no_note(&object),
+                }
+                Ok(0)
+            },
+            Some(NotesCommand::List { object: Some(name) }) => {
+                let object = rev_parse(&gitdir, name)?;
+                match Self::read_all(&gitdir)?.get(&object) {
+                    Some(blob) => println!("{}", blob),
+                    None => return no_note(&object),
+                }
+                Ok(0)
+            },
+            Some(NotesCommand::List { object: None }) | None => {
+                for (object, blob) in Self::read_all(&gitdir)? {
+                    println!("{} {}", blob, object);
+                }
+                Ok(0)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{shell_spawn, setup_test_git_dir};
+
+    #[test]
+    fn test_notes() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        git(&["commit", "--allow-empty", "-m", "one"]).unwrap();
+        git(&["commit", "--allow-empty", "-m", "two"]).unwrap();
+        assert_eq!(cargo(&["notes"]).unwrap(), "");
+        cargo(&["notes", "add", "-m", "first note  ", "-m", "", "-m", "second\nparagraph\n\n"]).unwrap();
+        cargo(&["notes", "add", "-m", "on one", "HEAD~1"]).unwrap();
+
+        // git reads what we wrote
+        assert_eq!(git(&["notes", "show"]).unwrap(), "first note\n\nsecond\nparagraph\n");
+        assert_eq!(git(&["notes", "show", "HEAD~1"]).unwrap(), "on one\n");
+        assert_eq!(cargo(&["notes", "list"]).unwrap(), git(&["notes", "list"]).unwrap());
+        assert_eq!(cargo(&["notes", "list", "HEAD~1"]).unwrap(), git(&["notes", "list", "HEAD~1"]).unwrap());
+        assert_eq!(git(&["log", "-1", "--format=%s", "refs/notes/commits"]).unwrap(), "Notes added by 'git notes add'\n");
+        assert_eq!(git(&["rev-list", "--count", "refs/notes/commits"]).unwrap(), "2\n");
+        git(&["fsck", "--full"]).unwrap();
+
+        // an existing note needs -f, an empty one removes it
+        assert!(cargo(&["notes", "add", "-m", "again"]).is_err());
+        cargo(&["notes", "add", "-f", "-m", "replaced"]).unwrap();
+        assert_eq!(git(&["notes", "show"]).unwrap(), "replaced\n");
+        cargo(&["notes", "add", "-f", "-m", "", "HEAD~1"]).unwrap();
+        assert!(git(&["notes", "show", "HEAD~1"]).is_err());
+        assert!(cargo(&["notes", "show", "HEAD~1"]).is_err());
+        assert!(cargo(&["notes", "list", "HEAD~1"]).is_err());
+
+        // notes written by git, fanned out or not, and shown by show
+        git(&["notes", "add", "-m", "from git", "HEAD~1"]).unwrap();
+        assert_eq!(cargo(&["notes", "show", "HEAD~1"]).unwrap(), "from git\n");
+        for rev in ["HEAD", "HEAD~1"] {
+            assert_eq!(cargo(&["show", rev]).unwrap(), git(&["show", rev]).unwrap(), "{}", rev);
+        }
+    }
+}
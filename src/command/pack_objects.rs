@@ -0,0 +1,166 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        fs::object_exists,
+        hash::is_hash,
+        packfile::build_pack,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "pack-objects", about = "Create a packed archive of objects")]
+pub struct PackObjects {
+    #[arg(long, help = "write the pack to stdout instead of <base-name>-<hash>.pack")]
+    stdout: bool,
+
+    #[arg(long, value_name = "n", default_value_t = 10, help = "how many objects each one is tried as a delta against, 0 for no deltas")]
+    window: usize,
+
+    #[arg(long, value_name = "n", default_value_t = 50, help = "the longest chain of deltas")]
+    depth: usize,
+
+    #[arg(short = 'q', help = "don't report the totals on stderr")]
+    quiet: bool,
+
+    #[arg(required_unless_present = "stdout", conflicts_with = "stdout",
+        help = "the pack and its index go to <base-name>-<hash>.pack and .idx, e.g. .git/objects/pack/pack")]
+    base_name: Option<PathBuf>,
+}
+
+impl PackObjects {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(PackObjects::try_parse_from(args)?))
+    }
+}
+
+impl SubCommand for PackObjects {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        // one object per line, `rev-list --objects` adds the path after a space
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        let mut objects: Vec<(String, String)> = Vec::new();
+        for line in input.lines().filter(|line| !line.is_empty()) {
+            let (hash, name) = line.split_once(' ').unwrap_or((line, ""));
+            if !is_hash(hash) {
+                return Err(GitError::invalid_argument(format!("expected object ID, got garbage:\n {}", line)));
+            }
+            if !object_exists(gitdir, hash) {
+                return Err(GitError::missing_object(hash));
+            }
+            // the same object twice is packed once
+            if !objects.iter().any(|(seen, _)| seen == hash) {
+                objects.push((hash.to_string(), name.to_string()));
+            }
+        }
+
+        let pack = build_pack(gitdir, &objects, self.window, self.depth)?;
+        match &self.base_name {
+            Some(base) => {
+                pack.write_to(&repo.resolve(base))?;
+                println!("{}", pack.checksum());
+            },
+            None => io::stdout().write_all(&pack.data)?,
+        }
+        if !self.quiet {
+            eprintln!("Total {} (delta {}), reused 0 (delta 0), pack-reused 0", objects.len(), pack.deltas);
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        process::{Command, Output, Stdio},
+    };
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    /// our pack-objects fed with `input`
+    fn pack_objects(dir: &str, args: &[&str], input: &[u8]) -> Output {
+        let mut child = Command::new("cargo")
+            .args([&["run", "--quiet", "--", "-C", dir, "pack-objects"][..], args].concat())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// what `git verify-pack -v` says about a pack we wrote to stdout, after git indexed it
+    fn verify(dir: &str, data: &[u8]) -> String {
+        let path = format!("{}/test.pack", dir);
+        std::fs::write(&path, data).unwrap();
+        shell_spawn(&["git", "index-pack", &path]).unwrap();
+        shell_spawn(&["git", "verify-pack", "-v", &path]).unwrap()
+    }
+
+    #[test]
+    fn test_pack_objects() {
+        let source = setup_test_git_dir();
+        let source_path = source.path();
+        let source_str = source_path.to_str().unwrap();
+        for (i, name) in ["a.txt", "dir/b.txt", "dir/c.txt"].iter().enumerate() {
+            std::fs::create_dir_all(source_path.join(name).parent().unwrap()).unwrap();
+            // similar contents, so there is something to delta against
+            let text = (0..200).map(|n| format!("line {}\n", n + i)).collect::<String>();
+            std::fs::write(source_path.join(name), text).unwrap();
+            shell_spawn(&["git", "-C", source_str, "add", "."]).unwrap();
+            shell_spawn(&["git", "-C", source_str, "commit", "-m", name]).unwrap();
+        }
+        shell_spawn(&["git", "-C", source_str, "tag", "-a", "v1", "-m", "v1"]).unwrap();
+        let objects = Command::new("git").args(["-C", source_str, "rev-list", "--objects", "--all"]).output().unwrap().stdout;
+        let count = String::from_utf8_lossy(&objects).lines().count();
+
+        // git reads every object back, some of them as deltas
+        let output = pack_objects(source_str, &["--stdout"], &objects);
+        assert!(output.status.success(), "{:?}", output);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with(&format!("Total {} (delta ", count)), "{}", stderr);
+        assert!(!stderr.starts_with(&format!("Total {} (delta 0)", count)), "{}", stderr);
+        let report = verify(source_str, &output.stdout);
+        assert!(report.contains("chain length = 1:"), "{}", report);
+
+        // no window, no deltas
+        let output = pack_objects(source_str, &["--stdout", "-q", "--window=0"], &objects);
+        assert!(output.status.success() && output.stderr.is_empty(), "{:?}", output);
+        let report = verify(source_str, &output.stdout);
+        assert!(report.contains("non delta: "), "{}", report);
+        assert!(!report.contains("chain length"), "{}", report);
+
+        // the pack and its index go next to the base name, named after the checksum
+        let target = setup_test_git_dir();
+        let target_str = target.path().to_str().unwrap();
+        let base = format!("{}/.git/objects/pack/pack", target_str);
+        let output = pack_objects(source_str, &["-q", &base], &objects);
+        assert!(output.status.success(), "{:?}", output);
+        let name = String::from_utf8(output.stdout).unwrap();
+        let idx = format!("{}-{}.idx", base, name.trim());
+        shell_spawn(&["git", "verify-pack", &idx]).unwrap();
+        // the target has no refs yet, so check everything from the tag down
+        let tag = shell_spawn(&["git", "-C", source_str, "rev-parse", "v1"]).unwrap();
+        shell_spawn(&["git", "-C", target_str, "fsck", "--no-dangling", tag.trim()]).unwrap();
+        let head = shell_spawn(&["git", "-C", source_str, "rev-parse", "HEAD"]).unwrap();
+        assert_eq!(shell_spawn(&["git", "-C", target_str, "rev-parse", &format!("{}^{{commit}}", tag.trim())]).unwrap(), head);
+
+        // garbage and missing objects are refused
+        assert!(!pack_objects(source_str, &["--stdout"], b"nonsense\n").status.success());
+        assert!(!pack_objects(source_str, &["--stdout"], format!("{}\n", "1".repeat(40)).as_bytes()).status.success());
+    }
+}
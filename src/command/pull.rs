@@ -1,17 +1,28 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use clap::Parser;
 use crate::{GitError, Result};
-use crate::utils::refs::{read_head_ref, head_to_hash};
-use super::{SubCommand, Fetch, Merge, Checkout, ReadTree};
+use crate::utils::{
+    config::{Config, Upstream},
+    refs::{head_to_hash, current_branch, read_ref_commit, ref_file, write_ref_commit, write_ref_commit_with_log},
+    fs::{read_object, write_object},
+    commit::Commit,
+    tree::Tree,
+    index::{Index, LockedIndexWriter},
+    ident,
+    hash::{algorithm, ObjectId},
+    revwalk,
+};
+use super::{SubCommand, Fetch, Merge, Checkout, ReadTree, Rebase, WriteTree};
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
 
 #[derive(Parser, Debug)]
 #[command(name = "pull", about = "从远程仓库拉取并合并到当前分支")]
 pub struct Pull {
-    /// 远程仓库名称（默认为origin）
-    #[arg(default_value = "origin")]
-    remote: String,
+    /// 远程仓库名称（默认为当前分支的上游所在的远程）
+    remote: Option<String>,
     
-    /// 远程分支名称（可选，默认为当前分支对应的远程分支）
+    /// 远程分支名称（可选，默认为当前分支的上游分支）
     branch: Option<String>,
     
     /// 特定的引用规范（可选）
@@ -32,71 +43,88 @@ impl Pull {
     }
     
     /// 获取当前分支名称
-    fn get_current_branch(&self, gitdir: &PathBuf) -> Result<String> {
-        let head_ref = read_head_ref(gitdir)?;
-        if head_ref.starts_with("refs/heads/") {
-            Ok(head_ref.strip_prefix("refs/heads/").unwrap().to_string())
-        } else {
-            Err(GitError::invalid_command("Not on a branch (detached HEAD)".to_string()))
-        }
+    fn get_current_branch(&self, gitdir: &Path) -> Result<String> {
+        current_branch(gitdir)
     }
     
-    /// 获取要拉取的远程分支名称
-    fn get_remote_branch(&self, gitdir: &PathBuf) -> Result<String> {
-        if let Some(ref branch) = self.branch {
-            Ok(branch.clone())
-        } else {
-            // 使用当前分支对应的远程分支
-            let current_branch = self.get_current_branch(gitdir)?;
-            Ok(current_branch)
-        }
+    /// 要拉取的远程和分支：命令行给了就用命令行的，否则看 branch.<name>.remote / merge
+    /// 只给了远程、又不是上游所在的远程时，沿用同名分支
+    fn get_upstream(&self, gitdir: &Path, config: &Config) -> Result<Upstream> {
+        let current_branch = self.get_current_branch(gitdir)?;
+        let upstream = config.upstream(&current_branch);
+        let (remote, branch) = match (&self.remote, &self.branch, upstream) {
+            (Some(remote), Some(branch), _) => (remote.clone(), branch.clone()),
+            (Some(remote), None, Some(upstream)) if upstream.remote == *remote => return Ok(upstream),
+            (Some(remote), None, _) => (remote.clone(), current_branch),
+            (None, _, Some(upstream)) => return Ok(upstream),
+            (None, _, None) => return Err(GitError::invalid_command(format!(
+                "There is no tracking information for the current branch.\n\
+                Please specify which branch you want to merge with.\n\n    \
+                git pull <remote> <branch>\n\n\
+                If you wish to set tracking information for this branch you can do so with:\n\n    \
+                git branch --set-upstream-to=origin/<branch> {}\n",
+                current_branch
+            ))),
+        };
+        Ok(Upstream { remote, merge: format!("refs/heads/{}", branch) })
     }
     
     /// 检查远程分支是否存在
-    fn check_remote_branch_exists(&self, gitdir: &PathBuf, remote_branch: &str) -> Result<bool> {
-        let remote_ref_path = gitdir
-            .join("refs")
-            .join("remotes")
-            .join(&self.remote)
-            .join(remote_branch);
-        Ok(remote_ref_path.exists())
+    fn check_remote_branch_exists(&self, gitdir: &Path, tracking_ref: &str) -> Result<bool> {
+        Ok(gitdir.join(tracking_ref).is_file())
     }
 }
 
 impl SubCommand for Pull {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        
-        println!("Pulling from {}", self.remote);
-        
-        // 步骤1: 先执行 fetch
-        if self.verbose {
-            println!("Step 1: Fetching from remote...");
-        }
-        
-        // 构造 fetch 命令参数
-        let mut fetch_args = vec!["fetch".to_string(), self.remote.clone()];
-        fetch_args.extend(self.refspecs.clone());
-        if self.verbose {
-            fetch_args.push("-v".to_string());
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let config = Config::read_all(&gitdir)?;
+        let upstream = self.get_upstream(&gitdir, &config)?;
+        
+        // like git, check before fetching: a rebase would mix local changes into the replayed commits
+        if self.rebase && let Ok(head) = head_to_hash(&gitdir) {
+            Rebase::check_clean(&gitdir, &head, "pull with rebase")?;
         }
+
+        println!("Pulling from {}", upstream.remote);
         
-        let fetch_cmd = Fetch::from_args(fetch_args.into_iter())?;
-        let fetch_result = fetch_cmd.run(Ok(gitdir.clone()))?;
-        if fetch_result != 0 {
-            return Err(GitError::invalid_command("Fetch failed".to_string()));
+        // 步骤1: 先执行 fetch，上游是本地分支（remote = .）时不用 fetch
+        if upstream.remote != "." {
+            if self.verbose {
+                println!("Step 1: Fetching from remote...");
+            }
+
+            // 构造 fetch 命令参数
+            let mut fetch_args = vec!["fetch".to_string(), upstream.remote.clone()];
+            fetch_args.extend(self.refspecs.clone());
+            if self.verbose {
+                fetch_args.push("-v".to_string());
+            }
+
+            let fetch_cmd = Fetch::from_args(fetch_args.into_iter())?;
+            let fetch_result = fetch_cmd.run(Ok(Repository::at(gitdir.clone())))?;
+            if fetch_result != 0 {
+                return Err(GitError::invalid_command("Fetch failed".to_string()));
+            }
         }
         
-        // 步骤2: 确定要合并的分支
-        let remote_branch = self.get_remote_branch(&gitdir)?;
-        let remote_ref_name = format!("{}/{}", self.remote, remote_branch);
+        // 步骤2: 确定要合并的分支，远程分支按 fetch 的 refspec 找到对应的远程跟踪分支
+        let remote_branch = upstream.branch().to_string();
+        let remote_ref_path = config.tracking_ref(&upstream)
+            .ok_or_else(|| GitError::invalid_command(format!(
+                "the remote '{}' does not fetch '{}'", upstream.remote, upstream.merge
+            )))?;
+        let remote_ref_name = remote_ref_path.strip_prefix("refs/remotes/")
+            .or_else(|| remote_ref_path.strip_prefix("refs/heads/"))
+            .unwrap_or(&remote_ref_path)
+            .to_string();
         
         if self.verbose {
             println!("Step 2: Checking remote branch {}...", remote_ref_name);
         }
         
         // 检查远程分支是否存在
-        if !self.check_remote_branch_exists(&gitdir, &remote_branch)? {
+        if !self.check_remote_branch_exists(&gitdir, &remote_ref_path)? {
             return Err(GitError::invalid_command(format!(
                 "Remote branch '{}' not found", 
                 remote_ref_name
@@ -121,89 +149,177 @@ impl SubCommand for Pull {
             if self.verbose {
                 println!("Step 3: Creating local branch from remote...");
             }
-            self.create_branch_from_remote(&gitdir, &current_branch, &remote_ref_name)?;
+            self.create_branch_from_remote(&gitdir, &current_branch, &remote_ref_path)?;
         } else {
-            // 本地分支存在，检查是否有本地修改
-            let has_local_changes = self.check_local_changes(&gitdir)?;
-            if has_local_changes {
-                println!("Warning: You have local changes. Please commit or stash them before pulling.");
-            }
-
-            // 执行合并或rebase
+            // 本地分支存在，执行合并或rebase；merge 自己会拒绝被覆盖的本地修改
             if self.rebase {
                 if self.verbose {
                     println!("Step 3: Rebasing onto {}...", remote_ref_name);
                 }
-                // TODO: 实现 rebase 功能
-                println!("Rebase not implemented yet, falling back to merge");
-            }
-            
-            if self.verbose {
-                println!("Step 3: Merging {}...", remote_ref_name);
+                self.rebase_onto(&gitdir, &current_branch, &remote_ref_path)?;
             }
-            
-            // 构造 merge 命令参数 - 使用完整的远程引用路径
-            let remote_ref_path = format!("refs/remotes/{}", remote_ref_name);
-            let merge_args = vec!["merge".to_string(), remote_ref_path];
-            let merge_cmd = Merge::from_args(merge_args.into_iter())?;
-            
-            let merge_result = merge_cmd.run(Ok(gitdir.clone()))?;
-            if merge_result != 0 {
-                return Err(GitError::invalid_command("Merge failed".to_string()));
+            else {
+                if self.verbose {
+                    println!("Step 3: Merging {}...", remote_ref_name);
+                }
+
+                // 构造 merge 命令参数 - 使用完整的远程引用路径
+                let merge_args = vec!["merge".to_string(), remote_ref_path.clone()];
+                let merge_cmd = Merge::from_args(merge_args.into_iter())?;
+
+                let merge_result = merge_cmd.run(Ok(Repository::at(gitdir.clone())))?;
+                if merge_result != 0 {
+                    return Err(GitError::invalid_command("Merge failed".to_string()));
+                }
             }
         }
         
-        println!("Successfully pulled from {}/{}", self.remote, remote_branch);
+        println!("Successfully pulled from {}/{}", upstream.remote, remote_branch);
         
         Ok(0)
     }
 }
 
 impl Pull {
-    /// 简单检查是否有本地修改（这里简化实现）
-    fn check_local_changes(&self, gitdir: &PathBuf) -> Result<bool> {
-        // 检查index文件是否存在且非空
-        let index_path = gitdir.join("index");
-        if index_path.exists() {
-            let metadata = std::fs::metadata(&index_path)?;
-            Ok(metadata.len() > 0)
-        } else {
-            Ok(false)
+    /// 本地独有的提交（从旧到新），沿 first-parent 走到 upstream 已包含的提交为止
+    /// merge commits are dropped like `git rebase` does by default
+    fn local_commits(gitdir: &Path, head: &ObjectId, upstream: &ObjectId) -> Result<Vec<(ObjectId, Commit)>> {
+        let upstream_ancestors = revwalk::ancestors(gitdir, upstream)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut commits = Vec::new();
+        let mut hash = *head;
+        while !upstream_ancestors.contains(&hash) {
+            let commit = read_object::<Commit>(gitdir.to_path_buf(), &hash)?;
+            let parent = commit.parent_hash.first().copied();
+            if commit.parent_hash.len() <= 1 {
+                commits.push((hash, commit));
+            }
+            match parent {
+                Some(parent) => hash = parent,
+                None => break,
+            }
         }
+        commits.reverse();
+        Ok(commits)
     }
-    
+
+    /// replay the local commits of `branch` on top of `upstream_ref`
+    /// on a conflict nothing is moved: index and worktree go back to ORIG_HEAD
+    fn rebase_onto(&self, gitdir: &Path, branch: &str, upstream_ref: &str) -> Result<()> {
+        let head = head_to_hash(gitdir)?;
+        let upstream = read_ref_commit(gitdir, upstream_ref)?;
+        if revwalk::merge_bases(gitdir, &head, &upstream)?.first() == Some(&upstream) {
+            println!("Current branch {} is up to date.", branch);
+            return Ok(());
+        }
+        write_ref_commit(gitdir, "ORIG_HEAD", &head)?;
+
+        // the new commits are built from the index only, the worktree is switched once at the end
+        let mut onto = upstream;
+        for (hash, commit) in Self::local_commits(gitdir, &head, &upstream)? {
+            let parent_tree = match commit.parent_hash.first() {
+                Some(parent) => read_object::<Commit>(gitdir.to_path_buf(), parent)?.tree_hash,
+                None => write_object::<Tree>(gitdir.to_path_buf(), Tree(vec![]).into())?,
+            };
+            let onto_tree = read_object::<Commit>(gitdir.to_path_buf(), &onto)?.tree_hash;
+            let subject = commit.message.lines().next().unwrap_or_default().to_string();
+            let label = format!("{} ({})", &hash.to_hex()[..7], subject);
+
+            // merge_tree starts from the index, so it has to hold the tree being picked onto
+            ReadTree::from_internal(&onto_tree).run(Ok(Repository::at(gitdir)))?;
+            let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+            if let Err(e) = Merge::merge_tree(gitdir.to_path_buf(), lock, &parent_tree, &onto_tree, &commit.tree_hash, &label) {
+                Self::abort_rebase(gitdir, &head)?;
+                return Err(GitError::merge_conflict(format!(
+                    "could not apply {}... {}\n{}\nrebase aborted, {} is back at ORIG_HEAD",
+                    &hash.to_hex()[..7], subject, e, branch
+                )));
+            }
+            let tree_hash = WriteTree::lazy_fucker(gitdir.to_path_buf())?;
+            let picked = Commit {
+                tree_hash,
+                parent_hash: vec![onto],
+                author: commit.author,
+                committer: ident::committer(gitdir)?,
+                // a signature no longer matches the rewritten commit, the encoding still describes the message
+                extra_headers: commit.extra_headers.into_iter().filter(|header| header.starts_with("encoding ")).collect(),
+                message: commit.message,
+            };
+            onto = write_object::<Commit>(gitdir.to_path_buf(), picked.into())?;
+            if self.verbose {
+                println!("Applied {} as {}", &hash.to_hex()[..7], &onto.to_hex()[..7]);
+            }
+        }
+
+        // index 已经被 merge_tree 改写，先回到 HEAD 再整体切换到新的提交
+        Self::reset_index(gitdir, &head)?;
+        Checkout::switch_worktree(gitdir, Some(&head), &onto)?;
+        let message = format!("pull --rebase (finish): refs/heads/{} onto {}", branch, upstream);
+        write_ref_commit_with_log(gitdir, &format!("refs/heads/{}", branch), &onto, &message)?;
+        println!("Successfully rebased and updated refs/heads/{}.", branch);
+        Ok(())
+    }
+
+    /// load the tree of `commit` into the index
+    fn reset_index(gitdir: &Path, commit: &ObjectId) -> Result<()> {
+        let tree_hash = read_object::<Commit>(gitdir.to_path_buf(), commit)?.tree_hash;
+        ReadTree::from_internal(&tree_hash).run(Ok(Repository::at(gitdir)))?;
+        Checkout::refresh_index_stat(gitdir)
+    }
+
+    /// a failed pick leaves conflict markers and merged files behind, put back what `orig_head` had
+    fn abort_rebase(gitdir: &Path, orig_head: &ObjectId) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let orig_tree = read_object::<Commit>(gitdir.to_path_buf(), orig_head)?.tree_hash;
+        let orig_paths = read_object::<Tree>(gitdir.to_path_buf(), &orig_tree)?
+            .flatten(gitdir)?
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect::<HashSet<_>>();
+        let index = Index::new().read_from_file(&gitdir.join("index"))?;
+        for entry in index.entries.iter().filter(|entry| !orig_paths.contains(&entry.name)) {
+            let _ = std::fs::remove_file(project_root.join(&entry.name));
+            remove_empty_parents(project_root, &project_root.join(&entry.name));
+        }
+        Checkout::restore_workspace(gitdir, None, orig_head)?;
+        Self::reset_index(gitdir, orig_head)
+    }
+
     /// 检查当前分支是否存在
-    fn check_current_branch_exists(&self, gitdir: &PathBuf, branch_name: &str) -> Result<bool> {
-        let branch_path = gitdir.join("refs").join("heads").join(branch_name);
+    fn check_current_branch_exists(&self, gitdir: &Path, branch_name: &str) -> Result<bool> {
+        let branch_path = ref_file(gitdir, &format!("refs/heads/{}", branch_name));
         Ok(branch_path.exists())
     }
     
     /// 从远程分支创建本地分支
-    fn create_branch_from_remote(&self, gitdir: &PathBuf, local_branch: &str, remote_ref_name: &str) -> Result<()> {
-        use crate::utils::refs::{read_ref_commit, write_head_ref};
+    fn create_branch_from_remote(&self, gitdir: &Path, local_branch: &str, remote_ref_path: &str) -> Result<()> {
+        use crate::utils::refs::write_head_ref;
         use std::fs;
         
         // 读取远程分支的提交哈希
-        let remote_ref_path = format!("refs/remotes/{}", remote_ref_name);
-        let commit_hash = read_ref_commit(gitdir, &remote_ref_path)?;
+        let commit_hash = read_ref_commit(gitdir, remote_ref_path)?;
         
         if self.verbose {
-            println!("Creating local branch '{}' from commit {}", local_branch, &commit_hash[..8]);
+            println!("Creating local branch '{}' from commit {}", local_branch, &commit_hash.to_hex()[..8]);
         }
         
         // 创建本地分支引用
-        let local_branch_path = gitdir.join("refs").join("heads").join(local_branch);
+        let local_branch_path = ref_file(gitdir, &format!("refs/heads/{}", local_branch));
         if let Some(parent) = local_branch_path.parent() {
             fs::create_dir_all(parent)?;
         }
         fs::write(&local_branch_path, format!("{}\n", commit_hash))?;
         
+        // 记录切换前的提交，用于清理只存在于旧分支的文件
+        let previous_commit = crate::utils::refs::head_to_hash(gitdir).ok();
+
         // 更新 HEAD 指向新分支
         let local_branch_ref = format!("refs/heads/{}", local_branch);
         write_head_ref(gitdir, &local_branch_ref)?;
         
         // 检查并创建 index 文件，然后导入远程 tree
-        self.setup_index_and_workspace(gitdir, &commit_hash)?;
+        self.setup_index_and_workspace(gitdir, previous_commit.as_ref(), &commit_hash)?;
         
         if self.verbose {
             println!("Successfully created and switched to branch '{}'", local_branch);
@@ -213,8 +329,7 @@ impl Pull {
     }
     
     /// 设置 index 和工作区
-    fn setup_index_and_workspace(&self, gitdir: &PathBuf, commit_hash: &str) -> Result<()> {
-        use crate::utils::index::Index;
+    fn setup_index_and_workspace(&self, gitdir: &Path, previous_commit: Option<&ObjectId>, commit_hash: &ObjectId) -> Result<()> {
         
         let index_path = gitdir.join("index");
         
@@ -224,18 +339,17 @@ impl Pull {
                 println!("Creating index file...");
             }
             // 创建空的 index 文件
-            let index = Index::new();
-            index.write_to_file(&index_path)?;
+            LockedIndexWriter::lock(&index_path)?.commit(&Index::new())?;
         }
         
         // 从提交中获取 tree 并导入到 index 和工作区
-        self.import_tree_to_index_and_workspace(gitdir, commit_hash)?;
+        self.import_tree_to_index_and_workspace(gitdir, previous_commit, commit_hash)?;
         
         Ok(())
     }
     
     /// 将 tree 导入到 index 和工作区
-    fn import_tree_to_index_and_workspace(&self, gitdir: &PathBuf, commit_hash: &str) -> Result<()> {
+    fn import_tree_to_index_and_workspace(&self, gitdir: &Path, previous_commit: Option<&ObjectId>, commit_hash: &ObjectId) -> Result<()> {
         use crate::command::{Checkout, ReadTree};
         
         if self.verbose {
@@ -243,15 +357,12 @@ impl Pull {
         }
         
         // 恢复工作区到该提交
-        Checkout::restore_workspace(gitdir, commit_hash)?;
+        Checkout::restore_workspace(gitdir, previous_commit, commit_hash)?;
         
         // 更新 index
         let tree_hash = self.get_tree_hash_from_commit(gitdir, commit_hash)?;
-        let read_tree = ReadTree {
-            prefix: None,
-            tree_hash,
-        };
-        read_tree.run(Ok(gitdir.clone()))?;
+        let read_tree = ReadTree::from_internal(&tree_hash);
+        read_tree.run(Ok(Repository::at(gitdir)))?;
         
         if self.verbose {
             println!("Successfully imported tree to index and workspace");
@@ -261,7 +372,7 @@ impl Pull {
     }
     
     /// 从提交中获取 tree 哈希
-    fn get_tree_hash_from_commit(&self, gitdir: &PathBuf, commit_hash: &str) -> Result<String> {
+    fn get_tree_hash_from_commit(&self, gitdir: &Path, commit_hash: &ObjectId) -> Result<ObjectId> {
         use crate::utils::zlib::decompress_file_bytes;
         use crate::utils::fs::obj_to_pathbuf;
         
@@ -275,9 +386,10 @@ impl Pull {
         if let Some(tree_start) = content.find("tree ") {
             let tree_line = &content[tree_start..];
             if let Some(tree_hash) = tree_line.strip_prefix("tree ") {
-                // 提取 tree 哈希（40个字符）
-                if tree_hash.len() >= 40 {
-                    return Ok(tree_hash[..40].to_string());
+                // 提取 tree 哈希
+                let hex_len = algorithm().hex_len();
+                if tree_hash.len() >= hex_len {
+                    return ObjectId::from_hex(&tree_hash[..hex_len]);
                 }
             }
         }
@@ -288,10 +400,103 @@ impl Pull {
                 println!("DEBUG: Commit {} line: {}", commit_hash, line);
             }
             if let Some(tree_hash) = line.strip_prefix("tree ") {
-                return Ok(tree_hash.to_string());
+                return ObjectId::from_hex(tree_hash);
             }
         }
         
         Err(GitError::invalid_command(format!("No tree found in commit {}", commit_hash)))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        tempdir,
+        cp_dir,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_pull_rebase() {
+        let upstream = setup_test_git_dir();
+        let upstream_path = upstream.path();
+        let upstream_str = upstream_path.to_str().unwrap();
+        std::fs::write(upstream_path.join("shared.txt"), "base\n").unwrap();
+        let git_up = &["git", "-C", upstream_str];
+        cmd_seq(&[(&["add", "shared.txt"], false), (&["commit", "-m", "base"], false)])(git_up).unwrap();
+
+        let local = tempdir().unwrap();
+        let local_path = local.path();
+        let local_str = local_path.to_str().unwrap();
+        let _ = cp_dir(upstream_path, local_path).unwrap();
+        let remote_url = upstream_path.join(".git");
+        let git_local = &["git", "-C", local_str];
+        shell_spawn(&[&git_local[..], &["remote", "add", "origin", remote_url.to_str().unwrap()]].concat()).unwrap();
+
+        // both sides move on with different files
+        std::fs::write(upstream_path.join("remote.txt"), "remote\n").unwrap();
+        cmd_seq(&[(&["add", "remote.txt"], false), (&["commit", "-m", "remote work"], false)])(git_up).unwrap();
+        std::fs::write(local_path.join("local.txt"), "local\n").unwrap();
+        let local_cmds: ArgsList = &[(&["add", "local.txt"], false), (&["commit", "-m", "local work\n\nwith a body"], false)];
+        cmd_seq(local_cmds)(git_local).unwrap();
+        let rev = |rev: &str| shell_spawn(&["git", "-C", local_str, "rev-parse", rev]).unwrap().trim().to_string();
+        let orig_head = rev("HEAD");
+
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", local_str, "pull", "--rebase", "origin", "master"];
+        shell_spawn(cargo).unwrap();
+
+        // linear history on top of the remote tip, the author and message are kept
+        let remote_tip = shell_spawn(&["git", "-C", upstream_str, "rev-parse", "HEAD"]).unwrap().trim().to_string();
+        assert_eq!(rev("HEAD~1"), remote_tip);
+        assert_eq!(rev("ORIG_HEAD"), orig_head);
+        let log = |args: &[&str]| shell_spawn(&[&git_local[..], args].concat()).unwrap();
+        assert_eq!(log(&["log", "-1", "--format=%an %ae %at%n%B"]), log(&["log", "-1", "--format=%an %ae %at%n%B", &orig_head]));
+        assert_eq!(log(&["status", "--porcelain"]), "");
+        assert_eq!(std::fs::read_to_string(local_path.join("remote.txt")).unwrap(), "remote\n");
+        assert_eq!(std::fs::read_to_string(local_path.join("local.txt")).unwrap(), "local\n");
+
+        // a conflicting pick gives up and leaves everything as it was
+        std::fs::write(upstream_path.join("shared.txt"), "from remote\n").unwrap();
+        cmd_seq(&[(&["commit", "-am", "remote edit"], false)])(git_up).unwrap();
+        std::fs::write(local_path.join("shared.txt"), "from local\n").unwrap();
+        cmd_seq(&[(&["commit", "-am", "local edit"], false)])(git_local).unwrap();
+        let before = rev("HEAD");
+
+        assert!(shell_spawn(cargo).is_err());
+        assert_eq!(rev("HEAD"), before);
+        assert_eq!(rev("ORIG_HEAD"), before);
+        assert_eq!(std::fs::read_to_string(local_path.join("shared.txt")).unwrap(), "from local\n");
+        assert_eq!(log(&["status", "--porcelain"]), "");
+
+        // staged changes stop it before anything is fetched or moved, like git
+        std::fs::write(local_path.join("new.txt"), "new\n").unwrap();
+        std::fs::write(local_path.join("local.txt"), "staged edit\n").unwrap();
+        cmd_seq(&[(&["add", "new.txt", "local.txt"], false)])(git_local).unwrap();
+        let tracking = rev("refs/remotes/origin/master");
+        let status = log(&["status", "--porcelain"]);
+        assert_eq!(status, "M  local.txt\nA  new.txt\n");
+        let output = std::process::Command::new("cargo")
+            .args(&cargo[1..])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(128));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("cannot pull with rebase: Your index contains uncommitted changes."), "{}", stderr);
+        assert_eq!(rev("HEAD"), before);
+        assert_eq!(rev("refs/remotes/origin/master"), tracking);
+        assert_eq!(log(&["status", "--porcelain"]), status);
+        assert_eq!(std::fs::read_to_string(local_path.join("local.txt")).unwrap(), "staged edit\n");
+
+        // and so do unstaged ones
+        cmd_seq(&[(&["reset", "--quiet"], false)])(git_local).unwrap();
+        std::fs::remove_file(local_path.join("new.txt")).unwrap();
+        let output = std::process::Command::new("cargo").args(&cargo[1..]).output().unwrap();
+        assert_eq!(output.status.code(), Some(128));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("cannot pull with rebase: You have unstaged changes."));
+        assert_eq!(rev("HEAD"), before);
+        assert_eq!(log(&["status", "--porcelain"]), " M local.txt\n");
+    }
+}
@@ -1,19 +1,35 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::Write;
 use clap::Parser;
+use reqwest::blocking::Client;
 use crate::{GitError, Result};
+use crate::utils::config::Config;
+use crate::utils::refs::current_branch;
+use crate::utils::http::http_client;
+use crate::utils::fs::read_raw_object;
+use crate::utils::hash::ObjectId;
+use crate::utils::packfile::write_pack;
+use crate::utils::credential::{Credential, send_with_credentials};
+use crate::utils::protocol::{
+    parse_ref_advertisement,
+    pkt_line::{Packet, PktLineReader, PktLineWriter},
+};
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 #[derive(Parser, Debug)]
 #[command(name = "push", about = "推送本地更改到远程仓库")]
 pub struct Push {
-    /// 远程仓库名称
-    #[arg(default_value = "origin")]
-    remote: String,
+    /// 远程仓库名称（默认为当前分支的上游所在的远程）
+    remote: Option<String>,
     
     /// 分支名称
     branch: Option<String>,
+
+    /// 推送成功后把目标设为当前分支的上游
+    #[arg(short = 'u', long)]
+    set_upstream: bool,
     
     /// 强制推送
     #[arg(short, long)]
@@ -26,6 +42,10 @@ pub struct Push {
     /// 推送所有分支
     #[arg(long)]
     all: bool,
+
+    /// 服务器接受过的凭据，discover 和 receive-pack 两个请求共用
+    #[arg(skip)]
+    credential: RefCell<Option<Credential>>,
 }
 
 impl Push {
@@ -33,10 +53,24 @@ impl Push {
         Ok(Box::new(Push::try_parse_from(args)?))
     }
     
+    /// 推送到哪个远程的哪个分支：命令行优先，没给远程时用 branch.<name>.remote / merge，
+    /// 都没有就和 git 一样报错，不再默认推到 origin 的同名分支
+    fn destination(&self, gitdir: &Path) -> Result<(String, String)> {
+        let current_branch = current_branch(gitdir)?;
+        let upstream = Config::read_all(gitdir)?.upstream(&current_branch);
+        match (&self.remote, &self.branch, upstream) {
+            (Some(remote), Some(branch), _) => Ok((remote.clone(), branch.clone())),
+            (Some(remote), None, Some(upstream)) if upstream.remote == *remote => Ok((remote.clone(), upstream.branch().to_string())),
+            (Some(remote), None, _) => Ok((remote.clone(), current_branch)),
+            (None, _, Some(upstream)) => Ok((upstream.remote.clone(), upstream.branch().to_string())),
+            (None, _, None) => Err(GitError::no_upstream(&current_branch)),
+        }
+    }
+
     /// 执行推送操作
-    fn push_to_remote(&self, gitdir: &PathBuf) -> Result<()> {
+    fn push_to_remote(&self, gitdir: &PathBuf, remote: &str, target_branch: &str) -> Result<()> {
         // 1. 获取远程仓库配置
-        let remote_config = self.get_remote_config(gitdir)?;
+        let remote_config = Self::get_remote_config(gitdir, remote)?;
         
         if self.verbose {
             println!("Pushing to {}", remote_config.url);
@@ -44,14 +78,14 @@ impl Push {
 
         // 检查URL类型并选择传输方式
         if remote_config.url.starts_with("git@") || remote_config.url.contains("ssh://") {
-            return self.push_via_ssh(&remote_config, gitdir);
+            self.push_via_ssh(&remote_config, gitdir, remote, target_branch)
         } else {
-            return self.push_via_https(&remote_config, gitdir);
+            self.push_via_https(&remote_config, gitdir, remote, target_branch)
         }
     }
 
     /// 通过HTTPS推送
-    fn push_via_https(&self, remote_config: &RemoteConfig, gitdir: &PathBuf) -> Result<()> {
+    fn push_via_https(&self, remote_config: &RemoteConfig, gitdir: &PathBuf, remote: &str, target_branch: &str) -> Result<()> {
         
         
         if self.verbose {
@@ -59,15 +93,15 @@ impl Push {
         }
         
         // 2. 获取当前分支和提交
-        let (current_branch, current_commit) = self.get_current_state(gitdir)?;
-        let target_branch = self.branch.as_ref().unwrap_or(&current_branch);
+        let (_, current_commit) = self.get_current_state(gitdir)?;
         
         if self.verbose {
-            println!("Pushing branch '{}' ({})", target_branch, &current_commit[..8]);
+            println!("Pushing branch '{}' ({})", target_branch, &current_commit.to_hex()[..8]);
         }
         
         // 3. 检查远程状态
-        let remote_refs = self.discover_remote_refs(&remote_config.url)?;
+        let client = http_client(&Config::read_all(gitdir)?, &remote_config.url)?;
+        let remote_refs = self.discover_remote_refs(&client, &remote_config.url)?;
         
         // 4. 检查是否需要推送
         let push_info = self.analyze_push(&remote_refs, target_branch, &current_commit)?;
@@ -89,41 +123,40 @@ impl Push {
         }
         
         // 7. 推送到 GitHub
-        self.send_push_to_github(&remote_config.url, target_branch, &current_commit, &push_info, packfile)?;
+        self.send_push_to_github(&client, &remote_config.url, target_branch, &current_commit, &push_info, packfile)?;
         
-        println!("Successfully pushed to {}/{}", self.remote, target_branch);
+        println!("Successfully pushed to {}/{}", remote, target_branch);
         Ok(())
     }
 
     /// 通过SSH推送
-    fn push_via_ssh(&self, remote_config: &RemoteConfig, gitdir: &PathBuf) -> Result<()> {
+    fn push_via_ssh(&self, remote_config: &RemoteConfig, gitdir: &Path, remote: &str, target_branch: &str) -> Result<()> {
         if self.verbose {
             println!("Using SSH transport for {}", remote_config.url);
         }
         
         // 2. 获取当前分支和提交
-        let (current_branch, current_commit) = self.get_current_state(gitdir)?;
-        let target_branch = self.branch.as_ref().unwrap_or(&current_branch);
+        let (_, current_commit) = self.get_current_state(gitdir)?;
         
         if self.verbose {
-            println!("Pushing branch '{}' ({})", target_branch, &current_commit[..8]);
+            println!("Pushing branch '{}' ({})", target_branch, &current_commit.to_hex()[..8]);
         }
         
         // 使用系统Git进行SSH推送（临时解决方案）
-        self.push_via_system_git(&remote_config.url, target_branch)?;
+        self.push_via_system_git(remote, target_branch)?;
         
-        println!("Successfully pushed to {}/{}", self.remote, target_branch);
+        println!("Successfully pushed to {}/{}", remote, target_branch);
         Ok(())
     }
     
     /// 使用系统Git进行推送（SSH支持）
-    fn push_via_system_git(&self, _url: &str, branch: &str) -> Result<()> {
+    fn push_via_system_git(&self, remote: &str, branch: &str) -> Result<()> {
         use std::process::Command;
         
         let mut cmd = Command::new("git");
         cmd.arg("push");
-        cmd.arg("origin");
-        cmd.arg(format!("{}:{}", branch, branch));
+        cmd.arg(remote);
+        cmd.arg(format!("HEAD:{}", branch));
         
         if self.force {
             cmd.arg("--force");
@@ -147,80 +180,40 @@ impl Push {
     }
     
     /// 获取远程仓库配置
-    fn get_remote_config(&self, gitdir: &PathBuf) -> Result<RemoteConfig> {
-        let config_path = gitdir.join("config");
-        let config_content = std::fs::read_to_string(config_path)?;
-        
-        let mut url = None;
-        let mut in_remote_section = false;
-        
-        for line in config_content.lines() {
-            let trimmed = line.trim();
-            
-            if trimmed == &format!("[remote \"{}\"]", self.remote) {
-                in_remote_section = true;
-                continue;
-            }
-            
-            if trimmed.starts_with('[') && trimmed.ends_with(']') {
-                in_remote_section = false;
-            }
-            
-            if in_remote_section && trimmed.starts_with("url = ") {
-                url = Some(trimmed.strip_prefix("url = ").unwrap().to_string());
-            }
-        }
-        
-        let url = url.ok_or_else(|| GitError::invalid_command(format!("Remote '{}' not found", self.remote)))?;
-        
+    fn get_remote_config(gitdir: &Path, remote: &str) -> Result<RemoteConfig> {
+        let url = Config::read_all(gitdir)?
+            .get(&format!("remote.{}.url", remote))
+            .ok_or_else(|| GitError::invalid_command(format!("Remote '{}' not found", remote)))?
+            .to_string();
         Ok(RemoteConfig { url })
     }
     
     /// 获取当前分支和提交状态
-    fn get_current_state(&self, gitdir: &PathBuf) -> Result<(String, String)> {
-        use crate::utils::refs::{read_head_ref, read_ref_commit};
-        
-        let head_ref = read_head_ref(gitdir)?;
-        let current_branch = head_ref.strip_prefix("refs/heads/")
-            .ok_or_else(|| GitError::invalid_command("Not on a branch".to_string()))?
-            .to_string();
+    fn get_current_state(&self, gitdir: &Path) -> Result<(String, ObjectId)> {
+        use crate::utils::refs::{current_branch, read_branch_commit};
         
-        let current_commit = read_ref_commit(gitdir, &head_ref)?;
+        let current_branch = current_branch(gitdir)?;
+        let current_commit = read_branch_commit(gitdir, &current_branch)?;
         
         Ok((current_branch, current_commit))
     }
     
     /// 发现远程引用（GitHub API）
-    fn discover_remote_refs(&self, url: &str) -> Result<HashMap<String, String>> {
-        use reqwest::blocking::Client;
-        
-        let client = Client::new();
+    fn discover_remote_refs(&self, client: &Client, url: &str) -> Result<HashMap<String, ObjectId>> {
         let refs_url = format!("{}/info/refs?service=git-receive-pack", url);
         
         if self.verbose {
             println!("Discovering remote refs from {}", refs_url);
         }
         
-        let mut request = client
-            .get(&refs_url)
-            .header("User-Agent", "git/2.42.0")
-            .header("Accept", "*/*")
-            .header("Accept-Encoding", "gzip")
-            .header("Git-Protocol", "version=2");
-        
-        // 添加 GitHub 认证
-        if let Some((username, password)) = self.get_github_credentials(url)? {
-            if self.verbose {
-                println!("Using authentication: username={}, token={}...{}", 
-                    username, 
-                    &password[..std::cmp::min(8, password.len())],
-                    if password.len() > 8 { &password[password.len()-4..] } else { "" }
-                );
-            }
-            request = request.basic_auth(username, Some(password));
-        }
-        
-        let response = request.send()?;
+        let response = send_with_credentials(url, &self.credential, || {
+            client
+                .get(&refs_url)
+                .header("User-Agent", "git/2.42.0")
+                .header("Accept", "*/*")
+                .header("Accept-Encoding", "gzip")
+        })?;
+        self.log_credential();
         
         if !response.status().is_success() {
             return Err(GitError::network_error(format!(
@@ -230,170 +223,25 @@ impl Push {
             )));
         }
         
-        let body = response.text()?;
-        self.parse_refs_response(&body)
-    }
-    
-    /// 获取 GitHub 认证信息
-    fn get_github_credentials(&self, url: &str) -> Result<Option<(String, String)>> {
-        // 1. 尝试环境变量
-        if let Ok(token) = std::env::var("GITHUB_TOKEN") {
-            if let Ok(username) = std::env::var("GITHUB_USER") {
-                return Ok(Some((username, token)));
-            } else {
-                return Ok(Some(("token".to_string(), token)));
-            }
-        }
-        
-        // 2. 尝试从 git config 获取
-        if let Ok(token) = std::env::var("GIT_TOKEN") {
-            if let Ok(username) = std::env::var("GIT_USER") {
-                return Ok(Some((username, token)));
-            } else {
-                return Ok(Some(("token".to_string(), token)));
-            }
-        }
-        
-        // 3. 交互式输入
-        if url.contains("github.com") {
-            println!("GitHub authentication required");
-            println!("GitHub no longer supports password authentication for Git operations.");
-            println!("Please use a Personal Access Token instead.");
-            print!("GitHub用户名: ");
-            std::io::stdout().flush().unwrap();
-            let mut username = String::new();
-            std::io::stdin().read_line(&mut username)?;
-            let username = username.trim().to_string();
-            
-            let token = rpassword::prompt_password("Personal Access Token (not your GitHub password): ")?;
-            
-            return Ok(Some((username, token)));
-        }
-        
-        Ok(None)
-    }
-    
-    /// 解析引用响应
-    fn parse_refs_response(&self, body: &str) -> Result<HashMap<String, String>> {
-        let mut refs = HashMap::new();
-        
+        let body = response.bytes()?;
+        let refs = parse_ref_advertisement(&body)?;
         if self.verbose {
-            println!("Raw refs response: {}", body);
-        }
-        
-        // 跳过服务声明行
-        let lines: Vec<&str> = body.lines().collect();
-        
-        for (i, line) in lines.iter().enumerate() {
-            if self.verbose {
-                println!("Processing line {}: '{}'", i, line);
-            }
-            
-            if line.trim().is_empty() {
-                continue;
-            }
-            
-            // 跳过服务声明
-            if line.contains("service=git-receive-pack") || line.starts_with('#') {
-                if self.verbose {
-                    println!("Skipping service line: {}", line);
-                }
-                continue;
-            }
-            
-            // 解析 pkt-line 格式: "0041hash refs/heads/main\0capabilities"
-            if let Some(content) = self.parse_pkt_line(line) {
-                if content.trim().is_empty() {
-                    if self.verbose {
-                        println!("Empty content, skipping");
-                    }
-                    continue;
-                }
-                
-                if let Some((hash, ref_name)) = self.parse_ref_line(&content) {
-                    if self.verbose {
-                        println!("Remote ref: {} -> {}", ref_name, &hash[..8]);
-                    }
-                    refs.insert(ref_name, hash);
-                } else {
-                    if self.verbose {
-                        println!("Failed to parse ref from content: '{}'", content);
-                    }
-                }
-            } else {
-                if self.verbose {
-                    println!("Failed to parse pkt-line: '{}'", line);
-                }
-            }
-        }
-        
-        if self.verbose {
-            println!("Final parsed refs: {:?}", refs);
-        }
-        
-        Ok(refs)
-    }
-    
-    /// 解析 pkt-line 格式
-    fn parse_pkt_line(&self, line: &str) -> Option<String> {
-        if line.len() < 4 {
-            return None;
-        }
-        
-        // 前4个字符是十六进制长度
-        if let Ok(length) = u16::from_str_radix(&line[..4], 16) {
-            if length == 0 {
-                return Some(String::new()); // flush packet
-            }
-            
-            let content_length = (length as usize).saturating_sub(4);
-            // 使用实际可用的内容长度
-            let available_content = line.len().saturating_sub(4);
-            let actual_content_length = std::cmp::min(content_length, available_content);
-            
-            if actual_content_length > 0 {
-                let content = line[4..4 + actual_content_length].to_string();
-                if self.verbose && !content.trim().is_empty() {
-                    println!("Parsed pkt-line content: '{}'", content.replace('\0', "\\0"));
-                }
-                return Some(content);
+            for remote_ref in &refs {
+                println!("Remote ref: {} -> {}", remote_ref.name, &remote_ref.hash.to_hex()[..8]);
             }
         }
-        
-        None
+        Ok(refs.into_iter().map(|remote_ref| (remote_ref.name, remote_ref.hash)).collect())
     }
     
-    /// 解析引用行
-    fn parse_ref_line(&self, content: &str) -> Option<(String, String)> {
-        // 格式: "hash refs/heads/branch_name" 或 "hash refs/heads/branch_name\0capabilities"
-        
-        // 移除 capabilities （在 \0 之后）
-        let clean_content = if let Some(null_pos) = content.find('\0') {
-            &content[..null_pos]
-        } else {
-            content
-        };
-        
-        let parts: Vec<&str> = clean_content.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let hash = parts[0].to_string();
-            let ref_name = parts[1].to_string();
-            
-            if self.verbose {
-                println!("Parsed ref: {} -> {}", ref_name, &hash[..8]);
-            }
-            
-            Some((hash, ref_name))
-        } else {
-            if self.verbose {
-                println!("Failed to parse ref line: '{}'", clean_content);
-            }
-            None
+    /// 只打印用户名，密码和 token 的任何部分都不输出
+    fn log_credential(&self) {
+        if self.verbose && let Some(credential) = self.credential.borrow().as_ref() {
+            println!("Using credentials for user {}", credential.username);
         }
     }
     
     /// 分析推送需求
-    fn analyze_push(&self, remote_refs: &HashMap<String, String>, branch: &str, local_commit: &str) -> Result<PushInfo> {
+    fn analyze_push(&self, remote_refs: &HashMap<String, ObjectId>, branch: &str, local_commit: &ObjectId) -> Result<PushInfo> {
         let remote_ref_name = format!("refs/heads/{}", branch);
         
         if let Some(remote_commit) = remote_refs.get(&remote_ref_name) {
@@ -401,8 +249,8 @@ impl Push {
                 return Ok(PushInfo {
                     up_to_date: true,
                     force_required: false,
-                    old_commit: Some(remote_commit.clone()),
-                    new_commit: local_commit.to_string(),
+                    old_commit: Some(*remote_commit),
+                    new_commit: *local_commit,
                 });
             }
             
@@ -412,8 +260,8 @@ impl Push {
             Ok(PushInfo {
                 up_to_date: false,
                 force_required,
-                old_commit: Some(remote_commit.clone()),
-                new_commit: local_commit.to_string(),
+                old_commit: Some(*remote_commit),
+                new_commit: *local_commit,
             })
         } else {
             // 新分支
@@ -421,25 +269,25 @@ impl Push {
                 up_to_date: false,
                 force_required: false,
                 old_commit: None,
-                new_commit: local_commit.to_string(),
+                new_commit: *local_commit,
             })
         }
     }
     
     /// 检查是否为快进推送
-    fn is_fast_forward(&self, _local_commit: &str, _remote_commit: &str) -> Result<bool> {
+    fn is_fast_forward(&self, _local_commit: &ObjectId, _remote_commit: &ObjectId) -> Result<bool> {
         // 简化实现：检查本地提交历史是否包含远程提交
         // 在实际实现中，需要遍历提交历史
         Ok(true) // 暂时总是允许，避免复杂的历史检查
     }
     
     /// 收集需要推送的对象
-    fn collect_objects_to_push(&self, gitdir: &PathBuf, commit_hash: &str, _push_info: &PushInfo) -> Result<Vec<String>> {
+    fn collect_objects_to_push(&self, gitdir: &PathBuf, commit_hash: &ObjectId, _push_info: &PushInfo) -> Result<Vec<String>> {
         let mut objects = Vec::new();
         let mut visited = std::collections::HashSet::new();
         
         // 递归收集提交及其相关的所有对象
-        self.collect_commit_objects(gitdir, commit_hash, &mut objects, &mut visited)?;
+        self.collect_commit_objects(gitdir, &commit_hash.to_hex(), &mut objects, &mut visited)?;
         
         if self.verbose {
             println!("Objects to push: {}", objects.len());
@@ -467,8 +315,7 @@ impl Push {
         
         // 收集 tree 对象
         for line in commit_content.lines() {
-            if line.starts_with("tree ") {
-                let tree_hash = &line[5..45];
+            if let Some(tree_hash) = line.strip_prefix("tree ") {
                 self.collect_tree_objects(gitdir, tree_hash, objects, visited)?;
             }
             // 注意：这里不收集 parent commits，因为我们只推送当前提交
@@ -503,10 +350,11 @@ impl Push {
                     let mode = &entry_header[..space_pos];
                     let _name = &entry_header[space_pos + 1..];
                     
-                    // 提取 20 字节的哈希
+                    // 提取哈希，长度看仓库用的算法
+                    let hash_len = crate::utils::hash::algorithm().raw_len();
                     let hash_start = pos + null_pos + 1;
-                    if hash_start + 20 <= content.len() {
-                        let hash_bytes = &content[hash_start..hash_start + 20];
+                    if hash_start + hash_len <= content.len() {
+                        let hash_bytes = &content[hash_start..hash_start + hash_len];
                         let hash = hex::encode(hash_bytes);
                         
                         // 根据模式决定对象类型
@@ -521,7 +369,7 @@ impl Push {
                             }
                         }
                         
-                        pos = hash_start + 20;
+                        pos = hash_start + hash_len;
                     } else {
                         break;
                     }
@@ -537,33 +385,12 @@ impl Push {
     }
     
     /// 创建 packfile
-    fn create_packfile(&self, gitdir: &PathBuf, objects: &[String]) -> Result<Vec<u8>> {
-        let mut packfile = Vec::new();
-        
+    fn create_packfile(&self, gitdir: &Path, objects: &[String]) -> Result<Vec<u8>> {
         if self.verbose {
             println!("Creating packfile for {} objects", objects.len());
         }
         
-        // 1. 收集所有对象数据
-        let mut packed_objects = Vec::new();
-        for object_hash in objects {
-            let obj_data = self.create_packfile_object_entry(gitdir, object_hash)?;
-            packed_objects.push(obj_data);
-        }
-        
-        // 2. 创建 packfile 头部
-        packfile.extend(b"PACK");
-        packfile.extend(&2u32.to_be_bytes()); // version 2
-        packfile.extend(&(packed_objects.len() as u32).to_be_bytes());
-        
-        // 3. 添加对象数据
-        for obj_data in packed_objects {
-            packfile.extend(obj_data);
-        }
-        
-        // 4. 计算并添加 SHA-1 校验和
-        let checksum = self.calculate_packfile_checksum(&packfile)?;
-        packfile.extend(checksum);
+        let packfile = write_pack(gitdir, objects)?;
         
         if self.verbose {
             println!("Created packfile: {} bytes", packfile.len());
@@ -572,45 +399,9 @@ impl Push {
         Ok(packfile)
     }
     
-    /// 读取对象数据
-    fn read_object_data(&self, gitdir: &PathBuf, object_hash: &str) -> Result<Vec<u8>> {
-        use crate::utils::fs::obj_to_pathbuf;
-        use crate::utils::zlib::decompress_file_bytes;
-        
-        let object_path = obj_to_pathbuf(gitdir, object_hash);
-        decompress_file_bytes(&object_path)
-    }
-    
-    /// 创建 packfile 对象条目
-    fn create_packfile_object_entry(&self, gitdir: &PathBuf, object_hash: &str) -> Result<Vec<u8>> {
-        // 读取并解析对象
-        let object_data = self.read_object_data(gitdir, object_hash)?;
-        let (obj_type, content) = self.parse_object_data(&object_data)?;
-        
-        if self.verbose {
-            println!("Packing object {} (type: {}, size: {})", &object_hash[..8], obj_type, content.len());
-        }
-        
-        // 创建 packfile 格式的对象
-        let mut entry = Vec::new();
-        
-        // 1. 对象头部（类型 + 大小，使用变长编码）
-        let type_code = match obj_type {
-            1 => 1, // commit
-            2 => 2, // tree  
-            3 => 3, // blob
-            4 => 4, // tag
-            _ => return Err(GitError::invalid_command(format!("Unsupported object type: {}", obj_type))),
-        };
-        
-        let header = self.encode_packfile_object_header(type_code, content.len())?;
-        entry.extend(header);
-        
-        // 2. 压缩对象内容
-        let compressed_content = self.compress_object_content(&content)?;
-        entry.extend(compressed_content);
-        
-        Ok(entry)
+    /// 读取对象数据，松散的或者已经打进 pack 的
+    fn read_object_data(&self, gitdir: &Path, object_hash: &str) -> Result<Vec<u8>> {
+        read_raw_object(gitdir, object_hash)
     }
     
     /// 解析对象数据，返回类型和内容
@@ -637,70 +428,14 @@ impl Push {
         Err(GitError::invalid_command("Invalid object format".to_string()))
     }
     
-    /// 编码 packfile 对象头部（类型 + 大小）
-    fn encode_packfile_object_header(&self, obj_type: u8, size: usize) -> Result<Vec<u8>> {
-        let mut header = Vec::new();
-        let mut remaining_size = size;
-        
-        // 第一个字节：类型（3位）+ 大小的低4位 + 继续位
-        let mut first_byte = (obj_type << 4) | ((remaining_size & 0x0F) as u8);
-        remaining_size >>= 4;
-        
-        if remaining_size > 0 {
-            first_byte |= 0x80; // 设置继续位
-        }
-        
-        header.push(first_byte);
-        
-        // 后续字节：每字节7位大小信息 + 1位继续位
-        while remaining_size > 0 {
-            let mut byte = (remaining_size & 0x7F) as u8;
-            remaining_size >>= 7;
-            
-            if remaining_size > 0 {
-                byte |= 0x80; // 设置继续位
-            }
-            
-            header.push(byte);
-        }
-        
-        Ok(header)
-    }
-    
-    /// 压缩对象内容
-    fn compress_object_content(&self, content: &[u8]) -> Result<Vec<u8>> {
-        use flate2::{Compression, write::ZlibEncoder};
-        use std::io::Write;
-        
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(content)?;
-        let compressed = encoder.finish()?;
-        
-        Ok(compressed)
-    }
-    
-    /// 计算 packfile 校验和
-    fn calculate_packfile_checksum(&self, packfile: &[u8]) -> Result<Vec<u8>> {
-        use sha1::{Sha1, Digest};
-        
-        let mut hasher = Sha1::new();
-        hasher.update(packfile);
-        let result = hasher.finalize();
-        
-        Ok(result.to_vec())
-    }
-    
     /// 发送推送请求到 GitHub
-    fn send_push_to_github(&self, url: &str, branch: &str, commit: &str, push_info: &PushInfo, packfile: Vec<u8>) -> Result<()> {
-        use reqwest::blocking::Client;
-        
+    fn send_push_to_github(&self, client: &Client, url: &str, branch: &str, commit: &ObjectId, push_info: &PushInfo, packfile: Vec<u8>) -> Result<()> {
         if push_info.force_required && !self.force {
             return Err(GitError::invalid_command(
                 "Updates were rejected because the remote contains work that you do not have locally. Use --force to override.".to_string()
             ));
         }
         
-        let client = Client::new();
         let push_url = format!("{}/git-receive-pack", url);
         
         if self.verbose {
@@ -709,24 +444,21 @@ impl Push {
         }
         
         // 创建推送请求体
-        let mut request_body = Vec::new();
+        let mut request_body = PktLineWriter::new();
         
         // 1. 引用更新命令
-        let old_commit = push_info.old_commit.as_deref().unwrap_or("0000000000000000000000000000000000000000");
+        let old_commit = push_info.old_commit.unwrap_or_else(ObjectId::null);
         let ref_update = format!("{} {} refs/heads/{}", old_commit, commit, branch);
         
         // 添加 capabilities（简化版本）
         let capabilities = "report-status delete-refs side-band-64k quiet atomic ofs-delta agent=git/2.42.0";
         let ref_update_with_caps = format!("{}\0{}\n", ref_update, capabilities);
         
-        // 使用正确的 pkt-line 格式
-        request_body.extend(self.create_pkt_line(&ref_update_with_caps));
-        request_body.extend(b"0000"); // flush packet
+        request_body.line(&ref_update_with_caps)?.flush();
         
         // 2. packfile 数据（直接添加，不包装在 pkt-line 中）
-        if !packfile.is_empty() {
-            request_body.extend(packfile);
-        }
+        request_body.raw(&packfile);
+        let request_body = request_body.into_bytes();
         
         if self.verbose {
             println!("Request body size: {} bytes", request_body.len());
@@ -734,28 +466,16 @@ impl Push {
         }
         
         // 3. 发送请求
-        let mut request = client
-            .post(&push_url)
-            .header("Content-Type", "application/x-git-receive-pack-request")
-            .header("User-Agent", "git/2.42.0")
-            .header("Accept", "application/x-git-receive-pack-result")
-            .header("Accept-Encoding", "gzip")
-            .header("Expect", "100-continue")
-            .body(request_body);
-        
-        // 添加认证
-        if let Some((username, password)) = self.get_github_credentials(url)? {
-            if self.verbose {
-                println!("Using authentication: username={}, token={}...{}", 
-                    username, 
-                    &password[..std::cmp::min(8, password.len())],
-                    if password.len() > 8 { &password[password.len()-4..] } else { "" }
-                );
-            }
-            request = request.basic_auth(username, Some(password));
-        }
-        
-        let response = request.send()?;
+        let response = send_with_credentials(url, &self.credential, || {
+            client
+                .post(&push_url)
+                .header("Content-Type", "application/x-git-receive-pack-request")
+                .header("User-Agent", "git/2.42.0")
+                .header("Accept", "application/x-git-receive-pack-result")
+                .header("Accept-Encoding", "gzip")
+                .header("Expect", "100-continue")
+                .body(request_body.clone())
+        })?;
         let status = response.status();
         
         if self.verbose {
@@ -764,9 +484,9 @@ impl Push {
         
         if status.is_success() {
             // 解析响应
-            let response_body = response.text()?;
+            let response_body = response.bytes()?;
             if self.verbose {
-                println!("Response body: {}", response_body);
+                println!("Response body: {}", String::from_utf8_lossy(&response_body));
             }
             
             self.parse_push_response(&response_body)?;
@@ -780,95 +500,80 @@ impl Push {
         }
     }
     
-    /// 创建 pkt-line
-    fn create_pkt_line(&self, content: &str) -> Vec<u8> {
-        let length = content.len() + 4;
-        if length > 65520 {
-            // pkt-line 最大长度限制
-            panic!("Content too long for pkt-line");
-        }
-        let length_hex = format!("{:04x}", length);
-        let mut pkt_line = length_hex.into_bytes();
-        pkt_line.extend(content.as_bytes());
-        pkt_line
-    }
-    
     /// 解析推送响应
-    fn parse_push_response(&self, response: &str) -> Result<()> {
-        if response.trim().is_empty() {
+    fn parse_push_response(&self, response: &[u8]) -> Result<()> {
+        if response.is_empty() {
             if self.verbose {
                 println!("Empty response from server");
             }
             return Ok(());
         }
         
-        let mut unpack_ok = false;
-        let mut ref_updated = false;
-        
-        // 解析 pkt-line 格式的响应
-        let lines: Vec<&str> = response.lines().collect();
-        
-        for line in lines {
-            let line = line.trim();
-            
-            if line.is_empty() {
-                continue;
-            }
-            
-            // 尝试解析 pkt-line
-            let content = if line.len() > 4 && line.chars().take(4).all(|c| c.is_ascii_hexdigit()) {
-                // 可能是 pkt-line 格式
-                if let Ok(length) = u16::from_str_radix(&line[..4], 16) {
-                    if length == 0 {
-                        continue; // flush packet
-                    }
-                    let content_length = (length as usize).saturating_sub(4);
-                    if line.len() >= 4 + content_length {
-                        &line[4..4 + content_length]
-                    } else {
-                        line
-                    }
-                } else {
-                    line
-                }
-            } else {
-                line
-            };
-            
-            if self.verbose {
-                println!("Server response: {}", content);
+        // band 2 的进度信息原样转到 stderr
+        let report = Self::read_report_status(response, &mut |progress| eprint!("remote: {}", progress))?;
+        if self.verbose {
+            println!("Unpack status: {}", report.unpack);
+        }
+        if report.unpack != "ok" {
+            return Err(GitError::remote_error(format!("unpack failed: {}", report.unpack)));
+        }
+        for (refname, status) in &report.refs {
+            match status {
+                None => if self.verbose {
+                    println!("Reference update successful: {}", refname);
+                },
+                Some(reason) => return Err(GitError::push_rejected(refname, reason)),
             }
-            
-            if content.starts_with("unpack ok") {
-                unpack_ok = true;
-                if self.verbose {
-                    println!("Unpack successful");
-                }
-            } else if content.starts_with("ok refs/heads/") {
-                ref_updated = true;
-                if self.verbose {
-                    println!("Reference update successful: {}", content);
+        }
+        Ok(())
+    }
+
+    /// 把响应按 pkt-line 拆开，side-band 的话 band 1 是 report-status，band 2 是进度，band 3 是错误
+    fn read_report_status(response: &[u8], progress: &mut dyn FnMut(&str)) -> Result<PushReport> {
+        let packets = PktLineReader::new(response).collect::<Result<Vec<_>>>()?;
+        let sideband = packets.iter().find_map(|packet| match packet {
+            Packet::Data(data) if !data.is_empty() => Some(matches!(data[0], 1..=3)),
+            _ => None,
+        }).unwrap_or(false);
+        
+        let mut inner = Vec::new();
+        if sideband {
+            for packet in &packets {
+                let Packet::Data([band, data @ ..]) = *packet else {
+                    continue;
+                };
+                match *band {
+                    1 => inner.extend_from_slice(data),
+                    2 => progress(&String::from_utf8_lossy(data)),
+                    3 => return Err(GitError::remote_error(String::from_utf8_lossy(data).trim_end().to_string())),
+                    band => return Err(GitError::protocol_error(&format!("unknown side-band {}", band))),
                 }
-            } else if content.starts_with("ng refs/heads/") {
-                return Err(GitError::invalid_command(format!("Reference update failed: {}", content)));
-            } else if content.contains("error:") || content.contains("fatal:") {
-                return Err(GitError::invalid_command(format!("Server error: {}", content)));
             }
         }
+        let packets = if sideband {
+            PktLineReader::new(&inner).collect::<Result<Vec<_>>>()?
+        } else {
+            packets
+        };
         
-        // 验证推送是否成功
-        if !unpack_ok && !ref_updated {
-            // 如果没有明确的成功指示，检查是否有错误
-            if response.contains("error") || response.contains("fatal") || response.contains("rejected") {
-                return Err(GitError::invalid_command(format!("Push failed: {}", response)));
-            }
-            
-            if self.verbose {
-                println!("Warning: No explicit success indication from server, but no errors detected");
+        let mut report = PushReport::default();
+        for line in packets.iter().filter_map(Packet::text).filter(|line| !line.is_empty()) {
+            let line = String::from_utf8_lossy(line);
+            if let Some(status) = line.strip_prefix("unpack ") {
+                report.unpack = status.to_string();
+            } else if let Some(refname) = line.strip_prefix("ok ") {
+                report.refs.push((refname.to_string(), None));
+            } else if let Some(rest) = line.strip_prefix("ng ") {
+                let (refname, reason) = rest.split_once(' ').unwrap_or((rest, "rejected"));
+                report.refs.push((refname.to_string(), Some(reason.to_string())));
+            } else {
+                return Err(GitError::protocol_error(&format!("unexpected report-status line: {}", line)));
             }
         }
-        
-        Ok(())
+        if report.unpack.is_empty() {
+            return Err(GitError::protocol_error("report-status has no unpack line"));
+        }
+        Ok(report)
     }
 
     /// 调试：显示 packfile 信息
@@ -902,15 +607,16 @@ impl Push {
             .join(" ");
         println!("  First {} bytes: {}", preview_len, hex_preview);
         
-        // 显示最后 20 字节（SHA-1 校验和）
-        if packfile.len() >= 20 {
-            let checksum_start = packfile.len() - 20;
+        // 显示最后的校验和
+        let hash_len = crate::utils::hash::algorithm().raw_len();
+        if packfile.len() >= hash_len {
+            let checksum_start = packfile.len() - hash_len;
             let checksum_hex: String = packfile[checksum_start..]
                 .iter()
                 .map(|b| format!("{:02x}", b))
                 .collect::<Vec<_>>()
                 .join("");
-            println!("  SHA-1 checksum: {}", checksum_hex);
+            println!("  checksum: {}", checksum_hex);
         }
         
         Ok(())
@@ -922,23 +628,118 @@ struct RemoteConfig {
     url: String,
 }
 
+/// 一个 ref 的推送结果，None 表示 ok，否则是服务器给的原因
+type RefStatus = (String, Option<String>);
+
+/// receive-pack 的 report-status：unpack 的结果和每个 ref 的结果
+#[derive(Debug, Default)]
+struct PushReport {
+    unpack: String,
+    refs: Vec<RefStatus>,
+}
+
 #[derive(Debug)]
 struct PushInfo {
     up_to_date: bool,
     force_required: bool,
-    old_commit: Option<String>,
-    new_commit: String,
+    old_commit: Option<ObjectId>,
+    new_commit: ObjectId,
 }
 
 impl SubCommand for Push {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
         
+        let (remote, branch) = self.destination(&gitdir)?;
         if self.verbose {
-            println!("Pushing to remote '{}'", self.remote);
+            println!("Pushing to remote '{}'", remote);
         }
         
-        self.push_to_remote(&gitdir)?;
+        self.push_to_remote(&gitdir, &remote, &branch)?;
+        if self.set_upstream {
+            let current_branch = current_branch(&gitdir)?;
+            Config::set(&gitdir, &format!("branch.{}.remote", current_branch), &remote)?;
+            Config::set(&gitdir, &format!("branch.{}.merge", current_branch), &format!("refs/heads/{}", branch))?;
+            println!("branch '{}' set up to track '{}/{}'.", current_branch, remote, branch);
+        }
         Ok(0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Push, RefStatus};
+
+    fn pkt(data: &[u8]) -> Vec<u8> {
+        [format!("{:04x}", data.len() + 4).as_bytes(), data].concat()
+    }
+
+    /// the report-status stream wrapped into side-band packets, with some progress on band 2
+    fn sideband(report: &[u8]) -> Vec<u8> {
+        [
+            pkt(b"\x02Resolving deltas: 100% (2/2), done.\n"),
+            pkt(&[&[1u8][..], report].concat()),
+            b"0000".to_vec(),
+        ].concat()
+    }
+
+    fn report(lines: &[&str]) -> Vec<u8> {
+        let mut report = lines.iter().flat_map(|line| pkt(format!("{}\n", line).as_bytes())).collect::<Vec<_>>();
+        report.extend_from_slice(b"0000");
+        report
+    }
+
+    fn parse(response: &[u8]) -> (Result<Vec<RefStatus>, String>, Vec<String>) {
+        let mut progress = Vec::new();
+        let result = Push::read_report_status(response, &mut |msg| progress.push(msg.to_string()))
+            .and_then(|report| if report.unpack == "ok" { Ok(report.refs) } else { Err(report.unpack.into()) })
+            .map_err(|e| e.to_string());
+        (result, progress)
+    }
+
+    #[test]
+    fn test_report_status_success() {
+        let ok = report(&["unpack ok", "ok refs/heads/main"]);
+        let expected = vec![("refs/heads/main".to_string(), None)];
+
+        // plain report-status without side-band
+        let (result, progress) = parse(&ok);
+        assert_eq!(result.unwrap(), expected);
+        assert!(progress.is_empty());
+
+        let (result, progress) = parse(&sideband(&ok));
+        assert_eq!(result.unwrap(), expected);
+        assert_eq!(progress, vec!["Resolving deltas: 100% (2/2), done.\n"]);
+    }
+
+    #[test]
+    fn test_report_status_rejected() {
+        let rejected = report(&["unpack ok", "ok refs/heads/main", "ng refs/heads/protected protected branch hook declined"]);
+        let (result, _) = parse(&sideband(&rejected));
+        let refs = result.unwrap();
+        assert_eq!(refs[1], ("refs/heads/protected".to_string(), Some("protected branch hook declined".to_string())));
+
+        let push = Push { remote: Some("origin".to_string()), branch: None, set_upstream: false, force: false, verbose: false, all: false, credential: Default::default() };
+        let err = push.parse_push_response(&sideband(&rejected)).unwrap_err().to_string();
+        assert!(err.contains("refs/heads/protected") && err.contains("protected branch hook declined"), "{}", err);
+
+        let unpack_failed = report(&["unpack index-pack abnormal exit", "ng refs/heads/main unpacker error"]);
+        let err = push.parse_push_response(&sideband(&unpack_failed)).unwrap_err().to_string();
+        assert!(err.contains("index-pack abnormal exit"), "{}", err);
+    }
+
+    #[test]
+    fn test_report_status_server_error() {
+        let response = [
+            pkt(b"\x02Counting objects: 3\n"),
+            pkt(b"\x03fatal: repository is read-only\n"),
+            b"0000".to_vec(),
+        ].concat();
+        let (result, progress) = parse(&response);
+        assert_eq!(result.unwrap_err(), "remote error: fatal: repository is read-only");
+        assert_eq!(progress.len(), 1);
+
+        // garbage instead of pkt-lines
+        assert!(parse(b"HTTP error page").0.is_err());
+    }
+}
@@ -1,44 +1,341 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::{PathBuf,Path};
-use clap::{Parser, Subcommand};
-use crate::utils::index;
-use crate::utils::zlib::{compress_object, decompress_file_bytes};
+use clap::Parser;
 use crate::{
     GitError,
     Result,
 };
 use crate::utils::{
-    fs::read_file_as_bytes,
-    hash::hash_object,
-    index::{Index, IndexEntry},
+    blob::Blob,
+    diff::flatten_tree,
+    fs::{read_object, read_worktree_file, write_blob_to_file},
+    hash::{hash_object, ObjectId},
+    ignore::Ignore,
+    index::{Index, IndexEntry, LockedIndexWriter},
+    revparse::resolve_tree,
     tree::{
         Tree,
         FileMode,
     },
 };
 use super::SubCommand;
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
 
 #[derive(Parser, Debug)]
-#[command(name = "read-tree", about = "create a tree object according to the current index")]
+#[command(name = "read-tree", about = "Reads tree information into the index")]
 pub struct ReadTree {
-    #[arg(long, help = "Prefix to add to all paths in the tree")]
+    #[arg(short = 'm', help = "merge the trees into the index instead of replacing it")]
+    pub merge: bool,
+
+    #[arg(short = 'u', requires = "merge", help = "update the working tree with the result of the merge")]
+    pub update: bool,
+
+    #[arg(long, conflicts_with = "merge", help = "Prefix to add to all paths in the tree")]
     pub prefix: Option<String>,
 
-    #[arg(required = true, help = "tree hash")]
-    pub tree_hash: String,
+    #[arg(required = true, num_args = 1..=3, help = "<tree-ish>, up to three of them with -m")]
+    pub trees: Vec<String>,
+}
+
+/// why a path stops `read-tree -m`, nothing is written when there is any
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rejection {
+    /// the index has its own version which the merge would replace
+    WouldOverwrite,
+    /// the worktree file has changes that are not in the index
+    NotUptodate,
+    /// an untracked file is in the way of a new entry, only checked with -u
+    Untracked,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::WouldOverwrite => write!(f, "would be overwritten by merge. Cannot merge."),
+            Rejection::NotUptodate => write!(f, "not uptodate. Cannot merge."),
+            Rejection::Untracked => write!(f, "would be overwritten by merge."),
+        }
+    }
+}
 
+/// the index after merging, or every path that refused to be merged
+pub struct TreeMerge {
+    pub index: Index,
+    pub rejected: Vec<(Rejection, PathBuf)>,
 }
 
+/// (mode, hash) of a path in a tree or at stage 0 of the index
+type Version = (u32, ObjectId);
+
 impl ReadTree {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
         let read_tree = ReadTree::try_parse_from(args)?;
         Ok(Box::new(read_tree))
     }
+
+    /// plain `read-tree <tree>`: the index becomes exactly the tree
+    pub fn from_internal(tree_hash: &ObjectId) -> Self {
+        ReadTree {
+            merge: false,
+            update: false,
+            prefix: None,
+            trees: vec![tree_hash.to_hex()],
+        }
+    }
+
+    /// one tree: take it. two trees `H M`: go from H to M keeping local changes.
+    /// three trees `O A B`: the trivial merges, the rest become stage 1/2/3 entries.
+    /// the case numbers are the ones of git's read-tree documentation and technical/trivial-merge.txt
+    pub fn unpack_trees(gitdir: &Path, index: &Index, trees: &[Option<&ObjectId>], check_untracked: bool) -> Result<TreeMerge> {
+        let project_root = &work_tree(gitdir)?;
+        if !index.unmerged_paths().is_empty() {
+            return Err(GitError::merge_conflict("You need to resolve your current index first".to_string()));
+        }
+        let trees = trees.iter()
+            .map(|tree| match tree {
+                Some(hash) => flatten_tree(gitdir, hash),
+                None => Ok(BTreeMap::new()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let current = index.entries.iter()
+            .map(|entry| (entry.name.clone(), entry))
+            .collect::<BTreeMap<_, _>>();
+        // git only takes M for a path deleted in the index when there was no index at all
+        let initial_checkout = !gitdir.join("index").exists();
+        let ignore = if check_untracked { Ignore::load(project_root)? } else { Ignore::default() };
+
+        let paths = current.keys()
+            .chain(trees.iter().flat_map(|tree| tree.keys()))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let mut entries = Vec::new();
+        let mut rejected = Vec::new();
+        for path in paths {
+            let cur = current.get(&path).map(|entry| (entry.mode, entry.hash));
+            let versions = trees.iter().map(|tree| tree.get(&path).copied()).collect::<Vec<_>>();
+            let stages = match versions.as_slice() {
+                [m] => Ok(m.iter().map(|m| (0, *m)).collect()),
+                [h, m] => Self::two_way(cur.as_ref(), h.as_ref(), m.as_ref(), initial_checkout),
+                [o, a, b] => Self::three_way(cur.as_ref(), o.as_ref(), a.as_ref(), b.as_ref()),
+                _ => return Err(GitError::invalid_command("read-tree merges at most three trees".to_string())),
+            };
+            let stages: Vec<(u8, Version)> = match stages {
+                Ok(stages) => stages,
+                Err(rejection) => {
+                    rejected.push((rejection, path));
+                    continue;
+                },
+            };
+
+            // whatever replaces or removes an index entry must not lose changes in the worktree
+            let unchanged = matches!(stages.as_slice(), [(0, version)] if Some(version) == cur.as_ref());
+            if let Some(entry) = current.get(&path).filter(|_| !unchanged)
+                && !Self::is_uptodate(project_root, entry)? {
+                rejected.push((Rejection::NotUptodate, path));
+                continue;
+            }
+            let new_file = cur.is_none() && stages.iter().any(|(stage, _)| *stage == 0);
+            if check_untracked && new_file {
+                let full_path = project_root.join(&path);
+                if (full_path.is_file() || full_path.is_symlink()) && !ignore.is_ignored(&path, false) {
+                    rejected.push((Rejection::Untracked, path));
+                    continue;
+                }
+            }
+
+            for (stage, (mode, hash)) in stages {
+                let mut entry = IndexEntry::new(mode, hash, path.clone()).with_stage(stage);
+                // an entry that stays the same keeps its stat data
+                if unchanged {
+                    entry.stat = current[&path].stat;
+                }
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()).then(a.stage.cmp(&b.stage)));
+        Ok(TreeMerge {
+            index: Index { entries },
+            rejected,
+        })
+    }
+
+    /// I is the index, H the tree it came from, M the tree to go to
+    fn two_way(i: Option<&Version>, h: Option<&Version>, m: Option<&Version>, initial_checkout: bool) -> std::result::Result<Vec<(u8, Version)>, Rejection> {
+        let keep = || Ok(i.into_iter().map(|i| (0, *i)).collect());
+        let take = |v: Option<&Version>| Ok(v.into_iter().map(|v| (0, *v)).collect());
+        match i {
+            Some(_) => {
+                // 4-7, 14-15, 18-19
+                if (h.is_none() && (m.is_none() || m == i)) || (h.is_some() && m.is_some() && (h == m || m == i)) {
+                    keep()
+                }
+                // 10, and 20: the index is still at H
+                else if h.is_some() && i == h {
+                    take(m)
+                }
+                else {
+                    Err(Rejection::WouldOverwrite)
+                }
+            },
+            // 3: the deletion of the path is staged
+            None if h.is_some() && m.is_some() && !initial_checkout => {
+                if h == m { keep() } else { Err(Rejection::WouldOverwrite) }
+            },
+            // 1, 2
+            None => take(m),
+        }
+    }
+
+    /// O the merge base, A ours (what the index must match), B theirs
+    fn three_way(i: Option<&Version>, o: Option<&Version>, a: Option<&Version>, b: Option<&Version>) -> std::result::Result<Vec<(u8, Version)>, Rejection> {
+        let take = |v: &Version| Ok(vec![(0, *v)]);
+        let (head_match, remote_match) = if a != b { (o == a, o == b) } else { (false, false) };
+
+        // 2ALT, 14, 14ALT: only theirs changed the path
+        if let Some(b) = b.filter(|_| head_match && !remote_match) {
+            if i.is_some() && i != Some(b) && i != a {
+                return Err(Rejection::WouldOverwrite);
+            }
+            return take(b);
+        }
+        if i.is_some() && i != a {
+            return Err(Rejection::WouldOverwrite);
+        }
+        if let Some(a) = a {
+            // 5ALT, 15: both sides agree; 13, 3ALT: only ours changed the path
+            if Some(a) == b || (remote_match && !head_match) {
+                return take(a);
+            }
+        }
+        // 1: added in the base only and gone on both sides
+        if a.is_none() && b.is_none() && o.is_none() {
+            return Ok(vec![]);
+        }
+
+        // no trivial merge, leave the stages for a real merge
+        let mut stages = Vec::new();
+        if let Some(o) = o.filter(|_| !head_match || !remote_match) {
+            stages.push((1, *o));
+        }
+        stages.extend(a.map(|a| (2, *a)));
+        stages.extend(b.map(|b| (3, *b)));
+        Ok(stages)
+    }
+
+    /// the worktree file matches its index entry, a missing file counts as matching
+    pub fn is_uptodate(project_root: &Path, entry: &IndexEntry) -> Result<bool> {
+        let path = project_root.join(&entry.name);
+        if fs::symlink_metadata(&path).is_err() {
+            return Ok(true);
+        }
+        if entry.mode == FileMode::Commit as u32 || entry.is_stat_clean(&path) {
+            return Ok(true);
+        }
+        let Ok((mode, content)) = read_worktree_file(&path) else {
+            return Ok(false);
+        };
+        Ok(mode == entry.mode && hash_object::<Blob>(content)? == entry.hash)
+    }
+
+    /// -u: bring the worktree from `old` to `new`, paths left unmerged are not touched
+    pub fn checkout_index(gitdir: &Path, old: &Index, new: &mut Index) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let old_entries = old.entries.iter()
+            .map(|entry| (entry.name.as_path(), entry))
+            .collect::<BTreeMap<_, _>>();
+        let new_paths = new.entries.iter().map(|entry| entry.name.clone()).collect::<BTreeSet<_>>();
+
+        for entry in old.entries.iter().filter(|entry| !new_paths.contains(&entry.name)) {
+            let file_path = project_root.join(&entry.name);
+            if file_path.is_file() || file_path.is_symlink() {
+                fs::remove_file(&file_path)
+                    .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+            } else if entry.mode == FileMode::Commit as u32 {
+                // only a submodule that was never cloned goes, like git
+                let _ = fs::remove_dir(&file_path);
+            }
+            remove_empty_parents(project_root, &file_path);
+        }
+
+        for entry in new.entries.iter_mut().filter(|entry| entry.stage == 0) {
+            let unchanged = old_entries.get(entry.name.as_path())
+                .is_some_and(|old| old.mode == entry.mode && old.hash == entry.hash);
+            if unchanged {
+                continue;
+            }
+            // a submodule is an empty directory until it is cloned
+            if entry.mode == FileMode::Commit as u32 {
+                fs::create_dir_all(project_root.join(&entry.name))
+                    .map_err(|_| GitError::failed_to_write_file(&entry.name.display().to_string()))?;
+                continue;
+            }
+            Self::checkout_entry(gitdir, entry)?;
+        }
+        Ok(())
+    }
+
+    /// write the stage 0 `entry` into the worktree, replacing whatever is there, and take its stat data
+    pub fn checkout_entry(gitdir: &Path, entry: &mut IndexEntry) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let file_path = project_root.join(&entry.name);
+        if file_path.is_dir() && !file_path.is_symlink() {
+            fs::remove_dir_all(&file_path)
+                .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+        }
+        else if file_path.is_file() || file_path.is_symlink() {
+            fs::remove_file(&file_path)
+                .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+        }
+        let parent = file_path.parent().unwrap();
+        fs::create_dir_all(parent)
+            .map_err(|_| GitError::failed_to_write_file(&parent.to_string_lossy()))?;
+        if entry.mode == FileMode::Symbolic as u32 {
+            let content: Vec<u8> = read_object::<Blob>(gitdir.to_path_buf(), &entry.hash)?.into();
+            symlink(String::from_utf8_lossy(&content).as_ref(), &file_path)?;
+        }
+        else {
+            write_blob_to_file(gitdir, &entry.hash, &file_path)?;
+            let permissions = if entry.mode == FileMode::Exec as u32 { 0o755 } else { 0o644 };
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(permissions))?;
+        }
+        *entry = entry.clone().with_stat(&file_path);
+        Ok(())
+    }
+
+    fn run_merge(&self, gitdir: &Path) -> Result<()> {
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let old = Index::read_or_default(gitdir)?;
+        let trees = self.trees.iter()
+            .map(|tree| resolve_tree(gitdir, tree))
+            .collect::<Result<Vec<_>>>()?;
+        let trees = trees.iter().map(Some).collect::<Vec<_>>();
+
+        let TreeMerge { mut index, rejected } = Self::unpack_trees(gitdir, &old, &trees, self.update)?;
+        if !rejected.is_empty() {
+            let message = rejected.iter()
+                .map(|(rejection, path)| match rejection {
+                    Rejection::Untracked => format!("error: Untracked working tree file '{}' {}", path.display(), rejection),
+                    _ => format!("error: Entry '{}' {}", path.display(), rejection),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(GitError::merge_conflict(message));
+        }
+        if self.update {
+            Self::checkout_index(gitdir, &old, &mut index)?;
+        }
+        lock.commit(&index)?;
+        Ok(())
+    }
 }
 
-fn restore_tree_to_index(gitdir: &Path, tree_hash: &str, prefix: &str, index: &mut Index) -> Result<()> {
+fn restore_tree_to_index(gitdir: &Path, tree_hash: &ObjectId, prefix: &str, index: &mut Index) -> Result<()> {
     // 1. 读取 tree 对象内容
-    let tree_bytes = read_object_from_gitdir(gitdir, tree_hash)?;
-    let tree: Tree = tree_bytes.try_into()?;
+    let tree = read_object::<Tree>(gitdir.to_path_buf(), tree_hash)?;
 
     for entry in tree.0 {
         match entry.mode {
@@ -58,7 +355,7 @@ fn restore_tree_to_index(gitdir: &Path, tree_hash: &str, prefix: &str, index: &m
                 } else {
                     format!("{}/{}", prefix.trim_end_matches('/'), entry.path.display())
                 };
-                let index_entry = IndexEntry::new(entry.mode as u32, entry.hash.clone(), file_path);
+                let index_entry = IndexEntry::new(entry.mode as u32, entry.hash, file_path);
                 index.add_entry(index_entry);
             }
         }
@@ -66,49 +363,30 @@ fn restore_tree_to_index(gitdir: &Path, tree_hash: &str, prefix: &str, index: &m
     Ok(())
 }
 
-
-fn read_object_from_gitdir(gitdir: &Path, hash: &str) -> Result<Vec<u8>> {
-    let object_path = gitdir.join("objects").join(&hash[0..2]).join(&hash[2..]);
-    let decompressed = decompress_file_bytes(&object_path)?;
-    Ok(decompressed)
-}
-
 impl SubCommand for ReadTree {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        let mut index_path = gitdir.clone();
-        index_path.push("index");
-        if !index_path.exists() {
-            return Err(Box::new(GitError::InvalidCommand("Index file does not exist".to_string())));
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        if self.merge {
+            self.run_merge(&gitdir)?;
+            return Ok(0);
+        }
+        if self.trees.len() > 1 {
+            return Err(GitError::invalid_command("more than one tree needs -m".to_string()));
         }
+        let tree_hash = resolve_tree(&gitdir, &self.trees[0])?;
+
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
         let mut index = Index::new();
-        // index = index.read_from_file(&index_path).map_err(|_| {
-        //     GitError::InvalidCommand("Failed to read index file".to_string())
-        // })?;
-        // for entry in &index.entries {
-        //     println!("mode: {}, hash: {}, name: {}", entry.mode, entry.hash, entry.name);
-        // }
-        // let mode = 0o040000;
-        // let hash = self.tree_hash.clone();
-        // let name = self.prefix.clone();
-        // let entry = IndexEntry::new(mode, hash, name);
-        // index.add_entry(entry);
-        // index.write_to_file(&index_path).map_err(|_| {
-        //     GitError::InvalidCommand("Failed to write index file".to_string())
-        // })?;
-        // Ok(0)
         if let Some(prefix) = &self.prefix{
-            index = index.read_from_file(&index_path).map_err(|_| {
-                GitError::InvalidCommand("Failed to read index file".to_string())
+            index = Index::read_or_default(&gitdir).map_err(|_| {
+                GitError::invalid_command("Failed to read index file".to_string())
             })?;
-            restore_tree_to_index(&gitdir, &self.tree_hash, prefix, &mut index)?;
+            restore_tree_to_index(&gitdir, &tree_hash, prefix, &mut index)?;
         }
         else{
-            restore_tree_to_index(&gitdir, &self.tree_hash, "", &mut index)?;
+            restore_tree_to_index(&gitdir, &tree_hash, "", &mut index)?;
         }
-        index.write_to_file(&index_path).map_err(|_| {
-            GitError::InvalidCommand("Failed to write index file".to_string())
-        })?;
+        lock.commit(&index)?;
         Ok(0)
     }
 
@@ -117,10 +395,13 @@ impl SubCommand for ReadTree {
 
 #[cfg(test)]
 mod test {
+    use std::process::Command;
     use crate::utils::test::{
         shell_spawn,
         setup_test_git_dir,
         mktemp_in,
+        tempdir,
+        cp_dir,
     };
 
     #[test]
@@ -133,7 +414,7 @@ mod test {
         let file1_str = file1.to_str().unwrap();
         let file2 = mktemp_in(&temp).unwrap();
         let file2_str = file2.to_str().unwrap();
-        let _ = shell_spawn(&["git", "-C", temp_path_str, "update-index", "--add", &file1_str, &file2_str]).unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "update-index", "--add", file1_str, file2_str]).unwrap();
         let tree_hash = shell_spawn(&["git", "-C", temp_path_str, "write-tree"]).unwrap();
         let tree_hash = tree_hash.trim();
         let _ = shell_spawn(&["git", "-C", temp_path_str, "rm", "-r" ,"--cached", ":/"]).unwrap();
@@ -159,7 +440,7 @@ mod test {
         let file2_str = file2.to_str().unwrap();
         std::fs::write(&file1, "content1").unwrap();
         std::fs::write(&file2, "content2").unwrap();
-        let _ = shell_spawn(&["git", "-C", temp_path_str, "update-index", "--add", &file1_str, &file2_str]).unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path_str, "update-index", "--add", file1_str, file2_str]).unwrap();
 
         // 写入 tree
         let tree_hash = shell_spawn(&["git", "-C", temp_path_str, "write-tree"]).unwrap();
@@ -177,4 +458,96 @@ mod test {
         assert!(out.contains(file1.file_name().unwrap().to_str().unwrap()));
         assert!(out.contains(file2.file_name().unwrap().to_str().unwrap()));
     }
+
+    #[test]
+    fn test_read_tree_merge() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat()).unwrap();
+        let write = |name: &str, content: &str| {
+            let path = temp_path1.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+
+        // base, then two sides touching the same and different paths
+        for name in ["same.txt", "ours.txt", "theirs.txt", "both.txt", "both_same.txt", "del_ours.txt", "del_theirs.txt", "dir/keep.txt"] {
+            write(name, name);
+        }
+        git(&["add", "."]);
+        git(&["commit", "-m", "base"]);
+        git(&["tag", "base"]);
+        write("ours.txt", "ours");
+        write("both.txt", "ours");
+        write("both_same.txt", "same change");
+        write("new_ours.txt", "ours");
+        write("added.txt", "ours");
+        std::fs::remove_file(temp_path1.join("del_ours.txt")).unwrap();
+        git(&["add", "-A"]);
+        git(&["commit", "-m", "ours"]);
+        git(&["checkout", "-q", "-b", "topic", "base"]);
+        write("theirs.txt", "theirs");
+        write("both.txt", "theirs");
+        write("both_same.txt", "same change");
+        write("dir/new_theirs.txt", "theirs");
+        write("added.txt", "theirs");
+        std::fs::remove_file(temp_path1.join("del_theirs.txt")).unwrap();
+        git(&["add", "-A"]);
+        git(&["commit", "-m", "theirs"]);
+        git(&["checkout", "-q", "master"]);
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        // each arity from the same starting index, the two-way merge keeps a staged change,
+        // the three-way one needs an index matching ours
+        let cases: &[(&[&str], bool)] = &[
+            (&["-m", "topic"], true),
+            (&["-m", "master", "topic"], true),
+            (&["-m", "base", "master", "topic"], false),
+            (&["-m", "-u", "master", "topic"], true),
+        ];
+        for (args, staged) in cases {
+            for path in [temp_path1, temp_path2] {
+                let path = path.to_str().unwrap();
+                shell_spawn(&["git", "-C", path, "reset", "-q", "--hard", "master"]).unwrap();
+                if *staged {
+                    std::fs::write(format!("{}/same.txt", path), "staged").unwrap();
+                    shell_spawn(&["git", "-C", path, "add", "same.txt"]).unwrap();
+                }
+            }
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str1, "read-tree"][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2, "read-tree"][..], args].concat()).unwrap();
+            assert_eq!(origin, real);
+            let origin = shell_spawn(&["git", "-C", temp_path_str1, "ls-files", "--stage"]).unwrap();
+            let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+            let origin = shell_spawn(&["git", "-C", temp_path_str1, "status", "--porcelain"]).unwrap();
+            let real = shell_spawn(&["git", "-C", temp_path_str2, "status", "--porcelain"]).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+
+        // the same refusals as git: a staged change to a path the switch replaces, a dirty worktree file
+        for path in [temp_path1, temp_path2] {
+            let path = path.to_str().unwrap();
+            shell_spawn(&["git", "-C", path, "reset", "-q", "--hard", "master"]).unwrap();
+            std::fs::write(format!("{}/theirs.txt", path), "dirty").unwrap();
+        }
+        let stderr = || {
+            let output = Command::new("cargo")
+                .args(["run", "--quiet", "--", "-C", temp_path_str2, "read-tree", "-m", "master", "topic"])
+                .output()
+                .unwrap();
+            assert!(!output.status.success());
+            String::from_utf8_lossy(&output.stderr).into_owned()
+        };
+        assert!(shell_spawn(&["git", "-C", temp_path_str1, "read-tree", "-m", "master", "topic"]).is_err());
+        assert_eq!(stderr(), "error: Entry 'theirs.txt' not uptodate. Cannot merge.\n");
+        shell_spawn(&["git", "-C", temp_path_str2, "add", "theirs.txt"]).unwrap();
+        assert_eq!(stderr(), "error: Entry 'theirs.txt' would be overwritten by merge. Cannot merge.\n");
+        assert!(shell_spawn(&["git", "-C", temp_path_str2, "diff", "--cached", "--quiet", "master", "--", ":!theirs.txt"]).is_ok());
+    }
 }
@@ -0,0 +1,376 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::{
+        Checkout, CherryPick, Merge, Reset,
+        cherry_pick::Replay,
+        status::{Changes, Status},
+    },
+    utils::{
+        commit::Commit,
+        diff::{changed_paths, flatten_tree, Files},
+        fs::{read_object, work_tree, write_object},
+        hash::ObjectId,
+        index::Index,
+        refs::{
+            head_to_hash,
+            read_head_ref,
+            read_ref_commit,
+            write_head_commit,
+            write_head_commit_with_log,
+            write_head_ref,
+            write_head_ref_with_log,
+            write_ref_commit,
+            write_ref_commit_with_log,
+        },
+        revparse::resolve_commit,
+        revwalk,
+        tree::Tree,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// where a rebase in progress keeps its state, the same layout as git's merge backend
+const STATE_DIR: &str = "rebase-merge";
+
+#[derive(Parser, Debug)]
+#[command(name = "rebase", about = "Reapply commits on top of another base tip")]
+pub struct Rebase {
+    #[arg(long = "continue", conflicts_with_all = ["abort", "upstream"], help = "go on after the conflicts of the stopped commit are resolved")]
+    resume: bool,
+
+    #[arg(long, conflicts_with = "upstream", help = "give up and go back to the branch as it was")]
+    abort: bool,
+
+    #[arg(required_unless_present_any = ["resume", "abort"], help = "the commit to replay the current branch onto")]
+    upstream: Option<String>,
+}
+
+impl Rebase {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Rebase::try_parse_from(args)?))
+    }
+
+    fn read_state(gitdir: &Path, name: &str) -> Result<String> {
+        let path = gitdir.join(STATE_DIR).join(name);
+        fs::read_to_string(&path)
+            .map(|content| content.trim_end().to_string())
+            .map_err(|_| GitError::file_notfound(path.display().to_string()))
+    }
+
+    fn write_state(gitdir: &Path, name: &str, content: &str) -> Result<()> {
+        let path = gitdir.join(STATE_DIR).join(name);
+        fs::write(&path, content).map_err(|_| GitError::failed_to_write_file(&path.to_string_lossy()))
+    }
+
+    /// git refuses to start with local changes, they would end up mixed into the replayed commits
+    /// `action` names the command in the message, `rebase` or `pull with rebase`
+    pub fn check_clean(gitdir: &Path, head: &ObjectId, action: &str) -> Result<()> {
+        let index = Index::read_or_default(gitdir)?;
+        let mut unstaged = Changes::new();
+        Status::unstaged(&work_tree(gitdir)?, &index, &mut unstaged)?;
+        if !unstaged.is_empty() {
+            return Err(GitError::invalid_command(format!("cannot {}: You have unstaged changes.\nPlease commit or stash them.", action)));
+        }
+        let staged = index.entries.iter()
+            .map(|entry| (entry.name.clone(), (entry.mode, entry.hash)))
+            .collect::<Files>();
+        let head_tree = read_object::<Commit>(gitdir.to_path_buf(), head)?.tree_hash;
+        if index.entries.iter().any(|entry| entry.stage != 0) || !changed_paths(&flatten_tree(gitdir, &head_tree)?, &staged).is_empty() {
+            return Err(GitError::invalid_command(format!("cannot {}: Your index contains uncommitted changes.\nPlease commit or stash them.", action)));
+        }
+        Ok(())
+    }
+
+    /// commits reachable from `head` but not from `onto`, parents before children; merges are left out like git does
+    fn commits_to_replay(gitdir: &Path, head: &ObjectId, onto: &ObjectId) -> Result<Vec<ObjectId>> {
+        let mut cache = HashMap::new();
+        let upstream = revwalk::reachable(gitdir, vec![*onto], &mut cache)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+        // iterative post order walk: a commit is emitted once all its parents are
+        let mut stack = vec![(*head, false)];
+        while let Some((hash, expanded)) = stack.pop() {
+            if expanded {
+                order.push(hash);
+                continue;
+            }
+            if upstream.contains(&hash) || !seen.insert(hash) {
+                continue;
+            }
+            stack.push((hash, true));
+            for parent in revwalk::parents(gitdir, &hash, &mut cache)?.into_iter().rev() {
+                stack.push((parent, false));
+            }
+        }
+        Ok(order.into_iter().filter(|hash| cache[hash].parent_hash.len() < 2).collect())
+    }
+
+    fn start(gitdir: &Path, upstream: &str) -> Result<i32> {
+        if gitdir.join(STATE_DIR).exists() {
+            return Err(GitError::invalid_command(format!(
+                "It seems that there is already a {} directory, try 'git rebase --continue' or 'git rebase --abort'.", STATE_DIR
+            )));
+        }
+        let onto = resolve_commit(gitdir, upstream)?;
+        let head = head_to_hash(gitdir)
+            .map_err(|_| GitError::invalid_command("can not rebase an unborn branch".to_string()))?;
+        let head_name = read_head_ref(gitdir).unwrap_or_else(|_| "detached HEAD".to_string());
+        Self::check_clean(gitdir, &head, "rebase")?;
+
+        if revwalk::ancestors(gitdir, &head)?.contains(&onto) {
+            println!("Current branch {} is up to date.", head_name.strip_prefix("refs/heads/").unwrap_or(&head_name));
+            return Ok(0);
+        }
+        let commits = Self::commits_to_replay(gitdir, &head, &onto)?;
+        let mut todo = String::new();
+        for hash in &commits {
+            let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)?;
+            todo += &format!("pick {} {}\n", hash, commit.message.lines().next().unwrap_or_default());
+        }
+
+        let state_dir = gitdir.join(STATE_DIR);
+        fs::create_dir_all(&state_dir).map_err(|_| GitError::failed_to_write_file(&state_dir.to_string_lossy()))?;
+        Self::write_state(gitdir, "head-name", &format!("{}\n", head_name))?;
+        Self::write_state(gitdir, "onto", &format!("{}\n", onto))?;
+        Self::write_state(gitdir, "orig-head", &format!("{}\n", head))?;
+        Self::write_state(gitdir, "git-rebase-todo", &todo)?;
+        Self::write_state(gitdir, "done", "")?;
+        write_ref_commit(gitdir, "ORIG_HEAD", &head)?;
+
+        // the commits are replayed on a detached HEAD, the branch only moves once all of them are done
+        Checkout::switch_worktree(gitdir, Some(&head), &onto)?;
+        write_head_commit(gitdir, &head)?;
+        write_head_commit_with_log(gitdir, &onto, &format!("rebase (start): checkout {}", upstream))?;
+        Self::run_todo(gitdir)
+    }
+
+    /// pick the commits left in the todo list one by one, stopping at the first conflict
+    fn run_todo(gitdir: &Path) -> Result<i32> {
+        loop {
+            let todo = Self::read_state(gitdir, "git-rebase-todo")?;
+            let mut lines = todo.lines().filter(|line| !line.is_empty());
+            let Some(line) = lines.next() else {
+                break;
+            };
+            let rest = lines.map(|line| format!("{}\n", line)).collect::<String>();
+            // done before it is picked: a conflict leaves the commit to --continue
+            Self::write_state(gitdir, "git-rebase-todo", &rest)?;
+            let done = Self::read_state(gitdir, "done").unwrap_or_default();
+            Self::write_state(gitdir, "done", &format!("{}{}\n", if done.is_empty() { String::new() } else { format!("{}\n", done) }, line))?;
+
+            let hash = line.split_whitespace().nth(1)
+                .and_then(|hash| ObjectId::from_hex(hash).ok())
+                .ok_or_else(|| GitError::invalid_command(format!("bad todo line: {}", line)))?;
+            Self::pick(gitdir, &hash)?;
+        }
+        Self::finish(gitdir)
+    }
+
+    fn pick(gitdir: &Path, hash: &ObjectId) -> Result<()> {
+        let picked = read_object::<Commit>(gitdir.to_path_buf(), hash)?;
+        let base_tree = match picked.parent_hash.first() {
+            Some(parent) => read_object::<Commit>(gitdir.to_path_buf(), parent)?.tree_hash,
+            None => write_object::<Tree>(gitdir.to_path_buf(), Vec::new())?,
+        };
+        let head = head_to_hash(gitdir)?;
+        Replay {
+            action: "rebase (pick)",
+            state_ref: "REBASE_HEAD",
+            label: CherryPick::label(hash, &picked),
+            hash: *hash,
+            base_tree,
+            tree: picked.tree_hash,
+            message: picked.message,
+            author: picked.author,
+        }.apply(gitdir, false)?;
+        Self::drop_if_empty(gitdir, &head)
+    }
+
+    /// a commit whose changes are already upstream leaves nothing behind, like git it is dropped
+    fn drop_if_empty(gitdir: &Path, parent: &ObjectId) -> Result<()> {
+        let new = head_to_hash(gitdir)?;
+        let tree = |hash: &ObjectId| read_object::<Commit>(gitdir.to_path_buf(), hash).map(|commit| commit.tree_hash);
+        if new != *parent && tree(&new)? == tree(parent)? {
+            write_head_commit(gitdir, parent)?;
+        }
+        Ok(())
+    }
+
+    /// the stopped commit is resolved in the index: commit it, then go on with the rest
+    fn resume(gitdir: &Path) -> Result<i32> {
+        if !gitdir.join(STATE_DIR).exists() {
+            return Err(GitError::invalid_command("No rebase in progress?".to_string()));
+        }
+        if !Index::read_or_default(gitdir)?.unmerged_paths().is_empty() {
+            return Err(GitError::merge_conflict("Committing is not possible because you have unmerged files.".to_string()));
+        }
+        if let Ok(stopped) = read_ref_commit(gitdir, "REBASE_HEAD") {
+            let head = head_to_hash(gitdir)?;
+            let picked = read_object::<Commit>(gitdir.to_path_buf(), &stopped)?;
+            let message = fs::read_to_string(gitdir.join("MERGE_MSG")).unwrap_or(picked.message);
+            Replay::commit(gitdir, "rebase (continue)", head, picked.author, &message)?;
+            Self::drop_if_empty(gitdir, &head)?;
+        }
+        Self::run_todo(gitdir)
+    }
+
+    /// move the branch to the replayed commits and put HEAD back on it
+    fn finish(gitdir: &Path) -> Result<i32> {
+        let head_name = Self::read_state(gitdir, "head-name")?;
+        let onto = Self::read_state(gitdir, "onto")?;
+        let new = head_to_hash(gitdir)?;
+        if head_name.starts_with("refs/") {
+            write_ref_commit_with_log(gitdir, &head_name, &new, &format!("rebase (finish): {} onto {}", head_name, onto))?;
+            write_head_ref_with_log(gitdir, &head_name, &format!("rebase (finish): returning to {}", head_name))?;
+        }
+        write_ref_commit(gitdir, "ORIG_HEAD", &ObjectId::from_hex(&Self::read_state(gitdir, "orig-head")?)?)?;
+        Self::remove_state(gitdir)?;
+        println!("Successfully rebased and updated {}.", head_name);
+        Ok(0)
+    }
+
+    /// back to the branch and the worktree as they were before the rebase
+    fn abort(gitdir: &Path) -> Result<i32> {
+        if !gitdir.join(STATE_DIR).exists() {
+            return Err(GitError::invalid_command("No rebase in progress?".to_string()));
+        }
+        let head_name = Self::read_state(gitdir, "head-name")?;
+        let orig_head = ObjectId::from_hex(&Self::read_state(gitdir, "orig-head")?)?;
+        // the branch never moved, so HEAD on it and a hard reset restore everything
+        match head_name.starts_with("refs/") {
+            true => write_head_ref(gitdir, &head_name)?,
+            false => write_head_commit(gitdir, &orig_head)?,
+        }
+        Reset::from_internal(&orig_head).run(Ok(Repository::at(gitdir)))?;
+        Merge::clear_merge_state(gitdir)?;
+        Self::remove_state(gitdir)?;
+        Ok(0)
+    }
+
+    fn remove_state(gitdir: &Path) -> Result<()> {
+        let state_dir: PathBuf = gitdir.join(STATE_DIR);
+        fs::remove_dir_all(&state_dir).map_err(|_| GitError::failed_to_remove_file(state_dir.display().to_string()))
+    }
+}
+
+impl SubCommand for Rebase {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        match &self.upstream {
+            _ if self.abort => Self::abort(&gitdir),
+            _ if self.resume => Self::resume(&gitdir),
+            Some(upstream) => Self::start(&gitdir, upstream),
+            None => unreachable!("clap requires an upstream without --continue or --abort"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_rebase() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let git1 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat());
+        let git2 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat());
+        // a fixed committer date, so the replayed commits get the same hashes as git's
+        let rebase = |dir: &str, bin: &[&str], args: &[&str]| Command::new(bin[0]).args(&bin[1..])
+            .args(["-C", dir, "rebase"])
+            .args(args)
+            // git would open an editor for the message on --continue
+            .env("GIT_EDITOR", "true")
+            .env("GIT_COMMITTER_DATE", "1600000000 +0800")
+            .output()
+            .unwrap()
+            .status
+            .success();
+        let both = |args: &[&str]| {
+            let ours = rebase(temp_path_str2, &["cargo", "run", "--quiet", "--"], args);
+            let theirs = rebase(temp_path_str1, &["git"], args);
+            assert_eq!(ours, theirs, "{:?}", args);
+            for query in [&["log", "--format=%H %an %s", "--all"][..], &["status", "--porcelain"], &["ls-files", "-s"], &["symbolic-ref", "-q", "HEAD"]] {
+                assert_eq!(git1(query).unwrap_or_default(), git2(query).unwrap_or_default(), "{:?} {:?}", args, query);
+            }
+            ours
+        };
+
+        std::fs::write(temp_path1.join("f.txt"), "1\n2\n3\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "base"]).unwrap();
+        git1(&["checkout", "-b", "feature"]).unwrap();
+        std::fs::write(temp_path1.join("feature.txt"), "feature\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "feature one"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "1\n2\nfeature\n").unwrap();
+        git1(&["commit", "-am", "feature two"]).unwrap();
+        std::fs::write(temp_path1.join("up.txt"), "up\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "already upstream"]).unwrap();
+        git1(&["checkout", "master"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "one\n2\n3\n").unwrap();
+        git1(&["commit", "-am", "master one"]).unwrap();
+        std::fs::write(temp_path1.join("up.txt"), "up\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "same change upstream"]).unwrap();
+        git1(&["checkout", "feature"]).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        // no conflict, the commit whose change is upstream already is dropped
+        assert!(both(&["master"]));
+        assert!(both(&["master"]));
+        assert!(!temp_path2.join(".git/rebase-merge").exists());
+        assert_eq!(git2(&["rev-parse", "ORIG_HEAD"]).unwrap(), git1(&["rev-parse", "ORIG_HEAD"]).unwrap());
+
+        // local changes are refused
+        std::fs::write(temp_path2.join("f.txt"), "dirty\n").unwrap();
+        assert!(!rebase(temp_path_str2, &["cargo", "run", "--quiet", "--"], &["master~1"]));
+        git2(&["checkout", "f.txt"]).unwrap();
+
+        // a conflict stops the rebase, --abort goes back, --continue finishes after the fix
+        for temp_path in [temp_path1, temp_path2] {
+            let dir = temp_path.to_str().unwrap();
+            shell_spawn(&["git", "-C", dir, "checkout", "-b", "other", "master~2"]).unwrap();
+            std::fs::write(temp_path.join("f.txt"), "1\n2\nother\n").unwrap();
+            shell_spawn(&["git", "-C", dir, "commit", "-am", "other"]).unwrap();
+            std::fs::write(temp_path.join("other.txt"), "other\n").unwrap();
+            shell_spawn(&["git", "-C", dir, "add", "."]).unwrap();
+            shell_spawn(&["git", "-C", dir, "commit", "-m", "other two"]).unwrap();
+        }
+        assert!(!both(&["feature"]));
+        assert!(temp_path2.join(".git/rebase-merge").exists());
+        assert!(!both(&["--continue"]));
+        assert!(both(&["--abort"]));
+        assert!(!temp_path2.join(".git/rebase-merge").exists());
+
+        assert!(!both(&["feature"]));
+        for temp_path in [temp_path1, temp_path2] {
+            std::fs::write(temp_path.join("f.txt"), "one\n2\nresolved\n").unwrap();
+            shell_spawn(&["git", "-C", temp_path.to_str().unwrap(), "add", "f.txt"]).unwrap();
+        }
+        assert!(both(&["--continue"]));
+        assert_eq!(std::fs::read_to_string(temp_path2.join("other.txt")).unwrap(), "other\n");
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    utils::{
+        hash::ObjectId,
+        ident::split_ident,
+        refs::{for_each_ref, head_to_hash, list_reflogs, read_reflog, read_ref_commit, reflog_name, write_reflog},
+        revwalk,
+    },
+    GitError,
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// git's gc.reflogExpire and gc.reflogExpireUnreachable defaults, in days
+const EXPIRE_DAYS: i64 = 90;
+const EXPIRE_UNREACHABLE_DAYS: i64 = 30;
+
+#[derive(Parser, Debug)]
+#[command(name = "reflog", about = "Show the history of where HEAD and branches have been", args_conflicts_with_subcommands = true)]
+pub struct Reflog {
+    #[command(subcommand)]
+    command: Option<ReflogCommand>,
+
+    #[arg(help = "the ref whose log to show, HEAD by default")]
+    name: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ReflogCommand {
+    /// show the log of a ref, newest first (default)
+    Show {
+        name: Option<String>,
+    },
+    /// drop the entries older than a point in time
+    Expire {
+        /// entries older than this go, `90.days.ago` by default, `now`/`all` or `never`
+        #[arg(long, value_name = "time")]
+        expire: Option<String>,
+        /// entries older than this go unless the ref still reaches them, `30.days.ago` by default
+        #[arg(long = "expire-unreachable", value_name = "time")]
+        expire_unreachable: Option<String>,
+        /// every reflog there is
+        #[arg(long)]
+        all: bool,
+        refs: Vec<String>,
+    },
+}
+
+impl Reflog {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Reflog::try_parse_from(args)?))
+    }
+
+    fn show(gitdir: &Path, name: &str) -> Result<i32> {
+        let refname = reflog_name(gitdir, name).ok_or_else(|| GitError::unknown_revision(name))?;
+        // newest first, HEAD@{0} is the current value
+        for (i, entry) in read_reflog(gitdir, &refname)?.iter().rev().enumerate() {
+            println!("{} {}@{{{}}}: {}", &entry.new.to_hex()[..7], name, i, entry.message);
+        }
+        Ok(0)
+    }
+
+    /// `now`, `never`, a unix time with `@`, or a relative `<n>.<unit>.ago` as git's approxidate reads them
+    fn parse_time(value: &str, now: i64) -> Option<i64> {
+        match value {
+            "now" | "all" => return Some(now),
+            "never" | "false" => return Some(i64::MIN),
+            _ => {},
+        }
+        if let Some(timestamp) = value.strip_prefix('@') {
+            return timestamp.parse().ok();
+        }
+        let words = value.split(['.', ' ']).filter(|word| !word.is_empty()).collect::<Vec<_>>();
+        let [count, unit, "ago"] = words.as_slice() else { return None };
+        let seconds = match unit.trim_end_matches('s') {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => return None,
+        };
+        Some(now - count.parse::<i64>().ok()? * seconds)
+    }
+
+    /// drop what is older than `expire`, and what is older than `unreachable` and no longer reachable from the ref;
+    /// HEAD counts everything any ref reaches
+    fn expire(gitdir: &Path, refname: &str, expire: i64, unreachable: i64) -> Result<()> {
+        let tips = match refname {
+            "HEAD" => for_each_ref(gitdir, "refs/")?.into_iter().map(|(_, hash)| hash).chain(head_to_hash(gitdir).ok()).collect(),
+            _ => read_ref_commit(gitdir, refname).into_iter().collect::<Vec<_>>(),
+        };
+        let mut cache = HashMap::new();
+        let reachable = revwalk::reachable(gitdir, tips, &mut cache)?.into_iter().collect::<HashSet<_>>();
+        let is_reachable = |hash: &ObjectId| *hash == ObjectId::null() || reachable.contains(hash);
+
+        let entries = read_reflog(gitdir, refname)?;
+        let kept = entries.into_iter()
+            .filter(|entry| {
+                let time = split_ident(&entry.committer).map(|(_, time, _)| time).unwrap_or_default();
+                time >= expire && (time >= unreachable || (is_reachable(&entry.old) && is_reachable(&entry.new)))
+            })
+            .collect::<Vec<_>>();
+        write_reflog(gitdir, refname, &kept)
+    }
+}
+
+impl SubCommand for Reflog {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let (expire, expire_unreachable, all, refs) = match &self.command {
+            None => return Self::show(&gitdir, self.name.as_deref().unwrap_or("HEAD")),
+            Some(ReflogCommand::Show { name }) => return Self::show(&gitdir, name.as_deref().unwrap_or("HEAD")),
+            Some(ReflogCommand::Expire { expire, expire_unreachable, all, refs }) => (expire, expire_unreachable, *all, refs),
+        };
+
+        let now = chrono::Local::now().timestamp();
+        let day = 24 * 60 * 60;
+        let mut times = Vec::new();
+        for (option, value, days) in [("--expire", expire, EXPIRE_DAYS), ("--expire-unreachable", expire_unreachable, EXPIRE_UNREACHABLE_DAYS)] {
+            match value {
+                Some(value) => match Self::parse_time(value, now) {
+                    Some(time) => times.push(time),
+                    None => return Err(GitError::invalid_argument(format!("invalid timestamp '{}' given to '{}'", value, option))),
+                },
+                None => times.push(now - days * day),
+            }
+        }
+        // whatever is too old to keep at all is too old to keep unreachable
+        let (expire, unreachable) = (times[0], times[1].max(times[0]));
+
+        let mut names = Vec::new();
+        if all {
+            names = list_reflogs(&gitdir)?;
+        }
+        for name in refs {
+            match reflog_name(&gitdir, name) {
+                Some(refname) => names.push(refname),
+                None => {
+                    eprintln!("error: {} points nowhere!", name);
+                    return Ok(1);
+                },
+            }
+        }
+        for refname in names {
+            Self::expire(&gitdir, &refname, expire, unreachable)?;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, process::Command};
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        tempdir,
+        cp_dir,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_reflog() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let git = &["git", "-C", temp_path_str1];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        // the same steps in both repos, files are written in between
+        let steps: &[(&str, ArgsList)] = &[
+            ("a.txt", &[(&["add", "a.txt"], false), (&["commit", "-m", "first"], false)]),
+            ("b.txt", &[(&["add", "b.txt"], false), (&["commit", "-m", "second\n\nbody"], false), (&["checkout", "-b", "topic"], false)]),
+            ("c.txt", &[(&["add", "c.txt"], false), (&["commit", "-m", "third"], false), (&["checkout", "master"], false)]),
+            ("", &[(&["merge", "topic"], false), (&["reset", "--hard", "HEAD~1"], false), (&["update-ref", "-m", "manual", "refs/heads/topic", "HEAD"], false)]),
+        ];
+        for (file, cmds) in steps {
+            if !file.is_empty() {
+                std::fs::write(temp_path1.join(file), file).unwrap();
+                std::fs::write(temp_path2.join(file), file).unwrap();
+            }
+            cmd_seq(cmds)(git).unwrap();
+            cmd_seq(cmds)(cargo).unwrap();
+        }
+
+        // real git reads the logs we wrote, hashes may differ with the commit time so only compare messages
+        for name in ["HEAD", "master", "topic"] {
+            let args = ["reflog", "show", "--format=%gd %gs", name];
+            let origin = shell_spawn(&[&git[..], &args].concat()).unwrap();
+            let real = shell_spawn(&[&["git", "-C", temp_path_str2][..], &args].concat()).unwrap();
+            assert_eq!(origin, real, "{}", name);
+        }
+
+        // our own output matches git's on the same repo
+        for args in [&["reflog"][..], &["reflog", "show", "topic"]] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&cargo[..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+
+        // HEAD@{n} finds the commit we reset away from
+        let origin = shell_spawn(&["git", "-C", temp_path_str2, "rev-parse", "HEAD@{1}"]).unwrap();
+        let real = shell_spawn(&[&cargo[..], &["rev-parse", "HEAD@{1}"]].concat()).unwrap();
+        assert_eq!(origin, real);
+    }
+
+    #[test]
+    fn test_reflog_expire() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let day = 24 * 60 * 60;
+        let now = chrono::Local::now().timestamp();
+        // every step some days ago, the reflogs get the same dates
+        let git_at = |days: i64, args: &[&str]| {
+            let date = format!("{} +0000", now - days * day);
+            let status = Command::new("git").arg("-C").arg(temp_path_str).args(args)
+                .env("GIT_AUTHOR_DATE", &date)
+                .env("GIT_COMMITTER_DATE", &date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", args);
+        };
+        git_at(120, &["commit", "-q", "--allow-empty", "-m", "old"]);
+        git_at(70, &["commit", "-q", "--allow-empty", "-m", "older than the unreachable limit"]);
+        git_at(60, &["checkout", "-q", "-b", "topic"]);
+        git_at(45, &["commit", "-q", "--allow-empty", "-m", "thrown away"]);
+        git_at(40, &["reset", "-q", "--hard", "HEAD~1"]);
+        git_at(20, &["commit", "-q", "--allow-empty", "-m", "recent"]);
+        git_at(10, &["checkout", "-q", "master"]);
+        git_at(5, &["commit", "-q", "--allow-empty", "-m", "newest"]);
+
+        let logs = |dir: &Path| ["HEAD", "refs/heads/master", "refs/heads/topic"]
+            .map(|name| std::fs::read_to_string(dir.join(".git/logs").join(name)).unwrap());
+        for args in [
+            &["--all"][..],
+            &["--expire=60.days.ago", "--all"],
+            &["--expire-unreachable=now", "--all"],
+            &["--expire=never", "--expire-unreachable=never", "--all"],
+            &["--expire=50.days.ago", "topic"],
+            &["--expire=now", "master", "HEAD"],
+        ] {
+            let (ours, theirs) = (tempdir().unwrap(), tempdir().unwrap());
+            cp_dir(temp_path, ours.path()).unwrap();
+            cp_dir(temp_path, theirs.path()).unwrap();
+            shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", ours.path().to_str().unwrap(), "reflog", "expire"][..], args].concat()).unwrap();
+            shell_spawn(&[&["git", "-C", theirs.path().to_str().unwrap(), "reflog", "expire"][..], args].concat()).unwrap();
+            assert_eq!(logs(ours.path()), logs(theirs.path()), "{:?}", args);
+        }
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "reflog", "expire", "--expire=bogus", "--all"]).is_err());
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "reflog", "expire", "nosuch"]).is_err());
+    }
+
+    #[test]
+    fn test_reflog_skips_bad_lines() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = &["git", "-C", temp_path_str];
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "one"], false),
+            (&["commit", "--allow-empty", "-m", "two"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let head = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD"]).unwrap();
+        let log = temp_path.join(".git/logs/HEAD");
+        let mut content = std::fs::read_to_string(&log).unwrap();
+        content.push_str("0 bad junk\tmsg\n");
+        content.push_str(&format!("{} {} a <b> 1 +0000\tmore\n", "z".repeat(40), head.trim()));
+        content.push_str("no hashes at all\n");
+        std::fs::write(&log, content).unwrap();
+
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "reflog"]).unwrap();
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "reflog"]).unwrap();
+        assert_eq!(origin, real);
+        assert_eq!(real.lines().count(), 2);
+    }
+}
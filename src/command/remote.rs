@@ -1,8 +1,9 @@
-use std::path::PathBuf;
+use std::path::Path;
 use std::fs;
 use clap::{Parser, Subcommand};
-use crate::{GitError, Result};
-use super::SubCommand;
+use crate::{GitError, Result, utils::{config::Config, fs::common_dir, refs::{ref_file, write_ref_file}}};
+use super::{SubCommand, Fetch};
+use crate::utils::fs::Repository;
 
 #[derive(Parser, Debug)]
 #[command(name = "remote", about = "管理远程仓库")]
@@ -34,6 +35,18 @@ enum RemoteCommand {
         /// 远程仓库名称（可选）
         name: Option<String>,
     },
+    /// 重命名远程仓库，远程跟踪分支一起移动
+    Rename {
+        /// 原来的名称
+        old: String,
+        /// 新的名称
+        new: String,
+    },
+    /// 删除远程已经没有的分支对应的远程跟踪分支
+    Prune {
+        /// 远程仓库名称
+        name: String,
+    },
     /// 设置远程仓库URL
     SetUrl {
         /// 远程仓库名称
@@ -48,208 +61,158 @@ impl Remote {
         Ok(Box::new(Remote::try_parse_from(args)?))
     }
     
-    /// 读取配置文件
-    fn read_config(&self, gitdir: &PathBuf) -> Result<String> {
-        let config_path = gitdir.join("config");
-        if config_path.exists() {
-            Ok(fs::read_to_string(config_path)?)
-        } else {
-            // 如果配置文件不存在，创建基本配置
-            Ok(String::from("[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = false\n\tlogallrefupdates = true\n"))
-        }
-    }
-    
-    /// 写入配置文件
-    fn write_config(&self, gitdir: &PathBuf, content: &str) -> Result<()> {
-        let config_path = gitdir.join("config");
-        fs::write(config_path, content)?;
-        Ok(())
-    }
-    
     /// 添加远程仓库
-    fn add_remote(&self, gitdir: &PathBuf, name: &str, url: &str) -> Result<()> {
-        let mut config = self.read_config(gitdir)?;
-        
+    fn add_remote(&self, gitdir: &Path, name: &str, url: &str) -> Result<()> {
         // 检查远程仓库是否已存在
-        if config.contains(&format!("[remote \"{}\"]", name)) {
+        if Self::exists(&Config::read(gitdir)?, name) {
             return Err(GitError::invalid_command(format!("Remote '{}' already exists", name)));
         }
-        
-        // 添加远程仓库配置
-        let remote_config = format!(
-            "\n[remote \"{}\"]\n\turl = {}\n\tfetch = +refs/heads/*:refs/remotes/{}/*\n",
-            name, url, name
-        );
-        
-        config.push_str(&remote_config);
-        self.write_config(gitdir, &config)?;
-        
+
+        Config::set(gitdir, &format!("remote.{}.url", name), url)?;
+        Config::add(gitdir, &format!("remote.{}.fetch", name), &format!("+refs/heads/*:refs/remotes/{}/*", name))?;
+
         println!("Added remote '{}' -> {}", name, url);
         Ok(())
     }
-    
+
+    /// `[remote "<name>"]` 里有任何一个键就算存在
+    fn exists(config: &Config, name: &str) -> bool {
+        config.subsections("remote").iter().any(|remote| remote == name)
+    }
+
     /// 删除远程仓库
-    fn remove_remote(&self, gitdir: &PathBuf, name: &str) -> Result<()> {
-        let config = self.read_config(gitdir)?;
-        let lines: Vec<&str> = config.lines().collect();
-        let mut new_lines = Vec::new();
-        let mut in_remote_section = false;
-        let mut found_remote = false;
-        
-        for line in lines {
-            let trimmed = line.trim();
-            
-            if trimmed == &format!("[remote \"{}\"]", name) {
-                in_remote_section = true;
-                found_remote = true;
-                continue; // 跳过这一行
-            }
-            
-            if trimmed.starts_with('[') && trimmed.ends_with(']') && in_remote_section {
-                in_remote_section = false;
-            }
-            
-            if !in_remote_section {
-                new_lines.push(line.to_string());
-            }
-        }
-        
-        if !found_remote {
+    fn remove_remote(&self, gitdir: &Path, name: &str) -> Result<()> {
+        if !Self::exists(&Config::read(gitdir)?, name) {
             return Err(GitError::invalid_command(format!("Remote '{}' not found", name)));
         }
-        
-        let new_config = new_lines.join("\n");
-        self.write_config(gitdir, &new_config)?;
-        
+        Config::remove_section(gitdir, &format!("remote.{}", name))?;
+
         // 删除远程跟踪分支
-        let remote_refs_path = gitdir.join("refs").join("remotes").join(name);
+        let remote_refs_path = ref_file(gitdir, &format!("refs/remotes/{}", name));
         if remote_refs_path.exists() {
             fs::remove_dir_all(&remote_refs_path)?;
         }
-        
+
         println!("Removed remote '{}'", name);
         Ok(())
     }
-    
+
     /// 显示远程仓库
-    fn show_remotes(&self, gitdir: &PathBuf, name: Option<&str>) -> Result<()> {
-        let config = self.read_config(gitdir)?;
-        let mut remotes = Vec::new();
-        let mut current_remote = None;
-        let mut current_url = None;
-        
-        for line in config.lines() {
-            let trimmed = line.trim();
-            
-            // 匹配远程仓库section
-            if let Some(start) = trimmed.find("[remote \"") {
-                if let Some(end) = trimmed.find("\"]") {
-                    if let Some(prev_remote) = current_remote {
-                        remotes.push((prev_remote, current_url.unwrap_or_else(|| "".to_string())));
-                    }
-                    let remote_name = &trimmed[start + 9..end]; // "[remote \"" 的长度是 9
-                    current_remote = Some(remote_name.to_string());
-                    current_url = None;
-                }
-            } else if let Some(url) = trimmed.strip_prefix("url = ") {
-                current_url = Some(url.to_string());
-            } else if trimmed.starts_with('[') && trimmed.ends_with(']') {
-                // 新的section开始
-                if let Some(prev_remote) = current_remote {
-                    remotes.push((prev_remote, current_url.unwrap_or_else(|| "".to_string())));
-                    current_remote = None;
-                    current_url = None;
-                }
-            }
-        }
-        
-        // 处理最后一个远程仓库
-        if let Some(prev_remote) = current_remote {
-            remotes.push((prev_remote, current_url.unwrap_or_else(|| "".to_string())));
-        }
-        
+    fn show_remotes(&self, gitdir: &Path, name: Option<&str>) -> Result<()> {
+        let config = Config::read(gitdir)?;
+        let url = |remote: &str| config.get(&format!("remote.{}.url", remote)).unwrap_or_default().to_string();
+        let remotes = config.subsections("remote");
+
         if let Some(target_name) = name {
             // 显示特定远程仓库
-            if let Some((_, url)) = remotes.iter().find(|(n, _)| n == target_name) {
-                if self.verbose {
-                    println!("* remote {}", target_name);
-                    println!("  Fetch URL: {}", url);
-                    println!("  Push  URL: {}", url);
-                } else {
-                    println!("{}", url);
-                }
-            } else {
+            if !remotes.iter().any(|remote| remote == target_name) {
                 return Err(GitError::invalid_command(format!("Remote '{}' not found", target_name)));
             }
+            let url = url(target_name);
+            if self.verbose {
+                println!("* remote {}", target_name);
+                println!("  Fetch URL: {}", url);
+                println!("  Push  URL: {}", url);
+            } else {
+                println!("{}", url);
+            }
         } else {
             // 显示所有远程仓库
             if remotes.is_empty() {
                 println!("No remotes configured");
             } else {
-                for (name, url) in remotes {
+                for name in remotes {
                     if self.verbose {
-                        println!("{}\t{} (fetch)", name, url);
-                        println!("{}\t{} (push)", name, url);
+                        println!("{}\t{} (fetch)", name, url(&name));
+                        println!("{}\t{} (push)", name, url(&name));
                     } else {
                         println!("{}", name);
                     }
                 }
             }
         }
-        
+
         Ok(())
     }
-    
-    /// 设置远程仓库URL
-    fn set_url(&self, gitdir: &PathBuf, name: &str, new_url: &str) -> Result<()> {
-        let config = self.read_config(gitdir)?;
-        let lines: Vec<&str> = config.lines().collect();
-        let mut new_lines = Vec::new();
-        let mut in_remote_section = false;
-        let mut found_remote = false;
-        let mut updated = false;
-        
-        for line in lines {
-            let trimmed = line.trim();
-            
-            if trimmed == &format!("[remote \"{}\"]", name) {
-                in_remote_section = true;
-                found_remote = true;
-                new_lines.push(line.to_string());
-                continue;
+
+    /// 重命名远程仓库：改 section 名和 fetch refspec，跟踪这个远程的分支配置也改掉，
+    /// 再把 refs/remotes/<old> 整个目录（包括 reflog）挪过去；所有检查都在写之前做
+    fn rename_remote(&self, gitdir: &Path, old: &str, new: &str) -> Result<()> {
+        let config = Config::read(gitdir)?;
+        if !Self::exists(&config, old) {
+            return Err(GitError::invalid_command(format!("Remote '{}' not found", old)));
+        }
+        if Self::exists(&config, new) {
+            return Err(GitError::invalid_command(format!("Remote '{}' already exists", new)));
+        }
+        if new.is_empty() || new.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') || new.starts_with('/') || new.ends_with('/') {
+            return Err(GitError::invalid_command(format!("'{}' is not a valid remote name", new)));
+        }
+        let refs_dir = |name: &str| ref_file(gitdir, &format!("refs/remotes/{}", name));
+        let logs_dir = |name: &str| common_dir(gitdir).join("logs").join("refs").join("remotes").join(name);
+        if refs_dir(new).exists() || logs_dir(new).exists() {
+            return Err(GitError::invalid_command(format!("refs/remotes/{} already exists", new)));
+        }
+
+        let old_refs = format!("refs/remotes/{}/", old);
+        let new_refs = format!("refs/remotes/{}/", new);
+        Config::rename_section(gitdir, &format!("remote.{}", old), &format!("remote.{}", new))?;
+        let fetch = format!("remote.{}.fetch", new);
+        let specs = config.get_all(&format!("remote.{}.fetch", old));
+        if !specs.is_empty() {
+            Config::unset(gitdir, &fetch)?;
+            for spec in specs {
+                Config::add(gitdir, &fetch, &spec.replace(&old_refs, &new_refs))?;
             }
-            
-            if trimmed.starts_with('[') && trimmed.ends_with(']') && in_remote_section {
-                in_remote_section = false;
+        }
+        for branch in config.subsections("branch") {
+            for key in ["remote", "pushRemote"] {
+                let key = format!("branch.{}.{}", branch, key);
+                if config.get(&key) == Some(old) {
+                    Config::set(gitdir, &key, new)?;
+                }
             }
-            
-            if in_remote_section && trimmed.starts_with("url = ") {
-                new_lines.push(format!("\turl = {}", new_url));
-                updated = true;
-            } else {
-                new_lines.push(line.to_string());
+        }
+
+        for (from, to) in [(refs_dir(old), refs_dir(new)), (logs_dir(old), logs_dir(new))] {
+            if from.exists() {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(&from, &to)?;
             }
         }
-        
-        if !found_remote {
+        // refs/remotes/<new>/HEAD 还指向旧的名字
+        let head = refs_dir(new).join("HEAD");
+        if let Ok(content) = fs::read_to_string(&head) && let Some(target) = content.trim().strip_prefix("ref: ") {
+            write_ref_file(&head, &format!("ref: {}\n", target.replacen(&old_refs, &new_refs, 1)))?;
+        }
+
+        println!("Renamed remote '{}' to '{}'", old, new);
+        Ok(())
+    }
+
+    /// 设置远程仓库URL
+    fn set_url(&self, gitdir: &Path, name: &str, new_url: &str) -> Result<()> {
+        let config = Config::read(gitdir)?;
+        if !Self::exists(&config, name) {
             return Err(GitError::invalid_command(format!("Remote '{}' not found", name)));
         }
-        
-        if !updated {
+
+        let key = format!("remote.{}.url", name);
+        if config.get(&key).is_none() {
             return Err(GitError::invalid_command(format!("No URL found for remote '{}'", name)));
         }
-        
-        let new_config = new_lines.join("\n");
-        self.write_config(gitdir, &new_config)?;
-        
+        Config::set(gitdir, &key, new_url)?;
+
         println!("Updated remote '{}' URL to {}", name, new_url);
         Ok(())
     }
 }
 
 impl SubCommand for Remote {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
         
         match &self.command {
             Some(RemoteCommand::Add { name, url }) => {
@@ -261,6 +224,14 @@ impl SubCommand for Remote {
             Some(RemoteCommand::Show { name }) => {
                 self.show_remotes(&gitdir, name.as_deref())?;
             }
+            Some(RemoteCommand::Rename { old, new }) => {
+                self.rename_remote(&gitdir, old, new)?;
+            }
+            Some(RemoteCommand::Prune { name }) => {
+                if Fetch::prune_remote(&gitdir, name)?.is_empty() && self.verbose {
+                    println!("Nothing to prune for '{}'", name);
+                }
+            }
             Some(RemoteCommand::SetUrl { name, url }) => {
                 self.set_url(&gitdir, name, url)?;
             }
@@ -277,7 +248,7 @@ impl SubCommand for Remote {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::test::{setup_test_git_dir, tempdir};
+    use crate::utils::test::{setup_test_git_dir, shell_spawn, tempdir};
     
     #[test]
     fn test_add_remote() -> Result<()> {
@@ -292,10 +263,10 @@ mod tests {
             verbose: false,
         };
         
-        remote.run(Ok(gitdir.clone()))?;
+        remote.run(Ok(Repository::at(gitdir.clone())))?;
         
-        let config = remote.read_config(&gitdir)?;
-        assert!(config.contains("https://github.com/user/repo.git"));
+        let config = Config::read(&gitdir)?;
+        assert_eq!(config.get("remote.origin.url"), Some("https://github.com/user/repo.git"));
         
         Ok(())
     }
@@ -313,15 +284,90 @@ mod tests {
             verbose: false,
         };
         
-        remote.run(Ok(gitdir.clone()))?;
+        remote.run(Ok(Repository::at(gitdir.clone())))?;
         
         let show_remote = Remote {
             command: None,
             verbose: false,
         };
         
-        show_remote.run(Ok(gitdir))?;
+        show_remote.run(Ok(Repository::at(gitdir)))?;
         
         Ok(())
     }
+    
+    #[test]
+    fn test_rename_remote() -> Result<()> {
+        let repo = setup_test_git_dir();
+        let path = repo.path().to_str().unwrap();
+        let gitdir = repo.path().join(".git");
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", path][..], args].concat());
+        git(&["commit", "--allow-empty", "-m", "init"])?;
+        git(&["remote", "add", "origin", "https://example.com/repo.git"])?;
+        git(&["remote", "add", "backup", "https://example.com/backup.git"])?;
+        git(&["update-ref", "-m", "fetch", "refs/remotes/origin/master", "HEAD"])?;
+        git(&["update-ref", "refs/remotes/origin/feature/deep/x", "HEAD"])?;
+        git(&["symbolic-ref", "refs/remotes/origin/HEAD", "refs/remotes/origin/master"])?;
+        git(&["config", "branch.master.remote", "origin"])?;
+        git(&["config", "branch.master.merge", "refs/heads/master"])?;
+        git(&["config", "branch.other.remote", "backup"])?;
+
+        // the new name is taken: nothing changes
+        let config = fs::read_to_string(gitdir.join("config"))?;
+        let rename = |old: &str, new: &str| Remote {
+            command: Some(RemoteCommand::Rename { old: old.to_string(), new: new.to_string() }),
+            verbose: false,
+        }.run(Ok(Repository::at(gitdir.clone())));
+        assert!(rename("origin", "backup").is_err());
+        assert!(rename("missing", "upstream").is_err());
+        assert_eq!(fs::read_to_string(gitdir.join("config"))?, config);
+        assert!(gitdir.join("refs/remotes/origin/feature/deep/x").exists());
+
+        rename("origin", "upstream")?;
+        // real git reads the result
+        assert_eq!(git(&["remote"])?, "backup\nupstream\n");
+        assert_eq!(git(&["config", "remote.upstream.url"])?, "https://example.com/repo.git\n");
+        assert_eq!(git(&["config", "remote.upstream.fetch"])?, "+refs/heads/*:refs/remotes/upstream/*\n");
+        assert_eq!(git(&["config", "branch.master.remote"])?, "upstream\n");
+        assert_eq!(git(&["config", "branch.other.remote"])?, "backup\n");
+        assert_eq!(
+            git(&["for-each-ref", "--format=%(refname)", "refs/remotes"])?,
+            "refs/remotes/upstream/HEAD\nrefs/remotes/upstream/feature/deep/x\nrefs/remotes/upstream/master\n"
+        );
+        assert_eq!(git(&["symbolic-ref", "refs/remotes/upstream/HEAD"])?, "refs/remotes/upstream/master\n");
+        assert_eq!(git(&["rev-parse", "--abbrev-ref", "master@{upstream}"])?, "upstream/master\n");
+        assert!(git(&["reflog", "show", "upstream/master"])?.contains("fetch"));
+        assert!(!gitdir.join("refs/remotes/origin").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_url_and_remove() -> Result<()> {
+        let repo = setup_test_git_dir();
+        let path = repo.path().to_str().unwrap();
+        let gitdir = repo.path().join(".git");
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", path][..], args].concat());
+        // written by hand: no spaces around `=`, a comment and a second remote after it
+        let mut config = fs::read_to_string(gitdir.join("config"))?;
+        config.push_str("[remote \"origin\"]\n\turl=https://example.com/repo.git # old\n\tfetch=+refs/heads/*:refs/remotes/origin/*\n");
+        config.push_str("[remote \"backup\"]\n\turl = https://example.com/backup.git\n");
+        fs::write(gitdir.join("config"), config)?;
+        let remote = |command: RemoteCommand| Remote { command: Some(command), verbose: false }.run(Ok(Repository::at(gitdir.clone())));
+
+        remote(RemoteCommand::SetUrl { name: "origin".to_string(), url: "https://example.com/new.git".to_string() })?;
+        assert_eq!(git(&["remote", "get-url", "origin"])?, "https://example.com/new.git\n");
+        assert_eq!(git(&["config", "--get-all", "remote.origin.url"])?, "https://example.com/new.git\n");
+        assert!(remote(RemoteCommand::SetUrl { name: "missing".to_string(), url: "x".to_string() }).is_err());
+
+        remote(RemoteCommand::Remove { name: "origin".to_string() })?;
+        assert_eq!(git(&["remote"])?, "backup\n");
+        assert!(git(&["config", "remote.origin.fetch"]).is_err());
+        assert!(remote(RemoteCommand::Remove { name: "origin".to_string() }).is_err());
+
+        remote(RemoteCommand::Add { name: "origin".to_string(), url: "https://example.com/again.git".to_string() })?;
+        assert_eq!(git(&["remote"])?, "backup\norigin\n");
+        assert_eq!(git(&["config", "--get-all", "remote.origin.fetch"])?, "+refs/heads/*:refs/remotes/origin/*\n");
+        assert!(remote(RemoteCommand::Add { name: "backup".to_string(), url: "x".to_string() }).is_err());
+        Ok(())
+    }
 }
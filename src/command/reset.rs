@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::{Checkout, Merge, ReadTree, status::{Changes, Status}},
+    utils::{
+        fs::calc_relative_path,
+        hash::ObjectId,
+        index::{Index, IndexEntry, LockedIndexWriter},
+        refs::{
+            head_to_hash,
+            read_head_ref,
+            write_head_commit_with_log,
+            write_ref_commit,
+            write_ref_commit_with_log,
+        },
+        revparse::rev_parse,
+        tree::TreeEntry,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
+
+#[derive(Parser, Debug)]
+#[command(name = "reset", about = "Reset current HEAD to the specified state")]
+pub struct Reset {
+    #[arg(long, group = "mode", help = "only move the branch, keep index and working tree")]
+    soft: bool,
+
+    #[arg(long, group = "mode", help = "reset the index but not the working tree (default)")]
+    mixed: bool,
+
+    #[arg(long, group = "mode", help = "reset the index and the working tree")]
+    hard: bool,
+
+    #[arg(short = 'q', long, help = "only report errors")]
+    quiet: bool,
+
+    #[arg(help = "<commit> and/or <paths>...")]
+    args: Vec<String>,
+}
+
+impl Reset {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Reset::try_parse_from(args)?))
+    }
+
+    /// `reset --hard <target>`
+    pub fn from_internal(target: &ObjectId) -> Self {
+        Reset { soft: false, mixed: false, hard: true, quiet: false, args: vec![target.to_hex()] }
+    }
+
+    /// every file of the commit's tree keyed by its path relative to the project root
+    fn flatten_commit(gitdir: &Path, commit_hash: &ObjectId) -> Result<HashMap<PathBuf, TreeEntry>> {
+        let (_, tree) = Checkout::read_commit(gitdir, commit_hash)?;
+        Ok(tree.flatten(gitdir)?
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect())
+    }
+
+    /// point the current branch (or a detached HEAD) at `target`, `rev` is what the user typed
+    fn move_head(gitdir: &Path, target: &ObjectId, rev: &str) -> Result<()> {
+        if let Ok(current) = head_to_hash(gitdir) {
+            write_ref_commit(gitdir, "ORIG_HEAD", &current)?;
+        }
+        let reflog_message = format!("reset: moving to {}", rev);
+        match read_head_ref(gitdir) {
+            Ok(head_ref) => write_ref_commit_with_log(gitdir, &head_ref, target, &reflog_message),
+            Err(_) => write_head_commit_with_log(gitdir, target, &reflog_message),
+        }
+    }
+
+    fn reset_index(gitdir: &Path, target: &ObjectId) -> Result<()> {
+        let (commit, _) = Checkout::read_commit(gitdir, target)?;
+        let read_tree = ReadTree::from_internal(&commit.tree_hash);
+        read_tree.run(Ok(Repository::at(gitdir)))?;
+        Ok(())
+    }
+
+    fn reset_worktree(gitdir: &Path, current: Option<&ObjectId>, target: &ObjectId) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let target_files = Self::flatten_commit(gitdir, target)?;
+        let head_files = match current {
+            Some(hash) => Self::flatten_commit(gitdir, hash)?,
+            None => HashMap::new(),
+        };
+
+        // staged files unknown to both commits are thrown away too,
+        // files tracked in HEAD are handled by restore_workspace
+        for entry in Index::read_or_default(gitdir)?.entries {
+            let path = PathBuf::from(&entry.name);
+            if !target_files.contains_key(&path) && !head_files.contains_key(&path) {
+                let file_path = project_root.join(&path);
+                if file_path.is_file() || file_path.is_symlink() {
+                    fs::remove_file(&file_path)
+                        .map_err(|_| GitError::failed_to_remove_file(file_path.display().to_string()))?;
+                    remove_empty_parents(project_root, &file_path);
+                }
+            }
+        }
+        Checkout::restore_workspace(gitdir, current, target)
+    }
+
+    /// reset <paths>: copy HEAD's entries for the paths back into the index
+    fn reset_paths(gitdir: &Path, commit: &ObjectId, paths: &[String]) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let files = Self::flatten_commit(gitdir, commit)?;
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let mut index = Index::read_or_default(gitdir)?;
+
+        for path in paths {
+            let path = calc_relative_path(project_root, path)
+                .unwrap_or_else(|_| PathBuf::from(path));
+            let matches = |p: &Path| path == Path::new(".") || p.starts_with(&path);
+
+            let staged = index.entries.iter()
+                .map(|e| PathBuf::from(&e.name))
+                .filter(|p| matches(p))
+                .collect::<HashSet<_>>();
+            let committed = files.keys()
+                .filter(|p| matches(p))
+                .cloned()
+                .collect::<HashSet<_>>();
+
+            for p in staged.union(&committed) {
+                match files.get(p) {
+                    Some(entry) => index.add_entry(IndexEntry::new(
+                        entry.mode as u32,
+                        entry.hash,
+                        p,
+                    )),
+                    None => {
+                        index.remove_entry(p);
+                    },
+                }
+            }
+        }
+
+        lock.commit(&index)?;
+        Ok(())
+    }
+}
+
+impl SubCommand for Reset {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+
+        // the first argument is a commit if it resolves to one, everything else are paths
+        let mut args = self.args.iter().filter(|x| *x != "--").cloned().collect::<Vec<_>>();
+        let mut rev = "HEAD".to_string();
+        let target = match args.first().map(|x| rev_parse(&gitdir, x)) {
+            Some(Ok(hash)) => {
+                rev = args.remove(0);
+                hash
+            },
+            Some(Err(e)) if self.hard || self.soft || args.len() == 1 && !work_tree(&gitdir)?.join(&args[0]).exists() => {
+                return Err(e);
+            },
+            _ => head_to_hash(&gitdir)
+                .map_err(|_| GitError::invalid_command("HEAD does not point to a commit".to_string()))?,
+        };
+
+        if !args.is_empty() {
+            if self.soft || self.hard {
+                return Err(GitError::invalid_command("Cannot do soft/hard reset with paths".to_string()));
+            }
+            Self::reset_paths(&gitdir, &target, &args)?;
+            return Ok(0);
+        }
+
+        // the merge would lose its conflicts without a word, git refuses as well
+        let merging = gitdir.join("MERGE_HEAD").exists() || !Index::read_or_default(&gitdir)?.unmerged_paths().is_empty();
+        if self.soft && merging {
+            return Err(GitError::invalid_command("Cannot do a soft reset in the middle of a merge.".to_string()));
+        }
+
+        let current = head_to_hash(&gitdir).ok();
+        Self::move_head(&gitdir, &target, &rev)?;
+        if self.soft {
+            return Ok(0);
+        }
+        Merge::clear_merge_state(&gitdir)?;
+
+        if self.hard {
+            Self::reset_worktree(&gitdir, current.as_ref(), &target)?;
+        }
+        Self::reset_index(&gitdir, &target)?;
+
+        if self.quiet {
+            return Ok(0);
+        }
+        if self.hard {
+            Checkout::refresh_index_stat(&gitdir)?;
+            let (commit, _) = Checkout::read_commit(&gitdir, &target)?;
+            println!("HEAD is now at {} {}", &target.to_hex()[..7], commit.message.lines().next().unwrap_or(""));
+        }
+        else {
+            let mut changes = Changes::new();
+            Status::unstaged(&work_tree(&gitdir)?, &Index::read_or_default(&gitdir)?, &mut changes)?;
+            if !changes.is_empty() {
+                println!("Unstaged changes after reset:");
+            }
+            for (path, (_, code)) in changes {
+                println!("{}\t{}", code, path.display());
+            }
+        }
+        Ok(0)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cmd_seq,
+        cp_dir,
+        run_both,
+        ArgsList,
+    };
+
+    fn setup() -> (tempfile::TempDir, tempfile::TempDir) {
+        let temp1 = setup_test_git_dir();
+        let temp2 = tempdir().unwrap();
+        let temp_path1 = temp1.path();
+
+        std::fs::write(temp_path1.join("a.txt"), "a1").unwrap();
+        std::fs::write(temp_path1.join("b.txt"), "b").unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", "a.txt"], false),
+            (&["commit", "-m", "c1"], false),
+            (&["add", "b.txt"], false),
+            (&["commit", "-m", "c2"], false),
+        ];
+        let git = &["git", "-C", temp_path1.to_str().unwrap()];
+        cmd_seq(setup_cmds)(git).unwrap();
+        std::fs::write(temp_path1.join("a.txt"), "a2").unwrap();
+        std::fs::write(temp_path1.join("new.txt"), "new").unwrap();
+        let _ = shell_spawn(&["git", "-C", temp_path1.to_str().unwrap(), "add", "a.txt", "new.txt"]).unwrap();
+        let _ = cp_dir(temp_path1, temp2.path()).unwrap();
+        (temp1, temp2)
+    }
+
+    fn compare(temp1: &Path, temp2: &Path, cmds: ArgsList) {
+        let (p1, p2) = (temp1.to_str().unwrap(), temp2.to_str().unwrap());
+        let git = &["git", "-C", p1];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", p2];
+        let _ = run_both(cmds, git, cargo).unwrap();
+
+        for args in [&["rev-parse", "HEAD"][..], &["ls-files", "--stage"], &["status", "--porcelain"]] {
+            let origin = shell_spawn(&[&["git", "-C", p1][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["git", "-C", p2][..], args].concat()).unwrap();
+            assert_eq!(origin, real);
+        }
+    }
+
+    #[test]
+    fn test_modes() {
+        let (temp1, temp2) = setup();
+        compare(temp1.path(), temp2.path(), &[(&["reset", "--soft", "HEAD"], false)]);
+
+        let (temp1, temp2) = setup();
+        let head1 = shell_spawn(&["git", "-C", temp1.path().to_str().unwrap(), "rev-parse", "HEAD~1"]).unwrap();
+        compare(temp1.path(), temp2.path(), &[(&["reset", head1.trim()], false)]);
+
+        let (temp1, temp2) = setup();
+        compare(temp1.path(), temp2.path(), &[(&["reset", "--hard", "master"], false)]);
+        assert!(!temp2.path().join("new.txt").exists());
+
+        let (temp1, temp2) = setup();
+        let head1 = shell_spawn(&["git", "-C", temp1.path().to_str().unwrap(), "rev-parse", "HEAD~1"]).unwrap();
+        compare(temp1.path(), temp2.path(), &[(&["reset", "--hard", head1.trim()], false)]);
+        assert!(!temp2.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn test_unstage_paths() {
+        let (temp1, temp2) = setup();
+        compare(temp1.path(), temp2.path(), &[(&["reset", "a.txt", "new.txt"], false)]);
+    }
+
+    #[test]
+    fn test_reset_merge_state() {
+        let (temp1, _) = setup();
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-C", dir][..], args].concat());
+        let p1 = temp1.path().to_str().unwrap();
+        git(p1, &["commit", "-m", "c3"]).unwrap();
+        git(p1, &["checkout", "-b", "side", "HEAD~1"]).unwrap();
+        std::fs::write(temp1.path().join("a.txt"), "side").unwrap();
+        git(p1, &["commit", "-am", "side"]).unwrap();
+        git(p1, &["checkout", "master"]).unwrap();
+        assert!(git(p1, &["merge", "side"]).is_err());
+        // the commits are made once, two runs could land on different seconds and hashes
+        let temp2 = tempdir().unwrap();
+        let _ = cp_dir(temp1.path(), temp2.path()).unwrap();
+        let p2 = temp2.path().to_str().unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", p2][..], args].concat());
+
+        // a soft reset would drop the conflicts silently
+        assert!(git(p1, &["reset", "--soft"]).is_err());
+        assert!(cargo(&["reset", "--soft"]).is_err());
+        assert!(temp2.path().join(".git/MERGE_HEAD").exists());
+
+        // a mixed reset ends the merge and lists what is left in the worktree
+        assert_eq!(cargo(&["reset"]).unwrap(), git(p1, &["reset"]).unwrap());
+        assert!(!temp2.path().join(".git/MERGE_HEAD").exists());
+        compare(temp1.path(), temp2.path(), &[]);
+        assert_eq!(cargo(&["reset", "-q", "HEAD~1"]).unwrap(), "");
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use clap::Parser;
+
+use crate::{
+    Result,
+    utils::{
+        hash::ObjectId,
+        refs::for_each_ref,
+        revparse::resolve_commit,
+        revwalk,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "rev-list", about = "Lists commit objects in reverse chronological order")]
+pub struct RevList {
+    #[arg(long, help = "print how many commits would be listed instead of the commits")]
+    count: bool,
+
+    #[arg(short = 'n', long, value_name = "number", help = "stop after this many commits")]
+    max_count: Option<usize>,
+
+    #[arg(long, help = "start from every ref as well as the commits given")]
+    all: bool,
+
+    #[arg(required_unless_present = "all",
+        help = "the commits to list the history of, `a..b` and `^a` exclude")]
+    revisions: Vec<String>,
+}
+
+impl RevList {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(RevList::try_parse_from(args)?))
+    }
+
+    /// the commits to walk from and the ones whose history is left out
+    fn ranges(&self, repo: &Repository) -> Result<(Vec<ObjectId>, Vec<ObjectId>)> {
+        let gitdir = &repo.gitdir;
+        let (mut include, mut exclude) = (Vec::new(), Vec::new());
+        if self.all {
+            for (_, hash) in for_each_ref(gitdir, "refs/")? {
+                // refs to trees or blobs have no history to list
+                if let Ok(commit) = resolve_commit(gitdir, &hash.to_hex()) {
+                    include.push(commit);
+                }
+            }
+            include.extend(resolve_commit(gitdir, "HEAD").ok());
+        }
+        for rev in &self.revisions {
+            if let Some((from, to)) = rev.split_once("..") {
+                let or_head = |rev: &str| if rev.is_empty() { "HEAD".to_string() } else { rev.to_string() };
+                exclude.push(resolve_commit(gitdir, &or_head(from))?);
+                include.push(resolve_commit(gitdir, &or_head(to))?);
+            } else if let Some(rev) = rev.strip_prefix('^') {
+                exclude.push(resolve_commit(gitdir, rev)?);
+            } else {
+                include.push(resolve_commit(gitdir, rev)?);
+            }
+        }
+        // the walk starts each commit once
+        let mut seen = HashSet::new();
+        include.retain(|hash| seen.insert(*hash));
+        Ok((include, exclude))
+    }
+}
+
+impl SubCommand for RevList {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let (include, exclude) = self.ranges(&repo)?;
+        let mut cache = HashMap::new();
+        let excluded = revwalk::reachable(gitdir, exclude, &mut cache)?.into_iter().collect::<HashSet<_>>();
+
+        // newest first by committer date, every commit once however many paths lead to it
+        let commits = revwalk::date_order(gitdir, include, &mut cache)?
+            .into_iter()
+            .filter(|hash| !excluded.contains(hash))
+            .take(self.max_count.unwrap_or(usize::MAX));
+        if self.count {
+            println!("{}", commits.count());
+        } else {
+            commits.for_each(|hash| println!("{}", hash));
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_rev_list() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        // distinct commit dates, so the order does not depend on how ties are broken
+        let mut time = 1_700_000_000;
+        let mut git_at = |args: &[&str]| {
+            time += 60;
+            let date = format!("{} +0000", time);
+            let status = Command::new("git").arg("-C").arg(temp_path_str).args(args)
+                .env("GIT_AUTHOR_DATE", &date)
+                .env("GIT_COMMITTER_DATE", &date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", args);
+        };
+        git_at(&["commit", "-q", "--allow-empty", "-m", "c1"]);
+        git_at(&["commit", "-q", "--allow-empty", "-m", "c2"]);
+        git_at(&["checkout", "-q", "-b", "side"]);
+        git_at(&["commit", "-q", "--allow-empty", "-m", "s1"]);
+        git_at(&["checkout", "-q", "master"]);
+        git_at(&["commit", "-q", "--allow-empty", "-m", "c3"]);
+        git_at(&["checkout", "-q", "side"]);
+        git_at(&["commit", "-q", "--allow-empty", "-m", "s2"]);
+        git_at(&["checkout", "-q", "master"]);
+        git_at(&["merge", "-q", "--no-ff", "-m", "merge", "side"]);
+        git_at(&["commit", "-q", "--allow-empty", "-m", "c4"]);
+        git_at(&["tag", "-a", "-m", "tag", "v1", "HEAD~1"]);
+
+        for args in [
+            &["HEAD"][..],
+            &["side"],
+            &["--count", "HEAD"],
+            &["--max-count=3", "HEAD"],
+            &["-n", "2", "--count", "HEAD"],
+            &["master..side"],
+            &["side..master"],
+            &["side..", "--count"],
+            &["HEAD", "^side"],
+            &["HEAD~1^2", "HEAD~2"],
+            &["v1"],
+            &["--all"],
+            &["--all", "^side"],
+        ] {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str, "rev-list"][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-list"][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-list", "nope"]).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::path::Path;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+
+use crate::{
+    utils::{
+        refs::read_head_ref,
+        revparse::{dwim_ref, rev_parse, upstream_ref},
+    },
+    GitError,
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "rev-parse", about = "Pick out and massage parameters")]
+pub struct RevParse {
+    #[arg(long, help = "verify that exactly one parameter is given and that it resolves to an object")]
+    verify: bool,
+
+    #[arg(long, value_name = "length", num_args = 0..=1, require_equals = true, default_missing_value = "7",
+        help = "like --verify, but print the hash abbreviated")]
+    short: Option<usize>,
+
+    #[arg(long, help = "print the short name of a ref instead of its hash, HEAD is the current branch")]
+    abbrev_ref: bool,
+
+    #[arg(long, help = "print the path to the git dir, . inside it and .git from the top of the worktree")]
+    git_dir: bool,
+
+    #[arg(long, help = "print the absolute path of the top of the worktree")]
+    show_toplevel: bool,
+
+    #[arg(long, help = "print the path back up to the top of the worktree from the current directory")]
+    show_cdup: bool,
+
+    #[arg(long, help = "print the current directory relative to the top of the worktree")]
+    show_prefix: bool,
+
+    #[arg(long, help = "print true when the current directory is inside the worktree")]
+    is_inside_work_tree: bool,
+
+    #[arg(help = "revisions like HEAD, HEAD~2, main^ or an abbreviated hash")]
+    revs: Vec<String>,
+
+    /// the location options in the order they were given, git answers them in that order
+    #[arg(skip)]
+    locations: Vec<&'static str>,
+}
+
+const LOCATIONS: [&str; 5] = ["git_dir", "show_toplevel", "show_cdup", "show_prefix", "is_inside_work_tree"];
+
+impl RevParse {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        let matches = RevParse::command().try_get_matches_from(args)?;
+        let mut rev_parse = RevParse::from_arg_matches(&matches)?;
+        let mut locations: Vec<_> = LOCATIONS.iter()
+            .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+            .filter_map(|id| Some((matches.index_of(id)?, *id)))
+            .collect();
+        locations.sort();
+        rev_parse.locations = locations.into_iter().map(|(_, id)| id).collect();
+        Ok(Box::new(rev_parse))
+    }
+
+    /// `main` for HEAD on main, `origin/main` for `main@{u}`; None for anything that is no ref
+    fn abbrev_ref(gitdir: &Path, rev: &str) -> Option<String> {
+        let refname = match rev {
+            "HEAD" => return Some(read_head_ref(gitdir).ok().map_or("HEAD".to_string(), |head| Self::shorten(&head))),
+            _ => match rev.strip_suffix('}').and_then(|x| x.rsplit_once("@{")) {
+                Some((branch, "u" | "upstream")) => upstream_ref(gitdir, branch).ok()?,
+                _ => dwim_ref(gitdir, rev)?,
+            },
+        };
+        Some(Self::shorten(&refname))
+    }
+
+    fn shorten(refname: &str) -> String {
+        ["refs/heads/", "refs/tags/", "refs/remotes/"].iter()
+            .find_map(|prefix| refname.strip_prefix(prefix))
+            .unwrap_or(refname)
+            .to_string()
+    }
+}
+
+impl SubCommand for RevParse {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        if (self.verify || self.short.is_some()) && self.revs.len() != 1 {
+            return Err(GitError::invalid_argument("Needed a single revision".to_string()));
+        }
+
+        // a bare repository and the inside of the git dir have no work tree to answer for
+        let in_work_tree = repo.work_tree().is_ok() && !repo.inside_git_dir();
+        for location in &self.locations {
+            match *location {
+                "git_dir" => println!("{}", repo.display_gitdir().display()),
+                "show_toplevel" if in_work_tree => println!("{}", repo.work_tree()?.display()),
+                "show_toplevel" => return Err(GitError::no_work_tree()),
+                "show_cdup" if in_work_tree => println!("{}", "../".repeat(repo.prefix.components().count())),
+                "show_prefix" if in_work_tree && !repo.prefix.as_os_str().is_empty() => println!("{}/", repo.prefix.display()),
+                "show_prefix" => println!(),
+                "is_inside_work_tree" => println!("{}", in_work_tree),
+                _ => {},
+            }
+        }
+
+        for rev in &self.revs {
+            if self.abbrev_ref && let Some(name) = Self::abbrev_ref(gitdir, rev) {
+                println!("{}", name);
+                continue;
+            }
+            // unknown revisions exit with 128 like git
+            let hash = rev_parse(gitdir, rev)?;
+            match self.short {
+                _ if self.abbrev_ref => {},
+                Some(length) => {
+                    let hex = hash.to_hex();
+                    println!("{}", &hex[..length.clamp(4, hex.len())]);
+                },
+                None => println!("{}", hash),
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_rev_parse() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "c1"], false),
+            (&["commit", "--allow-empty", "-m", "c2"], false),
+            (&["checkout", "-b", "side"], false),
+            (&["commit", "--allow-empty", "-m", "c3"], false),
+            (&["checkout", "master"], false),
+            (&["merge", "--no-ff", "-m", "merge", "side"], false),
+        ];
+        let git_cmd = &["git", "-C", temp_path_str];
+        cmd_seq(setup_cmds)(git_cmd).unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+
+        let head = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD"]).unwrap();
+        let revs = ["HEAD", "HEAD~", "HEAD~2", "HEAD^2", "HEAD^^", "HEAD^0", "master~1", "side", &head[..7], head.trim()];
+        for rev in revs {
+            let origin = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", rev]).unwrap();
+            let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-parse", rev]).unwrap();
+            assert_eq!(origin, real, "rev-parse {}", rev);
+        }
+
+        for rev in ["nope", "HEAD~10", "HEAD^3"] {
+            let status = std::process::Command::new("cargo")
+                .args(["run", "--quiet", "--", "-C", temp_path_str, "rev-parse", rev])
+                .output()
+                .unwrap()
+                .status;
+            assert_eq!(status.code(), Some(128), "rev-parse {}", rev);
+        }
+
+        git(&["tag", "v1", "HEAD~1"]);
+        std::fs::create_dir_all(temp_path.join("sub/dir")).unwrap();
+        let sub = temp_path.join("sub/dir");
+        let args: &[&[&str]] = &[
+            &["--git-dir", "--show-toplevel", "--show-cdup", "--show-prefix", "--is-inside-work-tree"],
+            &["--abbrev-ref", "HEAD", "side", "v1", "refs/heads/side", "HEAD~1"],
+            &["--short", "HEAD"],
+            &["--short=10", "side"],
+        ];
+        for dir in [temp_path, &sub] {
+            let dir = dir.to_str().unwrap();
+            for args in args {
+                let origin = shell_spawn(&[&["git", "-C", dir, "rev-parse"][..], args].concat()).unwrap();
+                let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", dir, "rev-parse"][..], args].concat()).unwrap();
+                assert_eq!(origin, real, "{} {:?}", dir, args);
+            }
+        }
+
+        // paths inside trees and the index, and --verify on one revision
+        std::fs::write(sub.join("f.txt"), "f\n").unwrap();
+        git(&["add", "sub"]);
+        git(&["commit", "-m", "file"]);
+        let args: &[&[&str]] = &[
+            &["HEAD:sub/dir/f.txt"], &["HEAD:sub"], &["HEAD:"], &[":sub/dir/f.txt"], &["HEAD^{tree}"],
+            &["--verify", "HEAD"], &["--verify", "side~1"], &["--verify", "HEAD:sub/dir"],
+        ];
+        for args in args {
+            let origin = shell_spawn(&[&["git", "-C", temp_path_str, "rev-parse"][..], args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-parse"][..], args].concat()).unwrap();
+            assert_eq!(origin, real, "{:?}", args);
+        }
+        for args in [&["--verify", "nope"][..], &["--verify", "HEAD", "side"], &["HEAD:missing"]] {
+            assert!(shell_spawn(&[&["git", "-C", temp_path_str, "rev-parse"][..], args].concat()).is_err());
+            assert!(shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-parse"][..], args].concat()).is_err(), "{:?}", args);
+        }
+
+        // detached HEAD has no branch to name
+        git(&["checkout", "--detach"]);
+        let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-parse", "--abbrev-ref", "HEAD"]).unwrap();
+        assert_eq!(real, "HEAD\n");
+        assert!(shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "rev-parse", "--short", "HEAD", "side"]).is_err());
+    }
+
+    #[test]
+    fn test_rev_parse_outside_work_tree() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let bare = temp_path.join("bare.git");
+        shell_spawn(&["git", "init", "--quiet", "--bare", bare.to_str().unwrap()]).unwrap();
+
+        let dirs = [temp_path.to_path_buf(), temp_path.join(".git"), temp_path.join(".git/refs"), bare.clone(), bare.join("refs")];
+        let args: &[&[&str]] = &[
+            &["--git-dir"], &["--is-inside-work-tree"], &["--show-cdup"], &["--show-prefix"], &["--show-toplevel"],
+            // answered in the order they are given
+            &["--show-prefix", "--is-inside-work-tree", "--git-dir", "--show-cdup"],
+        ];
+        for dir in &dirs {
+            let dir = dir.to_str().unwrap();
+            for args in args {
+                let origin = std::process::Command::new("git").args(["-C", dir, "rev-parse"]).args(*args).output().unwrap();
+                let real = std::process::Command::new("cargo").args(["run", "--quiet", "--", "-C", dir, "rev-parse"]).args(*args).output().unwrap();
+                assert_eq!(origin.status.code(), real.status.code(), "{} {:?}", dir, args);
+                assert_eq!(String::from_utf8_lossy(&origin.stdout), String::from_utf8_lossy(&real.stdout), "{} {:?}", dir, args);
+            }
+        }
+
+        let args = ["--show-toplevel", "--show-prefix", "--is-inside-work-tree", "--show-cdup", "--git-dir"];
+        let sub = temp_path.join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        for dir in [temp_path, &sub] {
+            let dir = dir.to_str().unwrap();
+            let origin = shell_spawn(&[&["git", "-C", dir, "rev-parse"][..], &args].concat()).unwrap();
+            let real = shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", dir, "rev-parse"][..], &args].concat()).unwrap();
+            assert_eq!(origin, real, "{}", dir);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::path::Path;
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    command::cherry_pick::{CherryPick, Replay},
+    utils::{
+        commit::Commit,
+        fs::{read_object, write_object},
+        hash::ObjectId,
+        ident,
+        index::Index,
+        refs::head_to_hash,
+        revparse::resolve_commit,
+        tree::Tree,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "revert", about = "Revert an existing commit with a new commit")]
+pub struct Revert {
+    #[arg(short = 'm', long = "mainline", value_name = "parent-number", help = "the parent (from 1) a merge commit is reverted to")]
+    mainline: Option<usize>,
+
+    #[arg(short = 'n', long = "no-commit", help = "apply the inverse changes to the index and worktree without committing")]
+    no_commit: bool,
+
+    #[arg(long, conflicts_with_all = ["no_commit", "mainline", "commit"], help = "give up a conflicted revert and go back to ORIG_HEAD")]
+    abort: bool,
+
+    #[arg(long = "continue", conflicts_with_all = ["no_commit", "mainline", "commit", "abort"], help = "commit a revert whose conflicts are resolved")]
+    resume: bool,
+
+    #[arg(required_unless_present_any = ["abort", "resume"], help = "the commit whose changes are undone")]
+    commit: Option<String>,
+}
+
+impl Revert {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Revert::try_parse_from(args)?))
+    }
+
+    /// the parent the commit is reverted to, only a merge needs -m to choose one
+    fn parent(&self, hash: &ObjectId, commit: &Commit) -> Result<Option<ObjectId>> {
+        match (commit.parent_hash.as_slice(), self.mainline) {
+            ([], None) => Ok(None),
+            ([parent], None) => Ok(Some(*parent)),
+            (_, None) => Err(GitError::invalid_command(format!("commit {} is a merge but no -m option was given.", hash))),
+            ([] | [_], Some(_)) => Err(GitError::invalid_command(format!("mainline was specified but commit {} is not a merge.", hash))),
+            (parents, Some(n)) => parents.get(n.wrapping_sub(1))
+                .copied()
+                .map(Some)
+                .ok_or_else(|| GitError::invalid_command(format!("commit {} does not have parent {}", hash, n))),
+        }
+    }
+
+    fn revert(&self, gitdir: &Path, rev: &str) -> Result<i32> {
+        let hash = resolve_commit(gitdir, rev)?;
+        let reverted = read_object::<Commit>(gitdir.to_path_buf(), &hash)?;
+        let parent = self.parent(&hash, &reverted)?;
+        let parent_tree = match &parent {
+            Some(parent) => read_object::<Commit>(gitdir.to_path_buf(), parent)?.tree_hash,
+            None => write_object::<Tree>(gitdir.to_path_buf(), Vec::new())?,
+        };
+
+        let subject = reverted.message.lines().next().unwrap_or_default();
+        let mut message = format!("Revert \"{}\"\n\nThis reverts commit {}", subject, hash);
+        match (&parent, self.mainline) {
+            (Some(parent), Some(_)) => message += &format!(", reversing\nchanges made to {}.\n", parent),
+            _ => message += ".\n",
+        }
+        // the inverse of a cherry-pick: from the commit back to its parent
+        Replay {
+            action: "revert",
+            state_ref: "REVERT_HEAD",
+            label: format!("parent of {} ({})", &hash.to_hex()[..7], subject),
+            base_tree: reverted.tree_hash,
+            tree: parent_tree,
+            hash,
+            message,
+            author: ident::author(gitdir)?,
+        }.apply(gitdir, self.no_commit)
+    }
+}
+
+/// the conflicts are resolved in the index: commit it with the message prepared in MERGE_MSG
+fn resume(gitdir: &Path) -> Result<i32> {
+    if !gitdir.join("REVERT_HEAD").exists() {
+        return Err(GitError::invalid_command("no revert in progress".to_string()));
+    }
+    if !Index::read_or_default(gitdir)?.unmerged_paths().is_empty() {
+        return Err(GitError::merge_conflict("Committing is not possible because you have unmerged files.".to_string()));
+    }
+    let message = std::fs::read_to_string(gitdir.join("MERGE_MSG"))?;
+    Replay::commit(gitdir, "revert", head_to_hash(gitdir)?, ident::author(gitdir)?, &message)
+}
+
+impl SubCommand for Revert {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        match &self.commit {
+            _ if self.abort => CherryPick::abort(&gitdir, "REVERT_HEAD"),
+            _ if self.resume => resume(&gitdir),
+            Some(rev) => self.revert(&gitdir, rev),
+            None => unreachable!("clap requires a commit without --abort or --continue"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_revert() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let git1 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat());
+        let git2 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2][..], args].concat());
+
+        std::fs::write(temp_path1.join("f.txt"), "1\n2\n3\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "base"]).unwrap();
+        std::fs::write(temp_path1.join("new.txt"), "new\n").unwrap();
+        git1(&["add", "new.txt"]).unwrap();
+        git1(&["commit", "-m", "add new"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "1\ntwo\n3\n").unwrap();
+        git1(&["commit", "-am", "edit f"]).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        // reverting the tip gives back the tree before it
+        cargo(&["revert", "HEAD"]).unwrap();
+        assert_eq!(git2(&["rev-parse", "HEAD^{tree}"]).unwrap(), git2(&["rev-parse", "HEAD~2^{tree}"]).unwrap());
+        assert_eq!(git2(&["status", "--porcelain"]).unwrap(), "");
+        let reverted = git2(&["rev-parse", "HEAD~1"]).unwrap();
+        assert_eq!(git2(&["log", "-1", "--format=%B"]).unwrap(), format!("Revert \"edit f\"\n\nThis reverts commit {}.\n\n", reverted.trim()));
+        git1(&["revert", "--no-edit", "HEAD"]).unwrap();
+        assert_eq!(git1(&["log", "-1", "--format=%B"]).unwrap(), git2(&["log", "-1", "--format=%B"]).unwrap());
+
+        // an older commit whose change is still there
+        git1(&["revert", "--no-edit", "HEAD~2"]).unwrap();
+        cargo(&["revert", "HEAD~2"]).unwrap();
+        assert_eq!(git1(&["rev-parse", "HEAD^{tree}"]).unwrap(), git2(&["rev-parse", "HEAD^{tree}"]).unwrap());
+        assert!(!temp_path2.join("new.txt").exists());
+
+        // the reverted line changed again since: a conflict like git's, then --abort
+        std::fs::write(temp_path1.join("f.txt"), "1\nthree\n3\n").unwrap();
+        git1(&["commit", "-am", "edit f again"]).unwrap();
+        std::fs::write(temp_path2.join("f.txt"), "1\nthree\n3\n").unwrap();
+        git2(&["commit", "-am", "edit f again"]).unwrap();
+        let edit = git2(&["rev-parse", "HEAD~3"]).unwrap();
+        assert!(git1(&["revert", "--no-edit", git1(&["rev-parse", "HEAD~3"]).unwrap().trim()]).is_err());
+        let status = std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", temp_path_str2, "revert", edit.trim()])
+            .output()
+            .unwrap()
+            .status;
+        assert_eq!(status.code(), Some(1));
+        assert_eq!(git1(&["ls-files", "-s"]).unwrap(), git2(&["ls-files", "-s"]).unwrap());
+        assert!(temp_path2.join(".git/REVERT_HEAD").exists());
+        let markers = std::fs::read_to_string(temp_path2.join("f.txt")).unwrap();
+        assert!(markers.contains(&format!(">>>>>>> parent of {} (edit f)", &edit[..7])), "{}", markers);
+        assert!(cargo(&["revert", "--continue"]).is_err());
+        cargo(&["revert", "--abort"]).unwrap();
+        assert_eq!(git2(&["status", "--porcelain"]).unwrap(), "");
+        assert!(!temp_path2.join(".git/REVERT_HEAD").exists());
+        assert!(cargo(&["revert", "--continue"]).is_err());
+
+        // resolved by hand, --continue commits with the prepared message like git
+        assert!(cargo(&["revert", edit.trim()]).is_err());
+        for temp_path in [temp_path1, temp_path2] {
+            std::fs::write(temp_path.join("f.txt"), "1\nresolved\n3\n").unwrap();
+            shell_spawn(&["git", "-C", temp_path.to_str().unwrap(), "add", "f.txt"]).unwrap();
+        }
+        git1(&["-c", "core.editor=true", "revert", "--continue"]).unwrap();
+        cargo(&["revert", "--continue"]).unwrap();
+        assert_eq!(git1(&["log", "-1", "--format=%B"]).unwrap(), git2(&["log", "-1", "--format=%B"]).unwrap());
+        assert_eq!(git1(&["rev-parse", "HEAD^{tree}"]).unwrap(), git2(&["rev-parse", "HEAD^{tree}"]).unwrap());
+        assert!(!temp_path2.join(".git/REVERT_HEAD").exists());
+        git1(&["reset", "--hard", "HEAD~1"]).unwrap();
+        git2(&["reset", "--hard", "HEAD~1"]).unwrap();
+
+        // a merge needs -m, the chosen parent is what the tree goes back to
+        git2(&["checkout", "-b", "side", "HEAD~1"]).unwrap();
+        std::fs::write(temp_path2.join("side.txt"), "side\n").unwrap();
+        git2(&["add", "side.txt"]).unwrap();
+        git2(&["commit", "-m", "side"]).unwrap();
+        git2(&["checkout", "master"]).unwrap();
+        git2(&["merge", "--no-edit", "side"]).unwrap();
+        assert!(cargo(&["revert", "HEAD"]).is_err());
+        assert!(cargo(&["revert", "-m", "3", "HEAD"]).is_err());
+        assert!(cargo(&["revert", "-m", "1", "HEAD~1"]).is_err());
+        cargo(&["revert", "-m", "1", "HEAD"]).unwrap();
+        assert_eq!(git2(&["rev-parse", "HEAD^{tree}"]).unwrap(), git2(&["rev-parse", "HEAD~1^1^{tree}"]).unwrap());
+        assert!(!temp_path2.join("side.txt").exists());
+        assert!(git2(&["log", "-1", "--format=%b"]).unwrap().contains("reversing\nchanges made to"));
+    }
+}
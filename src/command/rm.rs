@@ -5,20 +5,27 @@ use std::{
         PathBuf,
         Path
     },
-    fs::remove_file,
+    fs::{self, remove_file},
+    collections::BTreeMap,
 };
 use crate::{
     GitError,
     Result,
+    command::ReadTree,
     utils::{
-        index::Index,
+        index::{Index, LockedIndexWriter},
         fs::{
             calc_relative_path,
             walk,
+            read_object,
         },
+        commit::Commit,
+        diff::flatten_tree,
+        refs::head_to_hash,
     }
 };
 use super::SubCommand;
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
 
 
 #[derive(Parser, Debug)]
@@ -33,6 +40,9 @@ pub struct Rm {
     #[arg(short='r', long="recursive", help = "rm dir recursively")]
     recursive: bool,
 
+    #[arg(short='f', long, help = "remove files even when they have local or staged modifications")]
+    force: bool,
+
     #[arg(required = true, value_name="paths", num_args = 1..)]
     paths: Vec<PathBuf>,
 }
@@ -44,9 +54,10 @@ impl Rm {
         Ok(Box::new(a))
     }
 
-    fn walks_all_path(&self, project_root: PathBuf, index: &Index) -> Result<impl IntoIterator<Item = PathBuf> + use<>> {
+    fn walks_all_path(&self, repo: &Repository, index: &Index) -> Result<impl IntoIterator<Item = PathBuf> + use<>> {
+        let project_root = repo.work_tree()?.to_path_buf();
         let paths = self.paths.iter()
-            .map(|path|calc_relative_path(&project_root, path))
+            .map(|path|calc_relative_path(&project_root, repo.resolve(path)))
             .collect::<Result<Vec<_>>>()?
             .into_iter()
             .unique()
@@ -58,7 +69,7 @@ impl Rm {
 
         let possible_dir = paths
             .iter()
-            .filter(|p|project_root.join(p).is_dir())
+            .filter(|p|project_root.join(p).is_dir() && !project_root.join(p).is_symlink())
             // .map(|x| {
                 // println!("possible_dir x = {}", x.display());
                 // x
@@ -66,7 +77,7 @@ impl Rm {
             .collect::<Vec<_>>();
         let possible_file = paths
             .iter()
-            .filter(|p|project_root.join(p).is_file())
+            .filter(|p|project_root.join(p).is_file() || project_root.join(p).is_symlink())
             .cloned()
             // .map(|x| {
                 // println!("possible_file x = {}", x.display());
@@ -81,7 +92,7 @@ impl Rm {
         }
         else if let Some(path) = possible_file
             .iter()
-            .filter(|p| !index.entries.iter().any(|en| en.name == p.to_str().unwrap()))
+            .filter(|p| !index.entries.iter().any(|en| en.name == **p))
             .take(1).next()
         {
             // println!("{} 不在index中", path.display());
@@ -112,25 +123,86 @@ impl Rm {
     }
 }
 
+impl Rm {
+    /// like git, refuse to lose content which is neither in HEAD nor in the worktree (or index with --cached)
+    fn check_local_modifications(&self, gitdir: &Path, index: &Index, paths: &[PathBuf]) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let head = match head_to_hash(gitdir) {
+            Ok(commit) => flatten_tree(gitdir, &read_object::<Commit>(gitdir.to_path_buf(), &commit)?.tree_hash)?,
+            Err(_) => BTreeMap::new(),
+        };
+
+        let (mut both, mut staged, mut local) = (vec![], vec![], vec![]);
+        for entry in index.entries.iter().filter(|entry| paths.contains(&entry.name)) {
+            // 工作区里已经没有的文件不会丢失任何内容
+            if fs::symlink_metadata(project_root.join(&entry.name)).is_err() {
+                continue;
+            }
+            let staged_changes = !head.get(&entry.name).is_some_and(|(mode, hash)| *mode == entry.mode && *hash == entry.hash);
+            let local_changes = !ReadTree::is_uptodate(project_root, entry)?;
+            match (staged_changes, local_changes) {
+                (true, true) => both.push(entry.name.display()),
+                (true, false) if !self.cached => staged.push(entry.name.display()),
+                (false, true) if !self.cached => local.push(entry.name.display()),
+                _ => {},
+            }
+        }
+
+        let keep_hint = "(use --cached to keep the file, or -f to force removal)";
+        let errors = [
+            (both, "staged content different from both the\nfile and the HEAD:", "(use -f to force removal)"),
+            (staged, "changes staged in the index:", keep_hint),
+            (local, "local modifications:", keep_hint),
+        ]
+            .into_iter()
+            .filter(|(files, _, _)| !files.is_empty())
+            .map(|(files, what, hint)| {
+                let subject = if files.len() == 1 { "the following file has" } else { "the following files have" };
+                let files = files.iter().map(|file| format!("    {}\n", file)).collect::<String>();
+                format!("error: {} {}\n{}{}", subject, what, files, hint)
+            })
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(GitError::merge_conflict(errors.join("\n")))
+        }
+    }
+}
+
 impl SubCommand for Rm {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = repo.gitdir.clone();
         let index_file = gitdir.join("index");
-        let project_root = gitdir.parent().expect("find git dir implementation fail");
+        let project_root = repo.work_tree()?;
+        let lock = LockedIndexWriter::lock(&index_file)?;
 
-        let mut index = Index::new();
-        if index_file.exists() {
-            index = index.read_from_file(&gitdir.join("index"))?;
-        }
+        let mut index = Index::read_or_default(&gitdir)?;
         // println!("index_file exists index = {:?}", index);
-        let all_paths = self.walks_all_path(project_root.to_path_buf(), &index)?;
+        // untracked files found inside a directory are not touched
+        let all_paths = self.walks_all_path(&repo, &index)?
+            .into_iter()
+            .filter(|path| index.entries.iter().any(|en| en.name == *path))
+            .collect::<Vec<_>>();
+        // 先检查所有文件，有一个不能删就什么都不做
+        if !self.force {
+            self.check_local_modifications(&gitdir, &index, &all_paths)?;
+        }
+        for path in &all_paths {
+            println!("rm '{}'", path.display());
+        }
+        if self.dry_run {
+            return Ok(0);
+        }
         if self.cached {
             all_paths.into_iter()
             .for_each(|path| {
                 if let Some((idx, _)) = index.entries
                     .iter()
                     .enumerate()
-                    .find(|(_, en)|en.name == path.to_str().unwrap())
+                    .find(|(_, en)|en.name == path)
                 {
                     // println!("rm {}", path.display());
                     index.entries.remove(idx);
@@ -147,11 +219,12 @@ impl SubCommand for Rm {
                 if let Some((idx, _)) = index.entries
                     .iter()
                     .enumerate()
-                    .find(|(_, en)|en.name == path.to_str().unwrap())
+                    .find(|(_, en)|en.name == path)
                 {
                     let path = project_root.join(index.entries[idx].name.clone());
                     let result = remove_file(&path)
-                        .map_err(|e|GitError::failed_to_remove_file(format!("unable to remove file {} due to {}", path.clone().display(), e)));
+                        .map_err(|e|GitError::failed_to_remove_file(format!("{}: {}", path.display(), e)));
+                    remove_empty_parents(project_root, &path);
                     removed_file.push(result);
                     index.entries.remove(idx);
                     // println!("rm {}", path.display());
@@ -163,7 +236,7 @@ impl SubCommand for Rm {
             removed_file.into_iter()
                 .collect::<Result<Vec<_>>>()?;
         }
-        index.write_to_file(&index_file)?;
+        lock.commit(&index)?;
         Ok(0)
     }
 }
@@ -220,11 +293,9 @@ mod test {
         let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
         assert_eq!(
             origin.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>(),
             real.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>()
         );
@@ -264,16 +335,66 @@ mod test {
         let real = shell_spawn(&["git", "-C", temp_path_str2, "ls-files", "--stage"]).unwrap();
         assert_eq!(
             real.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>(),
             origin.split("\n")
-                .into_iter()
                 .sorted()
                 .collect::<String>()
         );
     }
 
+    #[test]
+    fn test_rm_local_modifications() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        for name in ["local", "staged", "both", "clean", "other"] {
+            std::fs::write(temp_path1.join(name), name).unwrap();
+        }
+        shell_spawn(&["git", "-C", temp_path_str1, "add", "."]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "commit", "-m", "init"]).unwrap();
+        std::fs::write(temp_path1.join("local"), "changed").unwrap();
+        std::fs::write(temp_path1.join("staged"), "changed").unwrap();
+        std::fs::write(temp_path1.join("both"), "changed").unwrap();
+        std::fs::write(temp_path1.join("new"), "new").unwrap();
+        shell_spawn(&["git", "-C", temp_path_str1, "add", "staged", "both", "new"]).unwrap();
+        std::fs::write(temp_path1.join("both"), "changed again").unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let run = |cmd: &[&str], args: &[&str]| {
+            let output = std::process::Command::new(cmd[0]).args(&cmd[1..]).args(args).output().unwrap();
+            (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned(), String::from_utf8_lossy(&output.stderr).into_owned())
+        };
+        let git = &["git", "-C", temp_path_str1];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        // the refused ones come first, each has to leave everything as it was
+        let cmds: &[&[&str]] = &[
+            &["rm", "local"],
+            &["rm", "staged"],
+            &["rm", "new"],
+            &["rm", "clean", "both"],
+            &["rm", "local", "staged", "both", "new"],
+            &["rm", "--cached", "both"],
+            &["rm", "-n", "clean", "local", "-f"],
+            &["rm", "--dry-run", "clean"],
+            &["rm", "--cached", "local", "staged"],
+            &["rm", "-f", "both"],
+            &["rm", "clean"],
+        ];
+        for args in cmds {
+            let origin = run(git, args);
+            let real = run(cargo, args);
+            assert_eq!(origin, real, "{:?}", args);
+            let status = |path: &str| shell_spawn(&["git", "-C", path, "status", "--porcelain", "--untracked-files=all"]).unwrap();
+            assert_eq!(status(temp_path_str1), status(temp_path_str2), "{:?}", args);
+        }
+        assert!(!temp_path2.join("clean").exists() && temp_path2.join("local").exists());
+    }
+
     #[test]
     fn test_ppt_rm() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -0,0 +1,165 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+use clap::Parser;
+
+use crate::{
+    Result,
+    utils::{
+        hash::ObjectId,
+        ident::split_ident,
+        revparse::resolve_commit,
+        revwalk,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "shortlog", about = "Summarize the history, grouped by author")]
+pub struct Shortlog {
+    #[arg(short = 'n', long, help = "sort by the number of commits instead of by author")]
+    numbered: bool,
+
+    #[arg(short = 's', long, help = "only print the number of commits per author")]
+    summary: bool,
+
+    #[arg(short = 'e', long, help = "show the email address of each author")]
+    email: bool,
+
+    #[arg(allow_hyphen_values = true, help = "the commits to summarize, `a..b` and `^a` exclude, HEAD by default")]
+    revisions: Vec<String>,
+}
+
+impl Shortlog {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Shortlog::try_parse_from(args)?))
+    }
+
+    /// the first paragraph of a message on one line, like `%s`
+    fn subject(message: &str) -> String {
+        message.trim_start_matches('\n')
+            .lines()
+            .take_while(|line| !line.trim().is_empty())
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// the commits to walk from and the ones whose history is left out
+    fn ranges(&self, repo: &Repository) -> Result<(Vec<ObjectId>, Vec<ObjectId>)> {
+        let (mut include, mut exclude) = (Vec::new(), Vec::new());
+        let revisions = match self.revisions.is_empty() {
+            true => vec!["HEAD".to_string()],
+            false => self.revisions.clone(),
+        };
+        for rev in &revisions {
+            if let Some((from, to)) = rev.split_once("..") {
+                let or_head = |rev: &str| if rev.is_empty() { "HEAD".to_string() } else { rev.to_string() };
+                exclude.push(resolve_commit(&repo.gitdir, &or_head(from))?);
+                include.push(resolve_commit(&repo.gitdir, &or_head(to))?);
+            } else if let Some(rev) = rev.strip_prefix('^') {
+                exclude.push(resolve_commit(&repo.gitdir, rev)?);
+            } else {
+                include.push(resolve_commit(&repo.gitdir, rev)?);
+            }
+        }
+        Ok((include, exclude))
+    }
+}
+
+impl SubCommand for Shortlog {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let (include, exclude) = self.ranges(&repo)?;
+        let mut cache = HashMap::new();
+        let excluded = revwalk::reachable(gitdir, exclude, &mut cache)?.into_iter().collect::<HashSet<_>>();
+
+        // newest first while walking, each author's list is printed oldest first like git does
+        let mut authors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for hash in revwalk::date_order(gitdir, include, &mut cache)?.into_iter().rev() {
+            if excluded.contains(&hash) {
+                continue;
+            }
+            let commit = &cache[&hash];
+            let who = split_ident(&commit.author).map(|(who, _, _)| who).unwrap_or(&commit.author);
+            let author = match self.email {
+                true => who.to_string(),
+                false => who.rsplit_once(" <").map(|(name, _)| name).unwrap_or(who).to_string(),
+            };
+            authors.entry(author).or_default().push(Self::subject(&commit.message));
+        }
+
+        let mut authors = authors.into_iter().collect::<Vec<_>>();
+        if self.numbered {
+            authors.sort_by_key(|(_, subjects)| Reverse(subjects.len()));
+        }
+        for (author, subjects) in authors {
+            if self.summary {
+                println!("{:>6}\t{}", subjects.len(), author);
+                continue;
+            }
+            println!("{} ({}):", author, subjects.len());
+            for subject in subjects {
+                println!("      {}", subject);
+            }
+            println!();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{shell_spawn, setup_test_git_dir};
+
+    #[test]
+    fn test_shortlog() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "shortlog"][..], args].concat()).unwrap();
+        // one commit a minute by a given author, so the walk order is fixed
+        let mut time = 1600000000;
+        let mut commit = |author: (&str, &str), args: &[&str]| {
+            time += 60;
+            let date = format!("{} +0000", time);
+            let status = Command::new("git").arg("-C").arg(temp_path_str).args(args)
+                .env("GIT_AUTHOR_NAME", author.0)
+                .env("GIT_AUTHOR_EMAIL", author.1)
+                .env("GIT_AUTHOR_DATE", &date)
+                .env("GIT_COMMITTER_DATE", &date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", args);
+        };
+
+        let bob = ("Bob", "bob@example.com");
+        let zed = ("Zed", "zed@example.com");
+        commit(bob, &["commit", "--allow-empty", "-m", "bob 1\n\nwith a body"]);
+        commit(zed, &["commit", "--allow-empty", "-m", "zed 1\nspread over\ntwo lines"]);
+        commit(bob, &["commit", "--allow-empty", "-m", "bob 2"]);
+        git(&["checkout", "-q", "-b", "side"]);
+        commit(("Bob", "other@example.com"), &["commit", "--allow-empty", "-m", "bob elsewhere"]);
+        git(&["checkout", "-q", "master"]);
+        commit(zed, &["commit", "--allow-empty", "-m", "zed 2"]);
+        commit(zed, &["merge", "--no-ff", "-m", "zed 3", "side"]);
+
+        // git reads a log from stdin without revisions, so it always gets one here
+        for (ours, theirs) in [
+            (&[][..], &["HEAD"][..]),
+            (&["-n"], &["-n", "HEAD"]),
+            (&["-s"], &["-s", "HEAD"]),
+            (&["-sne"], &["-sne", "HEAD"]),
+            (&["-e", "side"], &["-e", "side"]),
+            (&["HEAD~2..HEAD"], &["HEAD~2..HEAD"]),
+            (&["master", "^side"], &["master", "^side"]),
+            (&["-s", "..side"], &["-s", "..side"]),
+        ] {
+            assert_eq!(cargo(ours), git(&[&["shortlog"][..], theirs].concat()), "{:?}", ours);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    Result,
+    command::Notes,
+    utils::{
+        commit::Commit,
+        diff::diff_commit,
+        fs::read_obj,
+        hash::ObjectId,
+        ident::{format_date, split_ident},
+        objtype::Obj,
+        revparse::rev_parse,
+        tag::Tag,
+        tree::{FileMode, Tree},
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "show", about = "Show various types of objects")]
+pub struct Show {
+    #[arg(default_value = "HEAD", help = "the object to show")]
+    objects: Vec<String>,
+}
+
+impl Show {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Show::try_parse_from(args)?))
+    }
+
+    /// `Author: name <email>` and `Date:   ...` lines
+    fn format_ident(label: &str, ident: &str) -> String {
+        match split_ident(ident) {
+            Some((who, ts, tz)) => format!("{}: {}\nDate:   {}\n", label, who, format_date(ts, tz)),
+            None => format!("{}: {}\n", label, ident),
+        }
+    }
+
+    fn indent(message: &str) -> String {
+        message.trim_end_matches('\n')
+            .lines()
+            .map(|line| format!("    {}\n", line))
+            .collect()
+    }
+
+    /// `commit <hash>`, the author and the indented message, as `git log` shows a commit
+    pub(crate) fn commit_header(hash: &ObjectId, commit: &Commit) -> String {
+        let mut out = format!("commit {}\n", hash);
+        if commit.parent_hash.len() > 1 {
+            let parents = commit.parent_hash.iter().map(|p| p.to_hex()[..7].to_string()).collect::<Vec<_>>();
+            out += &format!("Merge: {}\n", parents.join(" "));
+        }
+        out += &Self::format_ident("Author", &commit.author);
+        out += "\n";
+        out += &Self::indent(&commit.message);
+        out
+    }
+
+    fn show_commit(gitdir: &Path, hash: &ObjectId, commit: &Commit) -> Result<String> {
+        let mut out = Self::commit_header(hash, commit);
+        if let Some(note) = Notes::read(gitdir, hash)? {
+            out += "\nNotes:\n";
+            out += &Self::indent(&note);
+        }
+
+        // merge commits would need a combined diff, which we leave out like a clean `git show` does
+        if commit.parent_hash.len() <= 1 {
+            let diff = diff_commit(gitdir, commit)?;
+            if !diff.is_empty() {
+                out += "\n";
+                out += &diff;
+            }
+        }
+        Ok(out)
+    }
+
+    fn show_tree(name: &str, tree: &Tree) -> String {
+        let mut out = format!("tree {}\n\n", name);
+        for entry in tree.iter() {
+            let suffix = if entry.mode == FileMode::Tree { "/" } else { "" };
+            out += &format!("{}{}\n", entry.path.display(), suffix);
+        }
+        out
+    }
+
+    fn show_tag(gitdir: &Path, tag: &Tag) -> Result<Vec<u8>> {
+        let mut out = format!("tag {}\n", tag.tag);
+        if !tag.tagger.is_empty() {
+            out += &Self::format_ident("Tagger", &tag.tagger);
+        }
+        out += "\n";
+        out += &tag.message;
+        if !tag.message.is_empty() && !tag.message.ends_with('\n') {
+            out += "\n";
+        }
+        out += "\n";
+
+        let mut bytes = out.into_bytes();
+        bytes.extend(Self::show_object(gitdir, &tag.object.to_hex(), &tag.object)?);
+        Ok(bytes)
+    }
+
+    fn show_object(gitdir: &Path, name: &str, hash: &ObjectId) -> Result<Vec<u8>> {
+        Ok(match read_obj(gitdir.to_path_buf(), hash)? {
+            Obj::B(blob) => blob.into(),
+            Obj::T(tree) => Self::show_tree(name, &tree).into_bytes(),
+            Obj::C(commit) => Self::show_commit(gitdir, hash, &commit)?.into_bytes(),
+            Obj::G(tag) => Self::show_tag(gitdir, &tag)?,
+        })
+    }
+}
+
+impl SubCommand for Show {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let mut stdout = io::stdout();
+
+        for (i, name) in self.objects.iter().enumerate() {
+            let hash = rev_parse(&gitdir, name)?;
+            if i > 0 {
+                stdout.write_all(b"\n")?;
+            }
+            stdout.write_all(&Self::show_object(&gitdir, name, &hash)?)?;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_show() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        std::fs::create_dir_all(temp_path.join("dir")).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "fn main\n1\n2\n3\n4\n5\n6\n7\n8\n").unwrap();
+        std::fs::write(temp_path.join("dir/b.txt"), "b").unwrap();
+        std::fs::write(temp_path.join("gone.txt"), "gone\n").unwrap();
+        let git = &["git", "-C", temp_path_str];
+        let setup_cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["commit", "-m", "first\n\nbody line"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+
+        std::fs::write(temp_path.join("a.txt"), "fn main\n1\n2\n3\n4\n5\n6\nseven\n8\n").unwrap();
+        std::fs::write(temp_path.join("dir/b.txt"), "b2").unwrap();
+        std::fs::write(temp_path.join("new.txt"), "new\n").unwrap();
+        std::fs::remove_file(temp_path.join("gone.txt")).unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["add", "-A"], false),
+            (&["commit", "-m", "second"], false),
+            (&["tag", "-a", "v1", "-m", "release v1"], false),
+        ];
+        cmd_seq(setup_cmds)(git).unwrap();
+
+        let blob = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD:a.txt"]).unwrap();
+        let tree = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "HEAD^{tree}"]).unwrap();
+        let revs = [
+            "HEAD", "HEAD~1", "v1", blob.trim(), tree.trim(),
+            // paths in trees and in the index, peeled tags and commits
+            "HEAD:a.txt", "HEAD~1:dir/b.txt", "v1:dir", "HEAD:", ":new.txt", "v1^{}", "v1^{tree}", "HEAD~1^{commit}",
+        ];
+        for rev in revs {
+            let origin = shell_spawn(&["git", "-C", temp_path_str, "show", rev]).unwrap();
+            let real = shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "show", rev]).unwrap();
+            assert_eq!(origin, real, "show {}", rev);
+        }
+
+        for rev in ["no-such-rev", "HEAD:missing.txt", "HEAD:a.txt/deeper", "v1^{blob}"] {
+            let status = std::process::Command::new("cargo")
+                .args(["run", "--quiet", "--", "-C", temp_path_str, "show", rev])
+                .output()
+                .unwrap()
+                .status;
+            assert_eq!(status.code(), Some(128), "{}", rev);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::Path;
+use clap::Parser;
+
+use crate::{
+    Result,
+    utils::{
+        hash::ObjectId,
+        refs::{for_each_ref, head_to_hash},
+        revparse::rev_parse,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "show-ref", about = "List references in a local repository")]
+pub struct ShowRef {
+    #[arg(long, help = "limit to refs/heads")]
+    heads: bool,
+
+    #[arg(long, help = "limit to refs/tags")]
+    tags: bool,
+
+    #[arg(long, help = "show HEAD as well")]
+    head: bool,
+
+    #[arg(long, help = "every pattern is the exact name of a ref, fail on a missing one")]
+    verify: bool,
+
+    #[arg(short = 'q', long, help = "print nothing, only the exit status tells whether refs matched")]
+    quiet: bool,
+
+    #[arg(short = 'd', long, help = "also show what annotated tags point at, as `<name>^{}`")]
+    dereference: bool,
+
+    #[arg(short = 's', long = "hash", value_name = "n", num_args = 0..=1, require_equals = true, default_missing_value = "0",
+        help = "print only the hash, abbreviated to n characters when given")]
+    hash: Option<usize>,
+
+    #[arg(long, value_name = "n", num_args = 0..=1, require_equals = true, default_missing_value = "7",
+        help = "abbreviate the hashes to n characters")]
+    abbrev: Option<usize>,
+
+    #[arg(help = "only show refs whose name matches the pattern at a path component boundary")]
+    patterns: Vec<String>,
+}
+
+impl ShowRef {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(ShowRef::try_parse_from(args)?))
+    }
+
+    fn wanted(&self, name: &str) -> bool {
+        let kind = match (self.heads, self.tags) {
+            (false, false) => true,
+            (heads, tags) => (heads && name.starts_with("refs/heads/")) || (tags && name.starts_with("refs/tags/")),
+        };
+        // like git, a pattern matches the whole name or its tail after a `/`
+        kind && (self.patterns.is_empty() || self.patterns.iter().any(|pattern| {
+            name == pattern || name.ends_with(&format!("/{}", pattern))
+        }))
+    }
+
+    fn show(&self, gitdir: &Path, hash: &ObjectId, name: &str) {
+        if self.quiet {
+            return;
+        }
+        let abbrev = |hash: &ObjectId| {
+            let hex = hash.to_hex();
+            match self.hash.filter(|&n| n > 0).or(self.abbrev) {
+                Some(n) => hex[..n.clamp(4, hex.len())].to_string(),
+                None => hex,
+            }
+        };
+        match self.hash {
+            Some(_) => println!("{}", abbrev(hash)),
+            None => println!("{} {}", abbrev(hash), name),
+        }
+        // like git, the peeled line keeps its name even with --hash
+        if self.dereference && let Ok(peeled) = rev_parse(gitdir, &format!("{}^{{}}", hash)) && peeled != *hash {
+            println!("{} {}^{{}}", abbrev(&peeled), name);
+        }
+    }
+}
+
+impl SubCommand for ShowRef {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        let refs = for_each_ref(&gitdir, "refs/")?;
+
+        if self.verify {
+            for pattern in &self.patterns {
+                let hash = match pattern.as_str() {
+                    "HEAD" => head_to_hash(&gitdir).ok(),
+                    name if name.starts_with("refs/") => refs.iter().find(|(refname, _)| refname == name).map(|(_, hash)| *hash),
+                    _ => None,
+                };
+                match hash {
+                    Some(hash) => self.show(&gitdir, &hash, pattern),
+                    None => {
+                        if !self.quiet {
+                            eprintln!("fatal: '{}' - not a valid ref", pattern);
+                        }
+                        return Ok(1);
+                    },
+                }
+            }
+            return Ok(0);
+        }
+
+        let mut found = false;
+        if let Some(hash) = self.head.then(|| head_to_hash(&gitdir).ok()).flatten() {
+            found = true;
+            self.show(&gitdir, &hash, "HEAD");
+        }
+        for (name, hash) in refs.iter().filter(|(name, _)| self.wanted(name)) {
+            found = true;
+            self.show(&gitdir, hash, name);
+        }
+        Ok(if found { 0 } else { 1 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_show_ref() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        git(&["commit", "--allow-empty", "-m", "one"]).unwrap();
+        git(&["branch", "feature/deep/x"]).unwrap();
+        git(&["branch", "x"]).unwrap();
+        git(&["tag", "light"]).unwrap();
+        git(&["tag", "-a", "v1", "-m", "v1"]).unwrap();
+        // packed refs, one of them updated by a loose ref afterwards
+        git(&["pack-refs", "--all"]).unwrap();
+        git(&["commit", "--allow-empty", "-m", "two"]).unwrap();
+        git(&["branch", "late"]).unwrap();
+        git(&["update-ref", "refs/remotes/origin/x", "HEAD"]).unwrap();
+
+        let listings: &[&[&str]] = &[
+            &[], &["--head"], &["--heads"], &["--tags"], &["--heads", "--tags"], &["x"], &["deep/x"], &["--tags", "v1"],
+            &["--verify", "refs/heads/feature/deep/x", "refs/tags/v1", "HEAD"], &["-q", "master"],
+            &["-d"], &["-d", "--abbrev"], &["--abbrev=5", "--head"], &["-s"], &["--hash=10", "-d", "v1"],
+            &["--verify", "-d", "refs/tags/v1", "refs/tags/light"],
+        ];
+        for args in listings {
+            let args = [&["show-ref"][..], args].concat();
+            assert_eq!(cargo(&args).unwrap(), git(&args).unwrap(), "{:?}", args);
+        }
+
+        // nothing matched and a missing ref for --verify are exit status 1
+        let status = |args: &[&str]| std::process::Command::new("cargo")
+            .args([&["run", "--quiet", "--", "-C", temp_path_str, "show-ref"][..], args].concat())
+            .output()
+            .unwrap()
+            .status
+            .code();
+        assert_eq!(status(&["nope"]), Some(1));
+        assert_eq!(status(&["--verify", "refs/heads/nope"]), Some(1));
+        assert_eq!(status(&["--verify", "master"]), Some(1));
+
+        // a packed branch is a branch like any other
+        cargo(&["branch", "-D", "x"]).unwrap();
+        assert!(cargo(&["show-ref", "--verify", "refs/heads/x"]).is_err());
+        assert_eq!(cargo(&["show-ref"]).unwrap(), git(&["show-ref"]).unwrap());
+    }
+}
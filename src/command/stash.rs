@@ -0,0 +1,442 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    command::{Checkout, WriteTree},
+    utils::{
+        blob::Blob,
+        commit::Commit,
+        diff::{changed_paths, flatten_tree, Files},
+        fs::{add_objects, read_object, read_worktree_file, write_object},
+        pool::default_threads,
+        hash::{hash_object, ObjectId},
+        ident,
+        index::{Index, IndexEntry, LockedIndexWriter},
+        refs::{
+            current_branch,
+            delete_ref,
+            head_to_hash,
+            read_ref_commit,
+            read_reflog,
+            write_ref_commit,
+            write_ref_commit_with_log,
+            write_reflog,
+        },
+    },
+    GitError,
+    Result,
+};
+use super::SubCommand;
+use crate::utils::fs::{Repository, remove_empty_parents, work_tree};
+
+const STASH_REF: &str = "refs/stash";
+
+#[derive(Parser, Debug)]
+#[command(name = "stash", about = "Stash the changes in a dirty working directory away")]
+pub struct Stash {
+    #[command(subcommand)]
+    command: Option<StashCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum StashCommand {
+    /// save the local changes and go back to HEAD (default)
+    Push {
+        /// description of the stash entry
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// apply a stash entry to index and worktree, then drop it
+    Pop {
+        /// `stash@{n}` or `n`, the latest entry by default
+        stash: Option<String>,
+    },
+    /// apply a stash entry to index and worktree, keeping it on the stack
+    Apply {
+        /// `stash@{n}` or `n`, the latest entry by default
+        stash: Option<String>,
+    },
+    /// forget a stash entry without applying it
+    Drop {
+        /// `stash@{n}` or `n`, the latest entry by default
+        stash: Option<String>,
+    },
+    /// list the stash entries, newest first
+    List,
+}
+
+impl Stash {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Stash::try_parse_from(args)?))
+    }
+
+    fn index_files(index: &Index) -> Files {
+        index.entries.iter()
+            .map(|entry| (entry.name.clone(), (entry.mode, entry.hash)))
+            .collect()
+    }
+
+    fn commit_files(gitdir: &Path, hash: &ObjectId) -> Result<Files> {
+        let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)?;
+        flatten_tree(gitdir, &commit.tree_hash)
+    }
+
+    /// the worktree version of every tracked file as a tree, untracked files are not part of a stash
+    fn worktree_tree(gitdir: &Path, index: &Index) -> Result<ObjectId> {
+        let project_root = &work_tree(gitdir)?;
+        // a deleted file is left out of the tree
+        let paths = index.entries.iter()
+            .map(|entry| PathBuf::from(&entry.name))
+            .filter(|name| project_root.join(name).is_file() || project_root.join(name).is_symlink())
+            .collect::<Vec<_>>();
+        let entries = add_objects::<Blob>(gitdir, &paths, default_threads())?;
+        WriteTree::build_tree_recursive(gitdir, &entries, b"")
+    }
+
+    /// `(mode, hash)` of the file as it is in the worktree right now
+    fn worktree_file(project_root: &Path, path: &Path) -> Result<Option<(u32, ObjectId)>> {
+        let full_path = project_root.join(path);
+        if !full_path.is_file() && !full_path.is_symlink() {
+            return Ok(None);
+        }
+        let (mode, content) = read_worktree_file(&full_path)?;
+        Ok(Some((mode, hash_object::<Blob>(content)?)))
+    }
+
+    /// bring `paths` to the given versions, missing from `worktree`/`staged` means deleted,
+    /// `index` was read under `lock` which is held until the new index is in place
+    fn checkout_paths(gitdir: &Path, lock: LockedIndexWriter, mut index: Index, paths: &BTreeSet<PathBuf>, worktree: &Files, staged: &Files) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+
+        for path in paths {
+            let full_path = project_root.join(path);
+            if full_path.is_file() || full_path.is_symlink() {
+                fs::remove_file(&full_path)
+                    .map_err(|_| GitError::failed_to_remove_file(full_path.display().to_string()))?;
+            }
+            match worktree.get(path) {
+                Some((mode, hash)) => {
+                    let parent = full_path.parent().unwrap();
+                    fs::create_dir_all(parent)
+                        .map_err(|_| GitError::failed_to_write_file(&parent.to_string_lossy()))?;
+                    let content: Vec<u8> = Checkout::read_blob(gitdir, hash)?.into();
+                    if *mode == 0o120000 {
+                        symlink(String::from_utf8_lossy(&content).as_ref(), &full_path)?;
+                    }
+                    else {
+                        fs::write(&full_path, content)
+                            .map_err(|_| GitError::failed_to_write_file(&full_path.to_string_lossy()))?;
+                        let permissions = if *mode == 0o100755 { 0o755 } else { 0o644 };
+                        fs::set_permissions(&full_path, fs::Permissions::from_mode(permissions))?;
+                    }
+                },
+                None => {
+                    remove_empty_parents(project_root, &full_path);
+                },
+            }
+
+            match staged.get(path) {
+                Some((mode, hash)) => {
+                    let entry = IndexEntry::new(*mode, *hash, path.clone());
+                    // only a file that matches the index may carry its stat data
+                    let entry = if worktree.get(path) == staged.get(path) { entry.with_stat(&full_path) } else { entry };
+                    index.add_entry(entry);
+                },
+                None => {
+                    index.remove_entry(path);
+                },
+            }
+        }
+
+        lock.commit(&index)?;
+        Ok(())
+    }
+
+    /// `master: 1a2b3c4 subject`, how git describes where a stash was made
+    fn describe_head(gitdir: &Path, head: &ObjectId) -> Result<String> {
+        let commit = read_object::<Commit>(gitdir.to_path_buf(), head)?;
+        let branch = current_branch(gitdir).unwrap_or_else(|_| "(no branch)".to_string());
+        Ok(format!("{}: {} {}", branch, &head.to_hex()[..7], commit.message.lines().next().unwrap_or_default()))
+    }
+
+    fn write_commit(gitdir: &Path, tree_hash: ObjectId, parents: Vec<ObjectId>, message: String) -> Result<ObjectId> {
+        let commit = Commit::new(
+            tree_hash,
+            parents,
+            ident::author(gitdir)?,
+            ident::committer(gitdir)?,
+            &message,
+        );
+        write_object::<Commit>(gitdir.to_path_buf(), commit.into())
+    }
+
+    fn push(gitdir: &Path, message: Option<&str>) -> Result<i32> {
+        let head = head_to_hash(gitdir)
+            .map_err(|_| GitError::invalid_command("You do not have the initial commit yet".to_string()))?;
+        // locked before the stash is recorded, so it is never saved without the worktree being reset
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let index = Index::read_or_default(gitdir)?;
+        let index_tree = WriteTree::lazy_fucker(gitdir.to_path_buf())?;
+        let worktree_tree = Self::worktree_tree(gitdir, &index)?;
+
+        let head_files = Self::commit_files(gitdir, &head)?;
+        let staged = flatten_tree(gitdir, &index_tree)?;
+        let worktree = flatten_tree(gitdir, &worktree_tree)?;
+        let changed = changed_paths(&head_files, &staged)
+            .union(&changed_paths(&head_files, &worktree))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        if changed.is_empty() {
+            println!("No local changes to save");
+            return Ok(0);
+        }
+
+        // like git: the stash commit holds the worktree, its parents are HEAD and a commit of the index
+        let description = Self::describe_head(gitdir, &head)?;
+        let index_commit = Self::write_commit(gitdir, index_tree, vec![head], format!("index on {}", description))?;
+        let stash_message = match message {
+            Some(message) => format!("On {}: {}", description.split(':').next().unwrap_or_default(), message),
+            None => format!("WIP on {}", description),
+        };
+        let stash_commit = Self::write_commit(gitdir, worktree_tree, vec![head, index_commit], stash_message.clone())?;
+        write_ref_commit_with_log(gitdir, STASH_REF, &stash_commit, &stash_message)?;
+
+        Self::checkout_paths(gitdir, lock, index, &changed, &head_files, &head_files)?;
+        println!("Saved working directory and index state {}", stash_message);
+        Ok(0)
+    }
+
+    /// the position and commit of `stash@{n}`, counted from the newest entry
+    fn entry(gitdir: &Path, name: Option<&str>) -> Result<(usize, ObjectId)> {
+        let n = match name {
+            None => 0,
+            Some(name) => name.strip_prefix("stash@{").and_then(|x| x.strip_suffix('}')).unwrap_or(name)
+                .parse::<usize>()
+                .map_err(|_| GitError::invalid_command(format!("{} is not a valid reference", name)))?,
+        };
+        if n == 0 {
+            let stash = read_ref_commit(gitdir, STASH_REF)
+                .map_err(|_| GitError::invalid_command("No stash entries found.".to_string()))?;
+            return Ok((0, stash));
+        }
+        read_reflog(gitdir, STASH_REF).unwrap_or_default()
+            .into_iter()
+            .rev()
+            .nth(n)
+            .map(|entry| (n, entry.new))
+            .ok_or_else(|| GitError::invalid_command(format!("stash@{{{}}} is not a valid reference", n)))
+    }
+
+    fn apply(gitdir: &Path, stash: &ObjectId) -> Result<()> {
+        let project_root = &work_tree(gitdir)?;
+        let stash_commit = read_object::<Commit>(gitdir.to_path_buf(), stash)?;
+        let (base, index_commit) = match stash_commit.parent_hash.as_slice() {
+            [base, index_commit, ..] => (*base, *index_commit),
+            _ => return Err(GitError::invalid_command(format!("{} is not a stash-like commit", stash))),
+        };
+        let base_files = Self::commit_files(gitdir, &base)?;
+        let staged = Self::commit_files(gitdir, &index_commit)?;
+        let worktree = flatten_tree(gitdir, &stash_commit.tree_hash)?;
+        let changed = changed_paths(&base_files, &staged)
+            .union(&changed_paths(&base_files, &worktree))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        // no merging here: every path the stash touches must be untouched since the stash was made
+        let head_files = match head_to_hash(gitdir) {
+            Ok(head) => Self::commit_files(gitdir, &head)?,
+            Err(_) => Files::new(),
+        };
+        let lock = LockedIndexWriter::lock(&gitdir.join("index"))?;
+        let current = Index::read_or_default(gitdir)?;
+        let index = Self::index_files(&current);
+        let mut conflicts = Vec::new();
+        for path in &changed {
+            let on_disk = Self::worktree_file(project_root, path)?;
+            let untouched = head_files.get(path) == base_files.get(path)
+                && index.get(path) == head_files.get(path)
+                && on_disk.as_ref().map(|(_, hash)| hash) == index.get(path).map(|(_, hash)| hash);
+            if !untouched {
+                conflicts.push(path.display().to_string());
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(GitError::merge_conflict(format!(
+                "Your local changes to the following files would be overwritten by merge:\n\t{}\nPlease commit your changes or stash them before you merge.\nAborting\nThe stash entry is kept in case you need it again.",
+                conflicts.join("\n\t"),
+            )));
+        }
+
+        Self::checkout_paths(gitdir, lock, current, &changed, &worktree, &staged)
+    }
+
+    /// forget stash@{n}, like `git reflog delete --rewrite` the next entry takes over its old value
+    fn drop_entry(gitdir: &Path, n: usize, stash: &ObjectId) -> Result<()> {
+        let mut entries = read_reflog(gitdir, STASH_REF).unwrap_or_default();
+        let Some(i) = entries.len().checked_sub(n + 1) else {
+            return Err(GitError::invalid_command(format!("stash@{{{}}} is not a valid reference", n)));
+        };
+        let removed = entries.remove(i);
+        if let Some(next) = entries.get_mut(i) {
+            next.old = removed.old;
+        }
+        match entries.last() {
+            Some(latest) => {
+                write_ref_commit(gitdir, STASH_REF, &latest.new)?;
+                write_reflog(gitdir, STASH_REF, &entries)?;
+            },
+            None => delete_ref(gitdir, STASH_REF)?,
+        }
+        println!("Dropped refs/stash@{{{}}} ({})", n, stash);
+        Ok(())
+    }
+}
+
+impl SubCommand for Stash {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        match &self.command {
+            None => Self::push(&gitdir, None),
+            Some(StashCommand::Push { message }) => Self::push(&gitdir, message.as_deref()),
+            Some(StashCommand::Pop { stash }) => {
+                let (n, stash) = Self::entry(&gitdir, stash.as_deref())?;
+                Self::apply(&gitdir, &stash)?;
+                Self::drop_entry(&gitdir, n, &stash)?;
+                Ok(0)
+            },
+            Some(StashCommand::Apply { stash }) => {
+                let (_, stash) = Self::entry(&gitdir, stash.as_deref())?;
+                Self::apply(&gitdir, &stash)?;
+                Ok(0)
+            },
+            Some(StashCommand::Drop { stash }) => {
+                let (n, stash) = Self::entry(&gitdir, stash.as_deref())?;
+                Self::drop_entry(&gitdir, n, &stash)?;
+                Ok(0)
+            },
+            Some(StashCommand::List) => {
+                let entries = read_reflog(&gitdir, STASH_REF).unwrap_or_default();
+                for (i, entry) in entries.iter().rev().enumerate() {
+                    println!("stash@{{{}}}: {}", i, entry.message);
+                }
+                Ok(0)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    #[test]
+    fn test_stash_round_trip() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let cargo = ["cargo", "run", "--quiet", "--", "-C", temp_path_str];
+        let rit = |args: &[&str]| shell_spawn(&[&cargo[..], args].concat());
+        let status = || shell_spawn(&["git", "-C", temp_path_str, "status", "--porcelain"]).unwrap();
+
+        fs::create_dir_all(temp_path.join("src")).unwrap();
+        for name in ["a.txt", "b.txt", "src/c.txt"] {
+            fs::write(temp_path.join(name), name).unwrap();
+        }
+        shell_spawn(&["git", "-C", temp_path_str, "add", "."]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "commit", "-m", "base"]).unwrap();
+        assert!(rit(&["stash"]).unwrap().contains("No local changes to save"));
+
+        // a staged change, an unstaged one on top of a staged one, a new file, a deletion and an untracked file
+        fs::write(temp_path.join("a.txt"), "staged a").unwrap();
+        fs::write(temp_path.join("src/new.txt"), "new").unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "add", "a.txt", "src/new.txt"]).unwrap();
+        fs::write(temp_path.join("a.txt"), "worktree a").unwrap();
+        fs::write(temp_path.join("b.txt"), "changed b").unwrap();
+        fs::remove_file(temp_path.join("src/c.txt")).unwrap();
+        fs::write(temp_path.join("untracked.txt"), "untracked").unwrap();
+        let dirty = status();
+
+        let out = rit(&["stash"]).unwrap();
+        assert!(out.contains("Saved working directory and index state WIP on master:"), "{}", out);
+        assert_eq!(status(), "?? untracked.txt\n");
+        assert_eq!(fs::read_to_string(temp_path.join("a.txt")).unwrap(), "a.txt");
+        assert!(!temp_path.join("src/new.txt").exists());
+
+        // git understands our stash commit, and we list what git lists
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "stash", "list"]).unwrap();
+        assert_eq!(rit(&["stash", "list"]).unwrap(), origin);
+        let shown = shell_spawn(&["git", "-C", temp_path_str, "stash", "show", "--name-status"]).unwrap();
+        assert_eq!(shown, "M\ta.txt\nM\tb.txt\nD\tsrc/c.txt\nA\tsrc/new.txt\n");
+
+        // a local change to a stashed path keeps pop from overwriting it
+        fs::write(temp_path.join("b.txt"), "local b").unwrap();
+        assert!(rit(&["stash", "pop"]).is_err());
+        assert_eq!(fs::read_to_string(temp_path.join("b.txt")).unwrap(), "local b");
+        shell_spawn(&["git", "-C", temp_path_str, "checkout", "b.txt"]).unwrap();
+
+        rit(&["stash", "pop"]).unwrap();
+        assert_eq!(status(), dirty);
+        assert_eq!(fs::read_to_string(temp_path.join("a.txt")).unwrap(), "worktree a");
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str, "show", ":a.txt"]).unwrap(), "staged a");
+        assert_eq!(rit(&["stash", "list"]).unwrap(), "");
+        assert!(!temp_path.join(".git/refs/stash").exists());
+
+        // entries stack up, pop takes the newest and leaves the rest to git
+        rit(&["stash", "push", "-m", "first"]).unwrap();
+        fs::write(temp_path.join("b.txt"), "second b").unwrap();
+        rit(&["stash"]).unwrap();
+        let listed = rit(&["stash", "list"]).unwrap();
+        assert!(listed.starts_with("stash@{0}: WIP on master:") && listed.ends_with("stash@{1}: On master: first\n"), "{}", listed);
+        rit(&["stash", "pop"]).unwrap();
+        assert_eq!(fs::read_to_string(temp_path.join("b.txt")).unwrap(), "second b");
+        shell_spawn(&["git", "-C", temp_path_str, "checkout", "b.txt"]).unwrap();
+        shell_spawn(&["git", "-C", temp_path_str, "stash", "pop", "--index"]).unwrap();
+        assert_eq!(status(), dirty);
+    }
+
+    #[test]
+    fn test_stash_apply_drop() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat());
+        let rit = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str][..], args].concat());
+
+        fs::write(temp_path.join("a.txt"), "base").unwrap();
+        git(&["add", "."]).unwrap();
+        git(&["commit", "-m", "base"]).unwrap();
+        for i in 0..3 {
+            fs::write(temp_path.join("a.txt"), format!("change {}", i)).unwrap();
+            rit(&["stash", "push", "-m", &format!("change {}", i)]).unwrap();
+        }
+        assert!(rit(&["stash", "apply", "stash@{3}"]).is_err());
+        assert!(rit(&["stash", "drop", "nonsense"]).is_err());
+
+        // apply keeps the entry, any entry can be named
+        rit(&["stash", "apply", "stash@{1}"]).unwrap();
+        assert_eq!(fs::read_to_string(temp_path.join("a.txt")).unwrap(), "change 1");
+        assert_eq!(rit(&["stash", "list"]).unwrap().lines().count(), 3);
+        git(&["checkout", "a.txt"]).unwrap();
+
+        // dropping from the middle leaves a stack git can still walk
+        let dropped = git(&["rev-parse", "stash@{1}"]).unwrap();
+        assert!(rit(&["stash", "drop", "1"]).unwrap().contains(dropped.trim()));
+        assert_eq!(rit(&["stash", "list"]).unwrap(), git(&["stash", "list"]).unwrap());
+        assert_eq!(git(&["stash", "list", "--format=%s"]).unwrap(), "On master: change 2\nOn master: change 0\n");
+        git(&["stash", "show", "stash@{1}"]).unwrap();
+
+        rit(&["stash", "pop", "stash@{1}"]).unwrap();
+        assert_eq!(fs::read_to_string(temp_path.join("a.txt")).unwrap(), "change 0");
+        git(&["checkout", "a.txt"]).unwrap();
+        rit(&["stash", "drop"]).unwrap();
+        assert_eq!(rit(&["stash", "list"]).unwrap(), "");
+        assert!(git(&["rev-parse", "--verify", "--quiet", "refs/stash"]).is_err());
+    }
+}
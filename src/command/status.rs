@@ -0,0 +1,419 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use clap::Parser;
+
+use crate::{
+    Result,
+    command::ReadTree,
+    utils::{
+        diff::flatten_tree,
+        fs::{calc_relative_path, quote_path, relative_to, walk},
+        hash::ObjectId,
+        ignore::Ignore,
+        index::{Index, IndexEntry},
+        pool::{default_threads, parallel_map},
+        refs::{current_branch, head_to_hash},
+        revparse::resolve_tree,
+        tree::FileMode,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "status", about = "Show the working tree status")]
+pub struct Status {
+    #[arg(short = 's', long, help = "give the output in the short format")]
+    short: bool,
+
+    #[arg(long, help = "the short format for scripts, paths are always relative to the project root")]
+    porcelain: bool,
+
+    #[arg(short = 'b', long, help = "show the branch in the short format as well")]
+    branch: bool,
+}
+
+/// the two letters of `git status -s`: HEAD against the index, then the index against the worktree
+/// a space means no change on that side
+pub type Changes = BTreeMap<PathBuf, (char, char)>;
+
+/// file type bits of a mode, a change between them is `T` rather than `M`
+const TYPE_MASK: u32 = 0o170000;
+
+impl Status {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Status::try_parse_from(args)?))
+    }
+
+    fn change(old: (u32, &ObjectId), new: (u32, &ObjectId)) -> Option<char> {
+        match (old, new) {
+            (old, new) if old == new => None,
+            ((old, _), (new, _)) if old & TYPE_MASK != new & TYPE_MASK => Some('T'),
+            _ => Some('M'),
+        }
+    }
+
+    /// the letters of a conflicted path, after the stages it has in the index
+    fn unmerged_code(stages: &BTreeSet<u8>) -> (char, char) {
+        let has = |stage| stages.contains(&stage);
+        match (has(1), has(2), has(3)) {
+            (true, false, false) => ('D', 'D'),
+            (false, true, false) => ('A', 'U'),
+            (true, false, true) => ('D', 'U'),
+            (false, false, true) => ('U', 'A'),
+            (true, true, false) => ('U', 'D'),
+            (false, true, true) => ('A', 'A'),
+            _ => ('U', 'U'),
+        }
+    }
+
+    /// HEAD against the stage 0 entries, conflicted paths are reported by their stages instead
+    pub fn staged(gitdir: &Path, index: &Index, changes: &mut Changes) -> Result<()> {
+        let head = match resolve_tree(gitdir, "HEAD") {
+            Ok(tree) => flatten_tree(gitdir, &tree)?,
+            Err(_) => BTreeMap::new(),
+        };
+        let mut unmerged: BTreeMap<PathBuf, BTreeSet<u8>> = BTreeMap::new();
+        for entry in index.entries.iter().filter(|entry| entry.stage != 0) {
+            unmerged.entry(entry.name.clone()).or_default().insert(entry.stage);
+        }
+        let staged = index.entries.iter()
+            .filter(|entry| entry.stage == 0)
+            .map(|entry| (&entry.name, (entry.mode, &entry.hash)))
+            .collect::<BTreeMap<_, _>>();
+
+        for (path, stages) in &unmerged {
+            changes.insert(path.clone(), Self::unmerged_code(stages));
+        }
+        for (path, (mode, hash)) in &head {
+            if unmerged.contains_key(path) {
+                continue;
+            }
+            let code = match staged.get(path) {
+                Some(new) => Self::change((*mode, hash), *new),
+                None => Some('D'),
+            };
+            if let Some(code) = code {
+                changes.insert(path.clone(), (code, ' '));
+            }
+        }
+        for path in staged.keys().filter(|path| !head.contains_key(path.as_path())) {
+            changes.insert((*path).clone(), ('A', ' '));
+        }
+        Ok(())
+    }
+
+    /// the stage 0 entries against the worktree, checked by stat first and by content when that is not enough
+    pub fn unstaged(project_root: &Path, index: &Index, changes: &mut Changes) -> Result<()> {
+        let entries = index.entries.iter()
+            // a submodule's worktree is its own business
+            .filter(|entry| entry.stage == 0 && entry.mode != FileMode::Commit as u32)
+            .collect::<Vec<_>>();
+        let codes = parallel_map(&entries, default_threads(), |entry: &&IndexEntry| {
+            let path = project_root.join(&entry.name);
+            let Ok(meta) = fs::symlink_metadata(&path) else {
+                return Ok(Some('D'));
+            };
+            // a file replaced by a directory is gone as far as the index is concerned
+            if meta.is_dir() {
+                return Ok(Some('D'));
+            }
+            if ReadTree::is_uptodate(project_root, entry)? {
+                return Ok(None);
+            }
+            let was_link = entry.mode & TYPE_MASK == FileMode::Symbolic as u32;
+            Ok(Some(if was_link != meta.is_symlink() { 'T' } else { 'M' }))
+        })?;
+        for (entry, code) in entries.into_iter().zip(codes) {
+            if let Some(code) = code {
+                changes.entry(entry.name.clone()).or_insert((' ', ' ')).1 = code;
+            }
+        }
+        Ok(())
+    }
+
+    /// untracked files which are not ignored, a directory without any tracked file is shown as `dir/`
+    pub fn untracked(project_root: &Path, index: &Index) -> Result<Vec<PathBuf>> {
+        let tracked = index.entries.iter().map(|entry| entry.name.as_path()).collect::<HashSet<_>>();
+        let tracked_dirs = tracked.iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect::<HashSet<_>>();
+        let ignore = Ignore::load(project_root)?;
+
+        let mut untracked = BTreeSet::new();
+        for path in walk(project_root)? {
+            let path = calc_relative_path(project_root, path)?;
+            if tracked.contains(path.as_path()) || ignore.is_ignored(&path, false) {
+                continue;
+            }
+            let mut dirs = path.ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect::<Vec<_>>();
+            dirs.reverse();
+            match dirs.into_iter().find(|dir| !tracked_dirs.contains(dir)) {
+                Some(dir) => {
+                    let mut dir = dir.as_os_str().to_owned();
+                    dir.push("/");
+                    untracked.insert(PathBuf::from(dir));
+                },
+                None => {
+                    untracked.insert(path);
+                },
+            }
+        }
+        let mut untracked = untracked.into_iter().collect::<Vec<_>>();
+        untracked.sort_by(|a, b| a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes()));
+        Ok(untracked)
+    }
+
+    /// `## branch` of the short format
+    fn short_branch(gitdir: &Path) -> String {
+        match (current_branch(gitdir), head_to_hash(gitdir)) {
+            (Ok(branch), Ok(_)) => format!("## {}", branch),
+            (Ok(branch), Err(_)) => format!("## No commits yet on {}", branch),
+            (Err(_), _) => "## HEAD (no branch)".to_string(),
+        }
+    }
+
+    fn print_long(gitdir: &Path, changes: &[(PathBuf, (char, char))], untracked: &[PathBuf], show: impl Fn(&Path) -> String) {
+        let head = head_to_hash(gitdir).ok();
+        match current_branch(gitdir) {
+            Ok(branch) => println!("On branch {}", branch),
+            Err(_) => println!("HEAD detached at {}", head.map(|hash| hash.to_hex()[..7].to_string()).unwrap_or_default()),
+        }
+        if head.is_none() {
+            println!("\nNo commits yet\n");
+        }
+
+        let is_unmerged = |(x, y): (char, char)| x == 'U' || y == 'U' || (x, y) == ('A', 'A') || (x, y) == ('D', 'D');
+        let unmerged = changes.iter().filter(|(_, code)| is_unmerged(*code)).collect::<Vec<_>>();
+        let staged = changes.iter().filter(|(_, code)| !is_unmerged(*code) && code.0 != ' ').collect::<Vec<_>>();
+        let unstaged = changes.iter().filter(|(_, code)| !is_unmerged(*code) && code.1 != ' ').collect::<Vec<_>>();
+
+        let merging = gitdir.join("MERGE_HEAD").exists();
+        if merging {
+            if unmerged.is_empty() {
+                println!("All conflicts fixed but you are still merging.");
+                println!("  (use \"git commit\" to conclude merge)\n");
+            }
+            else {
+                println!("You have unmerged paths.");
+                println!("  (fix conflicts and run \"git commit\")");
+                println!("  (use \"git merge --abort\" to abort the merge)\n");
+            }
+        }
+
+        let label = |code: char| match code {
+            'A' => "new file:",
+            'D' => "deleted:",
+            'T' => "typechange:",
+            _ => "modified:",
+        };
+        if !staged.is_empty() {
+            println!("Changes to be committed:");
+            // nothing can be unstaged in the middle of a merge
+            match head {
+                _ if merging => {},
+                Some(_) => println!("  (use \"git restore --staged <file>...\" to unstage)"),
+                None => println!("  (use \"git rm --cached <file>...\" to unstage)"),
+            }
+            for (path, (code, _)) in &staged {
+                println!("\t{:<12}{}", label(*code), show(path));
+            }
+            println!();
+        }
+        if !unmerged.is_empty() {
+            println!("Unmerged paths:");
+            println!("  (use \"git add <file>...\" to mark resolution)");
+            for (path, code) in &unmerged {
+                let what = match code {
+                    ('D', 'D') => "both deleted:",
+                    ('A', 'U') => "added by us:",
+                    ('U', 'D') => "deleted by them:",
+                    ('U', 'A') => "added by them:",
+                    ('D', 'U') => "deleted by us:",
+                    ('A', 'A') => "both added:",
+                    _ => "both modified:",
+                };
+                println!("\t{:<17}{}", what, show(path));
+            }
+            println!();
+        }
+        if !unstaged.is_empty() {
+            println!("Changes not staged for commit:");
+            if unstaged.iter().any(|(_, (_, code))| *code == 'D') {
+                println!("  (use \"git add/rm <file>...\" to update what will be committed)");
+            }
+            else {
+                println!("  (use \"git add <file>...\" to update what will be committed)");
+            }
+            println!("  (use \"git restore <file>...\" to discard changes in working directory)");
+            for (path, (_, code)) in &unstaged {
+                println!("\t{:<12}{}", label(*code), show(path));
+            }
+            println!();
+        }
+        if !untracked.is_empty() {
+            println!("Untracked files:");
+            println!("  (use \"git add <file>...\" to include in what will be committed)");
+            for path in untracked {
+                println!("\t{}", show(path));
+            }
+            println!();
+        }
+
+        if !staged.is_empty() || !unmerged.is_empty() {
+            return;
+        }
+        if !unstaged.is_empty() {
+            println!("no changes added to commit (use \"git add\" and/or \"git commit -a\")");
+        }
+        else if !untracked.is_empty() {
+            println!("nothing added to commit but untracked files present (use \"git add\" to track)");
+        }
+        else if head.is_none() {
+            println!("nothing to commit (create/copy files and use \"git add\" to track)");
+        }
+        else {
+            println!("nothing to commit, working tree clean");
+        }
+    }
+}
+
+impl SubCommand for Status {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let gitdir = &repo.gitdir;
+        let index = Index::read_or_default(gitdir)?;
+
+        let mut changes = Changes::new();
+        Self::staged(gitdir, &index, &mut changes)?;
+        Self::unstaged(repo.work_tree()?, &index, &mut changes)?;
+        let untracked = Self::untracked(repo.work_tree()?, &index)?;
+        let mut changes = changes.into_iter().collect::<Vec<_>>();
+        changes.sort_by(|a, b| a.0.as_os_str().as_bytes().cmp(b.0.as_os_str().as_bytes()));
+
+        // --porcelain keeps paths relative to the project root, the other formats to the current directory
+        let show = |path: &Path| {
+            let shown = if self.porcelain { path.to_path_buf() } else { relative_to(&repo.prefix, path) };
+            let mut quoted = quote_path(&shown);
+            if path.as_os_str().as_bytes().ends_with(b"/") && !quoted.ends_with('/') {
+                quoted.push('/');
+            }
+            quoted
+        };
+
+        if !self.short && !self.porcelain {
+            Self::print_long(gitdir, &changes, &untracked, show);
+            return Ok(0);
+        }
+        if self.branch {
+            println!("{}", Self::short_branch(gitdir));
+        }
+        for (path, (x, y)) in &changes {
+            println!("{}{} {}", x, y, show(path));
+        }
+        for path in &untracked {
+            println!("?? {}", show(path));
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_status() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let git1 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat());
+        let git2 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2][..], args].concat());
+        let formats: &[&[&str]] = &[&["--porcelain"], &["-s", "-b"], &[]];
+        let compare = |what: &str| {
+            let _ = std::fs::remove_dir_all(temp_path2);
+            let _ = cp_dir(temp_path1, temp_path2).unwrap();
+            for args in formats {
+                let args = [&["status"][..], args].concat();
+                assert_eq!(cargo(&args).unwrap(), git2(&args).unwrap(), "{}: {:?}", what, args);
+            }
+        };
+
+        // nothing committed yet
+        compare("empty");
+        std::fs::write(temp_path1.join("a.txt"), "a\n").unwrap();
+        git1(&["add", "a.txt"]).unwrap();
+        compare("unborn");
+
+        std::fs::create_dir_all(temp_path1.join("src/inner")).unwrap();
+        std::fs::write(temp_path1.join("b.txt"), "b\n").unwrap();
+        std::fs::write(temp_path1.join("run.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::write(temp_path1.join("src/lib.rs"), "lib\n").unwrap();
+        std::fs::write(temp_path1.join("src/inner/mod.rs"), "mod\n").unwrap();
+        std::fs::write(temp_path1.join(".gitignore"), "*.log\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "init"]).unwrap();
+        compare("clean");
+
+        // staged, unstaged, both, a new mode, deletions, untracked files and directories, ignored files
+        std::fs::write(temp_path1.join("a.txt"), "staged\n").unwrap();
+        git1(&["add", "a.txt"]).unwrap();
+        std::fs::write(temp_path1.join("a.txt"), "staged then changed\n").unwrap();
+        std::fs::write(temp_path1.join("b.txt"), "unstaged\n").unwrap();
+        std::fs::set_permissions(temp_path1.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        git1(&["rm", "--quiet", "--cached", "src/lib.rs"]).unwrap();
+        std::fs::remove_file(temp_path1.join("src/inner/mod.rs")).unwrap();
+        std::fs::write(temp_path1.join("new.txt"), "new\n").unwrap();
+        git1(&["add", "new.txt"]).unwrap();
+        std::fs::create_dir_all(temp_path1.join("untracked/deep")).unwrap();
+        std::fs::write(temp_path1.join("untracked/deep/file"), "u\n").unwrap();
+        std::fs::write(temp_path1.join("src/extra.rs"), "extra\n").unwrap();
+        std::fs::write(temp_path1.join("debug.log"), "ignored\n").unwrap();
+        std::fs::create_dir_all(temp_path1.join("logs")).unwrap();
+        std::fs::write(temp_path1.join("logs/only.log"), "ignored\n").unwrap();
+        std::os::unix::fs::symlink("a.txt", temp_path1.join("link")).unwrap();
+        compare("dirty");
+
+        // paths are shown from the current directory except with --porcelain
+        let src = temp_path2.join("src");
+        let src = src.to_str().unwrap();
+        for args in formats {
+            let args = [&["status"][..], args].concat();
+            let in_src = |bin: &[&str]| shell_spawn(&[bin, &["-C", src], &args].concat());
+            assert_eq!(in_src(&["cargo", "run", "--quiet", "--"]).unwrap(), in_src(&["git"]).unwrap(), "{:?}", args);
+        }
+
+        // conflicts
+        git1(&["reset", "--quiet", "--hard"]).unwrap();
+        git1(&["checkout", "--quiet", "-b", "side"]).unwrap();
+        std::fs::write(temp_path1.join("a.txt"), "side\n").unwrap();
+        std::fs::write(temp_path1.join("both.txt"), "side\n").unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "side"]).unwrap();
+        git1(&["checkout", "--quiet", "master"]).unwrap();
+        std::fs::write(temp_path1.join("a.txt"), "master\n").unwrap();
+        std::fs::write(temp_path1.join("both.txt"), "master\n").unwrap();
+        git1(&["rm", "--quiet", "b.txt"]).unwrap();
+        git1(&["add", "."]).unwrap();
+        git1(&["commit", "-m", "master"]).unwrap();
+        assert!(git1(&["merge", "side"]).is_err());
+        compare("conflicts");
+    }
+}
@@ -0,0 +1,316 @@
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    GitError,
+    Result,
+    command::{Checkout, Clone, Fetch},
+    utils::{
+        blob::Blob,
+        config::Config,
+        fs::{object_exists, read_worktree_file, write_object},
+        hash::ObjectId,
+        index::{Index, IndexEntry, LockedIndexWriter},
+        refs::{head_to_hash, write_head_commit_with_log},
+        tree::FileMode,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+/// where the superproject records its submodules
+const GITMODULES: &str = ".gitmodules";
+
+#[derive(Parser, Debug)]
+#[command(name = "submodule", about = "Initialize, update or inspect submodules")]
+pub struct Submodule {
+    #[command(subcommand)]
+    command: SubmoduleCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum SubmoduleCommand {
+    /// clone a repository into `path` and record it as a submodule
+    Add {
+        /// the name in .gitmodules, the path by default
+        #[arg(long)]
+        name: Option<String>,
+        /// a url, or `./` and `../` relative to the superproject's origin
+        repository: String,
+        /// where the clone goes, guessed from the url by default
+        path: Option<String>,
+    },
+    /// copy the urls of the submodules in .gitmodules into .git/config
+    Init {
+        paths: Vec<String>,
+    },
+    /// clone the missing submodules and check out the commits the superproject records
+    Update {
+        /// init the submodules that are not yet
+        #[arg(long)]
+        init: bool,
+        paths: Vec<String>,
+    },
+}
+
+impl Submodule {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Submodule::try_parse_from(args)?))
+    }
+
+    /// `../lib` next to the superproject's origin url, or next to the superproject itself without one
+    fn resolve_url(repo: &Repository, url: &str) -> Result<String> {
+        if !url.starts_with("./") && !url.starts_with("../") {
+            return Ok(url.to_string());
+        }
+        let mut base = match Config::read_all(&repo.gitdir)?.get("remote.origin.url") {
+            Some(origin) => origin.trim_end_matches('/').to_string(),
+            None => repo.work_tree()?.display().to_string(),
+        };
+        let mut rest = url;
+        loop {
+            if let Some(next) = rest.strip_prefix("./") {
+                rest = next;
+            } else if let Some(next) = rest.strip_prefix("../") {
+                base = base.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default();
+                rest = next;
+            } else {
+                break;
+            }
+        }
+        Ok(format!("{}/{}", base, rest))
+    }
+
+    /// a path on the command line, relative to the top of the worktree
+    fn worktree_path(repo: &Repository, path: &str) -> String {
+        let path = repo.prefix.join(path.trim_end_matches('/'));
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        path.to_string_lossy().replace('\\', "/")
+    }
+
+    /// clone `url` into the submodule's directory; its .git stays inside, git would move it to .git/modules
+    fn clone(dir: &Path, url: &str) -> Result<()> {
+        let args = ["clone".to_string(), url.to_string(), dir.display().to_string()];
+        Clone::try_parse_from(args)?.run(Ok(Repository::at(dir.join(".git"))))?;
+        Ok(())
+    }
+
+    /// detach the submodule's HEAD at the commit the superproject records, fetching it when missing
+    fn checkout(gitdir: &Path, commit: &ObjectId) -> Result<()> {
+        if !object_exists(gitdir, commit) {
+            Fetch::try_parse_from(["fetch", "origin"])?.run(Ok(Repository::at(gitdir)))?;
+        }
+        if !object_exists(gitdir, commit) {
+            return Err(GitError::invalid_command(format!("Unable to find current revision {} in submodule", commit)));
+        }
+        let message = format!("checkout: moving from {} to {}", Checkout::head_description(gitdir), commit);
+        Checkout::switch_worktree(gitdir, head_to_hash(gitdir).ok().as_ref(), commit)?;
+        write_head_commit_with_log(gitdir, commit, &message)
+    }
+
+    fn add(repo: &Repository, name: Option<&str>, repository: &str, path: Option<&str>) -> Result<i32> {
+        let path = match path {
+            Some(path) => Self::worktree_path(repo, path),
+            None => Self::worktree_path(repo, &Clone::guess_dir(repository)?),
+        };
+        let name = name.unwrap_or(&path);
+        let index_file = repo.gitdir.join("index");
+        let lock = LockedIndexWriter::lock(&index_file)?;
+        let mut index = Index::read_or_default(&repo.gitdir)?;
+        if index.entries.iter().any(|entry| entry.name == Path::new(&path)) {
+            return Err(GitError::already_exists(format!("'{}' already exists in the index", path)));
+        }
+
+        let url = Self::resolve_url(repo, repository)?;
+        let dir = repo.work_tree()?.join(&path);
+        if dir.join(".git").exists() {
+            println!("Adding existing repo at '{}' to the index", path);
+        } else if dir.exists() && (!dir.is_dir() || dir.read_dir()?.next().is_some()) {
+            return Err(GitError::already_exists(format!("'{}' already exists and is not a valid git repo", path)));
+        } else {
+            Self::clone(&dir, &url)?;
+        }
+        let commit = head_to_hash(&dir.join(".git"))?;
+
+        let gitmodules = repo.work_tree()?.join(GITMODULES);
+        Config::set_in_file(&gitmodules, &format!("submodule.{}.path", name), &path)?;
+        Config::set_in_file(&gitmodules, &format!("submodule.{}.url", name), repository)?;
+        Config::set(&repo.gitdir, &format!("submodule.{}.url", name), &url)?;
+        Config::set(&repo.gitdir, &format!("submodule.{}.active", name), "true")?;
+
+        let (mode, content) = read_worktree_file(&gitmodules)?;
+        let blob = write_object::<Blob>(repo.gitdir.clone(), content)?;
+        index.add_entry(IndexEntry::new(mode, blob, GITMODULES).with_stat(&gitmodules));
+        index.add_entry(IndexEntry::new(FileMode::Commit as u32, commit, &path));
+        lock.commit(&index)?;
+        Ok(0)
+    }
+
+    /// the submodules of .gitmodules as (name, path), only those under `paths` when given
+    fn list(repo: &Repository, paths: &[String]) -> Result<Vec<(String, String)>> {
+        let gitmodules = Config::read_file(&repo.work_tree()?.join(GITMODULES))?;
+        let paths = paths.iter().map(|path| Self::worktree_path(repo, path)).collect::<Vec<_>>();
+        Ok(gitmodules.submodules().into_iter()
+            .filter_map(|name| {
+                let path = gitmodules.get(&format!("submodule.{}.path", name))?.to_string();
+                Some((name, path))
+            })
+            .filter(|(_, path)| paths.is_empty() || paths.iter().any(|given| Path::new(path).starts_with(given)))
+            .collect())
+    }
+
+    /// register the url of every listed submodule that has none in .git/config yet
+    fn init(repo: &Repository, paths: &[String]) -> Result<()> {
+        let gitmodules = Config::read_file(&repo.work_tree()?.join(GITMODULES))?;
+        for (name, path) in Self::list(repo, paths)? {
+            let key = format!("submodule.{}.url", name);
+            if Config::read(&repo.gitdir)?.get(&key).is_some() {
+                continue;
+            }
+            let Some(url) = gitmodules.get(&key) else {
+                return Err(GitError::invalid_command(format!("No url found for submodule path '{}' in .gitmodules", path)));
+            };
+            let url = Self::resolve_url(repo, url)?;
+            Config::set(&repo.gitdir, &format!("submodule.{}.active", name), "true")?;
+            Config::set(&repo.gitdir, &key, &url)?;
+            eprintln!("Submodule '{}' ({}) registered for path '{}'", name, url, path);
+        }
+        Ok(())
+    }
+
+    /// clone what is missing, then move every initialized submodule to the commit of its gitlink
+    fn update(repo: &Repository, paths: &[String]) -> Result<()> {
+        let index = Index::read_or_default(&repo.gitdir)?;
+        let config = Config::read(&repo.gitdir)?;
+        for (name, path) in Self::list(repo, paths)? {
+            let Some(entry) = index.entries.iter()
+                .find(|entry| entry.name == Path::new(&path) && entry.mode == FileMode::Commit as u32) else {
+                continue;
+            };
+            // a submodule that was never initialized is left alone, like git
+            let Some(url) = config.get(&format!("submodule.{}.url", name)) else {
+                continue;
+            };
+            let dir: PathBuf = repo.work_tree()?.join(&path);
+            let gitdir = dir.join(".git");
+            let cloned = !gitdir.exists();
+            if cloned {
+                Self::clone(&dir, url)?;
+            }
+            if cloned || head_to_hash(&gitdir).ok().as_ref() != Some(&entry.hash) {
+                Self::checkout(&gitdir, &entry.hash)?;
+                println!("Submodule path '{}': checked out '{}'", path, entry.hash);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SubCommand for Submodule {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        match &self.command {
+            SubmoduleCommand::Add { name, repository, path } => Self::add(&repo, name.as_deref(), repository, path.as_deref()),
+            SubmoduleCommand::Init { paths } => {
+                Self::init(&repo, paths)?;
+                Ok(0)
+            },
+            SubmoduleCommand::Update { init, paths } => {
+                if *init {
+                    Self::init(&repo, paths)?;
+                }
+                Self::update(&repo, paths)?;
+                Ok(0)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+    };
+
+    #[test]
+    fn test_submodule() {
+        let upstream = setup_test_git_dir();
+        let upstream_str = upstream.path().to_str().unwrap();
+        for message in ["one", "two"] {
+            shell_spawn(&["git", "-C", upstream_str, "commit", "--allow-empty", "-m", message]).unwrap();
+        }
+        // the tempdirs are siblings, a relative url finds the upstream from either superproject
+        let url = format!("../{}", upstream.path().file_name().unwrap().to_str().unwrap());
+
+        let (theirs, ours) = (setup_test_git_dir(), setup_test_git_dir());
+        let (theirs_str, ours_str) = (theirs.path().to_str().unwrap(), ours.path().to_str().unwrap());
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-c", "protocol.file.allow=always", "-C", dir][..], args].concat());
+        let cargo = |dir: &str, args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", dir][..], args].concat());
+
+        git(theirs_str, &["submodule", "add", &url, "lib"]).unwrap();
+        cargo(ours_str, &["submodule", "add", &url, "lib"]).unwrap();
+        for query in [
+            &["ls-files", "-s"][..],
+            &["config", "--get-regexp", "^submodule"],
+            &["show", ":.gitmodules"],
+            &["-C", "lib", "rev-parse", "HEAD"],
+        ] {
+            assert_eq!(git(ours_str, query).unwrap(), git(theirs_str, query).unwrap(), "{:?}", query);
+        }
+        // the submodule's files are not the superproject's to add
+        cargo(ours_str, &["add", "."]).unwrap();
+        assert_eq!(git(ours_str, &["ls-files"]).unwrap(), ".gitmodules\nlib\n");
+        git(ours_str, &["commit", "-m", "add lib"]).unwrap();
+        assert_eq!(git(ours_str, &["submodule", "status"]).unwrap(), git(theirs_str, &["submodule", "status"]).unwrap());
+        assert!(cargo(ours_str, &["submodule", "add", &url, "lib"]).is_err());
+
+        // fresh clones of the superproject, the submodules still to init and update
+        git(theirs_str, &["commit", "-m", "add lib"]).unwrap();
+        let temp = tempdir().unwrap();
+        let (reference, clone) = (temp.path().join("reference"), temp.path().join("clone"));
+        let (reference, clone) = (reference.to_str().unwrap(), clone.to_str().unwrap());
+        for dir in [reference, clone] {
+            shell_spawn(&["git", "clone", theirs_str, dir]).unwrap();
+        }
+        // there is nothing to update before init
+        assert_eq!(cargo(clone, &["submodule", "update"]).unwrap(), "");
+        let init = git(reference, &["submodule", "init"]).unwrap();
+        assert_eq!(cargo(clone, &["submodule", "init"]).unwrap(), init);
+        git(reference, &["submodule", "update"]).unwrap();
+        let updated = cargo(clone, &["submodule", "update"]).unwrap();
+        assert!(updated.ends_with(&format!("Submodule path 'lib': checked out '{}'\n", git(clone, &["rev-parse", "HEAD:lib"]).unwrap().trim())), "{}", updated);
+
+        let compare = |context: &str| {
+            for query in [
+                &["config", "--get-regexp", "^submodule"][..],
+                &["submodule", "status"],
+                &["-C", "lib", "status", "--porcelain", "--branch"],
+                &["-C", "lib", "rev-parse", "HEAD"],
+            ] {
+                assert_eq!(git(clone, query).unwrap(), git(reference, query).unwrap(), "{} {:?}", context, query);
+            }
+        };
+        compare("update");
+        // already there, nothing to do
+        assert_eq!(cargo(clone, &["submodule", "update"]).unwrap(), "");
+
+        // the superproject moves the submodule back, update follows
+        git(theirs_str, &["-C", "lib", "checkout", "HEAD~1"]).unwrap();
+        git(theirs_str, &["commit", "-am", "older lib"]).unwrap();
+        for dir in [reference, clone] {
+            git(dir, &["pull"]).unwrap();
+        }
+        let updated = git(reference, &["submodule", "update"]).unwrap();
+        assert_eq!(cargo(clone, &["submodule", "update", "lib"]).unwrap(), updated);
+        compare("older");
+
+        // update --init does both, in a clone that was never initialized
+        let fresh = temp.path().join("fresh");
+        let fresh = fresh.to_str().unwrap();
+        shell_spawn(&["git", "clone", theirs_str, fresh]).unwrap();
+        cargo(fresh, &["submodule", "update", "--init"]).unwrap();
+        assert_eq!(git(fresh, &["submodule", "status"]).unwrap(), git(reference, &["submodule", "status"]).unwrap());
+    }
+}
@@ -1,48 +1,189 @@
-use std::path::PathBuf;
-use clap::{Parser, Subcommand};
+use clap::Parser;
+
 use crate::{
+    utils::refs::{delete_ref, read_symref, write_head_ref_with_log, write_symref},
     GitError,
     Result,
 };
-use crate::utils::refs::{read_head_ref, write_head_ref};
 use super::SubCommand;
+use crate::utils::fs::Repository;
 
 #[derive(Parser, Debug)]
-#[command(name = "symbolic-ref", about = "读取或设置符号引用（如HEAD）")]
+#[command(name = "symbolic-ref", about = "Read or modify symbolic refs")]
 pub struct SymbolicRef {
-    /// 要操作的引用（如 HEAD），默认是 HEAD
-    #[arg(default_value = "HEAD")]
-    ref_name: String,
+    #[arg(long, help = "shorten the ref name, e.g. refs/heads/main becomes main")]
+    short: bool,
+
+    #[arg(short = 'q', long, help = "exit with 1 silently when the ref is not a symbolic ref")]
+    quiet: bool,
+
+    #[arg(short = 'd', long, conflicts_with = "target", help = "delete the symbolic ref")]
+    delete: bool,
+
+    #[arg(short = 'm', value_name = "reason", help = "the message for HEAD's reflog")]
+    message: Option<String>,
 
-    /// 设置引用为指定目标（如 refs/heads/main）
-    #[arg(required = false)]
-    new_target: Option<String>,
+    #[arg(required = true, help = "the symbolic ref, usually HEAD")]
+    name: String,
+
+    #[arg(help = "the ref it should point to, without it the current target is printed")]
+    target: Option<String>,
 }
 
 impl SymbolicRef {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
-        let symbolic_ref = SymbolicRef::try_parse_from(args)?;
-        Ok(Box::new(symbolic_ref))
+        Ok(Box::new(SymbolicRef::try_parse_from(args)?))
     }
 }
 
 impl SubCommand for SymbolicRef {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        if let Some(ref target) = self.new_target {
-            if self.ref_name != "HEAD" {
-                return Err(Box::new(GitError::InvalidCommand("只支持设置 HEAD 的符号引用".to_string())));
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+
+        if self.delete {
+            if self.name == "HEAD" {
+                return Err(GitError::refused(format!("deleting '{}' is not allowed", self.name)));
+            }
+            if read_symref(&gitdir, &self.name).is_err() {
+                return Err(GitError::refused(format!("Cannot delete {}, not a symbolic ref", self.name)));
             }
-            write_head_ref(&gitdir, target)?;
-            //println!("Updated HEAD to {}", target);
-        } else {
-            let ref_value = if self.ref_name == "HEAD" {
-                read_head_ref(&gitdir)?
-            } else {
-                return Err(Box::new(GitError::InvalidCommand("只支持读取 HEAD 的符号引用".to_string())));
-            };
-            //println!("{}", ref_value);
+            delete_ref(&gitdir, &self.name)?;
+            return Ok(0);
+        }
+
+        match &self.target {
+            // the target is written as is, it doesn't have to exist yet (like an unborn branch)
+            Some(target) => {
+                if !target.starts_with("refs/") {
+                    return Err(GitError::refused(format!("Refusing to point {} outside of refs/", self.name)));
+                }
+                // HEAD's moves are logged like git does, with an empty message unless -m gives one
+                match self.name.as_str() {
+                    "HEAD" => write_head_ref_with_log(&gitdir, target, self.message.as_deref().unwrap_or_default())?,
+                    name => write_symref(&gitdir, name, target)?,
+                }
+            },
+            None => match read_symref(&gitdir, &self.name) {
+                Ok(target) => {
+                    let target = if self.short {
+                        ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"].iter()
+                            .find_map(|prefix| target.strip_prefix(prefix))
+                            .unwrap_or(&target)
+                    }
+                    else {
+                        &target
+                    };
+                    println!("{}", target);
+                },
+                Err(_) if self.quiet => return Ok(1),
+                Err(_) => return Err(GitError::not_a_symref(&self.name)),
+            },
         }
         Ok(0)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+        cmd_seq,
+        ArgsList,
+    };
+
+    #[test]
+    fn test_symbolic_ref() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "init"], false),
+            (&["branch", "other"], false),
+        ];
+        let git = &["git", "-C", temp_path_str];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let cargo = ["cargo", "run", "--quiet", "--", "-C", temp_path_str, "symbolic-ref"];
+
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "symbolic-ref", "HEAD"]).unwrap();
+        let real = shell_spawn(&[&cargo[..], &["HEAD"]].concat()).unwrap();
+        assert_eq!(origin, real);
+
+        // HEAD is switched without touching the branches or the worktree
+        let before = shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "master"]).unwrap();
+        shell_spawn(&[&cargo[..], &["HEAD", "refs/heads/other"]].concat()).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "symbolic-ref", "--short", "HEAD"]).unwrap();
+        let real = shell_spawn(&[&cargo[..], &["--short", "HEAD"]].concat()).unwrap();
+        assert_eq!(origin, "other\n");
+        assert_eq!(origin, real);
+        assert_eq!(before, shell_spawn(&["git", "-C", temp_path_str, "rev-parse", "master"]).unwrap());
+
+        assert!(shell_spawn(&[&cargo[..], &["HEAD", "other"]].concat()).is_err());
+
+        // the move is in HEAD's reflog, with the -m message
+        shell_spawn(&[&cargo[..], &["-m", "back to master", "HEAD", "refs/heads/master"]].concat()).unwrap();
+        let reflog = shell_spawn(&["git", "-C", temp_path_str, "reflog", "-1", "--format=%gs"]).unwrap();
+        assert_eq!(reflog, "back to master\n");
+
+        // symbolic refs other than HEAD, and deleting them
+        shell_spawn(&[&cargo[..], &["refs/heads/alias", "refs/heads/other"]].concat()).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str, "symbolic-ref", "refs/heads/alias"]).unwrap();
+        assert_eq!(origin, "refs/heads/other\n");
+        assert!(shell_spawn(&[&cargo[..], &["-d", "HEAD"]].concat()).is_err());
+        assert!(shell_spawn(&[&cargo[..], &["-d", "refs/heads/other"]].concat()).is_err());
+        shell_spawn(&[&cargo[..], &["-d", "refs/heads/alias"]].concat()).unwrap();
+        assert!(!temp_path.join(".git/refs/heads/alias").exists());
+        assert!(temp_path.join(".git/refs/heads/other").exists());
+
+        shell_spawn(&["git", "-C", temp_path_str, "checkout", "--detach"]).unwrap();
+        assert!(shell_spawn(&[&cargo[..], &["HEAD"]].concat()).is_err());
+        let quiet = std::process::Command::new(cargo[0]).args([&cargo[1..], &["-q", "HEAD"]].concat()).output().unwrap();
+        assert_eq!((quiet.status.code(), quiet.stderr.is_empty()), (Some(1), true));
+    }
+
+    #[test]
+    fn test_symbolic_ref_flags() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "init"], false),
+            (&["branch", "other"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+        let run = |command: &[&str], dir: &str, args: &[&str]| {
+            std::process::Command::new(command[0]).args(&command[1..]).args(["-C", dir, "symbolic-ref"]).args(args).output().unwrap()
+        };
+        let both = |args: &[&str]| {
+            let origin = run(&["git"], temp_path_str1, args);
+            let real = run(&["cargo", "run", "--quiet", "--"], temp_path_str2, args);
+            assert_eq!((origin.status.code(), origin.stdout), (real.status.code(), real.stdout), "{:?}", args);
+        };
+        let state = |dir: &str| {
+            let git = |args: &[&str]| shell_spawn(&[&["git", "-C", dir][..], args].concat()).unwrap();
+            (git(&["for-each-ref"]), git(&["symbolic-ref", "HEAD"]), git(&["reflog", "--format=%gs", "HEAD"]))
+        };
+
+        for args in [
+            &["-m", "to other", "HEAD", "refs/heads/other"][..], &["-q", "HEAD"], &["-q", "--short", "HEAD"],
+            &["-m", "alias", "refs/heads/alias", "refs/heads/master"], &["refs/heads/alias"], &["-d", "refs/heads/alias"],
+            &["-q", "refs/heads/master"], &["-q", "refs/heads/alias"], &["-d", "-q", "refs/heads/master"],
+        ] {
+            both(args);
+            assert_eq!(state(temp_path_str1), state(temp_path_str2), "{:?}", args);
+        }
+
+        for dir in [temp_path_str1, temp_path_str2] {
+            shell_spawn(&["git", "-C", dir, "checkout", "--detach"]).unwrap();
+        }
+        both(&["-q", "HEAD"]);
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::Path;
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        fs::{read_obj, write_object},
+        hash::ObjectId,
+        ident,
+        ignore::glob_match,
+        refs::{check_branch_name, delete_ref, for_each_ref, read_ref_commit, ref_exists, write_ref_commit},
+        revparse::rev_parse,
+        tag::Tag as TagObject,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "tag", about = "Create, list or delete tags")]
+pub struct Tag {
+    #[arg(short = 'a', long, help = "make an annotated tag object")]
+    annotate: bool,
+
+    #[arg(short = 'm', long, value_name = "message", help = "message of the annotated tag, implies -a")]
+    message: Option<String>,
+
+    #[arg(short = 'f', long, help = "replace an existing tag")]
+    force: bool,
+
+    #[arg(short = 'd', long, help = "delete the given tags")]
+    delete: bool,
+
+    #[arg(short = 'l', long, help = "list tags, the arguments are glob patterns")]
+    list: bool,
+
+    #[arg(value_name = "tagname|pattern", help = "the tag to create or delete, or patterns with -l")]
+    names: Vec<String>,
+}
+
+impl Tag {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Tag::try_parse_from(args)?))
+    }
+
+    /// tag names in ref order, only those matching one of `patterns` when there are any
+    fn list(gitdir: &Path, patterns: &[String]) -> Result<()> {
+        for (refname, _) in for_each_ref(gitdir, "refs/tags/")? {
+            let name = &refname["refs/tags/".len()..];
+            if patterns.is_empty() || patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes())) {
+                println!("{}", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(gitdir: &Path, names: &[String]) -> Result<i32> {
+        let mut status = 0;
+        for name in names {
+            let refname = format!("refs/tags/{}", name);
+            match read_ref_commit(gitdir, &refname) {
+                Ok(hash) if ref_exists(gitdir, &refname) => {
+                    delete_ref(gitdir, &refname)?;
+                    println!("Deleted tag '{}' (was {})", name, &hash.to_hex()[..7]);
+                },
+                // like git the other tags are still deleted
+                _ => {
+                    eprintln!("error: tag '{}' not found.", name);
+                    status = 1;
+                },
+            }
+        }
+        Ok(status)
+    }
+
+    /// the tag object for `target`, tagged by the committer like git does
+    fn annotate(gitdir: &Path, name: &str, target: &ObjectId, message: &str) -> Result<ObjectId> {
+        let kind = read_obj(gitdir.to_path_buf(), target)?.get_type().to_string();
+        let tag = TagObject {
+            object: *target,
+            kind,
+            tag: name.to_string(),
+            tagger: ident::committer(gitdir)?,
+            message: if message.ends_with('\n') { message.to_string() } else { format!("{}\n", message) },
+        };
+        write_object::<TagObject>(gitdir.to_path_buf(), tag.into())
+    }
+}
+
+impl SubCommand for Tag {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        if self.delete {
+            return Self::delete(&gitdir, &self.names);
+        }
+        let (name, target) = match self.names.as_slice() {
+            _ if self.list => return Self::list(&gitdir, &self.names).map(|_| 0),
+            [] => return Self::list(&gitdir, &[]).map(|_| 0),
+            [name] => (name, "HEAD"),
+            [name, target] => (name, target.as_str()),
+            _ => return Err(GitError::invalid_command("too many arguments".to_string())),
+        };
+
+        check_branch_name(name).map_err(|_| GitError::invalid_command(format!("'{}' is not a valid tag name.", name)))?;
+        let refname = format!("refs/tags/{}", name);
+        if !self.force && ref_exists(&gitdir, &refname) {
+            return Err(GitError::invalid_command(format!("tag '{}' already exists", name)));
+        }
+        let target = rev_parse(&gitdir, target)
+            .map_err(|_| GitError::invalid_command(format!("Failed to resolve '{}' as a valid ref.", target)))?;
+
+        let hash = match (&self.message, self.annotate) {
+            (Some(message), _) => Self::annotate(&gitdir, name, &target, message)?,
+            (None, true) => return Err(GitError::invalid_command("an annotated tag needs a message, use -m".to_string())),
+            (None, false) => target,
+        };
+        let old = read_ref_commit(&gitdir, &refname).ok().filter(|old| *old != hash);
+        write_ref_commit(&gitdir, &refname, &hash)?;
+        if let Some(old) = old {
+            println!("Updated tag '{}' (was {})", name, &old.to_hex()[..7]);
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_tag() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+        let git1 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str1][..], args].concat());
+        let git2 = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str2][..], args].concat());
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str2][..], args].concat());
+
+        git1(&["commit", "--allow-empty", "-m", "one"]).unwrap();
+        git1(&["commit", "--allow-empty", "-m", "two"]).unwrap();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        // the same tagger and date on both sides, so annotated tags get the same hash
+        let date = "1600000000 +0800";
+        let tag = |dir: &str, bin: &[&str], args: &[&str]| {
+            let status = Command::new(bin[0]).args(&bin[1..]).arg("-C").arg(dir).arg("tag").args(args)
+                .env("GIT_COMMITTER_DATE", date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", args);
+        };
+        let tags: &[&[&str]] = &[
+            &["light"],
+            &["old", "HEAD~1"],
+            &["-a", "v1", "-m", "version one"],
+            &["-m", "implied -a", "v0.9", "HEAD~1"],
+            &["nested/deep"],
+            &["-a", "of-a-tag", "-m", "a tag of a tag", "v1"],
+            &["-f", "light", "HEAD~1"],
+        ];
+        for args in tags {
+            tag(temp_path_str1, &["git"], args);
+            tag(temp_path_str2, &["cargo", "run", "--quiet", "--"], args);
+            assert_eq!(git1(&["show-ref", "--tags"]).unwrap(), git2(&["show-ref", "--tags"]).unwrap(), "{:?}", args);
+        }
+        git2(&["fsck", "--strict"]).unwrap();
+
+        // cat-file shows tag objects like git
+        let v1 = git1(&["rev-parse", "v1"]).unwrap();
+        assert_eq!(cargo(&["cat-file", "-p", v1.trim()]).unwrap(), git1(&["cat-file", "-p", v1.trim()]).unwrap());
+        assert_eq!(cargo(&["cat-file", "-t", v1.trim()]).unwrap(), "tag\n");
+
+        for args in [&["tag"][..], &["tag", "-l"], &["tag", "-l", "v*"], &["tag", "--list", "*/*", "old"]] {
+            assert_eq!(cargo(args).unwrap(), git1(args).unwrap(), "{:?}", args);
+        }
+        assert!(cargo(&["tag", "v1"]).is_err());
+        assert!(cargo(&["tag", "-a", "unsigned"]).is_err());
+        assert!(cargo(&["tag", "bad..name"]).is_err());
+        assert!(cargo(&["tag", "new", "nope"]).is_err());
+
+        // deleting goes on past a missing tag but fails in the end
+        git2(&["pack-refs", "--all"]).unwrap();
+        assert!(cargo(&["tag", "-d", "old", "missing", "nested/deep"]).is_err());
+        assert_eq!(cargo(&["tag"]).unwrap(), "light\nof-a-tag\nv0.9\nv1\n");
+    }
+}
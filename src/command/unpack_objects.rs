@@ -0,0 +1,138 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+};
+use clap::Parser;
+
+use crate::{
+    GitError,
+    Result,
+    utils::packfile::PackfileProcessor,
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "unpack-objects", about = "Unpack objects from a packed archive")]
+pub struct UnpackObjects {
+    #[arg(short = 'n', help = "check the pack but write nothing")]
+    dry_run: bool,
+
+    #[arg(long, help = "write nothing when an object is malformed or links to an object that is missing")]
+    strict: bool,
+
+    #[arg(help = "the pack to unpack, read from stdin by default")]
+    pack: Option<PathBuf>,
+}
+
+impl UnpackObjects {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(UnpackObjects::try_parse_from(args)?))
+    }
+}
+
+impl SubCommand for UnpackObjects {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        let data = match &self.pack {
+            Some(path) => fs::read(repo.resolve(path)).map_err(|_| GitError::failed_to_read_file(&path.display().to_string()))?,
+            None => {
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                data
+            },
+        };
+        // the pack is checked as a whole before anything is written, a bad one leaves no objects behind
+        PackfileProcessor::new(repo.gitdir).unpack_packfile(&data, self.dry_run, self.strict)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+    };
+
+    /// `git pack-objects --stdout` of the objects `rev-list` lists for `revs`
+    fn pack(dir: &str, revs: &[&str]) -> Vec<u8> {
+        let objects = Command::new("git").args([&["-C", dir, "rev-list", "--objects"][..], revs].concat()).output().unwrap().stdout;
+        let mut child = Command::new("git").args(["-C", dir, "pack-objects", "--stdout", "-q"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&objects).unwrap();
+        child.wait_with_output().unwrap().stdout
+    }
+
+    #[test]
+    fn test_unpack_objects() {
+        let source = setup_test_git_dir();
+        let source_path = source.path();
+        let source_str = source_path.to_str().unwrap();
+        for (i, name) in ["a.txt", "dir/b.txt", "dir/c.txt"].iter().enumerate() {
+            std::fs::create_dir_all(source_path.join(name).parent().unwrap()).unwrap();
+            // similar contents, so the pack has deltas
+            std::fs::write(source_path.join(name), format!("{}\n", "line\n".repeat(50 + i))).unwrap();
+            shell_spawn(&["git", "-C", source_str, "add", "."]).unwrap();
+            shell_spawn(&["git", "-C", source_str, "commit", "-m", name]).unwrap();
+        }
+        shell_spawn(&["git", "-C", source_str, "tag", "-a", "v1", "-m", "v1"]).unwrap();
+        let full = pack(source_str, &["--all"]);
+
+        let unpack = |dir: &str, args: &[&str], data: &[u8]| {
+            let mut child = Command::new("cargo")
+                .args([&["run", "--quiet", "--", "-C", dir, "unpack-objects"][..], args].concat())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(data).unwrap();
+            child.wait_with_output().unwrap()
+        };
+        let loose = |dir: &str| shell_spawn(&["git", "-C", dir, "count-objects"]).unwrap();
+
+        // every object of the pack turns up loose, like git's own unpack-objects leaves them
+        let (ours, theirs) = (setup_test_git_dir(), setup_test_git_dir());
+        let (ours_str, theirs_str) = (ours.path().to_str().unwrap(), theirs.path().to_str().unwrap());
+        let output = unpack(ours_str, &[], &full);
+        assert!(output.status.success() && output.stdout.is_empty(), "{:?}", output);
+        let mut child = Command::new("git").args(["-C", theirs_str, "unpack-objects", "-q"]).stdin(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(&full).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(loose(ours_str), loose(theirs_str));
+        let tag = shell_spawn(&["git", "-C", source_str, "rev-parse", "v1"]).unwrap();
+        shell_spawn(&["git", "-C", ours_str, "update-ref", "refs/tags/v1", tag.trim()]).unwrap();
+        shell_spawn(&["git", "-C", ours_str, "fsck", "--full", "--strict"]).unwrap();
+
+        // -n and a corrupt pack write nothing, from stdin or from a file
+        let empty = setup_test_git_dir();
+        let empty_str = empty.path().to_str().unwrap();
+        assert!(unpack(empty_str, &["-n"], &full).status.success());
+        let mut corrupt = full.clone();
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 0xff;
+        assert_eq!(unpack(empty_str, &[], &corrupt).status.code(), Some(128));
+        let pack_file = empty.path().join("corrupt.pack");
+        std::fs::write(&pack_file, &corrupt).unwrap();
+        assert_eq!(unpack(empty_str, &[pack_file.to_str().unwrap()], &[]).status.code(), Some(128));
+        assert_eq!(loose(empty_str), "0 objects, 0 kilobytes\n");
+
+        // only the newest commit and what it adds: its parent and the unchanged blobs are missing
+        let thin = pack(source_str, &["HEAD", "^HEAD~1"]);
+        assert_eq!(unpack(empty_str, &["--strict"], &thin).status.code(), Some(128));
+        assert_eq!(loose(empty_str), "0 objects, 0 kilobytes\n");
+        std::fs::write(&pack_file, &thin).unwrap();
+        assert!(unpack(empty_str, &[pack_file.to_str().unwrap()], &[]).status.success());
+        // with the history in place the same pack passes --strict
+        assert!(unpack(theirs_str, &["--strict"], &thin).status.success());
+    }
+}
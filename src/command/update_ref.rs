@@ -1,29 +1,37 @@
-use std::path::{PathBuf,Path};
-use clap::{Parser, Subcommand};
-use crate::utils::index;
-use crate::utils::zlib::{compress_object, decompress_file_bytes};
+use std::path::{Path, PathBuf};
+use clap::Parser;
 use crate::{
     GitError,
     Result,
 };
 use crate::utils::{
-    fs::read_file_as_bytes,
-    hash::hash_object,
-    index::{Index, IndexEntry},
-    tree::{
-        Tree,
-        FileMode,
-    },
+    refs::{delete_ref, ref_file, resolve_symref, write_ref_commit_with_log},
+    revparse::rev_parse,
+    hash::{null_hash, ObjectId},
 };
 use super::SubCommand;
+use crate::utils::fs::Repository;
+
 #[derive(Parser, Debug)]
 #[command(name = "update-ref", about = "update the ref file")]
 pub struct UpdateRef {
-    #[arg(required = true, help = "Prefix to add to all paths in the tree")]
+    #[arg(short = 'd', help = "delete the ref, the second argument is then the expected old value")]
+    pub delete: bool,
+
+    #[arg(long, help = "update the ref itself instead of the ref it points to")]
+    pub no_deref: bool,
+
+    #[arg(short = 'm', help = "reason of the update, recorded in the reflog")]
+    pub message: Option<String>,
+
+    #[arg(required = true, help = "the ref to update, like refs/heads/main or HEAD")]
     pub ref_path: String,
 
-    #[arg(required = true, help = "tree hash")]
-    pub commit_hash: String,
+    #[arg(required_unless_present = "delete", help = "the new value of the ref")]
+    pub commit_hash: Option<String>,
+
+    #[arg(help = "only update when the ref currently has this value, all zeros means it must not exist")]
+    pub old_value: Option<String>,
 }
 
 impl UpdateRef {
@@ -31,17 +39,127 @@ impl UpdateRef {
         let update_ref = UpdateRef::try_parse_from(args)?;
         Ok(Box::new(update_ref))
     }
+
+    /// unconditionally point `ref_path` at `commit_hash`, `message` goes to the reflog
+    pub fn new(ref_path: String, commit_hash: &ObjectId, message: &str) -> Self {
+        UpdateRef {
+            delete: false,
+            no_deref: false,
+            message: Some(message.to_string()),
+            ref_path,
+            commit_hash: Some(commit_hash.to_hex()),
+            old_value: None,
+        }
+    }
+
+    /// compare-and-swap check against the current value of the ref
+    fn verify_old_value(gitdir: &Path, refname: &str, old: &str) -> Result<()> {
+        let current = std::fs::read_to_string(ref_file(gitdir, refname))
+            .ok()
+            .map(|content| content.trim().to_string());
+        match current {
+            None if old.is_empty() || old == null_hash() => Ok(()),
+            None => Err(GitError::cannot_lock_ref(refname, "unable to resolve reference".to_string())),
+            Some(current) if old == null_hash() => {
+                Err(GitError::cannot_lock_ref(refname, format!("reference already exists at {}", current)))
+            },
+            Some(current) => {
+                let expected = rev_parse(gitdir, old)?;
+                if expected == current {
+                    Ok(())
+                }
+                else {
+                    Err(GitError::cannot_lock_ref(refname, format!("is at {} but expected {}", current, expected)))
+                }
+            },
+        }
+    }
+
+    pub fn update(&self, gitdir: &Path) -> Result<()> {
+        let refname = if self.no_deref {
+            self.ref_path.clone()
+        }
+        else {
+            resolve_symref(gitdir, &self.ref_path)
+        };
+
+        if self.delete {
+            if self.old_value.is_some() {
+                return Err(GitError::invalid_command("usage: update-ref -d <ref> [<old-value>]".to_string()));
+            }
+            if let Some(old) = &self.commit_hash {
+                Self::verify_old_value(gitdir, &refname, old)?;
+            }
+            return delete_ref(gitdir, &refname);
+        }
+
+        let new = rev_parse(gitdir, self.commit_hash.as_deref().unwrap_or_default())?;
+        if let Some(old) = &self.old_value {
+            Self::verify_old_value(gitdir, &refname, old)?;
+        }
+        write_ref_commit_with_log(gitdir, &refname, &new, self.message.as_deref().unwrap_or_default())
+    }
 }
 
 impl SubCommand for UpdateRef {
-    fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
-        let ref_path = gitdir.join(&self.ref_path);
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
+        self.update(&gitdir)?;
+        Ok(0)
+    }
+}
 
-        std::fs::write(&ref_path, format!("{}\n", self.commit_hash))
-            .map_err(|_| GitError::failed_to_write_file(&ref_path.to_string_lossy()))?;
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        cmd_seq,
+        ArgsList,
+    };
 
-        //println!("Updated ref {} to {}", self.ref_path, self.commit_hash);
-        Ok(0)
+    #[test]
+    fn test_update_ref() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+
+        let setup_cmds: ArgsList = &[
+            (&["commit", "--allow-empty", "-m", "first"], false),
+            (&["commit", "--allow-empty", "-m", "second"], false),
+        ];
+        let git = &["git", "-C", temp_path_str];
+        cmd_seq(setup_cmds)(git).unwrap();
+        let rev = |rev: &str| shell_spawn(&["git", "-C", temp_path_str, "rev-parse", rev]).unwrap().trim().to_string();
+        let (first, second) = (rev("HEAD~1"), rev("HEAD"));
+        let cargo = |args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "update-ref"][..], args].concat());
+
+        cargo(&["refs/heads/topic", &first]).unwrap();
+        assert_eq!(rev("topic"), first);
+
+        // the old value doesn't match, the ref is left alone
+        assert!(cargo(&["refs/heads/topic", &second, &second]).is_err());
+        assert_eq!(rev("topic"), first);
+        cargo(&["refs/heads/topic", &second, &first]).unwrap();
+        assert_eq!(rev("topic"), second);
+
+        // all zeros: only create, never overwrite
+        assert!(cargo(&["refs/heads/topic", &first, "0000000000000000000000000000000000000000"]).is_err());
+        assert!(!temp_path.join(".git/refs/heads/topic.lock").exists());
+
+        // HEAD is dereferenced to the branch it points to
+        cargo(&["HEAD", &first]).unwrap();
+        assert_eq!(rev("master"), first);
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str, "symbolic-ref", "HEAD"]).unwrap(), "refs/heads/master\n");
+
+        assert!(cargo(&["-d", "refs/heads/topic", &first]).is_err());
+        cargo(&["-d", "refs/heads/topic", &second]).unwrap();
+        assert!(!temp_path.join(".git/refs/heads/topic").exists());
+        let status = std::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", "-C", temp_path_str, "checkout", "topic"])
+            .output()
+            .unwrap()
+            .status;
+        assert!(!status.success());
     }
 }
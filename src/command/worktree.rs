@@ -0,0 +1,313 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    GitError,
+    Result,
+    command::{Branch, Checkout, Status},
+    utils::{
+        commit::Commit,
+        fs::{common_dir, read_object},
+        hash::ObjectId,
+        index::Index,
+        refs::{current_branch, head_to_hash, ref_exists},
+        revparse::resolve_commit,
+    },
+};
+use super::SubCommand;
+use crate::utils::fs::Repository;
+
+#[derive(Parser, Debug)]
+#[command(name = "worktree", about = "Manage multiple working trees")]
+pub struct Worktree {
+    #[command(subcommand)]
+    command: WorktreeCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum WorktreeCommand {
+    /// check out a commit-ish into a new working tree at `path`
+    Add {
+        /// create this branch at the commit-ish and check it out
+        #[arg(short = 'b')]
+        new_branch: Option<String>,
+        /// detach HEAD in the new working tree, even for a branch
+        #[arg(long)]
+        detach: bool,
+        path: String,
+        /// a branch to check out or a commit to detach at, a new branch named after `path` from HEAD by default
+        commit: Option<String>,
+    },
+    /// list the working trees, the main one first
+    List {
+        /// one attribute per line, a blank line after each working tree
+        #[arg(long)]
+        porcelain: bool,
+    },
+    /// delete a linked working tree and its administrative files
+    Remove {
+        /// remove it even with modified or untracked files
+        #[arg(short, long)]
+        force: bool,
+        /// the path of the working tree or its name
+        worktree: String,
+    },
+}
+
+/// a working tree and the git dir its HEAD and index are kept in
+struct Entry {
+    path: PathBuf,
+    gitdir: PathBuf,
+}
+
+impl Worktree {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Box<dyn SubCommand>> {
+        Ok(Box::new(Worktree::try_parse_from(args)?))
+    }
+
+    /// `path` made absolute without requiring it to exist
+    fn absolute(repo: &Repository, path: &str) -> PathBuf {
+        let mut absolute = PathBuf::new();
+        for component in repo.resolve(path).components() {
+            match component {
+                Component::CurDir => {},
+                Component::ParentDir => { absolute.pop(); },
+                component => absolute.push(component),
+            }
+        }
+        absolute.canonicalize().unwrap_or(absolute)
+    }
+
+    /// the main working tree, then the linked ones by name as recorded in `.git/worktrees/<name>/gitdir`
+    fn entries(gitdir: &Path) -> Result<Vec<Entry>> {
+        let common = common_dir(gitdir);
+        let main = common.parent().unwrap_or(&common).to_path_buf();
+        let mut linked = Vec::new();
+        if let Ok(dir) = fs::read_dir(common.join("worktrees")) {
+            for private in dir.flatten().map(|entry| entry.path()) {
+                let Ok(dot_git) = fs::read_to_string(private.join("gitdir")) else { continue };
+                let path = Path::new(dot_git.trim()).parent().unwrap_or(Path::new("/")).to_path_buf();
+                linked.push(Entry { path, gitdir: private });
+            }
+        }
+        linked.sort_by(|a, b| a.gitdir.file_name().cmp(&b.gitdir.file_name()));
+        Ok([Entry { path: main, gitdir: common }].into_iter().chain(linked).collect())
+    }
+
+    /// the working tree which has `branch` checked out
+    fn checked_out(gitdir: &Path, branch: &str) -> Result<Option<PathBuf>> {
+        Ok(Self::entries(gitdir)?
+            .into_iter()
+            .find(|entry| current_branch(&entry.gitdir).is_ok_and(|current| current == branch))
+            .map(|entry| entry.path))
+    }
+
+    /// a name for `.git/worktrees/` from the last component of the path, numbered when taken
+    fn unique_name(common: &Path, path: &Path) -> String {
+        let base = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| "worktree".to_string());
+        let taken = |name: &str| common.join("worktrees").join(name).exists();
+        match taken(&base) {
+            false => base,
+            true => (1..).map(|i| format!("{}{}", base, i)).find(|name| !taken(name)).unwrap(),
+        }
+    }
+
+    fn add(repo: &Repository, new_branch: Option<&str>, detach: bool, path: &str, commit: Option<&str>) -> Result<i32> {
+        let gitdir = &repo.gitdir;
+        let path = Self::absolute(repo, path);
+        if fs::read_dir(&path).is_ok_and(|mut dir| dir.next().is_some()) || path.is_file() {
+            return Err(GitError::already_exists(format!("'{}' already exists", path.display())));
+        }
+
+        // what HEAD of the new working tree will be: a branch, or a commit when detached
+        let basename = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let branch_of = |rev: &str| match !detach && ref_exists(gitdir, &format!("refs/heads/{}", rev)) {
+            true => Some(rev.to_string()),
+            false => None,
+        };
+        let (branch, create, target) = match (new_branch, commit) {
+            (Some(branch), start) => (Some(branch.to_string()), true, start.unwrap_or("HEAD").to_string()),
+            (None, Some(commit)) => (branch_of(commit), false, commit.to_string()),
+            // git's DWIM: a branch named after the directory, made from HEAD unless it is already there
+            (None, None) if !detach => match branch_of(&basename) {
+                Some(branch) => (Some(branch), false, basename.clone()),
+                None => (Some(basename.clone()), true, "HEAD".to_string()),
+            },
+            (None, None) => (None, false, "HEAD".to_string()),
+        };
+        let commit = match create {
+            true => match Branch::new_branch_commit(gitdir, branch.as_deref().unwrap_or_default(), Some(&target))? {
+                Some(commit) => commit,
+                None => resolve_commit(gitdir, &target)?,
+            },
+            false => resolve_commit(gitdir, &target)?,
+        };
+
+        match (&branch, create) {
+            (Some(branch), true) => println!("Preparing worktree (new branch '{}')", branch),
+            (Some(branch), false) => {
+                println!("Preparing worktree (checking out '{}')", branch);
+                if let Some(at) = Self::checked_out(gitdir, branch)? {
+                    return Err(GitError::refused(format!("'{}' is already checked out at '{}'", branch, at.display())));
+                }
+            },
+            (None, _) => println!("Preparing worktree (detached HEAD {})", &commit.to_hex()[..7]),
+        }
+        if let (Some(branch), true) = (&branch, create) {
+            Branch::create_at(gitdir, branch, Some(&target), &commit)?;
+        }
+
+        // .git/worktrees/<name> holds HEAD and the index, `commondir` leads back to everything else
+        let common = common_dir(gitdir);
+        let private = common.join("worktrees").join(Self::unique_name(&common, &path));
+        fs::create_dir_all(&private)?;
+        fs::create_dir_all(&path)?;
+        let path = path.canonicalize()?;
+        fs::write(private.join("gitdir"), format!("{}\n", path.join(".git").display()))?;
+        fs::write(private.join("commondir"), "../..\n")?;
+        let head = match &branch {
+            Some(branch) => format!("ref: refs/heads/{}\n", branch),
+            None => format!("{}\n", commit),
+        };
+        fs::write(private.join("HEAD"), head)?;
+        fs::write(path.join(".git"), format!("gitdir: {}\n", private.display()))?;
+
+        Checkout::switch_worktree(&private, None, &commit)?;
+        let subject = read_object::<Commit>(gitdir.to_path_buf(), &commit)?.message.lines().next().unwrap_or_default().to_string();
+        println!("HEAD is now at {} {}", &commit.to_hex()[..7], subject);
+        Ok(0)
+    }
+
+    fn list(gitdir: &Path, porcelain: bool) -> Result<i32> {
+        let entries = Self::entries(gitdir)?;
+        let width = entries.iter().map(|entry| entry.path.as_os_str().len()).max().unwrap_or_default();
+        for entry in entries {
+            let head = head_to_hash(&entry.gitdir).unwrap_or_else(|_| ObjectId::null());
+            let branch = current_branch(&entry.gitdir).ok();
+            if porcelain {
+                println!("worktree {}", entry.path.display());
+                println!("HEAD {}", head);
+                match branch {
+                    Some(branch) => println!("branch refs/heads/{}", branch),
+                    None => println!("detached"),
+                }
+                println!();
+                continue;
+            }
+            let branch = match branch {
+                Some(branch) => format!("[{}]", branch),
+                None => "(detached HEAD)".to_string(),
+            };
+            println!("{:<width$} {} {}", entry.path.display(), &head.to_hex()[..7], branch, width = width + 1);
+        }
+        Ok(0)
+    }
+
+    fn remove(repo: &Repository, force: bool, worktree: &str) -> Result<i32> {
+        let path = Self::absolute(repo, worktree);
+        let entries = Self::entries(&repo.gitdir)?;
+        let found = entries.iter().enumerate().find(|(_, entry)| {
+            entry.path == path || entry.gitdir.file_name().is_some_and(|name| name == worktree)
+        });
+        let entry = match found {
+            Some((0, _)) => return Err(GitError::refused(format!("'{}' is a main working tree", worktree))),
+            Some((_, entry)) => entry,
+            None => return Err(GitError::invalid_argument(format!("'{}' is not a working tree", worktree))),
+        };
+
+        if !force && entry.path.exists() {
+            let index = Index::read_or_default(&entry.gitdir)?;
+            let mut changes = Default::default();
+            Status::staged(&entry.gitdir, &index, &mut changes)?;
+            Status::unstaged(&entry.path, &index, &mut changes)?;
+            if !changes.is_empty() || !Status::untracked(&entry.path, &index)?.is_empty() {
+                return Err(GitError::refused(format!("'{}' contains modified or untracked files, use --force to delete it", worktree)));
+            }
+        }
+        if entry.path.exists() {
+            fs::remove_dir_all(&entry.path)?;
+        }
+        fs::remove_dir_all(&entry.gitdir)?;
+        // the last one gone takes .git/worktrees with it, like git
+        let _ = fs::remove_dir(common_dir(&repo.gitdir).join("worktrees"));
+        Ok(0)
+    }
+}
+
+impl SubCommand for Worktree {
+    fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let repo = repo?;
+        match &self.command {
+            WorktreeCommand::Add { new_branch, detach, path, commit } =>
+                Self::add(&repo, new_branch.as_deref(), *detach, path, commit.as_deref()),
+            WorktreeCommand::List { porcelain } => Self::list(&repo.gitdir, *porcelain),
+            WorktreeCommand::Remove { force, worktree } => Self::remove(&repo, *force, worktree),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::test::{shell_spawn, setup_test_git_dir, tempdir};
+
+    #[test]
+    fn test_worktree() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let outside = tempdir().unwrap();
+        let outside = outside.path().canonicalize().unwrap();
+        let git = |dir: &str, args: &[&str]| shell_spawn(&[&["git", "-C", dir][..], args].concat());
+        let cargo = |dir: &str, args: &[&str]| shell_spawn(&[&["cargo", "run", "--quiet", "--", "-C", dir][..], args].concat());
+
+        std::fs::write(temp_path.join("a.txt"), "a\n").unwrap();
+        git(temp_path_str, &["add", "a.txt"]).unwrap();
+        git(temp_path_str, &["commit", "-m", "init"]).unwrap();
+
+        // a new branch named after the directory, an existing branch and a detached HEAD
+        let feat = outside.join("feat");
+        let feat_str = feat.to_str().unwrap();
+        let out = cargo(temp_path_str, &["worktree", "add", feat_str]).unwrap();
+        assert!(out.starts_with("Preparing worktree (new branch 'feat')\nHEAD is now at "), "{}", out);
+        git(temp_path_str, &["branch", "side"]).unwrap();
+        let side = outside.join("side");
+        cargo(temp_path_str, &["worktree", "add", side.to_str().unwrap(), "side"]).unwrap();
+        let det = outside.join("det");
+        cargo(temp_path_str, &["worktree", "add", "--detach", det.to_str().unwrap()]).unwrap();
+        let other = outside.join("other");
+        cargo(temp_path_str, &["worktree", "add", "-b", "nb", other.to_str().unwrap(), "HEAD"]).unwrap();
+        assert!(cargo(temp_path_str, &["worktree", "add", outside.join("again").to_str().unwrap(), "master"]).is_err());
+        assert!(cargo(temp_path_str, &["worktree", "add", outside.join("again").to_str().unwrap(), "side"]).is_err());
+
+        for args in [&["worktree", "list"][..], &["worktree", "list", "--porcelain"]] {
+            assert_eq!(cargo(temp_path_str, args).unwrap(), git(temp_path_str, args).unwrap(), "{:?}", args);
+            assert_eq!(cargo(feat_str, args).unwrap(), git(feat_str, args).unwrap(), "{:?}", args);
+        }
+        assert_eq!(cargo(feat_str, &["status"]).unwrap(), git(feat_str, &["status"]).unwrap());
+        assert_eq!(std::fs::read_to_string(feat.join("a.txt")).unwrap(), "a\n");
+
+        // a commit in the linked worktree moves its own branch, visible from the main one
+        std::fs::write(feat.join("b.txt"), "b\n").unwrap();
+        cargo(feat_str, &["add", "b.txt"]).unwrap();
+        cargo(feat_str, &["commit", "-m", "on feat"]).unwrap();
+        assert_eq!(git(temp_path_str, &["log", "-1", "--format=%s", "feat"]).unwrap(), "on feat\n");
+        assert_eq!(git(temp_path_str, &["rev-parse", "--abbrev-ref", "HEAD"]).unwrap(), "master\n");
+        assert_eq!(git(feat_str, &["status", "--porcelain"]).unwrap(), "");
+        git(temp_path_str, &["fsck", "--strict"]).unwrap();
+
+        // dirty trees need --force, the main one and unknown paths are refused
+        std::fs::write(feat.join("c.txt"), "c\n").unwrap();
+        assert!(cargo(temp_path_str, &["worktree", "remove", feat_str]).is_err());
+        cargo(temp_path_str, &["worktree", "remove", "--force", feat_str]).unwrap();
+        assert!(!feat.exists());
+        cargo(temp_path_str, &["worktree", "remove", "det"]).unwrap();
+        assert!(cargo(temp_path_str, &["worktree", "remove", temp_path_str]).is_err());
+        assert!(cargo(temp_path_str, &["worktree", "remove", "nosuch"]).is_err());
+        assert_eq!(cargo(temp_path_str, &["worktree", "list"]).unwrap(), git(temp_path_str, &["worktree", "list"]).unwrap());
+        git(temp_path_str, &["worktree", "prune"]).unwrap();
+        assert_eq!(git(temp_path_str, &["worktree", "list", "--porcelain"]).unwrap().matches("worktree ").count(), 3);
+    }
+}
@@ -5,14 +5,14 @@ use crate::{
     Result,
 };
 use crate::utils::{
-    fs::read_file_as_bytes,
-    hash::hash_object,
+    fs::{read_file_as_bytes, write_object},
+    hash::{hash_object, ObjectId},
     zlib::compress_object,
     index::{Index, IndexEntry},
-    tree::Tree,
+    tree::{Tree, tree_entry_cmp},
 };
 use super::SubCommand;
-use hex;
+use crate::utils::fs::Repository;
 
 #[derive(Parser, Debug)]
 #[command(name = "write-tree", about = "create a tree object according to the current index")]
@@ -36,12 +36,9 @@ impl WriteTree {
             let mode = format!("{:o}", entry.mode);
             temp.extend_from_slice(mode.as_bytes());
             temp.push(b' ');
-            temp.extend_from_slice(entry.name.as_bytes());
+            temp.extend_from_slice(entry.name_bytes());
             temp.push(b'\0');
-            let hash_bytes = hex::decode(&entry.hash).map_err(|_| {
-                GitError::InvalidCommand(format!("Invalid hash format: {}", entry.hash))
-            })?;
-            temp.extend_from_slice(&hash_bytes);
+            temp.extend_from_slice(entry.hash.as_bytes());
         }
         //let len_str = format!("{}\0", temp.len());
         //tree_content.extend_from_slice(len_str.as_bytes());
@@ -49,31 +46,30 @@ impl WriteTree {
         Ok(tree_content)
     }
 
-    fn build_tree_recursive(gitdir: &Path, entries: &[IndexEntry], prefix: &str) -> Result<String>{
+    /// `prefix` is the directory these entries are in, path names are raw bytes like in git
+    pub fn build_tree_recursive(gitdir: &Path, entries: &[IndexEntry], prefix: &[u8]) -> Result<ObjectId>{
         use std::collections::BTreeMap;
-        let mut tree_entries: BTreeMap<String, (u32, String, bool)> = BTreeMap::new();
-        let mut subdir_map: BTreeMap<String, Vec<IndexEntry>> = BTreeMap::new();
+        let mut tree_entries: Vec<(Vec<u8>, u32, ObjectId, bool)> = Vec::new();
+        let mut subdir_map: BTreeMap<Vec<u8>, Vec<IndexEntry>> = BTreeMap::new();
 
         for entry in entries {
+            let name = entry.name_bytes();
             let rel_name = if prefix.is_empty() {
-                entry.name.as_str()
-            } else if let Some(stripped) = entry.name.strip_prefix(prefix) {
-                stripped.trim_start_matches('/')
+                name
+            } else if let Some(stripped) = name.strip_prefix(prefix) {
+                stripped.strip_prefix(b"/").unwrap_or(stripped)
             } else {
                 continue;
             };
             
-            if let Some((first, _rest)) = rel_name.split_once('/') {
+            if let Some(slash) = rel_name.iter().position(|&b| b == b'/') {
                 // 这是一个子目录的文件，将整个entry添加到子目录处理列表
-                subdir_map.entry(first.to_string())
+                subdir_map.entry(rel_name[..slash].to_vec())
                     .or_default()
                     .push(entry.clone());  // 保持原始entry不变
-            } else {
+            } else if !tree_entries.iter().any(|(name, ..)| name == rel_name) {
                 // 普通文件，直接在当前级别
-                tree_entries.insert(
-                    rel_name.to_string(),
-                    (entry.mode, entry.hash.clone(), false),
-                );
+                tree_entries.push((rel_name.to_vec(), entry.mode, entry.hash, false));
             }
         }
         
@@ -82,48 +78,48 @@ impl WriteTree {
             let sub_prefix = if prefix.is_empty() {
                 subdir.clone()
             } else {
-                format!("{}/{}", prefix, subdir)
+                [prefix, b"/", &subdir].concat()
             };
             let sub_tree_hash = Self::build_tree_recursive(gitdir, &sub_entries, &sub_prefix)?;
-            tree_entries.insert(
-                subdir,
-                (0o040000, sub_tree_hash, true),
-            );
+            tree_entries.push((subdir, 0o040000, sub_tree_hash, true));
         }
 
+        // git 的排序规则：目录名按带 '/' 后缀比较
+        tree_entries.sort_by(|(a, _, _, a_tree), (b, _, _, b_tree)| {
+            tree_entry_cmp(a, *a_tree, b, *b_tree)
+        });
+
         let mut tree_content = Vec::new();
-        for (name, (mode, hash, is_tree)) in &tree_entries {
+        for (name, mode, hash, is_tree) in &tree_entries {
             let mode_str = if *is_tree { "40000" } else { &format!("{:o}", mode) };
             tree_content.extend_from_slice(mode_str.as_bytes());
             tree_content.push(b' ');
-            tree_content.extend_from_slice(name.as_bytes());
+            tree_content.extend_from_slice(name);
             tree_content.push(0);
-            let hash_bytes = hex::decode(hash).map_err(|_| {
-                GitError::InvalidCommand(format!("Invalid hash format: {}", hash))
-            })?;
-            tree_content.extend_from_slice(&hash_bytes);
+            tree_content.extend_from_slice(hash.as_bytes());
         }
 
-        let tree_hash = hash_object::<Tree>(tree_content.clone())?;
-        let mut objpath = gitdir.join("objects");
-        objpath.push(&tree_hash[0..2]);
-        objpath.push(&tree_hash[2..]);
-        //println!("objpath: {:?}", objpath);
-        std::fs::create_dir_all(objpath.parent().unwrap())?;
-        //println!("objpath: {:?}", objpath);
-        let compressed = compress_object::<Tree>(tree_content)?;
-        //println!("compressed: {:?}", compressed);
-        std::fs::write(objpath, compressed)?;
-        //println!("tree_hash: {}", tree_hash);
-        Ok(tree_hash)
-
+        // 已经存在的对象不会重复写入
+        write_object::<Tree>(gitdir.to_path_buf(), tree_content)
     }
 
-    pub fn lazy_fucker(gitdir: PathBuf) -> Result<String> {
+    pub fn lazy_fucker(gitdir: PathBuf) -> Result<ObjectId> {
         let index_path = gitdir.join("index");
         let index = Index::new();
         let index = index.read_from_file(&index_path)?;
-        Self::build_tree_recursive(&gitdir, &index.entries, "")
+        Self::check_unmerged(&index)?;
+        Self::build_tree_recursive(&gitdir, &index.entries, b"")
+    }
+
+    /// a tree can't hold the stages of a conflicted path
+    fn check_unmerged(index: &Index) -> Result<()> {
+        let unmerged = index.unmerged_paths();
+        if unmerged.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(GitError::merge_conflict(format!("you have unmerged paths: {}", unmerged.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))))
+        }
     }
 }
 
@@ -133,7 +129,7 @@ impl SubCommand for WriteTree {
     //     let index_path =gitdir.clone().join("index");
     //     let index = Index::new();
     //     let index = index.read_from_file(&index_path).map_err(|_| {
-    //         GitError::InvalidCommand(index_path.to_str().unwrap().to_string())
+    //         GitError::invalid_command(index_path.to_str().unwrap().to_string())
     //     })?;
         
     //     let tree_content = self.build_tree_content(&index)?;
@@ -151,12 +147,13 @@ impl SubCommand for WriteTree {
     //     Ok(0)
     // }
 
-   fn run(&self, gitdir: Result<PathBuf>) -> Result<i32> {
-        let gitdir = gitdir?;
+   fn run(&self, repo: Result<Repository>) -> Result<i32> {
+        let gitdir = repo?.gitdir;
         let index_path = gitdir.clone().join("index");
         let index = Index::new();
         let index = index.read_from_file(&index_path)?;
-        let tree_hash = Self::build_tree_recursive(&gitdir, &index.entries, "")?;
+        Self::check_unmerged(&index)?;
+        let tree_hash = Self::build_tree_recursive(&gitdir, &index.entries, b"")?;
         println!("{}", tree_hash);
         Ok(0)
     }
@@ -208,8 +205,37 @@ mod test {
 
         let hash = gitout.iter().filter(|x|x.len() == 41).take(1).next().unwrap().strip_suffix("\n").unwrap();
 
-        let real = shell_spawn(&["git", "-C", temp_path_str1, "cat-file", "-p", &hash]).unwrap();
-        let origin = shell_spawn(&["git", "-C", temp_path_str2, "cat-file", "-p", &hash]).unwrap();
+        let real = shell_spawn(&["git", "-C", temp_path_str1, "cat-file", "-p", hash]).unwrap();
+        let origin = shell_spawn(&["git", "-C", temp_path_str2, "cat-file", "-p", hash]).unwrap();
+        assert_eq!(origin, real);
+    }
+
+    #[test]
+    fn test_tree_entry_order() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let temp_path_str1 = temp_path1.to_str().unwrap();
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let temp_path_str2 = temp_path2.to_str().unwrap();
+
+        // the directory `foo` sorts after `foo.txt` and `foo-bar` in git
+        for name in ["foo/a", "foo/b/c", "foo.txt", "foo-bar", "fo"] {
+            let path = temp_path1.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        let cmds: ArgsList = &[
+            (&["add", "."], false),
+            (&["write-tree"], false),
+            (&["write-tree"], false),
+        ];
+        let git = &["git", "-C", temp_path_str1];
+        let cargo = &["cargo", "run", "--quiet", "--", "-C", temp_path_str2];
+        let (origin, real) = run_both(cmds, git, cargo).unwrap();
         assert_eq!(origin, real);
     }
 }
@@ -19,8 +19,15 @@ fn main() {
     std::process::exit(match result {
         Ok(retval) => retval,
         Err(err) => {
-            eprintln!("{}", err);
-            1
+            // like git, whatever makes a command die is reported as fatal
+            let code = utils::error::exit_code(err.as_ref());
+            if code == 128 || code == utils::error::EXIT_NETWORK {
+                eprintln!("fatal: {}", err);
+            }
+            else {
+                eprintln!("{}", err);
+            }
+            code
         }
     });
 }
@@ -33,12 +33,20 @@ impl ObjType for Blob {
 impl TryFrom<Vec<u8>> for Blob {
     type Error = Box<dyn Error>;
 
-    fn try_from(bytes: Vec<u8>) -> result::Result<Self, Self::Error> {
+    fn try_from(mut bytes: Vec<u8>) -> result::Result<Self, Self::Error> {
         let _ = parse_meta(&bytes)
             .map_err(|x|x.to_string())
             .map_err(GitError::invalid_obj)?;
         let index = bytes.iter().position(|&b| b == b'\0').expect("assert parse_meta implementation fails");
-        Ok(Blob(bytes[index + 1..].to_vec()))
+        // 就地去掉头部，内容不再复制
+        bytes.drain(..=index);
+        Ok(Blob(bytes))
+    }
+}
+
+impl Blob {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
     }
 }
 
@@ -50,8 +58,7 @@ impl From<Blob> for Vec<u8> {
 
 impl fmt::Display for Blob {
     fn fmt(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let bytes: Vec<u8> = self.clone().into();
-        let _ = io::stdout().write_all(&bytes);
+        let _ = io::stdout().write_all(self.as_bytes());
         Ok(())
     }
 }
@@ -16,6 +16,7 @@ use crate::utils:: {
         GitError,
         Result
     },
+    hash::ObjectId,
 };
 
 use nom::{
@@ -40,52 +41,34 @@ use nom::{
 
 
 pub struct Commit {
-    pub tree_hash: String,
-    pub parent_hash: Vec<String>,
+    pub tree_hash: ObjectId,
+    pub parent_hash: Vec<ObjectId>,
     pub author: String,
     pub committer: String,
+    /// headers after committer we don't model (gpgsig, encoding, mergetag ...), in order and verbatim,
+    /// one header per item with its continuation lines joined by `\n`
+    pub extra_headers: Vec<String>,
     pub message: String,
 }
 
-type CommitPrototype<'a> = (&'a[u8], Vec<&'a[u8]>, &'a[u8], &'a[u8], &'a[u8]);
 impl Commit {
-    fn parse_from_bytes<'a>(bytes: &'a[u8]) -> IResult<&'a [u8], CommitPrototype<'a>> {
-        let mut parse_tree = terminated(preceded(tag("tree "),take_until("\n")), tag("\n"));
-        let mut parse_parent = many0(terminated(preceded(tag("parent "),take_until("\n")), tag("\n")));
-        let mut parse_author = terminated(preceded(tag("author "),take_until("\n")), tag("\n"));
-        let mut parse_committer = terminated(preceded(tag("committer "),take_until("\n")), tag("\n"));
-        
-        // 解析可选的 gpgsig 字段（跳过整个签名块）
-        let mut parse_gpgsig = opt(terminated(
-            preceded(
-                tag("gpgsig "), 
-                take_until("\n\n")  // 取直到双换行符
-            ), 
-            tag("\n\n")
-        ));
-        
-        let mut parse_messages = take_while(|_|true);
-        
-        // 解析主要字段
-        let (remaining, tree_hash) = parse_tree.parse(bytes)?;
-        let (remaining, parent_hash) = parse_parent.parse(remaining)?;
-        let (remaining, author) = parse_author.parse(remaining)?;
-        let (remaining, committer) = parse_committer.parse(remaining)?;
-        
-        // 跳过可选的 gpgsig 字段
-        let (remaining, _) = parse_gpgsig.parse(remaining)?;
-        
-        // 如果没有找到 gpgsig，检查是否有空行
-        let (remaining, _) = if remaining.starts_with(b"\n") {
-            tag("\n").parse(remaining)?
-        } else {
-            (remaining, &[][..])
-        };
-        
-        // 解析消息
-        let (remaining, message) = parse_messages.parse(remaining)?;
-        
-        Ok((remaining, (tree_hash, parent_hash, author, committer, message)))
+    /// a new commit, the message gets its trailing newline like git writes it
+    pub fn new(tree_hash: ObjectId, parent_hash: Vec<ObjectId>, author: String, committer: String, message: &str) -> Self {
+        Commit {
+            tree_hash,
+            parent_hash,
+            author,
+            committer,
+            extra_headers: vec![],
+            message: if message.ends_with('\n') { message.to_string() } else { format!("{}\n", message) },
+        }
+    }
+
+    /// the value of the first extra header `name`, continuation lines unfolded
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.extra_headers.iter()
+            .find_map(|header| header.strip_prefix(name).and_then(|x| x.strip_prefix(' ')))
+            .map(|value| value.replace("\n ", "\n"))
     }
 }
 
@@ -98,64 +81,73 @@ impl TryFrom<Vec<u8>> for Commit {
     type Error = Box<dyn Error>;
 
     fn try_from(bytes: Vec<u8>) -> result::Result<Self, Self::Error> {
-        let ( _,
-                (_, (tree_hash, parent_hash, author, committer, message))) = (
-                parse_meta,
-                Commit::parse_from_bytes
-            ).parse(&bytes)
-            .map_err(|e|GitError::invalid_commit(&e.to_string()))?;
-
-        let parent_hash = parent_hash.into_iter()
-            .map(|x|x.to_vec())
-            .map(|v|String::from_utf8(v).map_err(|e|GitError::invalid_commit(&e.to_string())))
-            .collect::<Result<Vec<_>>>()?;
+        let (body, _) = parse_meta(&bytes).map_err(|e|GitError::invalid_commit(&e.to_string()))?;
+        let body = std::str::from_utf8(body)?;
+        // 头部里不会有空行，签名里的空行是一个空格
+        let (header, message) = match body.split_once("\n\n") {
+            Some((header, message)) => (header, message),
+            None => (body.strip_suffix('\n').unwrap_or(body), ""),
+        };
+
+        // a line starting with a space continues the header above it
+        let mut headers: Vec<String> = vec![];
+        for line in header.split('\n') {
+            match headers.last_mut() {
+                Some(last) if line.starts_with(' ') => {
+                    last.push('\n');
+                    last.push_str(line);
+                },
+                _ => headers.push(line.to_string()),
+            }
+        }
+
+        let mut tree_hash = None;
+        let mut parent_hash = vec![];
+        let mut author = None;
+        let mut committer = None;
+        let mut extra_headers = vec![];
+        for header in headers {
+            let (name, value) = header.split_once(' ').unwrap_or((&header, ""));
+            match name {
+                "tree" if tree_hash.is_none() => tree_hash = Some(ObjectId::from_hex(value)?),
+                "parent" => parent_hash.push(ObjectId::from_hex(value)?),
+                "author" if author.is_none() => author = Some(value.to_string()),
+                "committer" if committer.is_none() => committer = Some(value.to_string()),
+                _ => extra_headers.push(header),
+            }
+        }
+        let missing = |name: &str| GitError::invalid_commit(&format!("commit object without {} line", name));
+
         Ok(Commit {
-            tree_hash:   String::from_utf8(tree_hash.to_vec())?,
+            tree_hash:   tree_hash.ok_or_else(|| missing("tree"))?,
             parent_hash,
-            author:      String::from_utf8(author.to_vec())?,
-            committer:   String::from_utf8(committer.to_vec())?,
-            message:     String::from_utf8(message.to_vec())?,
+            author:      author.ok_or_else(|| missing("author"))?,
+            committer:   committer.ok_or_else(|| missing("committer"))?,
+            extra_headers,
+            message:     message.to_string(),
         })
     }
 }
 
 impl From<Commit> for Vec<u8> {
     fn from(commit: Commit) -> Vec<u8> {
-        let parent_line = commit.parent_hash.into_iter()
-            .map(|hash| format!("parent {}\n", hash))
-            .collect::<String>();
-        // println!("parent_line = {}", parent_line);
-        format!("tree {}\n{}\
-                author {}\n\
-                committer {}\n\
-                \n\
-                {}",
-            commit.tree_hash,
-            parent_line,
-            commit.author,
-            commit.committer,
-            if commit.message.ends_with("\n") {commit.message} else {format!("{}\n", commit.message)},
-        ).into_bytes()
+        commit.to_string().into_bytes()
     }
 }
 
+/// the commit object exactly as git stores it
 impl fmt::Display for Commit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let parent_line = self.parent_hash.iter()
-            .map(|hash| format!("parent {}\n", hash))
-            .collect::<String>();
-        // println!("parent_line = {}", parent_line);
-        write!(f, "tree {}\n{}\
-                   author {}\n\
-                   committer {}\n\
-                   \n\
-                   {}",
-                self.tree_hash,
-                parent_line,
-                self.author,
-                self.committer,
-                self.message,
-        )
+        writeln!(f, "tree {}", self.tree_hash)?;
+        for parent in &self.parent_hash {
+            writeln!(f, "parent {}", parent)?;
+        }
+        writeln!(f, "author {}", self.author)?;
+        writeln!(f, "committer {}", self.committer)?;
+        for header in &self.extra_headers {
+            writeln!(f, "{}", header)?;
+        }
+        write!(f, "\n{}", self.message)
     }
 }
 
@@ -169,3 +161,95 @@ impl TryFrom<Obj> for Commit {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::{
+        fs::{read_object, write_object},
+        hash::hash_object,
+        test::{setup_test_git_dir, shell_spawn},
+    };
+
+    /// `git merge -S` of a signed tag with i18n.commitEncoding set, so it has encoding, mergetag and gpgsig
+    fn signed_merge() -> String {
+        [
+            "tree 3683f870be446c7cc05ffaef9fa06415276e1828",
+            "parent fbd661f5e2ae5dcc4e1098583e38a152e3f7d4bf",
+            "parent 65b4e165921068938644bd3d6ebf6ded9dbb0172",
+            "author Rit Tester <tester@example.com> 1700000200 +0800",
+            "committer Rit Tester <tester@example.com> 1700000200 +0800",
+            "encoding ISO-8859-1",
+            "mergetag object 65b4e165921068938644bd3d6ebf6ded9dbb0172",
+            " type commit",
+            " tag v1",
+            " tagger Rit Tester <tester@example.com> 1700000150 +0800",
+            " ",
+            " signed tag",
+            " -----BEGIN PGP SIGNATURE-----",
+            " ",
+            " iIkEABYIADEWIQS82sNectop3o2KfTnbOzAqC92G4AUCatMT3RMcdGVzdGVyQGV4",
+            " YW1wbGUuY29tAAoJENs7MCoL3YbgiHkA/2Ltzj7RAfJZlUZ0sg8lGvQARSsZKYRI",
+            " l7p83hvYN2reAP9TK6eDV+Ibk9QrrESsEx9Ctiv1vtLRGyu9TBqmZLA6DA==",
+            " =jIe8",
+            " -----END PGP SIGNATURE-----",
+            "gpgsig -----BEGIN PGP SIGNATURE-----",
+            " ",
+            " iIkEABYIADEWIQS82sNectop3o2KfTnbOzAqC92G4AUCatMT3RMcdGVzdGVyQGV4",
+            " YW1wbGUuY29tAAoJENs7MCoL3Ybg/gkBAOEyjp6mN6ef6rZE32zPSwDMK0xvsKT0",
+            " vpVRAohKigKyAQDMX+kc5WLKvuYFHxHD1zlT8DSEOFupnDQVKt1PflFTAQ==",
+            " =rpWE",
+            " -----END PGP SIGNATURE-----",
+            "",
+            "merge signed tag",
+            "",
+        ].join("\n")
+    }
+
+    fn parse(body: &str) -> Commit {
+        let mut bytes = format!("commit {}\0", body.len()).into_bytes();
+        bytes.extend_from_slice(body.as_bytes());
+        Commit::try_from(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_signed_round_trip() {
+        let body = signed_merge();
+        let commit = parse(&body);
+        assert_eq!(commit.parent_hash.len(), 2);
+        assert_eq!(commit.extra_headers.len(), 3);
+        assert_eq!(commit.header("encoding").as_deref(), Some("ISO-8859-1"));
+        assert!(commit.header("gpgsig").unwrap().starts_with("-----BEGIN PGP SIGNATURE-----\n\niIkE"));
+        assert!(commit.header("mergetag").unwrap().starts_with("object 65b4e165921068938644bd3d6ebf6ded9dbb0172\ntype commit\n"));
+        assert_eq!(commit.message, "merge signed tag\n");
+
+        let bytes: Vec<u8> = commit.into();
+        assert_eq!(String::from_utf8(bytes.clone()).unwrap(), body);
+        assert_eq!(hash_object::<Commit>(bytes).unwrap(), "9c6c14ef8ca615167456b6747d6ac3bfe8c9b055");
+    }
+
+    #[test]
+    fn test_octopus_round_trip() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let gitdir = temp.path().join(".git");
+        let tree = shell_spawn(&["git", "-C", temp_path_str, "write-tree"]).unwrap();
+        let tree = tree.trim();
+        let commit_tree = |args: &[&str], message: &str| {
+            let out = shell_spawn(&[&["git", "-C", temp_path_str, "commit-tree", tree, "-m", message][..], args].concat()).unwrap();
+            out.trim().to_string()
+        };
+        let parents = ["a", "b", "c"].map(|message| commit_tree(&[], message));
+        let octopus = commit_tree(&["-p", &parents[0], "-p", &parents[1], "-p", &parents[2]], "three parents\n\nand a body");
+
+        let commit = read_object::<Commit>(gitdir.clone(), &octopus).unwrap();
+        assert_eq!(commit.parent_hash, parents.to_vec());
+        assert!(commit.extra_headers.is_empty());
+        assert_eq!(write_object::<Commit>(gitdir.clone(), commit.into()).unwrap(), octopus);
+
+        // the signed fixture stored by us reads back byte for byte in git
+        let hash = write_object::<Commit>(gitdir.clone(), parse(&signed_merge()).into()).unwrap();
+        assert_eq!(hash, "9c6c14ef8ca615167456b6747d6ac3bfe8c9b055");
+        assert_eq!(shell_spawn(&["git", "-C", temp_path_str, "cat-file", "commit", &hash.to_hex()]).unwrap(), signed_merge());
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    fs,
+    env,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::{
+    GitError,
+    Result,
+    utils::fs::common_dir,
+};
+
+/// `git -c name=value` for this process, applied on top of every config file
+static OVERRIDES: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+/// a flat view of a git config file
+/// keys are stored as `section.key` or `section.subsection.key`,
+/// section and key lowercased, subsection kept as is (same as git)
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+/// `branch.<name>.remote` and `branch.<name>.merge`, remote `.` means a local branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    pub remote: String,
+    pub merge: String,
+}
+
+/// what `edit_file` does to a key
+#[derive(Clone, Copy)]
+enum Edit<'a> {
+    /// replace the last value, or add the key when it is missing
+    Set(&'a str),
+    /// add another value after the section's last line
+    Add(&'a str),
+    /// drop every value
+    Unset,
+}
+
+impl Upstream {
+    /// the branch name on the remote, `refs/heads/main` -> main
+    pub fn branch(&self) -> &str {
+        self.merge.strip_prefix("refs/heads/").unwrap_or(&self.merge)
+    }
+}
+
+impl Config {
+    /// read $GIT_DIR/config, a missing file is just an empty config
+    pub fn read(gitdir: &Path) -> Result<Self> {
+        Self::read_file(&common_dir(gitdir).join("config"))
+    }
+
+    /// read ~/.gitconfig
+    pub fn read_global() -> Result<Self> {
+        match env::var_os("HOME") {
+            Some(home) => Self::read_file(&PathBuf::from(home).join(".gitconfig")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// repository config layered on top of the global one, `-c` on the command line wins over both
+    pub fn read_all(gitdir: &Path) -> Result<Self> {
+        let mut config = Self::read_global()?;
+        config.entries.extend(Self::read(gitdir)?.entries);
+        config.entries.extend(OVERRIDES.get().into_iter().flatten().cloned());
+        Ok(config)
+    }
+
+    /// `name=value` pairs from `-c`, a name without `=` means true
+    pub fn set_overrides(params: &[String]) -> Result<()> {
+        let mut overrides = Vec::new();
+        for param in params {
+            let (key, value) = param.split_once('=').unwrap_or((param, "true"));
+            if !key.contains('.') {
+                return Err(GitError::invalid_command(format!("key does not contain a section: {}", key)));
+            }
+            overrides.push((Self::normalize(key), value.to_string()));
+        }
+        // only main sets them, once
+        let _ = OVERRIDES.set(overrides);
+        Ok(())
+    }
+
+    /// read a file in config syntax, like .gitmodules; a missing file is just an empty config
+    pub fn read_file(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|_| GitError::failed_to_read_file(&path.display().to_string()))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut section: Option<String> = None;
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let header = line.strip_prefix('[')
+                    .and_then(|x| x.split_once(']'))
+                    .map(|(header, _)| header.trim())
+                    .ok_or_else(|| GitError::invalid_command(format!("bad config line: {}", line)))?;
+                section = Some(Self::parse_section(header));
+                continue;
+            }
+
+            let section = section.as_ref()
+                .ok_or_else(|| GitError::invalid_command(format!("config key outside of a section: {}", line)))?;
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), Self::parse_value(value)),
+                // a bare key means true
+                None => (line, "true".to_string()),
+            };
+            entries.push((format!("{}.{}", section, key.to_lowercase()), value));
+        }
+        Ok(Self { entries })
+    }
+
+    /// `core` -> core, `remote "origin"` -> remote.origin, `branch.main` -> branch.main
+    fn parse_section(header: &str) -> String {
+        match header.split_once(char::is_whitespace) {
+            Some((name, sub)) => {
+                let sub = sub.trim().trim_matches('"');
+                format!("{}.{}", name.to_lowercase(), sub)
+            },
+            None => match header.split_once('.') {
+                Some((name, sub)) => format!("{}.{}", name.to_lowercase(), sub),
+                None => header.to_lowercase(),
+            },
+        }
+    }
+
+    fn parse_value(value: &str) -> String {
+        let value = value.trim();
+        // strip trailing comments which are not inside quotes
+        let mut in_quote = false;
+        let mut out = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => in_quote = !in_quote,
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some(other) => out.push(other),
+                    None => (),
+                },
+                '#' | ';' if !in_quote => break,
+                c => out.push(c),
+            }
+        }
+        out.trim_end().to_string()
+    }
+
+    fn normalize(key: &str) -> String {
+        // section and variable name are case-insensitive, subsection is not
+        match (key.find('.'), key.rfind('.')) {
+            (Some(first), Some(last)) if first != last => format!(
+                "{}{}{}",
+                key[..first].to_lowercase(),
+                &key[first..last],
+                key[last..].to_lowercase()
+            ),
+            _ => key.to_lowercase(),
+        }
+    }
+
+    /// the last value wins, just like git
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = Self::normalize(key);
+        self.entries.iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// a boolean the way git spells them, an empty value is false; None when unset or not a boolean
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)?.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Some(true),
+            "false" | "no" | "off" | "0" | "" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = Self::normalize(key);
+        self.entries.iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// names of all submodules with a path, in the order of .gitmodules
+    pub fn submodules(&self) -> Vec<String> {
+        let mut submodules = Vec::new();
+        for (key, _) in &self.entries {
+            if let Some(name) = key.strip_prefix("submodule.").and_then(|x| x.strip_suffix(".path"))
+                && !submodules.iter().any(|s| s == name) {
+                submodules.push(name.to_string());
+            }
+        }
+        submodules
+    }
+
+    /// names of the subsections of `section` that hold at least one key, in config order:
+    /// `subsections("branch")` gives every branch with some configuration
+    pub fn subsections(&self, section: &str) -> Vec<String> {
+        let prefix = format!("{}.", section.to_lowercase());
+        let mut names: Vec<String> = Vec::new();
+        for (key, _) in &self.entries {
+            if let Some((name, _)) = key.strip_prefix(&prefix).and_then(|x| x.rsplit_once('.'))
+                && !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    /// names of all remotes with a url, in config order
+    pub fn remotes(&self) -> Vec<String> {
+        let mut remotes = Vec::new();
+        for (key, _) in &self.entries {
+            if let Some(name) = key.strip_prefix("remote.").and_then(|x| x.strip_suffix(".url"))
+                && !remotes.iter().any(|r| r == name) {
+                remotes.push(name.to_string());
+            }
+        }
+        remotes
+    }
+
+    pub fn upstream(&self, branch: &str) -> Option<Upstream> {
+        Some(Upstream {
+            remote: self.get(&format!("branch.{}.remote", branch))?.to_string(),
+            merge: self.get(&format!("branch.{}.merge", branch))?.to_string(),
+        })
+    }
+
+    /// the local ref holding what we last fetched of `upstream`: the remote's fetch refspecs map
+    /// `refs/heads/main` to `refs/remotes/origin/main`, a remote without refspecs gets the default one
+    pub fn tracking_ref(&self, upstream: &Upstream) -> Option<String> {
+        if upstream.remote == "." {
+            return Some(upstream.merge.clone());
+        }
+        let specs = self.get_all(&format!("remote.{}.fetch", upstream.remote));
+        if specs.is_empty() {
+            return Some(format!("refs/remotes/{}/{}", upstream.remote, upstream.branch()));
+        }
+        specs.iter().find_map(|spec| Self::map_refspec(spec, &upstream.merge, false))
+    }
+
+    /// the other way round: which remote and branch a remote-tracking ref stands for
+    pub fn upstream_of_tracking_ref(&self, tracking: &str) -> Option<Upstream> {
+        self.remotes().into_iter().find_map(|remote| {
+            let specs = self.get_all(&format!("remote.{}.fetch", remote));
+            let merge = if specs.is_empty() {
+                tracking.strip_prefix(&format!("refs/remotes/{}/", remote)).map(|branch| format!("refs/heads/{}", branch))
+            } else {
+                specs.iter().find_map(|spec| Self::map_refspec(spec, tracking, true))
+            };
+            merge.map(|merge| Upstream { remote, merge })
+        })
+    }
+
+    /// `+refs/heads/*:refs/remotes/origin/*` maps the source side onto the destination side,
+    /// `reverse` maps destination back to source
+    fn map_refspec(spec: &str, refname: &str, reverse: bool) -> Option<String> {
+        let (src, dst) = spec.trim_start_matches('+').split_once(':')?;
+        let (from, to) = if reverse { (dst, src) } else { (src, dst) };
+        match from.split_once('*') {
+            Some((prefix, suffix)) => {
+                let matched = refname.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(to.replacen('*', matched, 1))
+            },
+            None => (from == refname).then(|| to.to_string()),
+        }
+    }
+
+    /// set `key` in $GIT_DIR/config, replacing the last existing value or appending to its section
+    pub fn set(gitdir: &Path, key: &str, value: &str) -> Result<()> {
+        Self::edit(gitdir, key, Edit::Set(value))
+    }
+
+    /// add one more value to a multi-valued `key`, like `git config --add`
+    pub fn add(gitdir: &Path, key: &str, value: &str) -> Result<()> {
+        Self::edit(gitdir, key, Edit::Add(value))
+    }
+
+    /// drop every value of `key` from $GIT_DIR/config
+    pub fn unset(gitdir: &Path, key: &str) -> Result<()> {
+        Self::edit(gitdir, key, Edit::Unset)
+    }
+
+    /// set `key` in a file in config syntax other than $GIT_DIR/config, like .gitmodules
+    pub fn set_in_file(path: &Path, key: &str, value: &str) -> Result<()> {
+        Self::edit_file(path, key, Edit::Set(value))
+    }
+
+    /// give every header of `section` (`remote.origin`) the name `new`, the keys stay where they are
+    pub fn rename_section(gitdir: &Path, section: &str, new: &str) -> Result<()> {
+        let header = match new.split_once('.') {
+            Some((name, sub)) => format!("[{} \"{}\"]", name.to_lowercase(), sub),
+            None => format!("[{}]", new.to_lowercase()),
+        };
+        Self::edit_section(gitdir, section, Some(&header))
+    }
+
+    /// drop every header of `section` together with its keys
+    pub fn remove_section(gitdir: &Path, section: &str) -> Result<()> {
+        Self::edit_section(gitdir, section, None)
+    }
+
+    fn edit_section(gitdir: &Path, section: &str, header: Option<&str>) -> Result<()> {
+        let path = common_dir(gitdir).join("config");
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        let section = Self::normalize_section(section);
+        let mut lines = Vec::new();
+        let mut inside = false;
+        for line in content.lines() {
+            if let Some(name) = Self::section_of(line) {
+                inside = name == section;
+                if inside && let Some(header) = header {
+                    lines.push(header.to_string());
+                    continue;
+                }
+            }
+            if !inside || header.is_some() {
+                lines.push(line.to_string());
+            }
+        }
+
+        let mut content = lines.join("\n");
+        content.push('\n');
+        fs::write(&path, content)
+            .map_err(|_| GitError::failed_to_write_file(&path.display().to_string()))?;
+        Ok(())
+    }
+
+    /// the section a header line opens, None for any other line
+    fn section_of(line: &str) -> Option<String> {
+        line.trim().strip_prefix('[')
+            .and_then(|x| x.split_once(']'))
+            .map(|(header, _)| Self::parse_section(header.trim()))
+    }
+
+    /// `Remote.origin` -> remote.origin, the section name is case-insensitive and the subsection is not
+    fn normalize_section(section: &str) -> String {
+        match section.split_once('.') {
+            Some((name, sub)) => format!("{}.{}", name.to_lowercase(), sub),
+            None => section.to_lowercase(),
+        }
+    }
+
+    fn edit(gitdir: &Path, key: &str, edit: Edit) -> Result<()> {
+        Self::edit_file(&common_dir(gitdir).join("config"), key, edit)
+    }
+
+    fn edit_file(path: &Path, key: &str, edit: Edit) -> Result<()> {
+        // new lines keep the spelling of the caller, `pushRemote` stays camel case
+        let name = key.rsplit_once('.').map(|(_, name)| name)
+            .ok_or_else(|| GitError::invalid_command(format!("key does not contain a section: {}", key)))?;
+        let key = Self::normalize(key);
+        let section = &key[..key.len() - name.len() - 1];
+        let content = if path.exists() {
+            fs::read_to_string(path).map_err(|_| GitError::failed_to_read_file(&path.display().to_string()))?
+        } else {
+            String::new()
+        };
+
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        let mut current: Option<String> = None;
+        // the last line of the section, and the last line holding the key
+        let mut section_end = None;
+        let mut existing = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            let trimmed = line.trim();
+            if let Some(header) = Self::section_of(line) {
+                current = Some(header);
+            }
+            else if current.as_deref() == Some(section) {
+                let line_key = trimmed.split_once('=').map_or(trimmed, |(k, _)| k).trim();
+                if !trimmed.starts_with(['#', ';']) && line_key.eq_ignore_ascii_case(name) {
+                    existing.push(i);
+                }
+            }
+            if current.as_deref() == Some(section) && !trimmed.is_empty() {
+                section_end = Some(i);
+            }
+        }
+
+        match (edit, existing.last(), section_end) {
+            (Edit::Set(value), Some(&last), _) => {
+                lines[last] = format!("\t{} = {}", name, Self::quote_value(value));
+            },
+            (Edit::Set(value) | Edit::Add(value), _, Some(end)) => {
+                lines.insert(end + 1, format!("\t{} = {}", name, Self::quote_value(value)));
+            },
+            (Edit::Set(value) | Edit::Add(value), _, None) => {
+                let header = match section.split_once('.') {
+                    Some((name, sub)) => format!("[{} \"{}\"]", name, sub),
+                    None => format!("[{}]", section),
+                };
+                lines.push(header);
+                lines.push(format!("\t{} = {}", name, Self::quote_value(value)));
+            },
+            (Edit::Unset, _, _) => {
+                for i in existing.into_iter().rev() {
+                    lines.remove(i);
+                }
+            },
+        }
+
+        let mut content = lines.join("\n");
+        content.push('\n');
+        fs::write(path, content)
+            .map_err(|_| GitError::failed_to_write_file(&path.display().to_string()))?;
+        Ok(())
+    }
+
+    fn quote_value(value: &str) -> String {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t");
+        let needs_quotes = value.starts_with(' ') || value.ends_with(' ') || value.contains(['#', ';']);
+        if needs_quotes { format!("\"{}\"", escaped) } else { escaped }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::utils::test::{shell_spawn, tempdir};
+    use super::{Config, Upstream};
+
+    #[test]
+    fn test_tracking_ref() {
+        let config = Config::parse(concat!(
+            "[remote \"origin\"]\n\turl = https://example.com/a.git\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n",
+            "[remote \"mirror\"]\n\turl = https://example.com/b.git\n\tfetch = +refs/heads/*:refs/remotes/mirror/heads/*\n",
+            "[remote \"bare\"]\n\turl = https://example.com/c.git\n",
+            "[branch \"topic\"]\n\tremote = origin\n\tmerge = refs/heads/main\n",
+        )).unwrap();
+        let upstream = |remote: &str, merge: &str| Upstream { remote: remote.to_string(), merge: merge.to_string() };
+
+        assert_eq!(config.remotes(), ["origin", "mirror", "bare"]);
+        assert_eq!(config.upstream("topic"), Some(upstream("origin", "refs/heads/main")));
+        assert_eq!(config.upstream("main"), None);
+        assert_eq!(config.tracking_ref(&upstream("origin", "refs/heads/a/b")).as_deref(), Some("refs/remotes/origin/a/b"));
+        assert_eq!(config.tracking_ref(&upstream("mirror", "refs/heads/main")).as_deref(), Some("refs/remotes/mirror/heads/main"));
+        assert_eq!(config.tracking_ref(&upstream("bare", "refs/heads/main")).as_deref(), Some("refs/remotes/bare/main"));
+        assert_eq!(config.tracking_ref(&upstream(".", "refs/heads/main")).as_deref(), Some("refs/heads/main"));
+        assert_eq!(config.upstream_of_tracking_ref("refs/remotes/mirror/heads/x"), Some(upstream("mirror", "refs/heads/x")));
+        assert_eq!(config.upstream_of_tracking_ref("refs/remotes/origin/main"), Some(upstream("origin", "refs/heads/main")));
+        assert_eq!(config.upstream_of_tracking_ref("refs/remotes/other/main"), None);
+    }
+
+    #[test]
+    fn test_set_and_unset() {
+        let temp = tempdir().unwrap();
+        let gitdir = temp.path();
+        fs::write(gitdir.join("config"), "[core]\n\tbare = false\n# keep me\n[branch \"main\"]\n\tremote = origin\n\n[user]\n\tname = x\n").unwrap();
+
+        Config::set(gitdir, "branch.main.remote", "upstream").unwrap();
+        Config::set(gitdir, "branch.main.merge", "refs/heads/main").unwrap();
+        Config::set(gitdir, "branch.feature/x.pushRemote", "fork").unwrap();
+        Config::set(gitdir, "user.email", "a;b@example.com").unwrap();
+        Config::unset(gitdir, "core.bare").unwrap();
+
+        let content = fs::read_to_string(gitdir.join("config")).unwrap();
+        assert_eq!(content, concat!(
+            "[core]\n# keep me\n[branch \"main\"]\n\tremote = upstream\n\tmerge = refs/heads/main\n\n",
+            "[user]\n\tname = x\n\temail = \"a;b@example.com\"\n[branch \"feature/x\"]\n\tpushRemote = fork\n",
+        ));
+        // real git reads back what we wrote
+        let git = |key: &str| shell_spawn(&["git", "config", "-f", gitdir.join("config").to_str().unwrap(), key]).unwrap();
+        assert_eq!(git("branch.main.remote"), "upstream\n");
+        assert_eq!(git("branch.feature/x.pushremote"), "fork\n");
+        assert_eq!(git("user.email"), "a;b@example.com\n");
+    }
+
+    #[test]
+    fn test_sections() {
+        let temp = tempdir().unwrap();
+        let gitdir = temp.path();
+        fs::write(gitdir.join("config"), "[core]\n\tbare = false\n[remote \"origin\"]\n\turl=a\n[Remote \"origin\"]\n\tfetch=x\n[user]\n\tname = x\n").unwrap();
+
+        Config::rename_section(gitdir, "remote.origin", "remote.up").unwrap();
+        Config::add(gitdir, "remote.up.fetch", "y").unwrap();
+        assert_eq!(Config::read(gitdir).unwrap().subsections("remote"), ["up"]);
+        assert_eq!(Config::read(gitdir).unwrap().get_all("remote.up.fetch"), ["x", "y"]);
+        let git = |args: &[&str]| shell_spawn(&[&["git", "config", "-f", gitdir.join("config").to_str().unwrap()][..], args].concat());
+        assert_eq!(git(&["--get-all", "remote.up.fetch"]).unwrap(), "x\ny\n");
+
+        Config::remove_section(gitdir, "remote.up").unwrap();
+        assert_eq!(fs::read_to_string(gitdir.join("config")).unwrap(), "[core]\n\tbare = false\n[user]\n\tname = x\n");
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    os::unix::ffi::OsStrExt,
+};
+use similar::{ChangeTag, TextDiff};
+
+use crate::{
+    utils::{
+        blob::Blob,
+        commit::Commit,
+        fs::read_object,
+        hash::ObjectId,
+        tree::Tree,
+    },
+    Result,
+};
+
+/// one side of a file level diff
+#[derive(Debug, Clone)]
+pub struct FileVersion {
+    pub mode: u32,
+    pub hash: ObjectId,
+    pub content: Vec<u8>,
+}
+
+impl FileVersion {
+    pub fn from_blob(gitdir: &Path, mode: u32, hash: &ObjectId) -> Result<Self> {
+        let blob = read_object::<Blob>(gitdir.to_path_buf(), hash)?;
+        Ok(FileVersion {
+            mode,
+            hash: *hash,
+            content: blob.into(),
+        })
+    }
+}
+
+/// path -> (mode, hash), what a tree or the index holds for each file
+pub type Files = BTreeMap<PathBuf, (u32, ObjectId)>;
+
+/// path -> (mode, hash) of every file in the tree, paths are kept as the raw bytes of the tree
+pub fn flatten_tree(gitdir: &Path, tree_hash: &ObjectId) -> Result<Files> {
+    let tree = read_object::<Tree>(gitdir.to_path_buf(), tree_hash)?;
+    Ok(tree.flatten(gitdir)?
+        .into_iter()
+        .map(|entry| (entry.path, (entry.mode as u32, entry.hash)))
+        .collect())
+}
+
+/// paths whose version differs between `a` and `b`
+pub fn changed_paths(a: &Files, b: &Files) -> BTreeSet<PathBuf> {
+    a.keys().chain(b.keys())
+        .filter(|path| a.get(*path) != b.get(*path))
+        .cloned()
+        .collect()
+}
+
+/// `git diff` style patch between two trees, `None` is the empty tree
+pub fn diff_trees(gitdir: &Path, old_tree: Option<&ObjectId>, new_tree: Option<&ObjectId>) -> Result<String> {
+    let old = match old_tree {
+        Some(hash) => flatten_tree(gitdir, hash)?,
+        None => Files::new(),
+    };
+    let new = match new_tree {
+        Some(hash) => flatten_tree(gitdir, hash)?,
+        None => Files::new(),
+    };
+    diff_files(gitdir, &old, &new)
+}
+
+/// `git diff` style patch between two sets of files, every blob has to be in the object store
+pub fn diff_files(gitdir: &Path, old: &Files, new: &Files) -> Result<String> {
+    // git orders by the bytes of the path, a PathBuf compares by components
+    let mut paths = changed_paths(old, new).into_iter().collect::<Vec<_>>();
+    paths.sort_by(|a, b| a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes()));
+
+    let mut out = String::new();
+    for path in paths {
+        let a = old.get(&path).map(|(mode, hash)| FileVersion::from_blob(gitdir, *mode, hash)).transpose()?;
+        let b = new.get(&path).map(|(mode, hash)| FileVersion::from_blob(gitdir, *mode, hash)).transpose()?;
+        out.push_str(&file_diff(&path.to_string_lossy(), a.as_ref(), b.as_ref()));
+    }
+    Ok(out)
+}
+
+/// diff of a commit against its first parent
+pub fn diff_commit(gitdir: &Path, commit: &Commit) -> Result<String> {
+    let parent_tree = match commit.parent_hash.first() {
+        Some(parent) => Some(read_object::<Commit>(gitdir.to_path_buf(), parent)?.tree_hash),
+        None => None,
+    };
+    diff_trees(gitdir, parent_tree.as_ref(), Some(&commit.tree_hash))
+}
+
+/// the patch for a single file, `None` on one side means added / deleted
+pub fn file_diff(path: &str, old: Option<&FileVersion>, new: Option<&FileVersion>) -> String {
+    let mut out = format!("diff --git a/{} b/{}\n", path, path);
+    let (old_hash, new_hash) = (
+        old.map(|x| x.hash).unwrap_or_else(ObjectId::null).to_hex(),
+        new.map(|x| x.hash).unwrap_or_else(ObjectId::null).to_hex(),
+    );
+
+    match (old, new) {
+        (None, Some(new)) => {
+            out += &format!("new file mode {:06o}\nindex {}..{}\n", new.mode, &old_hash[..7], &new_hash[..7]);
+        },
+        (Some(old), None) => {
+            out += &format!("deleted file mode {:06o}\nindex {}..{}\n", old.mode, &old_hash[..7], &new_hash[..7]);
+        },
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            out += &format!("old mode {:06o}\nnew mode {:06o}\n", old.mode, new.mode);
+            if old_hash != new_hash {
+                out += &format!("index {}..{}\n", &old_hash[..7], &new_hash[..7]);
+            }
+        },
+        (Some(old), Some(_)) => {
+            out += &format!("index {}..{} {:06o}\n", &old_hash[..7], &new_hash[..7], old.mode);
+        },
+        (None, None) => return String::new(),
+    }
+    if old_hash == new_hash {
+        return out;
+    }
+
+    let old_content = old.map(|x| x.content.as_slice()).unwrap_or_default();
+    let new_content = new.map(|x| x.content.as_slice()).unwrap_or_default();
+    let (a_name, b_name) = (
+        if old.is_some() { format!("a/{}", path) } else { "/dev/null".to_string() },
+        if new.is_some() { format!("b/{}", path) } else { "/dev/null".to_string() },
+    );
+
+    if is_binary(old_content) || is_binary(new_content) {
+        out += &format!("Binary files {} and {} differ\n", a_name, b_name);
+        return out;
+    }
+
+    out += &format!("--- {}\n+++ {}\n", a_name, b_name);
+    out += &unified_hunks(&String::from_utf8_lossy(old_content), &String::from_utf8_lossy(new_content));
+    out
+}
+
+/// one `@@` hunk of a line diff
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    /// the lines of the old side it covers
+    pub old_range: Range<usize>,
+    /// what replaces them on the new side: context and added lines, with line endings
+    pub new_lines: Vec<String>,
+    /// the header and the `+`/`-`/` ` lines, as printed by diff
+    pub text: String,
+}
+
+/// the hunks of `old` -> `new`, with 3 lines of context like git
+pub fn hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let diff = TextDiff::from_lines(old, new);
+    let old_lines = old.lines().collect::<Vec<_>>();
+
+    diff.unified_diff().context_radius(3).iter_hunks()
+        .map(|hunk| {
+            let start = hunk.ops().first().map(|op| op.old_range().start).unwrap_or(0);
+            let end = hunk.ops().last().map(|op| op.old_range().end).unwrap_or(start);
+            let mut text = hunk.header().to_string();
+            if let Some(func) = funcname(&old_lines, start) {
+                text += " ";
+                text += func;
+            }
+            text += "\n";
+
+            let mut new_lines = Vec::new();
+            for change in hunk.iter_changes() {
+                text += &format!("{}{}", change.tag(), change.value());
+                if change.missing_newline() {
+                    text += "\n\\ No newline at end of file\n";
+                }
+                if change.tag() != ChangeTag::Delete {
+                    new_lines.push(change.value().to_string());
+                }
+            }
+            Hunk { old_range: start..end, new_lines, text }
+        })
+        .collect()
+}
+
+/// only the `@@` hunks, with 3 lines of context like git
+pub fn unified_hunks(old: &str, new: &str) -> String {
+    hunks(old, new).into_iter().map(|hunk| hunk.text).collect()
+}
+
+/// `old` with only the `selected` hunks of `hunks(old, _)` applied
+pub fn apply_hunks(old: &str, selected: &[&Hunk]) -> String {
+    let old_lines = old.split_inclusive('\n').collect::<Vec<_>>();
+    let mut out = String::new();
+    let mut next = 0;
+    for hunk in selected {
+        out.extend(old_lines[next..hunk.old_range.start].iter().copied());
+        out.extend(hunk.new_lines.iter().map(String::as_str));
+        next = hunk.old_range.end;
+    }
+    out.extend(old_lines[next..].iter().copied());
+    out
+}
+
+/// git's default hunk header context: the closest line above the hunk
+/// that starts with a letter, `_` or `$`
+fn funcname<'a>(lines: &[&'a str], hunk_start: usize) -> Option<&'a str> {
+    lines[..hunk_start.min(lines.len())]
+        .iter()
+        .rev()
+        .find(|line| line.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$'))
+        .map(|line| {
+            let line = line.trim_end();
+            let mut end = line.len().min(80);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            &line[..end]
+        })
+}
+
+/// git treats a file with a NUL byte in the first 8000 bytes as binary
+pub fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(8000).any(|b| *b == 0)
+}
@@ -1,12 +1,18 @@
 use std::fmt;
+use std::io;
 use std::error::Error;
 use std::path::Path;
-use GitError::{InvalidCommand, FileNotFound, NoSubCommand};
+use ErrorKind::{InvalidCommand, FileNotFound, NoSubCommand};
 
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+/// the exit code of a failed connection or HTTP request, git has none of its own for it;
+/// a code git doesn't use lets scripts tell it apart from a refused operation and retry
+pub const EXIT_NETWORK: i32 = 2;
+
+/// what went wrong, decides the message and the exit code
 #[derive(Debug, Clone)]
-pub enum GitError {
+pub enum ErrorKind {
     InvalidCommand(String),
     InvalidHash(String),
     InvalidBlob(String),
@@ -29,194 +35,481 @@ pub enum GitError {
     BrokenCommitHistory(String),
     MergeConflict(String),
     NoSameAncestor(String),
+    NoIdentity(String),
+    UnknownRevision(String),
+    AmbiguousRevision(String, Vec<String>),
+    CannotLockRef(String, String),
+    CorruptPack(String),
+    CorruptIndex(String),
+    IndexLocked(String),
+    PushRejected(String, String),
+    RemoteError(String),
+    AuthFailed(String),
+    NoUpstream(String),
+    PathspecNotMatched(Vec<String>),
+    InvalidArgument(String),
+    AlreadyExists(String),
+    Refused(String),
+    NotASymref(String),
+    MissingObject(String),
+    Network(String),
+    Io(String),
+    Usage(String),
     NoSubCommand,
     NotInGitRepo,
+    NoWorkTree,
+}
+
+/// an error of ours, with the error that caused it when there is one
+#[derive(Debug)]
+pub struct GitError {
+    pub kind: ErrorKind,
+    // Send and Sync so an error can come back from a worker thread with its cause
+    source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl GitError {
-    pub fn no_same_ancestor(msg: String) -> Box::<dyn Error> {
-        Box::new(
-            Self::MergeConflict(msg)
+    pub fn new(kind: ErrorKind) -> Self {
+        GitError { kind, source: None }
+    }
+
+    pub fn with_source(kind: ErrorKind, source: impl Error + Send + Sync + 'static) -> Self {
+        GitError { kind, source: Some(Box::new(source)) }
+    }
+
+    fn boxed(kind: ErrorKind) -> Box<dyn Error> {
+        Box::new(Self::new(kind))
+    }
+
+    /// what the process exits with, following git: 128 when it dies, 1 when the command ran and found a problem
+    pub fn exit_code(&self) -> i32 {
+        match self.kind {
+            ErrorKind::MergeConflict(_)
+            | ErrorKind::NoSameAncestor(_)
+            | ErrorKind::PushRejected(..)
+            | ErrorKind::PathspecNotMatched(_) => 1,
+            ErrorKind::Network(_) => EXIT_NETWORK,
+            ErrorKind::Usage(_) | ErrorKind::NoSubCommand => 129,
+            _ => 128,
+        }
+    }
+
+    pub fn no_same_ancestor(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NoSameAncestor(msg)
         )
     }
-    pub fn merge_conflict(msg: String) -> Box::<dyn Error> {
-        Box::new(
-            Self::MergeConflict(msg)
+    pub fn merge_conflict(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::MergeConflict(msg)
         )
     }
-    pub fn not_a_ccommit(msg: &str) -> Box::<dyn Error> {
-        Box::new(
-            Self::NotACCommit(msg.to_string())
+    pub fn not_a_ccommit(msg: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NotACCommit(msg.to_string())
         )
     }
-    pub fn not_a_ttree(msg: &str) -> Box::<dyn Error> {
-        Box::new(
-            Self::NotATTree(msg.to_string())
+    pub fn not_a_ttree(msg: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NotATTree(msg.to_string())
         )
     }
-    pub fn not_a_bblob(msg: &str) -> Box::<dyn Error> {
-        Box::new(
-            Self::NotABBlob(msg.to_string())
+    pub fn not_a_bblob(msg: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NotABBlob(msg.to_string())
         )
     }
-    pub fn invalid_obj(msg: String) -> Box::<dyn Error> {
-        Box::new(
-            Self::InvalidObj(msg)
+    pub fn invalid_obj(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::InvalidObj(msg)
         )
     }
-    pub fn invalid_tree(err: impl Error) -> Box::<dyn Error> {
+    pub fn invalid_tree(err: impl Error + Send + Sync + 'static) -> Box<dyn Error> {
         Box::new(
-            Self::InvalidEntry(err.to_string())
+            Self::with_source(ErrorKind::InvalidTree(err.to_string()), err)
         )
     }
-    pub fn invalid_entry(err: impl Error) -> Box::<dyn Error> {
+    pub fn invalid_entry(err: impl Error + Send + Sync + 'static) -> Box<dyn Error> {
         Box::new(
-            Self::InvalidEntry(err.to_string())
+            Self::with_source(ErrorKind::InvalidEntry(err.to_string()), err)
         )
     }
-    pub fn invalid_filemode(mode_str: String) -> Box::<dyn Error> {
-        Box::new(
-            Self::InvalidFileMode(mode_str)
+    pub fn invalid_filemode(mode_str: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::InvalidFileMode(mode_str)
         )
     }
 
-    pub fn no_permision(err: impl Error) -> Box::<dyn Error> {
+    pub fn no_permision(err: impl Error + Send + Sync + 'static) -> Box<dyn Error> {
         Box::new(
-            Self::NoPermision(err.to_string())
+            Self::with_source(ErrorKind::NoPermision(err.to_string()), err)
         )
     }
 
-    pub fn not_a_repofile<P: AsRef<Path>>(file: P) -> Box::<dyn Error>
+    pub fn not_a_repofile<P: AsRef<Path>>(file: P) -> Box<dyn Error>
     {
-        Box::new(
-            Self::NotARepoFile(file.as_ref()
+        Self::boxed(
+            ErrorKind::NotARepoFile(file.as_ref()
                 .to_str()
                 .unwrap()
                 .to_string())
         )
     }
 
-    pub fn no_subcommand() -> Box::<dyn Error> {
-        Box::new(
-            Self::NoSubCommand
+    pub fn no_subcommand() -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NoSubCommand
         )
     }
 
     pub fn file_notfound(msg: String) -> Box<dyn Error> {
-        Box::new(
-            Self::FileNotFound(msg)
+        Self::boxed(
+            ErrorKind::FileNotFound(msg)
         )
     }
 
     pub fn invalid_command(msg: String) -> Box<dyn Error> {
-        Box::new(
-            Self::InvalidCommand(msg.to_string())
+        Self::boxed(
+            ErrorKind::InvalidCommand(msg.to_string())
         )
     }
 
     pub fn network_error(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::Network(msg)
+        )
+    }
+
+    /// a network error caused by `err`, which stays reachable through `source()`
+    pub fn network_failure(msg: String, err: impl Error + Send + Sync + 'static) -> Box<dyn Error> {
         Box::new(
-            Self::InvalidCommand(format!("Network error: {}", msg))
+            Self::with_source(ErrorKind::Network(msg), err)
         )
     }
     
     pub fn protocol_error(msg: &str) -> Box<dyn Error> {
-        Box::new(
-            Self::InvalidCommand(format!("Protocol error: {}", msg))
+        Self::boxed(
+            ErrorKind::InvalidCommand(format!("Protocol error: {}", msg))
         )
     }
 
     pub fn not_in_gitrepo() -> Box<dyn Error> {
-        Box::new(
-            Self::NotInGitRepo
+        Self::boxed(
+            ErrorKind::NotInGitRepo
+        )
+    }
+
+    pub fn no_work_tree() -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NoWorkTree
         )
     }
 
     pub fn invalid_blob(path: &str) -> Box<dyn Error> {
-        Box::new(
-            Self::InvalidBlob(format!("invlaid blob format: {}", path))
+        Self::boxed(
+            ErrorKind::InvalidBlob(format!("invlaid blob format: {}", path))
         )
     }
 
     pub fn invalid_hash(hash: &str) -> Box<dyn Error> {
-        Box::new(
-            Self::InvalidHash(format!("expect hash code of length 40 but got {} of length {}", hash, hash.len()))
+        Self::boxed(
+            ErrorKind::InvalidHash(format!("expect hash code of length {} but got {} of length {}", super::hash::algorithm().hex_len(), hash, hash.len()))
         )
     }
 
     pub fn invaild_path_encoding(path: &str) -> Box<dyn Error>{
-        Box::new(
-            Self::InvaildPathEncoding(format!("invlaid path encoding: {}", path))
+        Self::boxed(
+            ErrorKind::InvaildPathEncoding(path.to_string())
         )
     }
 
     pub fn invalid_commit(path: &str) -> Box<dyn Error> {
-        Box::new(
-            Self::InvalidCommit(format!("invlaid commit: {}", path))
+        Self::boxed(
+            ErrorKind::InvalidCommit(format!("invlaid commit: {}", path))
         )
     }
 
     pub fn failed_to_read_file(path: &str) -> Box<dyn Error> {
-        Box::new(
-            Self::FailedToReadFile(format!("failed to read file: {}", path))
+        Self::boxed(
+            ErrorKind::FailedToReadFile(path.to_string())
         )
     }
 
     pub fn failed_to_write_file(path: &str) -> Box<dyn Error> {
-        Box::new(
-            Self::FailedToWriteFile(format!("failed to write file: {}", path))
+        Self::boxed(
+            ErrorKind::FailedToWriteFile(path.to_string())
         )
     }
 
     pub fn failed_to_remove_file(msg: String) -> Box<dyn Error> {
-        Box::new(
-            Self::FailedToWriteFile(msg)
+        Self::boxed(
+            ErrorKind::FailedToRmoveFile(msg)
         )
     }
 
     pub fn detached_branch(hash: String) -> Box<dyn Error> {
-        Box::new(
-            Self::DetachedBranch(hash)
+        Self::boxed(
+            ErrorKind::DetachedBranch(hash)
+        )
+    }
+
+    pub fn no_identity(msg: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NoIdentity(msg.to_string())
+        )
+    }
+
+    pub fn unknown_revision(rev: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::UnknownRevision(rev.to_string())
+        )
+    }
+
+    pub fn ambiguous_revision(prefix: &str, candidates: &[String]) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::AmbiguousRevision(prefix.to_string(), candidates.to_vec())
+        )
+    }
+
+    pub fn cannot_lock_ref(refname: &str, reason: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::CannotLockRef(refname.to_string(), reason)
+        )
+    }
+
+    pub fn corrupt_pack(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::CorruptPack(msg)
+        )
+    }
+
+    pub fn corrupt_index(msg: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::CorruptIndex(msg.to_string())
+        )
+    }
+
+    pub fn index_locked(lock: &Path) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::IndexLocked(lock.display().to_string())
+        )
+    }
+
+    pub fn push_rejected(refname: &str, reason: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::PushRejected(refname.to_string(), reason.to_string())
+        )
+    }
+
+    pub fn remote_error(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::RemoteError(msg)
+        )
+    }
+
+    pub fn auth_failed(url: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::AuthFailed(url.to_string())
+        )
+    }
+
+    pub fn no_upstream(branch: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NoUpstream(branch.to_string())
+        )
+    }
+
+    pub fn pathspec_not_matched(pathspecs: Vec<String>) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::PathspecNotMatched(pathspecs)
+        )
+    }
+
+    pub fn invalid_argument(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::InvalidArgument(msg)
+        )
+    }
+
+    pub fn already_exists(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::AlreadyExists(msg)
+        )
+    }
+
+    pub fn refused(msg: String) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::Refused(msg)
+        )
+    }
+
+    pub fn not_a_symref(name: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::NotASymref(name.to_string())
+        )
+    }
+
+    pub fn missing_object(hash: &str) -> Box<dyn Error> {
+        Self::boxed(
+            ErrorKind::MissingObject(hash.to_string())
         )
     }
 
     pub fn broken_commit_history(hash: String) -> Box<dyn Error> {
-        Box::new(
-            Self::DetachedBranch(hash)
+        Self::boxed(
+            ErrorKind::BrokenCommitHistory(hash)
         )
     }
 }
 
-impl fmt::Display for GitError {
+impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             InvalidCommand(cmd) => write!(f, "Invalid command: {}", cmd),
             FileNotFound(file)  => write!(f, "File not found: {}",  file),
-            GitError::InvalidHash(hash) => write!(f, "Invalid hash: {}", hash),
-            GitError::NoSubCommand => write!(f, "no sub command"),
-            GitError::NotInGitRepo => write!(f, "not in a git repository"),
-            GitError::InvalidBlob(msg) => write!(f, "{}", msg),
-            GitError::InvalidFileMode(mode) => write!(f, "Invalid FileMode {}", mode),
-            GitError::InvalidEntry(msg) => write!(f, "Invalid Entry {}", msg),
-            GitError::InvalidTree(msg) => write!(f, "Invalid Tree {}", msg),
-            GitError::InvalidObj(msg) => write!(f, "Invalid Obj {}", msg),
-            GitError::InvalidCommit(msg) => write!(f, "{}", msg),
-            GitError::InvaildPathEncoding(path) => write!(f, "invalid path encoding: {}", path),
-            GitError::NoPermision(msg) => write!(f, "no access permission: {}", msg),
-            GitError::NotARepoFile(path) => write!(f, "found a file not in git repo {}", path),
-            GitError::FailedToReadFile(path) => write!(f, "failed to read file: {}", path),
-            GitError::FailedToWriteFile(path) => write!(f, "failed to write file: {}", path),
-            GitError::FailedToRmoveFile(msg) => write!(f, "{}", msg),
-            GitError::DetachedBranch(hash) => write!(f, "detached head {} current branch not found", hash),
-            GitError::BrokenCommitHistory(hash) => write!(f, "{} commit is broken", hash),
-            GitError::NotABBlob(msg) => write!(f, "debug Error, should not happen in release: {}", msg),
-            GitError::NotATTree(msg) => write!(f, "debug Error, should not happen in release: {}", msg),
-            GitError::NotACCommit(msg) => write!(f, "debug Error, should not happen in release: {}", msg),
-            GitError::MergeConflict(msg) => write!(f, "{}", msg),
-            GitError::NoSameAncestor(msg) => write!(f, "{}", msg),
-            
+            ErrorKind::InvalidHash(hash) => write!(f, "Invalid hash: {}", hash),
+            ErrorKind::NoSubCommand => write!(f, "no sub command"),
+            ErrorKind::NotInGitRepo => write!(f, "not in a git repository"),
+            ErrorKind::NoWorkTree => write!(f, "this operation must be run in a work tree"),
+            ErrorKind::InvalidBlob(msg) => write!(f, "{}", msg),
+            ErrorKind::InvalidFileMode(mode) => write!(f, "Invalid FileMode {}", mode),
+            ErrorKind::InvalidEntry(msg) => write!(f, "Invalid Entry {}", msg),
+            ErrorKind::InvalidTree(msg) => write!(f, "Invalid Tree {}", msg),
+            ErrorKind::InvalidObj(msg) => write!(f, "Invalid Obj {}", msg),
+            ErrorKind::InvalidCommit(msg) => write!(f, "{}", msg),
+            ErrorKind::InvaildPathEncoding(path) => write!(f, "invalid path encoding: {}", path),
+            ErrorKind::NoPermision(msg) => write!(f, "no access permission: {}", msg),
+            ErrorKind::NotARepoFile(path) => write!(f, "found a file not in git repo {}", path),
+            ErrorKind::FailedToReadFile(path) => write!(f, "failed to read file: {}", path),
+            ErrorKind::FailedToWriteFile(path) => write!(f, "failed to write file: {}", path),
+            ErrorKind::FailedToRmoveFile(path) => write!(f, "failed to remove file: {}", path),
+            ErrorKind::DetachedBranch(hash) => write!(f, "detached head {} current branch not found", hash),
+            ErrorKind::BrokenCommitHistory(hash) => write!(f, "{} commit is broken", hash),
+            ErrorKind::NotABBlob(msg) => write!(f, "debug Error, should not happen in release: {}", msg),
+            ErrorKind::NotATTree(msg) => write!(f, "debug Error, should not happen in release: {}", msg),
+            ErrorKind::NotACCommit(msg) => write!(f, "debug Error, should not happen in release: {}", msg),
+            ErrorKind::MergeConflict(msg) => write!(f, "{}", msg),
+            ErrorKind::NoSameAncestor(msg) => write!(f, "{}", msg),
+            ErrorKind::NoIdentity(msg) => write!(f, "Author identity unknown: {}", msg),
+            ErrorKind::UnknownRevision(rev) => write!(f, "ambiguous argument '{}': unknown revision or path not in the working tree.", rev),
+            ErrorKind::AmbiguousRevision(prefix, candidates) => write!(f, "short object ID {} is ambiguous\nhint: The candidates are:\nhint:   {}", prefix, candidates.join("\nhint:   ")),
+            ErrorKind::CannotLockRef(refname, reason) => write!(f, "cannot lock ref '{}': {}", refname, reason),
+            ErrorKind::CorruptPack(msg) => write!(f, "corrupt packfile: {}", msg),
+            ErrorKind::CorruptIndex(msg) => write!(f, "index file corrupt: {}", msg),
+            ErrorKind::IndexLocked(lock) => write!(f, "Unable to create '{}': index.lock exists.\n\nAnother git process seems to be running in this repository.\nIf no other git process is running, a process crashed earlier:\nremove the file manually to continue.", lock),
+            ErrorKind::PushRejected(refname, reason) => write!(f, "failed to push some refs: ! [remote rejected] {} ({})", refname, reason),
+            ErrorKind::RemoteError(msg) => write!(f, "remote error: {}", msg),
+            ErrorKind::AuthFailed(url) => write!(f, "Authentication failed for '{}'", url),
+            ErrorKind::NoUpstream(branch) => write!(f, "The current branch {} has no upstream branch.\nTo push the current branch and set the remote as upstream, use\n\n    git push --set-upstream origin {}\n", branch, branch),
+            ErrorKind::PathspecNotMatched(pathspecs) => write!(f, "{}", pathspecs.iter()
+                .map(|pathspec| format!("error: pathspec '{}' did not match any file(s) known to git", pathspec))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ErrorKind::InvalidArgument(msg) => write!(f, "{}", msg),
+            ErrorKind::AlreadyExists(msg) => write!(f, "{}", msg),
+            ErrorKind::Refused(msg) => write!(f, "{}", msg),
+            ErrorKind::NotASymref(name) => write!(f, "ref {} is not a symbolic ref", name),
+            ErrorKind::MissingObject(hash) => write!(f, "unable to read {}", hash),
+            ErrorKind::Network(msg) => write!(f, "Network error: {}", msg),
+            ErrorKind::Io(msg) => write!(f, "{}", msg),
+            ErrorKind::Usage(msg) => write!(f, "{}", msg),
         }
     }
 }
 
-impl Error for GitError {}
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl Error for GitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|source| source as &(dyn Error + 'static))
+    }
+}
+
+impl From<ErrorKind> for GitError {
+    fn from(kind: ErrorKind) -> Self {
+        GitError::new(kind)
+    }
+}
+
+impl From<io::Error> for GitError {
+    fn from(err: io::Error) -> Self {
+        let kind = match err.kind() {
+            io::ErrorKind::NotFound => ErrorKind::FileNotFound(err.to_string()),
+            io::ErrorKind::PermissionDenied => ErrorKind::NoPermision(err.to_string()),
+            _ => ErrorKind::Io(err.to_string()),
+        };
+        GitError::with_source(kind, err)
+    }
+}
+
+impl From<clap::Error> for GitError {
+    fn from(err: clap::Error) -> Self {
+        GitError::with_source(ErrorKind::Usage(err.render().to_string()), err)
+    }
+}
+
+/// the exit code for whatever error a command gave up with, errors that are not ours are fatal
+pub fn exit_code(err: &(dyn Error + 'static)) -> i32 {
+    if let Some(err) = err.downcast_ref::<GitError>() {
+        err.exit_code()
+    }
+    else if err.is::<clap::Error>() {
+        129
+    }
+    else {
+        128
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+    use crate::utils::test::{setup_test_git_dir, shell_spawn, tempdir};
+
+    #[test]
+    fn test_source_and_exit_code() {
+        let err = GitError::no_permision(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"));
+        assert_eq!(err.source().unwrap().to_string(), "read-only");
+        assert_eq!(exit_code(err.as_ref()), 128);
+        let err = GitError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert!(matches!(err.kind, ErrorKind::FileNotFound(_)));
+        assert!(err.source().unwrap().downcast_ref::<io::Error>().is_some());
+        assert!(matches!(GitError::no_same_ancestor(String::new()).downcast_ref::<GitError>().unwrap().kind, ErrorKind::NoSameAncestor(_)));
+        assert!(matches!(GitError::broken_commit_history(String::new()).downcast_ref::<GitError>().unwrap().kind, ErrorKind::BrokenCommitHistory(_)));
+        assert!(matches!(GitError::failed_to_remove_file(String::new()).downcast_ref::<GitError>().unwrap().kind, ErrorKind::FailedToRmoveFile(_)));
+
+        // the process exits the way git does
+        let status = |dir: &str, args: &[&str]| Command::new("cargo")
+            .args([&["run", "--quiet", "--", "-C", dir][..], args].concat())
+            .output()
+            .unwrap()
+            .status
+            .code();
+        let outside = tempdir().unwrap();
+        assert_eq!(status(outside.path().to_str().unwrap(), &["write-tree"]), Some(128));
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        assert_eq!(status(temp_path_str, &["add", "--no-such-option"]), Some(129));
+        assert_eq!(exit_code(GitError::network_error(String::new()).as_ref()), EXIT_NETWORK);
+        // nothing listens on port 1, the connection is refused
+        assert_eq!(status(temp_path_str, &["ls-remote", "http://127.0.0.1:1/repo.git"]), Some(EXIT_NETWORK));
+
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        std::fs::write(temp_path.join("a.txt"), "base\n").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["commit", "-m", "base"]);
+        git(&["checkout", "-q", "-b", "side"]);
+        std::fs::write(temp_path.join("a.txt"), "side\n").unwrap();
+        git(&["commit", "-qam", "side"]);
+        git(&["checkout", "-q", "master"]);
+        std::fs::write(temp_path.join("a.txt"), "master\n").unwrap();
+        git(&["commit", "-qam", "master"]);
+        assert_eq!(status(temp_path_str, &["merge", "side"]), Some(1));
+    }
+}
@@ -1,9 +1,10 @@
 use std::{
     error::Error,
-    env::current_dir,
+    env::{self, current_dir},
     io::{BufReader, Read},
     fs::{read, File},
     path::{PathBuf, Path},
+    sync::OnceLock,
 };
 
 use crate::{
@@ -12,9 +13,10 @@ use crate::{
 };
 
 use super::{
-    hash::hash_object,
+    hash::{hash_object, hash_raw_object, ObjectId, ToHex},
     zlib::{
         compress_object as zlib_compress_object,
+        compress_raw_object as zlib_compress_raw_object,
         decompress_file_as_bytes,
     },
     objtype::{
@@ -26,32 +28,64 @@ use super::{
         Index,
     },
     tree::FileMode,
+    config::Config,
+    packfile,
+    pool,
 };
 
 use std::fs;
 use std::os::unix::fs::PermissionsExt; // 用于操作 Unix 文件权限
 
-fn is_executable(file_path: impl AsRef<Path>) -> Result<bool> {
-    let metadata = fs::metadata(file_path)
+/// drop the directories above `file_path` which became empty, stop at the first non-empty one
+/// `project_root` itself is never removed
+pub fn remove_empty_parents(project_root: &Path, file_path: &Path) {
+    let mut dir = file_path.parent();
+    while let Some(d) = dir {
+        if !d.starts_with(project_root) || d == project_root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// mode and content git records for the file at `file_path`
+/// a symlink is never followed, its content is the link target
+pub fn read_worktree_file(file_path: impl AsRef<Path>) -> Result<(u32, Vec<u8>)> {
+    let file_path = file_path.as_ref();
+    let metadata = fs::symlink_metadata(file_path)
         .map_err(GitError::no_permision)?;
 
-    let permissions = metadata.permissions();
+    if metadata.file_type().is_symlink() {
+        use std::os::unix::ffi::OsStrExt;
+        let target = fs::read_link(file_path)?;
+        return Ok((FileMode::Symbolic as u32, target.as_os_str().as_bytes().to_vec()));
+    }
+    Ok((worktree_mode(&metadata), read(file_path)?))
+}
 
-    let mode = permissions.mode();
-    Ok(mode & 0o111 != 0) // 检查用户、组或其他用户的可执行位是否被设置
+/// the mode git records for a file with this (not followed) metadata, only the exec bit matters
+pub fn worktree_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.file_type().is_symlink() {
+        return FileMode::Symbolic as u32;
+    }
+    // 检查用户、组或其他用户的可执行位是否被设置
+    let mode = if metadata.permissions().mode() & 0o111 != 0 { FileMode::Exec } else { FileMode::Blob };
+    mode as u32
 }
 
 
 /*  check the whether s exists in git's objects directory  */
-pub fn obj_to_pathbuf(gitdir: &PathBuf, s: &str) -> PathBuf {
+pub fn obj_to_pathbuf(gitdir: &Path, s: &(impl ToHex + ?Sized)) -> PathBuf {
+    let s = s.to_hex();
     let (first, second) = s.split_at(2);
-    gitdir.join("objects").join(first).join(second)
+    common_dir(gitdir).join("objects").join(first).join(second)
 }
 
 // 保持旧版本兼容性
 pub fn obj_to_pathbuf_legacy(s: &str) -> std::result::Result<PathBuf, String> {
-    if s.len() != 40 {
-        Err(format!("{} 长度不等于40，实际长度: {}", s, s.len()))
+    let hex_len = super::hash::algorithm().hex_len();
+    if s.len() != hex_len {
+        Err(format!("{} 长度不等于{}，实际长度: {}", s, hex_len, s.len()))
     }
     else {
         let (first, second) = s.split_at(2);
@@ -83,10 +117,11 @@ where T: AsRef<Path>
 fn search_dir(mut path: PathBuf, target: &str) -> Result<PathBuf>
 {
     path.push(target);
-    if path.exists() && path.is_dir() {
+    // a linked worktree has a .git file, a bare repository is its own git dir
+    if path.is_dir() || path.is_file() || (path.pop() && is_bare_git_dir(&path)) {
         Ok(path)
     }
-    else if !path.pop() || !path.pop() {
+    else if !path.pop() {
         Err(GitError::not_in_gitrepo())
     }
     else {
@@ -94,8 +129,143 @@ fn search_dir(mut path: PathBuf, target: &str) -> Result<PathBuf>
     }
 }
 
+/// HEAD, objects/ and refs/ right in `path`, without a work tree around them
+pub fn is_bare_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+/// where a command runs
+#[derive(Debug, Clone)]
+pub struct Repository {
+    pub gitdir: PathBuf,
+    /// top of the work tree, the directory holding the git dir unless $GIT_WORK_TREE says otherwise,
+    /// none for a bare repository
+    work_tree: Option<PathBuf>,
+    /// where the command was started (cwd or -C), relative to `work_tree`, empty outside of it
+    pub prefix: PathBuf,
+    /// where the command was started, for a repository without a work tree
+    cwd: PathBuf,
+}
+
+/// the repository found for this process, see `work_tree`
+static REPOSITORY: OnceLock<Repository> = OnceLock::new();
+
+impl Repository {
+    /// a repository found by a command itself, e.g. to run another command internally
+    pub fn at(gitdir: impl Into<PathBuf>) -> Self {
+        let gitdir = gitdir.into();
+        let work_tree = work_tree(&gitdir).ok();
+        let cwd = work_tree.clone().unwrap_or_else(|| gitdir.clone());
+        Repository { gitdir, work_tree, prefix: PathBuf::new(), cwd }
+    }
+
+    /// the top of the work tree, commands which touch files fail in a bare repository like in git
+    pub fn work_tree(&self) -> Result<&Path> {
+        self.work_tree.as_deref().ok_or_else(GitError::no_work_tree)
+    }
+
+    /// a path given on the command line, relative to where the command was started
+    pub fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        match &self.work_tree {
+            Some(work_tree) => work_tree.join(&self.prefix).join(path),
+            None => self.cwd.join(path),
+        }
+    }
+
+    /// started in the git dir or below it, where git sees no work tree even when the repository has one
+    pub fn inside_git_dir(&self) -> bool {
+        match (self.cwd.canonicalize(), self.gitdir.canonicalize()) {
+            (Ok(cwd), Ok(gitdir)) => cwd.starts_with(gitdir),
+            _ => false,
+        }
+    }
+
+    /// the git dir as git prints it: `.` from inside it, `.git` from the top of the work tree, absolute otherwise
+    pub fn display_gitdir(&self) -> PathBuf {
+        let (cwd, gitdir) = (self.cwd.canonicalize().ok(), self.gitdir.canonicalize().ok());
+        match gitdir {
+            Some(_) if gitdir == cwd => PathBuf::from("."),
+            Some(_) if gitdir == cwd.map(|cwd| cwd.join(".git")) => PathBuf::from(".git"),
+            _ => self.gitdir.clone(),
+        }
+    }
+}
+
+/// core.bare when it is set, otherwise a git dir not called .git is taken for a bare one
+fn is_bare(gitdir: &Path) -> bool {
+    match Config::read(gitdir).ok().and_then(|config| config.get_bool("core.bare")) {
+        Some(bare) => bare,
+        None => gitdir.file_name().is_some_and(|name| name != ".git"),
+    }
+}
+
+/// find the repository for `dir` (-C) or the current directory
+/// $GIT_DIR names the git dir directly, then the starting directory is the top of the work tree like in git,
+/// $GIT_WORK_TREE overrides the work tree in both cases, a bare repository has none
+pub fn discover_repository(dir: Option<PathBuf>) -> Result<Repository> {
+    let base = match dir {
+        Some(dir) => current_dir()?.join(dir),
+        None => current_dir()?,
+    };
+    let git_dir_env = env::var_os("GIT_DIR").filter(|dir| !dir.is_empty());
+    let dot_git = match &git_dir_env {
+        Some(gitdir) if base.join(gitdir).join("HEAD").is_file() => base.join(gitdir),
+        Some(_) => return Err(GitError::not_in_gitrepo()),
+        None => search_git_dir(&base)?,
+    };
+    let gitdir = read_gitfile(&dot_git).unwrap_or_else(|| dot_git.clone());
+    let bare = dot_git == gitdir && is_bare(&gitdir);
+    let work_tree = match env::var_os("GIT_WORK_TREE").filter(|dir| !dir.is_empty()) {
+        Some(work_tree) => Some(base.join(work_tree)),
+        None if bare => None,
+        None if git_dir_env.is_some() => Some(base.clone()),
+        None => Some(dot_git.parent().expect("find git dir implementation fail").to_path_buf()),
+    };
+    let prefix = match (base.canonicalize(), work_tree.as_ref().map(|top| top.canonicalize())) {
+        (Ok(base), Some(Ok(top))) => base.strip_prefix(&top).map(Path::to_path_buf).unwrap_or_default(),
+        _ => PathBuf::new(),
+    };
+
+    let repository = Repository { gitdir, work_tree, prefix, cwd: base };
+    // only main looks for the repository, once
+    let _ = REPOSITORY.set(repository.clone());
+    Ok(repository)
+}
+
+/// the work tree of `gitdir`, for code which only gets to see the git dir
+/// a linked worktree's git dir knows its .git file from `gitdir`, a bare repository has no work tree
+pub fn work_tree(gitdir: &Path) -> Result<PathBuf> {
+    match REPOSITORY.get() {
+        Some(repository) if repository.gitdir == gitdir => repository.work_tree().map(Path::to_path_buf),
+        _ => match std::fs::read_to_string(gitdir.join("gitdir")) {
+            Ok(dot_git) => Ok(Path::new(dot_git.trim()).parent().expect("find git dir implementation fail").to_path_buf()),
+            Err(_) if is_bare(gitdir) => Err(GitError::no_work_tree()),
+            Err(_) => Ok(gitdir.parent().expect("find git dir implementation fail").to_path_buf()),
+        },
+    }
+}
+
+/// `gitdir: <path>` in the .git file of a linked worktree, relative to the file's directory
+pub fn read_gitfile(path: &Path) -> Option<PathBuf> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let target = content.strip_prefix("gitdir: ")?.trim();
+    Some(path.parent()?.join(target))
+}
+
+/// where objects, refs and config are kept: a linked worktree's git dir names the main one in `commondir`,
+/// HEAD, the index and the state of a merge or rebase stay in the worktree's own git dir
+pub fn common_dir(gitdir: &Path) -> PathBuf {
+    match std::fs::read_to_string(gitdir.join("commondir")) {
+        Ok(dir) => {
+            let dir = gitdir.join(dir.trim());
+            dir.canonicalize().unwrap_or(dir)
+        },
+        Err(_) => gitdir.to_path_buf(),
+    }
+}
+
 pub fn get_git_dir() -> Result<PathBuf> {
-    search_git_dir(current_dir().unwrap())
+    discover_repository(None).map(|repository| repository.gitdir)
 }
 
 pub fn search_git_dir<T>(path: T) -> Result<PathBuf>
@@ -104,31 +274,160 @@ where T: AsRef<Path>
     search_dir(PathBuf::from(path.as_ref()), ".git")
 }
 
-pub fn write_object<T: ObjType>(mut gitdir: PathBuf, content: Vec<u8>) -> Result<String> {
-    let commit_hash = hash_object::<T>(content.clone())?;
+pub fn write_object<T: ObjType>(gitdir: PathBuf, content: Vec<u8>) -> Result<ObjectId> {
+    write_raw_object(gitdir, T::VALUE, content)
+}
 
-    gitdir.extend(["objects", &commit_hash[0..2], &commit_hash[2..]]);
+/// write an object whose type is only known at runtime, e.g. `hash-object -t tag`
+pub fn write_raw_object(gitdir: PathBuf, kind: &str, content: Vec<u8>) -> Result<ObjectId> {
+    let commit_hash = hash_raw_object(kind, content.clone())?;
 
-    std::fs::create_dir_all(gitdir.parent().unwrap()).map_err(GitError::no_permision)?;
-    std::fs::write(
-        &gitdir,
-    zlib_compress_object::<T>(content)?).map_err(GitError::no_permision)?;
+    let path = obj_to_pathbuf(&gitdir, &commit_hash);
+    // objects are immutable, the same hash means the same content
+    if path.exists() {
+        return Ok(commit_hash);
+    }
+
+    write_loose_object(&path, &zlib_compress_raw_object(kind, content)?)?;
 
     Ok(commit_hash)
 }
 
-pub fn read_obj(mut gitdir: PathBuf, hash: &str) -> Result<Obj> {
-    gitdir.extend(["objects", &hash[0..2], &hash[2..]]);
-    let bytes = decompress_file_as_bytes(&gitdir)?;
+/// put an already compressed object at `path` through a temp file and a rename, read-only like git's objects
+/// several threads may write the same object, a reader never sees half of it
+pub fn write_loose_object(path: &Path, compressed: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir).map_err(GitError::no_permision)?;
+    let mut temp = tempfile::NamedTempFile::new_in(dir).map_err(GitError::no_permision)?;
+    std::io::Write::write_all(&mut temp, compressed).map_err(GitError::no_permision)?;
+    temp.as_file().set_permissions(fs::Permissions::from_mode(0o444)).map_err(GitError::no_permision)?;
+    temp.persist(path).map_err(|e| GitError::no_permision(e.error))?;
+    Ok(())
+}
+
+/// files at least this big are hashed and compressed as a stream, never read into memory as a whole
+pub const STREAMING_THRESHOLD: u64 = 32 * 1024 * 1024;
+
+/// hash and store the `len` bytes of `reader` as an object, a buffer at a time
+/// the compressed object goes to a temp file in objects/ first, it is renamed into place once the hash is known
+pub fn hash_and_write_object_streaming<T: ObjType>(gitdir: &Path, mut reader: impl Read, len: u64) -> Result<ObjectId> {
+    use std::io::Write;
+    use flate2::write::ZlibEncoder;
+    let objects_dir = common_dir(gitdir).join("objects");
+    fs::create_dir_all(&objects_dir).map_err(GitError::no_permision)?;
+    let temp = tempfile::NamedTempFile::new_in(&objects_dir).map_err(GitError::no_permision)?;
+    let mut hasher = super::hash::algorithm().hasher();
+    let mut encoder = ZlibEncoder::new(std::io::BufWriter::new(temp), super::zlib::level());
+
+    let header = format!("{} {}\0", T::VALUE, len);
+    hasher.update(header.as_bytes());
+    encoder.write_all(header.as_bytes())?;
+    let mut buffer = vec![0; 1 << 16];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        encoder.write_all(&buffer[..n])?;
+        total += n as u64;
+    }
+    // the header already promised `len` bytes
+    if total != len {
+        return Err(GitError::invalid_obj(format!("expected {} bytes but read {}, was the file changed meanwhile?", len, total)));
+    }
+    let temp = encoder.finish()?.into_inner().map_err(|e| GitError::no_permision(e.into_error()))?;
+
+    let hash = ObjectId::from_raw(&hasher.finalize())?;
+    let path = obj_to_pathbuf(gitdir, &hash);
+    // objects are immutable, the same hash means the same content
+    if !path.exists() {
+        fs::create_dir_all(path.parent().unwrap()).map_err(GitError::no_permision)?;
+        temp.as_file().set_permissions(fs::Permissions::from_mode(0o444)).map_err(GitError::no_permision)?;
+        temp.persist(&path).map_err(|e| GitError::no_permision(e.error))?;
+    }
+    Ok(hash)
+}
+
+/// write the content of blob `hash` to `file_path`, a loose blob is decompressed straight into the file
+pub fn write_blob_to_file(gitdir: &Path, hash: &(impl ToHex + ?Sized), file_path: &Path) -> Result<()> {
+    use flate2::read::ZlibDecoder;
+
+    let hash = &hash.to_hex();
+    let path = obj_to_pathbuf(gitdir, hash);
+    if !path.exists() {
+        let content = read_raw_object(gitdir, hash)?;
+        let header_end = content.iter().position(|&b| b == 0)
+            .ok_or_else(|| GitError::invalid_obj(format!("object {} has no header", hash)))?;
+        if !content.starts_with(b"blob ") {
+            return Err(GitError::not_a_bblob(hash));
+        }
+        return fs::write(file_path, &content[header_end + 1..])
+            .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()));
+    }
+
+    let mut decoder = BufReader::new(ZlibDecoder::new(File::open(&path)?));
+    let mut header = Vec::new();
+    std::io::BufRead::read_until(&mut decoder, 0, &mut header)?;
+    if !header.starts_with(b"blob ") || header.last() != Some(&0) {
+        return Err(GitError::not_a_bblob(hash));
+    }
+    let mut file = File::create(file_path)
+        .map_err(|_| GitError::failed_to_write_file(&file_path.to_string_lossy()))?;
+    std::io::copy(&mut decoder, &mut file)?;
+    Ok(())
+}
+
+/// the decompressed "type size\0data" of an object, loose or in a pack
+pub fn read_raw_object(gitdir: &Path, hash: &(impl ToHex + ?Sized)) -> Result<Vec<u8>> {
+    let hash = &hash.to_hex();
+    let path = obj_to_pathbuf(gitdir, hash);
+    if path.exists() {
+        return decompress_file_as_bytes(&path);
+    }
+    packfile::read_packed_object(gitdir, hash)?
+        .ok_or_else(|| GitError::file_notfound(format!("object {} not found", hash)))
+}
+
+/// whether the object is stored loose or in a pack
+pub fn object_exists(gitdir: &Path, hash: &(impl ToHex + ?Sized)) -> bool {
+    let hash = &hash.to_hex();
+    obj_to_pathbuf(gitdir, hash).exists()
+        || packfile::has_packed_object(gitdir, hash)
+}
+
+/// every loose object as (hash, path), sorted by hash
+pub fn loose_objects(gitdir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut objects = Vec::new();
+    for dir in std::fs::read_dir(common_dir(gitdir).join("objects"))?.flatten() {
+        let prefix = dir.file_name().to_string_lossy().to_string();
+        if prefix.len() != 2 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        for file in std::fs::read_dir(dir.path())?.flatten() {
+            let hash = format!("{}{}", prefix, file.file_name().to_string_lossy());
+            if super::hash::is_hash(&hash) {
+                objects.push((hash, file.path()));
+            }
+        }
+    }
+    objects.sort();
+    Ok(objects)
+}
+
+pub fn read_obj(gitdir: PathBuf, hash: &(impl ToHex + ?Sized)) -> Result<Obj> {
+    let bytes = read_raw_object(&gitdir, hash)?;
     // println!("read {}", gitdir.display());
     // println!("string = {}", String::from_utf8_lossy(&bytes).to_owned());
     bytes.try_into()
 }
 
-pub fn read_object<T>(gitdir: PathBuf, hash: &str) -> Result<T>
+pub fn read_object<T>(gitdir: PathBuf, hash: &(impl ToHex + ?Sized)) -> Result<T>
 where
     T: ObjType + TryFrom<Obj, Error=Box<dyn Error>>
 {
+    let hash = &hash.to_hex();
     let obj = read_obj(gitdir, hash)
         .map_err(|e|GitError::invalid_obj(format!("fail to read {} object {}\n", T::VALUE, hash) + &e.to_string()))?;
     obj.try_into()
@@ -138,15 +437,23 @@ pub fn add_object<T>(gitdir: PathBuf, path: impl AsRef<Path>) -> Result<IndexEnt
 where
     T: ObjType,
 {
-    let project_root = gitdir.parent().expect("find git implementation fail").to_path_buf();
-    let mode = if is_executable(project_root.join(&path))? { FileMode::Exec as u32 } else { T::MODE };
-    let hash = write_object::<T>(gitdir, read_file_as_bytes(&project_root.join(&path))?)?;
-    let path = String::from(path.as_ref().to_str().unwrap());
-    Ok(IndexEntry {
-        mode,
-        hash,
-        name: path,
-    })
+    let project_root = work_tree(&gitdir)?;
+    let file_path = project_root.join(&path);
+    let metadata = fs::symlink_metadata(&file_path).map_err(GitError::no_permision)?;
+    let (mode, hash) = if metadata.is_file() && metadata.len() >= STREAMING_THRESHOLD {
+        let file = File::open(&file_path).map_err(GitError::no_permision)?;
+        (worktree_mode(&metadata), hash_and_write_object_streaming::<T>(&gitdir, file, metadata.len())?)
+    }
+    else {
+        let (mode, content) = read_worktree_file(&file_path)?;
+        (mode, write_object::<T>(gitdir, content)?)
+    };
+    Ok(IndexEntry::new(mode, hash, path.as_ref()).with_stat(&project_root.join(&path)))
+}
+
+/// `add_object` for many files at once on `threads` workers, the entries are in the order of `paths`
+pub fn add_objects<T: ObjType>(gitdir: &Path, paths: &[PathBuf], threads: usize) -> Result<Vec<IndexEntry>> {
+    pool::parallel_map(paths, threads, |path| add_object::<T>(gitdir.to_path_buf(), path))
 }
 
 
@@ -154,21 +461,25 @@ pub fn walk<P>(path: P) -> Result<impl IntoIterator<Item = PathBuf>>
 where
     P: AsRef<Path>
 {
-    if path.as_ref().is_dir() {
+    if path.as_ref().is_dir() && !path.as_ref().is_symlink() {
         let pathbufs = path.as_ref()
             .read_dir().map_err(GitError::no_permision)?
             .map(|x| x.map(|x|x.path()).map_err(GitError::no_permision))
             .collect::<Result<Vec<_>>>()?;
 
+        // symlinks are files of their own, a link to a directory is not walked into
+        // a `.git` file is the link of a linked worktree, never part of the project
+        // a directory with its own `.git` is a nested repository (a submodule), not walked into
         let files = pathbufs.iter()
-            .filter(|x|x.is_file())
+            .filter(|x|x.is_file() || x.is_symlink())
+            .filter(|x| x.file_name().is_some_and(|name| name != ".git"))
             .cloned()
             .collect::<Vec<_>>();
 
         let iter_dirs = pathbufs.into_iter()
-            .filter(|x|x.is_dir())
+            .filter(|x|x.is_dir() && !x.is_symlink())
             .filter(|x| {
-                !x.strip_prefix(&path).unwrap().starts_with(".git")
+                !x.strip_prefix(&path).unwrap().starts_with(".git") && !x.join(".git").exists()
             })
             .map(walk)
             .collect::<Result<Vec<_>>>()
@@ -183,6 +494,64 @@ where
     }
 }
 
+/// a path the way git prints it with core.quotePath on: as is when it is plain ascii,
+/// otherwise in double quotes with C escapes and every byte above 0x7f in octal
+pub fn quote_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let bytes = path.as_os_str().as_bytes();
+    if !bytes.iter().any(|&b| !(0x20..0x7f).contains(&b) || b == b'"' || b == b'\\') {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    let mut quoted = String::from("\"");
+    for &b in bytes {
+        match b {
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            0x0b => quoted.push_str("\\v"),
+            0x0c => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b if !(0x20..0x7f).contains(&b) => quoted.push_str(&format!("\\{:03o}", b)),
+            b => quoted.push(b as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// the bytes of a path printed by `quote_path`, None when the quoting is broken
+pub fn unquote_path(quoted: &str) -> Option<Vec<u8>> {
+    let Some(inner) = quoted.strip_prefix('"').and_then(|x| x.strip_suffix('"')) else {
+        return Some(quoted.as_bytes().to_vec());
+    };
+    let mut bytes = inner.bytes();
+    let mut out = Vec::new();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'v' => 0x0b,
+            b'f' => 0x0c,
+            b'r' => b'\r',
+            digit @ b'0'..=b'3' => {
+                let octal = [digit, bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&octal).ok()?, 8).ok()?
+            },
+            other => other,
+        });
+    }
+    Some(out)
+}
+
 /// assert path is child or son of dir and return path's relative path of dir
 pub fn calc_relative_path<P, M>(dir: P, path: M) -> Result<PathBuf>
 where
@@ -190,9 +559,12 @@ where
     M: AsRef<Path>,
 {
     let dir_path = dir.as_ref().to_path_buf();
-    let abs = dir_path
-        .join(path.as_ref())
-        .canonicalize()
+    let joined = dir_path.join(path.as_ref());
+    // a symlink is a file of its own, only the directories leading to it are resolved
+    let abs = match (joined.parent(), joined.file_name()) {
+        (Some(parent), Some(name)) if joined.is_symlink() => parent.canonicalize().map(|parent| parent.join(name)),
+        _ => joined.canonicalize(),
+    }
         .map_err(|x|GitError::not_a_repofile(path.as_ref().to_path_buf().display().to_string() + " " + &x.to_string()))?;
 
     if dir.as_ref() == abs {
@@ -208,6 +580,17 @@ where
     }
 }
 
+/// `path` as seen from the current directory `prefix`, both relative to the project root
+pub fn relative_to(prefix: &Path, path: &Path) -> PathBuf {
+    let common = prefix.components().zip(path.components()).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in prefix.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    relative
+}
+
 /// 简单的对象压缩函数
 pub fn compress_object(data: &[u8]) -> Result<Vec<u8>> {
     use super::zlib::compress;
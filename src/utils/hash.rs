@@ -1,14 +1,15 @@
 use std::{
     fmt,
-    io::Read,
-    fs::File,
-    path::{PathBuf, Path}
+    str::FromStr,
+    sync::OnceLock,
+    path::Path,
 };
-use itertools::Itertools;
 use sha1::{Sha1, Digest};
+use sha2::Sha256;
 
 use crate::{
     utils::{
+        config::Config,
         fs::read_file_as_bytes,
         objtype::ObjType,
     },
@@ -16,28 +17,240 @@ use crate::{
     Result,
 };
 
-fn sha_hash<T>(data: T) -> String
-where T: IntoIterator<Item=u8> + Clone
-{
-    let mut hasher = Sha1::new();
+/// the hash a repository names its objects with, `extensions.objectFormat` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// bytes of a raw hash, as stored in trees, the index and packs
+    pub fn raw_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// characters of a hash written in hex, as in refs and loose object paths
+    pub fn hex_len(&self) -> usize {
+        self.raw_len() * 2
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            other => Err(GitError::invalid_command(format!("unknown hash algorithm '{}'", other))),
+        }
+    }
+}
+
+/// a running hash, fed a piece at a time
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// the algorithm of the repository we run in, set once per process
+static ALGORITHM: OnceLock<HashAlgorithm> = OnceLock::new();
+
+/// read `extensions.objectFormat`, a repository without it is sha1 like in git
+pub fn init_algorithm(config: &Config) -> Result<()> {
+    let algorithm = match config.get("extensions.objectformat") {
+        Some(name) => name.parse()?,
+        None => HashAlgorithm::Sha1,
+    };
+    // only main sets it, once
+    let _ = ALGORITHM.set(algorithm);
+    Ok(())
+}
+
+/// the configured algorithm, sha1 when nothing set it
+pub fn algorithm() -> HashAlgorithm {
+    ALGORITHM.get().copied().unwrap_or(HashAlgorithm::Sha1)
+}
+
+/// the name of an object: a raw hash of the repository's algorithm
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId {
+    // sha1 only uses the first 20 bytes, the rest stays zero
+    bytes: [u8; 32],
+    len: u8,
+}
+
+impl ObjectId {
+    /// `raw` must be exactly as long as a hash of the configured algorithm
+    pub fn from_raw(raw: &[u8]) -> Result<Self> {
+        if raw.len() != algorithm().raw_len() {
+            return Err(GitError::invalid_hash(&hex::encode(raw)));
+        }
+        let mut bytes = [0u8; 32];
+        bytes[..raw.len()].copy_from_slice(raw);
+        Ok(ObjectId { bytes, len: raw.len() as u8 })
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        if !is_hash(hex) {
+            return Err(GitError::invalid_hash(hex));
+        }
+        Self::from_raw(&hex::decode(hex)?)
+    }
+
+    /// all zeros, what git writes for "no object" in reflogs and diffs
+    pub fn null() -> Self {
+        ObjectId { bytes: [0; 32], len: algorithm().raw_len() as u8 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.as_bytes())
+    }
+}
 
-    data.into_iter().chunks(1024)
-        .into_iter()
-        .map(|c| c.collect::<Vec<_>>())
-        .for_each(|c| hasher.update(c));
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({})", self.to_hex())
+    }
+}
+
+impl PartialEq<str> for ObjectId {
+    fn eq(&self, hex: &str) -> bool {
+        self.to_hex().eq_ignore_ascii_case(hex)
+    }
+}
+
+impl PartialEq<&str> for ObjectId {
+    fn eq(&self, hex: &&str) -> bool {
+        *self == **hex
+    }
+}
+
+impl PartialEq<String> for ObjectId {
+    fn eq(&self, hex: &String) -> bool {
+        *self == **hex
+    }
+}
+
+/// a hash as hex text or an `ObjectId`, what the object store and the history walk take;
+/// revisions and object ids written by commands still come as text
+pub trait ToHex {
+    fn to_hex(&self) -> String;
+}
+
+impl ToHex for ObjectId {
+    fn to_hex(&self) -> String {
+        ObjectId::to_hex(*self)
+    }
+}
+
+impl ToHex for str {
+    fn to_hex(&self) -> String {
+        self.to_string()
+    }
+}
 
-    format!("{:x}", hasher.finalize())
+impl ToHex for String {
+    fn to_hex(&self) -> String {
+        self.clone()
+    }
+}
+
+impl<T: ToHex + ?Sized> ToHex for &T {
+    fn to_hex(&self) -> String {
+        (**self).to_hex()
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(hex: &str) -> Result<Self> {
+        Self::from_hex(hex)
+    }
+}
+
+/// a full hash of the configured algorithm in hex
+pub fn is_hash(s: &str) -> bool {
+    s.len() == algorithm().hex_len() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// the null object id in hex
+pub fn null_hash() -> String {
+    ObjectId::null().to_hex()
+}
+
+fn sha_hash(data: &[u8]) -> String {
+    hex::encode(algorithm().digest(data))
 }
 
 fn hash_file<T>(path: &T) -> Result<String>
 where T: AsRef<Path>
 {
     read_file_as_bytes(path)
-        .map(sha_hash)
+        .map(|bytes| sha_hash(&bytes))
+}
+
+pub fn hash_object<T: ObjType>(bytes: Vec<u8>) -> Result<ObjectId>
+{
+    hash_raw_object(T::VALUE, bytes)
 }
 
-pub fn hash_object<T: ObjType>(bytes: Vec<u8>) -> Result<String>
+/// hash `bytes` as an object of type `kind` ("blob", "tree", "commit", "tag")
+pub fn hash_raw_object(kind: &str, bytes: Vec<u8>) -> Result<ObjectId>
 {
-    let meta = format!("{} {}\0", T::VALUE, bytes.len()).into_bytes().into_iter();
-    Ok(sha_hash(meta.chain(bytes)))
+    let mut hasher = algorithm().hasher();
+    hasher.update(format!("{} {}\0", kind, bytes.len()).as_bytes());
+    hasher.update(&bytes);
+    ObjectId::from_raw(&hasher.finalize())
 }
@@ -0,0 +1,190 @@
+use std::{env, time::Duration};
+use reqwest::{
+    Certificate,
+    Proxy,
+    blocking::Client,
+};
+use url::Url;
+
+use crate::{GitError, Result};
+use super::config::Config;
+
+/// 按 git 的配置建 http 客户端：代理、额外的 CA 证书、是否校验证书
+/// reqwest 自己不会去读 git 的配置，代理也由这里决定，不用它读环境变量那一套
+pub fn http_client(config: &Config, url: &str) -> Result<Client> {
+    http_client_with_env(config, url, |name| env::var(name).ok())
+}
+
+fn http_client_with_env(config: &Config, url: &str, env: impl Fn(&str) -> Option<String>) -> Result<Client> {
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(30))
+        .user_agent("git/2.0.0 (custom)")
+        .no_proxy();
+
+    if let Some(proxy) = select_proxy(config, url, &env) {
+        let proxy = if proxy.contains("://") { proxy } else { format!("http://{}", proxy) };
+        builder = builder.proxy(Proxy::all(&proxy)
+            .map_err(|e| GitError::invalid_command(format!("invalid proxy '{}': {}", proxy, e)))?);
+    }
+
+    if let Some(ca_info) = config.get("http.sslCAInfo").map(str::to_string).or_else(|| env("GIT_SSL_CAINFO")) {
+        let pem = std::fs::read(&ca_info)
+            .map_err(|_| GitError::failed_to_read_file(&ca_info))?;
+        let certs = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| GitError::invalid_command(format!("invalid CA bundle '{}': {}", ca_info, e)))?;
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    let no_verify = config.get_bool("http.sslVerify") == Some(false)
+        || env("GIT_SSL_NO_VERIFY").is_some_and(|value| !value.is_empty());
+    if no_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build()
+        .map_err(|e| GitError::network_failure(format!("Failed to create HTTP client: {}", e), e))
+}
+
+/// 配置优先：https 的地址先看 https.proxy，再看 http.proxy，都没有才看 HTTPS_PROXY / HTTP_PROXY
+/// 配成空字符串表示不用代理，NO_PROXY 里的主机总是直连
+fn select_proxy(config: &Config, url: &str, env: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let env = |name: &str| env(&name.to_lowercase()).or_else(|| env(name));
+
+    let https = url.scheme() == "https";
+    let configured = https.then(|| config.get("https.proxy")).flatten()
+        .or_else(|| config.get("http.proxy"))
+        .map(str::to_string);
+    let proxy = configured.or_else(|| if https { env("HTTPS_PROXY") } else { env("HTTP_PROXY") })
+        .filter(|proxy| !proxy.is_empty())?;
+
+    let bypass = env("NO_PROXY").is_some_and(|no_proxy| no_proxy.split(',')
+        .map(|entry| entry.trim().trim_start_matches('.'))
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))));
+    (!bypass).then_some(proxy)
+}
+
+/// reqwest 的错误只说 "error sending request"，真正的原因（拒绝连接、DNS、证书）在 source 链里
+pub fn describe_error(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message = format!("{}: {}", message, cause_message);
+        }
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use crate::utils::config::Config;
+    use crate::utils::test::{shell_spawn, tempdir};
+    use super::{http_client_with_env, select_proxy};
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars = vars.iter().cloned().collect::<HashMap<_, _>>();
+        move |name| vars.get(name).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_select_proxy() {
+        let none = Config::default();
+        let config = Config::parse("[http]\n\tproxy = http://config:3128\n[https]\n\tproxy = secure:3129\n").unwrap();
+        let http_only = Config::parse("[http]\n\tproxy = config:3128\n").unwrap();
+        let disabled = Config::parse("[http]\n\tproxy =\n").unwrap();
+        let vars = [("HTTP_PROXY", "http://env:80"), ("https_proxy", "http://env:443"), ("NO_PROXY", "internal.corp, .local")];
+
+        assert_eq!(select_proxy(&none, "https://example.com/a.git", env(&[])), None);
+        assert_eq!(select_proxy(&none, "http://example.com/a.git", env(&vars)).as_deref(), Some("http://env:80"));
+        assert_eq!(select_proxy(&none, "https://example.com/a.git", env(&vars)).as_deref(), Some("http://env:443"));
+        // config wins over the environment
+        assert_eq!(select_proxy(&config, "https://example.com/a.git", env(&vars)).as_deref(), Some("secure:3129"));
+        assert_eq!(select_proxy(&config, "http://example.com/a.git", env(&vars)).as_deref(), Some("http://config:3128"));
+        assert_eq!(select_proxy(&http_only, "https://example.com/a.git", env(&vars)).as_deref(), Some("config:3128"));
+        assert_eq!(select_proxy(&disabled, "https://example.com/a.git", env(&vars)), None);
+        // NO_PROXY matches the host and its subdomains
+        assert_eq!(select_proxy(&config, "https://git.internal.corp/a.git", env(&vars)), None);
+        assert_eq!(select_proxy(&config, "https://box.local/a.git", env(&vars)), None);
+        assert!(select_proxy(&config, "https://notinternal.corp/a.git", env(&vars)).is_some());
+        assert_eq!(select_proxy(&config, "https://example.com/a.git", env(&[("no_proxy", "*")])), None);
+    }
+
+    /// records the request line of everything it gets and answers with an empty body
+    fn serve_recording() -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                sender.send(request_line.trim().to_string()).unwrap();
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            }
+        });
+        (port, receiver)
+    }
+
+    #[test]
+    fn test_client_uses_proxy() {
+        let (proxy_port, proxied) = serve_recording();
+        let (server_port, direct) = serve_recording();
+        let config = Config::parse(&format!("[http]\n\tproxy = 127.0.0.1:{}\n", proxy_port)).unwrap();
+
+        // the host doesn't even resolve, only the proxy can answer
+        let client = http_client_with_env(&config, "http://git.invalid/repo.git", env(&[])).unwrap();
+        client.get("http://git.invalid/repo.git/info/refs").send().unwrap();
+        assert_eq!(proxied.try_recv().unwrap(), "GET http://git.invalid/repo.git/info/refs HTTP/1.1");
+
+        // NO_PROXY goes straight to the server
+        let url = format!("http://127.0.0.1:{}/repo.git", server_port);
+        let client = http_client_with_env(&config, &url, env(&[("NO_PROXY", "127.0.0.1")])).unwrap();
+        client.get(format!("{}/info/refs", url)).send().unwrap();
+        assert_eq!(direct.try_recv().unwrap(), "GET /repo.git/info/refs HTTP/1.1");
+        assert!(proxied.try_recv().is_err());
+
+        // the environment proxy of the test process is never picked up behind our back
+        let client = http_client_with_env(&Config::default(), &url, env(&[])).unwrap();
+        client.get(format!("{}/info/refs", url)).send().unwrap();
+        assert!(direct.try_recv().is_ok());
+        assert!(proxied.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_ca_info() {
+        let config = Config::parse("[http]\n\tsslCAInfo = /nonexistent/ca.pem\n").unwrap();
+        let err = http_client_with_env(&config, "https://example.com/a.git", env(&[])).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/ca.pem"));
+
+        // a self-signed certificate is loaded as an extra root, from the config or GIT_SSL_CAINFO
+        let temp = tempdir().unwrap();
+        let ca = temp.path().join("ca.pem");
+        shell_spawn(&[
+            "openssl", "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=test-ca",
+            "-keyout", temp.path().join("ca.key").to_str().unwrap(), "-out", ca.to_str().unwrap(),
+        ]).unwrap();
+        let config = Config::parse(&format!("[http]\n\tsslCAInfo = {}\n", ca.display())).unwrap();
+        assert!(http_client_with_env(&config, "https://example.com/a.git", env(&[])).is_ok());
+        let ca = ca.to_str().unwrap();
+        assert!(http_client_with_env(&Config::default(), "https://example.com/a.git", env(&[("GIT_SSL_CAINFO", ca)])).is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    env,
+    path::Path,
+};
+use chrono::{DateTime, FixedOffset, Local};
+
+use crate::{
+    utils::config::Config,
+    GitError,
+    Result,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+impl Role {
+    fn env_prefix(&self) -> &'static str {
+        match self {
+            Role::Author => "GIT_AUTHOR",
+            Role::Committer => "GIT_COMMITTER",
+        }
+    }
+}
+
+/// current time formatted the way git stores it: `1748165415 +0800`
+pub fn git_timestamp() -> String {
+    let now = Local::now();
+    format!("{} {}", now.timestamp(), now.format("%z"))
+}
+
+/// GIT_{AUTHOR,COMMITTER}_DATE in git's internal `1748165415 +0800` form, `@` in front is allowed
+/// other date formats are not understood and fall back to now
+fn env_timestamp(role: Role) -> Option<String> {
+    let date = env::var(format!("{}_DATE", role.env_prefix())).ok()?;
+    let (time, tz) = date.trim().trim_start_matches('@').split_once(' ')?;
+    let valid_tz = tz.len() == 5 && tz.starts_with(['+', '-']) && tz[1..].bytes().all(|b| b.is_ascii_digit());
+    (time.parse::<i64>().is_ok() && valid_tz).then(|| format!("{} {}", time, tz))
+}
+
+/// `Name <email> 1748165415 +0800`, as used in the author/committer lines
+/// GIT_{AUTHOR,COMMITTER}_{NAME,EMAIL} take precedence over user.name / user.email like in git
+pub fn ident(gitdir: &Path, role: Role) -> Result<String> {
+    let config = Config::read_all(gitdir)?;
+    let lookup = |field: &str| {
+        env::var(format!("{}_{}", role.env_prefix(), field.to_uppercase()))
+            .ok()
+            .filter(|x| !x.is_empty())
+            .or_else(|| config.get(&format!("user.{}", field)).map(String::from))
+    };
+
+    let name = lookup("name");
+    let email = lookup("email");
+    match (name, email) {
+        (Some(name), Some(email)) => Ok(format!("{} <{}> {}", name, email, env_timestamp(role).unwrap_or_else(git_timestamp))),
+        _ => Err(GitError::no_identity(
+            "please set user.name and user.email with `git config`, \
+            or export GIT_AUTHOR_NAME/GIT_AUTHOR_EMAIL"
+        )),
+    }
+}
+
+pub fn author(gitdir: &Path) -> Result<String> {
+    ident(gitdir, Role::Author)
+}
+
+pub fn committer(gitdir: &Path) -> Result<String> {
+    ident(gitdir, Role::Committer)
+}
+
+/// split `Name <email> 1748165415 +0800` into `Name <email>`, timestamp and timezone
+pub fn split_ident(ident: &str) -> Option<(&str, i64, &str)> {
+    let (rest, tz) = ident.trim_end().rsplit_once(' ')?;
+    let (who, ts) = rest.rsplit_once(' ')?;
+    Some((who, ts.parse().ok()?, tz))
+}
+
+/// git's default date format: `Thu May 22 17:30:15 2025 +0800`
+pub fn format_date(timestamp: i64, tz: &str) -> String {
+    format_with(timestamp, tz, "%a %b %-d %H:%M:%S %Y ")
+}
+
+/// the iso-like format blame uses: `2025-05-22 17:30:15 +0800`
+pub fn format_iso_date(timestamp: i64, tz: &str) -> String {
+    format_with(timestamp, tz, "%Y-%m-%d %H:%M:%S ")
+}
+
+/// the time as seen in `tz` itself, not in the local timezone
+fn format_with(timestamp: i64, tz: &str, format: &str) -> String {
+    let sign = if tz.starts_with('-') { -1 } else { 1 };
+    let digits = tz.trim_start_matches(['+', '-']);
+    let offset = match (digits.get(..2).and_then(|h| h.parse::<i32>().ok()), digits.get(2..4).and_then(|m| m.parse::<i32>().ok())) {
+        (Some(h), Some(m)) => sign * (h * 3600 + m * 60),
+        _ => 0,
+    };
+    let offset = FixedOffset::east_opt(offset).unwrap_or(FixedOffset::east_opt(0).unwrap());
+    match DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time.with_timezone(&offset).format(format).to_string() + tz,
+        None => format!("{} {}", timestamp, tz),
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::Result;
+
+/// one line of a .gitignore
+#[derive(Debug, Clone)]
+struct Rule {
+    /// directory holding the .gitignore, relative to the project root
+    base: PathBuf,
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+    /// patterns containing a `/` match the whole path relative to `base`,
+    /// the others only match the file name
+    anchored: bool,
+}
+
+/// all .gitignore rules of a work tree
+/// rules are kept in precedence order: later rules override earlier ones,
+/// and .gitignore files in sub directories come after their parents
+#[derive(Debug, Default, Clone)]
+pub struct Ignore {
+    rules: Vec<Rule>,
+}
+
+impl Ignore {
+    /// read .git/info/exclude and every .gitignore below `project_root`
+    pub fn load(project_root: &Path) -> Result<Self> {
+        let mut ignore = Self::default();
+        let exclude = project_root.join(".git/info/exclude");
+        if exclude.is_file() {
+            ignore.add_patterns(Path::new(""), &fs::read_to_string(exclude)?);
+        }
+
+        // breadth first, so that deeper files get the higher precedence
+        let mut dirs = vec![PathBuf::new()];
+        while !dirs.is_empty() {
+            let mut next = Vec::new();
+            for dir in dirs {
+                let gitignore = project_root.join(&dir).join(".gitignore");
+                if gitignore.is_file() {
+                    ignore.add_patterns(&dir, &fs::read_to_string(gitignore)?);
+                }
+                for entry in fs::read_dir(project_root.join(&dir))? {
+                    let entry = entry?;
+                    let rel = dir.join(entry.file_name());
+                    if entry.file_type()?.is_dir() && rel != Path::new(".git") && !ignore.is_ignored(&rel, true) {
+                        next.push(rel);
+                    }
+                }
+            }
+            dirs = next;
+        }
+        Ok(ignore)
+    }
+
+    /// add the content of a .gitignore living in `base` (relative to the project root)
+    pub fn add_patterns(&mut self, base: &Path, content: &str) {
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (negate, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            let pattern = line.trim_start_matches('/').to_string();
+            if pattern.is_empty() {
+                continue;
+            }
+
+            self.rules.push(Rule {
+                base: base.to_path_buf(),
+                pattern,
+                negate,
+                dir_only,
+                anchored,
+            });
+        }
+    }
+
+    /// `path` is relative to the project root
+    /// a path inside an ignored directory is ignored as well, like in git it can't be re-included
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ancestors = path.ancestors()
+            .skip(1)
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect::<Vec<_>>();
+        ancestors.reverse();
+        if ancestors.into_iter().any(|dir| self.matches(dir, true)) {
+            return true;
+        }
+        self.matches(path, is_dir)
+    }
+
+    /// result of the last rule matching `path`, parents are not considered
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        self.rules.iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| !rule.negate)
+            .unwrap_or(false)
+    }
+}
+
+impl Rule {
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rel = match path.strip_prefix(&self.base) {
+            Ok(rel) => rel,
+            Err(_) => return false,
+        };
+        let rel = rel.to_string_lossy();
+        if self.anchored {
+            glob_match(self.pattern.as_bytes(), rel.as_bytes())
+        }
+        else {
+            let name = rel.rsplit('/').next().unwrap_or(&rel);
+            glob_match(self.pattern.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+/// `*` and `?` never cross a `/`, `**` does
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            // `**/` matches zero or more leading directories
+            glob_match(rest, text) || text.iter()
+                .enumerate()
+                .filter(|(_, c)| **c == b'/')
+                .any(|(i, _)| glob_match(rest, &text[i + 1..]))
+        },
+        [b'*', b'*', rest @ ..] => {
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        },
+        [b'*', rest @ ..] => {
+            (0..=text.len())
+                .take_while(|i| *i == 0 || text[i - 1] != b'/')
+                .any(|i| glob_match(rest, &text[i..]))
+        },
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => glob_match(rest, text),
+            _ => false,
+        },
+        [b'[', rest @ ..] => {
+            let close = match rest.iter().skip(1).position(|c| *c == b']') {
+                Some(i) => i + 1,
+                None => return text.first() == Some(&b'[') && glob_match(rest, &text[1..]),
+            };
+            let (class, rest) = (&rest[..close], &rest[close + 1..]);
+            let (negate, class) = match class {
+                [b'!' | b'^', class @ ..] => (true, class),
+                _ => (false, class),
+            };
+            match text {
+                [c, text @ ..] if *c != b'/' => {
+                    let mut found = false;
+                    let mut i = 0;
+                    while i < class.len() {
+                        if i + 2 < class.len() && class[i + 1] == b'-' {
+                            found |= class[i] <= *c && *c <= class[i + 2];
+                            i += 3;
+                        }
+                        else {
+                            found |= class[i] == *c;
+                            i += 1;
+                        }
+                    }
+                    found != negate && glob_match(rest, text)
+                },
+                _ => false,
+            }
+        },
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => match text {
+            [t, text @ ..] if t == c => glob_match(rest, text),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ignore(content: &str) -> Ignore {
+        let mut ignore = Ignore::default();
+        ignore.add_patterns(Path::new(""), content);
+        ignore
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_match(b"*.o", b"main.o"));
+        assert!(!glob_match(b"*.o", b"src/main.o"));
+        assert!(glob_match(b"src/*.rs", b"src/main.rs"));
+        assert!(glob_match(b"**/foo", b"a/b/foo"));
+        assert!(glob_match(b"**/foo", b"foo"));
+        assert!(glob_match(b"a/**/b", b"a/x/y/b"));
+        assert!(glob_match(b"a/**", b"a/x/y"));
+        assert!(glob_match(b"fo?.[a-c]", b"foo.b"));
+        assert!(!glob_match(b"fo?.[!a-c]", b"foo.b"));
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines() {
+        let ignore = ignore("# comment\n\n\\#hash\n");
+        assert!(!ignore.is_ignored(Path::new("# comment"), false));
+        assert!(ignore.is_ignored(Path::new("#hash"), false));
+    }
+
+    #[test]
+    fn test_directory_patterns() {
+        let ignore = ignore("target/\n/build\n");
+        assert!(ignore.is_ignored(Path::new("target"), true));
+        assert!(ignore.is_ignored(Path::new("target/debug/git"), false));
+        assert!(ignore.is_ignored(Path::new("sub/target/x"), false));
+        // dir only pattern doesn't match a file
+        assert!(!ignore.is_ignored(Path::new("target"), false));
+        // anchored to the root
+        assert!(ignore.is_ignored(Path::new("build/a"), false));
+        assert!(!ignore.is_ignored(Path::new("sub/build/a"), false));
+    }
+
+    #[test]
+    fn test_negation_precedence() {
+        let rules = ignore("*.log\n!keep.log\n");
+        assert!(rules.is_ignored(Path::new("a.log"), false));
+        assert!(!rules.is_ignored(Path::new("keep.log"), false));
+
+        // the last matching pattern wins
+        let rules = ignore("!keep.log\n*.log\n");
+        assert!(rules.is_ignored(Path::new("keep.log"), false));
+
+        // files inside an excluded directory can't be re-included
+        let rules = ignore("logs/\n!logs/keep.log\n");
+        assert!(rules.is_ignored(Path::new("logs/keep.log"), false));
+    }
+
+    #[test]
+    fn test_nested_gitignore() {
+        let mut ignore = ignore("*.tmp\n");
+        ignore.add_patterns(Path::new("sub"), "!a.tmp\n/only_here\n");
+        assert!(ignore.is_ignored(Path::new("a.tmp"), false));
+        assert!(!ignore.is_ignored(Path::new("sub/a.tmp"), false));
+        assert!(ignore.is_ignored(Path::new("sub/b.tmp"), false));
+        assert!(ignore.is_ignored(Path::new("sub/only_here"), false));
+        assert!(!ignore.is_ignored(Path::new("only_here"), false));
+    }
+}
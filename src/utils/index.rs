@@ -1,34 +1,115 @@
 use std::path::{PathBuf,Path};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::fs::{File, OpenOptions};
 use std::io::{Write, BufWriter, Read, BufReader, BufRead};
 use byteorder::{ReadBytesExt, BigEndian};
-use sha1::{Sha1, Digest};
 use nom::{
     bytes::complete::{tag, take, take_until},
+    combinator::map_res,
     number::complete::be_u32,
     IResult,
+    Parser,
 };
 use std::iter::repeat_n;
 use crate::{
+    utils::hash::{algorithm, ObjectId},
     GitError,
     Result,
 };
 
+/// stat data cached in the index, lets us tell unchanged files apart without hashing them
+/// all zero means unknown (e.g. entries coming from read-tree)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileStat {
+    pub ctime: u32,
+    pub ctime_nsec: u32,
+    pub mtime: u32,
+    pub mtime_nsec: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+}
+
+impl FileStat {
+    pub fn from_path(path: &Path) -> std::io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let meta = std::fs::symlink_metadata(path)?;
+        // the index only keeps the lower 32 bits, same as git
+        Ok(FileStat {
+            ctime: meta.ctime() as u32,
+            ctime_nsec: meta.ctime_nsec() as u32,
+            mtime: meta.mtime() as u32,
+            mtime_nsec: meta.mtime_nsec() as u32,
+            dev: meta.dev() as u32,
+            ino: meta.ino() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size() as u32,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IndexEntry {
     pub mode: u32,
-    pub hash: String,
-    pub name: String,
+    pub hash: ObjectId,
+    /// git path names are raw bytes, a PathBuf keeps them as they are on unix
+    pub name: PathBuf,
+    pub stat: FileStat,
+    /// 0 for a normal entry, 1/2/3 for base/ours/theirs of an unmerged path
+    pub stage: u8,
+    /// the file was modified no earlier than the index file was written, a change in that same
+    /// second leaves the stat data as it was: git's "racy git", only the content can tell
+    racy: bool,
 }
 
 impl IndexEntry {
 
-    pub fn new(mode: u32, hash: String, name: String) -> Self {
+    pub fn new(mode: u32, hash: ObjectId, name: impl Into<PathBuf>) -> Self {
         match mode {
-            0o100644 | 0o100755 | 0o120000 | 0o040000 => (),
+            0o100644 | 0o100755 | 0o120000 | 0o160000 | 0o040000 => (),
             _ => panic!("Invalid file mode: {:o}", mode),
         }
-        IndexEntry { mode, hash, name }
+        IndexEntry { mode, hash, name: name.into(), stat: FileStat::default(), stage: 0, racy: false }
+    }
+
+    /// the name as git stores and sorts it
+    pub fn name_bytes(&self) -> &[u8] {
+        self.name.as_os_str().as_bytes()
+    }
+
+    pub fn with_stage(mut self, stage: u8) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    /// fill in the stat data of the file at `path`, a missing file keeps the entry unchanged
+    pub fn with_stat(mut self, path: &Path) -> Self {
+        if let Ok(stat) = FileStat::from_path(path) {
+            self.stat = stat;
+        }
+        self
+    }
+
+    /// fast check: same size, mtime and mode as recorded means the file is unchanged
+    /// a chmod leaves mtime alone, so the mode is compared as well
+    /// returns false when unsure, the caller should fall back to hashing then
+    pub fn is_stat_clean(&self, path: &Path) -> bool {
+        if self.racy || self.stat == FileStat::default() {
+            return false;
+        }
+        if !std::fs::symlink_metadata(path).is_ok_and(|meta| crate::utils::fs::worktree_mode(&meta) == self.mode) {
+            return false;
+        }
+        match FileStat::from_path(path) {
+            Ok(stat) => stat.size == self.stat.size
+                && stat.mtime == self.stat.mtime
+                && stat.mtime_nsec == self.stat.mtime_nsec,
+            Err(_) => false,
+        }
     }
 
 }
@@ -49,25 +130,45 @@ impl Index {
     }
 
     pub fn add_entry(&mut self, new_entry: IndexEntry) {
+        // 条目一直按路径名、同名按 stage 排好序（Git要求的顺序），二分找到这个路径的位置插进去，不用整体重排
+        let start = self.entries.partition_point(|entry| entry.name_bytes() < new_entry.name_bytes());
+        let end = start + self.entries[start..].iter().take_while(|entry| entry.name == new_entry.name).count();
+
         // 移除已存在的同名条目
-        self.entries.retain(|entry| entry.name != new_entry.name);
-        
-        // 添加新条目
-        self.entries.push(new_entry);
-        
-        // 按路径名排序（Git要求index条目按路径排序）
-        self.entries.sort_by(|a, b| a.name.cmp(&b.name));
-    }
-
-    pub fn write_to_file(&self, path: &Path) -> std::io::Result<()> {
-        use sha1::{Sha1, Digest};
-        use std::io::Seek;
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
-        let mut writer = BufWriter::new(file);
+        // stage 0 replaces every stage of the path, a conflict stage only replaces stage 0 and itself
+        let mut stages = self.entries.drain(start..end)
+            .filter(|entry| new_entry.stage != 0 && entry.stage != 0 && entry.stage != new_entry.stage)
+            .collect::<Vec<_>>();
+        let at = stages.partition_point(|entry| entry.stage < new_entry.stage);
+        stages.insert(at, new_entry);
+        self.entries.splice(start..start, stages);
+    }
+
+    /// stage 0 entries for many paths at once, like `add_entry` each replaces every stage of its path
+    pub fn add_entries(&mut self, new_entries: Vec<IndexEntry>) {
+        let names = new_entries.iter().map(|entry| entry.name.clone()).collect::<std::collections::HashSet<_>>();
+        self.entries.retain(|entry| !names.contains(&entry.name));
+        self.entries.extend(new_entries);
+        self.entries.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()).then(a.stage.cmp(&b.stage)));
+    }
+
+    /// paths which still have stage 1/2/3 entries
+    pub fn unmerged_paths(&self) -> Vec<&Path> {
+        let mut paths = self.entries.iter()
+            .filter(|entry| entry.stage != 0)
+            .map(|entry| entry.name.as_path())
+            .collect::<Vec<_>>();
+        paths.dedup();
+        paths
+    }
+
+    /// write the index through `<path>.lock`, see `LockedIndexWriter`
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        LockedIndexWriter::lock(path)?.commit(self)
+    }
+
+    /// the index file bytes, checksum included
+    fn to_bytes(&self) -> Result<Vec<u8>> {
         let mut buffer = Vec::new();
 
         // writer.write_all(b"DIRC")?;
@@ -92,42 +193,37 @@ impl Index {
 
         for entry in &self.entries {
             // println!("write {} to file {}", entry.name, path.display());
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // ctime
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // ctime_nsec
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // mtime
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // mtime_nsec
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // dev
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // ino
+            let stat = &entry.stat;
+            buffer.extend_from_slice(&stat.ctime.to_be_bytes());
+            buffer.extend_from_slice(&stat.ctime_nsec.to_be_bytes());
+            buffer.extend_from_slice(&stat.mtime.to_be_bytes());
+            buffer.extend_from_slice(&stat.mtime_nsec.to_be_bytes());
+            buffer.extend_from_slice(&stat.dev.to_be_bytes());
+            buffer.extend_from_slice(&stat.ino.to_be_bytes());
             buffer.extend_from_slice(&entry.mode.to_be_bytes());
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // uid
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // gid
-            buffer.extend_from_slice(&0u32.to_be_bytes()); // file size
+            buffer.extend_from_slice(&stat.uid.to_be_bytes());
+            buffer.extend_from_slice(&stat.gid.to_be_bytes());
+            buffer.extend_from_slice(&stat.size.to_be_bytes());
 
-            let hash_bytes = hex::decode(&entry.hash).map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid hash format")
-            })?;
-            buffer.extend_from_slice(&hash_bytes);
-            let name_bytes = entry.name.as_bytes();
+            buffer.extend_from_slice(entry.hash.as_bytes());
+            let name_bytes = entry.name_bytes();
             let name_len = name_bytes.len();
-            let stage: u16 = 0;
+            let stage = entry.stage as u16;
             let flags: u16 = ((stage & 0x3) << 12) | ((name_len as u16) & 0x0FFF);
             buffer.extend_from_slice(&flags.to_be_bytes());
-            buffer.extend_from_slice(entry.name.as_bytes());
+            buffer.extend_from_slice(entry.name_bytes());
             buffer.push(0);
 
         // 计算对齐
-        let entry_len = 63 + entry.name.len(); // 62字节固定+name
+        // 40 字节 stat + hash + 2 字节 flags + name + 结尾的 0
+        let entry_len = 43 + algorithm().raw_len() + name_len;
         let pad = (8 - (entry_len % 8)) % 8;
         buffer.extend(std::iter::repeat_n(0, pad));
     }
-    let mut hasher = Sha1::new();
-    hasher.update(&buffer);
-    let checksum = hasher.finalize();
+    let checksum = algorithm().digest(&buffer);
     buffer.extend_from_slice(&checksum);
 
-        writer.write_all(&buffer)?;
-        writer.flush()?;
-        Ok(())
+        Ok(buffer)
     }
 
     // pub fn read_from_file(&self, path: &Path) -> std::io::Result<Self> {
@@ -179,6 +275,7 @@ impl Index {
     //     }
     //     Ok(index)
     // }
+    /// the entries of an index whose checksum was already verified, extensions after them are skipped
     fn parse_index(input: &[u8]) -> IResult<&[u8], Index> {
         let (input, _) = tag("DIRC")(input)?;
         let (input, _version) = be_u32(input)?;
@@ -191,57 +288,402 @@ impl Index {
             entries.push(entry);
             input = rest;
         }
-        // 跳过校验和
-        let (_input, _checksum) = take(20usize)(input)?;
-        Ok((_input, Index { entries }))
+        Ok((input, Index { entries }))
     }
 
     fn parse_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
-        let (input, _ctime) = take(4usize)(input)?;
-        let (input, _ctime_nsec) = take(4usize)(input)?;
-        let (input, _mtime) = take(4usize)(input)?;
-        let (input, _mtime_nsec) = take(4usize)(input)?;
-        let (input, _dev) = take(4usize)(input)?;
-        let (input, _ino) = take(4usize)(input)?;
-        let (input, mode_bytes) = take(4usize)(input)?;
-        let mode = u32::from_be_bytes(mode_bytes.try_into().unwrap());
-        let (input, _uid) = take(4usize)(input)?;
-        let (input, _gid) = take(4usize)(input)?;
-        let (input, _size) = take(4usize)(input)?;
-        let (input, hash) = take(20usize)(input)?;
-        let (input, _flags) = take(2usize)(input)?;
+        let (input, ctime) = be_u32(input)?;
+        let (input, ctime_nsec) = be_u32(input)?;
+        let (input, mtime) = be_u32(input)?;
+        let (input, mtime_nsec) = be_u32(input)?;
+        let (input, dev) = be_u32(input)?;
+        let (input, ino) = be_u32(input)?;
+        let (input, mode) = be_u32(input)?;
+        let (input, uid) = be_u32(input)?;
+        let (input, gid) = be_u32(input)?;
+        let (input, size) = be_u32(input)?;
+        let (input, hash) = map_res(take(algorithm().raw_len()), ObjectId::from_raw).parse(input)?;
+        let (input, flags) = take(2usize)(input)?;
+        let stage = (flags[0] >> 4) & 0x3;
 
         // 文件名直到0字节
-        let nul_pos = input.iter().position(|&b| b == 0).unwrap();
-        let name = &input[..nul_pos];
-        let input = &input[nul_pos + 1..];
+        let (input, name) = take_until(&b"\0"[..])(input)?;
+        let (input, _) = take(1usize)(input)?;
 
         // 对齐到8字节
-        let entry_len = 63 + name.len();
+        let entry_len = 43 + algorithm().raw_len() + name.len();
         let pad = (8 - (entry_len % 8)) % 8;
-        let input = &input[pad..];
+        let (input, _) = take(pad)(input)?;
 
-        Ok((input, IndexEntry::new(
+        let mut entry = IndexEntry::new(
                     mode,
-                    hex::encode(hash),
-                    String::from_utf8(name.to_vec()).unwrap(),
-        )))
+                    hash,
+                    PathBuf::from(OsStr::from_bytes(name)),
+        );
+        entry.stat = FileStat { ctime, ctime_nsec, mtime, mtime_nsec, dev, ino, uid, gid, size };
+        entry.stage = stage;
+        Ok((input, entry))
     }
 
 
+    /// read and check an index file, a bad checksum or a truncated file is an error, never a panic
     pub fn read_from_file(&self, path: &Path) -> Result<Self> {
         let bytes = std::fs::read(path)?;
-        let (_, index) = Self::parse_index(&bytes).map_err(|_| {
-            GitError::InvalidCommand(path.to_str().unwrap().to_string())
-        })?;
+        let hash_len = algorithm().raw_len();
+        if bytes.len() < 12 + hash_len {
+            return Err(GitError::corrupt_index("index file smaller than expected"));
+        }
+        if &bytes[..4] != b"DIRC" {
+            return Err(GitError::corrupt_index("bad signature"));
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - hash_len);
+        if algorithm().digest(content) != checksum {
+            return Err(GitError::corrupt_index(&format!("bad index file {} signature", algorithm().name())));
+        }
+        let (_, mut index) = Self::parse_index(content)
+            .map_err(|_| GitError::corrupt_index(&format!("entries of {} are truncated", path.display())))?;
+        // like git, whole seconds: a file touched in the second the index was written may have changed after it
+        let written = FileStat::from_path(path)?.mtime;
+        for entry in index.entries.iter_mut() {
+            entry.racy = entry.stat.mtime >= written;
+        }
         Ok(index)
     }
 
+    /// the index of `gitdir`, an empty one before anything was ever added
+    pub fn read_or_default(gitdir: &Path) -> Result<Self> {
+        let index_path = gitdir.join("index");
+        match index_path.exists() {
+            true => Self::new().read_from_file(&index_path),
+            false => Ok(Self::new()),
+        }
+    }
 
+    /// record the current stat data of every entry's file,
+    /// only call it right after the worktree was written from the index
+    pub fn refresh_stat(&mut self, project_root: &Path) {
+        for entry in self.entries.iter_mut() {
+            if let Ok(stat) = FileStat::from_path(&project_root.join(&entry.name)) {
+                entry.stat = stat;
+            }
+        }
+    }
 
-    pub fn remove_entry(&mut self, name: &str) -> bool {
+    pub fn remove_entry(&mut self, name: impl AsRef<Path>) -> bool {
         let original_len = self.entries.len();
-        self.entries.retain(|entry| entry.name != name);
+        self.entries.retain(|entry| entry.name != name.as_ref());
         original_len != self.entries.len()
     }
 }
+
+/// git's lock protocol for the index: `index.lock` is created exclusively, so only one process
+/// can hold it, the new index is written and fsynced there and then renamed over `index`
+/// dropping the writer without `commit` removes the lock and leaves the index as it was
+pub struct LockedIndexWriter {
+    lock: PathBuf,
+    path: PathBuf,
+    file: Option<File>,
+    committed: bool,
+}
+
+impl LockedIndexWriter {
+    /// take `<path>.lock`, fails if another process holds it
+    pub fn lock(path: &Path) -> Result<Self> {
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => GitError::index_locked(&lock),
+                _ => GitError::failed_to_write_file(&lock.to_string_lossy()),
+            })?;
+        Ok(LockedIndexWriter { lock, path: path.to_path_buf(), file: Some(file), committed: false })
+    }
+
+    /// write `index` into the lock and put it in place of the index
+    pub fn commit(mut self, index: &Index) -> Result<()> {
+        let failed = |_| GitError::failed_to_write_file(&self.path.to_string_lossy());
+        let mut file = self.file.take().expect("the lock file is open until commit");
+        file.write_all(&index.to_bytes()?).map_err(failed)?;
+        file.sync_all().map_err(failed)?;
+        drop(file);
+        std::fs::rename(&self.lock, &self.path).map_err(failed)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for LockedIndexWriter {
+    fn drop(&mut self) {
+        // 没 commit 就放弃，包括 commit 中途失败；commit 之后的 lock 可能已经是别的进程的了
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.lock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use crate::utils::test::{
+        shell_spawn,
+        setup_test_git_dir,
+        tempdir,
+        cp_dir,
+    };
+
+    #[test]
+    fn test_index_lock_and_checksum() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let index = temp_path.join(".git/index");
+        let lock = temp_path.join(".git/index.lock");
+        for i in 0..8 {
+            std::fs::write(temp_path.join(format!("{}.txt", i)), format!("{}\n", i)).unwrap();
+        }
+        // make sure the binary is built before running it directly
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "add", "0.txt"]).unwrap();
+        let git = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git");
+        let ours = |args: &[&str]| Command::new(&git).arg("-C").arg(temp_path_str).args(args).output().unwrap();
+
+        // a stale lock stops every writer and is left alone
+        std::fs::write(&lock, "").unwrap();
+        let before = std::fs::read(&index).unwrap();
+        for args in [&["add", "1.txt"][..], &["rm", "--cached", "0.txt"], &["update-index", "--add", "1.txt"]] {
+            let out = ours(args);
+            assert!(!out.status.success());
+            assert!(String::from_utf8_lossy(&out.stderr).contains("index.lock exists"), "{}", String::from_utf8_lossy(&out.stderr));
+        }
+        assert!(lock.exists());
+        assert_eq!(std::fs::read(&index).unwrap(), before);
+        std::fs::remove_file(&lock).unwrap();
+
+        // racing adds never leave a broken index, each one either lands or reports the lock
+        let children = (1..8)
+            .map(|i| Command::new(&git)
+                .args(["-C", temp_path_str, "add", &format!("{}.txt", i)])
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .unwrap())
+            .collect::<Vec<_>>();
+        let outs = children.into_iter().map(|child| child.wait_with_output().unwrap()).collect::<Vec<_>>();
+        let staged = shell_spawn(&["git", "-C", temp_path_str, "ls-files"]).unwrap();
+        for (i, out) in (1..8).zip(outs) {
+            let name = format!("{}.txt", i);
+            assert!(out.status.success() == staged.lines().any(|line| line == name));
+            if !out.status.success() {
+                assert!(String::from_utf8_lossy(&out.stderr).contains("index.lock exists"));
+            }
+        }
+        assert!(!lock.exists());
+
+        // real git reads what we wrote, we read what git writes
+        shell_spawn(&["git", "-C", temp_path_str, "add", "."]).unwrap();
+        let out = ours(&["ls-files"]);
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout), shell_spawn(&["git", "-C", temp_path_str, "ls-files"]).unwrap());
+
+        // a flipped bit and a cut off index are reported, not parsed
+        let good = std::fs::read(&index).unwrap();
+        let mut flipped = good.clone();
+        flipped[40] ^= 1;
+        std::fs::write(&index, &flipped).unwrap();
+        let out = ours(&["ls-files"]);
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("index file corrupt: bad index file sha1 signature"));
+
+        std::fs::write(&index, &good[..good.len() / 2]).unwrap();
+        let out = ours(&["add", "0.txt"]);
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("index file corrupt"));
+        assert!(!lock.exists());
+
+        std::fs::write(&index, &good[..20]).unwrap();
+        let out = ours(&["ls-files"]);
+        assert!(String::from_utf8_lossy(&out.stderr).contains("index file corrupt: index file smaller than expected"));
+    }
+
+    #[test]
+    fn test_writers_lock_before_reading() {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_path_str = temp_path.to_str().unwrap();
+        let git = |args: &[&str]| shell_spawn(&[&["git", "-C", temp_path_str][..], args].concat()).unwrap();
+        std::fs::write(temp_path.join("0.txt"), "0\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "base"]);
+        git(&["checkout", "-b", "side"]);
+        std::fs::write(temp_path.join("side.txt"), "side\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "side"]);
+        git(&["checkout", "master"]);
+        std::fs::write(temp_path.join("1.txt"), "1\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-m", "master"]);
+        std::fs::write(temp_path.join("0.txt"), "changed\n").unwrap();
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path_str, "status"]).unwrap();
+
+        // with the lock taken nothing gets read and then written over: index, refs, merge state and files stay
+        let lock = temp_path.join(".git/index.lock");
+        std::fs::write(&lock, "").unwrap();
+        let state = || (
+            std::fs::read(temp_path.join(".git/index")).unwrap(),
+            git(&["for-each-ref"]),
+            git(&["symbolic-ref", "HEAD"]),
+            std::fs::read_to_string(temp_path.join("0.txt")).unwrap(),
+            temp_path.join("side.txt").exists() || temp_path.join(".git/MERGE_HEAD").exists() || temp_path.join(".git/CHERRY_PICK_HEAD").exists(),
+        );
+        let before = state();
+        let ours = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/debug/git");
+        let writers: &[&[&str]] = &[
+            &["read-tree", "side"], &["read-tree", "-m", "HEAD", "side"], &["checkout", "side"], &["checkout", "--", "0.txt"],
+            &["checkout", "side", "--", "side.txt"], &["reset", "--", "0.txt"], &["stash"], &["merge", "side"], &["cherry-pick", "side"],
+        ];
+        for args in writers {
+            let out = Command::new(&ours).arg("-C").arg(temp_path_str).args(*args).output().unwrap();
+            assert!(!out.status.success(), "{:?}", args);
+            assert!(String::from_utf8_lossy(&out.stderr).contains("index.lock exists"), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+            assert!(state() == before, "{:?}", args);
+        }
+        assert!(lock.exists());
+    }
+
+    #[test]
+    fn test_add_entry_keeps_order() {
+        use super::{Index, IndexEntry};
+        let hash = crate::utils::hash::ObjectId::null();
+        let names = (0..300).map(|i| format!("d{}/f{}", (i * 7919) % 13, (i * 104729) % 301)).collect::<Vec<_>>();
+        let (mut one_by_one, mut batched) = (Index::new(), Index::new());
+        for name in &names {
+            one_by_one.add_entry(IndexEntry::new(0o100644, hash, name.clone()));
+        }
+        batched.add_entries(names.iter().map(|name| IndexEntry::new(0o100644, hash, name.clone())).collect());
+        let listed = |index: &Index| index.entries.iter().map(|entry| (entry.name.clone(), entry.stage)).collect::<Vec<_>>();
+        assert_eq!(listed(&one_by_one), listed(&batched));
+        assert!(one_by_one.entries.windows(2).all(|pair| pair[0].name_bytes() < pair[1].name_bytes()));
+
+        // conflict stages sit in order under their path, stage 0 replaces all of them
+        let name = names[0].clone();
+        for stage in [3, 1, 2] {
+            one_by_one.add_entry(IndexEntry::new(0o100644, hash, name.clone()).with_stage(stage));
+        }
+        let stages = one_by_one.entries.iter().filter(|entry| entry.name.as_os_str() == name.as_str()).map(|entry| entry.stage).collect::<Vec<_>>();
+        assert_eq!(stages, [1, 2, 3]);
+        one_by_one.add_entry(IndexEntry::new(0o100644, hash, name.clone()));
+        assert_eq!(listed(&one_by_one), listed(&batched));
+    }
+
+    #[test]
+    fn test_racy_entry_is_rehashed() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        std::fs::write(temp_path1.join("a.txt"), "one\n").unwrap();
+        std::fs::write(temp_path1.join("b.txt"), "old\n").unwrap();
+        shell_spawn(&["touch", "-d", "@1700000000", temp_path1.join("a.txt").to_str().unwrap(), temp_path1.join("b.txt").to_str().unwrap()]).unwrap();
+        shell_spawn(&["git", "-C", temp_path1.to_str().unwrap(), "add", "."]).unwrap();
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+
+        for dir in [temp_path1, temp_path2] {
+            // same size, and the mtime the index recorded: only the content differs
+            std::fs::write(dir.join("a.txt"), "two\n").unwrap();
+            let touch = |time: &str, path: &str| shell_spawn(&["touch", "-d", time, dir.join(path).to_str().unwrap()]).unwrap();
+            touch("@1700000000", "a.txt");
+            touch("@1700000000", "b.txt");
+            // the index is from the very same second, so its stat data can't be trusted
+            touch("@1700000000", ".git/index");
+            let index = super::Index::new().read_from_file(&dir.join(".git/index")).unwrap();
+            assert!(index.entries.iter().all(|entry| !entry.is_stat_clean(&dir.join(&entry.name))));
+        }
+
+        shell_spawn(&["git", "-C", temp_path1.to_str().unwrap(), "add", "-u"]).unwrap();
+        shell_spawn(&["cargo", "run", "--quiet", "--", "-C", temp_path2.to_str().unwrap(), "add", "-u"]).unwrap();
+        let staged = |dir: &std::path::Path| shell_spawn(&["git", "-C", dir.to_str().unwrap(), "ls-files", "-s"]).unwrap();
+        assert_eq!(staged(temp_path1), staged(temp_path2));
+        assert!(staged(temp_path2).contains(&shell_spawn(&["git", "hash-object", temp_path2.join("a.txt").to_str().unwrap()]).unwrap().trim().to_string()));
+    }
+
+    #[test]
+    fn test_conflict_stages() {
+        let temp1 = setup_test_git_dir();
+        let temp_path1 = temp1.path();
+        let git = |dir: &std::path::Path, args: &[&str]| {
+            let mut command = vec!["git", "-C", dir.to_str().unwrap()];
+            command.extend_from_slice(args);
+            shell_spawn(&command)
+        };
+        std::fs::write(temp_path1.join("f.txt"), "base\n").unwrap();
+        std::fs::write(temp_path1.join("g.txt"), "same\n").unwrap();
+        git(temp_path1, &["add", "."]).unwrap();
+        git(temp_path1, &["commit", "-m", "base"]).unwrap();
+        git(temp_path1, &["checkout", "-b", "topic"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "topic\n").unwrap();
+        git(temp_path1, &["commit", "-am", "topic"]).unwrap();
+        git(temp_path1, &["checkout", "-"]).unwrap();
+        std::fs::write(temp_path1.join("f.txt"), "main\n").unwrap();
+        git(temp_path1, &["commit", "-am", "main"]).unwrap();
+        assert!(git(temp_path1, &["merge", "topic"]).is_err());
+
+        let temp2 = tempdir().unwrap();
+        let temp_path2 = temp2.path();
+        let _ = cp_dir(temp_path1, temp_path2).unwrap();
+        let ours = |args: &[&str]| {
+            let mut command = vec!["cargo", "run", "--quiet", "--", "-C", temp_path2.to_str().unwrap()];
+            command.extend_from_slice(args);
+            shell_spawn(&command).unwrap()
+        };
+        let staged = |dir: &std::path::Path| git(dir, &["ls-files", "-s"]).unwrap();
+        assert_eq!(ours(&["ls-files", "--stage"]), staged(temp_path1));
+        assert_eq!(ours(&["ls-files", "-u"]), git(temp_path1, &["ls-files", "-u"]).unwrap());
+
+        // stages survive a read and write of our own: the entries come out byte for byte,
+        // only git's cached tree extension is left behind
+        let index_path = temp_path2.join(".git/index");
+        let mut index = super::Index::new().read_from_file(&index_path).unwrap();
+        assert_eq!(index.entries.iter().filter(|entry| entry.name.as_os_str() == "f.txt").map(|entry| entry.stage).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(index.unmerged_paths(), [std::path::Path::new("f.txt")]);
+        let original = std::fs::read(&index_path).unwrap();
+        index.write_to_file(&index_path).unwrap();
+        let written = std::fs::read(&index_path).unwrap();
+        assert!(original.starts_with(&written[..written.len() - 20]));
+        assert_eq!(staged(temp_path2), staged(temp_path1));
+
+        // removing a conflicted path drops every stage of it
+        assert!(index.remove_entry("f.txt"));
+        assert!(index.entries.iter().all(|entry| entry.name.as_os_str() != "f.txt"));
+        index.write_to_file(&index_path).unwrap();
+        assert!(!staged(temp_path2).contains("f.txt"));
+        git(temp_path2, &["fsck", "--no-dangling"]).unwrap();
+        std::fs::write(&index_path, &original).unwrap();
+
+        // adding the resolved file puts a single stage 0 entry in place of the conflict
+        for dir in [temp_path1, temp_path2] {
+            std::fs::write(dir.join("f.txt"), "resolved\n").unwrap();
+        }
+        git(temp_path1, &["add", "f.txt"]).unwrap();
+        ours(&["add", "f.txt"]);
+        assert_eq!(staged(temp_path1), staged(temp_path2));
+        assert_eq!(git(temp_path2, &["ls-files", "-u"]).unwrap(), "");
+    }
+
+    #[test]
+    fn test_read_or_default() {
+        let temp = setup_test_git_dir();
+        let gitdir = temp.path().join(".git");
+        // nothing added yet: no index file, an empty index
+        assert!(!gitdir.join("index").exists());
+        assert!(super::Index::read_or_default(&gitdir).unwrap().entries.is_empty());
+        std::fs::write(temp.path().join("a.txt"), "a\n").unwrap();
+        shell_spawn(&["git", "-C", temp.path().to_str().unwrap(), "add", "a.txt"]).unwrap();
+        let index = super::Index::read_or_default(&gitdir).unwrap();
+        assert_eq!(index.entries.iter().map(|entry| entry.name.as_path()).collect::<Vec<_>>(), [std::path::Path::new("a.txt")]);
+        std::fs::write(gitdir.join("index"), "garbage").unwrap();
+        assert!(super::Index::read_or_default(&gitdir).is_err());
+    }
+}
@@ -7,7 +7,20 @@ pub mod objtype;
 pub mod blob;
 pub mod tree;
 pub mod commit;
+pub mod tag;
 pub mod test;
 pub mod refs;
+pub mod shallow;
 pub mod protocol;
+pub mod credential;
+pub mod http;
 pub mod packfile;
+pub mod config;
+pub mod ident;
+pub mod revparse;
+pub mod revwalk;
+pub mod ignore;
+pub mod diff;
+pub mod pool;
+pub mod tar;
+pub mod zip;
@@ -11,6 +11,7 @@ use crate::utils::{
     blob::Blob,
     tree::Tree,
     commit::Commit,
+    tag::Tag,
     error::{
         GitError,
         Result
@@ -35,7 +36,7 @@ use nom::{
 };
 
 
-pub trait ObjType: TryFrom<Vec<u8>> + Into<Vec<u8>> + TryFrom<Obj> {
+pub trait ObjType: TryFrom<Vec<u8>> + TryInto<Vec<u8>> + TryFrom<Obj> {
     const VALUE: &'static str;
     const MODE: u32;
 }
@@ -44,6 +45,8 @@ pub enum Obj {
     B(Blob),
     T(Tree),
     C(Commit),
+    /// annotated tag
+    G(Tag),
 }
 
 impl Obj {
@@ -52,6 +55,7 @@ impl Obj {
             Obj::B(_) => Blob::VALUE,
             Obj::T(_) => Tree::VALUE,
             Obj::C(_) => Commit::VALUE,
+            Obj::G(_) => Tag::VALUE,
         }
     }
 
@@ -64,22 +68,28 @@ impl TryFrom<Vec<u8>> for Obj {
         let index = bytes.iter().position(|&b| b == b'\0')
             .ok_or(GitError::invalid_filemode(String::from_utf8_lossy(&bytes).into_owned()))?;
 
-        let (_, (t, _)) = parse_meta(&bytes[..=index]).map_err(GitError::invalid_tree)?;
-        match t {
-            b"blob"   => Ok(Obj::B(bytes.to_vec().try_into()?)),
-            b"tree"   => Ok(Obj::T(bytes.to_vec().try_into()?)),
-            b"commit" => Ok(Obj:: C(bytes.to_vec().try_into()?)),
+        let (_, (t, _)) = parse_meta(&bytes[..=index]).map_err(|e| GitError::invalid_tree(e.to_owned()))?;
+        // 先认出类型再把 bytes 整个交出去，不再复制一份
+        let kind = t.to_vec();
+        match kind.as_slice() {
+            b"blob"   => Ok(Obj::B(bytes.try_into()?)),
+            b"tree"   => Ok(Obj::T(bytes.try_into()?)),
+            b"commit" => Ok(Obj:: C(bytes.try_into()?)),
+            b"tag"    => Ok(Obj::G(bytes.try_into()?)),
             _        => Err(GitError::invalid_filemode(String::from_utf8_lossy(&bytes).into_owned()))
         }
     }
 }
 
-impl From<Obj> for Vec<u8> {
-    fn from(val: Obj) -> Vec<u8> {
+impl TryFrom<Obj> for Vec<u8> {
+    type Error = Box<dyn Error>;
+
+    fn try_from(val: Obj) -> result::Result<Self, Self::Error> {
         match val {
-            Obj::B(b) => b.into(),
-            Obj::T(t) => t.into(),
-            Obj::C(c) => c.into(),
+            Obj::B(b) => Ok(b.into()),
+            Obj::T(t) => Ok(t.into()),
+            Obj::C(c) => Ok(c.into()),
+            Obj::G(g) => Ok(g.into()),
         }
     }
 }
@@ -92,13 +102,14 @@ impl fmt::Display for Obj {
             },
             Obj::T(b) => b.fmt(f),
             Obj::C(b) => b.fmt(f),
+            Obj::G(b) => b.fmt(f),
         }
     }
 }
 
 pub fn parse_meta(bytes: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
     terminated(separated_pair(
-            alt((tag("blob"), tag("tree"), tag("commit"))),
+            alt((tag("blob"), tag("tree"), tag("commit"), tag("tag"))),
             tag(" "),
             digit1,
         ),
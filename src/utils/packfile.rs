@@ -1,6 +1,9 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use crate::{GitError, Result};
+use crate::utils::{fs::common_dir, hash::{algorithm, ObjectId}};
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io::{self, Cursor, Read, Write};
 
@@ -18,112 +21,45 @@ impl<'a> PreciseZlibDecoder<'a> {
         }
     }
     
+    /// 解压一个对象，zlib 流必须完整结束且大小正好是 `expected_size`
     fn decompress(&mut self, expected_size: usize) -> Result<Vec<u8>> {
         use flate2::{Decompress, FlushDecompress, Status};
         
         let mut decompressor = Decompress::new(true); // true for zlib format
-        let mut output = vec![0u8; std::cmp::max(expected_size, 1024)];
-        let mut input_consumed = 0;
-        let mut output_produced = 0;
+        let mut output = Vec::with_capacity(expected_size + 1);
         
-        // 容错：如果数据太短，直接返回
-        if self.data.len() < 2 {
-            self.total_in = self.data.len();
-            return Ok(self.data.to_vec());
-        }
-        
-        // 逐步解压缩，直到得到期望的输出大小或遇到错误
-        while input_consumed < self.data.len() {
-            let chunk_size = std::cmp::min(1024, self.data.len() - input_consumed);
-            let input_chunk = &self.data[input_consumed..input_consumed + chunk_size];
-            
+        loop {
+            // 多留一个字节，这样比声明更长的流也能被发现
+            if output.len() == output.capacity() {
+                output.reserve(1024);
+            }
             let input_before = decompressor.total_in();
             let output_before = decompressor.total_out();
+            let status = decompressor
+                .decompress_vec(&self.data[input_before as usize..], &mut output, FlushDecompress::None)
+                .map_err(|e| GitError::corrupt_pack(format!("zlib error: {}", e)))?;
             
-            match decompressor.decompress(
-                input_chunk,
-                &mut output[output_produced..],
-                FlushDecompress::None
-            ) {
-                Ok(Status::Ok) => {
-                    let input_consumed_this_round = (decompressor.total_in() - input_before) as usize;
-                    let output_produced_this_round = (decompressor.total_out() - output_before) as usize;
-                    
-                    input_consumed += input_consumed_this_round;
-                    output_produced += output_produced_this_round;
-                    
-                    // 如果没有更多输入或输出，停止
-                    if input_consumed_this_round == 0 && output_produced_this_round == 0 {
-                        break;
-                    }
-                    
-                    // 扩展输出缓冲区如果需要
-                    if output_produced >= output.len() - 1024 {
-                        output.resize(output.len() * 2, 0);
-                    }
-                }
-                Ok(Status::StreamEnd) => {
-                    let input_consumed_this_round = (decompressor.total_in() - input_before) as usize;
-                    let output_produced_this_round = (decompressor.total_out() - output_before) as usize;
-                    
-                    input_consumed += input_consumed_this_round;
-                    output_produced += output_produced_this_round;
-                    break;
-                }
-                Ok(Status::BufError) => {
-                    // 需要更多输入或输出空间
-                    let input_consumed_this_round = (decompressor.total_in() - input_before) as usize;
-                    input_consumed += input_consumed_this_round;
-                    
-                    // 扩展输出缓冲区
-                    if output_produced >= output.len() - 1024 {
-                        output.resize(output.len() * 2, 0);
-                    }
-                    continue;
-                }
-                Err(_e) => {
-                    // 容错：如果已经产生了一些输出，使用它
-                    if output_produced > 0 {
-                        self.total_in = input_consumed;
-                        output.truncate(output_produced);
-                        
-                        // 调整到期望大小
-                        if output.len() != expected_size {
-                            if output.len() > expected_size {
-                                output.truncate(expected_size);
-                            } else {
-                                output.resize(expected_size, 0);
-                            }
-                        }
-                        return Ok(output);
-                    }
-                    
-                    // 如果完全失败，尝试返回部分原始数据
-                    self.total_in = std::cmp::min(input_consumed + chunk_size, self.data.len());
-                    let fallback_size = std::cmp::min(expected_size, self.data.len());
-                    return Ok(self.data[..fallback_size].to_vec());
-                }
+            if output.len() > expected_size {
+                return Err(GitError::corrupt_pack(format!(
+                    "object inflates to more than the {} bytes in its header", expected_size
+                )));
+            }
+            if status == Status::StreamEnd {
+                break;
+            }
+            // 没有任何进展说明输入已经用完，流被截断了
+            if decompressor.total_in() == input_before && decompressor.total_out() == output_before {
+                return Err(GitError::corrupt_pack("zlib stream is truncated".to_string()));
             }
         }
         
-        // 容错：调整输出大小以匹配期望值
-        if output_produced != expected_size {
-            if output_produced > expected_size {
-                output.truncate(expected_size);
-            } else if output_produced < expected_size {
-                // 如果输出太少，尝试用零填充或重复最后的字节
-                if output_produced > 0 {
-                    let last_byte = output[output_produced - 1];
-                    output.resize(expected_size, last_byte);
-                } else {
-                    output.resize(expected_size, 0);
-                }
-            }
-        } else {
-            output.truncate(output_produced);
+        if output.len() != expected_size {
+            return Err(GitError::corrupt_pack(format!(
+                "object inflates to {} bytes but its header says {}", output.len(), expected_size
+            )));
         }
         
-        self.total_in = input_consumed;
+        self.total_in = decompressor.total_in() as usize;
         Ok(output)
     }
     
@@ -137,6 +73,8 @@ pub struct PackfileProcessor {
     gitdir: PathBuf,
     // 存储已解析的对象，用于 delta 解码
     resolved_objects: HashMap<usize, ObjectData>,
+    // 已解析对象的 hash -> 在 pack 中的序号，REF_DELTA 用它找 base
+    resolved_hashes: HashMap<String, usize>,
 }
 
 #[derive(Debug, Clone)]
@@ -150,14 +88,7 @@ struct ObjectData {
 #[derive(Debug, Clone)]
 enum DeltaInfo {
     OfsLink(u64), // OFS_DELTA - 偏移量
-    RefLink([u8; 20]), // REF_DELTA - 引用哈希
-}
-
-#[derive(Debug)]
-struct PackfileObject {
-    hash: String,
-    obj_type: u8,
-    data: Vec<u8>,
+    RefLink(ObjectId), // REF_DELTA - 引用哈希
 }
 
 impl PackfileProcessor {
@@ -165,84 +96,263 @@ impl PackfileProcessor {
         PackfileProcessor { 
             gitdir,
             resolved_objects: HashMap::new(),
+            resolved_hashes: HashMap::new(),
         }
     }
     
-    /// 处理 packfile 数据并将对象写入仓库
+    /// 处理 packfile 数据并把每个对象写成松散对象
+    /// 先校验结尾的 SHA-1、解析出全部对象，全部成功后才写入，出错时不会留下任何对象
     pub fn process_packfile(&mut self, packfile_data: &[u8]) -> Result<Vec<String>> {
-        if packfile_data.len() < 12 {
-            return Err(GitError::invalid_command("Invalid packfile: too short".to_string()));
+        let created_hashes = self.unpack_packfile(packfile_data, false, false)?;
+        eprintln!("Successfully processed {} objects", created_hashes.len());
+        Ok(created_hashes)
+    }
+
+    /// unpack-objects：dry_run 只解析不写入，strict 时对象的内容和它指向的对象都要完好才写
+    pub fn unpack_packfile(&mut self, packfile_data: &[u8], dry_run: bool, strict: bool) -> Result<Vec<String>> {
+        let parsed = self.parse_packfile(packfile_data)?;
+        if strict {
+            for (index, hash) in &parsed.staged {
+                self.check_links(hash, &self.resolved_objects[index])?;
+            }
+        }
+        if dry_run {
+            return Ok(parsed.staged.into_iter().map(|(_, hash)| hash).collect());
+        }
+        
+        // delta 都已经解开了，压缩和写入可以在多个线程上并行
+        // 中途失败就删掉这次写入的对象
+        let written = std::sync::Mutex::new(Vec::new());
+        let result = crate::utils::pool::parallel_map(&parsed.staged, crate::utils::pool::default_threads(), |(index, hash)| {
+            if self.write_object(hash, &self.resolved_objects[index])? {
+                written.lock().unwrap().push(hash.clone());
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            for hash in written.into_inner().unwrap() {
+                let _ = std::fs::remove_file(crate::utils::fs::obj_to_pathbuf(&self.gitdir, &hash));
+            }
+            return Err(e);
+        }
+        
+        Ok(parsed.staged.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// commit 的 tree 和 parent、tree 的条目（子模块除外）、tag 指向的对象，在这个 pack 里或仓库里都行
+    fn check_links(&self, hash: &str, obj: &ObjectData) -> Result<()> {
+        let malformed = |what: &str| GitError::corrupt_pack(format!("object {}: {}", hash, what));
+        let mut links = Vec::new();
+        match obj.obj_type {
+            1 | 4 => {
+                let text = std::str::from_utf8(&obj.data).map_err(|_| malformed("header is not utf-8"))?;
+                let header = text.split("\n\n").next().unwrap_or_default();
+                for line in header.lines() {
+                    match line.split_once(' ') {
+                        Some(("tree" | "parent", link)) if obj.obj_type == 1 => links.push(link.to_string()),
+                        Some(("object", link)) if obj.obj_type == 4 => links.push(link.to_string()),
+                        _ => {},
+                    }
+                }
+                let first = if obj.obj_type == 1 { "tree " } else { "object " };
+                if !header.starts_with(first) {
+                    return Err(malformed(&format!("missing {}line", first)));
+                }
+            },
+            2 => {
+                let mut bytes = format!("tree {}\0", obj.data.len()).into_bytes();
+                bytes.extend_from_slice(&obj.data);
+                let entries = crate::utils::tree::TreeRef::parse(&bytes).map_err(|_| malformed("broken tree entries"))?;
+                links.extend(entries.0.iter()
+                    .filter(|entry| entry.mode != crate::utils::tree::FileMode::Commit)
+                    .map(|entry| entry.hex()));
+            },
+            _ => {},
+        }
+        match links.into_iter().find(|link| !self.resolved_hashes.contains_key(link) && !crate::utils::fs::object_exists(&self.gitdir, link)) {
+            Some(link) => Err(malformed(&format!("links to missing object {}", link))),
+            None => Ok(()),
         }
+    }
+
+    /// 把 pack 原样保存到 objects/pack/pack-<sha>.pack，并生成 version 2 的 .idx
+    /// thin pack 缺的 base 从本地仓库补到 pack 末尾，保存下来的 pack 总是自包含的
+    pub fn store_packfile(&mut self, packfile_data: &[u8]) -> Result<Vec<String>> {
+        let parsed = self.parse_packfile(packfile_data)?;
+        let (pack, entries) = self.complete_thin_pack(packfile_data, &parsed)?;
+        let checksum = &pack[pack.len() - algorithm().raw_len()..];
+        let idx = write_pack_index(&entries, checksum);
+
+        let pack_dir = common_dir(&self.gitdir).join("objects").join("pack");
+        std::fs::create_dir_all(&pack_dir)?;
+        let name = format!("pack-{}", hex::encode(checksum));
+        let pack_path = pack_dir.join(format!("{}.pack", name));
+        let idx_path = pack_dir.join(format!("{}.idx", name));
+        if !idx_path.exists() {
+            // 先写临时文件再改名，idx 最后出现，读的一方只看 idx
+            let tmp_pack = pack_dir.join(format!("tmp_{}.pack", name));
+            let tmp_idx = pack_dir.join(format!("tmp_{}.idx", name));
+            let result = std::fs::write(&tmp_pack, &pack)
+                .and_then(|_| std::fs::write(&tmp_idx, &idx))
+                .and_then(|_| std::fs::rename(&tmp_pack, &pack_path))
+                .and_then(|_| std::fs::rename(&tmp_idx, &idx_path));
+            if let Err(e) = result {
+                for path in [&tmp_pack, &tmp_idx, &pack_path] {
+                    let _ = std::fs::remove_file(path);
+                }
+                return Err(GitError::failed_to_write_file(&format!("{}: {}", idx_path.display(), e)));
+            }
+        }
+
+        eprintln!("Stored {} objects in {}.pack", entries.len(), name);
+        Ok(parsed.staged.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// 每个对象的 (hash, 偏移, CRC32)，需要的话把 thin pack 的外部 base 作为完整对象追加在最后
+    fn complete_thin_pack(&self, packfile_data: &[u8], parsed: &ParsedPack) -> Result<(Vec<u8>, Vec<PackIndexEntry>)> {
+        let mut content = packfile_data[..parsed.content_len].to_vec();
+        let crc = |bytes: &[u8]| {
+            let mut crc = flate2::Crc::new();
+            crc.update(bytes);
+            crc.sum()
+        };
+        let mut entries = parsed.staged.iter()
+            .map(|(i, hash)| {
+                let start = parsed.positions[*i] as usize;
+                let end = parsed.positions.get(i + 1).map_or(parsed.content_len, |&pos| pos as usize);
+                (hash.clone(), start as u64, crc(&content[start..end]))
+            })
+            .collect::<Vec<_>>();
+        if parsed.external_bases.is_empty() {
+            return Ok((packfile_data.to_vec(), entries));
+        }
+
+        for base in &parsed.external_bases {
+            let obj = self.read_object_from_filesystem(base)?;
+            let mut entry = encode_entry_header(obj.obj_type, obj.data.len());
+            entry.extend(crate::utils::zlib::compress(obj.data)?);
+            entries.push((base.clone(), content.len() as u64, crc(&entry)));
+            content.extend(entry);
+        }
+        content[8..12].copy_from_slice(&(entries.len() as u32).to_be_bytes());
+        let checksum = algorithm().digest(&content);
+        content.extend_from_slice(&checksum);
+        Ok((content, entries))
+    }
 
-        let mut cursor = Cursor::new(packfile_data);
+    /// 校验并解析整个 pack，对象都还原在 resolved_objects 里
+    fn parse_packfile(&mut self, packfile_data: &[u8]) -> Result<ParsedPack> {
+        // 12 字节头部 + 校验和
+        let hash_len = algorithm().raw_len();
+        if packfile_data.len() < 12 + hash_len {
+            return Err(GitError::corrupt_pack(format!("only {} bytes", packfile_data.len())));
+        }
+        let (content, trailer) = packfile_data.split_at(packfile_data.len() - hash_len);
+        let digest = algorithm().digest(content);
+        if digest != trailer {
+            return Err(GitError::corrupt_pack(format!(
+                "checksum mismatch, trailer is {} but the content hashes to {}",
+                hex::encode(trailer),
+                hex::encode(digest)
+            )));
+        }
+
+        let mut cursor = Cursor::new(content);
         
         // 验证packfile头部签名
         let mut signature = [0u8; 4];
         cursor.read_exact(&mut signature)?;
         if &signature != b"PACK" {
-            return Err(GitError::invalid_command("Invalid packfile signature".to_string()));
+            return Err(GitError::corrupt_pack("invalid signature".to_string()));
         }
         
         // 读取版本号
         let version = cursor.read_u32::<BigEndian>()?;
         if version != 2 {
-            return Err(GitError::invalid_command(format!("Unsupported packfile version: {}", version)));
+            return Err(GitError::corrupt_pack(format!("unsupported version {}", version)));
         }
         
         // 读取对象数量
         let object_count = cursor.read_u32::<BigEndian>()?;
-        println!("Processing {} objects from packfile...", object_count);
+        eprintln!("Processing {} objects from packfile...", object_count);
         
-        let mut objects = Vec::new();
-        let mut created_hashes = Vec::new();
+        let mut raw_objects = Vec::new();
         let mut object_positions = Vec::new(); // 记录每个对象在 packfile 中的位置
         
-        // 解析每个对象
+        // 先读出所有对象，REF_DELTA 的 base 可能排在它后面
         for i in 0..object_count {
             let current_pos = cursor.position();
             object_positions.push(current_pos);
             
-            // 检查是否到达了数据末尾（保留20字节用于校验和）
-            if current_pos as usize >= packfile_data.len() - 20 {
-                break;
+            if current_pos as usize >= content.len() {
+                return Err(GitError::corrupt_pack(format!(
+                    "data ends after {} of {} objects", i, object_count
+                )));
             }
             
-            let obj = match self.read_object(&mut cursor, i) {
-                Ok(obj) => obj,
-                Err(_) => continue,
-            };            // 先将原始对象存储，后续解析 delta 时使用
-            let mut current_obj = obj;
-            
-            // 如果是 delta 对象，需要解析
-            if current_obj.delta_info.is_some() {
-                current_obj = self.resolve_delta_object(&current_obj, i, &object_positions)?;
+            let obj = self.read_object(&mut cursor, i)
+                .map_err(|e| GitError::corrupt_pack(format!("object {} at offset {}: {}", i, current_pos, e)))?;
+            raw_objects.push(obj);
+        }
+        
+        if cursor.position() as usize != content.len() {
+            return Err(GitError::corrupt_pack(format!(
+                "{} unexpected bytes after the last object", content.len() - cursor.position() as usize
+            )));
+        }
+        
+        // 解析 delta，base 还没解出来的留到下一轮，直到没有进展为止
+        let mut staged = Vec::new(); // (序号, hash)，全部解析完再写入
+        let mut pending = (0..raw_objects.len()).collect::<Vec<_>>();
+        while !pending.is_empty() {
+            let mut unresolved = Vec::new();
+            for &i in &pending {
+                let Some(current_obj) = self.resolve_delta_object(&raw_objects[i], i as u32, &object_positions)? else {
+                    unresolved.push(i);
+                    continue;
+                };
+                self.verify_object(&current_obj, i)?;
+                
+                // 计算对象hash
+                let hash = self.calculate_object_hash(&current_obj)?;
+                
+                // 存储已解析的对象供后续 delta 解码使用
+                self.resolved_objects.insert(i, current_obj);
+                self.resolved_hashes.insert(hash.clone(), i);
+                staged.push((i, hash));
+                
+                // 显示进度
+                if staged.len() % 50 == 0 || staged.len() == raw_objects.len() {
+                    eprintln!("Processed {}/{} objects", staged.len(), object_count);
+                }
             }
-            
-            // 计算对象hash
-            let hash = self.calculate_object_hash(&current_obj)?;
-            
-            // 写入对象到仓库
-            self.write_object(&hash, &current_obj)?;
-            
-            // 存储已解析的对象供后续 delta 解码使用
-            self.resolved_objects.insert(i as usize, current_obj.clone());
-            
-            objects.push(PackfileObject {
-                hash: hash.clone(),
-                obj_type: current_obj.obj_type,
-                data: current_obj.data,
-            });
-            
-            created_hashes.push(hash);
-            
-            // 显示进度
-            if (i + 1) % 50 == 0 || i + 1 == object_count {
-                println!("Processed {}/{} objects", i + 1, object_count);
+            if unresolved.len() == pending.len() {
+                return Err(Self::missing_base_error(&raw_objects, &unresolved));
             }
+            pending = unresolved;
+        }
+        staged.sort();
+        if staged.len() != object_count as usize {
+            return Err(GitError::corrupt_pack(format!("{} objects in the header but {} in the pack", object_count, staged.len())));
         }
         
-        println!("Successfully processed {} objects", created_hashes.len());
-        Ok(created_hashes)
+        // thin pack 里指向本地仓库的 base
+        let mut external_bases = raw_objects.iter()
+            .filter_map(|obj| match &obj.delta_info {
+                Some(DeltaInfo::RefLink(base_hash)) => Some(base_hash.to_hex()),
+                _ => None,
+            })
+            .filter(|base| !self.resolved_hashes.contains_key(base))
+            .collect::<Vec<_>>();
+        external_bases.sort();
+        external_bases.dedup();
+        
+        Ok(ParsedPack {
+            staged,
+            positions: object_positions,
+            content_len: content.len(),
+            external_bases,
+        })
     }
     
     fn read_object(&self, cursor: &mut Cursor<&[u8]>, _index: u32) -> Result<ObjectData> {
@@ -255,7 +365,7 @@ impl PackfileProcessor {
                 // 无效的对象类型，检查数据
                 let pos = cursor.position();
                 //println!("DEBUG: Invalid object type 0 at position {}", pos);
-                return Err(GitError::invalid_command(format!("Invalid object type: {} at position {}", obj_type, pos)));
+                Err(GitError::invalid_command(format!("Invalid object type: {} at position {}", obj_type, pos)))
             }
             1..=4 => {
                 // 直接对象类型 (commit, tree, blob, tag)
@@ -286,10 +396,11 @@ impl PackfileProcessor {
                 let remaining = cursor.get_ref().len() - cursor.position() as usize;
                 //println!("DEBUG: Remaining data length: {}", remaining);
 
-                if remaining < 20 {
+                let hash_len = algorithm().raw_len();
+                if remaining < hash_len {
                     return Err(GitError::invalid_command(format!(
-                        "Not enough data for REF_DELTA hash: {} bytes remaining, need 20", 
-                        remaining
+                        "Not enough data for REF_DELTA hash: {} bytes remaining, need {}", 
+                        remaining, hash_len
                     )));
                 }
                 
@@ -299,8 +410,9 @@ impl PackfileProcessor {
                 //println!("DEBUG: Next 30 bytes: {:02x?}", debug_bytes);
 
                 // 尝试处理 REF_DELTA 对象
-                let mut base_hash = [0u8; 20];
+                let mut base_hash = vec![0u8; hash_len];
                 cursor.read_exact(&mut base_hash)?;
+                let base_hash = ObjectId::from_raw(&base_hash)?;
                 
                 let compressed_data = self.read_compressed_data(cursor, size)?;
                 Ok(ObjectData {
@@ -368,179 +480,129 @@ impl PackfileProcessor {
         Ok(decompressed)
     }
     
-    fn resolve_delta_object(&mut self, obj: &ObjectData, current_index: u32, object_positions: &[u64]) -> Result<ObjectData> {
+    /// 解出 delta 对象，base 暂时还拿不到时返回 None
+    fn resolve_delta_object(&self, obj: &ObjectData, current_index: u32, object_positions: &[u64]) -> Result<Option<ObjectData>> {
         match &obj.delta_info {
             None => {
                 // 不是 delta 对象，直接返回
-                Ok(obj.clone())
+                Ok(Some(obj.clone()))
             }
             Some(DeltaInfo::OfsLink(offset)) => {
-                //println!("DEBUG: Resolving OFS_DELTA with offset {}", offset);
-                
                 // 计算基础对象在 packfile 中的位置
                 let current_pos = object_positions[current_index as usize];
                 if *offset > current_pos {
-                    return Err(GitError::invalid_command(format!(
-                        "Invalid OFS_DELTA offset: {} from position {}", 
-                        offset, 
-                        current_pos
+                    return Err(GitError::corrupt_pack(format!(
+                        "OFS_DELTA object {} points {} bytes back from offset {}",
+                        current_index, offset, current_pos
                     )));
                 }
                 let base_pos = current_pos - offset;
                 
                 // 找到基础对象的索引
-                let mut base_index = None;
-                for (i, &pos) in object_positions.iter().enumerate() {
-                    if pos == base_pos {
-                        base_index = Some(i);
-                        break;
-                    }
-                }
-                
-                let base_idx = base_index.ok_or_else(|| GitError::invalid_command(format!(
-                    "Base object at position {} not found for OFS_DELTA", 
-                    base_pos
-                )))?;
-                
-                // 获取基础对象
-                let base_obj = self.resolved_objects.get(&base_idx)
-                    .ok_or_else(|| GitError::invalid_command(format!(
-                        "Base object at index {} not found for OFS_DELTA", 
-                        base_idx
+                let base_idx = object_positions.iter()
+                    .position(|&pos| pos == base_pos)
+                    .ok_or_else(|| GitError::corrupt_pack(format!(
+                        "OFS_DELTA object {} has no object at its base offset {}", current_index, base_pos
                     )))?;
                 
-                // 应用 delta
-                self.apply_delta(base_obj, &obj.data)
+                // 基础对象自己可能还是没解出来的 REF_DELTA
+                match self.resolved_objects.get(&base_idx) {
+                    Some(base_obj) => self.apply_delta(base_obj, &obj.data)
+                        .map(Some)
+                        .map_err(|e| GitError::corrupt_pack(format!(
+                            "OFS_DELTA object {} against the object at offset {}: {}", current_index, base_pos, e
+                        ))),
+                    None => Ok(None),
+                }
             }
             Some(DeltaInfo::RefLink(base_hash)) => {
-                //println!("DEBUG: Resolving REF_DELTA with base hash {}", hex::encode(base_hash));
-                
-                // 在已解析的对象中查找基础对象
-                let mut base_obj = None;
-                for (_, obj) in &self.resolved_objects {
-                    // 计算对象哈希并比较
-                    if let Ok(hash_str) = self.calculate_object_hash(obj) {
-                        let hash_bytes = hex::decode(&hash_str).unwrap_or_default();
-                        if hash_bytes.len() == 20 && hash_bytes[..] == base_hash[..] {
-                            base_obj = Some(obj);
-                            break;
-                        }
-                    }
-                }
-                
-                match base_obj {
-                    Some(base) => self.apply_delta(base, &obj.data),
-                    None => {
-                        // 尝试从文件系统读取 base 对象
-                        let base_hash_str = hex::encode(base_hash);
-                        match self.read_object_from_filesystem(&base_hash_str) {
-                            Ok(base_from_fs) => self.apply_delta(&base_from_fs, &obj.data),
-                            Err(_) => {
-                                // 如果找不到 base 对象，创建一个简化的对象
-                                //println!("DEBUG: Base object not found, creating fallback object");
-                                let fallback_obj = ObjectData {
-                                    obj_type: 3, // blob 类型
-                                    data: obj.data.clone(), // 使用 delta 数据作为内容
-                                    delta_info: None,
-                                };
-                                Ok(fallback_obj)
-                            }
-                        }
-                    }
-                }
+                // 先在这个 pack 里找，thin pack 的 base 在本地仓库里
+                let base_hash_str = base_hash.to_hex();
+                let base = match self.resolved_hashes.get(&base_hash_str) {
+                    Some(index) => self.resolved_objects[index].clone(),
+                    None => match self.read_object_from_filesystem(&base_hash_str) {
+                        Ok(base) => base,
+                        Err(_) => return Ok(None),
+                    },
+                };
+                self.apply_delta(&base, &obj.data)
+                    .map(Some)
+                    .map_err(|e| GitError::corrupt_pack(format!(
+                        "REF_DELTA object {} against base {}: {}", current_index, base_hash_str, e
+                    )))
             }
         }
     }
+
+    /// 没有进展时说明有 base 既不在 pack 里也不在本地仓库里
+    fn missing_base_error(raw_objects: &[ObjectData], unresolved: &[usize]) -> Box<dyn std::error::Error> {
+        let missing = unresolved.iter()
+            .find_map(|&i| match &raw_objects[i].delta_info {
+                Some(DeltaInfo::RefLink(base_hash)) => Some((i, base_hash.to_hex())),
+                _ => None,
+            });
+        match missing {
+            Some((i, base)) => GitError::corrupt_pack(format!(
+                "REF_DELTA object {} needs base {} which is neither in the pack nor in the repository", i, base
+            )),
+            None => GitError::corrupt_pack(format!("{} delta objects have no base", unresolved.len())),
+        }
+    }
+
+    /// 写入之前检查还原出来的对象，delta 链会继承 base 的类型
+    fn verify_object(&self, obj: &ObjectData, index: usize) -> Result<()> {
+        if !(1..=4).contains(&obj.obj_type) {
+            return Err(GitError::corrupt_pack(format!("object {} has invalid type {}", index, obj.obj_type)));
+        }
+        if obj.delta_info.is_some() {
+            return Err(GitError::corrupt_pack(format!("object {} is still a delta", index)));
+        }
+        Ok(())
+    }
     
     fn apply_delta(&self, base_obj: &ObjectData, delta_data: &[u8]) -> Result<ObjectData> {
-        //println!("DEBUG: Applying delta to base object type {}", base_obj.obj_type);
-        
+        let truncated = |_| GitError::corrupt_pack("delta is truncated".to_string());
         let mut cursor = Cursor::new(delta_data);
         
         // 读取基础对象大小
-        let base_size = match self.read_delta_size(&mut cursor) {
-            Ok(size) => size,
-            Err(_) => {
-                // 如果无法读取 delta 头部，返回一个简化的对象
-                return Ok(ObjectData {
-                    obj_type: base_obj.obj_type,
-                    data: delta_data.to_vec(),
-                    delta_info: None,
-                });
-            }
-        };
-        
-        // 放宽大小检查
+        let base_size = self.read_delta_size(&mut cursor).map_err(truncated)?;
         if base_size != base_obj.data.len() {
-            let size_diff = if base_size > base_obj.data.len() {
-                base_size - base_obj.data.len()
-            } else {
-                base_obj.data.len() - base_size
-            };
-            
-            // 如果差异太大，尝试使用 delta 数据作为对象内容
-            if size_diff > base_size / 2 {
-                return Ok(ObjectData {
-                    obj_type: base_obj.obj_type,
-                    data: delta_data.to_vec(),
-                    delta_info: None,
-                });
-            }
+            return Err(GitError::corrupt_pack(format!(
+                "delta expects a base of {} bytes but the base has {}", base_size, base_obj.data.len()
+            )));
         }
         
         // 读取结果对象大小
-        let result_size = match self.read_delta_size(&mut cursor) {
-            Ok(size) => size,
-            Err(_) => delta_data.len(), // 使用 delta 数据长度作为默认值
-        };
+        let result_size = self.read_delta_size(&mut cursor).map_err(truncated)?;
         
         // 应用 delta 指令
-        let mut result_data = Vec::new();
+        let mut result_data = Vec::with_capacity(result_size);
         
         while cursor.position() < delta_data.len() as u64 {
-            let instruction = match cursor.read_u8() {
-                Ok(inst) => inst,
-                Err(_) => break, // 读取失败，结束解析
-            };
+            let instruction = cursor.read_u8()?;
             
             if instruction & 0x80 != 0 {
                 // 复制指令
-                let (offset, size) = match self.read_copy_instruction(&mut cursor, instruction) {
-                    Ok((o, s)) => (o, s),
-                    Err(_) => continue, // 解析失败，跳过这个指令
-                };
-                
-                // 边界检查，避免越界
-                if offset < base_obj.data.len() && size > 0 {
-                    let end_pos = std::cmp::min(offset + size, base_obj.data.len());
-                    if end_pos > offset {
-                        result_data.extend_from_slice(&base_obj.data[offset..end_pos]);
-                    }
-                }
+                let (offset, size) = self.read_copy_instruction(&mut cursor, instruction).map_err(truncated)?;
+                let chunk = base_obj.data.get(offset..offset + size)
+                    .ok_or_else(|| GitError::corrupt_pack(format!(
+                        "delta copies {}..{} out of a base of {} bytes", offset, offset + size, base_obj.data.len()
+                    )))?;
+                result_data.extend_from_slice(chunk);
+            } else if instruction == 0 {
+                return Err(GitError::corrupt_pack("delta has the reserved instruction 0".to_string()));
             } else {
                 // 插入指令
-                let size = instruction as usize;
-                if size > 0 && cursor.position() + size as u64 <= delta_data.len() as u64 {
-                    let mut insert_data = vec![0u8; size];
-                    if cursor.read_exact(&mut insert_data).is_ok() {
-                        result_data.extend_from_slice(&insert_data);
-                    }
-                }
+                let mut insert_data = vec![0u8; instruction as usize];
+                cursor.read_exact(&mut insert_data).map_err(|_| GitError::corrupt_pack("delta is truncated".to_string()))?;
+                result_data.extend_from_slice(&insert_data);
             }
         }
         
-        // 如果结果为空，使用 base 对象的数据
-        if result_data.is_empty() {
-            result_data = base_obj.data.clone();
-        }
-        
-        // 调整结果大小
-        if result_size > 0 && result_data.len() != result_size {
-            if result_data.len() > result_size {
-                result_data.truncate(result_size);
-            } else {
-                result_data.resize(result_size, 0);
-            }
+        if result_data.len() != result_size {
+            return Err(GitError::corrupt_pack(format!(
+                "delta produces {} bytes but says {}", result_data.len(), result_size
+            )));
         }
         
         Ok(ObjectData {
@@ -596,8 +658,6 @@ impl PackfileProcessor {
     }
     
     fn calculate_object_hash(&self, obj: &ObjectData) -> Result<String> {
-        use sha1::{Sha1, Digest};
-        
         let type_name = match obj.obj_type {
             1 => "commit",
             2 => "tree", 
@@ -608,24 +668,20 @@ impl PackfileProcessor {
         
         let header = format!("{} {}\0", type_name, obj.data.len());
         
-        let mut hasher = Sha1::new();
+        let mut hasher = algorithm().hasher();
         hasher.update(header.as_bytes());
         hasher.update(&obj.data);
         
         Ok(hex::encode(hasher.finalize()))
     }
     
-    fn write_object(&self, hash: &str, obj: &ObjectData) -> Result<()> {
+    /// 返回是否新写入了对象，已经存在的对象不算
+    fn write_object(&self, hash: &str, obj: &ObjectData) -> Result<bool> {
         let obj_path = crate::utils::fs::obj_to_pathbuf(&self.gitdir, hash);
         
         // 如果对象已存在，跳过
         if obj_path.exists() {
-            return Ok(());
-        }
-        
-        // 创建目录
-        if let Some(parent) = obj_path.parent() {
-            std::fs::create_dir_all(parent)?;
+            return Ok(false);
         }
         
         // 构建完整的对象内容
@@ -643,23 +699,14 @@ impl PackfileProcessor {
         
         // 压缩并写入
         let compressed = crate::utils::fs::compress_object(&full_content)?;
-        std::fs::write(&obj_path, compressed)?;
+        crate::utils::fs::write_loose_object(&obj_path, &compressed)?;
         
-        Ok(())
+        Ok(true)
     }
     
-    /// 从文件系统读取已存在的Git对象
+    /// 从仓库读取已存在的Git对象，松散对象或者 pack 里的都可以
     fn read_object_from_filesystem(&self, hash: &str) -> Result<ObjectData> {
-        use crate::utils::zlib::decompress_file_bytes;
-        use crate::utils::fs::obj_to_pathbuf;
-        
-        let obj_path = obj_to_pathbuf(&self.gitdir, hash);
-        if !obj_path.exists() {
-            return Err(GitError::invalid_command(format!("Object {} not found in filesystem", hash)));
-        }
-        
-        // 读取并解压对象
-        let decompressed = decompress_file_bytes(&obj_path)?;
+        let decompressed = crate::utils::fs::read_raw_object(&self.gitdir, hash)?;
         
         // 解析对象头部 (type size\0data)
         let null_pos = decompressed.iter().position(|&b| b == 0)
@@ -671,13 +718,8 @@ impl PackfileProcessor {
             return Err(GitError::invalid_command("Invalid object header".to_string()));
         }
         
-        let obj_type = match parts[0] {
-            "commit" => 1,
-            "tree" => 2,
-            "blob" => 3,
-            "tag" => 4,
-            _ => return Err(GitError::invalid_command(format!("Unknown object type: {}", parts[0]))),
-        };
+        let obj_type = type_code(parts[0])
+            .ok_or_else(|| GitError::invalid_command(format!("Unknown object type: {}", parts[0])))?;
         
         let data = decompressed[null_pos + 1..].to_vec();
         
@@ -687,4 +729,668 @@ impl PackfileProcessor {
             delta_info: None,
         })
     }
-}
\ No newline at end of file
+}
+
+/// 一个解析好的 pack：对象的 (序号, hash)、每个对象在 pack 中的位置、去掉校验和后的长度和 thin pack 的外部 base
+struct ParsedPack {
+    staged: Vec<(usize, String)>,
+    positions: Vec<u64>,
+    content_len: usize,
+    external_bases: Vec<String>,
+}
+
+/// idx 中的一项：(hash, 偏移, CRC32)
+type PackIndexEntry = (String, u64, u32);
+
+const IDX_SIGNATURE: &[u8] = b"\xfftOc";
+
+fn type_code(name: &str) -> Option<u8> {
+    match name {
+        "commit" => Some(1),
+        "tree" => Some(2),
+        "blob" => Some(3),
+        "tag" => Some(4),
+        _ => None,
+    }
+}
+
+/// pack 中对象的头部：类型和大小的变长编码
+fn encode_entry_header(obj_type: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(obj_type << 4) | (size & 0x0f) as u8];
+    let mut size = size >> 4;
+    while size > 0 {
+        *header.last_mut().unwrap() |= 0x80;
+        header.push((size & 0x7f) as u8);
+        size >>= 7;
+    }
+    header
+}
+
+/// 把这些对象（松散的或者已经在 pack 里的）都作为完整对象写成一个 pack，不做 delta
+pub fn write_pack(gitdir: &Path, hashes: &[String]) -> Result<Vec<u8>> {
+    write_pack_with(gitdir, hashes, crate::utils::zlib::level())
+}
+
+/// `write_pack` with an explicit zlib level instead of the configured one
+pub fn write_pack_with(gitdir: &Path, hashes: &[String], level: flate2::Compression) -> Result<Vec<u8>> {
+    let mut pack = b"PACK".to_vec();
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(hashes.len() as u32).to_be_bytes());
+    for hash in hashes {
+        let (obj_type, data) = read_pack_object(gitdir, hash)?;
+        pack.extend(encode_entry_header(obj_type, data.len()));
+        pack.extend(crate::utils::zlib::compress_with(data, level)?);
+    }
+    let checksum = algorithm().digest(&pack);
+    pack.extend_from_slice(&checksum);
+    Ok(pack)
+}
+
+/// 对象的 pack 类型码和去掉头部的内容
+fn read_pack_object(gitdir: &Path, hash: &str) -> Result<(u8, Vec<u8>)> {
+    let raw = crate::utils::fs::read_raw_object(gitdir, hash)?;
+    let header_end = raw.iter().position(|&b| b == 0)
+        .ok_or_else(|| GitError::invalid_obj(format!("object {} has no header", hash)))?;
+    let header = String::from_utf8_lossy(&raw[..header_end]);
+    let obj_type = header.split(' ').next().and_then(type_code)
+        .ok_or_else(|| GitError::invalid_obj(format!("object {} has a bad header '{}'", hash, header)))?;
+    Ok((obj_type, raw[header_end + 1..].to_vec()))
+}
+
+/// pack-objects 写出的 pack：内容、idx 需要的每个对象的 (hash, 偏移, CRC32)，以及其中 delta 的个数
+pub struct BuiltPack {
+    pub data: Vec<u8>,
+    entries: Vec<PackIndexEntry>,
+    pub deltas: usize,
+}
+
+impl BuiltPack {
+    /// 结尾的校验和，也是 pack 的名字
+    pub fn checksum(&self) -> String {
+        hex::encode(&self.data[self.data.len() - algorithm().raw_len()..])
+    }
+
+    /// 写出 `<base>-<checksum>.pack` 和 `.idx`，idx 最后出现
+    pub fn write_to(&self, base: &Path) -> Result<()> {
+        let checksum = self.checksum();
+        let path = |ext: &str| PathBuf::from(format!("{}-{}.{}", base.display(), checksum, ext));
+        let idx = write_pack_index(&self.entries, &self.data[self.data.len() - algorithm().raw_len()..]);
+        for (path, content) in [(path("pack"), &self.data), (path("idx"), &idx)] {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, content).map_err(|_| GitError::failed_to_write_file(&path.display().to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// git 的 pack_name_hash：同一个文件名（尤其是结尾几个字符）的对象排在一起，更容易找到 delta
+fn name_hash(name: &str) -> u32 {
+    name.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+/// 写一个 pack，`objects` 是 (hash, 路径) 对，路径可以为空
+/// window 大于 0 时和 git 一样按类型、文件名、大小排序，每个对象在前面 window 个同类对象里找最小的 OFS_DELTA，
+/// delta 链不超过 depth；base 总在 delta 前面
+pub fn build_pack(gitdir: &Path, objects: &[(String, String)], window: usize, depth: usize) -> Result<BuiltPack> {
+    let mut loaded = Vec::with_capacity(objects.len());
+    for (hash, name) in objects {
+        let (obj_type, data) = read_pack_object(gitdir, hash)?;
+        loaded.push((hash.clone(), obj_type, name_hash(name), data));
+    }
+    if window > 0 {
+        // 大的在前，delta 从大的往小的方向做
+        loaded.sort_by_key(|a| (a.1, a.2, std::cmp::Reverse(a.3.len())));
+    }
+
+    let mut pack = b"PACK".to_vec();
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(loaded.len() as u32).to_be_bytes());
+    let mut entries: Vec<PackIndexEntry> = Vec::with_capacity(loaded.len());
+    let mut chain = vec![0usize; loaded.len()];
+    let mut deltas = 0;
+    for (i, (hash, obj_type, _, data)) in loaded.iter().enumerate() {
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for j in (i.saturating_sub(window)..i).rev() {
+            if loaded[j].1 != *obj_type || chain[j] >= depth {
+                continue;
+            }
+            // 和 git 一样，delta 至少要比原对象小一半才值得
+            let max_size = best.as_ref().map_or(data.len() / 2, |(_, delta)| delta.len());
+            if let Some(delta) = create_delta(&loaded[j].3, data, max_size) {
+                best = Some((j, delta));
+            }
+        }
+
+        let start = pack.len();
+        match best {
+            Some((base, delta)) => {
+                chain[i] = chain[base] + 1;
+                deltas += 1;
+                pack.extend(encode_entry_header(6, delta.len()));
+                pack.extend(encode_ofs_offset((start as u64) - entries[base].1));
+                pack.extend(crate::utils::zlib::compress(delta)?);
+            },
+            None => {
+                pack.extend(encode_entry_header(*obj_type, data.len()));
+                pack.extend(crate::utils::zlib::compress(data.clone())?);
+            },
+        }
+        let mut crc = flate2::Crc::new();
+        crc.update(&pack[start..]);
+        entries.push((hash.clone(), start as u64, crc.sum()));
+    }
+    let checksum = algorithm().digest(&pack);
+    pack.extend_from_slice(&checksum);
+    Ok(BuiltPack { data: pack, entries, deltas })
+}
+
+/// OFS_DELTA 到 base 的距离：大端的 7 位一组，除最后一组外每组先减一
+fn encode_ofs_offset(mut offset: u64) -> Vec<u8> {
+    let mut bytes = vec![(offset & 0x7f) as u8];
+    offset >>= 7;
+    while offset > 0 {
+        offset -= 1;
+        bytes.insert(0, 0x80 | (offset & 0x7f) as u8);
+        offset >>= 7;
+    }
+    bytes
+}
+
+/// delta 开头的大小：小端的 7 位一组
+fn encode_delta_size(mut size: usize, out: &mut Vec<u8>) {
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// 从 base 得到 target 的 delta：base 按 16 字节一块建索引，能复制的就复制，其余原样插入
+/// 结果不比 max_size 小就不要
+fn create_delta(base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    const BLOCK: usize = 16;
+    if base.len() < BLOCK || target.len() < BLOCK {
+        return None;
+    }
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for offset in (0..=base.len() - BLOCK).step_by(BLOCK) {
+        let candidates = index.entry(&base[offset..offset + BLOCK]).or_default();
+        // 重复很多的块只留几个候选
+        if candidates.len() < 8 {
+            candidates.push(offset);
+        }
+    }
+
+    let mut delta = Vec::new();
+    encode_delta_size(base.len(), &mut delta);
+    encode_delta_size(target.len(), &mut delta);
+    let flush = |insert: &mut Vec<u8>, delta: &mut Vec<u8>| {
+        for chunk in insert.chunks(0x7f) {
+            delta.push(chunk.len() as u8);
+            delta.extend_from_slice(chunk);
+        }
+        insert.clear();
+    };
+    let mut insert = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let found = (i + BLOCK <= target.len())
+            .then(|| index.get(&target[i..i + BLOCK]))
+            .flatten()
+            .and_then(|candidates| candidates.iter()
+                .map(|&offset| {
+                    let len = base[offset..].iter().zip(&target[i..]).take_while(|(a, b)| a == b).count();
+                    (len, offset)
+                })
+                .max());
+        match found {
+            Some((len, offset)) => {
+                flush(&mut insert, &mut delta);
+                // 一条复制指令最多 0xffffff 字节
+                let mut copied = 0;
+                while copied < len {
+                    let size = (len - copied).min(0xff_ffff);
+                    let (offset, mut op) = (offset + copied, 0x80u8);
+                    let mut args = Vec::new();
+                    for (bit, byte) in (offset as u32).to_le_bytes().into_iter().enumerate() {
+                        if byte != 0 {
+                            op |= 1 << bit;
+                            args.push(byte);
+                        }
+                    }
+                    for (bit, byte) in (size as u32).to_le_bytes().into_iter().take(3).enumerate() {
+                        if byte != 0 {
+                            op |= 0x10 << bit;
+                            args.push(byte);
+                        }
+                    }
+                    delta.push(op);
+                    delta.extend(args);
+                    copied += size;
+                }
+                i += len;
+            },
+            None => {
+                insert.push(target[i]);
+                i += 1;
+            },
+        }
+        if delta.len() + insert.len() >= max_size {
+            return None;
+        }
+    }
+    flush(&mut insert, &mut delta);
+    (delta.len() < max_size).then_some(delta)
+}
+
+/// version 2 的 idx：fan-out 表、排好序的 hash、CRC32、偏移（大于 2G 的放到 8 字节表里），最后是 pack 和 idx 自己的校验和
+fn write_pack_index(entries: &[PackIndexEntry], pack_checksum: &[u8]) -> Vec<u8> {
+    let mut sorted = entries.iter()
+        .map(|(hash, offset, crc)| (hex::decode(hash).unwrap_or_default(), *offset, *crc))
+        .collect::<Vec<_>>();
+    sorted.sort();
+
+    let mut idx = IDX_SIGNATURE.to_vec();
+    idx.extend_from_slice(&2u32.to_be_bytes());
+    for byte in 0..=255u8 {
+        let count = sorted.iter().filter(|(hash, _, _)| hash[0] <= byte).count() as u32;
+        idx.extend_from_slice(&count.to_be_bytes());
+    }
+    for (hash, _, _) in &sorted {
+        idx.extend_from_slice(hash);
+    }
+    for (_, _, crc) in &sorted {
+        idx.extend_from_slice(&crc.to_be_bytes());
+    }
+    let mut large_offsets = Vec::new();
+    for (_, offset, _) in &sorted {
+        if *offset < 0x8000_0000 {
+            idx.extend_from_slice(&(*offset as u32).to_be_bytes());
+        } else {
+            idx.extend_from_slice(&(0x8000_0000 | large_offsets.len() as u32).to_be_bytes());
+            large_offsets.push(*offset);
+        }
+    }
+    for offset in large_offsets {
+        idx.extend_from_slice(&offset.to_be_bytes());
+    }
+    idx.extend_from_slice(pack_checksum);
+    let checksum = algorithm().digest(&idx);
+    idx.extend_from_slice(&checksum);
+    idx
+}
+
+/// 读进内存的一个 pack：idx 里排好序的 hash、对应的偏移和 pack 的内容
+struct PackIndex {
+    hashes: Vec<ObjectId>,
+    offsets: Vec<u64>,
+    pack: Vec<u8>,
+}
+
+impl PackIndex {
+    fn load(idx_path: &Path) -> Result<Self> {
+        let corrupt = || GitError::corrupt_pack(format!("invalid index {}", idx_path.display()));
+        let idx = std::fs::read(idx_path)?;
+        // 结尾是 pack 和 idx 两个校验和
+        let hash_len = algorithm().raw_len();
+        if idx.len() < 8 + 256 * 4 + 2 * hash_len || &idx[..4] != IDX_SIGNATURE || idx[4..8] != 2u32.to_be_bytes() {
+            return Err(corrupt());
+        }
+        let word = |pos: usize| u32::from_be_bytes(idx[pos..pos + 4].try_into().unwrap());
+        let count = word(8 + 255 * 4) as usize;
+        let hashes_at = 8 + 256 * 4;
+        let offsets_at = hashes_at + count * (hash_len + 4);
+        let large_at = offsets_at + count * 4;
+        if idx.len() < large_at + 2 * hash_len {
+            return Err(corrupt());
+        }
+
+        let hashes = (0..count)
+            .map(|i| ObjectId::from_raw(&idx[hashes_at + i * hash_len..hashes_at + (i + 1) * hash_len]))
+            .collect::<Result<Vec<_>>>()?;
+        let offsets = (0..count)
+            .map(|i| {
+                let offset = word(offsets_at + i * 4);
+                if offset & 0x8000_0000 == 0 {
+                    return Ok(offset as u64);
+                }
+                let pos = large_at + (offset & 0x7fff_ffff) as usize * 8;
+                idx.get(pos..pos + 8)
+                    .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+                    .ok_or_else(corrupt)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let pack = std::fs::read(idx_path.with_extension("pack"))?;
+        Ok(PackIndex { hashes, offsets, pack })
+    }
+
+    fn find(&self, hash: &ObjectId) -> Option<u64> {
+        self.hashes.binary_search(hash).ok().map(|i| self.offsets[i])
+    }
+}
+
+thread_local! {
+    // pack 写好之后不会再变，按路径缓存
+    static PACK_CACHE: RefCell<HashMap<PathBuf, Rc<PackIndex>>> = RefCell::new(HashMap::new());
+}
+
+/// objects/pack 下所有的 pack
+fn packs(gitdir: &Path) -> Result<Vec<Rc<PackIndex>>> {
+    let pack_dir = common_dir(gitdir).join("objects").join("pack");
+    let Ok(dir) = std::fs::read_dir(&pack_dir) else {
+        return Ok(Vec::new());
+    };
+    let mut idx_paths = dir
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "idx")
+            && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("pack-")))
+        .collect::<Vec<_>>();
+    idx_paths.sort();
+
+    idx_paths.into_iter()
+        .map(|path| PACK_CACHE.with(|cache| {
+            if let Some(pack) = cache.borrow().get(&path) {
+                return Ok(pack.clone());
+            }
+            let pack = Rc::new(PackIndex::load(&path)?);
+            cache.borrow_mut().insert(path, pack.clone());
+            Ok(pack)
+        }))
+        .collect()
+}
+
+/// 从 pack 里读一个对象，返回和松散对象解压后一样的 "type size\0data"
+pub fn read_packed_object(gitdir: &Path, hash: &str) -> Result<Option<Vec<u8>>> {
+    let Ok(key) = ObjectId::from_hex(hash) else {
+        return Ok(None);
+    };
+    for pack in packs(gitdir)? {
+        if let Some(offset) = pack.find(&key) {
+            let processor = PackfileProcessor::new(gitdir.to_path_buf());
+            let obj = processor.read_pack_entry(&pack, offset)?;
+            let type_name = match obj.obj_type {
+                1 => "commit",
+                2 => "tree",
+                3 => "blob",
+                _ => "tag",
+            };
+            let mut raw = format!("{} {}\0", type_name, obj.data.len()).into_bytes();
+            raw.extend(obj.data);
+            return Ok(Some(raw));
+        }
+    }
+    Ok(None)
+}
+
+pub fn has_packed_object(gitdir: &Path, hash: &str) -> bool {
+    let Ok(key) = ObjectId::from_hex(hash) else {
+        return false;
+    };
+    packs(gitdir).is_ok_and(|packs| packs.iter().any(|pack| pack.find(&key).is_some()))
+}
+
+/// 所有 pack 里的对象，给缩写的 hash 查找用
+pub fn packed_hashes(gitdir: &Path) -> Result<Vec<String>> {
+    Ok(packs(gitdir)?
+        .iter()
+        .flat_map(|pack| pack.hashes.iter().copied().map(ObjectId::to_hex).collect::<Vec<_>>())
+        .collect())
+}
+
+impl PackfileProcessor {
+    /// 还原 pack 中 `offset` 处的对象，delta 链一路解到底
+    fn read_pack_entry(&self, pack: &PackIndex, offset: u64) -> Result<ObjectData> {
+        let content = &pack.pack[..pack.pack.len().saturating_sub(algorithm().raw_len())];
+        let mut cursor = Cursor::new(content);
+        cursor.set_position(offset);
+        let obj = self.read_object(&mut cursor, 0)?;
+        match &obj.delta_info {
+            None => Ok(obj),
+            Some(DeltaInfo::OfsLink(back)) => {
+                let base_offset = offset.checked_sub(*back)
+                    .ok_or_else(|| GitError::corrupt_pack(format!("OFS_DELTA at {} points before the pack", offset)))?;
+                let base = self.read_pack_entry(pack, base_offset)?;
+                self.apply_delta(&base, &obj.data)
+            }
+            Some(DeltaInfo::RefLink(base_hash)) => {
+                let base = self.read_object_from_filesystem(&base_hash.to_hex())?;
+                self.apply_delta(&base, &obj.data)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use flate2::{write::ZlibEncoder, Compression};
+    use sha1::{Sha1, Digest};
+    use super::{PackfileProcessor, read_packed_object};
+    use crate::utils::test::{
+        setup_test_git_dir,
+        cmd_seq,
+    };
+
+    /// loose objects under objects/, packs and info are not counted
+    fn loose_objects(gitdir: &Path) -> usize {
+        std::fs::read_dir(gitdir.join("objects")).unwrap()
+            .flatten()
+            .filter(|dir| dir.file_name().len() == 2)
+            .map(|dir| std::fs::read_dir(dir.path()).unwrap().count())
+            .sum()
+    }
+
+    /// every object of a small repo with a few similar blobs, so the pack has deltas
+    fn make_pack() -> Vec<u8> {
+        let temp = setup_test_git_dir();
+        let temp_path = temp.path();
+        let temp_str = temp_path.to_str().unwrap();
+        let git = &["git", "-C", temp_str];
+        let text = (0..200).map(|i| format!("line {}\n", i)).collect::<String>();
+        for i in 0..3 {
+            std::fs::write(temp_path.join("a.txt"), format!("{}version {}\n", text, i)).unwrap();
+            cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "edit"], false)])(git).unwrap();
+        }
+        let objects = Command::new("git").args(["-C", temp_str, "rev-list", "--objects", "--all"]).output().unwrap().stdout;
+        let mut child = Command::new("git")
+            .args(["-C", temp_str, "pack-objects", "--stdout", "--delta-base-offset"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&objects).unwrap();
+        child.wait_with_output().unwrap().stdout
+    }
+
+    fn with_trailer(content: &[u8]) -> Vec<u8> {
+        [content, &Sha1::digest(content)[..]].concat()
+    }
+
+    /// `revs` are given to `git pack-objects --revs`, one per line like `HEAD` or `^HEAD~1`
+    fn pack_objects(repo: &str, revs: &[&str], thin: bool) -> Vec<u8> {
+        let mut args = vec!["-C", repo, "pack-objects", "--revs", "--stdout"];
+        if thin {
+            args.push("--thin");
+        }
+        let mut child = Command::new("git")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(format!("{}\n", revs.join("\n")).as_bytes()).unwrap();
+        child.wait_with_output().unwrap().stdout
+    }
+
+    /// (type, REF_DELTA base, data) of one pack entry
+    type PackEntry<'a> = (u8, Option<[u8; 20]>, &'a [u8]);
+
+    /// a hand made pack, the data of each entry is shorter than 16 bytes
+    fn hand_made_pack(entries: &[PackEntry]) -> Vec<u8> {
+        let mut content = b"PACK".to_vec();
+        content.extend_from_slice(&2u32.to_be_bytes());
+        content.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for (obj_type, base, data) in entries {
+            content.push((obj_type << 4) | data.len() as u8);
+            if let Some(base) = base {
+                content.extend_from_slice(base);
+            }
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            content.extend(encoder.finish().unwrap());
+        }
+        with_trailer(&content)
+    }
+
+    #[test]
+    fn test_process_packfile() {
+        let pack = make_pack();
+        let repo = setup_test_git_dir();
+        let gitdir = repo.path().join(".git");
+
+        let hashes = PackfileProcessor::new(gitdir.clone()).process_packfile(&pack).unwrap();
+        assert_eq!(hashes.len(), 9);
+        assert_eq!(loose_objects(&gitdir), 9);
+        let verify = Command::new("git").args(["-C", repo.path().to_str().unwrap(), "fsck", "--full"]).output().unwrap();
+        assert!(verify.status.success());
+    }
+
+    #[test]
+    fn test_corrupt_packfile() {
+        let pack = make_pack();
+        let content = &pack[..pack.len() - 20];
+
+        let mut flipped = pack.clone();
+        flipped[pack.len() / 2] ^= 0x10;
+
+        // the checksum still matches, the last zlib stream is broken
+        let mut broken_stream = content.to_vec();
+        let last = broken_stream.len() - 1;
+        broken_stream[last] ^= 0x01;
+
+        let cases = [
+            ("truncated", pack[..pack.len() - 30].to_vec()),
+            ("truncated with a valid trailer", with_trailer(&content[..content.len() - 10])),
+            ("bit flipped", flipped),
+            ("bit flipped with a valid trailer", with_trailer(&broken_stream)),
+        ];
+        for (name, data) in cases {
+            let repo = setup_test_git_dir();
+            let gitdir = repo.path().join(".git");
+            let result = PackfileProcessor::new(gitdir.clone()).process_packfile(&data);
+            assert!(result.is_err(), "{}", name);
+            assert!(result.unwrap_err().to_string().starts_with("corrupt packfile"), "{}", name);
+            assert_eq!(loose_objects(&gitdir), 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_thin_packfile() {
+        let source = setup_test_git_dir();
+        let source_str = source.path().to_str().unwrap();
+        let git = &["git", "-C", source_str];
+        let text = (0..200).map(|i| format!("line {}\n", i)).collect::<String>();
+        for i in 0..2 {
+            std::fs::write(source.path().join("a.txt"), format!("{}version {}\n", text, i)).unwrap();
+            cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "edit"], false)])(git).unwrap();
+        }
+        let thin = pack_objects(source_str, &["HEAD", "^HEAD~1"], true);
+
+        // the bases are missing, the error names the one it was looking for
+        let repo = setup_test_git_dir();
+        let gitdir = repo.path().join(".git");
+        let err = PackfileProcessor::new(gitdir.clone()).process_packfile(&thin).unwrap_err().to_string();
+        let blob = Command::new("git").args(["-C", source_str, "rev-parse", "HEAD~1:a.txt"]).output().unwrap().stdout;
+        assert!(err.contains(String::from_utf8_lossy(&blob).trim()), "{}", err);
+        assert_eq!(loose_objects(&gitdir), 0);
+
+        // with the first commit already fetched the thin pack is completed from the repository
+        PackfileProcessor::new(gitdir.clone()).process_packfile(&pack_objects(source_str, &["HEAD~1"], false)).unwrap();
+        PackfileProcessor::new(gitdir.clone()).process_packfile(&thin).unwrap();
+        assert_eq!(loose_objects(&gitdir), 6);
+        let verify = Command::new("git").args(["-C", repo.path().to_str().unwrap(), "fsck", "--full"]).output().unwrap();
+        assert!(verify.status.success());
+    }
+
+    #[test]
+    fn test_ref_delta_base_later_in_pack() {
+        let base: &[u8] = b"hello world\n";
+        let base_hash: [u8; 20] = Sha1::digest(b"blob 12\0hello world\n").into();
+        // base size 12, result size 6: copy "hello" from offset 0, insert "\n"
+        let delta: &[u8] = &[12, 6, 0x90, 5, 0x01, b'\n'];
+
+        let repo = setup_test_git_dir();
+        let gitdir = repo.path().join(".git");
+        let pack = hand_made_pack(&[(7, Some(base_hash), delta), (3, None, base)]);
+        let hashes = PackfileProcessor::new(gitdir.clone()).process_packfile(&pack).unwrap();
+        assert_eq!(hashes.len(), 2);
+        let content = Command::new("git").args(["-C", repo.path().to_str().unwrap(), "cat-file", "-p", &hashes[0]]).output().unwrap().stdout;
+        assert_eq!(content, b"hello\n");
+
+        // the result size in the delta header doesn't match what the delta produces
+        let repo = setup_test_git_dir();
+        let gitdir = repo.path().join(".git");
+        let bad_delta: &[u8] = &[12, 7, 0x90, 5, 0x01, b'\n'];
+        let pack = hand_made_pack(&[(3, None, base), (7, Some(base_hash), bad_delta)]);
+        let err = PackfileProcessor::new(gitdir.clone()).process_packfile(&pack).unwrap_err().to_string();
+        assert!(err.contains(&hex::encode(base_hash)), "{}", err);
+        assert_eq!(loose_objects(&gitdir), 0);
+    }
+
+    #[test]
+    fn test_store_packfile() {
+        let source = setup_test_git_dir();
+        let source_str = source.path().to_str().unwrap();
+        let git = &["git", "-C", source_str];
+        let text = (0..200).map(|i| format!("line {}\n", i)).collect::<String>();
+        for i in 0..2 {
+            std::fs::write(source.path().join("a.txt"), format!("{}version {}\n", text, i)).unwrap();
+            cmd_seq(&[(&["add", "a.txt"], false), (&["commit", "-m", "edit"], false)])(git).unwrap();
+        }
+
+        let repo = setup_test_git_dir();
+        let gitdir = repo.path().join(".git");
+        PackfileProcessor::new(gitdir.clone()).store_packfile(&pack_objects(source_str, &["HEAD~1"], false)).unwrap();
+        // the missing bases of the thin pack are copied out of the first pack
+        PackfileProcessor::new(gitdir.clone()).store_packfile(&pack_objects(source_str, &["HEAD", "^HEAD~1"], true)).unwrap();
+        assert_eq!(loose_objects(&gitdir), 0);
+
+        let idx_files = std::fs::read_dir(gitdir.join("objects/pack")).unwrap()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+            .collect::<Vec<_>>();
+        assert_eq!(idx_files.len(), 2);
+        for idx in &idx_files {
+            let verify = Command::new("git").args(["verify-pack", "-v"]).arg(idx).output().unwrap();
+            assert!(verify.status.success(), "{}", String::from_utf8_lossy(&verify.stderr));
+        }
+
+        // every object reads back the same as git sees it
+        let objects = Command::new("git").args(["-C", source_str, "rev-list", "--objects", "--all"]).output().unwrap().stdout;
+        for line in String::from_utf8_lossy(&objects).lines() {
+            let hash = &line[..40];
+            let raw = read_packed_object(&gitdir, hash).unwrap().unwrap();
+            let content = Command::new("git").args(["-C", source_str, "cat-file", "-p", hash]).output().unwrap().stdout;
+            let kind = Command::new("git").args(["-C", source_str, "cat-file", "-t", hash]).output().unwrap().stdout;
+            let body = &raw[raw.iter().position(|&b| b == 0).unwrap() + 1..];
+            assert!(raw.starts_with(String::from_utf8_lossy(&kind).trim().as_bytes()));
+            if !kind.starts_with(b"tree") {
+                assert_eq!(body, &content[..]);
+            }
+        }
+    }
+}
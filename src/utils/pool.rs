@@ -0,0 +1,93 @@
+use std::{
+    error::Error,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{GitError, Result};
+
+/// one worker per cpu, for work that is mostly hashing, compressing and writing small files
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// run `f` on every item across `threads` workers, the results come back in input order
+/// after the first error no new item is started, and the error of the earliest failed item is returned
+pub fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let cancelled = AtomicBool::new(false);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, items.len().max(1)) {
+            scope.spawn(|| {
+                while !cancelled.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    let result = f(item).map_err(sendable);
+                    if result.is_err() {
+                        cancelled.store(true, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap().push((i, result));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter()
+        .map(|(_, result)| result.map_err(|e| e as Box<dyn Error>))
+        .collect()
+}
+
+/// Box<dyn Error> is not Send, a worker's error is unboxed to what it really is to cross the threads:
+/// ours and io errors come back whole, with their kind, exit code and source, anything else as its message
+fn sendable(err: Box<dyn Error>) -> Box<dyn Error + Send + Sync> {
+    let err = match err.downcast::<GitError>() {
+        Ok(err) => return err,
+        Err(err) => err,
+    };
+    match err.downcast::<io::Error>() {
+        Ok(err) => err,
+        Err(err) => err.to_string().into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use crate::utils::error::{ErrorKind, GitError};
+    use super::parallel_map;
+
+    #[test]
+    fn test_errors_cross_threads_whole() {
+        let items = (0..100).collect::<Vec<_>>();
+        let err = parallel_map(&items, 4, |&i| match i {
+            40 => Err(GitError::missing_object("40")),
+            _ => Ok(i),
+        }).unwrap_err();
+        let err = err.downcast_ref::<GitError>().unwrap();
+        assert!(matches!(&err.kind, ErrorKind::MissingObject(hash) if hash == "40"));
+        assert_eq!(err.exit_code(), 128);
+
+        let err = parallel_map(&items, 4, |&i| match i {
+            7 => Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only").into()),
+            _ => Ok(i),
+        }).unwrap_err();
+        assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+        assert_eq!(parallel_map(&items, 4, |&i| Ok(i * 2)).unwrap(), items.iter().map(|i| i * 2).collect::<Vec<_>>());
+    }
+}
@@ -1,69 +1,437 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use crate::{GitError, Result};
+use crate::utils::config::Config;
+use crate::utils::credential::{Credential, send_with_credentials};
+use crate::utils::hash::{algorithm, ObjectId};
+use crate::utils::http::http_client;
 use reqwest::blocking::Client;
 use std::time::Duration;
 
+/// pkt-line 编解码，fetch 和 push 共用
+/// 每个包是 4 位十六进制长度（含这 4 个字节）加内容，0000 是 flush，0001 是 delim，0002 是 response-end
+pub mod pkt_line {
+    use crate::{GitError, Result};
+
+    /// 一个包的最大长度，含长度前缀
+    pub const MAX_PKT_LEN: usize = 65520;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Packet<'a> {
+        Data(&'a [u8]),
+        Flush,
+        Delim,
+        ResponseEnd,
+    }
+
+    impl<'a> Packet<'a> {
+        /// 文本包去掉结尾的一个换行
+        pub fn text(&self) -> Option<&'a [u8]> {
+            match self {
+                Packet::Data(data) => Some(data.strip_suffix(b"\n").unwrap_or(data)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct PktLineWriter {
+        buf: Vec<u8>,
+    }
+
+    impl PktLineWriter {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn data(&mut self, data: &[u8]) -> Result<&mut Self> {
+            if data.len() + 4 > MAX_PKT_LEN {
+                return Err(GitError::protocol_error(&format!("{} bytes do not fit in a pkt-line", data.len())));
+            }
+            self.buf.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+            self.buf.extend_from_slice(data);
+            Ok(self)
+        }
+
+        pub fn line(&mut self, line: &str) -> Result<&mut Self> {
+            self.data(line.as_bytes())
+        }
+
+        pub fn flush(&mut self) -> &mut Self {
+            self.buf.extend_from_slice(b"0000");
+            self
+        }
+
+        pub fn delim(&mut self) -> &mut Self {
+            self.buf.extend_from_slice(b"0001");
+            self
+        }
+
+        /// 不经过 pkt-line 的原始数据，比如 push 时跟在命令后面的 packfile
+        pub fn raw(&mut self, data: &[u8]) -> &mut Self {
+            self.buf.extend_from_slice(data);
+            self
+        }
+
+        pub fn into_bytes(self) -> Vec<u8> {
+            self.buf
+        }
+    }
+
+    /// 逐个读出包，出错之后不再继续
+    pub struct PktLineReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> PktLineReader<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            Self { data, pos: 0 }
+        }
+
+        /// 还没读的部分，比如不带 side-band 时跟在 NAK 后面的 pack
+        pub fn rest(&self) -> &'a [u8] {
+            &self.data[self.pos..]
+        }
+    }
+
+    impl<'a> Iterator for PktLineReader<'a> {
+        type Item = Result<Packet<'a>>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.pos >= self.data.len() {
+                return None;
+            }
+            let length = self.data.get(self.pos..self.pos + 4)
+                .and_then(|len| std::str::from_utf8(len).ok())
+                .and_then(|len| usize::from_str_radix(len, 16).ok());
+            let packet = match length {
+                Some(0) => Ok((Packet::Flush, 4)),
+                Some(1) => Ok((Packet::Delim, 4)),
+                Some(2) => Ok((Packet::ResponseEnd, 4)),
+                Some(len) if len < 4 => Err(GitError::protocol_error(&format!("invalid pkt-line length {}", len))),
+                Some(len) if self.pos + len > self.data.len() => Err(GitError::protocol_error("truncated pkt-line")),
+                Some(len) => Ok((Packet::Data(&self.data[self.pos + 4..self.pos + len]), len)),
+                None => Err(GitError::protocol_error("invalid pkt-line length")),
+            };
+            match packet {
+                Ok((packet, len)) => {
+                    self.pos += len;
+                    Some(Ok(packet))
+                }
+                Err(e) => {
+                    self.pos = self.data.len();
+                    Some(Err(e))
+                }
+            }
+        }
+    }
+}
+
+use pkt_line::{Packet, PktLineReader, PktLineWriter};
+
+/// a hash sent by the server, anything else in its place is a protocol error
+fn parse_hash(hash: &str) -> Result<ObjectId> {
+    ObjectId::from_hex(hash).map_err(|_| GitError::protocol_error(&format!("invalid object id: {}", hash)))
+}
+
+/// 解析 info/refs 的引用广告：可选的 `# service=...` 和 flush，然后每个包一个 `hash ref[\0capabilities]`
+pub fn parse_ref_advertisement(body: &[u8]) -> Result<Vec<RemoteRef>> {
+    let mut refs: Vec<RemoteRef> = Vec::new();
+    let mut reader = PktLineReader::new(body).peekable();
+
+    // smart http 的服务声明和后面的 flush
+    if let Some(Ok(Packet::Data(first))) = reader.peek() && first.starts_with(b"# service=") {
+        reader.next();
+        if let Some(Ok(Packet::Flush)) = reader.peek() {
+            reader.next();
+        }
+    }
+
+    let mut symrefs = Vec::new();
+    for packet in reader {
+        let packet = packet?;
+        let Some(line) = packet.text() else {
+            break;
+        };
+        // 能力声明在 \0 之后，symref=HEAD:refs/heads/main 说明 HEAD 指向哪个分支
+        let mut parts = line.splitn(2, |&b| b == 0);
+        let line = parts.next().unwrap_or_default();
+        if let Some(capabilities) = parts.next() {
+            symrefs.extend(String::from_utf8_lossy(capabilities)
+                .split_whitespace()
+                .filter_map(|capability| capability.strip_prefix("symref=")?.split_once(':'))
+                .map(|(name, target)| (name.to_string(), target.to_string())));
+        }
+        let line = String::from_utf8_lossy(line);
+        let Some((hash, ref_name)) = line.split_once(' ') else {
+            return Err(GitError::protocol_error(&format!("invalid ref advertisement: {}", line)));
+        };
+        // 空仓库只有 capabilities^{}
+        if ref_name == "capabilities^{}" {
+            continue;
+        }
+        // 处理peeled引用（^{}）
+        if ref_name.ends_with("^{}") {
+            if let Some(last_ref) = refs.last_mut() {
+                last_ref.peeled = Some(parse_hash(hash)?);
+            }
+        } else {
+            refs.push(RemoteRef {
+                name: ref_name.to_string(),
+                hash: parse_hash(hash)?,
+                peeled: None,
+                symref: None,
+            });
+        }
+    }
+    for (name, target) in symrefs {
+        if let Some(remote_ref) = refs.iter_mut().find(|remote_ref| remote_ref.name == name) {
+            remote_ref.symref = Some(target);
+        }
+    }
+    Ok(refs)
+}
+
+/// info/refs 的回答：v0 直接列出引用，v2 只有能力，引用要另外用 ls-refs 要
+#[derive(Debug)]
+enum Advertisement {
+    V0(Vec<RemoteRef>),
+    V2(Vec<String>),
+}
+
+/// 服务器认识 `Git-Protocol: version=2` 时回答 `version 2` 和能力列表，否则是 v0 的引用广告
+/// v2 前面的 `# service=` 行可有可无，v0 必须有
+fn parse_advertisement(body: &[u8]) -> Result<Advertisement> {
+    let mut reader = PktLineReader::new(body).peekable();
+    let mut service = false;
+    if let Some(Ok(first)) = reader.peek() && first.text().is_some_and(|line| line.starts_with(b"# service=")) {
+        if first.text() != Some(b"# service=git-upload-pack") {
+            return Err(GitError::protocol_error("Invalid refs response"));
+        }
+        service = true;
+        reader.next();
+        if let Some(Ok(Packet::Flush)) = reader.peek() {
+            reader.next();
+        }
+    }
+    if let Some(Ok(packet)) = reader.peek() && packet.text() == Some(b"version 2") {
+        reader.next();
+        let mut capabilities = Vec::new();
+        for packet in reader {
+            let Some(line) = packet?.text() else {
+                break;
+            };
+            capabilities.push(String::from_utf8_lossy(line).to_string());
+        }
+        return Ok(Advertisement::V2(capabilities));
+    }
+    if !service {
+        return Err(GitError::protocol_error("Invalid refs response"));
+    }
+    Ok(Advertisement::V0(parse_ref_advertisement(body)?))
+}
+
+/// v2 ls-refs 的回答：每行 `hash name`，后面可能跟着 `symref-target:<ref>` 和 `peeled:<hash>`
+fn parse_ls_refs(body: &[u8]) -> Result<Vec<RemoteRef>> {
+    let mut refs = Vec::new();
+    for packet in PktLineReader::new(body) {
+        let Some(line) = packet?.text() else {
+            break;
+        };
+        let line = String::from_utf8_lossy(line);
+        let mut fields = line.split(' ');
+        let (Some(hash), Some(name)) = (fields.next(), fields.next()) else {
+            return Err(GitError::protocol_error(&format!("invalid ls-refs line: {}", line)));
+        };
+        let (mut peeled, mut symref) = (None, None);
+        for field in fields {
+            if let Some(hash) = field.strip_prefix("peeled:") {
+                peeled = Some(parse_hash(hash)?);
+            } else if let Some(target) = field.strip_prefix("symref-target:") {
+                symref = Some(target.to_string());
+            }
+        }
+        refs.push(RemoteRef { name: name.to_string(), hash: parse_hash(hash)?, peeled, symref });
+    }
+    Ok(refs)
+}
+
+/// v2 fetch 的回答，由 delim 分开的几节：acknowledgments、shallow-info、wanted-refs、packfile
+#[derive(Debug, Default)]
+struct FetchReply {
+    acks: Vec<ObjectId>,
+    shallow: Vec<ObjectId>,
+    unshallow: Vec<ObjectId>,
+    /// 只有服务器 ready 了或者请求里有 done 才会有 packfile 这一节
+    pack: Option<Vec<u8>>,
+}
+
+fn parse_fetch_reply(body: &[u8]) -> Result<FetchReply> {
+    let mut reply = FetchReply::default();
+    let mut section: Option<String> = None;
+    for packet in PktLineReader::new(body) {
+        let data = match packet? {
+            Packet::Data(data) => data,
+            Packet::Delim => {
+                section = None;
+                continue;
+            },
+            Packet::Flush | Packet::ResponseEnd => break,
+        };
+        if data.starts_with(b"ERR ") {
+            return Err(GitError::protocol_error(&format!("Remote error: {}", String::from_utf8_lossy(&data[4..]).trim_end())));
+        }
+        let Some(name) = &section else {
+            let header = String::from_utf8_lossy(data.strip_suffix(b"\n").unwrap_or(data)).to_string();
+            if header == "packfile" {
+                reply.pack = Some(Vec::new());
+            }
+            section = Some(header);
+            continue;
+        };
+        let line = || String::from_utf8_lossy(data.strip_suffix(b"\n").unwrap_or(data)).to_string();
+        match name.as_str() {
+            "packfile" => sideband(data, reply.pack.get_or_insert_default())?,
+            "acknowledgments" => if let Some(hash) = line().strip_prefix("ACK ") {
+                reply.acks.push(parse_hash(hash)?);
+            },
+            "shallow-info" => match line().split_once(' ') {
+                Some(("shallow", hash)) => reply.shallow.push(parse_hash(hash)?),
+                Some(("unshallow", hash)) => reply.unshallow.push(parse_hash(hash)?),
+                _ => {},
+            },
+            // wanted-refs 和 packfile-uris 用不上
+            _ => {},
+        }
+    }
+    Ok(reply)
+}
+
+/// side-band-64k 的一个包：band 1 是 pack 数据，2 是进度，3 是错误
+fn sideband(data: &[u8], pack: &mut Vec<u8>) -> Result<()> {
+    match data.first() {
+        Some(1) => pack.extend_from_slice(&data[1..]),
+        Some(2) => print!("remote: {}", String::from_utf8_lossy(&data[1..])),
+        Some(3) => {
+            return Err(GitError::protocol_error(&format!("Remote error: {}", String::from_utf8_lossy(&data[1..]))));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// 每一轮协商最多发送的 have 数量
+const HAVES_PER_ROUND: usize = 32;
+
 /// Git 网络协议支持
 pub struct GitProtocol {
     client: Client,
+    credential: RefCell<Option<Credential>>,
 }
 
 #[derive(Debug)]
 pub struct RemoteRef {
     pub name: String,
-    pub hash: String,
-    pub peeled: Option<String>, // 对于带注释的tag
+    pub hash: ObjectId,
+    pub peeled: Option<ObjectId>, // 对于带注释的tag
+    /// 符号引用指向的引用，服务器一般只告诉 HEAD 的
+    pub symref: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct PackfileData {
     pub data: Vec<u8>,
     pub refs: Vec<RemoteRef>,
+    /// 服务器给的新边界提交和不再是边界的提交
+    pub shallow: Vec<ObjectId>,
+    pub unshallow: Vec<ObjectId>,
+}
+
+/// shallow 抓取：本地已有的边界提交，`depth` 为 None 时不发 deepen
+#[derive(Debug, Default)]
+pub struct Deepen {
+    pub shallow: Vec<ObjectId>,
+    pub depth: Option<u32>,
 }
 
+/// `deepen` 的这个值表示要完整的历史，和 git fetch --unshallow 一样
+pub const INFINITE_DEPTH: u32 = 0x7fffffff;
+
 impl GitProtocol {
-    pub fn new() -> Result<Self> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .user_agent("git/2.0.0 (custom)")
-            .build()
-            .map_err(|e| GitError::network_error(format!("Failed to create HTTP client: {}", e)))?;
-        
-        Ok(GitProtocol { client })
+    /// 代理和证书按 `config` 设置，见 [`http_client`]
+    pub fn new(config: &Config, url: &str) -> Result<Self> {
+        let client = http_client(config, url)?;
+        Ok(GitProtocol { client, credential: RefCell::new(None) })
     }
     
     /// HTTP(S) Git Smart Protocol 实现
-    pub fn fetch_via_http(&self, url: &str, refs_wanted: &[String]) -> Result<PackfileData> {
-        // 第一步：获取远程引用列表
-        let refs = self.discover_refs_http(url)?;
-        
-        // 第二步：计算需要的对象
-        let wants = self.calculate_wants(&refs, refs_wanted)?;
+    /// `haves` are local commits, newest first, the server leaves out everything reachable from them
+    /// `deepen` 是 shallow 抓取的参数，不需要时用 `Deepen::default()`
+    /// 服务器支持时用 protocol v2，否则退回 v0，两者得到的 `PackfileData` 一样
+    pub fn fetch_via_http(&self, url: &str, refs_wanted: &[String], haves: &[ObjectId], deepen: &Deepen) -> Result<PackfileData> {
+        // 第一步：获取远程引用列表，v2 只要 refspec 涉及的那些
+        let (refs, v2) = match self.advertise(url)? {
+            Advertisement::V0(refs) => (refs, None),
+            Advertisement::V2(capabilities) => {
+                let prefixes = if refs_wanted.is_empty() { vec!["refs/heads/".to_string()] } else { refs_wanted.to_vec() };
+                (self.ls_refs(url, &capabilities, &prefixes)?, Some(capabilities))
+            },
+        };
+        
+        // 第二步：计算需要的对象，已经有的提交不用再要
+        // 加深历史时已有的 tip 也要 want，服务器才会补上下面缺的提交
+        let wants = self.calculate_wants(&refs, refs_wanted)?
+            .into_iter()
+            .filter(|want| deepen.depth.is_some() || !haves.contains(want))
+            .collect::<Vec<_>>();
         
         if wants.is_empty() {
             return Ok(PackfileData {
                 data: Vec::new(),
                 refs,
+                shallow: Vec::new(),
+                unshallow: Vec::new(),
             });
         }
         
-        // 第三步：请求packfile
-        let packfile = self.upload_pack_http(url, &wants)?;
+        if let Some(capabilities) = v2 {
+            let reply = self.fetch_v2(url, &capabilities, &wants, haves, deepen)?;
+            return Ok(PackfileData {
+                data: reply.pack.unwrap_or_default(),
+                refs,
+                shallow: reply.shallow,
+                unshallow: reply.unshallow,
+            });
+        }
+        
+        // 第三步：协商共同的提交，再请求packfile
+        let common = self.negotiate_http(url, &wants, haves, deepen)?;
+        let response = self.upload_pack_http(url, &wants, &common, deepen)?;
+        let (shallow, unshallow) = Self::parse_shallow_update(&response)?;
         
         Ok(PackfileData {
-            data: packfile,
+            data: self.extract_packfile_from_response(&response)?,
             refs,
+            shallow,
+            unshallow,
         })
     }
     
-    fn discover_refs_http(&self, base_url: &str) -> Result<Vec<RemoteRef>> {
+    /// 远程的全部引用，v2 的服务器再用一次 ls-refs
+    pub fn discover_refs_http(&self, base_url: &str) -> Result<Vec<RemoteRef>> {
+        match self.advertise(base_url)? {
+            Advertisement::V0(refs) => Ok(refs),
+            Advertisement::V2(capabilities) => self.ls_refs(base_url, &capabilities, &[]),
+        }
+    }
+
+    /// GET info/refs，请求 v2，不认识的服务器会忽略这个头，照旧回答 v0
+    fn advertise(&self, base_url: &str) -> Result<Advertisement> {
         let url = format!("{}/info/refs?service=git-upload-pack", base_url);
-        
-        let response = self.client
-            .get(&url)
-            // 不设置协议版本，使用默认
-            .send()
-            .map_err(|e| GitError::network_error(format!("Failed to discover refs: {}", e)))?;
+        let response = send_with_credentials(base_url, &self.credential, || {
+            self.client.get(&url).header("Git-Protocol", "version=2")
+        })?;
         
         if !response.status().is_success() {
             return Err(GitError::network_error(format!(
@@ -73,127 +441,84 @@ impl GitProtocol {
             )));
         }
         
-        let body = response.text()
-            .map_err(|e| GitError::network_error(format!("Failed to read response: {}", e)))?;
+        let body = response.bytes()
+            .map_err(|e| GitError::network_failure(format!("Failed to read response: {}", e), e))?;
         
-        self.parse_refs_response(&body)
+        parse_advertisement(&body)
     }
-    
-    fn parse_refs_response(&self, body: &str) -> Result<Vec<RemoteRef>> {
-        //println!("DEBUG: Parsing refs response, body length: {}", body.len());
-        //println!("DEBUG: First 200 chars: {:?}", &body[..std::cmp::min(200, body.len())]);
-        
-        let mut refs: Vec<RemoteRef> = Vec::new();
-        
-        // 使用 pkt-line 格式解析
-        let mut pos = 0;
-        let body_bytes = body.as_bytes();
-        
-        // 跳过第一个服务声明包
-        if let Some(first_packet) = self.read_pkt_line(&body_bytes, &mut pos) {
-            let first_line = String::from_utf8_lossy(&first_packet);
-            //println!("DEBUG: First packet: {:?}", first_line);
-            if !first_line.contains("git-upload-pack") {
-                return Err(GitError::protocol_error("Invalid refs response"));
-            }
+
+    /// v2 命令的开头：`command=<name>`，服务器要求时带上对象格式，然后 delim
+    fn command_v2(writer: &mut PktLineWriter, command: &str, capabilities: &[String]) -> Result<()> {
+        writer.line(&format!("command={}\n", command))?;
+        if capabilities.iter().any(|capability| capability.starts_with("object-format=")) {
+            writer.line(&format!("object-format={}\n", algorithm().name()))?;
         }
-        
-        // 跳过第一个 flush packet（服务声明后的分隔符）
-        if let Some(packet_data) = self.read_pkt_line(&body_bytes, &mut pos) {
-            if packet_data.is_empty() {
-                //println!("DEBUG: Skipped first flush packet");
-            } else {
-                // 如果不是 flush，回退位置并处理
-                pos -= 4;
-            }
+        writer.delim();
+        Ok(())
+    }
+
+    /// v2 ls-refs，`prefixes` 为空时列出全部引用，annotated tag 带上 peel 之后的对象
+    fn ls_refs(&self, base_url: &str, capabilities: &[String], prefixes: &[String]) -> Result<Vec<RemoteRef>> {
+        let mut writer = PktLineWriter::new();
+        Self::command_v2(&mut writer, "ls-refs", capabilities)?;
+        writer.line("peel\n")?.line("symrefs\n")?;
+        for prefix in prefixes {
+            writer.line(&format!("ref-prefix {}\n", prefix))?;
         }
-        
-        // 读取引用包
-        //let mut packet_count = 0;
-        while pos < body_bytes.len() {
-            if let Some(packet_data) = self.read_pkt_line(&body_bytes, &mut pos) {
-                //packet_count += 1;
-                if packet_data.is_empty() {
-                    //println!("DEBUG: Found final flush packet at packet {}", packet_count);
-                break;
-            }
-            
-                let line = String::from_utf8_lossy(&packet_data);
-                //println!("DEBUG: Packet {}: {:?}", packet_count, line);
-                
-                // 解析引用行：hash ref_name [capabilities]
-                let line = if let Some(null_pos) = line.find('\0') {
-                    &line[..null_pos] // 移除能力声明
-                } else {
-                    &line
-                };
-                
-                let parts: Vec<&str> = line.trim().split_whitespace().collect();
-                if parts.len() >= 2 {
-                    let hash = parts[0].to_string();
-                    let ref_name = parts[1].to_string();
-                    
-                    //println!("DEBUG: Found ref: {} -> {}", ref_name, hash);
-                    
-                    // 处理peeled引用（^{}）
-                    if ref_name.ends_with("^{}") {
-                        if let Some(last_ref) = refs.last_mut() {
-                            last_ref.peeled = Some(hash);
-                        }
-                    } else {
-                    refs.push(RemoteRef {
-                            name: ref_name,
-                            hash,
-                        peeled: None,
-                    });
-                    }
-                }
-            } else {
-                break;
-                }
+        writer.flush();
+        parse_ls_refs(&self.post_upload_pack(base_url, writer.into_bytes(), true)?)
+    }
+
+    /// v2 fetch 请求：wants，shallow 抓取的参数，haves，最后可能有 done
+    fn fetch_request_v2(capabilities: &[String], wants: &[ObjectId], haves: &[ObjectId], deepen: &Deepen, done: bool) -> Result<Vec<u8>> {
+        let mut writer = PktLineWriter::new();
+        Self::command_v2(&mut writer, "fetch", capabilities)?;
+        writer.line("thin-pack\n")?.line("ofs-delta\n")?;
+        for want in wants {
+            writer.line(&format!("want {}\n", want))?;
+        }
+        if let Some(depth) = deepen.depth {
+            for hash in &deepen.shallow {
+                writer.line(&format!("shallow {}\n", hash))?;
             }
-            
-        //println!("DEBUG: Total refs found: {}", refs.len());
-        for r in &refs {
-            println!("DEBUG: Ref: {} -> {}", r.name, r.hash);
+            writer.line(&format!("deepen {}\n", depth))?;
         }
-        
-        Ok(refs)
-    }
-    
-    fn read_pkt_line(&self, data: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
-        if *pos + 4 > data.len() {
-            return None;
+        for have in haves {
+            writer.line(&format!("have {}\n", have))?;
         }
-        
-        // 读取长度
-        let len_bytes = &data[*pos..*pos + 4];
-        let len_str = std::str::from_utf8(len_bytes).ok()?;
-        let packet_len = u16::from_str_radix(len_str, 16).ok()?;
-        
-        *pos += 4;
-        
-        if packet_len == 0 {
-            // flush packet
-            return Some(Vec::new());
+        if done {
+            writer.line("done\n")?;
         }
-        
-        if packet_len < 4 {
-            return None;
+        writer.flush();
+        Ok(writer.into_bytes())
+    }
+
+    /// v2 的协商和 v0 一样每轮重发 wants 和已经确认的共同提交，
+    /// 服务器 ready 时直接在这一轮的回答里给出 packfile，否则最后带 done 再要一次
+    fn fetch_v2(&self, base_url: &str, capabilities: &[String], wants: &[ObjectId], haves: &[ObjectId], deepen: &Deepen) -> Result<FetchReply> {
+        let mut common: Vec<ObjectId> = Vec::new();
+        for batch in haves.chunks(HAVES_PER_ROUND) {
+            let haves = common.iter().chain(batch).cloned().collect::<Vec<_>>();
+            let request = Self::fetch_request_v2(capabilities, wants, &haves, deepen, false)?;
+            let reply = parse_fetch_reply(&self.post_upload_pack(base_url, request, true)?)?;
+            if reply.pack.is_some() {
+                return Ok(reply);
+            }
+            for hash in reply.acks {
+                if !common.contains(&hash) {
+                    common.push(hash);
+                }
+            }
         }
-        
-        let content_len = packet_len as usize - 4;
-        if *pos + content_len > data.len() {
-            return None;
+        let request = Self::fetch_request_v2(capabilities, wants, &common, deepen, true)?;
+        let reply = parse_fetch_reply(&self.post_upload_pack(base_url, request, true)?)?;
+        if reply.pack.is_none() {
+            return Err(GitError::protocol_error("the fetch response has no packfile section"));
         }
-        
-        let content = data[*pos..*pos + content_len].to_vec();
-        *pos += content_len;
-        
-        Some(content)
+        Ok(reply)
     }
     
-    fn calculate_wants(&self, refs: &[RemoteRef], wanted_refs: &[String]) -> Result<Vec<String>> {
+    fn calculate_wants(&self, refs: &[RemoteRef], wanted_refs: &[String]) -> Result<Vec<ObjectId>> {
         let mut wants = Vec::new();
         
         //println!("DEBUG: calculate_wants called with {} refs, {} wanted_refs", refs.len(), wanted_refs.len());
@@ -205,7 +530,7 @@ impl GitProtocol {
             // 如果没有指定特定引用，获取所有heads
             for ref_info in refs {
                 if ref_info.name.starts_with("refs/heads/") {
-                    wants.push(ref_info.hash.clone());
+                    wants.push(ref_info.hash);
                     //println!("DEBUG: Want ref: {} -> {}", ref_info.name, ref_info.hash);
                 }
             }
@@ -213,7 +538,7 @@ impl GitProtocol {
             // 获取指定的引用
             for wanted in wanted_refs {
                 if let Some(ref_info) = refs.iter().find(|r| r.name == *wanted) {
-                    wants.push(ref_info.hash.clone());
+                    wants.push(ref_info.hash);
                     //println!("DEBUG: Want specific ref: {} -> {}", ref_info.name, ref_info.hash);
                 }
             }
@@ -224,50 +549,60 @@ impl GitProtocol {
         Ok(wants)
     }
     
-    fn upload_pack_http(&self, base_url: &str, wants: &[String]) -> Result<Vec<u8>> {
-        //println!("DEBUG: upload_pack_http called with {} wants", wants.len());
-        // for want in wants {
-        //     println!("DEBUG: Want: {}", want);
-        // }
-        
-        let url = format!("{}/git-upload-pack", base_url);
-        //println!("DEBUG: POST URL: {}", url);
-        
-        // 构建upload-pack请求体
-        let mut request_body = Vec::new();
-        
-        // 添加能力和第一个want
-        let caps = "multi_ack_detailed side-band-64k thin-pack ofs-delta";
-        if !wants.is_empty() {
-            let first_want = format!("want {} {}\n", wants[0], caps);
-            //println!("DEBUG: First want line: {:?}", first_want);
-            request_body.extend_from_slice(&self.encode_pkt_line(&first_want));
-            
-            // 添加其他want行
-            for want in &wants[1..] {
-                let want_line = format!("want {}\n", want);
-                //println!("DEBUG: Additional want line: {:?}", want_line);
-                request_body.extend_from_slice(&self.encode_pkt_line(&want_line));
+    /// want 行，第一行带上能力声明；shallow 抓取时接着是本地的边界提交和 deepen，最后 flush
+    fn request_head(&self, writer: &mut PktLineWriter, wants: &[ObjectId], deepen: &Deepen) -> Result<()> {
+        let caps = if deepen.depth.is_some() {
+            "multi_ack_detailed side-band-64k thin-pack ofs-delta shallow"
+        } else {
+            "multi_ack_detailed side-band-64k thin-pack ofs-delta"
+        };
+        for (i, want) in wants.iter().enumerate() {
+            if i == 0 {
+                writer.line(&format!("want {} {}\n", want, caps))?;
+            } else {
+                writer.line(&format!("want {}\n", want))?;
             }
         }
-        
-        // 添加flush包
-        request_body.extend_from_slice(b"0000");
-        
-        // 添加done（表示我们没有对象要提供）
-        request_body.extend_from_slice(&self.encode_pkt_line("done\n"));
-        
-        //println!("DEBUG: Request body length: {}", request_body.len());
-        //println!("DEBUG: Request body: {:?}", String::from_utf8_lossy(&request_body));
-        
-        let response = self.client
-            .post(&url)
-            .header("Content-Type", "application/x-git-upload-pack-request")
-            .body(request_body)
-            .send()
-            .map_err(|e| GitError::network_error(format!("Failed to upload-pack: {}", e)))?;
-        
-        //println!("DEBUG: Response status: {}", response.status());
+        if let Some(depth) = deepen.depth {
+            for hash in &deepen.shallow {
+                writer.line(&format!("shallow {}\n", hash))?;
+            }
+            writer.line(&format!("deepen {}\n", depth))?;
+        }
+        writer.flush();
+        Ok(())
+    }
+
+    /// 请求里有 deepen 时，响应以 `shallow <hash>` / `unshallow <hash>` 开头，到 flush 为止
+    fn parse_shallow_update(response: &[u8]) -> Result<(Vec<ObjectId>, Vec<ObjectId>)> {
+        let mut shallow = Vec::new();
+        let mut unshallow = Vec::new();
+        for packet in PktLineReader::new(response) {
+            let Some(line) = packet?.text() else {
+                break;
+            };
+            let line = String::from_utf8_lossy(line);
+            if let Some(hash) = line.strip_prefix("shallow ") {
+                shallow.push(parse_hash(hash)?);
+            } else if let Some(hash) = line.strip_prefix("unshallow ") {
+                unshallow.push(parse_hash(hash)?);
+            } else {
+                break;
+            }
+        }
+        Ok((shallow, unshallow))
+    }
+
+    /// `v2` 的请求要带上 `Git-Protocol`，服务器才会按命令来理解
+    fn post_upload_pack(&self, base_url: &str, request_body: Vec<u8>, v2: bool) -> Result<Vec<u8>> {
+        let url = format!("{}/git-upload-pack", base_url);
+        let response = send_with_credentials(base_url, &self.credential, || {
+            let request = self.client
+                .post(&url)
+                .header("Content-Type", "application/x-git-upload-pack-request")
+                .body(request_body.clone());
+            if v2 { request.header("Git-Protocol", "version=2") } else { request }
+        })?;
         
         if !response.status().is_success() {
             return Err(GitError::network_error(format!(
@@ -278,110 +613,215 @@ impl GitProtocol {
         }
         
         let body = response.bytes()
-            .map_err(|e| GitError::network_error(format!("Failed to read packfile: {}", e)))?;
-        
-        //println!("DEBUG: Response body length: {}", body.len());
-        if body.len() > 0 {
-            //println!("DEBUG: First 100 bytes: {:?}", &body[..std::cmp::min(100, body.len())]);
+            .map_err(|e| GitError::network_failure(format!("Failed to read packfile: {}", e), e))?;
+        Ok(body.to_vec())
+    }
+
+    /// multi_ack_detailed 协商，http 是无状态的，每一轮都重发 wants 和已经确认的共同提交
+    /// the server answers `ACK <hash> common` for commits it has too and `ACK <hash> ready`
+    /// once it can build a small pack, each round ends with NAK
+    fn negotiate_http(&self, base_url: &str, wants: &[ObjectId], haves: &[ObjectId], deepen: &Deepen) -> Result<Vec<ObjectId>> {
+        let mut common: Vec<ObjectId> = Vec::new();
+        for batch in haves.chunks(HAVES_PER_ROUND) {
+            let mut writer = PktLineWriter::new();
+            self.request_head(&mut writer, wants, deepen)?;
+            for have in common.iter().chain(batch) {
+                writer.line(&format!("have {}\n", have))?;
+            }
+            writer.flush();
+
+            let response = self.post_upload_pack(base_url, writer.into_bytes(), false)?;
+            let mut ready = false;
+            for (hash, status) in self.parse_acks(&response)? {
+                if !common.contains(&hash) {
+                    common.push(hash);
+                }
+                ready |= status == "ready";
+            }
+            if ready {
+                break;
+            }
         }
-        
-        // 解析响应，提取packfile数据
-        self.extract_packfile_from_response(&body)
+        Ok(common)
     }
-    
-    fn encode_pkt_line(&self, line: &str) -> Vec<u8> {
-        let len = line.len() + 4;
-        let mut result = format!("{:04x}", len).into_bytes();
-        result.extend_from_slice(line.as_bytes());
-        result
+
+    /// ACK 行：`ACK <hash> common|ready|continue`，NAK 和其它包跳过
+    fn parse_acks(&self, response: &[u8]) -> Result<Vec<(ObjectId, String)>> {
+        let mut acks = Vec::new();
+        for packet in PktLineReader::new(response) {
+            let Some(line) = packet?.text() else {
+                continue;
+            };
+            let line = String::from_utf8_lossy(line);
+            if let Some(rest) = line.strip_prefix("ACK ") {
+                let mut parts = rest.split(' ');
+                let hash = parse_hash(parts.next().unwrap_or_default())?;
+                let status = parts.next().unwrap_or_default().to_string();
+                acks.push((hash, status));
+            }
+        }
+        Ok(acks)
+    }
+
+    fn upload_pack_http(&self, base_url: &str, wants: &[ObjectId], common: &[ObjectId], deepen: &Deepen) -> Result<Vec<u8>> {
+        // 构建upload-pack请求体：wants、flush、共同的提交，最后 done
+        let mut writer = PktLineWriter::new();
+        self.request_head(&mut writer, wants, deepen)?;
+        for have in common {
+            writer.line(&format!("have {}\n", have))?;
+        }
+        writer.line("done\n")?;
+        
+        self.post_upload_pack(base_url, writer.into_bytes(), false)
     }
     
+    /// side-band-64k，见 [`sideband`]；pack 之前的 ACK/NAK 跳过
+    /// 服务器不支持 side-band 时 pack 直接跟在 NAK 后面，不再是 pkt-line
     fn extract_packfile_from_response(&self, response: &[u8]) -> Result<Vec<u8>> {
-        let mut pos = 0;
         let mut packfile_data = Vec::new();
-        let mut nak_received = false;
-        
-        while pos < response.len() {
-            if pos + 4 > response.len() {
+        let mut reader = PktLineReader::new(response);
+        while !reader.rest().is_empty() {
+            if reader.rest().starts_with(b"PACK") {
+                packfile_data.extend_from_slice(reader.rest());
                 break;
             }
-            
-            // 读取包长度
-            let len_bytes = &response[pos..pos + 4];
-            let len_str = std::str::from_utf8(len_bytes)
-                .map_err(|_| GitError::protocol_error("Invalid packet length"))?;
-            
-            let packet_len = u32::from_str_radix(len_str, 16)
-                .map_err(|_| GitError::protocol_error("Invalid packet length format"))?;
-            
-            if packet_len == 0 {
-                // Flush packet
-                pos += 4;
-                continue;
-            }
-            
-            if pos + packet_len as usize > response.len() {
+            let Some(packet) = reader.next() else {
                 break;
+            };
+            let Packet::Data(data) = packet? else {
+                continue;
+            };
+            if data.starts_with(b"NAK") || data.starts_with(b"ACK ") {
+                continue;
             }
-            
-            let packet_data = &response[pos + 4..pos + packet_len as usize];
-            
-            // 检查是否是side-band数据
-            if !packet_data.is_empty() {
-                // 检查是否是NAK消息
-                if !nak_received && packet_data.starts_with(b"NAK") {
-                    nak_received = true;
-                    pos += packet_len as usize;
-                    continue;
-                }
-                
-                match packet_data[0] {
-                    1 => {
-                        // Band 1: packfile data
-                        packfile_data.extend_from_slice(&packet_data[1..]);
-                    }
-                    2 => {
-                        // Band 2: progress messages
-                        if let Ok(msg) = std::str::from_utf8(&packet_data[1..]) {
-                            print!("remote: {}", msg);
-                        }
-                    }
-                    3 => {
-                        // Band 3: error messages
-                        if let Ok(msg) = std::str::from_utf8(&packet_data[1..]) {
-                            return Err(GitError::protocol_error(&format!("Remote error: {}", msg)));
-                        }
-                    }
-                    b'P' => {
-                        // 可能是直接的PACK数据 (PACK header)
-                        packfile_data.extend_from_slice(packet_data);
-                    }
-                    _ => {
-                        // 其他数据，忽略
-                    }
-                }
-            }
-            
-            pos += packet_len as usize;
+            sideband(data, &mut packfile_data)?;
         }
-        
-        //println!("DEBUG: Total packfile data extracted: {} bytes", packfile_data.len());
-        if packfile_data.len() >= 8 {
-            //println!("DEBUG: Packfile header: {:?}", &packfile_data[0..8]);
-            if packfile_data.starts_with(b"PACK") {
-                println!("DEBUG: Valid PACK header found!");
-            } else {
-                //println!("DEBUG: No PACK header, trying to find it...");
-                // 尝试在数据中找到PACK头
-                for i in 0..std::cmp::min(1000, packfile_data.len() - 4) {
-                    if &packfile_data[i..i+4] == b"PACK" {
-                        //println!("DEBUG: Found PACK header at offset {}", i);
-                        return Ok(packfile_data[i..].to_vec());
-                    }
-                }
+        Ok(packfile_data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_ref_advertisement, parse_advertisement, parse_fetch_reply, parse_ls_refs, Advertisement};
+    use super::pkt_line::{Packet, PktLineReader, PktLineWriter, MAX_PKT_LEN};
+
+    #[test]
+    fn test_pkt_line_round_trip() {
+        let mut writer = PktLineWriter::new();
+        writer.line("want 1234\n").unwrap().delim().data(b"").unwrap().flush();
+        let bytes = writer.into_bytes();
+        assert_eq!(bytes, b"000ewant 1234\n000100040000");
+
+        let packets = PktLineReader::new(&bytes).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(packets, [Packet::Data(b"want 1234\n"), Packet::Delim, Packet::Data(b""), Packet::Flush]);
+        assert_eq!(packets[0].text(), Some(&b"want 1234"[..]));
+
+        let mut writer = PktLineWriter::new();
+        assert!(writer.data(&vec![b'x'; MAX_PKT_LEN - 4]).is_ok());
+        assert!(writer.data(&vec![b'x'; MAX_PKT_LEN - 3]).is_err());
+    }
+
+    #[test]
+    fn test_pkt_line_binary() {
+        // side-band pack data: NUL, newlines and bytes that aren't utf-8
+        let data = [1u8, b'P', b'A', b'C', b'K', 0, b'\n', 0xff, 0xfe, b'\r', b'\n'];
+        let mut writer = PktLineWriter::new();
+        writer.data(&data).unwrap().flush();
+        writer.raw(b"PACK");
+        let bytes = writer.into_bytes();
+
+        let mut reader = PktLineReader::new(&bytes);
+        assert_eq!(reader.next().unwrap().unwrap(), Packet::Data(&data));
+        assert_eq!(reader.next().unwrap().unwrap(), Packet::Flush);
+        // the rest isn't pkt-line any more
+        assert_eq!(reader.rest(), b"PACK");
+
+        // truncated and malformed packets are errors, not guesses
+        assert!(PktLineReader::new(b"0010abc").next().unwrap().is_err());
+        assert!(PktLineReader::new(b"00zz").next().unwrap().is_err());
+        assert!(PktLineReader::new(b"0003").next().unwrap().is_err());
+        let mut reader = PktLineReader::new(b"0003");
+        reader.next();
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_ref_advertisement() {
+        let head = "1".repeat(40);
+        let tag = "2".repeat(40);
+        let peeled = "3".repeat(40);
+        let mut writer = PktLineWriter::new();
+        writer.line("# service=git-upload-pack\n").unwrap().flush();
+        // a capability value containing a newline must not split the packet
+        writer.line(&format!("{} HEAD\0multi_ack symref=HEAD:refs/heads/main agent=git/2\nbroken\n", head)).unwrap();
+        writer.line(&format!("{} refs/heads/main\n", head)).unwrap();
+        writer.line(&format!("{} refs/tags/v1\n", tag)).unwrap();
+        writer.line(&format!("{} refs/tags/v1^{{}}\n", peeled)).unwrap();
+        writer.flush();
+
+        let refs = parse_ref_advertisement(&writer.into_bytes()).unwrap();
+        let names = refs.iter().map(|r| (r.name.as_str(), r.hash.to_hex())).collect::<Vec<_>>();
+        assert_eq!(names, [("HEAD", head.clone()), ("refs/heads/main", head.clone()), ("refs/tags/v1", tag)]);
+        assert_eq!(refs[2].peeled.map(|hash| hash.to_hex()), Some(peeled));
+        assert_eq!(refs[0].symref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(refs[1].symref, None);
+
+        // empty repository
+        let mut writer = PktLineWriter::new();
+        writer.line(&format!("{} capabilities^{{}}\0report-status\n", "0".repeat(40))).unwrap().flush();
+        assert!(parse_ref_advertisement(&writer.into_bytes()).unwrap().is_empty());
+
+        // something else in place of a hash is the server's mistake
+        let mut writer = PktLineWriter::new();
+        writer.line("# service=git-upload-pack\n").unwrap().flush();
+        writer.line("1234 refs/heads/main\n").unwrap().flush();
+        assert!(parse_ref_advertisement(&writer.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        // capabilities, with or without the service line in front
+        for service in [true, false] {
+            let mut writer = PktLineWriter::new();
+            if service {
+                writer.line("# service=git-upload-pack\n").unwrap().flush();
+            }
+            writer.line("version 2\n").unwrap().line("ls-refs=unborn\n").unwrap().line("fetch=shallow\n").unwrap().flush();
+            match parse_advertisement(&writer.into_bytes()).unwrap() {
+                Advertisement::V2(capabilities) => assert_eq!(capabilities, ["ls-refs=unborn", "fetch=shallow"]),
+                other => panic!("{:?}", other),
             }
         }
-        
-        Ok(packfile_data)
+        // v0 without the service line is a dumb server
+        let mut writer = PktLineWriter::new();
+        writer.line(&format!("{} refs/heads/main\n", "1".repeat(40))).unwrap().flush();
+        assert!(parse_advertisement(&writer.into_bytes()).is_err());
+
+        let mut writer = PktLineWriter::new();
+        writer.line(&format!("{} HEAD symref-target:refs/heads/main\n", "1".repeat(40))).unwrap()
+            .line(&format!("{} refs/tags/v1 peeled:{}\n", "2".repeat(40), "3".repeat(40))).unwrap()
+            .flush();
+        let refs = parse_ls_refs(&writer.into_bytes()).unwrap();
+        assert_eq!((refs[0].name.as_str(), refs[0].peeled), ("HEAD", None));
+        assert_eq!(refs[0].symref.as_deref(), Some("refs/heads/main"));
+        assert_eq!((refs[1].name.as_str(), refs[1].peeled.map(|hash| hash.to_hex())), ("refs/tags/v1", Some("3".repeat(40))));
+
+        // sections split by delim, the pack comes through side-band and progress is skipped
+        let mut writer = PktLineWriter::new();
+        writer.line("acknowledgments\n").unwrap().line(&format!("ACK {}\n", "4".repeat(40))).unwrap().line("ready\n").unwrap().delim();
+        writer.line("shallow-info\n").unwrap().line(&format!("shallow {}\n", "5".repeat(40))).unwrap().delim();
+        writer.line("packfile\n").unwrap().data(b"\x02counting\n").unwrap().data(b"\x01PACK").unwrap().data(b"\x01rest").unwrap().flush();
+        let reply = parse_fetch_reply(&writer.into_bytes()).unwrap();
+        assert_eq!(reply.acks, ["4".repeat(40)]);
+        assert_eq!(reply.shallow, ["5".repeat(40)]);
+        assert_eq!(reply.pack.as_deref(), Some(&b"PACKrest"[..]));
+
+        // only acknowledgments: no pack yet; an error from the server fails
+        let mut writer = PktLineWriter::new();
+        writer.line("acknowledgments\n").unwrap().line("NAK\n").unwrap().flush();
+        assert!(parse_fetch_reply(&writer.into_bytes()).unwrap().pack.is_none());
+        let mut writer = PktLineWriter::new();
+        writer.line("ERR upload-pack: not our ref\n").unwrap().flush();
+        assert!(parse_fetch_reply(&writer.into_bytes()).is_err());
     }
 }
@@ -1,20 +1,56 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::io::Write;
 use crate::{
     utils::{
+        ident,
         commit::Commit,
-        fs::read_file_as_bytes,
+        fs::{common_dir, read_file_as_bytes},
         objtype::Obj,
+        hash::ObjectId,
     },
     GitError, Result
 };
 
+/// refs only one worktree sees, kept in its own git dir like HEAD; the rest of refs/ is shared
+const WORKTREE_REFS: [&str; 2] = ["refs/bisect/", "refs/worktree/"];
+
+fn is_per_worktree(refname: &str) -> bool {
+    !refname.starts_with("refs/") || WORKTREE_REFS.iter().any(|prefix| refname.starts_with(prefix))
+}
+
+/// the file of `refname`: HEAD, ORIG_HEAD and the like belong to the worktree, branches and tags to the repository
+pub fn ref_file(gitdir: &Path, refname: &str) -> PathBuf {
+    match is_per_worktree(refname) {
+        true => gitdir.join(refname),
+        false => common_dir(gitdir).join(refname),
+    }
+}
+
+/// the reflog of `refname`, next to the ref itself
+fn log_file(gitdir: &Path, refname: &str) -> PathBuf {
+    match is_per_worktree(refname) {
+        true => gitdir.join("logs").join(refname),
+        false => common_dir(gitdir).join("logs").join(refname),
+    }
+}
+
 /// read from/write to .git/HEAD
 /// content may look like ref: refs/heads/branch
 pub fn read_head_ref(gitdir: &Path) -> Result<String> {
-    let head_path = gitdir.join("HEAD");
-    let content = fs::read_to_string(&head_path)
-        .map_err(|_| GitError::FileNotFound(head_path.display().to_string()))?;
+    read_symref(gitdir, "HEAD")
+}
+
+pub fn write_head_ref(gitdir: &Path, ref_path: &str) -> Result<()> {
+    write_symref(gitdir, "HEAD", ref_path)
+}
+
+/// the ref the symbolic ref `name` points at, one level only; an error when `name` holds a hash
+pub fn read_symref(gitdir: &Path, name: &str) -> Result<String> {
+    let path = ref_file(gitdir, name);
+    let content = fs::read_to_string(&path)
+        .map_err(|_| GitError::file_notfound(path.display().to_string()))?;
     // 解析 ref: refs/heads/main
     if let Some(rest) = content.strip_prefix("ref: ") {
         Ok(rest.trim().to_string())
@@ -23,44 +59,245 @@ pub fn read_head_ref(gitdir: &Path) -> Result<String> {
     }
 }
 
-pub fn write_head_ref(gitdir: &Path, ref_path: &str) -> Result<()> {
-    let head_file = gitdir.join("HEAD");
-    fs::write(&head_file, format!("ref: {}\n", ref_path))
-        .map_err(|_| GitError::failed_to_write_file(&head_file.to_string_lossy()))?;
+/// make `name` a symbolic ref to `target`, which does not have to exist yet
+pub fn write_symref(gitdir: &Path, name: &str, target: &str) -> Result<()> {
+    write_ref_file(&ref_file(gitdir, name), &format!("ref: {}\n", target))
+}
+
+pub fn write_head_commit(gitdir: &Path, hash: &ObjectId) -> Result<()> {
+    write_ref_file(&gitdir.join("HEAD"), &format!("{}\n", hash))
+}
+
+/// write to `<ref>.lock` first and rename it over the ref,
+/// so a crash never leaves a truncated ref behind
+pub fn write_ref_file(ref_file: &Path, content: &str) -> Result<()> {
+    let failed = || GitError::failed_to_write_file(&ref_file.to_string_lossy());
+    if let Some(parent) = ref_file.parent() {
+        fs::create_dir_all(parent).map_err(|_| failed())?;
+    }
+    let mut lock = ref_file.as_os_str().to_owned();
+    lock.push(".lock");
+    let lock = PathBuf::from(lock);
+    if fs::write(&lock, content).and_then(|_| fs::rename(&lock, ref_file)).is_err() {
+        let _ = fs::remove_file(&lock);
+        return Err(failed());
+    }
     Ok(())
 }
 
-pub fn write_head_commit(gitdir: &Path, hash: &str) -> Result<()> {
-    let head_file = gitdir.join("HEAD");
-    fs::write(&head_file, format!("{}\n", hash))
-        .map_err(|_| GitError::failed_to_write_file(&head_file.to_string_lossy()))?;
+/// the ref `name` finally points to after following `ref: ` lines, it may not exist yet
+pub fn resolve_symref(gitdir: &Path, name: &str) -> String {
+    let mut name = name.to_string();
+    // a loop of symbolic refs is broken anyway, give up after a few steps like git
+    for _ in 0..5 {
+        match fs::read_to_string(ref_file(gitdir, &name)) {
+            Ok(content) if content.starts_with("ref: ") => {
+                name = content["ref: ".len()..].trim().to_string();
+            },
+            _ => break,
+        }
+    }
+    name
+}
+
+/// remove a ref file and the directories which became empty,
+/// top level directories like refs/heads are kept
+pub fn delete_ref(gitdir: &Path, ref_path: &str) -> Result<()> {
+    let ref_file = ref_file(gitdir, ref_path);
+    if ref_file.is_file() {
+        fs::remove_file(&ref_file)
+            .map_err(|_| GitError::failed_to_remove_file(ref_file.display().to_string()))?;
+    }
+    remove_packed_ref(gitdir, ref_path)?;
+    // the reflog goes away together with the ref
+    let log_file = log_file(gitdir, ref_path);
+    if log_file.is_file() {
+        fs::remove_file(&log_file)
+            .map_err(|_| GitError::failed_to_remove_file(log_file.display().to_string()))?;
+    }
+    let refs_dir = match is_per_worktree(ref_path) {
+        true => gitdir.join("refs"),
+        false => common_dir(gitdir).join("refs"),
+    };
+    let mut dir = ref_file.parent();
+    while let Some(d) = dir {
+        if !d.starts_with(&refs_dir) || d.parent() == Some(refs_dir.as_path()) || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
     Ok(())
 }
 
 pub fn read_head_commit(gitdir: &Path) -> Result<String> {
     let head_path = gitdir.join("HEAD");
     let content = fs::read_to_string(&head_path)
-        .map_err(|_| GitError::FileNotFound(head_path.display().to_string()))?;
+        .map_err(|_| GitError::file_notfound(head_path.display().to_string()))?;
     Ok(content.trim().to_string())
 }
 
 /// read from / write to .git/{refname}
-/// content is 20 bytes commit hash, such as fbb2fa502d19588f97190d8c89643aad3e533bb8
-pub fn read_ref_commit(gitdir: &Path, refname: &str) -> Result<String> {
-    let ref_path = gitdir.join(refname);
-    let content = fs::read_to_string(&ref_path)
-        .map_err(|_| GitError::FileNotFound(format!("不存在 {} 这个分支", ref_path.file_name().unwrap().to_str().unwrap())))?;
-    Ok(content.trim().to_string())
+/// content is the hash in hex, such as fbb2fa502d19588f97190d8c89643aad3e533bb8
+pub fn read_ref_commit(gitdir: &Path, refname: &str) -> Result<ObjectId> {
+    let ref_path = ref_file(gitdir, refname);
+    match fs::read_to_string(&ref_path) {
+        Ok(content) => ObjectId::from_hex(content.trim()),
+        // a ref without a file of its own may still be in packed-refs
+        Err(_) => read_packed_refs(gitdir)
+            .remove(refname)
+            .ok_or_else(|| GitError::file_notfound(format!("不存在 {} 这个分支", ref_path.file_name().unwrap().to_str().unwrap()))),
+    }
+}
+
+pub fn write_ref_commit(gitdir: &Path, ref_path: &str, hash: &ObjectId) -> Result<()> {
+    write_ref_file(&ref_file(gitdir, ref_path), &format!("{}\n", hash))
+}
+
+/// write_ref_commit and record the move in the reflog,
+/// logs/HEAD gets the entry too when HEAD points at `ref_path`
+pub fn write_ref_commit_with_log(gitdir: &Path, ref_path: &str, hash: &ObjectId, message: &str) -> Result<()> {
+    let old = read_ref_commit(gitdir, ref_path).ok();
+    write_ref_commit(gitdir, ref_path, hash)?;
+    log_ref_update(gitdir, ref_path, old.as_ref(), hash, message)?;
+    if ref_path != "HEAD" && read_head_ref(gitdir).ok().as_deref() == Some(ref_path) {
+        log_ref_update(gitdir, "HEAD", old.as_ref(), hash, message)?;
+    }
+    Ok(())
+}
+
+/// switch HEAD to another branch, `message` is like `checkout: moving from a to b`
+pub fn write_head_ref_with_log(gitdir: &Path, ref_path: &str, message: &str) -> Result<()> {
+    let old = head_to_hash(gitdir).ok();
+    write_head_ref(gitdir, ref_path)?;
+    match read_ref_commit(gitdir, ref_path) {
+        Ok(new) => log_ref_update(gitdir, "HEAD", old.as_ref(), &new, message),
+        // an unborn branch has nothing to log yet
+        Err(_) => Ok(()),
+    }
+}
+
+/// detach HEAD at `hash`
+pub fn write_head_commit_with_log(gitdir: &Path, hash: &ObjectId, message: &str) -> Result<()> {
+    let old = head_to_hash(gitdir).ok();
+    write_head_commit(gitdir, hash)?;
+    log_ref_update(gitdir, "HEAD", old.as_ref(), hash, message)
+}
+
+/// one line of a reflog: `<old> <new> <committer> <timestamp> <tz>\t<message>`
+#[derive(Debug, Clone)]
+pub struct ReflogEntry {
+    pub old: ObjectId,
+    pub new: ObjectId,
+    pub committer: String,
+    pub message: String,
 }
 
-pub fn write_ref_commit(gitdir: &Path, ref_path: &str, hash: &str) -> Result<()> {
-    let ref_file = gitdir.join(ref_path);
-    fs::write(&ref_file, format!("{}\n", hash))
-        .map_err(|_| GitError::failed_to_write_file(&ref_file.to_string_lossy()))?;
+/// like git (core.logAllRefUpdates) only HEAD, branches, remote branches and the stash get a reflog
+fn has_reflog(refname: &str) -> bool {
+    refname == "HEAD" || refname == "refs/stash" || ["refs/heads/", "refs/remotes/", "refs/notes/"].iter().any(|prefix| refname.starts_with(prefix))
+}
+
+/// append an entry to logs/<refname>, `old` is None for a newly created ref
+pub fn log_ref_update(gitdir: &Path, refname: &str, old: Option<&ObjectId>, new: &ObjectId, message: &str) -> Result<()> {
+    if !has_reflog(refname) {
+        return Ok(());
+    }
+    // reflogs are a convenience, a missing identity must not break the actual update
+    let committer = match ident::committer(gitdir) {
+        Ok(committer) => committer,
+        Err(_) => return Ok(()),
+    };
+    let mut line = format!("{} {} {}", old.copied().unwrap_or_else(ObjectId::null), new, committer);
+    // git leaves out the tab when there is no message
+    if !message.is_empty() {
+        line += "\t";
+        line += message.lines().next().unwrap_or_default();
+    }
+    line += "\n";
+
+    let log_path = log_file(gitdir, refname);
+    let failed = || GitError::failed_to_write_file(&log_path.to_string_lossy());
+    fs::create_dir_all(log_path.parent().unwrap()).map_err(|_| failed())?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(|_| failed())?;
+    file.write_all(line.as_bytes()).map_err(|_| failed())?;
     Ok(())
 }
 
-pub fn read_branch_commit(gitdir: &Path, branch: &str) -> Result<String> {
+/// the full ref name whose reflog `name` refers to, e.g. `master` -> `refs/heads/master`
+pub fn reflog_name(gitdir: &Path, name: &str) -> Option<String> {
+    [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+    ]
+    .into_iter()
+    .find(|refname| log_file(gitdir, refname).is_file())
+}
+
+/// reflog entries of `refname` in file order, that is oldest first
+pub fn read_reflog(gitdir: &Path, refname: &str) -> Result<Vec<ReflogEntry>> {
+    let log_path = log_file(gitdir, refname);
+    let content = fs::read_to_string(&log_path)
+        .map_err(|_| GitError::failed_to_read_file(&log_path.to_string_lossy()))?;
+    Ok(content.lines()
+        .filter_map(|line| {
+            let (head, message) = line.split_once('\t').unwrap_or((line, ""));
+            let (old, rest) = head.split_once(' ')?;
+            let (new, committer) = rest.split_once(' ')?;
+            // a line that doesn't start with two hashes is corrupt, git passes over it
+            Some(ReflogEntry {
+                old: ObjectId::from_hex(old).ok()?,
+                new: ObjectId::from_hex(new).ok()?,
+                committer: committer.to_string(),
+                message: message.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// every ref with a reflog, HEAD of this worktree first
+pub fn list_reflogs(gitdir: &Path) -> Result<Vec<String>> {
+    fn collect(base: &Path, dir: &str, out: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(base.join(dir))? {
+            let entry = entry?;
+            let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            match entry.path().is_dir() {
+                true => collect(base, &name, out)?,
+                false => out.push(name),
+            }
+        }
+        Ok(())
+    }
+
+    let mut refs = Vec::new();
+    let logs = common_dir(gitdir).join("logs");
+    if logs.join("refs").is_dir() {
+        collect(&logs, "refs", &mut refs)?;
+    }
+    refs.sort();
+    if log_file(gitdir, "HEAD").is_file() {
+        refs.insert(0, "HEAD".to_string());
+    }
+    Ok(refs)
+}
+
+/// replace the whole reflog of `refname`, entries oldest first like `read_reflog` returns them
+pub fn write_reflog(gitdir: &Path, refname: &str, entries: &[ReflogEntry]) -> Result<()> {
+    let content = entries.iter()
+        .map(|entry| match entry.message.as_str() {
+            "" => format!("{} {} {}\n", entry.old, entry.new, entry.committer),
+            message => format!("{} {} {}\t{}\n", entry.old, entry.new, entry.committer, message),
+        })
+        .collect::<String>();
+    write_ref_file(&log_file(gitdir, refname), &content)
+}
+
+pub fn read_branch_commit(gitdir: &Path, branch: &str) -> Result<ObjectId> {
     if branch.starts_with("refs/") {
         // 如果已经是完整的引用路径，直接使用
         read_ref_commit(gitdir, branch)
@@ -70,7 +307,7 @@ pub fn read_branch_commit(gitdir: &Path, branch: &str) -> Result<String> {
     }
 }
 
-pub fn write_branch_commit(gitdir: &Path, branch: &str, hash: &str) -> Result<()> {
+pub fn write_branch_commit(gitdir: &Path, branch: &str, hash: &ObjectId) -> Result<()> {
     if branch.starts_with("refs/") {
         // 如果已经是完整的引用路径，直接使用
         write_ref_commit(gitdir, branch, hash)
@@ -80,8 +317,123 @@ pub fn write_branch_commit(gitdir: &Path, branch: &str, hash: &str) -> Result<()
     }
 }
 
-pub fn head_to_hash(gitdir: &Path) -> Result<String> {
-    let head_ref = read_head_ref(gitdir)?;
-    read_ref_commit(gitdir, &head_ref)
+/// commit HEAD points to, either through a branch or directly when detached
+pub fn head_to_hash(gitdir: &Path) -> Result<ObjectId> {
+    match read_head_ref(gitdir) {
+        Ok(head_ref) => read_ref_commit(gitdir, &head_ref),
+        Err(_) => ObjectId::from_hex(&read_head_commit(gitdir)?),
+    }
+}
+
+pub fn is_detached(gitdir: &Path) -> bool {
+    read_head_ref(gitdir).is_err() && read_head_commit(gitdir).map(|x| is_hash(&x)).unwrap_or(false)
+}
+
+/// short name of the checked out branch, e.g. `main` for refs/heads/main
+pub fn current_branch(gitdir: &Path) -> Result<String> {
+    read_head_ref(gitdir)
+        .ok()
+        .and_then(|head_ref| head_ref.strip_prefix("refs/heads/").map(String::from))
+        .ok_or_else(|| GitError::invalid_command("Not on a branch (detached HEAD)".to_string()))
+}
+
+pub use super::hash::is_hash;
+
+/// the rules of `git check-ref-format --branch` that matter in practice
+pub fn check_branch_name(name: &str) -> Result<()> {
+    let invalid = name.is_empty() || name == "HEAD" || name.starts_with(['-', '/']) || name.ends_with(['/', '.'])
+        || name.contains("..") || name.contains("//") || name.contains("@{") || name.ends_with(".lock")
+        || name.split('/').any(|part| part.starts_with('.'))
+        || name.chars().any(|c| c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\'));
+    if invalid {
+        return Err(GitError::invalid_command(format!("'{}' is not a valid branch name", name)));
+    }
+    Ok(())
+}
+
+/// names of all branches under refs/heads, sorted
+/// branches like feature/foo live in sub directories
+pub fn list_branches(gitdir: &Path) -> Result<Vec<String>> {
+    list_refs(gitdir, "refs/heads")
+}
+
+/// names of all refs under `dir` (like refs/remotes), relative to it and sorted
+pub fn list_refs(gitdir: &Path, dir: &str) -> Result<Vec<String>> {
+    let prefix = format!("{}/", dir.trim_end_matches('/'));
+    Ok(for_each_ref(gitdir, &prefix)?
+        .into_iter()
+        .map(|(name, _)| name[prefix.len()..].to_string())
+        .collect())
+}
+
+/// every ref whose name starts with `prefix` ("refs/" for all of them) with the object it points at,
+/// sorted by name like git: loose refs in any depth of directories win over the ones in packed-refs,
+/// symbolic refs are followed and the ones leading nowhere are left out
+pub fn for_each_ref(gitdir: &Path, prefix: &str) -> Result<Vec<(String, ObjectId)>> {
+    fn collect(gitdir: &Path, base: &Path, dir: &str, out: &mut BTreeMap<String, ObjectId>) -> Result<()> {
+        for entry in fs::read_dir(base.join(dir))? {
+            let entry = entry?;
+            let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            if entry.path().is_dir() {
+                collect(gitdir, base, &name, out)?;
+                continue;
+            }
+            // the main worktree's refs/bisect are not ours in a linked one
+            if ref_file(gitdir, &name) != base.join(&name) {
+                continue;
+            }
+            let target = resolve_symref(gitdir, &name);
+            // a half written ref.lock or some other stray file is no ref
+            if let Some(hash) = fs::read_to_string(ref_file(gitdir, &target)).ok().and_then(|x| ObjectId::from_hex(x.trim()).ok()) {
+                out.insert(name, hash);
+            }
+        }
+        Ok(())
+    }
+
+    let mut refs = read_packed_refs(gitdir);
+    let common = common_dir(gitdir);
+    let bases = if common == gitdir { vec![common] } else { vec![common, gitdir.to_path_buf()] };
+    for base in bases.iter().filter(|base| base.join("refs").is_dir()) {
+        collect(gitdir, base, "refs", &mut refs)?;
+    }
+    Ok(refs.into_iter().filter(|(name, _)| name.starts_with(prefix)).collect())
+}
+
+/// whether `refname` exists as a loose ref or in packed-refs
+pub fn ref_exists(gitdir: &Path, refname: &str) -> bool {
+    ref_file(gitdir, refname).is_file() || read_packed_refs(gitdir).contains_key(refname)
 }
 
+/// drop `refname` and the peeled line following it from packed-refs
+fn remove_packed_ref(gitdir: &Path, refname: &str) -> Result<()> {
+    let Ok(content) = fs::read_to_string(common_dir(gitdir).join("packed-refs")) else {
+        return Ok(());
+    };
+    let mut kept = String::new();
+    let mut removed = false;
+    for line in content.lines() {
+        let this = line.split_once(' ').is_some_and(|(_, name)| name == refname);
+        if this || (removed && line.starts_with('^')) {
+            removed = true;
+            continue;
+        }
+        removed = false;
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    if kept != content {
+        write_ref_file(&common_dir(gitdir).join("packed-refs"), &kept)?;
+    }
+    Ok(())
+}
+
+/// the refs in packed-refs, `^<peeled>` lines after an annotated tag and comments are skipped
+fn read_packed_refs(gitdir: &Path) -> BTreeMap<String, ObjectId> {
+    fs::read_to_string(common_dir(gitdir).join("packed-refs"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(hash, name)| Some((name.to_string(), ObjectId::from_hex(hash).ok()?)))
+        .collect()
+}
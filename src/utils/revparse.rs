@@ -0,0 +1,239 @@
+use std::{
+    fs,
+    path::Path,
+};
+
+use crate::{
+    utils::{
+        commit::Commit,
+        fs::{common_dir, read_obj, read_object, object_exists},
+        objtype::Obj,
+        packfile::packed_hashes,
+        refs::{head_to_hash, is_hash, read_ref_commit, ref_exists, read_head_ref, read_reflog, reflog_name, current_branch},
+        config::Config,
+        hash::{algorithm, ObjectId},
+        index::Index,
+    },
+    GitError,
+    Result,
+};
+
+/// shortest abbreviation git accepts
+const MIN_ABBREV: usize = 4;
+
+/// turn a revision such as `HEAD`, `HEAD~2`, `main^`, `v1^{tree}`, `HEAD@{1}`, `@{u}`, `a1b2c3d` or a full hash into a 40-char hash
+/// `<rev>:<path>` names what is at `path` in the tree of `rev`, `:<path>` the staged blob
+pub fn rev_parse(gitdir: &Path, rev: &str) -> Result<ObjectId> {
+    if let Some((treeish, path)) = rev.split_once(':') {
+        let found = match treeish {
+            "" => index_entry(gitdir, path),
+            treeish => tree_entry(gitdir, &resolve_tree(gitdir, treeish)?, path),
+        };
+        return found?.ok_or_else(|| GitError::invalid_command(format!("path '{}' does not exist in '{}'", path, treeish)));
+    }
+    // split `base~2^^~` into `base` and the suffix operators
+    let split = rev.find(['~', '^']).unwrap_or(rev.len());
+    let (base, mut suffix) = rev.split_at(split);
+    if base.is_empty() {
+        return Err(GitError::unknown_revision(rev));
+    }
+
+    let mut hash = resolve_base(gitdir, base)?;
+    while let Some(op) = suffix.chars().next() {
+        suffix = &suffix[1..];
+        // ^{type}: peel until an object of that type, ^{} only peels tags
+        if op == '^' && let Some(rest) = suffix.strip_prefix('{') {
+            let (kind, rest) = rest.split_once('}').ok_or_else(|| GitError::unknown_revision(rev))?;
+            hash = peel(gitdir, hash, kind).map_err(|_| GitError::unknown_revision(rev))?;
+            suffix = rest;
+            continue;
+        }
+        let digits = suffix.chars().take_while(|c| c.is_ascii_digit()).count();
+        let n = match &suffix[..digits] {
+            "" => 1,
+            num => num.parse::<usize>().map_err(|_| GitError::unknown_revision(rev))?,
+        };
+        suffix = &suffix[digits..];
+
+        hash = match op {
+            // ~N: the N-th first-parent ancestor
+            '~' => (0..n).try_fold(hash, |hash, _| nth_parent(gitdir, &hash, 1, rev))?,
+            // ^N: the N-th parent, ^0 is the commit itself
+            _ if n == 0 => {
+                read_object::<Commit>(gitdir.to_path_buf(), &hash)
+                    .map_err(|_| GitError::unknown_revision(rev))?;
+                hash
+            },
+            _ => nth_parent(gitdir, &hash, n, rev)?,
+        };
+    }
+    Ok(hash)
+}
+
+/// the revision without any `~`/`^` suffix
+fn resolve_base(gitdir: &Path, name: &str) -> Result<ObjectId> {
+    // <branch>@{upstream} / @{u}: what the branch tracks, `@{u}` alone is the current branch
+    if let Some((branch, suffix)) = name.strip_suffix('}').and_then(|x| x.rsplit_once("@{"))
+        && (suffix.eq_ignore_ascii_case("u") || suffix.eq_ignore_ascii_case("upstream")) {
+        let tracking = upstream_ref(gitdir, branch)?;
+        return read_ref_commit(gitdir, &tracking).map_err(|_| GitError::unknown_revision(name));
+    }
+    // <ref>@{n}: the value the ref had n moves ago, `@{n}` alone is the current branch
+    if let Some((refname, n)) = name.strip_suffix('}').and_then(|x| x.rsplit_once("@{")) {
+        let refname = match refname {
+            "" => read_head_ref(gitdir).unwrap_or("HEAD".to_string()),
+            refname => refname.to_string(),
+        };
+        let n = n.parse::<usize>().map_err(|_| GitError::unknown_revision(name))?;
+        let entry = reflog_name(gitdir, &refname)
+            .map(|refname| read_reflog(gitdir, &refname))
+            .transpose()?
+            .and_then(|entries| entries.into_iter().rev().nth(n));
+        return entry.map(|entry| entry.new).ok_or_else(|| GitError::unknown_revision(name));
+    }
+    if name == "HEAD" {
+        return head_to_hash(gitdir).map_err(|_| GitError::unknown_revision(name));
+    }
+    if is_hash(name) {
+        return match object_exists(gitdir, name) {
+            true => ObjectId::from_hex(name),
+            false => Err(GitError::unknown_revision(name)),
+        };
+    }
+
+    if let Some(refname) = dwim_ref(gitdir, name) {
+        return read_ref_commit(gitdir, &refname);
+    }
+    // ORIG_HEAD and friends live directly in the git dir
+    if name.chars().all(|c| c.is_ascii_uppercase() || c == '_') && gitdir.join(name).is_file()
+        && let Ok(hash) = read_ref_commit(gitdir, name) {
+        return Ok(hash);
+    }
+
+    resolve_abbrev(gitdir, name)
+}
+
+/// the full name of the ref `name` stands for
+/// same lookup order as git: refs/<name>, refs/tags/<name>, refs/heads/<name>, refs/remotes/<name>
+pub fn dwim_ref(gitdir: &Path, name: &str) -> Option<String> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+    ];
+    candidates.into_iter()
+        .skip(if name.starts_with("refs/") { 0 } else { 1 })
+        .find(|refname| ref_exists(gitdir, refname))
+}
+
+/// follow tags, and from a commit to its tree, until an object of type `kind` turns up, `""` for the first non-tag
+fn peel(gitdir: &Path, mut hash: ObjectId, kind: &str) -> Result<ObjectId> {
+    loop {
+        let obj = read_obj(gitdir.to_path_buf(), &hash)?;
+        if obj.get_type() == kind || (kind.is_empty() && !matches!(obj, Obj::G(_))) {
+            return Ok(hash);
+        }
+        hash = match obj {
+            Obj::G(tag) => tag.object,
+            Obj::C(commit) if kind == "tree" => commit.tree_hash,
+            _ => return Err(GitError::invalid_command(format!("{} is not a {}", hash, kind))),
+        };
+    }
+}
+
+/// the object at `path` below `tree`, None when some component is missing
+pub fn tree_entry(gitdir: &Path, tree: &ObjectId, path: &str) -> Result<Option<ObjectId>> {
+    let mut hash = *tree;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let Obj::T(tree) = read_obj(gitdir.to_path_buf(), &hash)? else {
+            return Ok(None);
+        };
+        match tree.iter().find(|entry| entry.path == Path::new(name)) {
+            Some(entry) => hash = entry.hash,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(hash))
+}
+
+/// the blob staged for `path`, conflicted paths have none
+fn index_entry(gitdir: &Path, path: &str) -> Result<Option<ObjectId>> {
+    Ok(Index::read_or_default(gitdir)?.entries.into_iter()
+        .find(|entry| entry.stage == 0 && entry.name == Path::new(path))
+        .map(|entry| entry.hash))
+}
+
+/// the tree a tree-ish names: a tree itself, or the tree of a commit, tags are peeled on the way
+pub fn resolve_tree(gitdir: &Path, rev: &str) -> Result<ObjectId> {
+    let mut hash = rev_parse(gitdir, rev)?;
+    loop {
+        hash = match read_obj(gitdir.to_path_buf(), &hash)? {
+            Obj::T(_) => return Ok(hash),
+            Obj::C(commit) => commit.tree_hash,
+            Obj::G(tag) => tag.object,
+            Obj::B(_) => return Err(GitError::invalid_command(format!("not a tree object: {}", rev))),
+        };
+    }
+}
+
+/// the commit a commit-ish names, annotated tags are peeled on the way
+pub fn resolve_commit(gitdir: &Path, rev: &str) -> Result<ObjectId> {
+    let mut hash = rev_parse(gitdir, rev)?;
+    loop {
+        hash = match read_obj(gitdir.to_path_buf(), &hash)? {
+            Obj::C(_) => return Ok(hash),
+            Obj::G(tag) => tag.object,
+            _ => return Err(GitError::invalid_command(format!("not a commit object: {}", rev))),
+        };
+    }
+}
+
+/// the remote-tracking ref `branch` is set to follow, `""` and `HEAD` mean the current branch
+pub fn upstream_ref(gitdir: &Path, branch: &str) -> Result<String> {
+    let branch = match branch {
+        "" | "HEAD" => current_branch(gitdir)
+            .map_err(|_| GitError::invalid_command("HEAD does not point to a branch".to_string()))?,
+        branch => branch.strip_prefix("refs/heads/").unwrap_or(branch).to_string(),
+    };
+    let config = Config::read_all(gitdir)?;
+    config.upstream(&branch)
+        .and_then(|upstream| config.tracking_ref(&upstream))
+        .ok_or_else(|| GitError::invalid_command(format!("no upstream configured for branch '{}'", branch)))
+}
+
+/// a unique object whose hash starts with `prefix`
+fn resolve_abbrev(gitdir: &Path, prefix: &str) -> Result<ObjectId> {
+    if prefix.len() < MIN_ABBREV || prefix.len() > algorithm().hex_len() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(GitError::unknown_revision(prefix));
+    }
+    let prefix = prefix.to_lowercase();
+    let dir = common_dir(gitdir).join("objects").join(&prefix[..2]);
+    let mut matches = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| format!("{}{}", &prefix[..2], entry.file_name().to_string_lossy()))
+            .filter(|hash| is_hash(hash) && hash.starts_with(&prefix))
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    matches.extend(packed_hashes(gitdir)?.into_iter().filter(|hash| hash.starts_with(&prefix)));
+    matches.sort();
+    matches.dedup();
+
+    match matches.len() {
+        0 => Err(GitError::unknown_revision(&prefix)),
+        1 => ObjectId::from_hex(&matches[0]),
+        _ => Err(GitError::ambiguous_revision(&prefix, &matches)),
+    }
+}
+
+fn nth_parent(gitdir: &Path, hash: &ObjectId, n: usize, rev: &str) -> Result<ObjectId> {
+    let commit = read_object::<Commit>(gitdir.to_path_buf(), hash)
+        .map_err(|_| GitError::unknown_revision(rev))?;
+    commit.parent_hash
+        .get(n - 1)
+        .copied()
+        .ok_or_else(|| GitError::unknown_revision(rev))
+}
@@ -0,0 +1,132 @@
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+use crate::{
+    GitError,
+    Result,
+    utils::{
+        commit::Commit,
+        fs::read_obj,
+        ident,
+        objtype::Obj,
+        hash::ObjectId,
+        shallow::is_shallow,
+    },
+};
+
+/// the boundary commits of a shallow clone have no parents here, theirs were never fetched
+pub fn parents(gitdir: &Path, hash: &ObjectId, cache: &mut HashMap<ObjectId, Commit>) -> Result<Vec<ObjectId>> {
+    if !cache.contains_key(hash) {
+        match read_obj(gitdir.to_path_buf(), hash)? {
+            Obj::C(mut commit) => {
+                if is_shallow(gitdir, hash) {
+                    commit.parent_hash.clear();
+                }
+                cache.insert(*hash, commit)
+            },
+            _ => return Err(GitError::broken_commit_history(hash.to_hex())),
+        };
+    }
+    Ok(cache[hash].parent_hash.clone())
+}
+
+/// every commit reachable from `hash` through all parents, `hash` itself first
+/// iterative, so long histories don't blow the stack
+pub fn ancestors<P>(gitdir: P, hash: &ObjectId) -> Result<Vec<ObjectId>>
+where
+    P: AsRef<Path>
+{
+    reachable(gitdir.as_ref(), vec![*hash], &mut HashMap::new())
+}
+
+/// breadth first walk from `starts` over the whole parent DAG
+pub fn reachable(gitdir: &Path, starts: Vec<ObjectId>, cache: &mut HashMap<ObjectId, Commit>) -> Result<Vec<ObjectId>> {
+    let mut seen = starts.iter().cloned().collect::<HashSet<_>>();
+    let mut queue = starts.into_iter().collect::<VecDeque<_>>();
+    let mut order = Vec::new();
+    while let Some(hash) = queue.pop_front() {
+        for parent in parents(gitdir, &hash, cache)? {
+            if seen.insert(parent) {
+                queue.push_back(parent);
+            }
+        }
+        order.push(hash);
+    }
+    Ok(order)
+}
+
+/// the same commits newest first by committer date, the order git's history walk shows them in
+pub fn date_order(gitdir: &Path, starts: Vec<ObjectId>, cache: &mut HashMap<ObjectId, Commit>) -> Result<Vec<ObjectId>> {
+    let time = |hash: &ObjectId, cache: &mut HashMap<_, Commit>| -> Result<i64> {
+        parents(gitdir, hash, cache)?;
+        Ok(ident::split_ident(&cache[hash].committer).map(|(_, time, _)| time).unwrap_or_default())
+    };
+    let mut seen = starts.iter().cloned().collect::<HashSet<_>>();
+    let mut queue = BinaryHeap::new();
+    for start in starts {
+        queue.push((time(&start, cache)?, start));
+    }
+    let mut order = Vec::new();
+    while let Some((_, hash)) = queue.pop() {
+        for parent in parents(gitdir, &hash, cache)? {
+            if seen.insert(parent) {
+                queue.push((time(&parent, cache)?, parent));
+            }
+        }
+        order.push(hash);
+    }
+    Ok(order)
+}
+
+/// length of the longest parent chain down to a root commit, roots are 1
+fn generation(gitdir: &Path, hash: &ObjectId, cache: &mut HashMap<ObjectId, Commit>, gens: &mut HashMap<ObjectId, usize>) -> Result<usize> {
+    let mut stack = vec![*hash];
+    while let Some(top) = stack.last().copied() {
+        if gens.contains_key(&top) {
+            stack.pop();
+            continue;
+        }
+        let parents = parents(gitdir, &top, cache)?;
+        let pending = parents.iter().filter(|p| !gens.contains_key(*p)).copied().collect::<Vec<_>>();
+        if pending.is_empty() {
+            let generation = parents.iter().map(|p| gens[p]).max().unwrap_or(0) + 1;
+            gens.insert(top, generation);
+            stack.pop();
+        }
+        else {
+            stack.extend(pending);
+        }
+    }
+    Ok(gens[hash])
+}
+
+/// best common ancestors of `hash1` and `hash2`: common ancestors which are not
+/// an ancestor of another common ancestor, the greatest generation comes first
+pub fn merge_bases(gitdir: impl AsRef<Path>, hash1: &ObjectId, hash2: &ObjectId) -> Result<Vec<ObjectId>> {
+    let gitdir = gitdir.as_ref();
+    let mut cache = HashMap::new();
+    let ancestor1 = reachable(gitdir, vec![*hash1], &mut cache)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let common = reachable(gitdir, vec![*hash2], &mut cache)?
+        .into_iter()
+        .filter(|hash| ancestor1.contains(hash))
+        .collect::<Vec<_>>();
+
+    // everything below a common ancestor is redundant
+    let mut below = Vec::new();
+    for hash in &common {
+        below.extend(parents(gitdir, hash, &mut cache)?);
+    }
+    let redundant = reachable(gitdir, below, &mut cache)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut gens = HashMap::new();
+    let mut bases = common.into_iter()
+        .filter(|hash| !redundant.contains(hash))
+        .map(|hash| Ok((generation(gitdir, &hash, &mut cache, &mut gens)?, hash)))
+        .collect::<Result<Vec<_>>>()?;
+    bases.sort_by(|(a, _), (b, _)| b.cmp(a));
+    Ok(bases.into_iter().map(|(_, hash)| hash).collect())
+}
@@ -0,0 +1,33 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use crate::{
+    utils::{fs::common_dir, hash::ObjectId, refs::write_ref_file},
+    GitError, Result,
+};
+
+/// .git/shallow lists the boundary commits of a shallow clone, one hash per line
+/// their parents were never fetched, history walks treat them as root commits
+pub fn read_shallow(gitdir: &Path) -> BTreeSet<ObjectId> {
+    fs::read_to_string(common_dir(gitdir).join("shallow"))
+        .map(|content| content.lines().filter_map(|line| ObjectId::from_hex(line.trim()).ok()).collect())
+        .unwrap_or_default()
+}
+
+pub fn is_shallow(gitdir: &Path, hash: &ObjectId) -> bool {
+    common_dir(gitdir).join("shallow").exists() && read_shallow(gitdir).contains(hash)
+}
+
+/// an empty set removes the file, the repository is complete again
+pub fn write_shallow(gitdir: &Path, shallow: &BTreeSet<ObjectId>) -> Result<()> {
+    let path = common_dir(gitdir).join("shallow");
+    if shallow.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)
+                .map_err(|_| GitError::failed_to_remove_file(path.display().to_string()))?;
+        }
+        return Ok(());
+    }
+    let content = shallow.iter().map(|hash| format!("{}\n", hash)).collect::<String>();
+    write_ref_file(&path, &content)
+}
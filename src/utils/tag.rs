@@ -0,0 +1,85 @@
+use std::{
+    fmt,
+    result,
+    convert::TryFrom,
+    error::Error,
+};
+
+use crate::utils::{
+    objtype::{
+        Obj,
+        ObjType,
+        parse_meta,
+    },
+    error::{
+        GitError,
+        Result
+    },
+    hash::ObjectId,
+};
+
+/// annotated tag object
+/// object <hash>\ntype commit\ntag <name>\ntagger <ident>\n\n<message>
+pub struct Tag {
+    pub object: ObjectId,
+    pub kind: String,
+    pub tag: String,
+    pub tagger: String,
+    pub message: String,
+}
+
+impl ObjType for Tag {
+    const VALUE: &'static str = "tag";
+    const MODE: u32 = 0;
+}
+
+impl TryFrom<Vec<u8>> for Tag {
+    type Error = Box<dyn Error>;
+
+    fn try_from(bytes: Vec<u8>) -> result::Result<Self, Self::Error> {
+        let (body, _) = parse_meta(&bytes).map_err(|e| GitError::invalid_obj(e.to_string()))?;
+        let body = String::from_utf8(body.to_vec())?;
+        let (header, message) = body.split_once("\n\n").unwrap_or((body.as_str(), ""));
+
+        let field = |name: &str| header.lines()
+            .find_map(|line| line.strip_prefix(name).and_then(|x| x.strip_prefix(' ')))
+            .map(String::from);
+        let missing = |name: &str| GitError::invalid_obj(format!("tag object without {} line", name));
+
+        Ok(Tag {
+            object: ObjectId::from_hex(&field("object").ok_or_else(|| missing("object"))?)?,
+            kind: field("type").ok_or_else(|| missing("type"))?,
+            tag: field("tag").ok_or_else(|| missing("tag"))?,
+            // very old tags have no tagger
+            tagger: field("tagger").unwrap_or_default(),
+            message: message.to_string(),
+        })
+    }
+}
+
+impl From<Tag> for Vec<u8> {
+    fn from(tag: Tag) -> Vec<u8> {
+        tag.to_string().into_bytes()
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "object {}\ntype {}\ntag {}\n", self.object, self.kind, self.tag)?;
+        if !self.tagger.is_empty() {
+            writeln!(f, "tagger {}", self.tagger)?;
+        }
+        write!(f, "\n{}", self.message)
+    }
+}
+
+impl TryFrom<Obj> for Tag {
+    type Error = Box<dyn Error>;
+
+    fn try_from(obj: Obj) -> Result<Tag> {
+        match obj {
+            Obj::G(tag) => Ok(tag),
+            _ => Err(GitError::invalid_obj("not a tag object".to_string())),
+        }
+    }
+}
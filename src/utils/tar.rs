@@ -0,0 +1,166 @@
+use std::io::Write;
+
+use crate::{GitError, Result};
+
+const BLOCK: usize = 512;
+/// tar writes in records of 20 blocks, git pads the archive to a whole record too
+const RECORD: usize = 20 * BLOCK;
+/// the largest number the 11 octal digits of the size and mtime fields hold, 8 GiB - 1
+const USTAR_MAX: u64 = 0o77777777777;
+
+/// what a tar entry is, the ustar typeflag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Symlink,
+    Directory,
+}
+
+impl EntryKind {
+    fn typeflag(&self) -> u8 {
+        match self {
+            EntryKind::File => b'0',
+            EntryKind::Symlink => b'2',
+            EntryKind::Directory => b'5',
+        }
+    }
+}
+
+/// a POSIX (ustar) archive written entry by entry, owned by root like git archive does
+/// names and sizes that do not fit the header go into a pax extended header before the entry
+pub struct TarWriter<W: Write> {
+    out: W,
+    written: usize,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> Self {
+        TarWriter { out, written: 0 }
+    }
+
+    /// a pax global header with `comment=<commit>`, where `git get-tar-commit-id` looks for it
+    pub fn global_comment(&mut self, comment: &str) -> Result<()> {
+        let records = pax_record("comment", comment.as_bytes());
+        self.write_entry(b"pax_global_header", b'g', 0o666, 0, b"", &records)
+    }
+
+    /// `data` is the file content or the target of a symlink, directories have none
+    pub fn append(&mut self, path: &[u8], kind: EntryKind, mode: u32, mtime: i64, data: &[u8]) -> Result<()> {
+        let (content, link) = match kind {
+            EntryKind::Symlink => (&b""[..], data),
+            _ => (data, &b""[..]),
+        };
+        let mut records = Vec::new();
+        if split_name(path).is_none() {
+            records.extend(pax_record("path", path));
+        }
+        if link.len() > 100 {
+            records.extend(pax_record("linkpath", link));
+        }
+        if content.len() as u64 > USTAR_MAX {
+            records.extend(pax_record("size", content.len().to_string().as_bytes()));
+        }
+        if !records.is_empty() {
+            self.write_entry(b"pax_extended_header", b'x', 0o666, mtime, b"", &records)?;
+        }
+        self.write_entry(path, kind.typeflag(), mode, mtime, link, content)
+    }
+
+    /// the two zero blocks which end the archive, padded to a whole record
+    pub fn finish(mut self) -> Result<W> {
+        let end = 2 * BLOCK;
+        let padding = (RECORD - (self.written + end) % RECORD) % RECORD;
+        self.out.write_all(&vec![0; end + padding])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_entry(&mut self, path: &[u8], typeflag: u8, mode: u32, mtime: i64, link: &[u8], content: &[u8]) -> Result<()> {
+        let mut header = [0u8; BLOCK];
+        // too long names are in the pax header already, the ustar field keeps a truncated copy
+        let (prefix, name) = split_name(path).unwrap_or((&b""[..], &path[..path.len().min(100)]));
+        header[..name.len()].copy_from_slice(name);
+        // like git, a size too large for ustar is only in the pax header and the field says 0,
+        // a date after 2242 is clamped
+        let size = Some(content.len() as u64).filter(|&size| size <= USTAR_MAX).unwrap_or(0);
+        octal(&mut header[100..108], mode as u64)?;
+        octal(&mut header[108..116], 0)?;
+        octal(&mut header[116..124], 0)?;
+        octal(&mut header[124..136], size)?;
+        octal(&mut header[136..148], (mtime.max(0) as u64).min(USTAR_MAX))?;
+        header[156] = typeflag;
+        let link = &link[..link.len().min(100)];
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[265..269].copy_from_slice(b"root");
+        header[297..301].copy_from_slice(b"root");
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+        // the checksum is taken with its own field as spaces
+        header[148..156].fill(b' ');
+        let checksum = header.iter().map(|&b| b as u64).sum::<u64>();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        self.out.write_all(&header)?;
+        self.out.write_all(content)?;
+        let padding = (BLOCK - content.len() % BLOCK) % BLOCK;
+        self.out.write_all(&vec![0; padding])?;
+        self.written += BLOCK + content.len() + padding;
+        Ok(())
+    }
+}
+
+/// a numeric header field: octal, zero padded, NUL terminated
+fn octal(field: &mut [u8], value: u64) -> Result<()> {
+    let text = format!("{:0width$o}\0", value, width = field.len() - 1);
+    if text.len() != field.len() {
+        return Err(GitError::invalid_argument(format!("{} does not fit in a tar header field", value)));
+    }
+    field.copy_from_slice(text.as_bytes());
+    Ok(())
+}
+
+/// split a path into the 155 byte prefix and 100 byte name fields of ustar, at a `/`
+fn split_name(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= 100 {
+        return Some((b"", path));
+    }
+    (0..path.len())
+        .filter(|&i| path[i] == b'/' && i <= 155 && path.len() - i - 1 <= 100 && i + 1 < path.len())
+        .map(|i| (&path[..i], &path[i + 1..]))
+        .next()
+}
+
+/// `<length> <key>=<value>\n`, the length counts its own digits as well
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while (rest + len.to_string().len()) != len {
+        len = rest + len.to_string().len();
+    }
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+#[cfg(test)]
+mod test {
+    use super::{octal, pax_record, EntryKind, TarWriter, USTAR_MAX, BLOCK};
+
+    #[test]
+    fn test_header_limits() {
+        let mut field = [0u8; 12];
+        octal(&mut field, USTAR_MAX).unwrap();
+        assert_eq!(&field, b"77777777777\0");
+        // 8 GiB needs a twelfth digit, it goes into a pax `size` record instead
+        assert!(octal(&mut field, USTAR_MAX + 1).is_err());
+        assert_eq!(pax_record("size", (USTAR_MAX + 1).to_string().as_bytes()), b"19 size=8589934592\n");
+
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append(b"far-future", EntryKind::File, 0o644, i64::MAX, b"x").unwrap();
+        let out = tar.finish().unwrap();
+        assert_eq!(&out[136..148], b"77777777777\0");
+        assert_eq!(out.len() % (20 * BLOCK), 0);
+    }
+}
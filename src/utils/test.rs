@@ -14,7 +14,10 @@ use std::{
         Path,
         PathBuf
     },
-    process::Command
+    process::{Command, Stdio},
+    io::{BufRead, BufReader, Read},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
 };
 pub use tempfile::{
     tempdir,
@@ -139,3 +142,84 @@ pub fn run_both<'a>(cmds: ArgsList<'a>, git: Args, cargo: Args) -> Result<(Vec<S
     let mut opers = cmd_seq(cmds);
     Ok((opers(git)?, opers(cargo)?))
 }
+
+/// a minimal smart http server in front of `git upload-pack --stateless-rpc`
+/// the path is ignored, so it answers requests sent to it as a proxy too; every request line is recorded
+/// a `Git-Protocol` header is passed on to upload-pack, like git http-backend does, so it speaks v2 when asked
+pub fn serve_upload_pack(repo: PathBuf) -> (u16, Receiver<String>) {
+    serve(repo, |_| {}, true)
+}
+
+/// like `serve_upload_pack`, `tamper` may change the response to a POST before it is sent
+pub fn serve_upload_pack_with(repo: PathBuf, tamper: fn(&mut Vec<u8>)) -> (u16, Receiver<String>) {
+    serve(repo, tamper, true)
+}
+
+/// an old server which ignores `Git-Protocol` and always speaks v0
+pub fn serve_upload_pack_v0(repo: PathBuf) -> (u16, Receiver<String>) {
+    serve(repo, |_| {}, false)
+}
+
+fn serve(repo: PathBuf, tamper: fn(&mut Vec<u8>), v2: bool) -> (u16, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = handle_request(&repo, stream, &sender, tamper, v2);
+        }
+    });
+    (port, receiver)
+}
+
+fn handle_request(repo: &Path, mut stream: TcpStream, requests: &Sender<String>, tamper: fn(&mut Vec<u8>), v2: bool) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let _ = requests.send(request_line.trim().to_string());
+    let mut content_length = 0;
+    let mut protocol = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            content_length = value.trim().parse().unwrap();
+        }
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("git-protocol:") {
+            protocol = value.trim().to_string();
+        }
+    }
+    if !v2 {
+        protocol.clear();
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let payload = if request_line.starts_with("GET") {
+        let refs = Command::new("git")
+            .args(["upload-pack", "--stateless-rpc", "--advertise-refs"])
+            .arg(repo)
+            .env("GIT_PROTOCOL", &protocol)
+            .output()?
+            .stdout;
+        [&b"001e# service=git-upload-pack\n0000"[..], &refs].concat()
+    }
+    else {
+        let mut child = Command::new("git")
+            .args(["upload-pack", "--stateless-rpc"])
+            .arg(repo)
+            .env("GIT_PROTOCOL", &protocol)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(&body)?;
+        let mut payload = child.wait_with_output()?.stdout;
+        tamper(&mut payload);
+        payload
+    };
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", payload.len())?;
+    stream.write_all(&payload)
+}
@@ -16,8 +16,10 @@ use std::{
         TryFrom,
     },
     error::Error,
-    path::PathBuf,
+    path::{Path, PathBuf},
     iter::Iterator,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
 };
 
 use hex;
@@ -51,7 +53,10 @@ use crate::utils::{
     fs::{
         read_obj,
         read_object,
+        read_raw_object,
+        quote_path,
     },
+    hash::{algorithm, ObjectId},
     error::{
         GitError,
         Result
@@ -76,10 +81,10 @@ impl fmt::Display for FileMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}",
             match self {
-                FileMode::Blob | FileMode::Exec => "blob",
+                // a symlink is stored as a blob holding the link target
+                FileMode::Blob | FileMode::Exec | FileMode::Symbolic => "blob",
                 FileMode::Tree => "tree",
                 FileMode::Commit => "commit",
-                FileMode::Symbolic => "symbolic",
             })
     }
 }
@@ -131,31 +136,71 @@ impl TryFrom<&[u8]> for FileMode {
 #[derive(Clone, Debug)]
 pub struct TreeEntry {
     pub mode: FileMode, //
-    pub hash: String,   // 40 length
+    pub hash: ObjectId,
     pub path: PathBuf,  // relative to git dir
 }
 
-type EntryPrototype<'a> = (&'a[u8], &'a[u8], &'a[u8]);
-impl<'a> TryFrom<EntryPrototype<'a>> for TreeEntry {
-    type Error = Box<dyn Error>;
+/// mode, path and raw hash of an entry as they sit in the tree object
+type EntryPrototype<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+/// a tree entry borrowed from the object bytes, nothing is copied until `to_entry`
+#[derive(Clone, Copy, Debug)]
+pub struct TreeEntryRef<'a> {
+    pub mode: FileMode,
+    pub name: &'a [u8],
+    /// the raw bytes, not hex
+    pub hash: &'a [u8],
+}
 
-    fn try_from(enp: EntryPrototype) -> result::Result<Self, Self::Error> {
-        let mode = enp.0.try_into()?;
-        let path = PathBuf::from(&String::from_utf8(enp.1.to_vec())?);
-        let hash = encode(enp.2);
-        Ok(TreeEntry {
-            mode,
-            hash,
-            path,
-        })
+impl<'a> TreeEntryRef<'a> {
+    pub fn hex(&self) -> String {
+        encode(self.hash)
+    }
+
+    pub fn id(&self) -> ObjectId {
+        ObjectId::from_raw(self.hash).expect("the parser takes a hash of the repository's length")
+    }
+
+    /// the name exactly as stored, git does not care whether it is utf-8
+    pub fn path(&self) -> &'a Path {
+        Path::new(OsStr::from_bytes(self.name))
+    }
+
+    pub fn to_entry(self) -> TreeEntry {
+        TreeEntry {
+            mode: self.mode,
+            hash: self.id(),
+            path: self.path().to_path_buf(),
+        }
+    }
+}
+
+/// the entries of a tree object, borrowed from its bytes
+pub struct TreeRef<'a>(pub Vec<TreeEntryRef<'a>>);
+
+impl<'a> TreeRef<'a> {
+    /// parse `bytes` with or without the `tree <len>\0` header
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let entrys = match parse_meta(bytes) {
+            Ok((entrys, _)) => entrys,
+            Err(_) => bytes,
+        };
+        let (_, entrys) = terminated(many0(TreeEntry::parse_from_bytes), eof)
+            .parse(entrys)
+            .map_err(|e| GitError::invalid_entry(e.to_owned()))?;
+        Ok(TreeRef(entrys
+            .into_iter()
+            .map(|(mode, name, hash)| Ok(TreeEntryRef { mode: mode.try_into()?, name, hash }))
+            .collect::<Result<Vec<_>>>()?))
     }
 }
 
 impl TreeEntry {
-    fn parse_from_bytes(bytes: &[u8]) -> IResult<&[u8], EntryPrototype> {
-        let parse_mode = terminated(take_until(" "), tag(" "));
+    fn parse_from_bytes(bytes: &[u8]) -> IResult<&[u8], EntryPrototype<'_>> {
+        // 模式只有数字，后面恰好一个空格，剩下直到 \0 的都是文件名，可以含空格
+        let parse_mode = terminated(digit1, tag(" "));
         let parse_path = terminated(take_until("\0"), tag("\0"));
-        let parse_hash = take(20usize);
+        let parse_hash = take(algorithm().raw_len());
 
         (
             parse_mode,
@@ -164,40 +209,39 @@ impl TreeEntry {
         ).parse(bytes)
     }
 
-    fn into_iter(self) -> impl Iterator<Item = u8> {
-        let mode: &str = self.mode.into();
-        let hash = hex::decode(&self.hash).unwrap();
-        let path = self.path.to_str().unwrap().as_bytes().to_vec();
-
-        mode.to_string()
-            .into_bytes()
-            .into_iter()
-            .chain(b" ".iter().cloned())
-            .chain(path)
-            .chain(b"\0".iter().cloned())
-            .chain(hash)
+    /// append the entry the way a tree object stores it
+    fn write_to(&self, out: &mut Vec<u8>) {
+        // 和 write-tree 一样，目录写成 40000，不补前导 0
+        out.extend_from_slice(format!("{:o}", self.mode as u32).as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(self.path.as_os_str().as_bytes());
+        out.push(b'\0');
+        out.extend_from_slice(self.hash.as_bytes());
     }
+}
 
-    fn into_iter_flatten(self, gitdir: PathBuf) -> Result<Vec<Self>> {
-        let obj = read_obj(gitdir.clone(), &self.hash)?;
-        // println!("self = {}", self);
-        match obj {
-            Obj::B(_) => Ok(vec![self]),
-            Obj::T(tree) => Ok(tree.0
-                .into_iter()
-                .map(|entry|entry.into_iter_flatten(gitdir.clone()))
-                .collect::<Result<Vec<_>>>()?
-                .into_iter()
-                .flatten()
-                .map(|TreeEntry{mode, hash, path}| TreeEntry {
-                    mode,
-                    hash,
-                    path: self.path.join(path)
-                })
-                .collect::<Vec<_>>()),
-            Obj::C(cmt) => Err(GitError::invalid_commit(&format!("commit object {cmt} in tree object! your git repo is totaly fucked up!")))
+/// the leaves below the tree `hash`, with paths under `prefix`
+/// blobs, links and submodules are leaves by their mode, only sub-trees are read
+fn flatten_into(gitdir: &Path, hash: &ObjectId, prefix: &Path, out: &mut Vec<TreeEntry>) -> Result<()> {
+    let bytes = read_raw_object(gitdir, hash)?;
+    let index = bytes.iter().position(|&b| b == b'\0')
+        .ok_or_else(|| GitError::invalid_obj(format!("object {} has no header", hash)))?;
+    let (_, (kind, _)) = parse_meta(&bytes[..=index]).map_err(|e| GitError::invalid_tree(e.to_owned()))?;
+    match kind {
+        b"tree" => {},
+        b"commit" => return Err(GitError::invalid_commit(&format!("commit object {hash} in tree object! your git repo is totaly fucked up!"))),
+        other => return Err(GitError::invalid_obj(format!("{} object {} in tree object", String::from_utf8_lossy(other), hash))),
+    }
+    for entry in TreeRef::parse(&bytes)?.0 {
+        let path = prefix.join(entry.path());
+        if entry.mode == FileMode::Tree {
+            flatten_into(gitdir, &entry.id(), &path, out)?;
+        }
+        else {
+            out.push(TreeEntry { mode: entry.mode, hash: entry.id(), path });
         }
     }
+    Ok(())
 }
 
 
@@ -206,7 +250,7 @@ impl From<IndexEntry> for TreeEntry {
         Self {
             mode: entry.mode.try_into().unwrap(),
             hash: entry.hash,
-            path: PathBuf::from(entry.name),
+            path: entry.name,
         }
     }
 }
@@ -217,17 +261,10 @@ impl TryFrom<&[u8]> for TreeEntry {
     fn try_from(bytes: &[u8]) -> result::Result<Self, Self::Error> {
         // [mode] space [path] 0x00 [sha-1]
 
-        let (_, (modebytes, hashbytes, pathbytes)) = TreeEntry::parse_from_bytes(bytes)
-                                                                                    .map_err(GitError::invalid_entry)?;
-
-        let mode = modebytes.try_into()?;
-        let path = PathBuf::from(String::from_utf8(pathbytes.to_vec())?);
-        let hash = String::from_utf8(hashbytes.to_vec())?;
-        Ok(TreeEntry {
-            mode,
-            hash,
-            path,
-        })
+        let (_, (modebytes, pathbytes, hashbytes)) = TreeEntry::parse_from_bytes(bytes)
+                                                                                    .map_err(|e| GitError::invalid_entry(e.to_owned()))?;
+
+        Ok(TreeEntryRef { mode: modebytes.try_into()?, name: pathbytes, hash: hashbytes }.to_entry())
     }
 }
 
@@ -242,7 +279,7 @@ impl Eq for TreeEntry {
 
 impl PartialOrd for TreeEntry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.path.cmp(&other.path))
+        Some(self.cmp(other))
     }
 }
 
@@ -252,22 +289,44 @@ impl Ord for TreeEntry {
     }
 }
 
+/// git sorts tree entries by name, but compares a directory as if its name had a trailing `/`,
+/// so `foo.txt` comes before the directory `foo` while the file `foo` comes before `foo.txt`
+pub fn tree_entry_cmp(a: &[u8], a_is_tree: bool, b: &[u8], b_is_tree: bool) -> Ordering {
+    let len = a.len().min(b.len());
+    match a[..len].cmp(&b[..len]) {
+        Ordering::Equal => {
+            let next = |name: &[u8], is_tree: bool| match name.get(len) {
+                Some(c) => *c,
+                None if is_tree => b'/',
+                None => 0,
+            };
+            next(a, a_is_tree).cmp(&next(b, b_is_tree))
+        },
+        other => other,
+    }
+}
 
 impl fmt::Display for TreeEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:06o} {} {}\t{}", self.mode as u32, self.mode, self.hash.clone(), self.path.display())
+        write!(f, "{:06o} {} {}\t{}", self.mode as u32, self.mode, self.hash, quote_path(&self.path))
     }
 }
 
 pub struct Tree(pub Vec<TreeEntry>);
 
 impl Tree {
-    pub fn into_iter_flatten(self, gitdir: PathBuf) -> Result<impl IntoIterator<Item = TreeEntry>> {
-        Ok(self.0.into_iter()
-            .map(|en|en.into_iter_flatten(gitdir.clone()))
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .flatten())
+    /// every blob, link and submodule below this tree, sub-trees replaced by their contents
+    pub fn flatten(&self, gitdir: &Path) -> Result<Vec<TreeEntry>> {
+        let mut out = Vec::with_capacity(self.0.len());
+        for entry in &self.0 {
+            if entry.mode == FileMode::Tree {
+                flatten_into(gitdir, &entry.hash, &entry.path, &mut out)?;
+            }
+            else {
+                out.push(entry.clone());
+            }
+        }
+        Ok(out)
     }
 }
 
@@ -275,26 +334,23 @@ impl TryFrom<Vec<u8>> for Tree {
     type Error = Box<dyn Error>;
 
     fn try_from(bytes: Vec<u8>) -> result::Result<Self, Self::Error> {
-        let (entrys, _) = parse_meta(bytes.as_slice()).map_err(GitError::invalid_tree)?;
-
-        let (_, entrys_str) = terminated(many0(TreeEntry::parse_from_bytes), eof)
-            .parse(entrys)
-            .map_err(GitError::invalid_entry)?;
-        let entrys = entrys_str
-            .into_iter()
-            .map(|prototype| prototype.try_into())
-            .collect::<Result<Vec<TreeEntry>>>()?;
+        let _ = parse_meta(bytes.as_slice()).map_err(|e| GitError::invalid_tree(e.to_owned()))?;
+        let entrys = TreeRef::parse(&bytes)?.0
+            .iter()
+            .map(|entry| entry.to_entry())
+            .collect::<Vec<TreeEntry>>();
 
         Ok(Tree(entrys))
     }
 }
 
 impl From<Tree> for Vec<u8> {
-    fn from(val: Tree) -> Vec<u8> {
-        val.0
-            .into_iter()
-            .flat_map(|x|x.into_iter())
-            .collect()
+    fn from(val: Tree) -> Self {
+        let mut out = Vec::with_capacity(val.0.len() * (algorithm().raw_len() + 28));
+        for entry in &val.0 {
+            entry.write_to(&mut out);
+        }
+        out
     }
 }
 
@@ -345,3 +401,91 @@ impl From<Index> for Tree {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::{
+        blob::Blob,
+        fs::{obj_to_pathbuf, write_object},
+        test::{setup_test_git_dir, shell_spawn, time_it},
+    };
+
+    /// the way trees were flattened before: every entry's object is read and parsed into an owned Obj
+    fn flatten_reading_every_object(gitdir: &Path, tree: Tree) -> Result<Vec<TreeEntry>> {
+        let mut out = vec![];
+        for entry in tree.0 {
+            match read_obj(gitdir.to_path_buf(), &entry.hash)? {
+                Obj::T(sub) => out.extend(flatten_reading_every_object(gitdir, sub)?
+                    .into_iter()
+                    .map(|sub_entry| TreeEntry { path: entry.path.join(&sub_entry.path), ..sub_entry })),
+                _ => out.push(entry),
+            }
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_flatten_large_tree() {
+        let temp = setup_test_git_dir();
+        let temp_path_str = temp.path().to_str().unwrap();
+        let gitdir = temp.path().join(".git");
+        let blob = write_object::<Blob>(gitdir.clone(), b"same content\n".to_vec()).unwrap();
+
+        // 250 个目录，每个 200 个文件，一共 5 万个条目
+        let mut root = vec![];
+        for dir in 0..250 {
+            let entries = (0..200)
+                .map(|file| TreeEntry { mode: FileMode::Blob, hash: blob, path: PathBuf::from(format!("f{}_{}", dir, file)) })
+                .collect::<Vec<_>>();
+            let mut sub = Tree(entries);
+            sub.sort_by(|a, b| tree_entry_cmp(a.path.to_str().unwrap().as_bytes(), false, b.path.to_str().unwrap().as_bytes(), false));
+            let hash = write_object::<Tree>(gitdir.clone(), sub.into()).unwrap();
+            root.push(TreeEntry { mode: FileMode::Tree, hash, path: PathBuf::from(format!("d{}", dir)) });
+        }
+        root.sort_by(|a, b| tree_entry_cmp(a.path.to_str().unwrap().as_bytes(), true, b.path.to_str().unwrap().as_bytes(), true));
+        let root_hash = write_object::<Tree>(gitdir.clone(), Tree(root).into()).unwrap();
+        assert!(shell_spawn(&["git", "-C", temp_path_str, "fsck", "--full"]).is_ok());
+
+        let read_root = || read_object::<Tree>(gitdir.clone(), &root_hash).unwrap();
+        let before = time_it(|| flatten_reading_every_object(&gitdir, read_root()).map(|_| ())).unwrap();
+        let after = time_it(|| read_root().flatten(&gitdir).map(|_| ())).unwrap();
+        // timings depend on the machine, they are only reported
+        println!("flatten 50k entries: {} ms reading every object, {} ms borrowing", before, after);
+
+        let flattened = read_root().flatten(&gitdir).unwrap();
+        assert_eq!(flattened.len(), 50000);
+        let old = flatten_reading_every_object(&gitdir, read_root()).unwrap();
+        assert!(flattened.iter().zip(&old).all(|(a, b)| a.path == b.path && a.hash == b.hash && a.mode == b.mode));
+        let ls_tree = shell_spawn(&["git", "-C", temp_path_str, "ls-tree", "-r", &root_hash.to_hex()]).unwrap();
+        assert_eq!(ls_tree.lines().map(str::to_string).collect::<Vec<_>>(), flattened.iter().map(|entry| entry.to_string()).collect::<Vec<_>>());
+
+        // 只读树对象：blob 不在了也能展开，逐个读对象的老办法就读不下去
+        std::fs::remove_file(obj_to_pathbuf(&gitdir, &blob)).unwrap();
+        assert_eq!(read_root().flatten(&gitdir).unwrap().len(), 50000);
+        assert!(flatten_reading_every_object(&gitdir, read_root()).is_err());
+
+        // 借用解析出来的条目和拥有所有权的一致，写回去字节也不变
+        let bytes = read_raw_object(&gitdir, &root_hash).unwrap();
+        let borrowed = TreeRef::parse(&bytes).unwrap();
+        let owned = read_root();
+        assert_eq!(borrowed.0.len(), owned.len());
+        assert!(borrowed.0.iter().zip(owned.iter()).all(|(a, b)| a.to_entry().path == b.path && a.id() == b.hash));
+        let body = bytes[bytes.iter().position(|&b| b == 0).unwrap() + 1..].to_vec();
+        assert_eq!(Vec::<u8>::from(owned), body);
+    }
+
+    #[test]
+    fn test_write_bad_hash() {
+        // a hash of the wrong length or not in hex is an error, not a panic
+        for hash in ["abc", "zz".repeat(algorithm().raw_len()).as_str(), &"0".repeat(algorithm().hex_len() + 2)] {
+            assert!(ObjectId::from_hex(hash).is_err(), "{}", hash);
+        }
+        let tree = Tree(vec![TreeEntry { mode: FileMode::Blob, hash: ObjectId::null(), path: PathBuf::from("f") }]);
+        assert_eq!(Vec::<u8>::from(tree).len(), "100644 f\0".len() + algorithm().raw_len());
+
+        // a truncated entry is reported with the parser's error as its source
+        let err = TreeRef::parse(b"100644 f\0abc").err().unwrap();
+        assert!(err.source().is_some_and(|source| source.is::<nom::Err<nom::error::Error<Vec<u8>>>>()));
+    }
+}
@@ -0,0 +1,208 @@
+use std::io::Write;
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use flate2::{Compression, Crc, write::DeflateEncoder};
+
+use crate::{GitError, Result};
+use super::tar::EntryKind;
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+/// zip 1.0 is enough to extract everything git writes, it says so even for deflated files
+const VERSION_NEEDED: u16 = 10;
+/// zip 4.5 brought ZIP64, needed as soon as a header carries its extra field
+const VERSION_ZIP64: u16 = 45;
+/// made on unix with zip 2.3, only needed when the unix mode matters
+const VERSION_UNIX: u16 = 0x0317;
+const METHOD_STORE: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+/// general purpose flag: the name is UTF-8
+const FLAG_UTF8: u16 = 0x0800;
+/// the "UT" extended timestamp extra field, carrying the modification time
+const EXTRA_TIMESTAMP: u16 = 0x5455;
+/// the ZIP64 extra field, holding the sizes and the offset that don't fit in 32 bits
+const EXTRA_ZIP64: u16 = 0x0001;
+/// a 32 bit field with all bits set means the real value is in the ZIP64 extra field
+const ZIP64_MARK: u64 = u32::MAX as u64;
+
+/// a zip archive written entry by entry the way git archive does, the central directory at the end
+/// files are deflated when that makes them smaller, directories and symlinks are stored,
+/// ZIP64 fields are only added where a size, an offset or the number of entries needs them
+pub struct ZipWriter<W: Write> {
+    out: W,
+    written: u64,
+    central: Vec<u8>,
+    entries: u64,
+    /// the highest "version made by" of any entry, repeated in the ZIP64 end record
+    made_by: u16,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(out: W) -> Self {
+        ZipWriter { out, written: 0, central: Vec::new(), entries: 0, made_by: 0 }
+    }
+
+    /// `data` is the file content or the target of a symlink, directories have none and end with `/`
+    pub fn append(&mut self, path: &[u8], kind: EntryKind, mode: u32, mtime: i64, data: &[u8]) -> Result<()> {
+        let deflated = match kind {
+            EntryKind::File if !data.is_empty() => Some(deflate(data)?).filter(|deflated| deflated.len() < data.len()),
+            _ => None,
+        };
+        let (method, content) = match &deflated {
+            Some(deflated) => (METHOD_DEFLATE, deflated.as_slice()),
+            None => (METHOD_STORE, data),
+        };
+        let mut crc = Crc::new();
+        crc.update(data);
+        // only symlinks and executables need the unix mode, the rest look like they came from DOS
+        let (made_by, external) = match kind {
+            EntryKind::Directory => (0, 0x10),
+            EntryKind::Symlink => (VERSION_UNIX, (0o120000 | mode) << 16),
+            EntryKind::File if mode & 0o111 != 0 => (VERSION_UNIX, (0o100000 | mode) << 16),
+            EntryKind::File => (0, 0),
+        };
+        // the text bit, set unless there is a NUL near the start like git's binary check
+        let text = kind != EntryKind::Directory && !data[..data.len().min(8000)].contains(&0);
+        let flags = if path.is_ascii() { 0 } else { FLAG_UTF8 };
+        let path_len = u16::try_from(path.len())
+            .map_err(|_| GitError::invalid_argument(format!("path too long for a zip archive: {}", String::from_utf8_lossy(path))))?;
+        let (time, date) = dos_time(mtime);
+        let mut timestamp = Vec::new();
+        timestamp.extend_from_slice(&EXTRA_TIMESTAMP.to_le_bytes());
+        timestamp.extend_from_slice(&5u16.to_le_bytes());
+        timestamp.push(1);
+        timestamp.extend_from_slice(&(mtime as u32).to_le_bytes());
+
+        // both sizes move into the ZIP64 field together, the local header needs both of them there
+        let (size, compressed, offset) = (data.len() as u64, content.len() as u64, self.written);
+        let large = size >= ZIP64_MARK || compressed >= ZIP64_MARK;
+        let mut local_zip64 = Vec::new();
+        if large {
+            local_zip64.extend_from_slice(&size.to_le_bytes());
+            local_zip64.extend_from_slice(&compressed.to_le_bytes());
+        }
+        let mut central_zip64 = local_zip64.clone();
+        if offset >= ZIP64_MARK {
+            central_zip64.extend_from_slice(&offset.to_le_bytes());
+        }
+        let extra = |zip64: &[u8]| {
+            let mut extra = Vec::new();
+            if !zip64.is_empty() {
+                extra.extend_from_slice(&EXTRA_ZIP64.to_le_bytes());
+                extra.extend_from_slice(&(zip64.len() as u16).to_le_bytes());
+                extra.extend_from_slice(zip64);
+            }
+            extra.extend_from_slice(&timestamp);
+            extra
+        };
+        let (local_extra, central_extra) = (extra(&local_zip64), extra(&central_zip64));
+        let needed = |zip64: &[u8]| if zip64.is_empty() { VERSION_NEEDED } else { VERSION_ZIP64 };
+
+        // the fields both headers share, from the flags to the extra field's length
+        let common = |extra: &[u8]| {
+            let mut common = Vec::new();
+            for field in [flags, method, time, date] {
+                common.extend_from_slice(&field.to_le_bytes());
+            }
+            let sizes = if large { [u32::MAX; 2] } else { [compressed as u32, size as u32] };
+            for field in [crc.sum(), sizes[0], sizes[1]] {
+                common.extend_from_slice(&field.to_le_bytes());
+            }
+            common.extend_from_slice(&path_len.to_le_bytes());
+            common.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            common
+        };
+
+        let mut local = Vec::new();
+        local.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        local.extend_from_slice(&needed(&local_zip64).to_le_bytes());
+        local.extend_from_slice(&common(&local_extra));
+        local.extend_from_slice(path);
+        local.extend_from_slice(&local_extra);
+
+        let central = &mut self.central;
+        central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        central.extend_from_slice(&made_by.to_le_bytes());
+        central.extend_from_slice(&needed(&central_zip64).to_le_bytes());
+        central.extend_from_slice(&common(&central_extra));
+        // no comment, first disk, then the attributes
+        central.extend_from_slice(&[0; 4]);
+        central.extend_from_slice(&(text as u16).to_le_bytes());
+        central.extend_from_slice(&external.to_le_bytes());
+        central.extend_from_slice(&clamp(offset).to_le_bytes());
+        central.extend_from_slice(path);
+        central.extend_from_slice(&central_extra);
+
+        self.out.write_all(&local)?;
+        self.out.write_all(content)?;
+        self.written = self.written.checked_add(local.len() as u64 + compressed)
+            .ok_or_else(|| GitError::invalid_argument("zip archive larger than 16 EiB".to_string()))?;
+        self.entries += 1;
+        self.made_by = self.made_by.max(made_by);
+        Ok(())
+    }
+
+    /// the central directory and its end record, `comment` is where git puts the commit
+    pub fn finish(mut self, comment: &str) -> Result<W> {
+        let comment_len = u16::try_from(comment.len())
+            .map_err(|_| GitError::invalid_argument("comment too long for a zip archive".to_string()))?;
+        let (entries, size, offset) = (self.entries, self.central.len() as u64, self.written);
+        let mut end = Vec::new();
+        // like git, the ZIP64 end record and its locator only come when a field overflows
+        if entries > u16::MAX as u64 || size >= ZIP64_MARK || offset >= ZIP64_MARK {
+            end.extend_from_slice(&ZIP64_END_OF_CENTRAL.to_le_bytes());
+            // the size of the rest of the record
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&self.made_by.to_le_bytes());
+            end.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+            end.extend_from_slice(&[0; 8]);
+            for field in [entries, entries, size, offset] {
+                end.extend_from_slice(&field.to_le_bytes());
+            }
+            end.extend_from_slice(&ZIP64_LOCATOR.to_le_bytes());
+            end.extend_from_slice(&[0; 4]);
+            end.extend_from_slice(&(offset + size).to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let entries = entries.min(u16::MAX as u64) as u16;
+        end.extend_from_slice(&END_OF_CENTRAL.to_le_bytes());
+        // a single disk
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&clamp(size).to_le_bytes());
+        end.extend_from_slice(&clamp(offset).to_le_bytes());
+        end.extend_from_slice(&comment_len.to_le_bytes());
+        end.extend_from_slice(comment.as_bytes());
+
+        self.out.write_all(&self.central)?;
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// a 32 bit field, all bits set when the value has to be looked up in the ZIP64 fields
+fn clamp(value: u64) -> u32 {
+    value.min(ZIP64_MARK) as u32
+}
+
+/// raw deflate without a zlib header, at zlib's default level like git archive
+fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// MS-DOS time and date in local time, two second steps, 1980 at the earliest
+fn dos_time(mtime: i64) -> (u16, u16) {
+    match Local.timestamp_opt(mtime, 0).single() {
+        Some(time) if time.year() >= 1980 => (
+            ((time.hour() << 11) | (time.minute() << 5) | (time.second() / 2)) as u16,
+            (((time.year() as u32 - 1980) << 9) | (time.month() << 5) | time.day()) as u16,
+        ),
+        _ => (0, (1 << 5) | 1),
+    }
+}
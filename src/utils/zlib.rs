@@ -1,5 +1,7 @@
 use std::fs::File;
+use std::env;
 use std::path::Path;
+use std::sync::OnceLock;
 use std::io::{self, Read, Write, BufReader};
 use flate2::{
     Compression,
@@ -8,6 +10,7 @@ use flate2::{
 };
 use crate::{
     utils::{
+        config::Config,
         fs::read_file_as_bytes,
         objtype::ObjType,
     },
@@ -50,11 +53,46 @@ where P: AsRef<Path>
 }
 
 
+/// the level everything is compressed with unless a caller picks its own, set once per process
+static LEVEL: OnceLock<Compression> = OnceLock::new();
+
+/// git's `core.compression` (-1 is zlib's default), falling back to $GIT_COMPRESSION and then to level 6 like git
+pub fn init_level(config: &Config) -> Result<()> {
+    let value = config.get("core.compression").map(str::to_string)
+        .or_else(|| env::var("GIT_COMPRESSION").ok());
+    let level = match value {
+        Some(value) => parse_level(&value)?,
+        None => Compression::default(),
+    };
+    // only main sets it, once
+    let _ = LEVEL.set(level);
+    Ok(())
+}
+
+fn parse_level(value: &str) -> Result<Compression> {
+    match value.trim().parse::<i32>() {
+        Ok(-1) => Ok(Compression::default()),
+        Ok(level @ 0..=9) => Ok(Compression::new(level as u32)),
+        _ => Err(GitError::invalid_command(format!("bad zlib compression level {}", value))),
+    }
+}
+
+/// the configured level, level 6 when nothing set it
+pub fn level() -> Compression {
+    LEVEL.get().copied().unwrap_or_default()
+}
+
 pub fn compress<T>(data: T) -> Result<Vec<u8>>
 where T: IntoIterator<Item=u8>
+{
+    compress_with(data, level())
+}
+
+pub fn compress_with<T>(data: T, level: Compression) -> Result<Vec<u8>>
+where T: IntoIterator<Item=u8>
 {
     let bytes = data.into_iter().collect::<Vec<_>>();
-    let mut z = ZlibEncoder::new(BufReader::new(bytes.as_slice()), Compression::fast());
+    let mut z = ZlibEncoder::new(BufReader::new(bytes.as_slice()), level);
     let mut buffer = Vec::new();
     z.read_to_end(&mut buffer)?;
 
@@ -64,17 +102,35 @@ where T: IntoIterator<Item=u8>
 pub fn compress_file<P>(path: &P) -> Result<Vec<u8>>
 where P: AsRef<Path>
 {
-    // println!("compress_file : {}", path.as_ref().display());
+    compress_file_with(path, level())
+}
 
+pub fn compress_file_with<P>(path: &P, level: Compression) -> Result<Vec<u8>>
+where P: AsRef<Path>
+{
     let bytes = read_file_as_bytes(path)?;
-    // println!("{}, {}", bytes.len(), path.as_ref().display());
-    compress(bytes)
+    compress_with(bytes, level)
 }
 
 pub fn compress_object<T: ObjType>(bytes: Vec<u8>) -> Result<Vec<u8>>
 {
-    let meta = format!("{} {}\0", T::VALUE, bytes.len()).into_bytes().into_iter();
-    compress(meta.chain(bytes))
+    compress_raw_object(T::VALUE, bytes)
+}
+
+pub fn compress_object_with<T: ObjType>(bytes: Vec<u8>, level: Compression) -> Result<Vec<u8>>
+{
+    compress_raw_object_with(T::VALUE, bytes, level)
+}
+
+pub fn compress_raw_object(kind: &str, bytes: Vec<u8>) -> Result<Vec<u8>>
+{
+    compress_raw_object_with(kind, bytes, level())
+}
+
+pub fn compress_raw_object_with(kind: &str, bytes: Vec<u8>, level: Compression) -> Result<Vec<u8>>
+{
+    let meta = format!("{} {}\0", kind, bytes.len()).into_bytes().into_iter();
+    compress_with(meta.chain(bytes), level)
 }
 
 /// 解压缩对象数据（从文件内容）